@@ -5,10 +5,10 @@ use blstrs::Scalar as Fr;
 use dialoguer::{theme::ColorfulTheme, MultiSelect};
 use filecoin_proofs::{
     parameters::{public_params, window_post_public_params, winning_post_public_params},
-    with_shape, DefaultPieceHasher, PaddedBytesAmount, PoRepConfig, PoRepProofPartitions,
-    PoStConfig, PoStType, SectorSize, POREP_PARTITIONS, PUBLISHED_SECTOR_SIZES,
-    WINDOW_POST_CHALLENGE_COUNT, WINDOW_POST_SECTOR_COUNT, WINNING_POST_CHALLENGE_COUNT,
-    WINNING_POST_SECTOR_COUNT,
+    tree_info, with_shape, DefaultPieceHasher, PaddedBytesAmount, PoRepConfig,
+    PoRepProofPartitions, PoStConfig, PoStType, SectorSize, OCT_ARITY, POREP_PARTITIONS,
+    PUBLISHED_SECTOR_SIZES, WINDOW_POST_CHALLENGE_COUNT, WINDOW_POST_SECTOR_COUNT,
+    WINNING_POST_CHALLENGE_COUNT, WINNING_POST_SECTOR_COUNT,
 };
 use humansize::{file_size_opts, FileSize};
 use log::{info, warn};
@@ -92,6 +92,8 @@ struct Opt {
     window: bool,
     #[structopt(long)]
     porep: bool,
+    #[structopt(long)]
+    trees: bool,
     #[structopt(short = "z", long, use_delimiter = true)]
     constraints_for_sector_sizes: Vec<u64>,
     #[structopt(default_value = "1.0.0", long)]
@@ -218,6 +220,7 @@ pub fn main() {
     let count_winning = opts.winning;
     let count_window = opts.window;
     let count_porep = opts.porep;
+    let show_trees = opts.trees;
     let api_version = ApiVersion::from_str(&opts.api_version)
         .expect("Failed to parse api_version from semver string");
 
@@ -250,5 +253,19 @@ pub fn main() {
                 human_size, info.constraints, info.inputs, partitions
             );
         }
+
+        if show_trees {
+            let info = tree_info(SectorSize(sector_size), OCT_ARITY, None)
+                .expect("failed to get tree info");
+            println!(
+                "{} tree_c and tree_r_last leaves: {}, base trees: {}, height: {}, tree_c nodes: {}, tree_r_last nodes: {}",
+                human_size,
+                info.leafs,
+                info.base_tree_count,
+                info.height,
+                info.tree_c_nodes,
+                info.tree_r_last_nodes
+            );
+        }
     }
 }
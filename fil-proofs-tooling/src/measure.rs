@@ -1,6 +1,5 @@
 use std::time::{Duration, Instant};
 
-use anyhow::Result;
 use cpu_time::ProcessTime;
 
 pub struct FuncMeasurement<T> {
@@ -9,9 +8,9 @@ pub struct FuncMeasurement<T> {
     pub return_value: T,
 }
 
-pub fn measure<T, F>(f: F) -> Result<FuncMeasurement<T>>
+pub fn measure<T, E, F>(f: F) -> Result<FuncMeasurement<T>, E>
 where
-    F: FnOnce() -> Result<T>,
+    F: FnOnce() -> Result<T, E>,
 {
     let cpu_time_start = ProcessTime::now();
     let wall_start_time = Instant::now();
@@ -2,25 +2,29 @@ use std::cmp::Ordering;
 use std::hash::{Hash as StdHash, Hasher as StdHasher};
 use std::panic::panic_any;
 
-use anyhow::ensure;
+use anyhow::{bail, ensure, Result};
 use bellperson::{
     gadgets::{boolean::Boolean, num::AllocatedNum},
     ConstraintSystem, SynthesisError,
 };
 use blstrs::Scalar as Fr;
 use ff::{Field, PrimeField};
-use generic_array::typenum::{marker_traits::Unsigned, U2};
+use generic_array::typenum::{marker_traits::Unsigned, U11, U16, U2, U24, U36, U4, U8};
 use merkletree::{
     hash::{Algorithm as LightAlgorithm, Hashable},
     merkle::Element,
 };
-use neptune::{circuit::poseidon_hash, poseidon::Poseidon};
+use neptune::{
+    circuit::poseidon_hash,
+    poseidon::{Poseidon, PoseidonConstants},
+};
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 
 use crate::types::{
-    Domain, HashFunction, Hasher, PoseidonArity, PoseidonMDArity, POSEIDON_CONSTANTS_16,
-    POSEIDON_CONSTANTS_2, POSEIDON_CONSTANTS_4, POSEIDON_CONSTANTS_8, POSEIDON_MD_CONSTANTS,
+    Domain, HashFunction, Hasher, PoseidonArity, PoseidonMDArity, POSEIDON_CONSTANTS_11,
+    POSEIDON_CONSTANTS_16, POSEIDON_CONSTANTS_2, POSEIDON_CONSTANTS_24, POSEIDON_CONSTANTS_4,
+    POSEIDON_CONSTANTS_8, POSEIDON_MD_CONSTANTS,
 };
 
 #[derive(Default, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -380,6 +384,67 @@ impl From<PoseidonDomain> for Fr {
     }
 }
 
+/// The parameters of the Poseidon hash used for one arity, to allow reimplementing it.
+///
+/// All field elements are hex encoded in their canonical little-endian representation. A hash of
+/// `arity` elements starts from the state `[domain_tag, inputs..]` and applies
+/// `full_rounds / 2` full rounds, `partial_rounds` partial rounds and `full_rounds / 2` full
+/// rounds again, and then returns the second element of the state. Every round adds the next
+/// `width` round constants to the state, applies the S-box `x^5` to all elements (full rounds) or
+/// only the first one (partial rounds), and multiplies the state as a row vector with the MDS
+/// matrix, i.e. `state'[j] = sum_i state[i] * mds_matrix[i][j]`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoseidonParams {
+    pub arity: usize,
+    /// The number of elements of the state, `arity + 1`.
+    pub width: usize,
+    pub full_rounds: usize,
+    pub partial_rounds: usize,
+    /// The first element of the state, which separates the hashes of different arities.
+    pub domain_tag: String,
+    /// `width` constants per round, in the order they are added.
+    pub round_constants: Vec<String>,
+    pub mds_matrix: Vec<Vec<String>>,
+}
+
+fn fr_to_hex(fr: &Fr) -> String {
+    hex::encode(fr.to_repr())
+}
+
+fn poseidon_params_from<A: PoseidonArity>(constants: &PoseidonConstants<Fr, A>) -> PoseidonParams {
+    PoseidonParams {
+        arity: A::to_usize(),
+        width: A::to_usize() + 1,
+        full_rounds: constants.full_rounds,
+        partial_rounds: constants.partial_rounds,
+        domain_tag: fr_to_hex(&constants.domain_tag),
+        round_constants: constants.round_constants.iter().map(fr_to_hex).collect(),
+        mds_matrix: constants
+            .mds_matrices
+            .m
+            .iter()
+            .map(|row| row.iter().map(fr_to_hex).collect())
+            .collect(),
+    }
+}
+
+/// Returns the parameters of the Poseidon hash of `arity` elements, as used for the merkle trees
+/// (arities 2, 4, 8, 11, 16 and 24) and for `hash_md` (arity 36).
+pub fn poseidon_parameters(arity: usize) -> Result<PoseidonParams> {
+    let params = match arity {
+        2 => poseidon_params_from::<U2>(&*POSEIDON_CONSTANTS_2),
+        4 => poseidon_params_from::<U4>(&*POSEIDON_CONSTANTS_4),
+        8 => poseidon_params_from::<U8>(&*POSEIDON_CONSTANTS_8),
+        11 => poseidon_params_from::<U11>(&*POSEIDON_CONSTANTS_11),
+        16 => poseidon_params_from::<U16>(&*POSEIDON_CONSTANTS_16),
+        24 => poseidon_params_from::<U24>(&*POSEIDON_CONSTANTS_24),
+        36 => poseidon_params_from::<U36>(&*POSEIDON_MD_CONSTANTS),
+        _ => bail!("unsupported arity for Poseidon hasher: {}", arity),
+    };
+
+    Ok(params)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -568,4 +633,106 @@ mod tests {
             circuit_hashed.get_value().expect("get_value failure")
         );
     }
+
+    /// Hashes `inputs` as described on `PoseidonParams`, using nothing but the parameters.
+    fn reference_hash(params: &PoseidonParams, inputs: &[Fr]) -> Fr {
+        let fr = |s: &String| {
+            let mut repr = [0u8; 32];
+            repr.copy_from_slice(&hex::decode(s).expect("invalid hex"));
+            Fr::from_repr_vartime(repr).expect("from_repr failure")
+        };
+        let round_constants: Vec<Fr> = params.round_constants.iter().map(fr).collect();
+        let mds: Vec<Vec<Fr>> = params
+            .mds_matrix
+            .iter()
+            .map(|row| row.iter().map(fr).collect())
+            .collect();
+
+        let mut state = vec![fr(&params.domain_tag)];
+        state.extend_from_slice(inputs);
+        assert_eq!(state.len(), params.width);
+
+        let half_full_rounds = params.full_rounds / 2;
+        let rounds = params.full_rounds + params.partial_rounds;
+        for round in 0..rounds {
+            for (i, element) in state.iter_mut().enumerate() {
+                *element += round_constants[round * params.width + i];
+            }
+
+            let full =
+                round < half_full_rounds || round >= half_full_rounds + params.partial_rounds;
+            let s_boxed = if full { params.width } else { 1 };
+            for element in state.iter_mut().take(s_boxed) {
+                let x = *element;
+                *element = x.square().square() * x;
+            }
+
+            state = (0..params.width)
+                .map(|j| {
+                    state
+                        .iter()
+                        .zip(mds.iter())
+                        .fold(Fr::zero(), |acc, (element, row)| acc + *element * row[j])
+                })
+                .collect();
+        }
+
+        state[1]
+    }
+
+    fn check_parameters<A: PoseidonArity>(constants: &PoseidonConstants<Fr, A>) {
+        let arity = A::to_usize();
+        let params = poseidon_parameters(arity).expect("poseidon_parameters failure");
+        assert_eq!(params.arity, arity);
+        assert_eq!(params.mds_matrix.len(), params.width);
+        assert_eq!(
+            params.round_constants.len(),
+            params.width * (params.full_rounds + params.partial_rounds)
+        );
+
+        let inputs: Vec<Fr> = (0..arity as u64).map(|i| Fr::from(i + 1)).collect();
+        let expected = Poseidon::new_with_preimage(&inputs, constants).hash();
+        assert_eq!(
+            reference_hash(&params, &inputs),
+            expected,
+            "arity {}",
+            arity
+        );
+    }
+
+    #[test]
+    fn test_poseidon_parameters() {
+        check_parameters::<U2>(&*POSEIDON_CONSTANTS_2);
+        check_parameters::<U4>(&*POSEIDON_CONSTANTS_4);
+        check_parameters::<U8>(&*POSEIDON_CONSTANTS_8);
+        check_parameters::<U11>(&*POSEIDON_CONSTANTS_11);
+        check_parameters::<U16>(&*POSEIDON_CONSTANTS_16);
+        check_parameters::<U24>(&*POSEIDON_CONSTANTS_24);
+        check_parameters::<U36>(&*POSEIDON_MD_CONSTANTS);
+
+        assert!(poseidon_parameters(3).is_err());
+
+        let params = poseidon_parameters(2).expect("poseidon_parameters failure");
+        let serialized = serde_json::to_string(&params).expect("failed to serialize params");
+        let deserialized: PoseidonParams =
+            serde_json::from_str(&serialized).expect("failed to deserialize params");
+        assert_eq!(deserialized, params);
+    }
+
+    #[test]
+    fn test_poseidon_parameters_known_vector() {
+        // The same node as in `test_poseidon_hasher`.
+        let params = poseidon_parameters(2).expect("poseidon_parameters failure");
+        let hash = reference_hash(&params, &[Fr::one(), Fr::zero()]);
+
+        assert_eq!(
+            hash.to_repr(),
+            u64s_to_u8s([
+                0xb339ff6079800b5e,
+                0xec5907b3dc3094af,
+                0x93c003cc74a24f26,
+                0x042f94ffbe786bc3,
+            ])
+        );
+    }
 }
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
@@ -8,48 +9,72 @@ use filecoin_hashers::Hasher;
 use fr32::{write_unpadded, Fr32Reader};
 use log::{info, trace};
 use memmap::MmapOptions;
-use merkletree::store::{DiskStore, LevelCacheStore, StoreConfig};
+use merkletree::{
+    merkle::{get_merkle_tree_cache_size, get_merkle_tree_len, get_merkle_tree_row_count},
+    store::{DiskStore, LevelCacheStore, StoreConfig},
+};
 use storage_proofs_core::{
     cache_key::CacheKey,
     measurements::{measure_op, Operation},
     merkle::get_base_tree_count,
     pieces::generate_piece_commitment_bytes_from_source,
     sector::SectorId,
-    util::default_rows_to_discard,
+    settings::SETTINGS,
+    util::{default_rows_to_discard, rows_to_discard, NODE_SIZE},
 };
 use storage_proofs_porep::{
-    stacked::{generate_replica_id, PersistentAux, StackedDrg, TemporaryAux},
+    stacked::{
+        generate_replica_id, ParentCacheProgress, ParentCacheProgressCallback, PersistentAux,
+        StackedDrg, TemporaryAux,
+    },
     PoRep,
 };
 pub use storage_proofs_update::constants::TreeRHasher;
 use typenum::Unsigned;
 
 use crate::{
+    caches::get_stacked_verifying_key,
     commitment_reader::CommitmentReader,
     constants::{
-        DefaultBinaryTree, DefaultOctTree, DefaultPieceDomain, DefaultPieceHasher,
+        DefaultBinaryTree, DefaultOctTree, DefaultPieceDomain, DefaultPieceHasher, LAYERS,
         MINIMUM_RESERVED_BYTES_FOR_PIECE_IN_FULLY_ALIGNED_SECTOR as MINIMUM_PIECE_SIZE,
     },
+    error::{classified, ensure_input, FilProofsError},
     parameters::public_params,
-    pieces::{get_piece_alignment, sum_piece_bytes_with_alignment},
+    pieces::{get_piece_alignment, sum_piece_bytes_with_alignment, ZeroPaddedSource},
     types::{
         Commitment, MerkleTreeTrait, PaddedBytesAmount, PieceInfo, PoRepConfig,
-        PoRepProofPartitions, ProverId, SealPreCommitPhase1Output, Ticket, UnpaddedByteIndex,
-        UnpaddedBytesAmount,
+        PoRepProofPartitions, ProverId, SealPreCommitPhase1Output, SectorSize, Ticket, TreeInfo,
+        UnpaddedByteIndex, UnpaddedBytesAmount,
     },
+    with_shape,
 };
 
+pub use crate::caches::clear_param_cache;
+
 mod fake_seal;
+#[cfg(feature = "async")]
+mod nonblocking;
 mod post_util;
+mod reconstruct;
+mod repair;
 mod seal;
+#[cfg(feature = "self-test")]
+mod self_test;
 mod update;
 mod util;
 mod window_post;
 mod winning_post;
 
 pub use fake_seal::*;
+#[cfg(feature = "async")]
+pub use nonblocking::*;
 pub use post_util::*;
+pub use reconstruct::*;
+pub use repair::*;
 pub use seal::*;
+#[cfg(feature = "self-test")]
+pub use self_test::*;
 pub use update::*;
 pub use util::*;
 pub use window_post::*;
@@ -86,29 +111,32 @@ pub fn get_unsealed_range<T: Into<PathBuf> + AsRef<Path>, Tree: 'static + Merkle
     ticket: Ticket,
     offset: UnpaddedByteIndex,
     num_bytes: UnpaddedBytesAmount,
-) -> Result<UnpaddedBytesAmount> {
-    info!("get_unsealed_range:start");
-
-    let f_out = File::create(&output_path)
-        .with_context(|| format!("could not create output_path={:?}", output_path.as_ref()))?;
-
-    let buf_f_out = BufWriter::new(f_out);
-
-    let result = unseal_range_mapped::<_, _, Tree>(
-        porep_config,
-        cache_path,
-        sealed_path.into(),
-        buf_f_out,
-        prover_id,
-        sector_id,
-        comm_d,
-        ticket,
-        offset,
-        num_bytes,
-    );
-
-    info!("get_unsealed_range:finish");
-    result
+) -> Result<UnpaddedBytesAmount, FilProofsError> {
+    classified(|| {
+        info!("get_unsealed_range:start");
+
+        let f_out = File::create(&output_path)
+            .with_context(|| format!("could not create output_path={:?}", output_path.as_ref()))?;
+
+        let buf_f_out = BufWriter::new(f_out);
+
+        let result = unseal_range_mapped::<_, _, Tree>(
+            porep_config,
+            cache_path,
+            sealed_path.into(),
+            buf_f_out,
+            prover_id,
+            sector_id,
+            comm_d,
+            ticket,
+            offset,
+            num_bytes,
+        )
+        .map_err(Into::into);
+
+        info!("get_unsealed_range:finish");
+        result
+    })
 }
 
 /// Unseals the sector read from `sealed_sector` and returns the bytes for a
@@ -140,43 +168,45 @@ pub fn unseal_range<P, R, W, Tree>(
     ticket: Ticket,
     offset: UnpaddedByteIndex,
     num_bytes: UnpaddedBytesAmount,
-) -> Result<UnpaddedBytesAmount>
+) -> Result<UnpaddedBytesAmount, FilProofsError>
 where
     P: Into<PathBuf> + AsRef<Path>,
     R: Read,
     W: Write,
     Tree: 'static + MerkleTreeTrait,
 {
-    info!("unseal_range:start");
-    ensure!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
-
-    let comm_d =
-        as_safe_commitment::<<DefaultPieceHasher as Hasher>::Domain, _>(&comm_d, "comm_d")?;
-
-    let replica_id = generate_replica_id::<Tree::Hasher, _>(
-        &prover_id,
-        sector_id.into(),
-        &ticket,
-        comm_d,
-        &porep_config.porep_id,
-    );
+    classified(|| {
+        info!("unseal_range:start");
+        ensure_input!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
+
+        let comm_d =
+            as_safe_commitment::<<DefaultPieceHasher as Hasher>::Domain, _>(&comm_d, "comm_d")?;
+
+        let replica_id = generate_replica_id::<Tree::Hasher, _>(
+            &prover_id,
+            sector_id.into(),
+            &ticket,
+            comm_d,
+            &porep_config.porep_id,
+        );
 
-    let mut data = Vec::new();
-    sealed_sector.read_to_end(&mut data)?;
-
-    let res = unseal_range_inner::<_, _, Tree>(
-        porep_config,
-        cache_path,
-        &mut data,
-        unsealed_output,
-        replica_id,
-        offset,
-        num_bytes,
-    )?;
+        let mut data = Vec::new();
+        sealed_sector.read_to_end(&mut data)?;
+
+        let res = unseal_range_inner::<_, _, Tree>(
+            porep_config,
+            cache_path,
+            &mut data,
+            unsealed_output,
+            replica_id,
+            offset,
+            num_bytes,
+        )?;
 
-    info!("unseal_range:finish");
+        info!("unseal_range:finish");
 
-    Ok(res)
+        Ok(res)
+    })
 }
 
 /// Unseals the sector read from `sealed_sector` and returns the bytes for a
@@ -208,44 +238,47 @@ pub fn unseal_range_mapped<P, W, Tree>(
     ticket: Ticket,
     offset: UnpaddedByteIndex,
     num_bytes: UnpaddedBytesAmount,
-) -> Result<UnpaddedBytesAmount>
+) -> Result<UnpaddedBytesAmount, FilProofsError>
 where
     P: Into<PathBuf> + AsRef<Path>,
     W: Write,
     Tree: 'static + MerkleTreeTrait,
 {
-    info!("unseal_range_mapped:start");
-    ensure!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
-
-    let comm_d =
-        as_safe_commitment::<<DefaultPieceHasher as Hasher>::Domain, _>(&comm_d, "comm_d")?;
-
-    let replica_id = generate_replica_id::<Tree::Hasher, _>(
-        &prover_id,
-        sector_id.into(),
-        &ticket,
-        comm_d,
-        &porep_config.porep_id,
-    );
+    classified(|| {
+        info!("unseal_range_mapped:start");
+        ensure_input!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
+
+        let comm_d =
+            as_safe_commitment::<<DefaultPieceHasher as Hasher>::Domain, _>(&comm_d, "comm_d")?;
+
+        let replica_id = generate_replica_id::<Tree::Hasher, _>(
+            &prover_id,
+            sector_id.into(),
+            &ticket,
+            comm_d,
+            &porep_config.porep_id,
+        );
 
-    let mapped_file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(&sealed_path)?;
-    let mut data = unsafe { MmapOptions::new().map_copy(&mapped_file)? };
-
-    let result = unseal_range_inner::<_, _, Tree>(
-        porep_config,
-        cache_path,
-        &mut data,
-        unsealed_output,
-        replica_id,
-        offset,
-        num_bytes,
-    );
-    info!("unseal_range_mapped:finish");
+        ensure_replica_len(&sealed_path, porep_config.sector_size)?;
+        let mapped_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&sealed_path)?;
+        let mut data = unsafe { MmapOptions::new().map_copy(&mapped_file)? };
+
+        let result = unseal_range_inner::<_, _, Tree>(
+            porep_config,
+            cache_path,
+            &mut data,
+            unsealed_output,
+            replica_id,
+            offset,
+            num_bytes,
+        );
+        info!("unseal_range_mapped:finish");
 
-    result
+        result
+    })
 }
 
 /// Unseals the sector read from `sealed_sector` and returns the bytes for a
@@ -330,26 +363,28 @@ where
 pub fn generate_piece_commitment<T: Read>(
     source: T,
     piece_size: UnpaddedBytesAmount,
-) -> Result<PieceInfo> {
-    trace!("generate_piece_commitment:start");
+) -> Result<PieceInfo, FilProofsError> {
+    classified(|| {
+        trace!("generate_piece_commitment:start");
 
-    let result = measure_op(Operation::GeneratePieceCommitment, || {
-        ensure_piece_size(piece_size)?;
+        let result = measure_op(Operation::GeneratePieceCommitment, || {
+            ensure_piece_size(piece_size)?;
 
-        // send the source through the preprocessor
-        let source = BufReader::new(source);
-        let mut fr32_reader = Fr32Reader::new(source);
+            // send the source through the preprocessor
+            let source = BufReader::new(source);
+            let mut fr32_reader = Fr32Reader::new(source);
 
-        let commitment = generate_piece_commitment_bytes_from_source::<DefaultPieceHasher>(
-            &mut fr32_reader,
-            PaddedBytesAmount::from(piece_size).into(),
-        )?;
+            let commitment = generate_piece_commitment_bytes_from_source::<DefaultPieceHasher>(
+                &mut fr32_reader,
+                PaddedBytesAmount::from(piece_size).into(),
+            )?;
 
-        PieceInfo::new(commitment, piece_size)
-    });
+            PieceInfo::new(commitment, piece_size)
+        });
 
-    trace!("generate_piece_commitment:finish");
-    result
+        trace!("generate_piece_commitment:finish");
+        result
+    })
 }
 
 /// Computes a NUL-byte prefix and/or suffix for `source` using the provided
@@ -376,65 +411,125 @@ pub fn add_piece<R, W>(
     target: W,
     piece_size: UnpaddedBytesAmount,
     piece_lengths: &[UnpaddedBytesAmount],
-) -> Result<(PieceInfo, UnpaddedBytesAmount)>
+) -> Result<(PieceInfo, UnpaddedBytesAmount), FilProofsError>
 where
     R: Read,
     W: Write,
 {
-    trace!("add_piece:start");
+    classified(|| {
+        trace!("add_piece:start");
 
-    let result = measure_op(Operation::AddPiece, || {
-        ensure_piece_size(piece_size)?;
+        let result = measure_op(Operation::AddPiece, || {
+            add_piece_inner(source, target, piece_size, piece_lengths)
+        });
 
-        let source = BufReader::new(source);
-        let mut target = BufWriter::new(target);
+        trace!("add_piece:finish");
+        result
+    })
+}
 
-        let written_bytes = sum_piece_bytes_with_alignment(piece_lengths);
-        let piece_alignment = get_piece_alignment(written_bytes, piece_size);
-        let fr32_reader = Fr32Reader::new(source);
+/// Like `add_piece`, but for a `source` whose length is not known in advance, e.g. a network
+/// stream, which only has to implement `Read`. Its bytes are fr32 padded and written to `target`
+/// as they are read, and once it ends, the piece of `piece_size` is filled up with zeros, whose
+/// `PieceInfo` is returned. So the source is neither copied nor read ahead to learn its length.
+///
+/// Returns the piece info, the number of bytes written to `target` like `add_piece`, and the
+/// number of bytes read from `source`. Fails if `source` has no bytes or more than `piece_size`
+/// bytes, in which case `target` may hold part of the piece already.
+///
+/// # Arguments
+///
+/// * `source` - a readable source of at most `piece_size` unprocessed piece bytes.
+/// * `target` - a writer where we will write the processed piece bytes.
+/// * `piece_size` - the number of unpadded bytes of the piece the source is padded to.
+/// * `piece_lengths` - the number of bytes for each previous piece in the sector.
+pub fn add_piece_streaming<R, W>(
+    source: R,
+    target: W,
+    piece_size: UnpaddedBytesAmount,
+    piece_lengths: &[UnpaddedBytesAmount],
+) -> Result<(PieceInfo, UnpaddedBytesAmount, UnpaddedBytesAmount), FilProofsError>
+where
+    R: Read,
+    W: Write,
+{
+    classified(|| {
+        trace!("add_piece_streaming:start");
 
-        // write left alignment
-        for _ in 0..usize::from(PaddedBytesAmount::from(piece_alignment.left_bytes)) {
-            target.write_all(&[0u8][..])?;
-        }
+        let result = measure_op(Operation::AddPiece, || {
+            let mut source = ZeroPaddedSource::new(source, piece_size.into());
+            let (piece_info, written) =
+                add_piece_inner(&mut source, target, piece_size, piece_lengths)?;
+            ensure!(
+                source.source_bytes() != 0,
+                "add_piece_streaming: read 0 bytes before EOF from source"
+            );
 
-        let mut commitment_reader = CommitmentReader::new(fr32_reader);
-        let n = io::copy(&mut commitment_reader, &mut target)
-            .context("failed to write and preprocess bytes")?;
+            Ok((
+                piece_info,
+                written,
+                UnpaddedBytesAmount(source.source_bytes()),
+            ))
+        });
 
-        ensure!(n != 0, "add_piece: read 0 bytes before EOF from source");
-        let n = PaddedBytesAmount(n as u64);
-        let n: UnpaddedBytesAmount = n.into();
+        trace!("add_piece_streaming:finish");
+        result
+    })
+}
 
-        ensure!(n == piece_size, "add_piece: invalid bytes amount written");
+fn add_piece_inner<R: Read, W: Write>(
+    source: R,
+    target: W,
+    piece_size: UnpaddedBytesAmount,
+    piece_lengths: &[UnpaddedBytesAmount],
+) -> Result<(PieceInfo, UnpaddedBytesAmount)> {
+    ensure_piece_size(piece_size)?;
 
-        // write right alignment
-        for _ in 0..usize::from(PaddedBytesAmount::from(piece_alignment.right_bytes)) {
-            target.write_all(&[0u8][..])?;
-        }
+    let source = BufReader::new(source);
+    let mut target = BufWriter::new(target);
+
+    let written_bytes = sum_piece_bytes_with_alignment(piece_lengths);
+    let piece_alignment = get_piece_alignment(written_bytes, piece_size);
+    let fr32_reader = Fr32Reader::new(source);
+
+    // write left alignment
+    for _ in 0..usize::from(PaddedBytesAmount::from(piece_alignment.left_bytes)) {
+        target.write_all(&[0u8][..])?;
+    }
+
+    let mut commitment_reader = CommitmentReader::new(fr32_reader);
+    let n = io::copy(&mut commitment_reader, &mut target)
+        .context("failed to write and preprocess bytes")?;
+
+    ensure!(n != 0, "add_piece: read 0 bytes before EOF from source");
+    let n = PaddedBytesAmount(n as u64);
+    let n: UnpaddedBytesAmount = n.into();
+
+    ensure!(n == piece_size, "add_piece: invalid bytes amount written");
 
-        let commitment = commitment_reader.finish()?;
-        let mut comm = [0u8; 32];
-        comm.copy_from_slice(commitment.as_ref());
+    // write right alignment
+    for _ in 0..usize::from(PaddedBytesAmount::from(piece_alignment.right_bytes)) {
+        target.write_all(&[0u8][..])?;
+    }
 
-        let written = piece_alignment.left_bytes + piece_alignment.right_bytes + piece_size;
+    let commitment = commitment_reader.finish()?;
+    let mut comm = [0u8; 32];
+    comm.copy_from_slice(commitment.as_ref());
 
-        Ok((PieceInfo::new(comm, n)?, written))
-    });
+    let written = piece_alignment.left_bytes + piece_alignment.right_bytes + piece_size;
 
-    trace!("add_piece:finish");
-    result
+    Ok((PieceInfo::new(comm, n)?, written))
 }
 
 fn ensure_piece_size(piece_size: UnpaddedBytesAmount) -> Result<()> {
-    ensure!(
+    ensure_input!(
         piece_size >= UnpaddedBytesAmount(MINIMUM_PIECE_SIZE),
         "Piece must be at least {} bytes",
         MINIMUM_PIECE_SIZE
     );
 
     let padded_piece_size: PaddedBytesAmount = piece_size.into();
-    ensure!(
+    ensure_input!(
         u64::from(padded_piece_size).is_power_of_two(),
         "Bit-padded piece size must be a power of 2 ({:?})",
         padded_piece_size,
@@ -461,7 +556,7 @@ pub fn write_and_preprocess<R, W>(
     source: R,
     target: W,
     piece_size: UnpaddedBytesAmount,
-) -> Result<(PieceInfo, UnpaddedBytesAmount)>
+) -> Result<(PieceInfo, UnpaddedBytesAmount), FilProofsError>
 where
     R: Read,
     W: Write,
@@ -626,42 +721,44 @@ pub fn validate_cache_for_precommit_phase2<R, T, Tree: MerkleTreeTrait>(
     cache_path: R,
     replica_path: T,
     seal_precommit_phase1_output: &SealPreCommitPhase1Output<Tree>,
-) -> Result<()>
+) -> Result<(), FilProofsError>
 where
     R: AsRef<Path>,
     T: AsRef<Path>,
 {
-    info!("validate_cache_for_precommit_phase2:start");
+    classified(|| {
+        info!("validate_cache_for_precommit_phase2:start");
 
-    ensure!(
-        replica_path.as_ref().exists(),
-        "Missing replica: {}",
-        replica_path.as_ref().to_path_buf().display()
-    );
+        ensure!(
+            replica_path.as_ref().exists(),
+            "Missing replica: {}",
+            replica_path.as_ref().to_path_buf().display()
+        );
 
-    // Verify all stores/labels within the Labels object, but
-    // respecting the current cache_path.
-    let cache = cache_path.as_ref().to_path_buf();
-    seal_precommit_phase1_output
-        .labels
-        .verify_stores(verify_store, &cache)?;
-
-    // Update the previous phase store path to the current cache_path.
-    let mut config = StoreConfig::from_config(
-        &seal_precommit_phase1_output.config,
-        &seal_precommit_phase1_output.config.id,
-        seal_precommit_phase1_output.config.size,
-    );
-    config.path = cache_path.as_ref().into();
+        // Verify all stores/labels within the Labels object, but
+        // respecting the current cache_path.
+        let cache = cache_path.as_ref().to_path_buf();
+        seal_precommit_phase1_output
+            .labels
+            .verify_stores(verify_store, &cache)?;
+
+        // Update the previous phase store path to the current cache_path.
+        let mut config = StoreConfig::from_config(
+            &seal_precommit_phase1_output.config,
+            &seal_precommit_phase1_output.config.id,
+            seal_precommit_phase1_output.config.size,
+        );
+        config.path = cache_path.as_ref().into();
 
-    let result = verify_store(
-        &config,
-        <DefaultBinaryTree as MerkleTreeTrait>::Arity::to_usize(),
-        get_base_tree_count::<Tree>(),
-    );
+        let result = verify_store(
+            &config,
+            <DefaultBinaryTree as MerkleTreeTrait>::Arity::to_usize(),
+            get_base_tree_count::<Tree>(),
+        );
 
-    info!("validate_cache_for_precommit_phase2:finish");
-    result
+        info!("validate_cache_for_precommit_phase2:finish");
+        result
+    })
 }
 
 // Checks for the existence of the replica data and t_aux, which in
@@ -670,68 +767,330 @@ where
 pub fn validate_cache_for_commit<R, T, Tree: MerkleTreeTrait>(
     cache_path: R,
     replica_path: T,
-) -> Result<()>
+) -> Result<(), FilProofsError>
 where
     R: AsRef<Path>,
     T: AsRef<Path>,
 {
-    info!("validate_cache_for_commit:start");
+    classified(|| {
+        info!("validate_cache_for_commit:start");
 
-    // Verify that the replica exists and is not empty.
-    ensure!(
-        replica_path.as_ref().exists(),
-        "Missing replica: {}",
-        replica_path.as_ref().to_path_buf().display()
-    );
+        // Verify that the replica exists and is not empty.
+        ensure!(
+            replica_path.as_ref().exists(),
+            "Missing replica: {}",
+            replica_path.as_ref().to_path_buf().display()
+        );
 
-    let metadata = File::open(&replica_path)?.metadata()?;
-    ensure!(
-        metadata.len() > 0,
-        "Replica {} exists, but is empty!",
-        replica_path.as_ref().to_path_buf().display()
-    );
+        // The length of a block device is checked against tree_d below.
+        let metadata = File::open(&replica_path)?.metadata()?;
+        ensure!(
+            !metadata.is_file() || metadata.len() > 0,
+            "Replica {} exists, but is empty!",
+            replica_path.as_ref().to_path_buf().display()
+        );
 
-    let cache = &cache_path.as_ref();
+        let cache = &cache_path.as_ref();
+
+        // Make sure p_aux exists and is valid.
+        let p_aux_path = cache.join(CacheKey::PAux.to_string());
+        let p_aux_bytes = fs::read(&p_aux_path)
+            .with_context(|| format!("could not read file p_aux={:?}", p_aux_path))?;
+
+        let _: PersistentAux<<Tree::Hasher as Hasher>::Domain> = deserialize(&p_aux_bytes)?;
+        drop(p_aux_bytes);
+
+        // Make sure t_aux exists and is valid.
+        let t_aux = {
+            let t_aux_path = cache.join(CacheKey::TAux.to_string());
+            let t_aux_bytes = fs::read(&t_aux_path)
+                .with_context(|| format!("could not read file t_aux={:?}", t_aux_path))?;
+
+            let mut res: TemporaryAux<Tree, DefaultPieceHasher> = deserialize(&t_aux_bytes)?;
+
+            // Switch t_aux to the passed in cache_path
+            res.set_cache_path(&cache_path);
+            res
+        };
+
+        // The replica holds one node per leaf of tree d.
+        let tree_d_size = t_aux
+            .tree_d_config
+            .size
+            .context("tree_d config has no size")?;
+        let nodes = get_base_tree_leafs::<DefaultBinaryTree>(tree_d_size)?;
+        ensure_replica_len(
+            replica_path.as_ref(),
+            SectorSize((nodes * NODE_SIZE) as u64),
+        )?;
 
-    // Make sure p_aux exists and is valid.
-    let p_aux_path = cache.join(CacheKey::PAux.to_string());
-    let p_aux_bytes = fs::read(&p_aux_path)
-        .with_context(|| format!("could not read file p_aux={:?}", p_aux_path))?;
+        // Verify all stores/labels within the Labels object.
+        let cache = cache_path.as_ref().to_path_buf();
+        t_aux.labels.verify_stores(verify_store, &cache)?;
 
-    let _: PersistentAux<<Tree::Hasher as Hasher>::Domain> = deserialize(&p_aux_bytes)?;
-    drop(p_aux_bytes);
+        // Verify each tree disk store.
+        verify_store(
+            &t_aux.tree_d_config,
+            <DefaultBinaryTree as MerkleTreeTrait>::Arity::to_usize(),
+            get_base_tree_count::<Tree>(),
+        )?;
+        verify_store(
+            &t_aux.tree_c_config,
+            Tree::TreeCArity::to_usize(),
+            get_base_tree_count::<Tree>(),
+        )?;
+        verify_level_cache_store::<DefaultOctTree>(&t_aux.tree_r_last_config)?;
 
-    // Make sure t_aux exists and is valid.
-    let t_aux = {
-        let t_aux_path = cache.join(CacheKey::TAux.to_string());
-        let t_aux_bytes = fs::read(&t_aux_path)
-            .with_context(|| format!("could not read file t_aux={:?}", t_aux_path))?;
+        info!("validate_cache_for_commit:finish");
 
-        let mut res: TemporaryAux<Tree, DefaultPieceHasher> = deserialize(&t_aux_bytes)?;
+        Ok(())
+    })
+}
 
-        // Switch t_aux to the passed in cache_path
-        res.set_cache_path(&cache_path);
-        res
-    };
+/// An upper bound for the serialized size of a store config in t_aux, whose path may be as long
+/// as Linux allows (`PATH_MAX`).
+const MAX_STORE_CONFIG_BYTES: u64 = 4096 + 64;
 
-    // Verify all stores/labels within the Labels object.
-    let cache = cache_path.as_ref().to_path_buf();
-    t_aux.labels.verify_stores(verify_store, &cache)?;
+/// Returns the number of bytes the cache directory of a sector grows to while sealing, which is
+/// reached after `seal_pre_commit_phase2` and lasts until the cache is cleared.
+///
+/// This accounts for the labels of all layers, tree_d, tree_c and tree_r_last, as well as p_aux and
+/// t_aux. Of the current settings, only `rows_to_discard` changes the result, since it determines
+/// how much of tree_r_last is kept on disk, unless `rows_to_discard_override` overrides it, as
+/// `seal_pre_commit_phase2_with_rows_to_discard` does; `tree_c_low_memory` and the GPU tree
+/// builders only change how the trees are built. The size of t_aux depends on the length of the
+/// cache path, so it is accounted for the longest possible path. No files are read or written.
+pub fn expected_cache_size<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    rows_to_discard_override: Option<usize>,
+) -> Result<u64, FilProofsError> {
+    classified(|| {
+        let sector_size = u64::from(porep_config.sector_size);
+        let nodes = sector_size as usize / NODE_SIZE;
+        let layers = *LAYERS
+            .read()
+            .expect("LAYERS poisoned")
+            .get(&sector_size)
+            .with_context(|| format!("unknown sector size {}", sector_size))?
+            as u64;
+
+        let base_tree_count = get_base_tree_count::<Tree>() as u64;
+        let arity = Tree::Arity::to_usize();
+        let base_tree_size = get_base_tree_size::<Tree>(porep_config.sector_size)?;
+        let base_tree_leafs = get_base_tree_leafs::<Tree>(base_tree_size)?;
+
+        let labels = layers * sector_size;
+        let tree_d = get_merkle_tree_len(
+            nodes,
+            <DefaultBinaryTree as MerkleTreeTrait>::Arity::to_usize(),
+        )? as u64;
+        let tree_c = base_tree_count
+            * get_merkle_tree_len(base_tree_leafs, Tree::TreeCArity::to_usize())? as u64;
+        // tree_r_last discards its lowest rows, and reads its leaves from the replica.
+        let tree_r_last = base_tree_count
+            * get_merkle_tree_cache_size(
+                base_tree_leafs,
+                arity,
+                rows_to_discard(nodes, arity, rows_to_discard_override),
+            )? as u64;
 
-    // Verify each tree disk store.
-    verify_store(
-        &t_aux.tree_d_config,
-        <DefaultBinaryTree as MerkleTreeTrait>::Arity::to_usize(),
-        get_base_tree_count::<Tree>(),
-    )?;
-    verify_store(
-        &t_aux.tree_c_config,
-        <DefaultOctTree as MerkleTreeTrait>::Arity::to_usize(),
-        get_base_tree_count::<Tree>(),
-    )?;
-    verify_level_cache_store::<DefaultOctTree>(&t_aux.tree_r_last_config)?;
+        // p_aux holds comm_c and comm_r_last, t_aux the store configs of all layers and trees.
+        let p_aux = 2 * NODE_SIZE as u64;
+        let t_aux = (layers + 3) * MAX_STORE_CONFIG_BYTES;
 
-    info!("validate_cache_for_commit:finish");
+        Ok(labels + (tree_d + tree_c + tree_r_last) * NODE_SIZE as u64 + p_aux + t_aux)
+    })
+}
 
-    Ok(())
+/// Returns the number of leaves, the height and the number of stored nodes of tree_c and
+/// tree_r_last of a sector of `sector_size`, whose base trees have `arity`.
+///
+/// The base trees are joined by the sub and top trees of the shape of `sector_size`, e.g. the
+/// 32GiB shape splits the sector into 8 base trees joined by an 8-ary sub tree. Like
+/// `expected_cache_size`, the nodes of tree_r_last depend on `rows_to_discard_override`, or the
+/// `rows_to_discard` setting if it is `None`. No files are read.
+///
+/// # Arguments
+///
+/// * `sector_size` - one of the supported sector sizes.
+/// * `arity` - the arity of the base trees, 8 for the oct trees of all supported shapes.
+/// * `rows_to_discard_override` - the `rows_to_discard` the sector is sealed with, see
+///   `seal_pre_commit_phase2_with_rows_to_discard`.
+pub fn tree_info(
+    sector_size: SectorSize,
+    arity: usize,
+    rows_to_discard_override: Option<usize>,
+) -> Result<TreeInfo, FilProofsError> {
+    classified(|| {
+        let size = u64::from(sector_size);
+        ensure_input!(
+            LAYERS.read().expect("LAYERS poisoned").contains_key(&size),
+            "unknown sector size {}",
+            size
+        );
+        ensure_input!(
+            arity > 1 && arity.is_power_of_two(),
+            "invalid arity {}, must be a power of two",
+            arity
+        );
+
+        let (sub_tree_arity, top_tree_arity) = with_shape!(size, shape_arities);
+        let leafs = size as usize / NODE_SIZE;
+        let base_tree_count = sub_tree_arity.max(1) * top_tree_arity.max(1);
+        let base_tree_leafs = leafs / base_tree_count;
+        // All supported sector sizes are powers of two.
+        ensure_input!(
+            base_tree_leafs.trailing_zeros() % arity.trailing_zeros() == 0,
+            "{} leaves do not form a tree of arity {}",
+            base_tree_leafs,
+            arity
+        );
+
+        let base_tree_nodes = get_merkle_tree_len(base_tree_leafs, arity)?;
+        let height = get_merkle_tree_row_count(base_tree_leafs, arity)
+            + usize::from(sub_tree_arity > 0)
+            + usize::from(top_tree_arity > 0);
+        let tree_r_last_nodes = get_merkle_tree_cache_size(
+            base_tree_leafs,
+            arity,
+            rows_to_discard(base_tree_leafs, arity, rows_to_discard_override),
+        )?;
+
+        Ok(TreeInfo {
+            leafs,
+            base_tree_count,
+            height,
+            tree_c_nodes: base_tree_count * base_tree_nodes,
+            tree_r_last_nodes: base_tree_count * tree_r_last_nodes,
+        })
+    })
+}
+
+/// Returns the arities of the sub and top trees of `Tree`, zero if it has none.
+fn shape_arities<Tree: MerkleTreeTrait>() -> (usize, usize) {
+    (
+        Tree::SubTreeArity::to_usize(),
+        Tree::TopTreeArity::to_usize(),
+    )
+}
+
+/// Generates the parent cache of `porep_config` unless it exists already, and returns its path.
+///
+/// While the cache is generated, `progress` is called with the nodes done so far after every
+/// `progress_interval` nodes, and once more when all are done. The nodes in between two calls are
+/// generated in parallel, so an interval that is too small slows the generation down; a few
+/// hundred calls per cache are cheap. Nothing is reported for a cache which exists already.
+///
+/// # Arguments
+///
+/// * `porep_config` - the porep config of the sectors the cache is for.
+/// * `progress_interval` - the number of nodes between two progress reports, must not be zero.
+/// * `progress` - receives the progress of the generation.
+pub fn generate_parent_cache<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    progress_interval: u32,
+    progress: impl Fn(ParentCacheProgress) + Sync,
+) -> Result<PathBuf, FilProofsError> {
+    classified(|| {
+        info!("generate_parent_cache:start");
+
+        let pp = public_params::<Tree>(
+            PaddedBytesAmount::from(porep_config),
+            usize::from(PoRepProofPartitions::from(porep_config)),
+            porep_config.porep_id,
+            porep_config.api_version,
+        )?;
+        let cache = pp
+            .graph
+            .parent_cache_with_progress(Some(ParentCacheProgressCallback {
+                interval: progress_interval,
+                callback: &progress,
+            }))?;
+
+        info!("generate_parent_cache:finish: {}", cache.path.display());
+        Ok(cache.path)
+    })
+}
+
+/// Generates the parent cache of `porep_config` unless it exists already, and reads all of it, so
+/// that it is in memory before the first sector is sealed. With `parent_cache_shm_dir`, the cache
+/// is copied to shared memory first, unless another process did already, so this only needs to be
+/// called once per machine. Returns the path of the cache which is read.
+///
+/// # Arguments
+///
+/// * `porep_config` - the porep config of the sectors the cache is for.
+pub fn prewarm_parent_cache<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+) -> Result<PathBuf, FilProofsError> {
+    classified(|| {
+        info!("prewarm_parent_cache:start");
+
+        let pp = public_params::<Tree>(
+            PaddedBytesAmount::from(porep_config),
+            usize::from(PoRepProofPartitions::from(porep_config)),
+            porep_config.porep_id,
+            porep_config.api_version,
+        )?;
+        let cache = pp.graph.parent_cache()?;
+        let bytes = cache.prewarm()?;
+
+        info!(
+            "prewarm_parent_cache:finish: {} bytes of {}",
+            bytes,
+            cache.path.display()
+        );
+        Ok(cache.path)
+    })
+}
+
+/// Loads and prepares the seal verifying keys of `porep_configs` into the in-process verifying
+/// key cache, so that the first `verify_seal` for each of them is as fast as the following ones.
+///
+/// The cache holds at most `verifying_key_cache_size` keys, so this fails without loading any
+/// key if `porep_configs` need more, as the last keys would evict the first ones. Configs which
+/// share a key are only counted and loaded once.
+///
+/// # Arguments
+///
+/// * `porep_configs` - the porep configs of the sectors which are going to be verified.
+pub fn warmup_verifying_keys(porep_configs: &[PoRepConfig]) -> Result<(), FilProofsError> {
+    classified(|| {
+        info!("warmup_verifying_keys:start");
+
+        let mut identifiers = HashSet::new();
+        let mut distinct_configs = Vec::new();
+        for porep_config in porep_configs {
+            porep_config.validate_porep_id()?;
+            let identifier = with_shape!(
+                u64::from(porep_config.sector_size),
+                cache_identifier,
+                porep_config
+            )?;
+            if identifiers.insert(identifier) {
+                distinct_configs.push(*porep_config);
+            }
+        }
+        ensure!(
+            distinct_configs.len() <= SETTINGS.verifying_key_cache_size,
+            "{} verifying keys do not fit into a cache of verifying_key_cache_size={}",
+            distinct_configs.len(),
+            SETTINGS.verifying_key_cache_size
+        );
+
+        for porep_config in distinct_configs {
+            with_shape!(
+                u64::from(porep_config.sector_size),
+                get_stacked_verifying_key,
+                porep_config
+            )?;
+        }
+
+        info!("warmup_verifying_keys:finish: {} keys", identifiers.len());
+        Ok(())
+    })
+}
+
+fn cache_identifier<Tree: 'static + MerkleTreeTrait>(porep_config: &PoRepConfig) -> Result<String> {
+    porep_config.get_cache_identifier::<Tree>()
 }
@@ -0,0 +1,262 @@
+//! Asynchronous adapters for the long running operations of the API.
+//!
+//! Every function runs its synchronous counterpart on a dedicated thread and resolves once that
+//! thread finishes, so callers on an async runtime don't have to wrap the calls in
+//! `spawn_blocking` themselves. The work itself is unchanged, in particular multicore SDR still
+//! binds its own labeling threads to cores.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::thread;
+
+use anyhow::{Context, Result};
+use storage_proofs_core::{merkle::MerkleTreeTrait, sector::SectorId};
+use tokio::sync::oneshot;
+
+use crate::{
+    api::{
+        generate_window_post, generate_winning_post, seal_commit_phase1, seal_commit_phase2,
+        seal_pre_commit_phase1, seal_pre_commit_phase2, verify_seal, verify_window_post,
+        verify_winning_post,
+    },
+    error::FilProofsError,
+    types::{
+        ChallengeSeed, Commitment, PieceInfo, PoRepConfig, PoStConfig, PrivateReplicaInfo,
+        ProverId, PublicReplicaInfo, SealCommitOutput, SealCommitPhase1Output, SealPreCommitOutput,
+        SealPreCommitPhase1Output, SnarkProof, Ticket,
+    },
+};
+
+/// Runs `f` on a new thread named after `name` and returns its result once it is done.
+async fn run_on_thread<F, T>(name: &str, f: F) -> Result<T, FilProofsError>
+where
+    F: FnOnce() -> Result<T, FilProofsError> + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    thread::Builder::new()
+        .name(format!("fil-proofs-{}", name))
+        .spawn(move || {
+            // The receiver is gone if the future was dropped, nobody is left to care.
+            let _ = tx.send(f());
+        })
+        .with_context(|| format!("failed to spawn thread for {}", name))?;
+
+    rx.await
+        .with_context(|| format!("{} panicked before finishing", name))?
+}
+
+/// Asynchronous version of [`seal_pre_commit_phase1`].
+#[allow(clippy::too_many_arguments)]
+pub async fn seal_pre_commit_phase1_async<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: PathBuf,
+    in_path: PathBuf,
+    out_path: PathBuf,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    piece_infos: Vec<PieceInfo>,
+) -> Result<SealPreCommitPhase1Output<Tree>, FilProofsError> {
+    run_on_thread("seal_pre_commit_phase1", move || {
+        seal_pre_commit_phase1::<_, _, _, Tree>(
+            porep_config,
+            cache_path,
+            in_path,
+            out_path,
+            prover_id,
+            sector_id,
+            ticket,
+            &piece_infos,
+        )
+    })
+    .await
+}
+
+/// Asynchronous version of [`seal_pre_commit_phase2`].
+pub async fn seal_pre_commit_phase2_async<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealPreCommitPhase1Output<Tree>,
+    cache_path: PathBuf,
+    replica_path: PathBuf,
+) -> Result<SealPreCommitOutput, FilProofsError> {
+    run_on_thread("seal_pre_commit_phase2", move || {
+        seal_pre_commit_phase2(porep_config, phase1_output, cache_path, replica_path)
+    })
+    .await
+}
+
+/// Asynchronous version of [`seal_commit_phase1`].
+#[allow(clippy::too_many_arguments)]
+pub async fn seal_commit_phase1_async<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: PathBuf,
+    replica_path: PathBuf,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    seed: Ticket,
+    pre_commit: SealPreCommitOutput,
+    piece_infos: Vec<PieceInfo>,
+) -> Result<SealCommitPhase1Output<Tree>, FilProofsError> {
+    run_on_thread("seal_commit_phase1", move || {
+        seal_commit_phase1::<_, Tree>(
+            porep_config,
+            cache_path,
+            replica_path,
+            prover_id,
+            sector_id,
+            ticket,
+            seed,
+            pre_commit,
+            &piece_infos,
+        )
+    })
+    .await
+}
+
+/// Asynchronous version of [`seal_commit_phase2`].
+pub async fn seal_commit_phase2_async<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealCommitPhase1Output<Tree>,
+    prover_id: ProverId,
+    sector_id: SectorId,
+) -> Result<SealCommitOutput, FilProofsError> {
+    run_on_thread("seal_commit_phase2", move || {
+        seal_commit_phase2(porep_config, phase1_output, prover_id, sector_id)
+    })
+    .await
+}
+
+/// Asynchronous version of [`verify_seal`].
+#[allow(clippy::too_many_arguments)]
+pub async fn verify_seal_async<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    comm_r_in: Commitment,
+    comm_d_in: Commitment,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    seed: Ticket,
+    proof_vec: Vec<u8>,
+) -> Result<bool, FilProofsError> {
+    run_on_thread("verify_seal", move || {
+        verify_seal::<Tree>(
+            porep_config,
+            comm_r_in,
+            comm_d_in,
+            prover_id,
+            sector_id,
+            ticket,
+            seed,
+            &proof_vec,
+        )
+    })
+    .await
+}
+
+/// Asynchronous version of [`generate_window_post`].
+pub async fn generate_window_post_async<Tree: 'static + MerkleTreeTrait>(
+    post_config: PoStConfig,
+    randomness: ChallengeSeed,
+    replicas: BTreeMap<SectorId, PrivateReplicaInfo<Tree>>,
+    prover_id: ProverId,
+) -> Result<SnarkProof, FilProofsError> {
+    run_on_thread("generate_window_post", move || {
+        generate_window_post::<Tree>(&post_config, &randomness, &replicas, prover_id)
+    })
+    .await
+}
+
+/// Asynchronous version of [`verify_window_post`].
+pub async fn verify_window_post_async<Tree: 'static + MerkleTreeTrait>(
+    post_config: PoStConfig,
+    randomness: ChallengeSeed,
+    replicas: BTreeMap<SectorId, PublicReplicaInfo>,
+    prover_id: ProverId,
+    proof: Vec<u8>,
+) -> Result<bool, FilProofsError> {
+    run_on_thread("verify_window_post", move || {
+        verify_window_post::<Tree>(&post_config, &randomness, &replicas, prover_id, &proof)
+    })
+    .await
+}
+
+/// Asynchronous version of [`generate_winning_post`].
+pub async fn generate_winning_post_async<Tree: 'static + MerkleTreeTrait>(
+    post_config: PoStConfig,
+    randomness: ChallengeSeed,
+    replicas: Vec<(SectorId, PrivateReplicaInfo<Tree>)>,
+    prover_id: ProverId,
+) -> Result<SnarkProof, FilProofsError> {
+    run_on_thread("generate_winning_post", move || {
+        generate_winning_post::<Tree>(&post_config, &randomness, &replicas, prover_id)
+    })
+    .await
+}
+
+/// Asynchronous version of [`verify_winning_post`].
+pub async fn verify_winning_post_async<Tree: 'static + MerkleTreeTrait>(
+    post_config: PoStConfig,
+    randomness: ChallengeSeed,
+    replicas: Vec<(SectorId, PublicReplicaInfo)>,
+    prover_id: ProverId,
+    proof: Vec<u8>,
+) -> Result<bool, FilProofsError> {
+    run_on_thread("verify_winning_post", move || {
+        verify_winning_post::<Tree>(&post_config, &randomness, &replicas, prover_id, &proof)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+
+    use anyhow::anyhow;
+
+    /// Wakes the thread blocked in [`block_on`].
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Polls `future` on the current thread until it completes, so that the tests need no runtime
+    /// beyond the `sync` feature of tokio.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker: Waker = Arc::new(ThreadWaker(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match Pin::as_mut(&mut future).poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_on_thread() {
+        let value = block_on(run_on_thread("test", || Ok(42))).expect("run_on_thread failed");
+        assert_eq!(value, 42);
+
+        let err = block_on(run_on_thread("test", || -> Result<(), FilProofsError> {
+            Err(anyhow!("expected failure").into())
+        }));
+        assert!(err.is_err());
+
+        let panicked = block_on(run_on_thread("test", || -> Result<(), FilProofsError> {
+            panic!("expected panic");
+        }));
+        assert!(panicked.is_err());
+    }
+}
@@ -1,124 +1,590 @@
 use std::collections::BTreeMap;
-use std::fs;
-use std::path::Path;
-
-use anyhow::{anyhow, ensure, Context, Result};
-use bincode::deserialize;
-use filecoin_hashers::{sha256::Sha256Hasher, Hasher};
-use log::{debug, info};
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use bincode::{deserialize, serialize};
+use filecoin_hashers::{sha256::Sha256Hasher, Domain, Hasher};
+use log::{debug, info, warn};
+use merkletree::hash::Algorithm;
 use storage_proofs_core::{
-    cache_key::CacheKey, merkle::MerkleTreeTrait, proof::ProofScheme, sector::SectorId,
+    cache_key::CacheKey,
+    merkle::{get_base_tree_count, MerkleTreeTrait},
+    proof::ProofScheme,
+    sector::SectorId,
+    util::NODE_SIZE,
 };
+use storage_proofs_porep::stacked::{create_label, finalize_comm_r};
 use storage_proofs_post::fallback::{self, generate_leaf_challenge, FallbackPoSt, SectorProof};
+use typenum::Unsigned;
 
 use crate::{
-    api::as_safe_commitment,
+    api::{as_safe_commitment, commitment_from_fr},
     constants::DefaultPieceHasher,
+    error::{classified, ensure_input, FilProofsError},
     types::{
-        ChallengeSeed, FallbackPoStSectorProof, PoStConfig, PrivateReplicaInfo, ProverId,
-        TemporaryAux, VanillaProof,
+        CacheArtifact, CacheArtifactRole, CacheComparison, CacheDivergence, CacheManifest,
+        CacheRetention, ChallengeSeed, Commitment, FallbackPoStSectorProof, PersistentAux,
+        PoStConfig, PrivateReplicaInfo, ProverId, TemporaryAux, VanillaProof,
     },
     PartitionSnarkProof, PoStType, SnarkProof, SINGLE_PARTITION_PROOF_LEN,
 };
 
 // Ensure that any associated cached data persisted is discarded.
-pub fn clear_cache<Tree: MerkleTreeTrait>(cache_dir: &Path) -> Result<()> {
-    info!("clear_cache:start");
+pub fn clear_cache<Tree: MerkleTreeTrait>(cache_dir: &Path) -> Result<(), FilProofsError> {
+    classified(|| {
+        info!("clear_cache:start");
 
-    let mut t_aux: TemporaryAux<Tree, Sha256Hasher> = {
-        let f_aux_path = cache_dir.to_path_buf().join(CacheKey::TAux.to_string());
-        let aux_bytes = fs::read(&f_aux_path)
-            .with_context(|| format!("could not read from path={:?}", f_aux_path))?;
+        let mut t_aux: TemporaryAux<Tree, Sha256Hasher> = {
+            let f_aux_path = cache_dir.to_path_buf().join(CacheKey::TAux.to_string());
+            let aux_bytes = fs::read(&f_aux_path)
+                .with_context(|| format!("could not read from path={:?}", f_aux_path))?;
 
-        deserialize(&aux_bytes)
-    }?;
+            deserialize(&aux_bytes)
+        }?;
 
-    t_aux.set_cache_path(cache_dir);
-    let result = TemporaryAux::<Tree, DefaultPieceHasher>::clear_temp(t_aux);
+        t_aux.set_cache_path(cache_dir);
+        let result = TemporaryAux::<Tree, DefaultPieceHasher>::clear_temp(t_aux);
 
-    info!("clear_cache:finish");
+        info!("clear_cache:finish");
 
-    result
+        result
+    })
 }
 
 // Ensure that any associated cached data persisted is discarded.
 pub fn clear_caches<Tree: MerkleTreeTrait>(
     replicas: &BTreeMap<SectorId, PrivateReplicaInfo<Tree>>,
-) -> Result<()> {
-    info!("clear_caches:start");
+) -> Result<(), FilProofsError> {
+    classified(|| {
+        info!("clear_caches:start");
+
+        for replica in replicas.values() {
+            clear_cache::<Tree>(replica.cache_dir.as_path())?;
+        }
+
+        info!("clear_caches:finish");
+
+        Ok(())
+    })
+}
+
+/// Deletes the intermediate sealing artifacts kept in `cache_dir` once the sector has been
+/// committed, retaining the files selected by `keep`.
+///
+/// Leftover temporary files from an interrupted labeling or cache compression are removed as well,
+/// including the layers written to `SETTINGS.scratch_dir`, and so is the progress the tree builders
+/// record for pre commit phase 2 to resume from. Calling this again on an already cleaned up cache
+/// directory is a no-op.
+pub fn cleanup_seal_cache<Tree: MerkleTreeTrait>(
+    cache_dir: &Path,
+    keep: CacheRetention,
+) -> Result<(), FilProofsError> {
+    classified(|| {
+        info!("cleanup_seal_cache:start");
+
+        let t_aux_path = cache_dir.join(CacheKey::TAux.to_string());
+        if t_aux_path.exists() {
+            clear_cache::<Tree>(cache_dir)?;
+        }
+
+        for entry in fs::read_dir(cache_dir)
+            .with_context(|| format!("could not read cache dir={:?}", cache_dir))?
+        {
+            let path = entry?.path();
+            if path.is_file()
+                && path
+                    .extension()
+                    .map_or(false, |ext| ext == "tmp" || ext == "progress")
+            {
+                fs::remove_file(&path)
+                    .with_context(|| format!("could not remove path={:?}", path))?;
+            }
+        }
+        create_label::remove_scratch_layers(cache_dir)?;
+
+        if keep == CacheRetention::KeepForPoSt && t_aux_path.exists() {
+            fs::remove_file(&t_aux_path)
+                .with_context(|| format!("could not remove path={:?}", t_aux_path))?;
+        }
+
+        info!("cleanup_seal_cache:finish");
 
-    for replica in replicas.values() {
-        clear_cache::<Tree>(replica.cache_dir.as_path())?;
+        Ok(())
+    })
+}
+
+/// Lists the files in `cache_dir` with their role, e.g. for tools which back up or migrate
+/// sectors.
+///
+/// The roles are derived from the names the sealing steps give to the files, so unknown files are
+/// listed as well, with the role `Unknown`. Subdirectories are skipped. Nothing is read from or
+/// written to the files, so this neither checks that they are complete nor that they belong to the
+/// same sector.
+pub fn describe_cache(cache_dir: &Path) -> Result<CacheManifest, FilProofsError> {
+    classified(|| {
+        info!("describe_cache:start");
+
+        let mut artifacts = Vec::new();
+        for entry in fs::read_dir(cache_dir)
+            .with_context(|| format!("could not read cache dir={:?}", cache_dir))?
+        {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+
+            let role = CacheArtifactRole::from_file_name(&entry.file_name().to_string_lossy());
+            artifacts.push(CacheArtifact {
+                path: entry.path(),
+                role,
+                size: metadata.len(),
+                required_for_post: role.required_for_post(),
+            });
+        }
+        artifacts.sort_by(|a, b| a.path.cmp(&b.path));
+
+        info!("describe_cache:finish");
+
+        Ok(CacheManifest {
+            cache_dir: cache_dir.to_path_buf(),
+            artifacts,
+        })
+    })
+}
+
+/// The number of bytes compared at once by `compare_caches`.
+const COMPARE_CHUNK_BYTES: usize = 1 << 20;
+
+/// Compares the cache directories `cache_dir_a` and `cache_dir_b` of a sealed sector by reading
+/// all of their files, e.g. to confirm that a copy on another host is complete and intact.
+///
+/// The files are compared in the order of their names, and the first difference is reported: a
+/// file missing from one directory, a different size, or the first differing byte. t_aux names
+/// the cache directory it was written to, so it is compared as if both named the same directory.
+/// The comm_r the trees of each directory commit to is reported as well, so that a copy which
+/// only lacks files PoSt does not need is still recognized as the same sector, see
+/// `read_comm_r`.
+pub fn compare_caches<Tree: 'static + MerkleTreeTrait>(
+    cache_dir_a: &Path,
+    cache_dir_b: &Path,
+) -> Result<CacheComparison, FilProofsError> {
+    classified(|| {
+        info!("compare_caches:start");
+
+        let manifest_a = describe_cache(cache_dir_a)?;
+        let manifest_b = describe_cache(cache_dir_b)?;
+        let comm_r_a = read_comm_r::<Tree>(&manifest_a)?;
+        let comm_r_b = read_comm_r::<Tree>(&manifest_b)?;
+
+        let divergence = first_divergence::<Tree>(&manifest_a, &manifest_b)?;
+        if let Some(divergence) = &divergence {
+            info!("caches diverge: {:?}", divergence);
+        }
+
+        info!("compare_caches:finish");
+
+        Ok(CacheComparison {
+            manifest_a,
+            manifest_b,
+            comm_r_a,
+            comm_r_b,
+            divergence,
+        })
+    })
+}
+
+fn artifact_file_name(artifact: &CacheArtifact) -> String {
+    artifact
+        .path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Returns the comm_r the trees of a cache commit to, if it has a p_aux which matches them.
+///
+/// comm_r_last is recomputed from the cached rows of tree_r_last, and comm_c from tree_c if the
+/// cache still has it, as PoSt only needs its root, which p_aux records. A cache whose p_aux or
+/// trees are missing or do not match has no comm_r.
+fn read_comm_r<Tree: 'static + MerkleTreeTrait>(
+    manifest: &CacheManifest,
+) -> Result<Option<Commitment>> {
+    let p_aux_path = match manifest.by_role(CacheArtifactRole::PAux).next() {
+        Some(p_aux) => &p_aux.path,
+        None => return Ok(None),
+    };
+    let p_aux_bytes = fs::read(p_aux_path)
+        .with_context(|| format!("could not read file p_aux={:?}", p_aux_path))?;
+    let p_aux: PersistentAux<<Tree::Hasher as Hasher>::Domain> = deserialize(&p_aux_bytes)?;
+
+    let tree_r_last = base_tree_paths(manifest, |role| match role {
+        CacheArtifactRole::TreeRLast(index) => Some(index),
+        _ => None,
+    });
+    if tree_r_last.is_empty()
+        || !matches_root::<Tree>(
+            &tree_r_last,
+            Tree::Arity::to_usize(),
+            p_aux.comm_r_last,
+            "tree_r_last",
+        )
+    {
+        return Ok(None);
     }
 
-    info!("clear_caches:finish");
+    let tree_c = base_tree_paths(manifest, |role| match role {
+        CacheArtifactRole::TreeC(index) => Some(index),
+        _ => None,
+    });
+    let tree_c_arity = Tree::TreeCArity::to_usize();
+    if !tree_c.is_empty() && !matches_root::<Tree>(&tree_c, tree_c_arity, p_aux.comm_c, "tree_c") {
+        return Ok(None);
+    }
 
-    Ok(())
+    let comm_r = finalize_comm_r::<Tree::Hasher>(&p_aux.comm_c, &p_aux.comm_r_last);
+    Ok(Some(commitment_from_fr(comm_r.into())))
 }
 
-/// Generates the challenges per SectorId required for either a Window
-/// proof-of-spacetime or a Winning proof-of-spacetime.
-pub fn generate_fallback_sector_challenges<Tree: 'static + MerkleTreeTrait>(
-    post_config: &PoStConfig,
-    randomness: &ChallengeSeed,
-    pub_sectors: &[SectorId],
-    _prover_id: ProverId,
-) -> Result<BTreeMap<SectorId, Vec<u64>>> {
-    info!("generate_sector_challenges:start");
+/// Returns whether the tree stored at `paths`, whose base trees have `arity`, has the root
+/// `expected`, logging why not.
+fn matches_root<Tree: 'static + MerkleTreeTrait>(
+    paths: &[PathBuf],
+    arity: usize,
+    expected: <Tree::Hasher as Hasher>::Domain,
+    name: &str,
+) -> bool {
+    match recompute_root::<Tree>(paths, arity) {
+        Ok(root) if root == expected => true,
+        Ok(_) => {
+            warn!("{} in {:?} does not match p_aux", name, paths);
+            false
+        }
+        Err(err) => {
+            warn!(
+                "could not recompute the root of {} in {:?}: {:?}",
+                name, paths, err
+            );
+            false
+        }
+    }
+}
+
+/// Returns the paths of the base trees of the tree `base_tree` selects by their role, in the order
+/// of their index.
+fn base_tree_paths(
+    manifest: &CacheManifest,
+    base_tree: impl Fn(CacheArtifactRole) -> Option<Option<usize>>,
+) -> Vec<PathBuf> {
+    let mut paths: Vec<_> = manifest
+        .artifacts
+        .iter()
+        .filter_map(|artifact| base_tree(artifact.role).map(|index| (index, artifact.path.clone())))
+        .collect();
+    paths.sort();
+
+    paths.into_iter().map(|(_, path)| path).collect()
+}
+
+/// Recomputes the root of the tree of `Tree` whose base trees of `arity` are stored at `paths`.
+///
+/// Every base tree is hashed up from the lowest of its rows the file holds, which are the leaves
+/// of tree_c and the first row above the discarded ones of tree_r_last, and the roots of the base
+/// trees are hashed into the root of the tree with the arities of its sub and top trees.
+fn recompute_root<Tree: 'static + MerkleTreeTrait>(
+    paths: &[PathBuf],
+    arity: usize,
+) -> Result<<Tree::Hasher as Hasher>::Domain> {
+    let base_tree_count = get_base_tree_count::<Tree>();
     ensure!(
-        post_config.typ == PoStType::Window || post_config.typ == PoStType::Winning,
-        "invalid post config type"
+        paths.len() == base_tree_count,
+        "found {} base trees, expected {}",
+        paths.len(),
+        base_tree_count
     );
 
-    let randomness_safe: <Tree::Hasher as Hasher>::Domain =
-        as_safe_commitment(randomness, "randomness")?;
+    let roots = paths
+        .iter()
+        .map(|path| recompute_base_root::<Tree>(path, arity))
+        .collect::<Result<Vec<_>>>()?;
 
-    let public_params = fallback::PublicParams {
-        sector_size: u64::from(post_config.sector_size),
-        challenge_count: post_config.challenge_count,
-        sector_count: post_config.sector_count,
-        api_version: post_config.api_version,
-    };
+    hash_base_roots::<Tree>(roots)
+}
+
+/// Hashes the roots of the base trees of a tree of `Tree` into its root, with the arities of its
+/// sub and top trees.
+pub(crate) fn hash_base_roots<Tree: 'static + MerkleTreeTrait>(
+    mut roots: Vec<<Tree::Hasher as Hasher>::Domain>,
+) -> Result<<Tree::Hasher as Hasher>::Domain> {
+    for arity in &[
+        Tree::SubTreeArity::to_usize(),
+        Tree::TopTreeArity::to_usize(),
+    ] {
+        if *arity > 0 {
+            roots = hash_row::<Tree::Hasher>(&roots, *arity);
+        }
+    }
+    ensure!(roots.len() == 1, "base trees do not hash into one root");
 
-    let mut sector_challenges: BTreeMap<SectorId, Vec<u64>> = BTreeMap::new();
+    Ok(roots[0])
+}
 
-    let num_sectors_per_chunk = post_config.sector_count;
-    let partitions = match post_config.typ {
-        PoStType::Window => {
-            get_partitions_for_window_post(pub_sectors.len(), post_config).unwrap_or(1)
+/// Recomputes the root of the base tree of `arity` stored at `path` from the lowest row the file
+/// holds.
+fn recompute_base_root<Tree: 'static + MerkleTreeTrait>(
+    path: &Path,
+    arity: usize,
+) -> Result<<Tree::Hasher as Hasher>::Domain> {
+    let nodes = (fs::metadata(path)
+        .with_context(|| format!("could not read metadata of {:?}", path))?
+        .len()
+        / NODE_SIZE as u64) as usize;
+
+    // The file holds the rows from the lowest one it keeps up to the root, so the width of the
+    // lowest one is the width at which the rows above it add up to the file.
+    let mut width = 1;
+    let mut stored = 1;
+    while stored < nodes {
+        width *= arity;
+        stored += width;
+    }
+    ensure!(
+        stored == nodes,
+        "{:?} does not hold the rows of a tree of arity {}",
+        path,
+        arity
+    );
+
+    let file = File::open(path).with_context(|| format!("could not open {:?}", path))?;
+    let mut reader = BufReader::with_capacity(COMPARE_CHUNK_BYTES, file);
+    let mut buf = vec![0u8; arity * NODE_SIZE];
+    let mut function = <Tree::Hasher as Hasher>::Function::default();
+    let mut row = Vec::with_capacity(width / arity);
+    if width == 1 {
+        reader
+            .read_exact(&mut buf[..NODE_SIZE])
+            .with_context(|| format!("could not read {:?}", path))?;
+        row.push(<Tree::Hasher as Hasher>::Domain::try_from_bytes(
+            &buf[..NODE_SIZE],
+        )?);
+    }
+    for _ in 0..width / arity {
+        reader
+            .read_exact(&mut buf)
+            .with_context(|| format!("could not read {:?}", path))?;
+        let children = buf
+            .chunks(NODE_SIZE)
+            .map(<Tree::Hasher as Hasher>::Domain::try_from_bytes)
+            .collect::<Result<Vec<_>>>()?;
+        function.reset();
+        row.push(function.multi_node(&children, 0));
+    }
+    while row.len() > 1 {
+        row = hash_row::<Tree::Hasher>(&row, arity);
+    }
+
+    Ok(row[0])
+}
+
+/// Hashes every `arity` nodes of `row` into a node of the row above it.
+pub(crate) fn hash_row<H: Hasher>(row: &[H::Domain], arity: usize) -> Vec<H::Domain> {
+    let mut function = H::Function::default();
+    row.chunks(arity)
+        .map(|nodes| {
+            function.reset();
+            function.multi_node(nodes, 0)
+        })
+        .collect()
+}
+
+fn first_divergence<Tree: 'static + MerkleTreeTrait>(
+    manifest_a: &CacheManifest,
+    manifest_b: &CacheManifest,
+) -> Result<Option<CacheDivergence>> {
+    let files_b: BTreeMap<String, &CacheArtifact> = manifest_b
+        .artifacts
+        .iter()
+        .map(|artifact| (artifact_file_name(artifact), artifact))
+        .collect();
+    let mut files: BTreeMap<String, (Option<&CacheArtifact>, Option<&CacheArtifact>)> = manifest_a
+        .artifacts
+        .iter()
+        .map(|artifact| (artifact_file_name(artifact), (Some(artifact), None)))
+        .collect();
+    for (file_name, artifact) in files_b {
+        files.entry(file_name).or_insert((None, None)).1 = Some(artifact);
+    }
+
+    for (file_name, artifacts) in files {
+        let (a, b) = match artifacts {
+            (Some(a), Some(b)) => (a, b),
+            (a, _) => {
+                return Ok(Some(CacheDivergence::Missing {
+                    file_name,
+                    in_a: a.is_some(),
+                }))
+            }
+        };
+
+        if a.role == CacheArtifactRole::TAux {
+            if normalized_t_aux::<Tree>(&a.path)? != normalized_t_aux::<Tree>(&b.path)? {
+                return Ok(Some(CacheDivergence::TAux));
+            }
+            continue;
         }
-        PoStType::Winning => 1,
+
+        if a.size != b.size {
+            return Ok(Some(CacheDivergence::Size {
+                file_name,
+                size_a: a.size,
+                size_b: b.size,
+            }));
+        }
+        if let Some(offset) = first_difference(&a.path, &b.path)? {
+            return Ok(Some(CacheDivergence::Content { file_name, offset }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Returns the t_aux at `path`, serialized with the cache directory removed from it.
+fn normalized_t_aux<Tree: 'static + MerkleTreeTrait>(path: &Path) -> Result<Vec<u8>> {
+    let t_aux_bytes =
+        fs::read(path).with_context(|| format!("could not read file t_aux={:?}", path))?;
+    let mut t_aux: TemporaryAux<Tree, DefaultPieceHasher> = deserialize(&t_aux_bytes)?;
+    t_aux.set_cache_path("");
+
+    Ok(serialize(&t_aux)?)
+}
+
+/// Returns the offset of the first byte in which the files of equal size at `path_a` and `path_b`
+/// differ.
+fn first_difference(path_a: &Path, path_b: &Path) -> Result<Option<u64>> {
+    let open = |path: &Path| -> Result<_> {
+        let file = File::open(path).with_context(|| format!("could not open {:?}", path))?;
+        Ok(BufReader::with_capacity(COMPARE_CHUNK_BYTES, file))
     };
+    let mut file_a = open(path_a)?;
+    let mut file_b = open(path_b)?;
+    let mut chunk_a = vec![0u8; COMPARE_CHUNK_BYTES];
+    let mut chunk_b = vec![0u8; COMPARE_CHUNK_BYTES];
+
+    let mut offset = 0u64;
+    loop {
+        let len = read_chunk(&mut file_a, &mut chunk_a)
+            .with_context(|| format!("could not read {:?}", path_a))?;
+        let len_b = read_chunk(&mut file_b, &mut chunk_b)
+            .with_context(|| format!("could not read {:?}", path_b))?;
+        if let Some(i) = chunk_a[..len.min(len_b)]
+            .iter()
+            .zip(&chunk_b[..len.min(len_b)])
+            .position(|(a, b)| a != b)
+        {
+            return Ok(Some(offset + i as u64));
+        }
+        if len != len_b {
+            // The file changed size while it was read.
+            return Ok(Some(offset + len.min(len_b) as u64));
+        }
+        if len == 0 {
+            return Ok(None);
+        }
+        offset += len as u64;
+    }
+}
 
-    for partition_index in 0..partitions {
-        let sectors = pub_sectors
-            .chunks(num_sectors_per_chunk)
-            .nth(partition_index)
-            .ok_or_else(|| anyhow!("invalid number of sectors/partition index"))?;
-
-        for (i, sector) in sectors.iter().enumerate() {
-            let mut challenges = Vec::new();
-
-            for n in 0..post_config.challenge_count {
-                let challenge_index = ((partition_index * post_config.sector_count + i)
-                    * post_config.challenge_count
-                    + n) as u64;
-                let challenged_leaf = generate_leaf_challenge(
-                    &public_params,
-                    randomness_safe,
-                    u64::from(*sector),
-                    challenge_index,
-                );
-                challenges.push(challenged_leaf);
+/// Fills `buf` unless the end of `reader` is reached first, and returns the number of bytes read.
+fn read_chunk<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    Ok(len)
+}
+
+/// Generates the challenges per SectorId required for either a Window
+/// proof-of-spacetime or a Winning proof-of-spacetime.
+///
+/// The challenges of a sector depend on its position among the proven sectors. For a Window PoSt,
+/// `pub_sectors` may be passed in any order, the challenges are derived with the sectors sorted by
+/// ascending sector id, the order in which they are proven and verified.
+pub fn generate_fallback_sector_challenges<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    pub_sectors: &[SectorId],
+    _prover_id: ProverId,
+) -> Result<BTreeMap<SectorId, Vec<u64>>, FilProofsError> {
+    classified(|| {
+        info!("generate_sector_challenges:start");
+        ensure_input!(
+            post_config.typ == PoStType::Window || post_config.typ == PoStType::Winning,
+            "invalid post config type"
+        );
+
+        let randomness_safe: <Tree::Hasher as Hasher>::Domain =
+            as_safe_commitment(randomness, "randomness")?;
+
+        let public_params = fallback::PublicParams {
+            sector_size: u64::from(post_config.sector_size),
+            challenge_count: post_config.challenge_count,
+            sector_count: post_config.sector_count,
+            api_version: post_config.api_version,
+        };
+
+        let mut pub_sectors = pub_sectors.to_vec();
+        if post_config.typ == PoStType::Window {
+            pub_sectors.sort();
+            if let Some(pair) = pub_sectors.windows(2).find(|pair| pair[0] == pair[1]) {
+                bail!("duplicate sector {:?}", pair[0]);
             }
+        }
 
-            sector_challenges.insert(*sector, challenges);
+        let mut sector_challenges: BTreeMap<SectorId, Vec<u64>> = BTreeMap::new();
+
+        let num_sectors_per_chunk = post_config.sector_count;
+        let partitions = match post_config.typ {
+            PoStType::Window => {
+                get_partitions_for_window_post(pub_sectors.len(), post_config).unwrap_or(1)
+            }
+            PoStType::Winning => 1,
+        };
+
+        for partition_index in 0..partitions {
+            let sectors = pub_sectors
+                .chunks(num_sectors_per_chunk)
+                .nth(partition_index)
+                .ok_or_else(|| anyhow!("invalid number of sectors/partition index"))?;
+
+            for (i, sector) in sectors.iter().enumerate() {
+                let mut challenges = Vec::new();
+
+                for n in 0..post_config.challenge_count {
+                    let challenge_index = ((partition_index * post_config.sector_count + i)
+                        * post_config.challenge_count
+                        + n) as u64;
+                    let challenged_leaf = generate_leaf_challenge(
+                        &public_params,
+                        randomness_safe,
+                        u64::from(*sector),
+                        challenge_index,
+                    );
+                    challenges.push(challenged_leaf);
+                }
+
+                sector_challenges.insert(*sector, challenges);
+            }
         }
-    }
 
-    info!("generate_sector_challenges:finish");
+        info!("generate_sector_challenges:finish");
 
-    Ok(sector_challenges)
+        Ok(sector_challenges)
+    })
 }
 
 /// Generates a single vanilla proof required for either Window proof-of-spacetime
@@ -128,50 +594,53 @@ pub fn generate_single_vanilla_proof<Tree: 'static + MerkleTreeTrait>(
     sector_id: SectorId,
     replica: &PrivateReplicaInfo<Tree>,
     challenges: &[u64],
-) -> Result<FallbackPoStSectorProof<Tree>> {
-    info!("generate_single_vanilla_proof:start: {:?}", sector_id);
-
-    let tree = &replica
-        .merkle_tree(post_config.sector_size)
-        .with_context(|| {
-            format!(
-                "generate_single_vanilla_proof: merkle_tree failed: {:?}",
-                sector_id
-            )
-        })?;
-    let comm_r = replica.safe_comm_r().with_context(|| {
-        format!(
-            "generate_single_vanilla_poof: safe_comm_r failed: {:?}",
-            sector_id
-        )
-    })?;
-    let comm_c = replica.safe_comm_c();
-    let comm_r_last = replica.safe_comm_r_last();
-
-    let priv_sectors = vec![fallback::PrivateSector {
-        tree,
-        comm_c,
-        comm_r_last,
-    }];
-
-    let priv_inputs = fallback::PrivateInputs::<Tree> {
-        sectors: &priv_sectors,
-    };
-
-    let vanilla_proof =
-        fallback::vanilla_proof(sector_id, &priv_inputs, challenges).with_context(|| {
+) -> Result<FallbackPoStSectorProof<Tree>, FilProofsError> {
+    classified(|| {
+        info!("generate_single_vanilla_proof:start: {:?}", sector_id);
+
+        let tree = &replica
+            .merkle_tree(post_config.sector_size)
+            .with_context(|| {
+                format!(
+                    "generate_single_vanilla_proof: merkle_tree failed: {:?}",
+                    sector_id
+                )
+            })?;
+        let comm_r = replica.safe_comm_r().with_context(|| {
             format!(
-                "generate_single_vanilla_proof: vanilla_proof failed: {:?}",
+                "generate_single_vanilla_poof: safe_comm_r failed: {:?}",
                 sector_id
             )
         })?;
-
-    info!("generate_single_vanilla_proof:finish: {:?}", sector_id);
-
-    Ok(FallbackPoStSectorProof {
-        sector_id,
-        comm_r,
-        vanilla_proof,
+        let comm_c = replica.safe_comm_c();
+        let comm_r_last = replica.safe_comm_r_last();
+
+        let priv_sectors = vec![fallback::PrivateSector {
+            tree,
+            comm_c,
+            comm_r_last,
+            rows_to_discard: Some(replica.rows_to_discard(post_config.sector_size)?),
+        }];
+
+        let priv_inputs = fallback::PrivateInputs::<Tree> {
+            sectors: &priv_sectors,
+        };
+
+        let vanilla_proof = fallback::vanilla_proof(sector_id, &priv_inputs, challenges)
+            .with_context(|| {
+                format!(
+                    "generate_single_vanilla_proof: vanilla_proof failed: {:?}",
+                    sector_id
+                )
+            })?;
+
+        info!("generate_single_vanilla_proof:finish: {:?}", sector_id);
+
+        Ok(FallbackPoStSectorProof {
+            sector_id,
+            comm_r,
+            vanilla_proof,
+        })
     })
 }
 
@@ -184,66 +653,73 @@ pub fn partition_vanilla_proofs<Tree: MerkleTreeTrait>(
     pub_inputs: &fallback::PublicInputs<<Tree::Hasher as Hasher>::Domain>,
     partition_count: usize,
     vanilla_proofs: &[FallbackPoStSectorProof<Tree>],
-) -> Result<Vec<VanillaProof<Tree>>> {
-    info!("partition_vanilla_proofs:start");
-    ensure!(
-        post_config.typ == PoStType::Window || post_config.typ == PoStType::Winning,
-        "invalid post config type"
-    );
-
-    let num_sectors_per_chunk = pub_params.sector_count;
-    let num_sectors = pub_inputs.sectors.len();
-
-    ensure!(
-        num_sectors <= partition_count * num_sectors_per_chunk,
-        "cannot prove the provided number of sectors: {} > {} * {}",
-        num_sectors,
-        partition_count,
-        num_sectors_per_chunk,
-    );
-
-    let mut partition_proofs = Vec::new();
-
-    // Note that the partition proofs returned are shaped differently
-    // based on which type of PoSt is being considered.
-    match post_config.typ {
-        PoStType::Window => {
-            for (j, sectors_chunk) in pub_inputs.sectors.chunks(num_sectors_per_chunk).enumerate() {
-                let proof = single_partition_vanilla_proofs(
-                    post_config,
-                    pub_params,
-                    &fallback::PublicInputs {
-                        randomness: pub_inputs.randomness,
-                        prover_id: pub_inputs.prover_id,
-                        sectors: sectors_chunk.to_vec(),
-                        k: Some(j),
-                    },
-                    vanilla_proofs,
-                )?;
-                partition_proofs.push(proof);
+) -> Result<Vec<VanillaProof<Tree>>, FilProofsError> {
+    classified(|| {
+        info!("partition_vanilla_proofs:start");
+        ensure_input!(
+            post_config.typ == PoStType::Window || post_config.typ == PoStType::Winning,
+            "invalid post config type"
+        );
+
+        let num_sectors_per_chunk = pub_params.sector_count;
+        let num_sectors = pub_inputs.sectors.len();
+
+        ensure_input!(
+            num_sectors <= partition_count * num_sectors_per_chunk,
+            "cannot prove the provided number of sectors: {} > {} * {}",
+            num_sectors,
+            partition_count,
+            num_sectors_per_chunk,
+        );
+
+        let mut partition_proofs = Vec::new();
+
+        // Note that the partition proofs returned are shaped differently
+        // based on which type of PoSt is being considered.
+        match post_config.typ {
+            PoStType::Window => {
+                for (j, sectors_chunk) in
+                    pub_inputs.sectors.chunks(num_sectors_per_chunk).enumerate()
+                {
+                    let proof = single_partition_vanilla_proofs(
+                        post_config,
+                        pub_params,
+                        &fallback::PublicInputs {
+                            randomness: pub_inputs.randomness,
+                            prover_id: pub_inputs.prover_id,
+                            sectors: sectors_chunk.to_vec(),
+                            k: Some(j),
+                        },
+                        vanilla_proofs,
+                    )?;
+                    partition_proofs.push(proof);
+                }
             }
-        }
-        PoStType::Winning => {
-            for (j, sectors_chunk) in vanilla_proofs.chunks(num_sectors_per_chunk).enumerate() {
-                let proof = single_partition_vanilla_proofs(
-                    post_config,
-                    pub_params,
-                    &fallback::FallbackPoSt::<Tree>::with_partition(pub_inputs.clone(), Some(j)),
-                    sectors_chunk,
-                )?;
-                partition_proofs.push(proof);
+            PoStType::Winning => {
+                for (j, sectors_chunk) in vanilla_proofs.chunks(num_sectors_per_chunk).enumerate() {
+                    let proof = single_partition_vanilla_proofs(
+                        post_config,
+                        pub_params,
+                        &fallback::FallbackPoSt::<Tree>::with_partition(
+                            pub_inputs.clone(),
+                            Some(j),
+                        ),
+                        sectors_chunk,
+                    )?;
+                    partition_proofs.push(proof);
+                }
             }
         }
-    }
 
-    info!("partition_vanilla_proofs:finish");
+        info!("partition_vanilla_proofs:finish");
 
-    ensure!(
-        FallbackPoSt::<Tree>::verify_all_partitions(pub_params, pub_inputs, &partition_proofs)?,
-        "partitioned vanilla proofs failed to verify"
-    );
+        ensure!(
+            FallbackPoSt::<Tree>::verify_all_partitions(pub_params, pub_inputs, &partition_proofs)?,
+            "partitioned vanilla proofs failed to verify"
+        );
 
-    Ok(partition_proofs)
+        Ok(partition_proofs)
+    })
 }
 
 pub(crate) fn get_partitions_for_window_post(
@@ -264,122 +740,126 @@ pub fn single_partition_vanilla_proofs<Tree: MerkleTreeTrait>(
     pub_params: &fallback::PublicParams,
     pub_inputs: &fallback::PublicInputs<<Tree::Hasher as Hasher>::Domain>,
     vanilla_proofs: &[FallbackPoStSectorProof<Tree>],
-) -> Result<VanillaProof<Tree>> {
-    info!("single_partition_vanilla_proofs:start");
-    ensure!(pub_inputs.k.is_some(), "must have a partition index");
-    let partition_index = pub_inputs.k.expect("prechecked");
-
-    debug!("processing partition: {}", partition_index);
-    ensure!(
-        post_config.typ == PoStType::Window || post_config.typ == PoStType::Winning,
-        "invalid post config type"
-    );
-
-    let num_sectors_per_chunk = pub_params.sector_count;
-    let num_sectors = pub_inputs.sectors.len();
-    ensure!(
-        num_sectors <= num_sectors_per_chunk,
-        "can only prove a single partition"
-    );
-
-    // Note that the partition proofs returned are shaped differently
-    // based on which type of PoSt is being considered.
-    let partition_proof = match post_config.typ {
-        PoStType::Window => {
-            let sectors_chunk = &pub_inputs.sectors;
-
-            let mut sector_proofs = Vec::with_capacity(num_sectors_per_chunk);
-
-            for pub_sector in sectors_chunk.iter() {
-                let cur_proof = vanilla_proofs
-                    .iter()
-                    .find(|&proof| proof.sector_id == pub_sector.id)
-                    .expect("failed to locate sector proof");
-
-                // Note: Window post requires all inclusion proofs (based on the challenge
-                // count per sector) per sector proof.
-                sector_proofs.extend(cur_proof.vanilla_proof.sectors.clone());
-            }
-
-            // If there were less than the required number of sectors provided, we duplicate the last one
-            // to pad the proof out, such that it works in the circuit part.
-            while sector_proofs.len() < num_sectors_per_chunk {
-                sector_proofs.push(sector_proofs[sector_proofs.len() - 1].clone());
-            }
-
-            fallback::Proof::<<Tree as MerkleTreeTrait>::Proof> {
-                sectors: sector_proofs,
-            }
-        }
-        PoStType::Winning => {
-            let sectors_chunk = vanilla_proofs;
-            // Sanity check incoming structure
-            ensure!(
-                sectors_chunk.len() == 1,
-                "Invalid sector chunk for Winning PoSt"
-            );
-            ensure!(
-                sectors_chunk[0].vanilla_proof.sectors.len() == 1,
-                "Invalid sector count for Winning PoSt chunk"
-            );
-
-            // Winning post sector_count is winning post challenges per sector
-            ensure!(
-                post_config.sector_count
-                    == sectors_chunk[partition_index].vanilla_proof.sectors.len(),
-                "invalid number of sector proofs for Winning PoSt"
-            );
-
-            let mut sector_proofs = Vec::with_capacity(post_config.challenge_count);
-            let cur_sector_proof = &sectors_chunk[0].vanilla_proof.sectors[0];
-
-            // Unroll inclusions proofs from the single provided sector_proof (per partition)
-            // into individual sector proofs, required for winning post.
-            for cur_inclusion_proof in cur_sector_proof.inclusion_proofs() {
-                sector_proofs.push(SectorProof {
-                    inclusion_proofs: vec![cur_inclusion_proof.clone()],
-                    comm_c: cur_sector_proof.comm_c,
-                    comm_r_last: cur_sector_proof.comm_r_last,
-                });
+) -> Result<VanillaProof<Tree>, FilProofsError> {
+    classified(|| {
+        info!("single_partition_vanilla_proofs:start");
+        ensure_input!(pub_inputs.k.is_some(), "must have a partition index");
+        let partition_index = pub_inputs.k.expect("prechecked");
+
+        debug!("processing partition: {}", partition_index);
+        ensure_input!(
+            post_config.typ == PoStType::Window || post_config.typ == PoStType::Winning,
+            "invalid post config type"
+        );
+
+        let num_sectors_per_chunk = pub_params.sector_count;
+        let num_sectors = pub_inputs.sectors.len();
+        ensure_input!(
+            num_sectors <= num_sectors_per_chunk,
+            "can only prove a single partition"
+        );
+
+        // Note that the partition proofs returned are shaped differently
+        // based on which type of PoSt is being considered.
+        let partition_proof = match post_config.typ {
+            PoStType::Window => {
+                let sectors_chunk = &pub_inputs.sectors;
+
+                let mut sector_proofs = Vec::with_capacity(num_sectors_per_chunk);
+
+                for pub_sector in sectors_chunk.iter() {
+                    let cur_proof = vanilla_proofs
+                        .iter()
+                        .find(|&proof| proof.sector_id == pub_sector.id)
+                        .expect("failed to locate sector proof");
+
+                    // Note: Window post requires all inclusion proofs (based on the challenge
+                    // count per sector) per sector proof.
+                    sector_proofs.extend(cur_proof.vanilla_proof.sectors.clone());
+                }
+
+                // If there were less than the required number of sectors provided, we duplicate the last one
+                // to pad the proof out, such that it works in the circuit part.
+                while sector_proofs.len() < num_sectors_per_chunk {
+                    sector_proofs.push(sector_proofs[sector_proofs.len() - 1].clone());
+                }
+
+                fallback::Proof::<<Tree as MerkleTreeTrait>::Proof> {
+                    sectors: sector_proofs,
+                }
             }
+            PoStType::Winning => {
+                let sectors_chunk = vanilla_proofs;
+                // Sanity check incoming structure
+                ensure_input!(
+                    sectors_chunk.len() == 1,
+                    "Invalid sector chunk for Winning PoSt"
+                );
+                ensure_input!(
+                    sectors_chunk[0].vanilla_proof.sectors.len() == 1,
+                    "Invalid sector count for Winning PoSt chunk"
+                );
 
-            // If there were less than the required number of sectors provided, we duplicate the last one
-            // to pad the proof out, such that it works in the circuit part.
-            while sector_proofs.len() < num_sectors_per_chunk {
-                sector_proofs.push(sector_proofs[sector_proofs.len() - 1].clone());
-            }
+                // Winning post sector_count is winning post challenges per sector
+                ensure_input!(
+                    post_config.sector_count
+                        == sectors_chunk[partition_index].vanilla_proof.sectors.len(),
+                    "invalid number of sector proofs for Winning PoSt"
+                );
 
-            // Winning post Challenge count is the total winning post challenges
-            ensure!(
-                sector_proofs.len() == post_config.challenge_count,
-                "invalid number of partition proofs based on Winning PoSt challenges"
-            );
+                let mut sector_proofs = Vec::with_capacity(post_config.challenge_count);
+                let cur_sector_proof = &sectors_chunk[0].vanilla_proof.sectors[0];
+
+                // Unroll inclusions proofs from the single provided sector_proof (per partition)
+                // into individual sector proofs, required for winning post.
+                for cur_inclusion_proof in cur_sector_proof.inclusion_proofs() {
+                    sector_proofs.push(SectorProof {
+                        inclusion_proofs: vec![cur_inclusion_proof.clone()],
+                        comm_c: cur_sector_proof.comm_c,
+                        comm_r_last: cur_sector_proof.comm_r_last,
+                    });
+                }
+
+                // If there were less than the required number of sectors provided, we duplicate the last one
+                // to pad the proof out, such that it works in the circuit part.
+                while sector_proofs.len() < num_sectors_per_chunk {
+                    sector_proofs.push(sector_proofs[sector_proofs.len() - 1].clone());
+                }
+
+                // Winning post Challenge count is the total winning post challenges
+                ensure_input!(
+                    sector_proofs.len() == post_config.challenge_count,
+                    "invalid number of partition proofs based on Winning PoSt challenges"
+                );
 
-            fallback::Proof::<<Tree as MerkleTreeTrait>::Proof> {
-                sectors: sector_proofs,
+                fallback::Proof::<<Tree as MerkleTreeTrait>::Proof> {
+                    sectors: sector_proofs,
+                }
             }
-        }
-    };
+        };
 
-    info!("single_partition_vanilla_proofs:finish");
+        info!("single_partition_vanilla_proofs:finish");
 
-    ensure!(
-        FallbackPoSt::<Tree>::verify(pub_params, pub_inputs, &partition_proof)?,
-        "partitioned vanilla proofs failed to verify"
-    );
+        ensure!(
+            FallbackPoSt::<Tree>::verify(pub_params, pub_inputs, &partition_proof)?,
+            "partitioned vanilla proofs failed to verify"
+        );
 
-    Ok(partition_proof)
+        Ok(partition_proof)
+    })
 }
 
 pub fn merge_window_post_partition_proofs(
     mut proofs: Vec<PartitionSnarkProof>,
-) -> Result<SnarkProof> {
-    let mut proof = Vec::with_capacity(proofs.len() * SINGLE_PARTITION_PROOF_LEN);
-    for p in proofs.iter_mut() {
-        proof.append(&mut p.0);
-    }
+) -> Result<SnarkProof, FilProofsError> {
+    classified(|| {
+        let mut proof = Vec::with_capacity(proofs.len() * SINGLE_PARTITION_PROOF_LEN);
+        for p in proofs.iter_mut() {
+            proof.append(&mut p.0);
+        }
 
-    Ok(proof)
+        Ok(proof)
+    })
 }
 
 pub fn get_num_partition_for_fallback_post(config: &PoStConfig, num_sectors: usize) -> usize {
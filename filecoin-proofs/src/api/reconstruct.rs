@@ -0,0 +1,362 @@
+use std::fs;
+use std::marker::PhantomData;
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use bincode::deserialize;
+use filecoin_hashers::Hasher;
+use log::{info, warn};
+use merkletree::{merkle::get_merkle_tree_len, store::StoreConfig};
+use storage_proofs_core::{
+    cache_key::CacheKey,
+    merkle::{
+        create_disk_tree, create_lc_tree, get_base_tree_count, split_config,
+        split_config_and_replica, DiskTree, LCTree, MerkleTreeTrait,
+    },
+    util::{default_rows_to_discard, retry_transient_io, NODE_SIZE},
+};
+use storage_proofs_porep::stacked::{
+    finalize_comm_r, GpuOptions, Labels, PersistentAux, StackedDrg, TemporaryAux,
+};
+use typenum::Unsigned;
+
+use crate::{
+    api::{
+        as_safe_commitment, commitment_from_fr, ensure_replica_len, get_base_tree_leafs,
+        get_tree_r_last_rows_to_discard, persist_aux, verify_level_cache_store, verify_store,
+    },
+    constants::{DefaultPieceHasher, LAYERS},
+    error::{classified, FilProofsError},
+    types::{Commitment, SectorSize, BINARY_ARITY},
+};
+
+/// Rebuilds p_aux and t_aux of a sealed sector from the trees in its cache, and writes them to
+/// `cache_path`, e.g. after they were deleted by accident.
+///
+/// t_aux only records where and how the layers and trees of the sector are stored, so it is
+/// recomputed from `sector_size` and `cache_path` alone, exactly as `seal_pre_commit_phase2`
+/// writes it. The rows_to_discard of tree_c and tree_r_last, which
+/// `seal_pre_commit_phase2_with_rows_to_discard` may have overridden, are taken from the old t_aux
+/// if it can still be read, and are inferred from the length of the stored tree_r_last otherwise.
+/// p_aux holds the
+/// roots of tree_c and tree_r_last, so both trees have to be present: tree_c in the cache, and
+/// tree_r_last in the cache with its leaves read from the replica. The roots are checked against
+/// `comm_r` before anything is written.
+///
+/// This cannot recover a sector whose tree_c or tree_r_last is missing as well. Such a sector can
+/// only be recovered by rebuilding the trees from its labels, which `clear_cache` removes, or by
+/// sealing it again. The layers and tree_d are neither needed nor checked, so a recovered t_aux
+/// only allows `seal_commit_phase1` if they are still in the cache; PoSt only needs p_aux.
+///
+/// # Arguments
+///
+/// * `sector_size` - the size of the sector, which has to match the shape `Tree`.
+/// * `cache_path` - path to the sector's cache directory.
+/// * `replica_path` - path to the sealed replica.
+/// * `comm_r` - commitment to the sector's replica (`comm_r`).
+pub fn reconstruct_aux<Tree: 'static + MerkleTreeTrait>(
+    sector_size: SectorSize,
+    cache_path: &Path,
+    replica_path: &Path,
+    comm_r: Commitment,
+) -> Result<
+    (
+        PersistentAux<<Tree::Hasher as Hasher>::Domain>,
+        TemporaryAux<Tree, DefaultPieceHasher>,
+    ),
+    FilProofsError,
+> {
+    classified(|| {
+        info!("reconstruct_aux:start: {:?}", cache_path);
+
+        let comm_r_safe: <Tree::Hasher as Hasher>::Domain = as_safe_commitment(&comm_r, "comm_r")?;
+        ensure_replica_len(replica_path, sector_size)?;
+
+        let t_aux = temporary_aux::<Tree>(sector_size, cache_path)?;
+        let tree_count = get_base_tree_count::<Tree>();
+        let tree_c_arity = Tree::TreeCArity::to_usize();
+
+        verify_store(&t_aux.tree_c_config, tree_c_arity, tree_count)
+            .context("tree_c is missing, comm_c cannot be recovered")?;
+        verify_level_cache_store::<Tree>(&t_aux.tree_r_last_config)
+            .context("tree_r_last is missing, comm_r_last cannot be recovered")?;
+
+        let tree_c_size = t_aux
+            .tree_c_config
+            .size
+            .context("tree_c config has no size")?;
+        let configs = split_config(t_aux.tree_c_config.clone(), tree_count)?;
+        let tree_c = retry_transient_io(|| {
+            create_disk_tree::<
+                DiskTree<Tree::Hasher, Tree::TreeCArity, Tree::SubTreeArity, Tree::TopTreeArity>,
+            >(tree_c_size, &configs)
+        })
+        .context("failed to open tree_c")?;
+
+        let tree_r_last_size = t_aux
+            .tree_r_last_config
+            .size
+            .context("tree_r_last config has no size")?;
+        let (configs, replica_config) = split_config_and_replica(
+            t_aux.tree_r_last_config.clone(),
+            replica_path.to_path_buf(),
+            get_base_tree_leafs::<Tree>(tree_r_last_size)?,
+            tree_count,
+        )?;
+        let tree_r_last = retry_transient_io(|| {
+            create_lc_tree::<
+                LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>,
+            >(tree_r_last_size, &configs, &replica_config)
+        })
+        .context("failed to open tree_r_last")?;
+
+        let p_aux = PersistentAux {
+            comm_c: tree_c.root(),
+            comm_r_last: tree_r_last.root(),
+        };
+        ensure!(
+            finalize_comm_r::<Tree::Hasher>(&p_aux.comm_c, &p_aux.comm_r_last) == comm_r_safe,
+            "comm_r does not match the trees in the sector cache"
+        );
+
+        persist_aux(cache_path, &p_aux, &t_aux)?;
+
+        info!("reconstruct_aux:finish: {:?}", cache_path);
+
+        Ok((p_aux, t_aux))
+    })
+}
+
+/// Returns the t_aux which `seal_pre_commit_phase2` writes for a sector of `sector_size` in
+/// `cache_path`. The trees are assumed to discard as many rows as the stored tree_r_last does.
+fn temporary_aux<Tree: 'static + MerkleTreeTrait>(
+    sector_size: SectorSize,
+    cache_path: &Path,
+) -> Result<TemporaryAux<Tree, DefaultPieceHasher>> {
+    let layers = *LAYERS
+        .read()
+        .expect("LAYERS poisoned")
+        .get(&u64::from(sector_size))
+        .with_context(|| format!("unknown sector size {}", u64::from(sector_size)))?;
+
+    let nodes = u64::from(sector_size) as usize / NODE_SIZE;
+    let tree_count = get_base_tree_count::<Tree>();
+    ensure!(
+        nodes % tree_count == 0,
+        "sector size {} does not match the tree shape",
+        u64::from(sector_size)
+    );
+    let base_nodes = nodes / tree_count;
+    let arity = Tree::Arity::to_usize();
+    let tree_c_arity = Tree::TreeCArity::to_usize();
+
+    // The layers inherit the rows_to_discard of the tree_d config of phase 1.
+    let label_config = StoreConfig::new(
+        cache_path,
+        CacheKey::CommDTree.to_string(),
+        default_rows_to_discard(nodes, BINARY_ARITY),
+    );
+    let labels = Labels::new(
+        (1..=layers)
+            .map(|layer| {
+                StoreConfig::from_config(&label_config, CacheKey::label_layer(layer), Some(nodes))
+            })
+            .collect(),
+    );
+
+    let mut tree_d_config = StoreConfig::from_config(
+        &label_config,
+        CacheKey::CommDTree.to_string(),
+        Some(get_merkle_tree_len(nodes, BINARY_ARITY)?),
+    );
+    tree_d_config.rows_to_discard = default_rows_to_discard(base_nodes, BINARY_ARITY);
+
+    let mut tree_r_last_config = StoreConfig::from_config(
+        &label_config,
+        CacheKey::CommRLastTree.to_string(),
+        Some(get_merkle_tree_len(base_nodes, arity)?),
+    );
+    let rows_to_discard = get_tree_r_last_rows_to_discard::<Tree>(cache_path, base_nodes)?;
+    tree_r_last_config.rows_to_discard = rows_to_discard;
+
+    let mut tree_c_config = StoreConfig::from_config(
+        &label_config,
+        CacheKey::CommCTree.to_string(),
+        Some(get_merkle_tree_len(base_nodes, tree_c_arity)?),
+    );
+    tree_c_config.rows_to_discard = rows_to_discard;
+
+    Ok(TemporaryAux {
+        labels,
+        tree_d_config,
+        tree_r_last_config,
+        tree_c_config,
+        _g: PhantomData,
+    })
+}
+
+/// The directory in a sector's cache in which `rebuild_tree_c` and `rebuild_tree_r_last` build
+/// a tree, before it replaces the one in the cache.
+const REBUILD_DIR: &str = "rebuild.tmp";
+
+/// Builds tree_c of a sealed sector again from the layers in its cache, and replaces the tree_c
+/// in `cache_path` with it, e.g. if only tree_c is corrupted.
+///
+/// The layers, p_aux and t_aux have to be in the cache, so this is only possible before
+/// `clear_cache` is called, and only if the layers were not consolidated. The tree is built in a
+/// directory in the cache first, and only replaces the existing tree_c if its root matches
+/// `comm_c` of p_aux, i.e. if the `comm_r` it results in is the one the sector was sealed with.
+///
+/// Returns that `comm_r`.
+///
+/// # Arguments
+///
+/// * `cache_path` - path to the sector's cache directory.
+/// * `sector_size` - the size of the sector, which has to match the shape `Tree`.
+pub fn rebuild_tree_c<Tree: 'static + MerkleTreeTrait>(
+    cache_path: &Path,
+    sector_size: SectorSize,
+) -> Result<Commitment, FilProofsError> {
+    classified(|| {
+        info!("rebuild_tree_c:start: {:?}", cache_path);
+
+        let (p_aux, t_aux) = read_aux_configs::<Tree>(cache_path, sector_size)?;
+        replace_tree(cache_path, "tree_c", p_aux.comm_c, |rebuild_dir| {
+            let mut tree_c_config = t_aux.tree_c_config.clone();
+            tree_c_config.path = rebuild_dir.to_path_buf();
+            StackedDrg::<Tree, DefaultPieceHasher>::rebuild_tree_c(
+                &t_aux.labels,
+                tree_c_config,
+                &GpuOptions::default(),
+            )
+            .context("tree_c cannot be rebuilt without the layers")
+        })?;
+        let comm_r = finalize_comm_r::<Tree::Hasher>(&p_aux.comm_c, &p_aux.comm_r_last);
+
+        info!("rebuild_tree_c:finish: {:?}", cache_path);
+
+        Ok(commitment_from_fr(comm_r.into()))
+    })
+}
+
+/// Builds tree_r_last of a sealed sector again from its replica, and replaces the tree_r_last in
+/// `cache_path` with it, e.g. if only tree_r_last is corrupted.
+///
+/// Only p_aux and t_aux have to be in the cache, as the leaves of tree_r_last are the nodes of
+/// the replica, so this also works after `clear_cache`. Like `rebuild_tree_c`, the existing
+/// tree_r_last is only replaced if the root of the new one matches `comm_r_last` of p_aux, which
+/// fails if the replica itself is corrupted; see `repair_replica_node` for that.
+///
+/// Returns the `comm_r` of the sector.
+///
+/// # Arguments
+///
+/// * `cache_path` - path to the sector's cache directory.
+/// * `replica_path` - path to the sealed replica.
+/// * `sector_size` - the size of the sector, which has to match the shape `Tree`.
+pub fn rebuild_tree_r_last<Tree: 'static + MerkleTreeTrait>(
+    cache_path: &Path,
+    replica_path: &Path,
+    sector_size: SectorSize,
+) -> Result<Commitment, FilProofsError> {
+    classified(|| {
+        info!("rebuild_tree_r_last:start: {:?}", cache_path);
+
+        ensure_replica_len(replica_path, sector_size)?;
+        let (p_aux, t_aux) = read_aux_configs::<Tree>(cache_path, sector_size)?;
+        replace_tree(
+            cache_path,
+            "tree_r_last",
+            p_aux.comm_r_last,
+            |rebuild_dir| {
+                let mut tree_r_last_config = t_aux.tree_r_last_config.clone();
+                tree_r_last_config.path = rebuild_dir.to_path_buf();
+                StackedDrg::<Tree, DefaultPieceHasher>::rebuild_tree_r_last(
+                    tree_r_last_config,
+                    replica_path,
+                )
+            },
+        )?;
+        let comm_r = finalize_comm_r::<Tree::Hasher>(&p_aux.comm_c, &p_aux.comm_r_last);
+
+        info!("rebuild_tree_r_last:finish: {:?}", cache_path);
+
+        Ok(commitment_from_fr(comm_r.into()))
+    })
+}
+
+/// Reads p_aux and t_aux from `cache_path`, and ensures that t_aux is for `sector_size`.
+fn read_aux_configs<Tree: 'static + MerkleTreeTrait>(
+    cache_path: &Path,
+    sector_size: SectorSize,
+) -> Result<(
+    PersistentAux<<Tree::Hasher as Hasher>::Domain>,
+    TemporaryAux<Tree, DefaultPieceHasher>,
+)> {
+    let p_aux_path = cache_path.join(CacheKey::PAux.to_string());
+    let p_aux_bytes = fs::read(&p_aux_path).with_context(|| {
+        format!(
+            "could not read file p_aux={:?}, the rebuilt tree cannot be checked",
+            p_aux_path
+        )
+    })?;
+    let p_aux = deserialize(&p_aux_bytes)?;
+
+    let t_aux_path = cache_path.join(CacheKey::TAux.to_string());
+    let t_aux_bytes = fs::read(&t_aux_path)
+        .with_context(|| format!("could not read file t_aux={:?}", t_aux_path))?;
+    let mut t_aux: TemporaryAux<Tree, DefaultPieceHasher> = deserialize(&t_aux_bytes)?;
+    t_aux.set_cache_path(cache_path);
+
+    let expected = temporary_aux::<Tree>(sector_size, cache_path)?;
+    ensure!(
+        t_aux.labels.len() == expected.labels.len()
+            && t_aux.tree_c_config.size == expected.tree_c_config.size
+            && t_aux.tree_r_last_config.size == expected.tree_r_last_config.size,
+        "t_aux is not for a sector of {} bytes",
+        u64::from(sector_size)
+    );
+
+    Ok((p_aux, t_aux))
+}
+
+/// Builds a tree with the stores of `build` in the rebuild directory of `cache_path`, and moves
+/// them into the cache if the root matches `expected_root`. The rebuild directory is removed
+/// either way.
+fn replace_tree<D: PartialEq + std::fmt::Debug>(
+    cache_path: &Path,
+    name: &str,
+    expected_root: D,
+    build: impl FnOnce(&Path) -> Result<D>,
+) -> Result<()> {
+    let rebuild_dir = cache_path.join(REBUILD_DIR);
+    if rebuild_dir.exists() {
+        fs::remove_dir_all(&rebuild_dir)
+            .with_context(|| format!("could not remove {:?}", rebuild_dir))?;
+    }
+    fs::create_dir(&rebuild_dir).with_context(|| format!("could not create {:?}", rebuild_dir))?;
+
+    let result = build(&rebuild_dir).and_then(|root| {
+        ensure!(
+            root == expected_root,
+            "the rebuilt {} has root {:?}, p_aux expects {:?}",
+            name,
+            root,
+            expected_root
+        );
+
+        for entry in fs::read_dir(&rebuild_dir)? {
+            let entry = entry?;
+            let path = cache_path.join(entry.file_name());
+            fs::rename(entry.path(), &path)
+                .with_context(|| format!("could not move the rebuilt {} to {:?}", name, path))?;
+        }
+        Ok(())
+    });
+
+    if let Err(err) = fs::remove_dir_all(&rebuild_dir) {
+        warn!("could not remove {:?}: {:?}", rebuild_dir, err);
+    }
+
+    result
+}
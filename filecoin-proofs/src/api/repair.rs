@@ -0,0 +1,184 @@
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use bincode::deserialize;
+use blstrs::Scalar as Fr;
+use filecoin_hashers::Hasher;
+use log::{info, warn};
+use memmap::MmapOptions;
+use merkletree::store::Store;
+use storage_proofs_core::{
+    cache_key::CacheKey,
+    merkle::{MerkleProofTrait, MerkleTreeTrait},
+    settings::SETTINGS,
+    util::NODE_SIZE,
+};
+use storage_proofs_porep::stacked::{
+    finalize_comm_r, PersistentAux, TemporaryAux, TemporaryAuxCache,
+};
+
+use crate::{
+    api::{as_safe_commitment, ensure_replica_len},
+    constants::DefaultPieceHasher,
+    error::{classified, ensure_input, FilProofsError},
+    types::{Commitment, PoRepConfig},
+};
+
+/// The number of nodes compared at once while looking for corrupted nodes.
+const REPAIR_SCAN_CHUNK_NODES: usize = 1 << 16;
+
+/// Rewrites the corrupted node `node_index` of a sealed replica.
+///
+/// The node is re-encoded from its key in the last layer labels and its data in tree_d, so this
+/// only works as long as the cache still holds them, i.e. before `clear_cache` was called.
+///
+/// All nodes of the replica are compared against their expected encoding first. The repair is
+/// refused if `node_index` is not corrupted, or if more than
+/// `SETTINGS.repair_max_corrupted_nodes` nodes are, in which case the sector has to be resealed.
+/// After the rewrite, the inclusion of the node in tree_r_last is checked against `comm_r`.
+///
+/// # Arguments
+///
+/// * `porep_config` - this sector's porep config that contains the number of bytes in the sector.
+/// * `cache_path` - path to the sector's cache directory.
+/// * `replica_path` - path to the sealed replica.
+/// * `comm_r` - commitment to the sector's replica (`comm_r`).
+/// * `node_index` - the index of the corrupted node.
+pub fn repair_replica_node<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: &Path,
+    replica_path: &Path,
+    comm_r: Commitment,
+    node_index: usize,
+) -> Result<(), FilProofsError> {
+    classified(|| {
+        info!("repair_replica_node:start: {}", node_index);
+
+        let nodes = u64::from(porep_config.sector_size) as usize / NODE_SIZE;
+        ensure_input!(
+            node_index < nodes,
+            "node index {} is out of range for {} nodes",
+            node_index,
+            nodes
+        );
+
+        let p_aux: PersistentAux<<Tree::Hasher as Hasher>::Domain> = {
+            let p_aux_path = cache_path.join(CacheKey::PAux.to_string());
+            let p_aux_bytes = fs::read(&p_aux_path)
+                .with_context(|| format!("could not read file p_aux={:?}", p_aux_path))?;
+
+            deserialize(&p_aux_bytes)
+        }?;
+
+        let comm_r_safe: <Tree::Hasher as Hasher>::Domain = as_safe_commitment(&comm_r, "comm_r")?;
+        ensure!(
+            finalize_comm_r::<Tree::Hasher>(&p_aux.comm_c, &p_aux.comm_r_last) == comm_r_safe,
+            "comm_r does not match the sector cache"
+        );
+
+        let t_aux = {
+            let t_aux_path = cache_path.join(CacheKey::TAux.to_string());
+            let t_aux_bytes = fs::read(&t_aux_path)
+                .with_context(|| format!("could not read file t_aux={:?}", t_aux_path))?;
+
+            let mut res: TemporaryAux<Tree, DefaultPieceHasher> = deserialize(&t_aux_bytes)?;
+
+            // Switch t_aux to the passed in cache_path
+            res.set_cache_path(cache_path);
+            res
+        };
+
+        ensure_replica_len(replica_path, porep_config.sector_size)?;
+        let replica_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(replica_path)
+            .with_context(|| format!("could not open replica_path={:?}", replica_path))?;
+        let mut replica = unsafe {
+            MmapOptions::new()
+                .map_mut(&replica_file)
+                .with_context(|| format!("could not mmap replica_path={:?}", replica_path))?
+        };
+        ensure!(
+            replica.len() >= nodes * NODE_SIZE,
+            "replica has {} bytes, expected {}",
+            replica.len(),
+            nodes * NODE_SIZE
+        );
+
+        let repaired = {
+            let t_aux_cache: TemporaryAuxCache<Tree, DefaultPieceHasher> =
+                TemporaryAuxCache::new(&t_aux, replica_path.to_path_buf())
+                    .context("failed to restore contents of t_aux")?;
+            let last_layer = t_aux_cache.labels.len();
+
+            let mut corrupted = Vec::new();
+            let mut repaired = None;
+            for start in (0..nodes).step_by(REPAIR_SCAN_CHUNK_NODES) {
+                let end = (start + REPAIR_SCAN_CHUNK_NODES).min(nodes);
+                let chunk_keys = t_aux_cache.labels.read_range(last_layer, start..end)?;
+                let chunk_data = t_aux_cache.tree_d.read_range(start, end)?;
+
+                for (i, (key, data)) in chunk_keys.iter().zip(chunk_data.iter()).enumerate() {
+                    let node = start + i;
+                    // Replica nodes are encoded as key + data.
+                    let mut expected: Fr = (*key).into();
+                    expected += Into::<Fr>::into(*data);
+                    let expected: <Tree::Hasher as Hasher>::Domain = expected.into();
+                    if replica[node * NODE_SIZE..(node + 1) * NODE_SIZE] != *expected.as_ref() {
+                        corrupted.push(node);
+                        if node == node_index {
+                            repaired = Some(expected);
+                        }
+                    }
+                }
+
+                ensure!(
+                    corrupted.len() <= SETTINGS.repair_max_corrupted_nodes,
+                    "more than {} replica nodes are corrupted, the sector must be resealed",
+                    SETTINGS.repair_max_corrupted_nodes
+                );
+            }
+
+            if corrupted.len() > 1 {
+                warn!(
+                    "repair_replica_node: corrupted nodes {:?}, only repairing {}",
+                    corrupted, node_index
+                );
+            }
+
+            repaired.with_context(|| format!("replica node {} is not corrupted", node_index))?
+        };
+
+        replica[node_index * NODE_SIZE..(node_index + 1) * NODE_SIZE]
+            .copy_from_slice(repaired.as_ref());
+        replica
+            .flush()
+            .context("failed to flush the repaired replica")?;
+        drop(replica);
+
+        // Reopen the trees, so that tree_r_last reads the repaired replica.
+        let t_aux_cache: TemporaryAuxCache<Tree, DefaultPieceHasher> =
+            TemporaryAuxCache::new(&t_aux, replica_path.to_path_buf())
+                .context("failed to restore contents of t_aux")?;
+        let proof = t_aux_cache.tree_r_last.gen_cached_proof(
+            node_index,
+            Some(t_aux_cache.tree_r_last_config_rows_to_discard),
+        )?;
+        ensure!(
+            proof.leaf() == repaired && proof.validate(node_index),
+            "repaired node {} is not included in tree_r_last",
+            node_index
+        );
+        ensure!(
+            proof.root() == p_aux.comm_r_last,
+            "repaired node {} does not match comm_r_last",
+            node_index
+        );
+
+        info!("repair_replica_node:finish: {}", node_index);
+
+        Ok(())
+    })
+}
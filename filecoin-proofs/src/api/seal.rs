@@ -1,6 +1,8 @@
 use std::fs::{self, metadata, File, OpenOptions};
-use std::io::Write;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+use std::time::Duration;
 
 use anyhow::{ensure, Context, Result};
 use bellperson::groth16;
@@ -9,7 +11,11 @@ use blstrs::{Bls12, Scalar as Fr};
 use filecoin_hashers::{Domain, Hasher};
 use log::{info, trace};
 use memmap::MmapOptions;
-use merkletree::store::{DiskStore, Store, StoreConfig};
+use merkletree::{
+    hash::Algorithm,
+    merkle::get_merkle_tree_len,
+    store::{DiskStore, Store, StoreConfig},
+};
 use rayon::prelude::*;
 use sha2::{Digest, Sha256};
 use storage_proofs_core::{
@@ -22,34 +28,52 @@ use storage_proofs_core::{
     parameter_cache::SRS_MAX_PROOFS_TO_AGGREGATE,
     proof::ProofScheme,
     sector::SectorId,
-    util::default_rows_to_discard,
+    settings::SETTINGS,
+    util::{default_rows_to_discard, retry_transient_io, NODE_SIZE},
     Data,
 };
 use storage_proofs_porep::stacked::{
-    self, generate_replica_id, ChallengeRequirements, StackedCompound, StackedDrg, Tau,
-    TemporaryAux, TemporaryAuxCache,
+    self, generate_replica_id, ChallengeRequirements, PersistentAux, StackedCompound, StackedDrg,
+    Tau, TemporaryAux, TemporaryAuxCache,
 };
+#[cfg(feature = "tracing")]
+use storage_proofs_porep::tracing;
 
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+use crate::types::{BufferPool, CoreIndex};
 use crate::{
-    api::{as_safe_commitment, commitment_from_fr, get_base_tree_leafs, get_base_tree_size},
+    api::{
+        as_safe_commitment, commitment_from_fr, ensure_replica_len, get_base_tree_leafs,
+        get_base_tree_size,
+    },
     caches::{
         get_stacked_params, get_stacked_srs_key, get_stacked_srs_verifier_key,
         get_stacked_verifying_key,
     },
     constants::{
-        DefaultBinaryTree, DefaultPieceDomain, DefaultPieceHasher, POREP_MINIMUM_CHALLENGES,
-        SINGLE_PARTITION_PROOF_LEN,
+        DefaultBinaryTree, DefaultPieceDomain, DefaultPieceHasher, DefaultTreeDomain, LAYERS,
+        POREP_MINIMUM_CHALLENGES, SINGLE_PARTITION_PROOF_LEN,
     },
+    error::{classified, ensure_input, FilProofsError},
     parameters::setup_params,
     pieces::{self, verify_pieces},
     types::{
-        AggregateSnarkProof, Commitment, PaddedBytesAmount, PieceInfo, PoRepConfig,
-        PoRepProofPartitions, ProverId, SealCommitOutput, SealCommitPhase1Output,
-        SealPreCommitOutput, SealPreCommitPhase1Output, SectorSize, Ticket, BINARY_ARITY,
+        AggregateSnarkProof, Commitment, GpuOptions, GpuSelection, Labels, PaddedBytesAmount,
+        PieceInfo, PoRepConfig, PoRepId, PoRepNetwork, PoRepProofPartitions, ProverId, ReplicaId,
+        SealCommitOutput, SealCommitPhase1Output, SealPreCommitOutput, SealPreCommitPhase1Output,
+        SealPublicInputs, SectorSize, Ticket, BINARY_ARITY,
     },
 };
 
 #[allow(clippy::too_many_arguments)]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "seal_pre_commit_phase1",
+        skip_all,
+        fields(sector_id = u64::from(sector_id))
+    )
+)]
 pub fn seal_pre_commit_phase1<R, S, T, Tree: 'static + MerkleTreeTrait>(
     porep_config: PoRepConfig,
     cache_path: R,
@@ -59,24 +83,366 @@ pub fn seal_pre_commit_phase1<R, S, T, Tree: 'static + MerkleTreeTrait>(
     sector_id: SectorId,
     ticket: Ticket,
     piece_infos: &[PieceInfo],
+) -> Result<SealPreCommitPhase1Output<Tree>, FilProofsError>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+    T: AsRef<Path>,
+{
+    classified(|| {
+        info!("seal_pre_commit_phase1:start: {:?}", sector_id);
+
+        let out = seal_pre_commit_phase1_inner::<_, _, _, Tree, _, _>(
+            porep_config,
+            cache_path,
+            in_path,
+            out_path,
+            piece_infos,
+            |comm_d| {
+                generate_replica_id::<Tree::Hasher, _>(
+                    &prover_id,
+                    sector_id.into(),
+                    &ticket,
+                    comm_d,
+                    &porep_config.porep_id,
+                )
+            },
+            |pp, replica_id, config| {
+                StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase1(pp, replica_id, config)
+            },
+        )?;
+
+        info!("seal_pre_commit_phase1:finish: {:?}", sector_id);
+        Ok(out)
+    })
+}
+
+/// Like [`seal_pre_commit_phase1`], but labels the sector on the cores in `core_subset` if it is
+/// set.
+///
+/// This is for a caller which reserved some cores for other work. The labeling threads are bound
+/// to a core group of exactly these cores instead of one of the global core groups, which are
+/// neither used nor locked, so the caller has to keep concurrent seals on different cores. The
+/// cores are labeled with multicore SDR even if `use_multicore_sdr` is not set. Without
+/// `core_subset`, this is the same as [`seal_pre_commit_phase1`].
+///
+/// # Arguments
+///
+/// * `porep_config` - this sector's porep config that contains the number of bytes in the sector.
+/// * `cache_path` - path to a directory in which the sector data's Merkle Tree can be written.
+/// * `in_path` - the path where the unsealed sector data is read.
+/// * `out_path` - the path where the sealed sector data will be written.
+/// * `prover_id` - the prover-id that is sealing the sector.
+/// * `sector_id` - the sector-id of this sector.
+/// * `ticket` - the ticket that will be used to generate this sector's replica-id.
+/// * `piece_infos` - the piece info (commitment and byte length) for each piece in this sector.
+/// * `core_subset` - the cores to label the sector on, each at most once.
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+#[allow(clippy::too_many_arguments)]
+pub fn seal_pre_commit_phase1_with_core_subset<R, S, T, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: R,
+    in_path: S,
+    out_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    piece_infos: &[PieceInfo],
+    core_subset: Option<Vec<CoreIndex>>,
+) -> Result<SealPreCommitPhase1Output<Tree>, FilProofsError>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+    T: AsRef<Path>,
+{
+    classified(|| {
+        info!(
+            "seal_pre_commit_phase1_with_core_subset:start: {:?} {:?}",
+            sector_id, core_subset
+        );
+
+        let out = seal_pre_commit_phase1_inner::<_, _, _, Tree, _, _>(
+            porep_config,
+            cache_path,
+            in_path,
+            out_path,
+            piece_infos,
+            |comm_d| {
+                generate_replica_id::<Tree::Hasher, _>(
+                    &prover_id,
+                    sector_id.into(),
+                    &ticket,
+                    comm_d,
+                    &porep_config.porep_id,
+                )
+            },
+            |pp, replica_id, config| match core_subset {
+                Some(cores) => StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase1_on_cores(
+                    pp, replica_id, config, cores,
+                ),
+                None => {
+                    StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase1(pp, replica_id, config)
+                }
+            },
+        )?;
+
+        info!(
+            "seal_pre_commit_phase1_with_core_subset:finish: {:?}",
+            sector_id
+        );
+        Ok(out)
+    })
+}
+
+/// Like [`seal_pre_commit_phase1`], but labels the sector in the layer buffers of `buffer_pool`.
+///
+/// A caller sealing many sectors one after another can create one [`BufferPool`] for its sector
+/// size up front, so that the two layer buffers the labeling needs are not allocated and freed
+/// again for every sector. The buffers are returned to the pool once the labels are written, and
+/// if the pool has no buffers left, the labeling waits for them. The sector is labeled with
+/// multicore SDR even if `use_multicore_sdr` is not set.
+///
+/// # Arguments
+///
+/// * `porep_config` - this sector's porep config that contains the number of bytes in the sector.
+/// * `cache_path` - path to a directory in which the sector data's Merkle Tree can be written.
+/// * `in_path` - the path where the unsealed sector data is read.
+/// * `out_path` - the path where the sealed sector data will be written.
+/// * `prover_id` - the prover-id that is sealing the sector.
+/// * `sector_id` - the sector-id of this sector.
+/// * `ticket` - the ticket that will be used to generate this sector's replica-id.
+/// * `piece_infos` - the piece info (commitment and byte length) for each piece in this sector.
+/// * `buffer_pool` - the pool to take the layer buffers from, for the sector size of `porep_config`.
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+#[allow(clippy::too_many_arguments)]
+pub fn seal_pre_commit_phase1_with_buffer_pool<R, S, T, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: R,
+    in_path: S,
+    out_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    piece_infos: &[PieceInfo],
+    buffer_pool: &BufferPool,
+) -> Result<SealPreCommitPhase1Output<Tree>, FilProofsError>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+    T: AsRef<Path>,
+{
+    classified(|| {
+        info!(
+            "seal_pre_commit_phase1_with_buffer_pool:start: {:?}",
+            sector_id
+        );
+
+        ensure_input!(
+            buffer_pool.sector_size() == u64::from(porep_config.sector_size),
+            "buffer pool is for sectors of {} bytes, not {}",
+            buffer_pool.sector_size(),
+            u64::from(porep_config.sector_size)
+        );
+
+        let out = seal_pre_commit_phase1_inner::<_, _, _, Tree, _, _>(
+            porep_config,
+            cache_path,
+            in_path,
+            out_path,
+            piece_infos,
+            |comm_d| {
+                generate_replica_id::<Tree::Hasher, _>(
+                    &prover_id,
+                    sector_id.into(),
+                    &ticket,
+                    comm_d,
+                    &porep_config.porep_id,
+                )
+            },
+            |pp, replica_id, config| {
+                StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase1_with_buffer_pool(
+                    pp,
+                    replica_id,
+                    config,
+                    buffer_pool,
+                )
+            },
+        )?;
+
+        info!(
+            "seal_pre_commit_phase1_with_buffer_pool:finish: {:?}",
+            sector_id
+        );
+        Ok(out)
+    })
+}
+
+/// Like [`seal_pre_commit_phase1`], but waits up to `timeout` for a core group to be released if
+/// all of them are checked out, instead of labeling the sector unbound right away.
+///
+/// Sectors waiting for a group of the same size get the released groups in the order of their
+/// `priority`, the highest first. A group is only released once the labeling holding it is done.
+/// If none is released within `timeout`, the sector is labeled unbound, as by
+/// [`seal_pre_commit_phase1`]. The sector is labeled with multicore SDR even if
+/// `use_multicore_sdr` is not set.
+///
+/// # Arguments
+///
+/// * `porep_config` - this sector's porep config that contains the number of bytes in the sector.
+/// * `cache_path` - path to a directory in which the sector data's Merkle Tree can be written.
+/// * `in_path` - the path where the unsealed sector data is read.
+/// * `out_path` - the path where the sealed sector data will be written.
+/// * `prover_id` - the prover-id that is sealing the sector.
+/// * `sector_id` - the sector-id of this sector.
+/// * `ticket` - the ticket that will be used to generate this sector's replica-id.
+/// * `piece_infos` - the piece info (commitment and byte length) for each piece in this sector.
+/// * `priority` - the priority of this sector among those waiting for a core group.
+/// * `timeout` - how long to wait for a core group.
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+#[allow(clippy::too_many_arguments)]
+pub fn seal_pre_commit_phase1_with_priority<R, S, T, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: R,
+    in_path: S,
+    out_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    piece_infos: &[PieceInfo],
+    priority: u8,
+    timeout: Duration,
+) -> Result<SealPreCommitPhase1Output<Tree>, FilProofsError>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+    T: AsRef<Path>,
+{
+    classified(|| {
+        info!(
+            "seal_pre_commit_phase1_with_priority:start: {:?} {}",
+            sector_id, priority
+        );
+
+        let out = seal_pre_commit_phase1_inner::<_, _, _, Tree, _, _>(
+            porep_config,
+            cache_path,
+            in_path,
+            out_path,
+            piece_infos,
+            |comm_d| {
+                generate_replica_id::<Tree::Hasher, _>(
+                    &prover_id,
+                    sector_id.into(),
+                    &ticket,
+                    comm_d,
+                    &porep_config.porep_id,
+                )
+            },
+            |pp, replica_id, config| {
+                StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase1_with_priority(
+                    pp, replica_id, config, priority, timeout,
+                )
+            },
+        )?;
+
+        info!(
+            "seal_pre_commit_phase1_with_priority:finish: {:?}",
+            sector_id
+        );
+        Ok(out)
+    })
+}
+
+/// Like [`seal_pre_commit_phase1`], but labels the sector with `replica_id` instead of deriving
+/// it from the prover id, sector id, ticket and `comm_d`.
+///
+/// This exists to create reproducible sealing fixtures for tests and test networks, which are
+/// sealed with the same replica id on every run and machine. A sector sealed this way can never
+/// be proven on a Filecoin network, and this must never be used for sectors of one, so it fails
+/// for every registered `porep_id`. Such a sector is committed with
+/// [`seal_commit_phase1_with_replica_id`] and [`seal_commit_phase2_with_replica_id`], and verified
+/// with [`verify_seal_with_replica_id`].
+///
+/// # Arguments
+///
+/// * `porep_config` - this sector's porep config, its `porep_id` must not be a registered one.
+/// * `cache_path` - path to a directory in which the sector data's Merkle Tree can be written.
+/// * `in_path` - the path where the unsealed sector data is read.
+/// * `out_path` - the path where the sealed sector data will be written.
+/// * `replica_id` - the replica-id to label the sector with.
+/// * `piece_infos` - the piece info (commitment and byte length) for each piece in this sector.
+pub fn seal_pre_commit_phase1_with_replica_id<R, S, T, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: R,
+    in_path: S,
+    out_path: T,
+    replica_id: ReplicaId,
+    piece_infos: &[PieceInfo],
+) -> Result<SealPreCommitPhase1Output<Tree>, FilProofsError>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+    T: AsRef<Path>,
+{
+    classified(|| {
+        info!("seal_pre_commit_phase1_with_replica_id:start");
+        ensure_unregistered_porep_id(porep_config, "seal_pre_commit_phase1_with_replica_id")?;
+
+        let out = seal_pre_commit_phase1_inner::<_, _, _, Tree, _, _>(
+            porep_config,
+            cache_path,
+            in_path,
+            out_path,
+            piece_infos,
+            |_comm_d| replica_id.into(),
+            |pp, replica_id, config| {
+                StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase1(pp, replica_id, config)
+            },
+        )?;
+
+        info!("seal_pre_commit_phase1_with_replica_id:finish");
+        Ok(out)
+    })
+}
+
+/// Seals the sector data for `seal_pre_commit_phase1`, labeling the sector with `label` and the
+/// replica id `replica_id` returns for its `comm_d`.
+fn seal_pre_commit_phase1_inner<R, S, T, Tree: 'static + MerkleTreeTrait, F, L>(
+    porep_config: PoRepConfig,
+    cache_path: R,
+    in_path: S,
+    out_path: T,
+    piece_infos: &[PieceInfo],
+    replica_id: F,
+    label: L,
 ) -> Result<SealPreCommitPhase1Output<Tree>>
 where
     R: AsRef<Path>,
     S: AsRef<Path>,
     T: AsRef<Path>,
+    F: FnOnce(Commitment) -> <Tree::Hasher as Hasher>::Domain,
+    L: FnOnce(
+        &stacked::PublicParams<Tree>,
+        &<Tree::Hasher as Hasher>::Domain,
+        StoreConfig,
+    ) -> Result<Labels<Tree>>,
 {
-    info!("seal_pre_commit_phase1:start: {:?}", sector_id);
+    porep_config.validate_porep_id()?;
+    porep_config.validate_tree_c_arity::<Tree>()?;
+    if !SETTINGS.skip_piece_layout_validation {
+        pieces::validate_piece_layout(piece_infos, porep_config.sector_size)?;
+    }
 
     // Sanity check all input path types.
-    ensure!(
+    ensure_input!(
         metadata(in_path.as_ref())?.is_file(),
         "in_path must be a file"
     );
-    ensure!(
+    ensure_input!(
         metadata(out_path.as_ref())?.is_file(),
         "out_path must be a file"
     );
-    ensure!(
+    ensure_input!(
         metadata(cache_path.as_ref())?.is_dir(),
         "cache_path must be a directory"
     );
@@ -168,160 +534,624 @@ where
 
     trace!("verifying pieces");
 
-    ensure!(
+    ensure_input!(
         verify_pieces(&comm_d, piece_infos, porep_config.into())?,
         "pieces and comm_d do not match"
     );
 
-    let replica_id = generate_replica_id::<Tree::Hasher, _>(
-        &prover_id,
-        sector_id.into(),
-        &ticket,
-        comm_d,
-        &porep_config.porep_id,
-    );
+    let replica_id = replica_id(comm_d);
 
-    let labels = StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase1(
+    let labels = label(
         &compound_public_params.vanilla_params,
         &replica_id,
         config.clone(),
     )?;
 
-    let out = SealPreCommitPhase1Output {
+    Ok(SealPreCommitPhase1Output {
         labels,
         config,
         comm_d,
-    };
+    })
+}
 
-    info!("seal_pre_commit_phase1:finish: {:?}", sector_id);
-    Ok(out)
+/// Ensures that `porep_config` is not for a registered proof type, as `function` uses a given
+/// replica id, which is only meant for tests.
+fn ensure_unregistered_porep_id(porep_config: PoRepConfig, function: &str) -> Result<()> {
+    let porep_id = PoRepId(porep_config.porep_id);
+    ensure_input!(
+        porep_id.network() == PoRepNetwork::Unregistered,
+        "{} cannot be used with the registered proof type {}",
+        function,
+        porep_id.proof_type()
+    );
+    Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
-pub fn seal_pre_commit_phase2<R, S, Tree: 'static + MerkleTreeTrait>(
+/// Like [`seal_pre_commit_phase1`] for a committed-capacity (CC) sector, which holds no pieces
+/// and is all zeros.
+///
+/// Instead of copying and hashing the unsealed data, the replica at `out_path` is created as a
+/// sector of zeros, and tree_d is written directly: all nodes of a level of tree_d are equal, so
+/// only one node per level is hashed. comm_d is the same constant for all CC sectors of a size,
+/// see [`compute_comm_d`] with no pieces. The output is identical to that of
+/// `seal_pre_commit_phase1` for an all-zero sector with no pieces, so the sector is sealed further
+/// the same way, passing no pieces to `seal_commit_phase1`.
+///
+/// # Arguments
+///
+/// * `porep_config` - this sector's porep config that contains the number of bytes in the sector.
+/// * `cache_path` - path to a directory in which the sector data's Merkle Tree can be written.
+/// * `out_path` - path where the sector's replica is created, replacing any existing file.
+/// * `prover_id` - the prover-id that is sealing the sector.
+/// * `sector_id` - the sector-id of this sector.
+/// * `ticket` - the ticket that will be used to generate this sector's replica-id.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "seal_cc_sector",
+        skip_all,
+        fields(sector_id = u64::from(sector_id))
+    )
+)]
+pub fn seal_cc_sector<R, T, Tree: 'static + MerkleTreeTrait>(
     porep_config: PoRepConfig,
-    phase1_output: SealPreCommitPhase1Output<Tree>,
-    cache_path: S,
-    replica_path: R,
-) -> Result<SealPreCommitOutput>
+    cache_path: R,
+    out_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+) -> Result<SealPreCommitPhase1Output<Tree>, FilProofsError>
 where
     R: AsRef<Path>,
-    S: AsRef<Path>,
+    T: AsRef<Path>,
 {
-    info!("seal_pre_commit_phase2:start");
-
-    // Sanity check all input path types.
-    ensure!(
-        metadata(cache_path.as_ref())?.is_dir(),
-        "cache_path must be a directory"
-    );
-    ensure!(
-        metadata(replica_path.as_ref())?.is_file(),
-        "replica_path must be a file"
-    );
-
-    let SealPreCommitPhase1Output {
-        mut labels,
-        mut config,
-        comm_d,
-        ..
-    } = phase1_output;
+    classified(|| {
+        info!("seal_cc_sector:start: {:?}", sector_id);
+
+        porep_config.validate_porep_id()?;
+        porep_config.validate_tree_c_arity::<Tree>()?;
+        ensure_input!(
+            metadata(cache_path.as_ref())?.is_dir(),
+            "cache_path must be a directory"
+        );
 
-    labels.update_root(cache_path.as_ref());
-    config.path = cache_path.as_ref().into();
+        let sector_bytes = usize::from(PaddedBytesAmount::from(porep_config));
+        let f_data = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&out_path)
+            .with_context(|| {
+                format!("could not open out_path={:?}", out_path.as_ref().display())
+            })?;
+        f_data.set_len(sector_bytes as u64)?;
+        drop(f_data);
+
+        let compound_setup_params = compound_proof::SetupParams {
+            vanilla_params: setup_params(
+                PaddedBytesAmount::from(porep_config),
+                usize::from(PoRepProofPartitions::from(porep_config)),
+                porep_config.porep_id,
+                porep_config.api_version,
+            )?,
+            partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
+            priority: false,
+        };
 
-    let f_data = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(&replica_path)
-        .with_context(|| {
-            format!(
-                "could not open replica_path={:?}",
-                replica_path.as_ref().display()
-            )
+        let compound_public_params =
+            <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
+                StackedDrg<'_, Tree, DefaultPieceHasher>,
+                _,
+            >>::setup(&compound_setup_params)?;
+
+        trace!("building merkle tree for the zero data");
+        let (config, comm_d) = measure_op(Operation::CommD, || -> Result<_> {
+            let base_tree_size = get_base_tree_size::<DefaultBinaryTree>(porep_config.sector_size)?;
+            let base_tree_leafs = get_base_tree_leafs::<DefaultBinaryTree>(base_tree_size)?;
+            ensure!(
+                compound_public_params.vanilla_params.graph.size() == base_tree_leafs,
+                "graph size and leaf size don't match"
+            );
+
+            let mut config = StoreConfig::new(
+                cache_path.as_ref(),
+                CacheKey::CommDTree.to_string(),
+                default_rows_to_discard(base_tree_leafs, BINARY_ARITY),
+            );
+            let comm_d_root: Fr = write_zero_tree_d(&config, base_tree_leafs)?.into();
+            config.size = Some(base_tree_size);
+            let comm_d = commitment_from_fr(comm_d_root);
+
+            Ok((config, comm_d))
         })?;
-    let data = unsafe {
-        MmapOptions::new().map_mut(&f_data).with_context(|| {
-            format!(
-                "could not mmap replica_path={:?}",
-                replica_path.as_ref().display()
-            )
-        })?
-    };
-    let data: Data<'_> = (data, PathBuf::from(replica_path.as_ref())).into();
-
-    // Load data tree from disk
-    let data_tree = {
-        let base_tree_size = get_base_tree_size::<DefaultBinaryTree>(porep_config.sector_size)?;
-        let base_tree_leafs = get_base_tree_leafs::<DefaultBinaryTree>(base_tree_size)?;
 
-        trace!(
-            "seal phase 2: base tree size {}, base tree leafs {}, rows to discard {}",
-            base_tree_size,
-            base_tree_leafs,
-            default_rows_to_discard(base_tree_leafs, BINARY_ARITY)
-        );
         ensure!(
-            config.rows_to_discard == default_rows_to_discard(base_tree_leafs, BINARY_ARITY),
-            "Invalid cache size specified"
+            comm_d == pieces::compute_comm_d(porep_config.sector_size, &[])?,
+            "comm_d of the zero tree_d does not match the CC sector commitment"
         );
 
-        let store: DiskStore<DefaultPieceDomain> =
-            DiskStore::new_from_disk(base_tree_size, BINARY_ARITY, &config)?;
-        BinaryMerkleTree::<DefaultPieceHasher>::from_data_store(store, base_tree_leafs)?
-    };
+        let replica_id = generate_replica_id::<Tree::Hasher, _>(
+            &prover_id,
+            sector_id.into(),
+            &ticket,
+            comm_d,
+            &porep_config.porep_id,
+        );
 
-    let compound_setup_params = compound_proof::SetupParams {
-        vanilla_params: setup_params(
-            PaddedBytesAmount::from(porep_config),
-            usize::from(PoRepProofPartitions::from(porep_config)),
-            porep_config.porep_id,
-            porep_config.api_version,
-        )?,
-        partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
-        priority: false,
-    };
+        let labels = StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase1(
+            &compound_public_params.vanilla_params,
+            &replica_id,
+            config.clone(),
+        )?;
 
-    let compound_public_params = <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
-        StackedDrg<'_, Tree, DefaultPieceHasher>,
-        _,
-    >>::setup(&compound_setup_params)?;
+        let out = SealPreCommitPhase1Output {
+            labels,
+            config,
+            comm_d,
+        };
 
-    let (tau, (p_aux, t_aux)) = StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase2(
-        &compound_public_params.vanilla_params,
-        labels,
-        data,
-        data_tree,
-        config,
-        replica_path.as_ref().to_path_buf(),
-    )?;
+        info!("seal_cc_sector:finish: {:?}", sector_id);
+        Ok(out)
+    })
+}
 
-    let comm_r = commitment_from_fr(tau.comm_r.into());
+/// Writes the store of tree_d over `leafs` zero nodes, as `create_base_merkle_tree` would, and
+/// returns its root.
+fn write_zero_tree_d(
+    config: &StoreConfig,
+    leafs: usize,
+) -> Result<<DefaultPieceHasher as Hasher>::Domain> {
+    const WRITE_CHUNK_NODES: usize = 1 << 15;
 
-    // Persist p_aux and t_aux here
-    let p_aux_path = cache_path.as_ref().join(CacheKey::PAux.to_string());
-    let mut f_p_aux = File::create(&p_aux_path)
-        .with_context(|| format!("could not create file p_aux={:?}", p_aux_path))?;
-    let p_aux_bytes = serialize(&p_aux)?;
-    f_p_aux
-        .write_all(&p_aux_bytes)
-        .with_context(|| format!("could not write to file p_aux={:?}", p_aux_path))?;
+    ensure!(
+        leafs.is_power_of_two(),
+        "tree_d must have a power of two leafs"
+    );
 
-    let t_aux_path = cache_path.as_ref().join(CacheKey::TAux.to_string());
-    let mut f_t_aux = File::create(&t_aux_path)
-        .with_context(|| format!("could not create file t_aux={:?}", t_aux_path))?;
-    let t_aux_bytes = serialize(&t_aux)?;
-    f_t_aux
-        .write_all(&t_aux_bytes)
-        .with_context(|| format!("could not write to file t_aux={:?}", t_aux_path))?;
+    let path = StoreConfig::data_path(&config.path, &config.id);
+    let file =
+        File::create(&path).with_context(|| format!("could not create tree_d={:?}", path))?;
+    let mut writer = BufWriter::new(file);
+
+    // Every level is one node repeated, starting with the zero leafs.
+    let mut node = <DefaultPieceHasher as Hasher>::Domain::default();
+    let mut level_leafs = leafs;
+    let mut height = 0;
+    loop {
+        let chunk = AsRef::<[u8]>::as_ref(&node).repeat(level_leafs.min(WRITE_CHUNK_NODES));
+        for _ in 0..(level_leafs + WRITE_CHUNK_NODES - 1) / WRITE_CHUNK_NODES {
+            writer.write_all(&chunk)?;
+        }
+
+        if level_leafs == 1 {
+            break;
+        }
+        let mut hasher = <DefaultPieceHasher as Hasher>::Function::default();
+        hasher.reset();
+        node = hasher.node(node, node, height);
+        level_leafs /= 2;
+        height += 1;
+    }
 
-    let out = SealPreCommitOutput { comm_r, comm_d };
+    let file = writer.into_inner().context("failed to write tree_d")?;
+    file.sync_all()?;
+    ensure!(
+        file.metadata()?.len() as usize == get_merkle_tree_len(leafs, BINARY_ARITY)? * NODE_SIZE,
+        "tree_d has an unexpected size"
+    );
 
-    info!("seal_pre_commit_phase2:finish");
-    Ok(out)
+    Ok(node)
 }
 
 #[allow(clippy::too_many_arguments)]
+pub fn seal_pre_commit_phase2<R, S, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealPreCommitPhase1Output<Tree>,
+    cache_path: S,
+    replica_path: R,
+) -> Result<SealPreCommitOutput, FilProofsError>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+{
+    seal_pre_commit_phase2_with_gpu(
+        porep_config,
+        phase1_output,
+        cache_path,
+        replica_path,
+        &GpuSelection::Default,
+    )
+}
+
+/// Like [`seal_pre_commit_phase2`], but builds tree_c and tree_r_last on the GPU selected by
+/// `gpu` instead of the one neptune picks, so that sectors can be spread over the GPUs of a
+/// machine.
+///
+/// The selection only matters if the GPU tree builders are enabled. It fails with
+/// `FilProofsError::GpuUnavailable` if no such GPU is found, including when GPU support is not
+/// compiled in, rather than falling back to another device.
+pub fn seal_pre_commit_phase2_with_gpu<R, S, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealPreCommitPhase1Output<Tree>,
+    cache_path: S,
+    replica_path: R,
+    gpu: &GpuSelection,
+) -> Result<SealPreCommitOutput, FilProofsError>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+{
+    seal_pre_commit_phase2_with_gpu_options(
+        porep_config,
+        phase1_output,
+        cache_path,
+        replica_path,
+        &gpu.clone().into(),
+    )
+}
+
+/// Like [`seal_pre_commit_phase2_with_gpu`], but also overrides the batch sizes the GPU tree
+/// builders hash with for this sector, e.g. to fit the trees of a sector sealed alongside others
+/// into the memory left on its GPU. Batch sizes which are not given are derived as configured.
+pub fn seal_pre_commit_phase2_with_gpu_options<R, S, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealPreCommitPhase1Output<Tree>,
+    cache_path: S,
+    replica_path: R,
+    gpu: &GpuOptions,
+) -> Result<SealPreCommitOutput, FilProofsError>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+{
+    seal_pre_commit_phase2_with_roots(
+        porep_config,
+        phase1_output,
+        cache_path,
+        replica_path,
+        gpu,
+        &mut |_| {},
+    )
+}
+
+/// Like [`seal_pre_commit_phase2_with_gpu_options`], but calls `on_roots` with `comm_r` and
+/// `comm_d` as soon as tree_c and tree_r_last are built, so that a pipeline can start its next
+/// stage early.
+///
+/// The layers are consolidated or compressed, the replica is flushed, and p_aux and t_aux are
+/// written only after `on_roots` returns. The sector is only pre-committed once this returns
+/// `Ok`, with the same output that `on_roots` received.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "seal_pre_commit_phase2",
+        skip_all,
+        fields(cache_path = ?cache_path.as_ref())
+    )
+)]
+pub fn seal_pre_commit_phase2_with_roots<R, S, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealPreCommitPhase1Output<Tree>,
+    cache_path: S,
+    replica_path: R,
+    gpu: &GpuOptions,
+    on_roots: &mut (dyn FnMut(&SealPreCommitOutput) + Send),
+) -> Result<SealPreCommitOutput, FilProofsError>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+{
+    classified(|| {
+        info!("seal_pre_commit_phase2:start: {:?}", gpu);
+
+        let out = seal_pre_commit_phase2_in_place(
+            porep_config,
+            phase1_output,
+            cache_path.as_ref(),
+            replica_path.as_ref(),
+            gpu,
+            None,
+            Some(&mut *on_roots),
+        )?;
+
+        info!("seal_pre_commit_phase2:finish");
+        Ok(out)
+    })
+}
+
+/// Like [`seal_pre_commit_phase2_with_gpu_options`], but discards `rows_to_discard` rows of tree_c
+/// and tree_r_last of this sector instead of the number the `rows_to_discard` setting gives, so
+/// that sectors with different cache shapes can be sealed in one process.
+///
+/// The value is capped as the setting is, and recorded in t_aux. PoSt reads it back from t_aux,
+/// or infers it from the length of the stored tree_r_last if there is no t_aux.
+pub fn seal_pre_commit_phase2_with_rows_to_discard<R, S, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealPreCommitPhase1Output<Tree>,
+    cache_path: S,
+    replica_path: R,
+    gpu: &GpuOptions,
+    rows_to_discard: usize,
+) -> Result<SealPreCommitOutput, FilProofsError>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+{
+    classified(|| {
+        info!(
+            "seal_pre_commit_phase2:start: {:?}, rows_to_discard {}",
+            gpu, rows_to_discard
+        );
+
+        let out = seal_pre_commit_phase2_in_place(
+            porep_config,
+            phase1_output,
+            cache_path.as_ref(),
+            replica_path.as_ref(),
+            gpu,
+            Some(rows_to_discard),
+            None,
+        )?;
+
+        info!("seal_pre_commit_phase2:finish");
+        Ok(out)
+    })
+}
+
+/// Encodes the sector in the file at `replica_path` while building its trees, see
+/// `seal_pre_commit_phase2_inner`.
+fn seal_pre_commit_phase2_in_place<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealPreCommitPhase1Output<Tree>,
+    cache_path: &Path,
+    replica_path: &Path,
+    gpu: &GpuOptions,
+    rows_to_discard: Option<usize>,
+    on_roots: Option<&mut (dyn FnMut(&SealPreCommitOutput) + Send)>,
+) -> Result<SealPreCommitOutput> {
+    gpu.batch_sizes.validate()?;
+    ensure_pre_commit_phase2_paths(porep_config, cache_path, replica_path)?;
+
+    let f_data = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(replica_path)
+        .with_context(|| format!("could not open replica_path={:?}", replica_path.display()))?;
+    let data = unsafe {
+        MmapOptions::new()
+            .map_mut(&f_data)
+            .with_context(|| format!("could not mmap replica_path={:?}", replica_path.display()))?
+    };
+    let data: Data<'_> = (data, PathBuf::from(replica_path)).into();
+
+    seal_pre_commit_phase2_inner(
+        porep_config,
+        phase1_output,
+        cache_path,
+        replica_path,
+        data,
+        gpu,
+        rows_to_discard,
+        on_roots,
+    )
+}
+
+/// Like [`seal_pre_commit_phase2`], but also writes the replica to `replica_out`.
+///
+/// `replica_path` is the file which `seal_pre_commit_phase1` copied the sector to. The sector is
+/// encoded in place as by `seal_pre_commit_phase2`, and the file is then streamed to
+/// `replica_out` from its start, so the sector is never held in memory. `replica_path` can be
+/// removed once this returns, and as the later steps (commit, PoSt, unsealing) read the replica
+/// from a path, it has to be made available at one again, e.g. by mounting the storage it was
+/// written to.
+///
+/// `replica_out` has to be seekable. For a backend which is not, use `seal_pre_commit_phase2`
+/// and copy the replica file instead.
+pub fn seal_pre_commit_phase2_to_writer<R, S, W, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealPreCommitPhase1Output<Tree>,
+    cache_path: S,
+    replica_path: R,
+    replica_out: &mut W,
+) -> Result<SealPreCommitOutput, FilProofsError>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+    W: Write + Seek,
+{
+    classified(|| {
+        info!("seal_pre_commit_phase2_to_writer:start");
+
+        let out = seal_pre_commit_phase2_in_place(
+            porep_config,
+            phase1_output,
+            cache_path.as_ref(),
+            replica_path.as_ref(),
+            &GpuOptions::default(),
+            None,
+            None,
+        )?;
+
+        let mut replica = File::open(&replica_path).with_context(|| {
+            format!(
+                "could not open replica_path={:?}",
+                replica_path.as_ref().display()
+            )
+        })?;
+        replica_out.seek(SeekFrom::Start(0))?;
+        io::copy(&mut replica, replica_out).context("could not write the replica")?;
+        replica_out.flush().context("could not flush the replica")?;
+
+        info!("seal_pre_commit_phase2_to_writer:finish");
+        Ok(out)
+    })
+}
+
+/// Sanity checks the input paths of the pre commit phase 2 variants.
+fn ensure_pre_commit_phase2_paths(
+    porep_config: PoRepConfig,
+    cache_path: &Path,
+    replica_path: &Path,
+) -> Result<()> {
+    ensure_input!(
+        metadata(cache_path)?.is_dir(),
+        "cache_path must be a directory"
+    );
+    ensure_input!(
+        metadata(replica_path)?.is_file(),
+        "replica_path must be a file"
+    );
+    Ok(ensure_replica_len(replica_path, porep_config.sector_size)?)
+}
+
+/// Builds the trees of the sector, encoding `data` into the replica, and persists p_aux and t_aux.
+/// `rows_to_discard` overrides the setting for tree_c and tree_r_last. `on_roots` is called with the
+/// output once the trees are built.
+#[allow(clippy::too_many_arguments)]
+fn seal_pre_commit_phase2_inner<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealPreCommitPhase1Output<Tree>,
+    cache_path: &Path,
+    replica_path: &Path,
+    data: Data<'_>,
+    gpu: &GpuOptions,
+    rows_to_discard: Option<usize>,
+    mut on_roots: Option<&mut (dyn FnMut(&SealPreCommitOutput) + Send)>,
+) -> Result<SealPreCommitOutput> {
+    let SealPreCommitPhase1Output {
+        mut labels,
+        mut config,
+        comm_d,
+        ..
+    } = phase1_output;
+
+    labels.update_root(cache_path);
+    config.path = cache_path.into();
+
+    // Load data tree from disk
+    let data_tree = {
+        let base_tree_size = get_base_tree_size::<DefaultBinaryTree>(porep_config.sector_size)?;
+        let base_tree_leafs = get_base_tree_leafs::<DefaultBinaryTree>(base_tree_size)?;
+
+        trace!(
+            "seal phase 2: base tree size {}, base tree leafs {}, rows to discard {}",
+            base_tree_size,
+            base_tree_leafs,
+            default_rows_to_discard(base_tree_leafs, BINARY_ARITY)
+        );
+        ensure!(
+            config.rows_to_discard == default_rows_to_discard(base_tree_leafs, BINARY_ARITY),
+            "Invalid cache size specified"
+        );
+
+        let store: DiskStore<DefaultPieceDomain> =
+            DiskStore::new_from_disk(base_tree_size, BINARY_ARITY, &config)?;
+        BinaryMerkleTree::<DefaultPieceHasher>::from_data_store(store, base_tree_leafs)?
+    };
+
+    let compound_setup_params = compound_proof::SetupParams {
+        vanilla_params: setup_params(
+            PaddedBytesAmount::from(porep_config),
+            usize::from(PoRepProofPartitions::from(porep_config)),
+            porep_config.porep_id,
+            porep_config.api_version,
+        )?,
+        partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
+        priority: false,
+    };
+
+    let compound_public_params = <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
+        StackedDrg<'_, Tree, DefaultPieceHasher>,
+        _,
+    >>::setup(&compound_setup_params)?;
+
+    let mut on_tree_roots =
+        |tau: &Tau<<Tree::Hasher as Hasher>::Domain, DefaultPieceDomain>,
+         _: &PersistentAux<<Tree::Hasher as Hasher>::Domain>| {
+            if let Some(on_roots) = on_roots.as_mut() {
+                on_roots(&SealPreCommitOutput {
+                    comm_r: commitment_from_fr(tau.comm_r.into()),
+                    comm_d,
+                });
+            }
+        };
+    let (tau, (p_aux, t_aux)) =
+        StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase2_with_roots(
+            &compound_public_params.vanilla_params,
+            labels,
+            data,
+            data_tree,
+            config,
+            replica_path.to_path_buf(),
+            rows_to_discard,
+            gpu,
+            Some(&mut on_tree_roots),
+        )?;
+
+    let comm_r = commitment_from_fr(tau.comm_r.into());
+
+    persist_aux(cache_path, &p_aux, &t_aux)?;
+
+    Ok(SealPreCommitOutput { comm_r, comm_d })
+}
+
+/// Writes p_aux and t_aux to `cache_path`, replacing any existing ones.
+pub(crate) fn persist_aux<Tree: MerkleTreeTrait>(
+    cache_path: &Path,
+    p_aux: &PersistentAux<<Tree::Hasher as Hasher>::Domain>,
+    t_aux: &TemporaryAux<Tree, DefaultPieceHasher>,
+) -> Result<()> {
+    let p_aux_path = cache_path.join(CacheKey::PAux.to_string());
+    let mut f_p_aux = File::create(&p_aux_path)
+        .with_context(|| format!("could not create file p_aux={:?}", p_aux_path))?;
+    let p_aux_bytes = serialize(p_aux)?;
+    f_p_aux
+        .write_all(&p_aux_bytes)
+        .with_context(|| format!("could not write to file p_aux={:?}", p_aux_path))?;
+
+    let t_aux_path = cache_path.join(CacheKey::TAux.to_string());
+    let mut f_t_aux = File::create(&t_aux_path)
+        .with_context(|| format!("could not create file t_aux={:?}", t_aux_path))?;
+    let t_aux_bytes = serialize(t_aux)?;
+    f_t_aux
+        .write_all(&t_aux_bytes)
+        .with_context(|| format!("could not write to file t_aux={:?}", t_aux_path))?;
+
+    Ok(())
+}
+
+/// Computes `comm_r` from the roots of tree_c (`comm_c`) and tree_r_last (`comm_r_last`).
+///
+/// This is the `comm_r` that `seal_pre_commit_phase2` returns, so a pipeline which builds the two
+/// trees in separate stages can start the next stage as soon as both roots are known.
+///
+/// # Arguments
+///
+/// * `comm_c` - the root of tree_c.
+/// * `comm_r_last` - the root of tree_r_last.
+pub fn finalize_comm_r<Tree: 'static + MerkleTreeTrait>(
+    comm_c: Commitment,
+    comm_r_last: Commitment,
+) -> Result<Commitment, FilProofsError> {
+    classified(|| {
+        let comm_c: <Tree::Hasher as Hasher>::Domain = as_safe_commitment(&comm_c, "comm_c")?;
+        let comm_r_last: <Tree::Hasher as Hasher>::Domain =
+            as_safe_commitment(&comm_r_last, "comm_r_last")?;
+        let comm_r = stacked::finalize_comm_r::<Tree::Hasher>(&comm_c, &comm_r_last);
+
+        Ok(commitment_from_fr(comm_r.into()))
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "seal_commit_phase1",
+        skip_all,
+        fields(sector_id = u64::from(sector_id))
+    )
+)]
 pub fn seal_commit_phase1<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
     porep_config: PoRepConfig,
     cache_path: T,
@@ -332,64 +1162,117 @@ pub fn seal_commit_phase1<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
     seed: Ticket,
     pre_commit: SealPreCommitOutput,
     piece_infos: &[PieceInfo],
-) -> Result<SealCommitPhase1Output<Tree>> {
-    info!("seal_commit_phase1:start: {:?}", sector_id);
+) -> Result<SealCommitPhase1Output<Tree>, FilProofsError> {
+    classified(|| {
+        info!("seal_commit_phase1:start: {:?}", sector_id);
+
+        let out = seal_commit_phase1_inner::<_, Tree, _>(
+            porep_config,
+            cache_path,
+            replica_path,
+            |comm_d| {
+                generate_replica_id::<Tree::Hasher, _>(
+                    &prover_id,
+                    sector_id.into(),
+                    &ticket,
+                    comm_d,
+                    &porep_config.porep_id,
+                )
+            },
+            ticket,
+            seed,
+            pre_commit,
+            piece_infos,
+        )?;
+
+        info!("seal_commit_phase1:finish: {:?}", sector_id);
+        Ok(out)
+    })
+}
 
+/// Like [`seal_commit_phase1`] for a sector sealed with
+/// [`seal_pre_commit_phase1_with_replica_id`], which is proven for `replica_id` instead of the one
+/// derived from the prover id, sector id, ticket and `comm_d`.
+///
+/// There is no ticket, so the `ticket` of the output is all zeros. Fails for every registered
+/// `porep_id`.
+///
+/// # Arguments
+///
+/// * `porep_config` - this sector's porep config, its `porep_id` must not be a registered one.
+/// * `cache_path` - path to the sector's cache directory.
+/// * `replica_path` - path to the sealed replica.
+/// * `replica_id` - the replica-id the sector was sealed with.
+/// * `seed` - the seed used to derive the porep challenges.
+/// * `pre_commit` - the commitments returned by `seal_pre_commit_phase2`.
+/// * `piece_infos` - the piece info (commitment and byte length) for each piece in this sector.
+pub fn seal_commit_phase1_with_replica_id<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: T,
+    replica_path: T,
+    replica_id: ReplicaId,
+    seed: Ticket,
+    pre_commit: SealPreCommitOutput,
+    piece_infos: &[PieceInfo],
+) -> Result<SealCommitPhase1Output<Tree>, FilProofsError> {
+    classified(|| {
+        info!("seal_commit_phase1_with_replica_id:start");
+        ensure_unregistered_porep_id(porep_config, "seal_commit_phase1_with_replica_id")?;
+
+        let out = seal_commit_phase1_inner::<_, Tree, _>(
+            porep_config,
+            cache_path,
+            replica_path,
+            |_comm_d| replica_id.into(),
+            [0; 32],
+            seed,
+            pre_commit,
+            piece_infos,
+        )?;
+
+        info!("seal_commit_phase1_with_replica_id:finish");
+        Ok(out)
+    })
+}
+
+/// Generates the vanilla proofs for `seal_commit_phase1`, for the replica id `replica_id` returns
+/// for the sector's `comm_d`.
+#[allow(clippy::too_many_arguments)]
+fn seal_commit_phase1_inner<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait, F>(
+    porep_config: PoRepConfig,
+    cache_path: T,
+    replica_path: T,
+    replica_id: F,
+    ticket: Ticket,
+    seed: Ticket,
+    pre_commit: SealPreCommitOutput,
+    piece_infos: &[PieceInfo],
+) -> Result<SealCommitPhase1Output<Tree>>
+where
+    F: FnOnce(DefaultPieceDomain) -> <Tree::Hasher as Hasher>::Domain,
+{
     // Sanity check all input path types.
-    ensure!(
+    ensure_input!(
         metadata(cache_path.as_ref())?.is_dir(),
         "cache_path must be a directory"
     );
-    ensure!(
-        metadata(replica_path.as_ref())?.is_file(),
-        "replica_path must be a file"
-    );
+    ensure_replica_len(replica_path.as_ref(), porep_config.sector_size)?;
 
     let SealPreCommitOutput { comm_d, comm_r } = pre_commit;
 
-    ensure!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
-    ensure!(comm_r != [0; 32], "Invalid all zero commitment (comm_r)");
-    ensure!(
+    ensure_input!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
+    ensure_input!(comm_r != [0; 32], "Invalid all zero commitment (comm_r)");
+    ensure_input!(
         verify_pieces(&comm_d, piece_infos, porep_config.into())?,
         "pieces and comm_d do not match"
     );
 
-    let p_aux = {
-        let p_aux_path = cache_path.as_ref().join(CacheKey::PAux.to_string());
-        let p_aux_bytes = fs::read(&p_aux_path)
-            .with_context(|| format!("could not read file p_aux={:?}", p_aux_path))?;
-
-        deserialize(&p_aux_bytes)
-    }?;
-
-    let t_aux = {
-        let t_aux_path = cache_path.as_ref().join(CacheKey::TAux.to_string());
-        let t_aux_bytes = fs::read(&t_aux_path)
-            .with_context(|| format!("could not read file t_aux={:?}", t_aux_path))?;
-
-        let mut res: TemporaryAux<_, _> = deserialize(&t_aux_bytes)?;
-
-        // Switch t_aux to the passed in cache_path
-        res.set_cache_path(cache_path);
-        res
-    };
-
-    // Convert TemporaryAux to TemporaryAuxCache, which instantiates all
-    // elements based on the configs stored in TemporaryAux.
-    let t_aux_cache: TemporaryAuxCache<Tree, DefaultPieceHasher> =
-        TemporaryAuxCache::new(&t_aux, replica_path.as_ref().to_path_buf())
-            .context("failed to restore contents of t_aux")?;
+    let (p_aux, t_aux_cache) = read_aux::<Tree>(cache_path.as_ref(), replica_path.as_ref())?;
 
     let comm_r_safe = as_safe_commitment(&comm_r, "comm_r")?;
     let comm_d_safe = DefaultPieceDomain::try_from_bytes(&comm_d)?;
 
-    let replica_id = generate_replica_id::<Tree::Hasher, _>(
-        &prover_id,
-        sector_id.into(),
-        &ticket,
-        comm_d_safe,
-        &porep_config.porep_id,
-    );
+    let replica_id = replica_id(comm_d_safe);
 
     let public_inputs = stacked::PublicInputs {
         replica_id,
@@ -436,39 +1319,236 @@ pub fn seal_commit_phase1<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
     )?;
     ensure!(sanity_check, "Invalid vanilla proof generated");
 
-    let out = SealCommitPhase1Output {
+    Ok(SealCommitPhase1Output {
         vanilla_proofs,
         comm_r,
         comm_d,
         replica_id,
         seed,
         ticket,
+    })
+}
+
+/// Reads p_aux and t_aux from `cache_path`, and opens the layers and trees t_aux refers to.
+fn read_aux<Tree: 'static + MerkleTreeTrait>(
+    cache_path: &Path,
+    replica_path: &Path,
+) -> Result<(
+    PersistentAux<<Tree::Hasher as Hasher>::Domain>,
+    TemporaryAuxCache<Tree, DefaultPieceHasher>,
+)> {
+    let p_aux = {
+        let p_aux_path = cache_path.join(CacheKey::PAux.to_string());
+        let p_aux_bytes = retry_transient_io(|| {
+            fs::read(&p_aux_path)
+                .with_context(|| format!("could not read file p_aux={:?}", p_aux_path))
+        })?;
+
+        deserialize(&p_aux_bytes)
+    }?;
+
+    let t_aux = {
+        let t_aux_path = cache_path.join(CacheKey::TAux.to_string());
+        let t_aux_bytes = retry_transient_io(|| {
+            fs::read(&t_aux_path)
+                .with_context(|| format!("could not read file t_aux={:?}", t_aux_path))
+        })?;
+
+        let mut res: TemporaryAux<_, _> = deserialize(&t_aux_bytes)?;
+
+        // Switch t_aux to the passed in cache_path
+        res.set_cache_path(cache_path);
+        res
     };
 
-    info!("seal_commit_phase1:finish: {:?}", sector_id);
-    Ok(out)
+    // Convert TemporaryAux to TemporaryAuxCache, which instantiates all
+    // elements based on the configs stored in TemporaryAux.
+    let t_aux_cache: TemporaryAuxCache<Tree, DefaultPieceHasher> =
+        TemporaryAuxCache::new(&t_aux, replica_path.to_path_buf())
+            .context("failed to restore contents of t_aux")?;
+
+    Ok((p_aux, t_aux_cache))
+}
+
+/// Generates the vanilla proofs of all partitions of a seal from the sector cache, and verifies
+/// them against `public_inputs`.
+///
+/// This checks that the challenges of `public_inputs` can be answered from the layers and trees on
+/// disk before `seal_commit_phase2` spends minutes on the SNARKs, e.g. as a pre-flight check
+/// before committing on chain. No Groth parameters are needed, so this also works on machines
+/// which don't have them. `public_inputs.tau` has to be set. Like `seal_commit_phase1`, this needs
+/// the whole cache, so it is only possible before `clear_cache` is called.
+///
+/// Returns `false` if `comm_d` or `comm_r` don't match the cache, or if the proofs don't verify.
+/// Failing to generate the proofs is an error, e.g. for a missing cache, or if the layers were not
+/// labeled with `public_inputs.replica_id`.
+///
+/// # Arguments
+///
+/// * `porep_config` - this sector's porep config that contains the number of bytes in the sector.
+/// * `cache_path` - path to the sector's cache directory.
+/// * `replica_path` - path to the sealed replica.
+/// * `public_inputs` - the public inputs of the seal proof.
+pub fn verify_vanilla_seal<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: &Path,
+    replica_path: &Path,
+    public_inputs: &stacked::PublicInputs<<Tree::Hasher as Hasher>::Domain, DefaultPieceDomain>,
+) -> Result<bool, FilProofsError> {
+    classified(|| {
+        info!("verify_vanilla_seal:start");
+
+        ensure_input!(
+            public_inputs.tau.is_some(),
+            "public inputs without comm_d and comm_r cannot be verified"
+        );
+        ensure_replica_len(replica_path, porep_config.sector_size)?;
+
+        let (p_aux, t_aux_cache) = read_aux::<Tree>(cache_path, replica_path)?;
+        let tau = public_inputs.tau.as_ref().expect("tau is set");
+        if tau.comm_d != t_aux_cache.tree_d.root()
+            || tau.comm_r
+                != stacked::finalize_comm_r::<Tree::Hasher>(&p_aux.comm_c, &p_aux.comm_r_last)
+        {
+            info!("verify_vanilla_seal:finish: the commitments do not match the cache");
+            return Ok(false);
+        }
+
+        let private_inputs = stacked::PrivateInputs::<Tree, DefaultPieceHasher> {
+            p_aux,
+            t_aux: t_aux_cache,
+        };
+
+        let vanilla_params = setup_params(
+            PaddedBytesAmount::from(porep_config),
+            porep_config.partition_count(),
+            porep_config.porep_id,
+            porep_config.api_version,
+        )?;
+        let public_params = StackedDrg::<Tree, DefaultPieceHasher>::setup(&vanilla_params)?;
+
+        let vanilla_proofs = StackedDrg::prove_all_partitions(
+            &public_params,
+            public_inputs,
+            &private_inputs,
+            porep_config.partition_count(),
+        )?;
+        let valid = StackedDrg::<Tree, DefaultPieceHasher>::verify_all_partitions(
+            &public_params,
+            public_inputs,
+            &vanilla_proofs,
+        )?;
+
+        info!("verify_vanilla_seal:finish: {}", valid);
+        Ok(valid)
+    })
 }
 
 #[allow(clippy::too_many_arguments)]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "seal_commit_phase2",
+        skip_all,
+        fields(sector_id = u64::from(sector_id))
+    )
+)]
 pub fn seal_commit_phase2<Tree: 'static + MerkleTreeTrait>(
     porep_config: PoRepConfig,
     phase1_output: SealCommitPhase1Output<Tree>,
     prover_id: ProverId,
     sector_id: SectorId,
-) -> Result<SealCommitOutput> {
-    info!("seal_commit_phase2:start: {:?}", sector_id);
+) -> Result<SealCommitOutput, FilProofsError> {
+    classified(|| {
+        info!("seal_commit_phase2:start: {:?}", sector_id);
 
+        let SealCommitPhase1Output {
+            comm_d,
+            comm_r,
+            seed,
+            ticket,
+            ..
+        } = phase1_output;
+        let buf = seal_commit_phase2_inner(porep_config, phase1_output)?;
+
+        // Verification is cheap when parameters are cached,
+        // and it is never correct to return a proof which does not verify.
+        verify_seal::<Tree>(
+            porep_config,
+            comm_r,
+            comm_d,
+            prover_id,
+            sector_id,
+            ticket,
+            seed,
+            &buf,
+        )
+        .context("post-seal verification sanity check failed")?;
+
+        let out = SealCommitOutput { proof: buf };
+
+        info!("seal_commit_phase2:finish: {:?}", sector_id);
+        Ok(out)
+    })
+}
+
+/// Like [`seal_commit_phase2`] for the output of [`seal_commit_phase1_with_replica_id`], whose
+/// proof is verified against its replica id. Fails for every registered `porep_id`.
+///
+/// # Arguments
+///
+/// * `porep_config` - this sector's porep config, its `porep_id` must not be a registered one.
+/// * `phase1_output` - the output of `seal_commit_phase1_with_replica_id`.
+pub fn seal_commit_phase2_with_replica_id<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealCommitPhase1Output<Tree>,
+) -> Result<SealCommitOutput, FilProofsError> {
+    classified(|| {
+        info!("seal_commit_phase2_with_replica_id:start");
+        ensure_unregistered_porep_id(porep_config, "seal_commit_phase2_with_replica_id")?;
+
+        let SealCommitPhase1Output {
+            comm_d,
+            comm_r,
+            replica_id,
+            seed,
+            ..
+        } = phase1_output;
+        let buf = seal_commit_phase2_inner(porep_config, phase1_output)?;
+
+        verify_seal_for_replica_id::<Tree>(
+            porep_config,
+            comm_r,
+            comm_d,
+            replica_id,
+            seed,
+            &buf,
+            None,
+            None,
+        )
+        .context("post-seal verification sanity check failed")?;
+
+        info!("seal_commit_phase2_with_replica_id:finish");
+        Ok(SealCommitOutput { proof: buf })
+    })
+}
+
+/// Generates the SNARKs for the vanilla proofs of `phase1_output`, and returns them serialized.
+fn seal_commit_phase2_inner<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealCommitPhase1Output<Tree>,
+) -> Result<Vec<u8>> {
     let SealCommitPhase1Output {
         vanilla_proofs,
         comm_d,
         comm_r,
         replica_id,
         seed,
-        ticket,
+        ..
     } = phase1_output;
 
-    ensure!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
-    ensure!(comm_r != [0; 32], "Invalid all zero commitment (comm_r)");
+    ensure_input!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
+    ensure_input!(comm_r != [0; 32], "Invalid all zero commitment (comm_r)");
 
     let comm_r_safe = as_safe_commitment(&comm_r, "comm_r")?;
     let comm_d_safe = DefaultPieceDomain::try_from_bytes(&comm_d)?;
@@ -524,24 +1604,7 @@ pub fn seal_commit_phase2<Tree: 'static + MerkleTreeTrait>(
 
     proof.write(&mut buf)?;
 
-    // Verification is cheap when parameters are cached,
-    // and it is never correct to return a proof which does not verify.
-    verify_seal::<Tree>(
-        porep_config,
-        comm_r,
-        comm_d,
-        prover_id,
-        sector_id,
-        ticket,
-        seed,
-        &buf,
-    )
-    .context("post-seal verification sanity check failed")?;
-
-    let out = SealCommitOutput { proof: buf };
-
-    info!("seal_commit_phase2:finish: {:?}", sector_id);
-    Ok(out)
+    Ok(buf)
 }
 
 /// Given the specified arguments, this method returns the inputs that were used to
@@ -568,69 +1631,72 @@ pub fn get_seal_inputs<Tree: 'static + MerkleTreeTrait>(
     sector_id: SectorId,
     ticket: Ticket,
     seed: Ticket,
-) -> Result<Vec<Vec<Fr>>> {
-    trace!("get_seal_inputs:start");
-
-    ensure!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
-    ensure!(comm_r != [0; 32], "Invalid all zero commitment (comm_r)");
-
-    let replica_id = generate_replica_id::<Tree::Hasher, _>(
-        &prover_id,
-        sector_id.into(),
-        &ticket,
-        comm_d,
-        &porep_config.porep_id,
-    );
-
-    let comm_r_safe = as_safe_commitment(&comm_r, "comm_r")?;
-    let comm_d_safe = DefaultPieceDomain::try_from_bytes(&comm_d)?;
+) -> Result<Vec<Vec<Fr>>, FilProofsError> {
+    classified(|| {
+        trace!("get_seal_inputs:start");
 
-    let public_inputs = stacked::PublicInputs {
-        replica_id,
-        tau: Some(stacked::Tau {
-            comm_d: comm_d_safe,
-            comm_r: comm_r_safe,
-        }),
-        k: None,
-        seed,
-    };
+        ensure_input!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
+        ensure_input!(comm_r != [0; 32], "Invalid all zero commitment (comm_r)");
 
-    let compound_setup_params = compound_proof::SetupParams {
-        vanilla_params: setup_params(
-            PaddedBytesAmount::from(porep_config),
-            usize::from(PoRepProofPartitions::from(porep_config)),
-            porep_config.porep_id,
-            porep_config.api_version,
-        )?,
-        partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
-        priority: false,
-    };
+        let replica_id = generate_replica_id::<Tree::Hasher, _>(
+            &prover_id,
+            sector_id.into(),
+            &ticket,
+            comm_d,
+            &porep_config.porep_id,
+        );
 
-    let compound_public_params = <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
-        StackedDrg<'_, Tree, DefaultPieceHasher>,
-        _,
-    >>::setup(&compound_setup_params)?;
+        let comm_r_safe = as_safe_commitment(&comm_r, "comm_r")?;
+        let comm_d_safe = DefaultPieceDomain::try_from_bytes(&comm_d)?;
 
-    let partitions = <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
-        StackedDrg<'_, Tree, DefaultPieceHasher>,
-        _,
-    >>::partition_count(&compound_public_params);
-
-    // These are returned for aggregated proof verification.
-    let inputs: Vec<_> = (0..partitions)
-        .into_par_iter()
-        .map(|k| {
-            StackedCompound::<Tree, DefaultPieceHasher>::generate_public_inputs(
-                &public_inputs,
-                &compound_public_params.vanilla_params,
-                Some(k),
-            )
-        })
-        .collect::<Result<_>>()?;
+        let public_inputs = stacked::PublicInputs {
+            replica_id,
+            tau: Some(stacked::Tau {
+                comm_d: comm_d_safe,
+                comm_r: comm_r_safe,
+            }),
+            k: None,
+            seed,
+        };
 
-    trace!("get_seal_inputs:finish");
+        let compound_setup_params = compound_proof::SetupParams {
+            vanilla_params: setup_params(
+                PaddedBytesAmount::from(porep_config),
+                usize::from(PoRepProofPartitions::from(porep_config)),
+                porep_config.porep_id,
+                porep_config.api_version,
+            )?,
+            partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
+            priority: false,
+        };
 
-    Ok(inputs)
+        let compound_public_params =
+            <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
+                StackedDrg<'_, Tree, DefaultPieceHasher>,
+                _,
+            >>::setup(&compound_setup_params)?;
+
+        let partitions = <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
+            StackedDrg<'_, Tree, DefaultPieceHasher>,
+            _,
+        >>::partition_count(&compound_public_params);
+
+        // These are returned for aggregated proof verification.
+        let inputs: Vec<_> = (0..partitions)
+            .into_par_iter()
+            .map(|k| {
+                StackedCompound::<Tree, DefaultPieceHasher>::generate_public_inputs(
+                    &public_inputs,
+                    &compound_public_params.vanilla_params,
+                    Some(k),
+                )
+            })
+            .collect::<Result<_>>()?;
+
+        trace!("get_seal_inputs:finish");
+
+        Ok(inputs)
+    })
 }
 
 /// Given a value, get one suitable for aggregation.
@@ -649,11 +1715,11 @@ fn pad_proofs_to_target(proofs: &mut Vec<groth16::Proof<Bls12>>, target_len: usi
         target_len,
         proofs.len()
     );
-    ensure!(
+    ensure_input!(
         target_len >= proofs.len(),
         "target len must be greater than actual num proofs"
     );
-    ensure!(
+    ensure_input!(
         proofs.last().is_some(),
         "invalid last proof for duplication"
     );
@@ -667,11 +1733,11 @@ fn pad_proofs_to_target(proofs: &mut Vec<groth16::Proof<Bls12>>, target_len: usi
         .collect();
     proofs.append(&mut padding);
 
-    ensure!(
+    ensure_input!(
         proofs.len().next_power_of_two() == proofs.len(),
         "proof count must be a power of 2 for aggregation"
     );
-    ensure!(
+    ensure_input!(
         proofs.len() <= SRS_MAX_PROOFS_TO_AGGREGATE,
         "proof count for aggregation is larger than the max supported value"
     );
@@ -685,7 +1751,7 @@ fn pad_inputs_to_target(
     num_inputs_per_proof: usize,
     target_len: usize,
 ) -> Result<Vec<Vec<Fr>>> {
-    ensure!(
+    ensure_input!(
         !commit_inputs.is_empty(),
         "cannot aggregate with empty public inputs"
     );
@@ -694,7 +1760,7 @@ fn pad_inputs_to_target(
     let mut new_inputs = commit_inputs.to_owned();
 
     if target_len != num_inputs {
-        ensure!(
+        ensure_input!(
             target_len > num_inputs,
             "target len must be greater than actual num inputs"
         );
@@ -714,6 +1780,9 @@ fn pad_inputs_to_target(
 /// those proofs (naively padding the count if necessary up to a power of 2) and
 /// returns the aggregate proof bytes.
 ///
+/// The aggregate only depends on its inputs, aggregating the same outputs again returns the same
+/// bytes. `comm_rs` and `seeds` hold the values of each commit output, in the same order.
+///
 /// # Arguments
 ///
 /// * `porep_config` - this sector's porep config that contains the number of bytes in the sector.
@@ -724,73 +1793,82 @@ pub fn aggregate_seal_commit_proofs<Tree: 'static + MerkleTreeTrait>(
     comm_rs: &[[u8; 32]],
     seeds: &[[u8; 32]],
     commit_outputs: &[SealCommitOutput],
-) -> Result<AggregateSnarkProof> {
-    info!("aggregate_seal_commit_proofs:start");
-
-    ensure!(
-        !commit_outputs.is_empty(),
-        "cannot aggregate with empty outputs"
-    );
+) -> Result<AggregateSnarkProof, FilProofsError> {
+    classified(|| {
+        info!("aggregate_seal_commit_proofs:start");
 
-    let partitions = usize::from(PoRepProofPartitions::from(porep_config));
-    let verifying_key = get_stacked_verifying_key::<Tree>(porep_config)?;
-    let mut proofs: Vec<_> =
-        commit_outputs
-            .iter()
-            .try_fold(Vec::new(), |mut acc, commit_output| -> Result<_> {
-                acc.extend(
-                    MultiProof::new_from_reader(
-                        Some(partitions),
-                        &commit_output.proof[..],
-                        &verifying_key,
-                    )?
-                    .circuit_proofs,
-                );
-
-                Ok(acc)
-            })?;
-    trace!(
-        "aggregate_seal_commit_proofs called with {} commit_outputs containing {} proofs",
-        commit_outputs.len(),
-        proofs.len(),
-    );
+        ensure_input!(
+            !commit_outputs.is_empty(),
+            "cannot aggregate with empty outputs"
+        );
+        ensure_input!(
+            comm_rs.len() == commit_outputs.len() && seeds.len() == commit_outputs.len(),
+            "expected a comm_r and a seed for each of the {} commit outputs, got {} and {}",
+            commit_outputs.len(),
+            comm_rs.len(),
+            seeds.len()
+        );
 
-    let target_proofs_len = get_aggregate_target_len(proofs.len());
-    ensure!(
-        target_proofs_len > 1,
-        "cannot aggregate less than two proofs"
-    );
-    trace!(
-        "aggregate_seal_commit_proofs will pad proofs to target_len {}",
-        target_proofs_len
-    );
+        let partitions = usize::from(PoRepProofPartitions::from(porep_config));
+        let verifying_key = get_stacked_verifying_key::<Tree>(porep_config)?;
+        let mut proofs: Vec<_> =
+            commit_outputs
+                .iter()
+                .try_fold(Vec::new(), |mut acc, commit_output| -> Result<_> {
+                    acc.extend(
+                        MultiProof::new_from_reader(
+                            Some(partitions),
+                            &commit_output.proof[..],
+                            &verifying_key,
+                        )?
+                        .circuit_proofs,
+                    );
+
+                    Ok(acc)
+                })?;
+        trace!(
+            "aggregate_seal_commit_proofs called with {} commit_outputs containing {} proofs",
+            commit_outputs.len(),
+            proofs.len(),
+        );
 
-    // If we're not at the pow2 target, duplicate the last proof until we are.
-    pad_proofs_to_target(&mut proofs, target_proofs_len)?;
+        let target_proofs_len = get_aggregate_target_len(proofs.len());
+        ensure_input!(
+            target_proofs_len > 1,
+            "cannot aggregate less than two proofs"
+        );
+        trace!(
+            "aggregate_seal_commit_proofs will pad proofs to target_len {}",
+            target_proofs_len
+        );
 
-    // Hash all of the seeds and comm_r's pair-wise into a digest for the aggregate proof method.
-    let hashed_seeds_and_comm_rs: [u8; 32] = {
-        let mut hasher = Sha256::new();
-        for cur in seeds.iter().zip(comm_rs.iter()) {
-            let (seed, comm_r) = cur;
-            hasher.update(seed);
-            hasher.update(comm_r);
-        }
-        hasher.finalize().into()
-    };
+        // If we're not at the pow2 target, duplicate the last proof until we are.
+        pad_proofs_to_target(&mut proofs, target_proofs_len)?;
+
+        // Hash all of the seeds and comm_r's pair-wise into a digest for the aggregate proof method.
+        let hashed_seeds_and_comm_rs: [u8; 32] = {
+            let mut hasher = Sha256::new();
+            for cur in seeds.iter().zip(comm_rs.iter()) {
+                let (seed, comm_r) = cur;
+                hasher.update(seed);
+                hasher.update(comm_r);
+            }
+            hasher.finalize().into()
+        };
 
-    let srs_prover_key = get_stacked_srs_key::<Tree>(porep_config, proofs.len())?;
-    let aggregate_proof = StackedCompound::<Tree, DefaultPieceHasher>::aggregate_proofs(
-        &srs_prover_key,
-        &hashed_seeds_and_comm_rs,
-        proofs.as_slice(),
-    )?;
-    let mut aggregate_proof_bytes = Vec::new();
-    aggregate_proof.write(&mut aggregate_proof_bytes)?;
+        let srs_prover_key = get_stacked_srs_key::<Tree>(porep_config, proofs.len())?;
+        let aggregate_proof = StackedCompound::<Tree, DefaultPieceHasher>::aggregate_proofs(
+            &srs_prover_key,
+            &hashed_seeds_and_comm_rs,
+            proofs.as_slice(),
+        )?;
+        let mut aggregate_proof_bytes = Vec::new();
+        aggregate_proof.write(&mut aggregate_proof_bytes)?;
 
-    info!("aggregate_seal_commit_proofs:finish");
+        info!("aggregate_seal_commit_proofs:finish");
 
-    Ok(aggregate_proof_bytes)
+        Ok(aggregate_proof_bytes)
+    })
 }
 
 /// Given a porep_config, an aggregate proof, a list of seeds and a combined and flattened list
@@ -798,114 +1876,430 @@ pub fn aggregate_seal_commit_proofs<Tree: 'static + MerkleTreeTrait>(
 ///
 /// # Arguments
 ///
-/// * `porep_config` - this sector's porep config that contains the number of bytes in the sector.
-/// * `seeds` - an ordered list of seeds used to derive the PoRep challenges.
-/// * `aggregate_proof_bytes` - the returned aggregate proof from 'aggreate_seal_commit_proofs'.
-/// * `commit_inputs` - a flattened/combined and ordered list of all public inputs, which must match
-///    the ordering of the seal proofs when aggregated.
-pub fn verify_aggregate_seal_commit_proofs<Tree: 'static + MerkleTreeTrait>(
+/// * `porep_config` - this sector's porep config that contains the number of bytes in the sector.
+/// * `seeds` - an ordered list of seeds used to derive the PoRep challenges.
+/// * `aggregate_proof_bytes` - the returned aggregate proof from 'aggreate_seal_commit_proofs'.
+/// * `commit_inputs` - a flattened/combined and ordered list of all public inputs, which must match
+///    the ordering of the seal proofs when aggregated.
+///
+/// The comm_rs and the number of inputs are checked like the public inputs of a single seal proof
+/// by [`validate_public_inputs`], and an error is returned for malformed ones.
+///
+/// Returns `false` if any of the aggregated proofs, including those duplicated as padding, is
+/// invalid for its inputs.
+pub fn verify_aggregate_seal_commit_proofs<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    aggregate_proof_bytes: AggregateSnarkProof,
+    comm_rs: &[[u8; 32]],
+    seeds: &[[u8; 32]],
+    commit_inputs: Vec<Vec<Fr>>,
+) -> Result<bool, FilProofsError> {
+    classified(|| {
+        info!("verify_aggregate_seal_commit_proofs:start");
+
+        let aggregate_proof =
+            groth16::aggregate::AggregateProof::read(std::io::Cursor::new(&aggregate_proof_bytes))?;
+
+        let aggregated_proofs_len = aggregate_proof.tmipp.gipa.nproofs as usize;
+
+        ensure_input!(aggregated_proofs_len != 0, "cannot verify zero proofs");
+        ensure_input!(!commit_inputs.is_empty(), "cannot verify with empty inputs");
+        ensure_input!(
+            comm_rs.len() == seeds.len(),
+            "invalid comm_rs and seeds len mismatch"
+        );
+        porep_config.validate_porep_id()?;
+        porep_config.validate_tree_c_arity::<Tree>()?;
+        validate_seal_config(porep_config)?;
+        for comm_r in comm_rs {
+            validate_seal_comm_r(comm_r)?;
+        }
+        // Every sector contributes the inputs of each of its partitions, which all have the same
+        // number of challenges.
+        let partitions = porep_config.partition_count();
+        ensure_input!(
+            commit_inputs.len() == comm_rs.len() * partitions,
+            "expected the inputs of {} partitions for {} sectors, got {}",
+            partitions,
+            comm_rs.len(),
+            commit_inputs.len()
+        );
+        ensure_input!(
+            commit_inputs
+                .iter()
+                .all(|inputs| inputs.len() == commit_inputs[0].len()),
+            "the inputs of all partitions have to be of the same length"
+        );
+
+        trace!(
+            "verify_aggregate_seal_commit_proofs called with len {}",
+            aggregated_proofs_len,
+        );
+
+        ensure_input!(
+            aggregated_proofs_len > 1,
+            "cannot verify less than two proofs"
+        );
+        ensure_input!(
+            aggregated_proofs_len == aggregated_proofs_len.next_power_of_two(),
+            "cannot verify non-pow2 aggregate seal proofs"
+        );
+
+        let num_inputs = commit_inputs.len();
+        let num_inputs_per_proof = get_aggregate_target_len(num_inputs) / aggregated_proofs_len;
+        let target_inputs_len = aggregated_proofs_len * num_inputs_per_proof;
+        ensure_input!(
+            target_inputs_len % aggregated_proofs_len == 0,
+            "invalid number of inputs provided",
+        );
+
+        trace!(
+            "verify_aggregate_seal_commit_proofs got {} inputs with {} inputs per proof",
+            num_inputs,
+            target_inputs_len / aggregated_proofs_len,
+        );
+
+        // Pad public inputs if needed.
+        let commit_inputs =
+            pad_inputs_to_target(&commit_inputs, num_inputs_per_proof, target_inputs_len)?;
+
+        let verifying_key = get_stacked_verifying_key::<Tree>(porep_config)?;
+        let srs_verifier_key =
+            get_stacked_srs_verifier_key::<Tree>(porep_config, aggregated_proofs_len)?;
+
+        // Hash all of the seeds and comm_r's pair-wise into a digest for the aggregate proof method.
+        let hashed_seeds_and_comm_rs: [u8; 32] = {
+            let mut hasher = Sha256::new();
+            for cur in seeds.iter().zip(comm_rs.iter()) {
+                let (seed, comm_r) = cur;
+                hasher.update(seed);
+                hasher.update(comm_r);
+            }
+            hasher.finalize().into()
+        };
+
+        trace!("start verifying aggregate proof");
+        let result = StackedCompound::<Tree, DefaultPieceHasher>::verify_aggregate_proofs(
+            &srs_verifier_key,
+            &verifying_key,
+            &hashed_seeds_and_comm_rs,
+            commit_inputs.as_slice(),
+            &aggregate_proof,
+        )?;
+        trace!("end verifying aggregate proof");
+
+        info!("verify_aggregate_seal_commit_proofs:finish");
+
+        Ok(result)
+    })
+}
+
+/// Computes a sectors's `comm_d` given its pieces.
+///
+/// # Arguments
+///
+/// * `porep_config` - this sector's porep config that contains the number of bytes in the sector.
+/// * `piece_infos` - the piece info (commitment and byte length) for each piece in this sector.
+pub fn compute_comm_d(
+    sector_size: SectorSize,
+    piece_infos: &[PieceInfo],
+) -> Result<Commitment, FilProofsError> {
+    classified(|| {
+        trace!("compute_comm_d:start");
+
+        let result = pieces::compute_comm_d(sector_size, piece_infos);
+
+        trace!("compute_comm_d:finish");
+        result
+    })
+}
+
+/// Ensures that the pieces of a sector can be packed in the given order, see
+/// [`pieces::validate_piece_layout`].
+///
+/// `seal_pre_commit_phase1` runs this check unless `SETTINGS.skip_piece_layout_validation` is set.
+///
+/// # Arguments
+///
+/// * `sector_size` - the number of bytes in the sector.
+/// * `piece_infos` - the piece info (commitment and byte length) for each piece in this sector.
+pub fn validate_piece_layout(
+    sector_size: SectorSize,
+    piece_infos: &[PieceInfo],
+) -> Result<(), FilProofsError> {
+    classified(|| pieces::validate_piece_layout(piece_infos, sector_size))
+}
+
+/// Checks that the public inputs of a seal proof are well-formed for `porep_config`, without
+/// verifying the proof.
+///
+/// The verify functions run these checks before loading the verifying key, so this is for
+/// rejecting malformed inputs up front, e.g. before queueing proofs for verification. The error
+/// names the malformed input. Inputs which pass can still belong to an invalid proof.
+///
+/// # Arguments
+///
+/// * `pub_inputs` - the public inputs of the seal proof.
+/// * `porep_config` - the sector's porep config.
+pub fn validate_public_inputs(
+    pub_inputs: &SealPublicInputs,
     porep_config: PoRepConfig,
-    aggregate_proof_bytes: AggregateSnarkProof,
-    comm_rs: &[[u8; 32]],
-    seeds: &[[u8; 32]],
-    commit_inputs: Vec<Vec<Fr>>,
-) -> Result<bool> {
-    info!("verify_aggregate_seal_commit_proofs:start");
+) -> Result<(), FilProofsError> {
+    classified(|| validate_seal_public_inputs(pub_inputs, porep_config))
+}
 
-    let aggregate_proof =
-        groth16::aggregate::AggregateProof::read(std::io::Cursor::new(&aggregate_proof_bytes))?;
+fn validate_seal_public_inputs(
+    pub_inputs: &SealPublicInputs,
+    porep_config: PoRepConfig,
+) -> Result<()> {
+    ensure_input!(
+        pub_inputs.comm_d != [0; 32],
+        "Invalid all zero commitment (comm_d)"
+    );
+    as_safe_commitment::<DefaultPieceDomain, _>(&pub_inputs.comm_d, "comm_d")?;
+    validate_seal_comm_r(&pub_inputs.comm_r)?;
 
-    let aggregated_proofs_len = aggregate_proof.tmipp.gipa.nproofs as usize;
+    validate_seal_config(porep_config)
+}
 
-    ensure!(aggregated_proofs_len != 0, "cannot verify zero proofs");
-    ensure!(!commit_inputs.is_empty(), "cannot verify with empty inputs");
-    ensure!(
-        comm_rs.len() == seeds.len(),
-        "invalid comm_rs and seeds len mismatch"
-    );
+fn validate_seal_comm_r(comm_r: &Commitment) -> Result<()> {
+    ensure_input!(*comm_r != [0; 32], "Invalid all zero commitment (comm_r)");
+    as_safe_commitment::<DefaultTreeDomain, _>(comm_r, "comm_r")?;
 
-    trace!(
-        "verify_aggregate_seal_commit_proofs called with len {}",
-        aggregated_proofs_len,
-    );
+    Ok(())
+}
 
-    ensure!(
-        aggregated_proofs_len > 1,
-        "cannot verify less than two proofs"
+fn validate_seal_config(porep_config: PoRepConfig) -> Result<()> {
+    let sector_size = u64::from(porep_config.sector_size);
+    ensure_input!(
+        POREP_MINIMUM_CHALLENGES
+            .read()
+            .expect("POREP_MINIMUM_CHALLENGES poisoned")
+            .contains_key(&sector_size)
+            && LAYERS
+                .read()
+                .expect("LAYERS poisoned")
+                .contains_key(&sector_size),
+        "Unsupported sector size {}",
+        sector_size
     );
-    ensure!(
-        aggregated_proofs_len == aggregated_proofs_len.next_power_of_two(),
-        "cannot verify non-pow2 aggregate seal proofs"
+    ensure_input!(
+        porep_config.partition_count() > 0,
+        "Invalid partition count 0"
     );
 
-    let num_inputs = commit_inputs.len();
-    let num_inputs_per_proof = get_aggregate_target_len(num_inputs) / aggregated_proofs_len;
-    let target_inputs_len = aggregated_proofs_len * num_inputs_per_proof;
-    ensure!(
-        target_inputs_len % aggregated_proofs_len == 0,
-        "invalid number of inputs provided",
-    );
+    Ok(())
+}
 
-    trace!(
-        "verify_aggregate_seal_commit_proofs got {} inputs with {} inputs per proof",
-        num_inputs,
-        target_inputs_len / aggregated_proofs_len,
-    );
+/// Verifies the output of some previously-run seal operation.
+///
+/// Proofs are verified with the logic of `porep_config.api_version`, so proofs of older sectors
+/// are verified by passing the api version they were sealed with. For registered proof types, it
+/// has to be the api version encoded in `porep_id`.
+///
+/// There is no separate legacy verifier. The api versions only differ in the parents of a node:
+/// where the immediate predecessor is among its DRG parents, see `BucketGraph::parents`, and how
+/// its expander parents are truncated, see `StackedGraph::correspondent`. Proving and verifying
+/// both build the graph for the api version of the public parameters, so a 1.0.0 proof is checked
+/// against the parents it was produced with.
+///
+/// # Arguments
+///
+/// * `porep_config` - this sector's porep config that contains the number of bytes in this sector.
+/// * `comm_r_in` - commitment to the sector's replica (`comm_r`).
+/// * `comm_d_in` - commitment to the sector's data (`comm_d`).
+/// * `prover_id` - the prover-id that sealed this sector.
+/// * `sector_id` - this sector's sector-id.
+/// * `ticket` - the ticket that was used to generate this sector's replica-id.
+/// * `seed` - the seed used to derive the porep challenges.
+/// * `proof_vec` - the porep circuit proof serialized into a vector of bytes.
+#[allow(clippy::too_many_arguments)]
+pub fn verify_seal<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    comm_r_in: Commitment,
+    comm_d_in: Commitment,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    seed: Ticket,
+    proof_vec: &[u8],
+) -> Result<bool, FilProofsError> {
+    classified(|| {
+        info!("verify_seal:start: {:?}", sector_id);
+
+        let result = verify_seal_inner::<Tree>(
+            porep_config,
+            comm_r_in,
+            comm_d_in,
+            prover_id,
+            sector_id,
+            ticket,
+            seed,
+            proof_vec,
+            None,
+            None,
+        );
 
-    // Pad public inputs if needed.
-    let commit_inputs =
-        pad_inputs_to_target(&commit_inputs, num_inputs_per_proof, target_inputs_len)?;
-
-    let verifying_key = get_stacked_verifying_key::<Tree>(porep_config)?;
-    let srs_verifier_key =
-        get_stacked_srs_verifier_key::<Tree>(porep_config, aggregated_proofs_len)?;
-
-    // Hash all of the seeds and comm_r's pair-wise into a digest for the aggregate proof method.
-    let hashed_seeds_and_comm_rs: [u8; 32] = {
-        let mut hasher = Sha256::new();
-        for cur in seeds.iter().zip(comm_rs.iter()) {
-            let (seed, comm_r) = cur;
-            hasher.update(seed);
-            hasher.update(comm_r);
-        }
-        hasher.finalize().into()
-    };
+        info!("verify_seal:finish: {:?}", sector_id);
+        result
+    })
+}
 
-    trace!("start verifying aggregate proof");
-    let result = StackedCompound::<Tree, DefaultPieceHasher>::verify_aggregate_proofs(
-        &srs_verifier_key,
-        &verifying_key,
-        &hashed_seeds_and_comm_rs,
-        commit_inputs.as_slice(),
-        &aggregate_proof,
-    )?;
-    trace!("end verifying aggregate proof");
+/// Verifies a single partition of the output of some previously-run seal operation.
+///
+/// The proof is valid exactly when every partition in `0..porep_config.partition_count()`
+/// verifies, which allows spreading the verification of one proof across workers.
+///
+/// # Arguments
+///
+/// * `porep_config` - this sector's porep config that contains the number of bytes in this sector.
+/// * `comm_r_in` - commitment to the sector's replica (`comm_r`).
+/// * `comm_d_in` - commitment to the sector's data (`comm_d`).
+/// * `prover_id` - the prover-id that sealed this sector.
+/// * `sector_id` - this sector's sector-id.
+/// * `ticket` - the ticket that was used to generate this sector's replica-id.
+/// * `seed` - the seed used to derive the porep challenges.
+/// * `proof_vec` - the porep circuit proof serialized into a vector of bytes.
+/// * `partition_index` - the index of the partition to verify.
+#[allow(clippy::too_many_arguments)]
+pub fn verify_seal_partition<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    comm_r_in: Commitment,
+    comm_d_in: Commitment,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    seed: Ticket,
+    proof_vec: &[u8],
+    partition_index: usize,
+) -> Result<bool, FilProofsError> {
+    classified(|| {
+        info!(
+            "verify_seal_partition:start: {:?} {}",
+            sector_id, partition_index
+        );
+        ensure_input!(
+            partition_index < porep_config.partition_count(),
+            "Invalid partition index {} for {} partitions",
+            partition_index,
+            porep_config.partition_count()
+        );
 
-    info!("verify_aggregate_seal_commit_proofs:finish");
+        let result = verify_seal_inner::<Tree>(
+            porep_config,
+            comm_r_in,
+            comm_d_in,
+            prover_id,
+            sector_id,
+            ticket,
+            seed,
+            proof_vec,
+            Some(partition_index),
+            None,
+        );
 
-    Ok(result)
+        info!(
+            "verify_seal_partition:finish: {:?} {}",
+            sector_id, partition_index
+        );
+        result
+    })
 }
 
-/// Computes a sectors's `comm_d` given its pieces.
+/// Returns the node indices the challenges of `partition` resolve to for `public_inputs`.
+///
+/// These are the nodes opened by the vanilla proofs of that partition, which makes it possible to
+/// compare what a verifier expects against what a prover generated when a seal proof fails to
+/// verify.
 ///
 /// # Arguments
 ///
-/// * `porep_config` - this sector's porep config that contains the number of bytes in the sector.
-/// * `piece_infos` - the piece info (commitment and byte length) for each piece in this sector.
-pub fn compute_comm_d(sector_size: SectorSize, piece_infos: &[PieceInfo]) -> Result<Commitment> {
-    trace!("compute_comm_d:start");
+/// * `porep_config` - this sector's porep config that contains the number of bytes in this sector.
+/// * `public_inputs` - the public inputs of the seal proof.
+/// * `partition` - the index of the partition to return the challenged nodes of.
+pub fn porep_challenged_nodes<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    public_inputs: &stacked::PublicInputs<<Tree::Hasher as Hasher>::Domain, DefaultPieceDomain>,
+    partition: usize,
+) -> Result<Vec<usize>, FilProofsError> {
+    classified(|| {
+        ensure_input!(
+            partition < porep_config.partition_count(),
+            "Invalid partition index {} for {} partitions",
+            partition,
+            porep_config.partition_count()
+        );
+
+        let vanilla_params = setup_params(
+            PaddedBytesAmount::from(porep_config),
+            porep_config.partition_count(),
+            porep_config.porep_id,
+            porep_config.api_version,
+        )?;
+        let public_params = StackedDrg::<Tree, DefaultPieceHasher>::setup(&vanilla_params)?;
+
+        Ok(public_inputs.challenges(
+            &public_params.layer_challenges,
+            public_params.graph.size(),
+            Some(partition),
+        ))
+    })
+}
 
-    let result = pieces::compute_comm_d(sector_size, piece_infos);
+/// Verifies the output of some previously-run seal operation against the given `replica_id`,
+/// instead of deriving it from the prover id, sector id, ticket and `comm_d`.
+///
+/// This exists for conformance testing against other implementations, which need to feed the
+/// exact replica id another prover used. It must never be used to verify sectors of the Filecoin
+/// networks, so it fails for every registered `porep_id`.
+///
+/// # Arguments
+///
+/// * `porep_config` - this sector's porep config, its `porep_id` must not be a registered one.
+/// * `comm_r_in` - commitment to the sector's replica (`comm_r`).
+/// * `comm_d_in` - commitment to the sector's data (`comm_d`).
+/// * `replica_id` - the replica-id the sector was sealed with.
+/// * `seed` - the seed used to derive the porep challenges.
+/// * `proof_vec` - the porep circuit proof serialized into a vector of bytes.
+pub fn verify_seal_with_replica_id<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    comm_r_in: Commitment,
+    comm_d_in: Commitment,
+    replica_id: ReplicaId,
+    seed: Ticket,
+    proof_vec: &[u8],
+) -> Result<bool, FilProofsError> {
+    classified(|| {
+        info!("verify_seal_with_replica_id:start");
+        ensure_unregistered_porep_id(porep_config, "verify_seal_with_replica_id")?;
+
+        let result = verify_seal_for_replica_id::<Tree>(
+            porep_config,
+            comm_r_in,
+            comm_d_in,
+            replica_id.into(),
+            seed,
+            proof_vec,
+            None,
+            None,
+        );
 
-    trace!("compute_comm_d:finish");
-    result
+        info!("verify_seal_with_replica_id:finish");
+        result
+    })
 }
 
-/// Verifies the output of some previously-run seal operation.
+/// Verifies the output of some previously-run seal operation against the given `verifying_key`,
+/// instead of the one from the parameter cache.
+///
+/// This doesn't access the disk, which lets callers that hold the verifying keys in memory (or
+/// load them from their own storage) verify seal proofs without a parameter cache. The caller is
+/// responsible for passing the verifying key matching `porep_config`.
 ///
 /// # Arguments
 ///
 /// * `porep_config` - this sector's porep config that contains the number of bytes in this sector.
+/// * `verifying_key` - the prepared verifying key of the porep circuit for `porep_config`.
 /// * `comm_r_in` - commitment to the sector's replica (`comm_r`).
 /// * `comm_d_in` - commitment to the sector's data (`comm_d`).
 /// * `prover_id` - the prover-id that sealed this sector.
@@ -914,8 +2308,9 @@ pub fn compute_comm_d(sector_size: SectorSize, piece_infos: &[PieceInfo]) -> Res
 /// * `seed` - the seed used to derive the porep challenges.
 /// * `proof_vec` - the porep circuit proof serialized into a vector of bytes.
 #[allow(clippy::too_many_arguments)]
-pub fn verify_seal<Tree: 'static + MerkleTreeTrait>(
+pub fn verify_seal_with_verifying_key<Tree: 'static + MerkleTreeTrait>(
     porep_config: PoRepConfig,
+    verifying_key: &groth16::PreparedVerifyingKey<Bls12>,
     comm_r_in: Commitment,
     comm_d_in: Commitment,
     prover_id: ProverId,
@@ -923,14 +2318,54 @@ pub fn verify_seal<Tree: 'static + MerkleTreeTrait>(
     ticket: Ticket,
     seed: Ticket,
     proof_vec: &[u8],
-) -> Result<bool> {
-    info!("verify_seal:start: {:?}", sector_id);
+) -> Result<bool, FilProofsError> {
+    classified(|| {
+        info!("verify_seal_with_verifying_key:start: {:?}", sector_id);
+
+        let result = verify_seal_inner::<Tree>(
+            porep_config,
+            comm_r_in,
+            comm_d_in,
+            prover_id,
+            sector_id,
+            ticket,
+            seed,
+            proof_vec,
+            None,
+            Some(verifying_key),
+        );
 
-    ensure!(comm_d_in != [0; 32], "Invalid all zero commitment (comm_d)");
-    ensure!(comm_r_in != [0; 32], "Invalid all zero commitment (comm_r)");
-    ensure!(!proof_vec.is_empty(), "Invalid proof bytes (empty vector)");
+        info!("verify_seal_with_verifying_key:finish: {:?}", sector_id);
+        result
+    })
+}
 
-    let comm_r: <Tree::Hasher as Hasher>::Domain = as_safe_commitment(&comm_r_in, "comm_r")?;
+/// Verifies all partitions of a seal proof, or only `partition_index` if given.
+/// The verifying key is loaded from the parameter cache unless `verifying_key` is given.
+#[allow(clippy::too_many_arguments)]
+fn verify_seal_inner<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    comm_r_in: Commitment,
+    comm_d_in: Commitment,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    seed: Ticket,
+    proof_vec: &[u8],
+    partition_index: Option<usize>,
+    verifying_key: Option<&groth16::PreparedVerifyingKey<Bls12>>,
+) -> Result<bool> {
+    validate_seal_public_inputs(
+        &SealPublicInputs {
+            comm_r: comm_r_in,
+            comm_d: comm_d_in,
+            prover_id,
+            sector_id,
+            ticket,
+            seed,
+        },
+        porep_config,
+    )?;
     let comm_d: DefaultPieceDomain = as_safe_commitment(&comm_d_in, "comm_d")?;
 
     let replica_id = generate_replica_id::<Tree::Hasher, _>(
@@ -941,6 +2376,38 @@ pub fn verify_seal<Tree: 'static + MerkleTreeTrait>(
         &porep_config.porep_id,
     );
 
+    verify_seal_for_replica_id::<Tree>(
+        porep_config,
+        comm_r_in,
+        comm_d_in,
+        replica_id,
+        seed,
+        proof_vec,
+        partition_index,
+        verifying_key,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn verify_seal_for_replica_id<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    comm_r_in: Commitment,
+    comm_d_in: Commitment,
+    replica_id: <Tree::Hasher as Hasher>::Domain,
+    seed: Ticket,
+    proof_vec: &[u8],
+    partition_index: Option<usize>,
+    verifying_key: Option<&groth16::PreparedVerifyingKey<Bls12>>,
+) -> Result<bool> {
+    ensure_input!(comm_d_in != [0; 32], "Invalid all zero commitment (comm_d)");
+    ensure_input!(comm_r_in != [0; 32], "Invalid all zero commitment (comm_r)");
+    ensure_input!(!proof_vec.is_empty(), "Invalid proof bytes (empty vector)");
+    porep_config.validate_porep_id()?;
+    porep_config.validate_tree_c_arity::<Tree>()?;
+
+    let comm_r: <Tree::Hasher as Hasher>::Domain = as_safe_commitment(&comm_r_in, "comm_r")?;
+    let comm_d: DefaultPieceDomain = as_safe_commitment(&comm_d_in, "comm_d")?;
+
     let compound_setup_params = compound_proof::SetupParams {
         vanilla_params: setup_params(
             PaddedBytesAmount::from(porep_config),
@@ -965,37 +2432,50 @@ pub fn verify_seal<Tree: 'static + MerkleTreeTrait>(
             k: None,
         };
 
-    let result = {
-        let sector_bytes = PaddedBytesAmount::from(porep_config);
-        let verifying_key = get_stacked_verifying_key::<Tree>(porep_config)?;
+    let sector_bytes = PaddedBytesAmount::from(porep_config);
+    let loaded_verifying_key;
+    let verifying_key = match verifying_key {
+        Some(verifying_key) => verifying_key,
+        None => {
+            loaded_verifying_key = get_stacked_verifying_key::<Tree>(porep_config)?;
+            &*loaded_verifying_key
+        }
+    };
 
-        trace!(
-            "got verifying key ({}) while verifying seal",
-            u64::from(sector_bytes)
-        );
+    trace!(
+        "got verifying key ({}) while verifying seal",
+        u64::from(sector_bytes)
+    );
 
-        let proof = MultiProof::new_from_reader(
-            Some(usize::from(PoRepProofPartitions::from(porep_config))),
-            proof_vec,
-            &verifying_key,
-        )?;
+    let proof = MultiProof::new_from_reader(
+        Some(usize::from(PoRepProofPartitions::from(porep_config))),
+        proof_vec,
+        verifying_key,
+    )?;
 
-        StackedCompound::verify(
+    let requirements = ChallengeRequirements {
+        minimum_challenges: *POREP_MINIMUM_CHALLENGES
+            .read()
+            .expect("POREP_MINIMUM_CHALLENGES poisoned")
+            .get(&u64::from(SectorSize::from(porep_config)))
+            .expect("unknown sector size") as usize,
+    };
+
+    match partition_index {
+        Some(k) => StackedCompound::verify_partition(
             &compound_public_params,
             &public_inputs,
             &proof,
-            &ChallengeRequirements {
-                minimum_challenges: *POREP_MINIMUM_CHALLENGES
-                    .read()
-                    .expect("POREP_MINIMUM_CHALLENGES poisoned")
-                    .get(&u64::from(SectorSize::from(porep_config)))
-                    .expect("unknown sector size") as usize,
-            },
-        )
-    };
-
-    info!("verify_seal:finish: {:?}", sector_id);
-    result
+            &requirements,
+            k,
+        ),
+        None => StackedCompound::verify(
+            &compound_public_params,
+            &public_inputs,
+            &proof,
+            &requirements,
+        ),
+    }
 }
 
 /// Verifies a batch of outputs of some previously-run seal operations.
@@ -1020,103 +2500,108 @@ pub fn verify_batch_seal<Tree: 'static + MerkleTreeTrait>(
     tickets: &[Ticket],
     seeds: &[Ticket],
     proof_vecs: &[&[u8]],
-) -> Result<bool> {
-    info!("verify_batch_seal:start");
-    ensure!(!comm_r_ins.is_empty(), "Cannot prove empty batch");
-    let l = comm_r_ins.len();
-    ensure!(l == comm_d_ins.len(), "Inconsistent inputs");
-    ensure!(l == prover_ids.len(), "Inconsistent inputs");
-    ensure!(l == prover_ids.len(), "Inconsistent inputs");
-    ensure!(l == sector_ids.len(), "Inconsistent inputs");
-    ensure!(l == tickets.len(), "Inconsistent inputs");
-    ensure!(l == seeds.len(), "Inconsistent inputs");
-    ensure!(l == proof_vecs.len(), "Inconsistent inputs");
-
-    for comm_d_in in comm_d_ins {
-        ensure!(
-            comm_d_in != &[0; 32],
-            "Invalid all zero commitment (comm_d)"
-        );
-    }
-    for comm_r_in in comm_r_ins {
-        ensure!(
-            comm_r_in != &[0; 32],
-            "Invalid all zero commitment (comm_r)"
-        );
-    }
-    for proofs in proof_vecs {
-        ensure!(!proofs.is_empty(), "Invalid proof (empty bytes) found");
-    }
-
-    let sector_bytes = PaddedBytesAmount::from(porep_config);
-
-    let verifying_key = get_stacked_verifying_key::<Tree>(porep_config)?;
-    trace!(
-        "got verifying key ({}) while verifying seal",
-        u64::from(sector_bytes)
-    );
-
-    let compound_setup_params = compound_proof::SetupParams {
-        vanilla_params: setup_params(
-            PaddedBytesAmount::from(porep_config),
-            usize::from(PoRepProofPartitions::from(porep_config)),
-            porep_config.porep_id,
-            porep_config.api_version,
-        )?,
-        partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
-        priority: false,
-    };
-
-    let compound_public_params: compound_proof::PublicParams<
-        '_,
-        StackedDrg<'_, Tree, DefaultPieceHasher>,
-    > = StackedCompound::setup(&compound_setup_params)?;
-
-    let mut public_inputs = Vec::with_capacity(l);
-    let mut proofs = Vec::with_capacity(l);
+) -> Result<bool, FilProofsError> {
+    classified(|| {
+        info!("verify_batch_seal:start");
+        ensure_input!(!comm_r_ins.is_empty(), "Cannot prove empty batch");
+        let l = comm_r_ins.len();
+        ensure_input!(l == comm_d_ins.len(), "Inconsistent inputs");
+        ensure_input!(l == prover_ids.len(), "Inconsistent inputs");
+        ensure_input!(l == prover_ids.len(), "Inconsistent inputs");
+        ensure_input!(l == sector_ids.len(), "Inconsistent inputs");
+        ensure_input!(l == tickets.len(), "Inconsistent inputs");
+        ensure_input!(l == seeds.len(), "Inconsistent inputs");
+        ensure_input!(l == proof_vecs.len(), "Inconsistent inputs");
+
+        for i in 0..l {
+            validate_seal_public_inputs(
+                &SealPublicInputs {
+                    comm_r: comm_r_ins[i],
+                    comm_d: comm_d_ins[i],
+                    prover_id: prover_ids[i],
+                    sector_id: sector_ids[i],
+                    ticket: tickets[i],
+                    seed: seeds[i],
+                },
+                porep_config,
+            )?;
+        }
+        for proofs in proof_vecs {
+            ensure_input!(!proofs.is_empty(), "Invalid proof (empty bytes) found");
+        }
+        porep_config.validate_porep_id()?;
+        porep_config.validate_tree_c_arity::<Tree>()?;
 
-    for i in 0..l {
-        let comm_r = as_safe_commitment(&comm_r_ins[i], "comm_r")?;
-        let comm_d = as_safe_commitment(&comm_d_ins[i], "comm_d")?;
+        let sector_bytes = PaddedBytesAmount::from(porep_config);
 
-        let replica_id = generate_replica_id::<Tree::Hasher, _>(
-            &prover_ids[i],
-            sector_ids[i].into(),
-            &tickets[i],
-            comm_d,
-            &porep_config.porep_id,
+        let verifying_key = get_stacked_verifying_key::<Tree>(porep_config)?;
+        trace!(
+            "got verifying key ({}) while verifying seal",
+            u64::from(sector_bytes)
         );
 
-        public_inputs.push(stacked::PublicInputs::<
-            <Tree::Hasher as Hasher>::Domain,
-            DefaultPieceDomain,
-        > {
-            replica_id,
-            tau: Some(Tau { comm_r, comm_d }),
-            seed: seeds[i],
-            k: None,
-        });
-        proofs.push(MultiProof::new_from_reader(
-            Some(usize::from(PoRepProofPartitions::from(porep_config))),
-            proof_vecs[i],
-            &verifying_key,
-        )?);
-    }
+        let compound_setup_params = compound_proof::SetupParams {
+            vanilla_params: setup_params(
+                PaddedBytesAmount::from(porep_config),
+                usize::from(PoRepProofPartitions::from(porep_config)),
+                porep_config.porep_id,
+                porep_config.api_version,
+            )?,
+            partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
+            priority: false,
+        };
 
-    let result = StackedCompound::<Tree, DefaultPieceHasher>::batch_verify(
-        &compound_public_params,
-        &public_inputs,
-        &proofs,
-        &ChallengeRequirements {
-            minimum_challenges: *POREP_MINIMUM_CHALLENGES
-                .read()
-                .expect("POREP_MINIMUM_CHALLENGES poisoned")
-                .get(&u64::from(SectorSize::from(porep_config)))
-                .expect("unknown sector size") as usize,
-        },
-    )
-    .map_err(Into::into);
+        let compound_public_params: compound_proof::PublicParams<
+            '_,
+            StackedDrg<'_, Tree, DefaultPieceHasher>,
+        > = StackedCompound::setup(&compound_setup_params)?;
+
+        let mut public_inputs = Vec::with_capacity(l);
+        let mut proofs = Vec::with_capacity(l);
+
+        for i in 0..l {
+            let comm_r = as_safe_commitment(&comm_r_ins[i], "comm_r")?;
+            let comm_d = as_safe_commitment(&comm_d_ins[i], "comm_d")?;
+
+            let replica_id = generate_replica_id::<Tree::Hasher, _>(
+                &prover_ids[i],
+                sector_ids[i].into(),
+                &tickets[i],
+                comm_d,
+                &porep_config.porep_id,
+            );
+
+            public_inputs.push(stacked::PublicInputs::<
+                <Tree::Hasher as Hasher>::Domain,
+                DefaultPieceDomain,
+            > {
+                replica_id,
+                tau: Some(Tau { comm_r, comm_d }),
+                seed: seeds[i],
+                k: None,
+            });
+            proofs.push(MultiProof::new_from_reader(
+                Some(usize::from(PoRepProofPartitions::from(porep_config))),
+                proof_vecs[i],
+                &verifying_key,
+            )?);
+        }
+
+        let result = StackedCompound::<Tree, DefaultPieceHasher>::batch_verify(
+            &compound_public_params,
+            &public_inputs,
+            &proofs,
+            &ChallengeRequirements {
+                minimum_challenges: *POREP_MINIMUM_CHALLENGES
+                    .read()
+                    .expect("POREP_MINIMUM_CHALLENGES poisoned")
+                    .get(&u64::from(SectorSize::from(porep_config)))
+                    .expect("unknown sector size") as usize,
+            },
+        )
+        .map_err(Into::into);
 
-    info!("verify_batch_seal:finish");
-    result
+        info!("verify_batch_seal:finish");
+        result
+    })
 }
@@ -0,0 +1,348 @@
+//! An end to end check of an installation, see [`run_self_test`].
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Context, Result};
+use log::info;
+use storage_proofs_core::{api_version::ApiVersion, merkle::MerkleTreeTrait, sector::SectorId};
+use storage_proofs_porep::stacked::{
+    create_label::labeling_stats, detected_gpus, tree_builder_stats, TreeBuilderStats,
+};
+use tempfile::tempdir;
+
+use crate::{
+    api::{
+        add_piece, clear_cache, generate_window_post, generate_winning_post, seal_commit_phase1,
+        seal_commit_phase2, seal_pre_commit_phase1, seal_pre_commit_phase2, verify_seal,
+        verify_window_post, verify_winning_post,
+    },
+    constants::{
+        WINDOW_POST_CHALLENGE_COUNT, WINNING_POST_CHALLENGE_COUNT, WINNING_POST_SECTOR_COUNT,
+    },
+    types::{
+        PoRepConfig, PoRepId, PoStConfig, PoStType, PrivateReplicaInfo, PublicReplicaInfo,
+        SectorSize, UnpaddedBytesAmount,
+    },
+    with_shape,
+};
+
+const PROVER_ID: [u8; 32] = [1; 32];
+const TICKET: [u8; 32] = [2; 32];
+const SEED: [u8; 32] = [3; 32];
+const RANDOMNESS: [u8; 32] = [4; 32];
+
+/// A step of [`run_self_test`] which succeeded.
+#[derive(Debug, Clone)]
+pub struct SelfTestStep {
+    pub name: &'static str,
+    pub duration: Duration,
+}
+
+/// The outcome of [`run_self_test`].
+#[derive(Debug, Clone)]
+pub struct SelfTestReport {
+    pub sector_size: SectorSize,
+    /// The steps which succeeded, in the order they ran.
+    pub steps: Vec<SelfTestStep>,
+    /// The name and error of the step which failed. No steps are run after a failure.
+    pub failure: Option<(&'static str, String)>,
+    /// Whether the layers were labeled by multicore SDR, rather than on a single core.
+    pub multicore_sdr: bool,
+    /// The SHA-256 implementation the layers were labeled with.
+    pub sha256_implementation: &'static str,
+    /// How many base trees of tree_c and tree_r_last were built on the CPU and on each GPU.
+    pub tree_builder: TreeBuilderStats,
+    /// The GPUs detected when the seal was proven, which bellperson proves the SNARKs on. It is
+    /// empty if they were proven on the CPU. bellperson also falls back to the CPU if a GPU fails,
+    /// and only logs so.
+    pub proving_gpus: Vec<String>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+
+    /// The total duration of the steps which succeeded.
+    pub fn duration(&self) -> Duration {
+        self.steps.iter().map(|step| step.duration).sum()
+    }
+
+    fn run<T>(&mut self, name: &'static str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        info!("self test: {}", name);
+        let start = Instant::now();
+        match f() {
+            Ok(value) => {
+                self.steps.push(SelfTestStep {
+                    name,
+                    duration: start.elapsed(),
+                });
+                Ok(value)
+            }
+            Err(err) => {
+                self.failure = Some((name, format!("{:#}", err)));
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Seals a sector of `sector_size` in a temporary directory, proves and verifies its commit,
+/// winning PoSt and window PoSt, and reports how long each step took.
+///
+/// This confirms that an installation works end to end: the parameters are in place, and the
+/// configured GPU and core binding can be used. `SECTOR_SIZE_2_KIB` is the smallest sector size
+/// with published parameters, and takes seconds. The sector is sealed with the `V1_1` proof type
+/// of the sector size, so it has to be a registered one.
+///
+/// The labeling and tree builders are reported from what they count while the self test runs, so
+/// sectors sealed at the same time in this process are counted as well.
+///
+/// Failures of the steps are recorded in the report rather than returned.
+pub fn run_self_test(sector_size: SectorSize) -> SelfTestReport {
+    info!("run_self_test:start: {:?}", sector_size);
+
+    let labeling_before = labeling_stats();
+    let tree_builder_before = tree_builder_stats();
+    let mut report = SelfTestReport {
+        sector_size,
+        steps: Vec::new(),
+        failure: None,
+        multicore_sdr: false,
+        sha256_implementation: labeling_before.sha256_implementation,
+        tree_builder: TreeBuilderStats::default(),
+        proving_gpus: Vec::new(),
+    };
+
+    let config = report.run("config", || {
+        let proof_type = (5..=9)
+            .find(|proof_type| PoRepId::new(*proof_type, 0).sector_size() == Some(sector_size))
+            .with_context(|| {
+                format!(
+                    "no registered proof type for sector size {}",
+                    u64::from(sector_size)
+                )
+            })?;
+
+        PoRepConfig::builder()
+            .sector_size(sector_size)
+            .porep_id(PoRepId::new(proof_type, 0).into())
+            .api_version(ApiVersion::V1_1_0)
+            .build()
+    });
+
+    if let Ok(config) = config {
+        let result = with_shape!(
+            u64::from(sector_size),
+            self_test_with_shape,
+            &mut report,
+            config,
+        );
+        // Failures of the steps are recorded already, only those in between are left.
+        if let Err(err) = result {
+            if report.failure.is_none() {
+                report.failure = Some(("setup", format!("{:#}", err)));
+            }
+        }
+    }
+
+    let labeling = labeling_stats();
+    report.multicore_sdr = labeling.multi_core > labeling_before.multi_core;
+    report.sha256_implementation = labeling.sha256_implementation;
+    report.tree_builder = tree_builder_since(&tree_builder_before);
+
+    info!(
+        "run_self_test:finish: {:?}, passed: {}",
+        sector_size,
+        report.passed()
+    );
+    report
+}
+
+/// Returns the base trees built since `before` was taken.
+fn tree_builder_since(before: &TreeBuilderStats) -> TreeBuilderStats {
+    let mut stats = tree_builder_stats();
+    stats.cpu -= before.cpu;
+    for (gpu, built) in stats.gpus.iter_mut() {
+        *built -= before.gpus.get(gpu).copied().unwrap_or(0);
+    }
+    stats.gpus.retain(|_, built| *built > 0);
+
+    stats
+}
+
+fn self_test_with_shape<Tree: 'static + MerkleTreeTrait>(
+    report: &mut SelfTestReport,
+    config: PoRepConfig,
+) -> Result<()> {
+    let dir = tempdir().context("failed to create the self test directory")?;
+    let cache_path = dir.path().join("cache");
+    let staged_path = dir.path().join("staged");
+    let sealed_path = dir.path().join("sealed");
+    std::fs::create_dir(&cache_path)?;
+
+    let piece_size = UnpaddedBytesAmount::from(config);
+    let piece_info = report.run("add_piece", || {
+        let (piece_info, _) = add_piece(
+            io::repeat(0).take(u64::from(piece_size)),
+            File::create(&staged_path)?,
+            piece_size,
+            &[],
+        )?;
+        File::create(&sealed_path)?;
+        Ok(piece_info)
+    })?;
+    let piece_infos = vec![piece_info];
+
+    let sector_id = SectorId::from(1);
+    let phase1_output = report.run("seal_pre_commit_phase1", || {
+        Ok(seal_pre_commit_phase1::<_, _, _, Tree>(
+            config,
+            &cache_path,
+            &staged_path,
+            &sealed_path,
+            PROVER_ID,
+            sector_id,
+            TICKET,
+            &piece_infos,
+        )?)
+    })?;
+    let pre_commit = report.run("seal_pre_commit_phase2", || {
+        Ok(seal_pre_commit_phase2(
+            config,
+            phase1_output,
+            &cache_path,
+            &sealed_path,
+        )?)
+    })?;
+    let comm_r = pre_commit.comm_r;
+    let comm_d = pre_commit.comm_d;
+
+    let commit_phase1_output = report.run("seal_commit_phase1", || {
+        Ok(seal_commit_phase1::<_, Tree>(
+            config,
+            &cache_path,
+            &sealed_path,
+            PROVER_ID,
+            sector_id,
+            TICKET,
+            SEED,
+            pre_commit,
+            &piece_infos,
+        )?)
+    })?;
+    report.run("clear_cache", || Ok(clear_cache::<Tree>(&cache_path)?))?;
+    // bellperson proves on all detected GPUs.
+    report.proving_gpus = detected_gpus();
+    let commit_output = report.run("seal_commit_phase2", || {
+        Ok(seal_commit_phase2(
+            config,
+            commit_phase1_output,
+            PROVER_ID,
+            sector_id,
+        )?)
+    })?;
+    report.run("verify_seal", || {
+        let valid = verify_seal::<Tree>(
+            config,
+            comm_r,
+            comm_d,
+            PROVER_ID,
+            sector_id,
+            TICKET,
+            SEED,
+            &commit_output.proof,
+        )?;
+        ensure!(valid, "the seal proof is invalid");
+        Ok(())
+    })?;
+
+    let private_replica = PrivateReplicaInfo::<Tree>::new(sealed_path, comm_r, cache_path)?;
+    let public_replica = PublicReplicaInfo::new(comm_r)?;
+
+    let winning_config = PoStConfig {
+        sector_size: config.sector_size,
+        challenge_count: WINNING_POST_CHALLENGE_COUNT,
+        sector_count: WINNING_POST_SECTOR_COUNT,
+        typ: PoStType::Winning,
+        priority: false,
+        api_version: config.api_version,
+    };
+    let private_replicas = vec![(sector_id, private_replica.clone())];
+    let public_replicas = vec![(sector_id, public_replica.clone())];
+    let winning_proof = report.run("generate_winning_post", || {
+        Ok(generate_winning_post::<Tree>(
+            &winning_config,
+            &RANDOMNESS,
+            &private_replicas,
+            PROVER_ID,
+        )?)
+    })?;
+    report.run("verify_winning_post", || {
+        let valid = verify_winning_post::<Tree>(
+            &winning_config,
+            &RANDOMNESS,
+            &public_replicas,
+            PROVER_ID,
+            &winning_proof,
+        )?;
+        ensure!(valid, "the winning PoSt proof is invalid");
+        Ok(())
+    })?;
+
+    let window_config = PoStConfig {
+        sector_size: config.sector_size,
+        challenge_count: WINDOW_POST_CHALLENGE_COUNT,
+        sector_count: 1,
+        typ: PoStType::Window,
+        priority: false,
+        api_version: config.api_version,
+    };
+    let mut private_replicas = BTreeMap::new();
+    private_replicas.insert(sector_id, private_replica);
+    let mut public_replicas = BTreeMap::new();
+    public_replicas.insert(sector_id, public_replica);
+    let window_proof = report.run("generate_window_post", || {
+        Ok(generate_window_post::<Tree>(
+            &window_config,
+            &RANDOMNESS,
+            &private_replicas,
+            PROVER_ID,
+        )?)
+    })?;
+    report.run("verify_window_post", || {
+        let valid = verify_window_post::<Tree>(
+            &window_config,
+            &RANDOMNESS,
+            &public_replicas,
+            PROVER_ID,
+            &window_proof,
+        )?;
+        ensure!(valid, "the window PoSt proof is invalid");
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::constants::SECTOR_SIZE_4_KIB;
+
+    #[test]
+    fn test_self_test_unregistered_sector_size() {
+        let report = run_self_test(SectorSize(SECTOR_SIZE_4_KIB));
+        assert!(!report.passed());
+        assert!(report.steps.is_empty());
+        assert_eq!(
+            report.failure.as_ref().map(|(name, _)| *name),
+            Some("config")
+        );
+        assert!(report.proving_gpus.is_empty());
+    }
+}
@@ -7,24 +7,29 @@ use bincode::{deserialize, serialize};
 use filecoin_hashers::{Domain, Hasher};
 use generic_array::typenum::Unsigned;
 use log::{info, trace};
+use memmap::MmapOptions;
 use merkletree::merkle::get_merkle_tree_len;
 use merkletree::store::StoreConfig;
+use rayon::prelude::{ParallelIterator, ParallelSlice};
 use storage_proofs_core::{
     cache_key::CacheKey,
     compound_proof::{self, CompoundProof},
     merkle::{get_base_tree_count, MerkleTreeTrait},
     multi_proof::MultiProof,
     proof::ProofScheme,
+    util::NODE_SIZE,
 };
-use storage_proofs_porep::stacked::{PersistentAux, TemporaryAux};
+use storage_proofs_porep::stacked::{finalize_comm_r, PersistentAux, TemporaryAux};
 use storage_proofs_update::{
     constants::TreeDArity, constants::TreeRHasher, EmptySectorUpdate, EmptySectorUpdateCompound,
     PartitionProof, PrivateInputs, PublicInputs, PublicParams, SetupParams,
 };
 
 use crate::{
+    api::{ensure_replica_len, hash_base_roots, hash_row},
     caches::{get_empty_sector_update_params, get_empty_sector_update_verifying_key},
     constants::{DefaultPieceDomain, DefaultPieceHasher},
+    error::{classified, ensure_input, FilProofsError},
     pieces::verify_pieces,
     types::{
         Commitment, EmptySectorUpdateEncoded, EmptySectorUpdateProof, PieceInfo, PoRepConfig,
@@ -45,6 +50,110 @@ fn get_p_aux<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>>(
     Ok(p_aux)
 }
 
+// Ensures that the p_aux from the sector key cache belongs to the replica committed to by
+// comm_r_old and that the sector key is not truncated, so that a mismatch is reported before
+// proving rather than as an invalid proof. This does not read the sector key, so it is cheap enough
+// for every partition, see `ensure_sector_key_data_matches_comm_r_old` for the full check.
+fn ensure_sector_key_matches_comm_r_old<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>>(
+    config: &SectorUpdateConfig,
+    comm_r_old: &<TreeRHasher as Hasher>::Domain,
+    p_aux_old: &PersistentAux<<Tree::Hasher as Hasher>::Domain>,
+    sector_key_path: &Path,
+) -> Result<()> {
+    ensure_replica_len(sector_key_path, config.sector_size)?;
+
+    let comm_r = finalize_comm_r::<TreeRHasher>(&p_aux_old.comm_c, &p_aux_old.comm_r_last);
+    ensure_input!(
+        &comm_r == comm_r_old,
+        "comm_r_old does not match the sector key cache"
+    );
+
+    Ok(())
+}
+
+// Like `ensure_sector_key_matches_comm_r_old`, but also ensures that the sector key data itself
+// belongs to comm_r_old. As p_aux only records the roots, comm_r_last is recomputed from the sector
+// key, which hashes the whole sector once more, so this is only done once per sector, when all of
+// its partitions are proven together.
+fn ensure_sector_key_data_matches_comm_r_old<
+    Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>,
+>(
+    config: &SectorUpdateConfig,
+    comm_r_old: &<TreeRHasher as Hasher>::Domain,
+    p_aux_old: &PersistentAux<<Tree::Hasher as Hasher>::Domain>,
+    sector_key_path: &Path,
+) -> Result<()> {
+    ensure_sector_key_matches_comm_r_old::<Tree>(config, comm_r_old, p_aux_old, sector_key_path)?;
+
+    let comm_r_last = sector_key_comm_r_last::<Tree>(sector_key_path)?;
+    ensure_input!(
+        comm_r_last == p_aux_old.comm_r_last,
+        "sector key {:?} does not match comm_r_old",
+        sector_key_path
+    );
+
+    Ok(())
+}
+
+// The number of leaves up to which the subtrees of tree_r_last are hashed on one thread when
+// recomputing its root from the sector key.
+const SECTOR_KEY_SUBTREE_LEAFS: usize = 1 << 15;
+
+// Recomputes the root of tree_r_last, i.e. comm_r_last, from the sector key, which are its
+// leaves. The subtrees are hashed in parallel without writing the tree to disk.
+fn sector_key_comm_r_last<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>>(
+    sector_key_path: &Path,
+) -> Result<<TreeRHasher as Hasher>::Domain> {
+    let arity = Tree::Arity::to_usize();
+    let sector_key_file = File::open(sector_key_path)
+        .with_context(|| format!("could not open sector_key_path={:?}", sector_key_path))?;
+    let sector_key = unsafe {
+        MmapOptions::new()
+            .map(&sector_key_file)
+            .with_context(|| format!("could not mmap sector_key_path={:?}", sector_key_path))?
+    };
+
+    let base_tree_count = get_base_tree_count::<Tree>();
+    let base_tree_leafs = sector_key.len() / NODE_SIZE / base_tree_count;
+    let mut subtree_leafs = 1;
+    while subtree_leafs * arity <= base_tree_leafs.min(SECTOR_KEY_SUBTREE_LEAFS) {
+        subtree_leafs *= arity;
+    }
+    ensure!(
+        base_tree_leafs * base_tree_count * NODE_SIZE == sector_key.len()
+            && base_tree_leafs % subtree_leafs == 0,
+        "sector key {:?} of {} bytes is not the leaves of a tree of arity {}",
+        sector_key_path,
+        sector_key.len(),
+        arity
+    );
+
+    let mut roots = sector_key
+        .par_chunks(subtree_leafs * NODE_SIZE)
+        .map(|chunk| -> Result<_> {
+            let mut row = chunk
+                .chunks(NODE_SIZE)
+                .map(<TreeRHasher as Hasher>::Domain::try_from_bytes)
+                .collect::<Result<Vec<_>>>()?;
+            while row.len() > 1 {
+                row = hash_row::<TreeRHasher>(&row, arity);
+            }
+            Ok(row[0])
+        })
+        .collect::<Result<Vec<_>>>()?;
+    while roots.len() > base_tree_count {
+        roots = hash_row::<TreeRHasher>(&roots, arity);
+    }
+    ensure!(
+        roots.len() == base_tree_count,
+        "sector key {:?} is not the leaves of {} base trees",
+        sector_key_path,
+        base_tree_count
+    );
+
+    hash_base_roots::<Tree>(roots)
+}
+
 fn persist_p_aux<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>>(
     p_aux: &PersistentAux<<Tree::Hasher as Hasher>::Domain>,
     cache_path: &Path,
@@ -147,62 +256,65 @@ pub fn encode_into<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>>(
     sector_key_cache_path: &Path,
     staged_data_path: &Path,
     piece_infos: &[PieceInfo],
-) -> Result<EmptySectorUpdateEncoded> {
-    info!("encode_into:start");
-    let config = SectorUpdateConfig::from_porep_config(porep_config);
-
-    let p_aux = get_p_aux::<Tree>(sector_key_cache_path)?;
-    let t_aux = get_t_aux::<Tree>(sector_key_cache_path)?;
-
-    let (tree_d_new_config, tree_r_last_new_config) =
-        get_new_configs_from_t_aux_old::<Tree>(&t_aux, new_cache_path, config.nodes_count)?;
-
-    let (comm_r_domain, comm_r_last_domain, comm_d_domain) =
-        EmptySectorUpdate::<Tree>::encode_into(
-            config.nodes_count,
-            tree_d_new_config,
-            tree_r_last_new_config,
-            <Tree::Hasher as Hasher>::Domain::try_from_bytes(&p_aux.comm_c.into_bytes())?,
-            <Tree::Hasher as Hasher>::Domain::try_from_bytes(&p_aux.comm_r_last.into_bytes())?,
-            new_replica_path,
-            new_cache_path,
-            sector_key_path,
-            sector_key_cache_path,
-            staged_data_path,
-            usize::from(config.h_select),
-        )?;
-
-    let mut comm_d = [0; 32];
-    let mut comm_r = [0; 32];
-    let mut comm_r_last = [0; 32];
-
-    comm_d_domain.write_bytes(&mut comm_d)?;
-    comm_r_domain.write_bytes(&mut comm_r)?;
-    comm_r_last_domain.write_bytes(&mut comm_r_last)?;
-
-    ensure!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
-    ensure!(comm_r != [0; 32], "Invalid all zero commitment (comm_r)");
-    ensure!(
-        comm_r_last != [0; 32],
-        "Invalid all zero commitment (comm_r)"
-    );
-    ensure!(
-        verify_pieces(&comm_d, piece_infos, porep_config.into())?,
-        "pieces and comm_d do not match"
-    );
-
-    // Persist p_aux and t_aux into the new_cache_path here
-    let mut p_aux = p_aux;
-    p_aux.comm_r_last = comm_r_last_domain;
-    persist_p_aux::<Tree>(&p_aux, new_cache_path)?;
-    persist_t_aux::<Tree>(&t_aux, new_cache_path)?;
-
-    info!("encode_into:finish");
-
-    Ok(EmptySectorUpdateEncoded {
-        comm_r_new: comm_r,
-        comm_r_last_new: comm_r_last,
-        comm_d_new: comm_d,
+) -> Result<EmptySectorUpdateEncoded, FilProofsError> {
+    classified(|| {
+        info!("encode_into:start");
+        let config = SectorUpdateConfig::from_porep_config(porep_config);
+        ensure_replica_len(sector_key_path, config.sector_size)?;
+
+        let p_aux = get_p_aux::<Tree>(sector_key_cache_path)?;
+        let t_aux = get_t_aux::<Tree>(sector_key_cache_path)?;
+
+        let (tree_d_new_config, tree_r_last_new_config) =
+            get_new_configs_from_t_aux_old::<Tree>(&t_aux, new_cache_path, config.nodes_count)?;
+
+        let (comm_r_domain, comm_r_last_domain, comm_d_domain) =
+            EmptySectorUpdate::<Tree>::encode_into(
+                config.nodes_count,
+                tree_d_new_config,
+                tree_r_last_new_config,
+                <Tree::Hasher as Hasher>::Domain::try_from_bytes(&p_aux.comm_c.into_bytes())?,
+                <Tree::Hasher as Hasher>::Domain::try_from_bytes(&p_aux.comm_r_last.into_bytes())?,
+                new_replica_path,
+                new_cache_path,
+                sector_key_path,
+                sector_key_cache_path,
+                staged_data_path,
+                usize::from(config.h_select),
+            )?;
+
+        let mut comm_d = [0; 32];
+        let mut comm_r = [0; 32];
+        let mut comm_r_last = [0; 32];
+
+        comm_d_domain.write_bytes(&mut comm_d)?;
+        comm_r_domain.write_bytes(&mut comm_r)?;
+        comm_r_last_domain.write_bytes(&mut comm_r_last)?;
+
+        ensure_input!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
+        ensure_input!(comm_r != [0; 32], "Invalid all zero commitment (comm_r)");
+        ensure_input!(
+            comm_r_last != [0; 32],
+            "Invalid all zero commitment (comm_r)"
+        );
+        ensure_input!(
+            verify_pieces(&comm_d, piece_infos, porep_config.into())?,
+            "pieces and comm_d do not match"
+        );
+
+        // Persist p_aux and t_aux into the new_cache_path here
+        let mut p_aux = p_aux;
+        p_aux.comm_r_last = comm_r_last_domain;
+        persist_p_aux::<Tree>(&p_aux, new_cache_path)?;
+        persist_t_aux::<Tree>(&t_aux, new_cache_path)?;
+
+        info!("encode_into:finish");
+
+        Ok(EmptySectorUpdateEncoded {
+            comm_r_new: comm_r,
+            comm_r_last_new: comm_r_last,
+            comm_d_new: comm_d,
+        })
     })
 }
 
@@ -215,26 +327,30 @@ pub fn decode_from<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>>(
     sector_key_path: &Path,
     sector_key_cache_path: &Path,
     comm_d_new: Commitment,
-) -> Result<()> {
-    info!("decode_from:start");
-
-    let p_aux = get_p_aux::<Tree>(sector_key_cache_path)?;
+) -> Result<(), FilProofsError> {
+    classified(|| {
+        info!("decode_from:start");
+        ensure_replica_len(replica_path, config.sector_size)?;
+        ensure_replica_len(sector_key_path, config.sector_size)?;
+
+        let p_aux = get_p_aux::<Tree>(sector_key_cache_path)?;
+
+        let nodes_count = config.nodes_count;
+        EmptySectorUpdate::<Tree>::decode_from(
+            nodes_count,
+            out_data_path,
+            replica_path,
+            sector_key_path,
+            sector_key_cache_path,
+            <Tree::Hasher as Hasher>::Domain::try_from_bytes(&p_aux.comm_c.into_bytes())?,
+            comm_d_new.into(),
+            <Tree::Hasher as Hasher>::Domain::try_from_bytes(&p_aux.comm_r_last.into_bytes())?,
+            usize::from(config.h_select),
+        )?;
 
-    let nodes_count = config.nodes_count;
-    EmptySectorUpdate::<Tree>::decode_from(
-        nodes_count,
-        out_data_path,
-        replica_path,
-        sector_key_path,
-        sector_key_cache_path,
-        <Tree::Hasher as Hasher>::Domain::try_from_bytes(&p_aux.comm_c.into_bytes())?,
-        comm_d_new.into(),
-        <Tree::Hasher as Hasher>::Domain::try_from_bytes(&p_aux.comm_r_last.into_bytes())?,
-        usize::from(config.h_select),
-    )?;
-
-    info!("decode_from:finish");
-    Ok(())
+        info!("decode_from:finish");
+        Ok(())
+    })
 }
 
 /// Removes encoded data and outputs the sector key.
@@ -247,38 +363,44 @@ pub fn remove_encoded_data<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>
     replica_cache_path: &Path,
     data_path: &Path,
     comm_d_new: Commitment,
-) -> Result<()> {
-    info!("remove_data:start");
+) -> Result<(), FilProofsError> {
+    classified(|| {
+        info!("remove_data:start");
+        ensure_replica_len(replica_path, config.sector_size)?;
 
-    let p_aux = get_p_aux::<Tree>(replica_cache_path)?;
-    let t_aux = get_t_aux::<Tree>(replica_cache_path)?;
+        let p_aux = get_p_aux::<Tree>(replica_cache_path)?;
+        let t_aux = get_t_aux::<Tree>(replica_cache_path)?;
 
-    let (_, tree_r_last_new_config) =
-        get_new_configs_from_t_aux_old::<Tree>(&t_aux, sector_key_cache_path, config.nodes_count)?;
+        let (_, tree_r_last_new_config) = get_new_configs_from_t_aux_old::<Tree>(
+            &t_aux,
+            sector_key_cache_path,
+            config.nodes_count,
+        )?;
 
-    let nodes_count = config.nodes_count;
-    let tree_r_last_new = EmptySectorUpdate::<Tree>::remove_encoded_data(
-        nodes_count,
-        sector_key_path,
-        sector_key_cache_path,
-        replica_path,
-        replica_cache_path,
-        data_path,
-        tree_r_last_new_config,
-        <Tree::Hasher as Hasher>::Domain::try_from_bytes(&p_aux.comm_c.into_bytes())?,
-        comm_d_new.into(),
-        <Tree::Hasher as Hasher>::Domain::try_from_bytes(&p_aux.comm_r_last.into_bytes())?,
-        usize::from(config.h_select),
-    )?;
-
-    // Persist p_aux and t_aux into the sector_key_cache_path here
-    let mut p_aux = p_aux;
-    p_aux.comm_r_last = tree_r_last_new;
-    persist_p_aux::<Tree>(&p_aux, sector_key_cache_path)?;
-    persist_t_aux::<Tree>(&t_aux, sector_key_cache_path)?;
-
-    info!("remove_data:finish");
-    Ok(())
+        let nodes_count = config.nodes_count;
+        let tree_r_last_new = EmptySectorUpdate::<Tree>::remove_encoded_data(
+            nodes_count,
+            sector_key_path,
+            sector_key_cache_path,
+            replica_path,
+            replica_cache_path,
+            data_path,
+            tree_r_last_new_config,
+            <Tree::Hasher as Hasher>::Domain::try_from_bytes(&p_aux.comm_c.into_bytes())?,
+            comm_d_new.into(),
+            <Tree::Hasher as Hasher>::Domain::try_from_bytes(&p_aux.comm_r_last.into_bytes())?,
+            usize::from(config.h_select),
+        )?;
+
+        // Persist p_aux and t_aux into the sector_key_cache_path here
+        let mut p_aux = p_aux;
+        p_aux.comm_r_last = tree_r_last_new;
+        persist_p_aux::<Tree>(&p_aux, sector_key_cache_path)?;
+        persist_t_aux::<Tree>(&t_aux, sector_key_cache_path)?;
+
+        info!("remove_data:finish");
+        Ok(())
+    })
 }
 
 /// Generate a single vanilla partition proof for a specified partition.
@@ -293,50 +415,62 @@ pub fn generate_single_partition_proof<Tree: 'static + MerkleTreeTrait<Hasher =
     sector_key_cache_path: &Path,
     replica_path: &Path,
     replica_cache_path: &Path,
-) -> Result<PartitionProof<Tree>> {
-    info!("generate_single_partition_proof:start");
+) -> Result<PartitionProof<Tree>, FilProofsError> {
+    classified(|| {
+        info!("generate_single_partition_proof:start");
 
-    let comm_r_old_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_old)?;
-    let comm_r_new_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_new)?;
+        let comm_r_old_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_old)?;
+        let comm_r_new_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_new)?;
 
-    let comm_d_new_safe = DefaultPieceDomain::try_from_bytes(&comm_d_new)?;
+        let comm_d_new_safe = DefaultPieceDomain::try_from_bytes(&comm_d_new)?;
 
-    let public_params: storage_proofs_update::PublicParams =
-        PublicParams::from_sector_size(u64::from(config.sector_size));
+        let public_params: storage_proofs_update::PublicParams =
+            PublicParams::from_sector_size(u64::from(config.sector_size));
 
-    let p_aux_old = get_p_aux::<Tree>(sector_key_cache_path)?;
+        let p_aux_old = get_p_aux::<Tree>(sector_key_cache_path)?;
+        ensure_sector_key_matches_comm_r_old::<Tree>(
+            &config,
+            &comm_r_old_safe,
+            &p_aux_old,
+            sector_key_path,
+        )?;
+        ensure_replica_len(replica_path, config.sector_size)?;
 
-    let partitions = usize::from(config.update_partitions);
-    ensure!(partition_index < partitions, "invalid partition index");
+        let partitions = usize::from(config.update_partitions);
+        ensure_input!(partition_index < partitions, "invalid partition index");
 
-    let public_inputs: storage_proofs_update::PublicInputs = PublicInputs {
-        k: partition_index,
-        comm_r_old: comm_r_old_safe,
-        comm_d_new: comm_d_new_safe,
-        comm_r_new: comm_r_new_safe,
-        h: usize::from(config.h_select),
-    };
+        let public_inputs: storage_proofs_update::PublicInputs = PublicInputs {
+            k: partition_index,
+            comm_r_old: comm_r_old_safe,
+            comm_d_new: comm_d_new_safe,
+            comm_r_new: comm_r_new_safe,
+            h: usize::from(config.h_select),
+        };
 
-    let t_aux_old = get_t_aux::<Tree>(sector_key_cache_path)?;
+        let t_aux_old = get_t_aux::<Tree>(sector_key_cache_path)?;
 
-    let (tree_d_new_config, tree_r_last_new_config) =
-        get_new_configs_from_t_aux_old::<Tree>(&t_aux_old, replica_cache_path, config.nodes_count)?;
+        let (tree_d_new_config, tree_r_last_new_config) = get_new_configs_from_t_aux_old::<Tree>(
+            &t_aux_old,
+            replica_cache_path,
+            config.nodes_count,
+        )?;
 
-    let private_inputs: PrivateInputs = PrivateInputs {
-        comm_c: p_aux_old.comm_c,
-        tree_r_old_config: t_aux_old.tree_r_last_config,
-        old_replica_path: sector_key_path.to_path_buf(),
-        tree_d_new_config,
-        tree_r_new_config: tree_r_last_new_config,
-        replica_path: replica_path.to_path_buf(),
-    };
+        let private_inputs: PrivateInputs = PrivateInputs {
+            comm_c: p_aux_old.comm_c,
+            tree_r_old_config: t_aux_old.tree_r_last_config,
+            old_replica_path: sector_key_path.to_path_buf(),
+            tree_d_new_config,
+            tree_r_new_config: tree_r_last_new_config,
+            replica_path: replica_path.to_path_buf(),
+        };
 
-    let partition_proof =
-        EmptySectorUpdate::<Tree>::prove(&public_params, &public_inputs, &private_inputs)?;
+        let partition_proof =
+            EmptySectorUpdate::<Tree>::prove(&public_params, &public_inputs, &private_inputs)?;
 
-    info!("generate_single_partition_proof:finish");
+        info!("generate_single_partition_proof:finish");
 
-    Ok(partition_proof)
+        Ok(partition_proof)
+    })
 }
 
 /// Verify a single vanilla partition proof for a specified partition.
@@ -348,33 +482,35 @@ pub fn verify_single_partition_proof<Tree: 'static + MerkleTreeTrait<Hasher = Tr
     comm_r_old: Commitment,
     comm_r_new: Commitment,
     comm_d_new: Commitment,
-) -> Result<bool> {
-    info!("verify_single_partition_proof:start");
+) -> Result<bool, FilProofsError> {
+    classified(|| {
+        info!("verify_single_partition_proof:start");
 
-    let comm_r_old_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_old)?;
-    let comm_r_new_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_new)?;
+        let comm_r_old_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_old)?;
+        let comm_r_new_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_new)?;
 
-    let comm_d_new_safe = DefaultPieceDomain::try_from_bytes(&comm_d_new)?;
+        let comm_d_new_safe = DefaultPieceDomain::try_from_bytes(&comm_d_new)?;
 
-    let public_params: storage_proofs_update::PublicParams =
-        PublicParams::from_sector_size(u64::from(config.sector_size));
+        let public_params: storage_proofs_update::PublicParams =
+            PublicParams::from_sector_size(u64::from(config.sector_size));
 
-    let partitions = usize::from(config.update_partitions);
-    ensure!(partition_index < partitions, "invalid partition index");
+        let partitions = usize::from(config.update_partitions);
+        ensure_input!(partition_index < partitions, "invalid partition index");
 
-    let public_inputs: storage_proofs_update::PublicInputs = PublicInputs {
-        k: partition_index,
-        comm_r_old: comm_r_old_safe,
-        comm_d_new: comm_d_new_safe,
-        comm_r_new: comm_r_new_safe,
-        h: usize::from(config.h_select),
-    };
+        let public_inputs: storage_proofs_update::PublicInputs = PublicInputs {
+            k: partition_index,
+            comm_r_old: comm_r_old_safe,
+            comm_d_new: comm_d_new_safe,
+            comm_r_new: comm_r_new_safe,
+            h: usize::from(config.h_select),
+        };
 
-    let valid = EmptySectorUpdate::<Tree>::verify(&public_params, &public_inputs, &proof)?;
+        let valid = EmptySectorUpdate::<Tree>::verify(&public_params, &public_inputs, &proof)?;
 
-    info!("verify_single_partition_proof:finish");
+        info!("verify_single_partition_proof:finish");
 
-    Ok(valid)
+        Ok(valid)
+    })
 }
 
 /// Generate all vanilla partition proofs across all partitions.
@@ -388,51 +524,63 @@ pub fn generate_partition_proofs<Tree: 'static + MerkleTreeTrait<Hasher = TreeRH
     sector_key_cache_path: &Path,
     replica_path: &Path,
     replica_cache_path: &Path,
-) -> Result<Vec<PartitionProof<Tree>>> {
-    info!("generate_partition_proofs:start");
+) -> Result<Vec<PartitionProof<Tree>>, FilProofsError> {
+    classified(|| {
+        info!("generate_partition_proofs:start");
 
-    let comm_r_old_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_old)?;
-    let comm_r_new_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_new)?;
+        let comm_r_old_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_old)?;
+        let comm_r_new_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_new)?;
 
-    let comm_d_new_safe = DefaultPieceDomain::try_from_bytes(&comm_d_new)?;
+        let comm_d_new_safe = DefaultPieceDomain::try_from_bytes(&comm_d_new)?;
 
-    let public_params: storage_proofs_update::PublicParams =
-        PublicParams::from_sector_size(u64::from(config.sector_size));
+        let public_params: storage_proofs_update::PublicParams =
+            PublicParams::from_sector_size(u64::from(config.sector_size));
 
-    let p_aux_old = get_p_aux::<Tree>(sector_key_cache_path)?;
-
-    let public_inputs: storage_proofs_update::PublicInputs = PublicInputs {
-        k: usize::from(config.update_partitions),
-        comm_r_old: comm_r_old_safe,
-        comm_d_new: comm_d_new_safe,
-        comm_r_new: comm_r_new_safe,
-        h: usize::from(config.h_select),
-    };
+        let p_aux_old = get_p_aux::<Tree>(sector_key_cache_path)?;
+        ensure_sector_key_data_matches_comm_r_old::<Tree>(
+            &config,
+            &comm_r_old_safe,
+            &p_aux_old,
+            sector_key_path,
+        )?;
+        ensure_replica_len(replica_path, config.sector_size)?;
 
-    let t_aux_old = get_t_aux::<Tree>(sector_key_cache_path)?;
+        let public_inputs: storage_proofs_update::PublicInputs = PublicInputs {
+            k: usize::from(config.update_partitions),
+            comm_r_old: comm_r_old_safe,
+            comm_d_new: comm_d_new_safe,
+            comm_r_new: comm_r_new_safe,
+            h: usize::from(config.h_select),
+        };
 
-    let (tree_d_new_config, tree_r_last_new_config) =
-        get_new_configs_from_t_aux_old::<Tree>(&t_aux_old, replica_cache_path, config.nodes_count)?;
+        let t_aux_old = get_t_aux::<Tree>(sector_key_cache_path)?;
 
-    let private_inputs: PrivateInputs = PrivateInputs {
-        comm_c: p_aux_old.comm_c,
-        tree_r_old_config: t_aux_old.tree_r_last_config,
-        old_replica_path: sector_key_path.to_path_buf(),
-        tree_d_new_config,
-        tree_r_new_config: tree_r_last_new_config,
-        replica_path: replica_path.to_path_buf(),
-    };
+        let (tree_d_new_config, tree_r_last_new_config) = get_new_configs_from_t_aux_old::<Tree>(
+            &t_aux_old,
+            replica_cache_path,
+            config.nodes_count,
+        )?;
 
-    let partition_proofs = EmptySectorUpdate::<Tree>::prove_all_partitions(
-        &public_params,
-        &public_inputs,
-        &private_inputs,
-        usize::from(config.update_partitions),
-    )?;
+        let private_inputs: PrivateInputs = PrivateInputs {
+            comm_c: p_aux_old.comm_c,
+            tree_r_old_config: t_aux_old.tree_r_last_config,
+            old_replica_path: sector_key_path.to_path_buf(),
+            tree_d_new_config,
+            tree_r_new_config: tree_r_last_new_config,
+            replica_path: replica_path.to_path_buf(),
+        };
+
+        let partition_proofs = EmptySectorUpdate::<Tree>::prove_all_partitions(
+            &public_params,
+            &public_inputs,
+            &private_inputs,
+            usize::from(config.update_partitions),
+        )?;
 
-    info!("generate_partition_proofs:finish");
+        info!("generate_partition_proofs:finish");
 
-    Ok(partition_proofs)
+        Ok(partition_proofs)
+    })
 }
 
 /// Verify all vanilla partition proofs across all partitions.
@@ -442,31 +590,36 @@ pub fn verify_partition_proofs<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHas
     comm_r_old: Commitment,
     comm_r_new: Commitment,
     comm_d_new: Commitment,
-) -> Result<bool> {
-    info!("verify_partition_proofs:start");
-
-    let comm_r_old_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_old)?;
-    let comm_r_new_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_new)?;
-
-    let comm_d_new_safe = DefaultPieceDomain::try_from_bytes(&comm_d_new)?;
-
-    let public_params: storage_proofs_update::PublicParams =
-        PublicParams::from_sector_size(u64::from(config.sector_size));
-
-    let public_inputs: storage_proofs_update::PublicInputs = PublicInputs {
-        k: usize::from(config.update_partitions),
-        comm_r_old: comm_r_old_safe,
-        comm_d_new: comm_d_new_safe,
-        comm_r_new: comm_r_new_safe,
-        h: usize::from(config.h_select),
-    };
-
-    let valid =
-        EmptySectorUpdate::<Tree>::verify_all_partitions(&public_params, &public_inputs, proofs)?;
+) -> Result<bool, FilProofsError> {
+    classified(|| {
+        info!("verify_partition_proofs:start");
+
+        let comm_r_old_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_old)?;
+        let comm_r_new_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_new)?;
+
+        let comm_d_new_safe = DefaultPieceDomain::try_from_bytes(&comm_d_new)?;
+
+        let public_params: storage_proofs_update::PublicParams =
+            PublicParams::from_sector_size(u64::from(config.sector_size));
+
+        let public_inputs: storage_proofs_update::PublicInputs = PublicInputs {
+            k: usize::from(config.update_partitions),
+            comm_r_old: comm_r_old_safe,
+            comm_d_new: comm_d_new_safe,
+            comm_r_new: comm_r_new_safe,
+            h: usize::from(config.h_select),
+        };
+
+        let valid = EmptySectorUpdate::<Tree>::verify_all_partitions(
+            &public_params,
+            &public_inputs,
+            proofs,
+        )?;
 
-    info!("verify_partition_proofs:finish");
+        info!("verify_partition_proofs:finish");
 
-    Ok(valid)
+        Ok(valid)
+    })
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -478,47 +631,63 @@ pub fn generate_empty_sector_update_proof_with_vanilla<
     comm_r_old: Commitment,
     comm_r_new: Commitment,
     comm_d_new: Commitment,
-) -> Result<EmptySectorUpdateProof> {
-    info!("generate_empty_sector_update_proof_with_vanilla:start");
-
-    let comm_r_old_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_old)?;
-    let comm_r_new_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_new)?;
-
-    let comm_d_new_safe = DefaultPieceDomain::try_from_bytes(&comm_d_new)?;
-
-    let config = SectorUpdateConfig::from_porep_config(porep_config);
-
-    let partitions = usize::from(config.update_partitions);
-    let public_inputs: storage_proofs_update::PublicInputs = PublicInputs {
-        k: partitions,
-        comm_r_old: comm_r_old_safe,
-        comm_d_new: comm_d_new_safe,
-        comm_r_new: comm_r_new_safe,
-        h: usize::from(config.h_select),
-    };
-
-    let setup_params_compound = compound_proof::SetupParams {
-        vanilla_params: SetupParams {
-            sector_bytes: u64::from(config.sector_size),
-        },
-        partitions: Some(partitions),
-        priority: false,
-    };
-    let pub_params_compound = EmptySectorUpdateCompound::<Tree>::setup(&setup_params_compound)?;
-
-    let groth_params = get_empty_sector_update_params::<Tree>(porep_config)?;
-    let multi_proof = EmptySectorUpdateCompound::prove_with_vanilla(
-        &pub_params_compound,
-        &public_inputs,
-        vanilla_proofs,
-        &groth_params,
-    )?;
+) -> Result<EmptySectorUpdateProof, FilProofsError> {
+    classified(|| {
+        info!("generate_empty_sector_update_proof_with_vanilla:start");
+
+        let comm_r_old_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_old)?;
+        let comm_r_new_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_new)?;
+
+        let comm_d_new_safe = DefaultPieceDomain::try_from_bytes(&comm_d_new)?;
+
+        let config = SectorUpdateConfig::from_porep_config(porep_config);
+
+        let partitions = usize::from(config.update_partitions);
+        let public_inputs: storage_proofs_update::PublicInputs = PublicInputs {
+            k: partitions,
+            comm_r_old: comm_r_old_safe,
+            comm_d_new: comm_d_new_safe,
+            comm_r_new: comm_r_new_safe,
+            h: usize::from(config.h_select),
+        };
+
+        let setup_params_compound = compound_proof::SetupParams {
+            vanilla_params: SetupParams {
+                sector_bytes: u64::from(config.sector_size),
+            },
+            partitions: Some(partitions),
+            priority: false,
+        };
+        let pub_params_compound = EmptySectorUpdateCompound::<Tree>::setup(&setup_params_compound)?;
+
+        let groth_params = get_empty_sector_update_params::<Tree>(porep_config)?;
+        let multi_proof = EmptySectorUpdateCompound::prove_with_vanilla(
+            &pub_params_compound,
+            &public_inputs,
+            vanilla_proofs,
+            &groth_params,
+        )?;
 
-    info!("generate_empty_sector_update_proof_with_vanilla:finish");
+        info!("generate_empty_sector_update_proof_with_vanilla:finish");
 
-    Ok(EmptySectorUpdateProof(multi_proof.to_vec()?))
+        Ok(EmptySectorUpdateProof(multi_proof.to_vec()?))
+    })
 }
 
+/// Generates the compound proof that a sector key was updated with new data into a replica.
+///
+/// # Arguments
+///
+/// * `porep_config` - the porep config the sector key was sealed with.
+/// * `comm_r_old` - commitment to the sector key replica.
+/// * `comm_r_new` - commitment to the updated replica (`comm_r_new` returned by `encode_into`).
+/// * `comm_d_new` - commitment to the new data (`comm_d_new` returned by `encode_into`).
+/// * `sector_key_path` - path to the sector key replica.
+/// * `sector_key_cache_path` - path to the cache directory of the sector key.
+/// * `replica_path` - path to the updated replica.
+/// * `replica_cache_path` - path to the cache directory of the updated replica.
+///
+/// Fails before proving if the sector key and its cache don't match `comm_r_old`.
 #[allow(clippy::too_many_arguments)]
 pub fn generate_empty_sector_update_proof<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>>(
     porep_config: PoRepConfig,
@@ -529,101 +698,129 @@ pub fn generate_empty_sector_update_proof<Tree: 'static + MerkleTreeTrait<Hasher
     sector_key_cache_path: &Path,
     replica_path: &Path,
     replica_cache_path: &Path,
-) -> Result<EmptySectorUpdateProof> {
-    info!("generate_empty_sector_update_proof:start");
-
-    let comm_r_old_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_old)?;
-    let comm_r_new_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_new)?;
-
-    let comm_d_new_safe = DefaultPieceDomain::try_from_bytes(&comm_d_new)?;
-
-    let config = SectorUpdateConfig::from_porep_config(porep_config);
+) -> Result<EmptySectorUpdateProof, FilProofsError> {
+    classified(|| {
+        info!("generate_empty_sector_update_proof:start");
 
-    let p_aux_old = get_p_aux::<Tree>(sector_key_cache_path)?;
+        let comm_r_old_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_old)?;
+        let comm_r_new_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_new)?;
 
-    let partitions = usize::from(config.update_partitions);
-    let public_inputs: storage_proofs_update::PublicInputs = PublicInputs {
-        k: partitions,
-        comm_r_old: comm_r_old_safe,
-        comm_d_new: comm_d_new_safe,
-        comm_r_new: comm_r_new_safe,
-        h: usize::from(config.h_select),
-    };
-
-    let t_aux_old = get_t_aux::<Tree>(sector_key_cache_path)?;
+        let comm_d_new_safe = DefaultPieceDomain::try_from_bytes(&comm_d_new)?;
 
-    let (tree_d_new_config, tree_r_last_new_config) =
-        get_new_configs_from_t_aux_old::<Tree>(&t_aux_old, replica_cache_path, config.nodes_count)?;
+        let config = SectorUpdateConfig::from_porep_config(porep_config);
 
-    let private_inputs: PrivateInputs = PrivateInputs {
-        comm_c: p_aux_old.comm_c,
-        tree_r_old_config: t_aux_old.tree_r_last_config,
-        old_replica_path: sector_key_path.to_path_buf(),
-        tree_d_new_config,
-        tree_r_new_config: tree_r_last_new_config,
-        replica_path: replica_path.to_path_buf(),
-    };
-
-    let setup_params_compound = compound_proof::SetupParams {
-        vanilla_params: SetupParams {
-            sector_bytes: u64::from(config.sector_size),
-        },
-        partitions: Some(partitions),
-        priority: false,
-    };
-    let pub_params_compound = EmptySectorUpdateCompound::<Tree>::setup(&setup_params_compound)?;
+        let p_aux_old = get_p_aux::<Tree>(sector_key_cache_path)?;
+        ensure_sector_key_data_matches_comm_r_old::<Tree>(
+            &config,
+            &comm_r_old_safe,
+            &p_aux_old,
+            sector_key_path,
+        )?;
+        ensure_replica_len(replica_path, config.sector_size)?;
+
+        let partitions = usize::from(config.update_partitions);
+        let public_inputs: storage_proofs_update::PublicInputs = PublicInputs {
+            k: partitions,
+            comm_r_old: comm_r_old_safe,
+            comm_d_new: comm_d_new_safe,
+            comm_r_new: comm_r_new_safe,
+            h: usize::from(config.h_select),
+        };
+
+        let t_aux_old = get_t_aux::<Tree>(sector_key_cache_path)?;
+
+        let (tree_d_new_config, tree_r_last_new_config) = get_new_configs_from_t_aux_old::<Tree>(
+            &t_aux_old,
+            replica_cache_path,
+            config.nodes_count,
+        )?;
 
-    let groth_params = get_empty_sector_update_params::<Tree>(porep_config)?;
-    let multi_proof = EmptySectorUpdateCompound::prove(
-        &pub_params_compound,
-        &public_inputs,
-        &private_inputs,
-        &groth_params,
-    )?;
+        let private_inputs: PrivateInputs = PrivateInputs {
+            comm_c: p_aux_old.comm_c,
+            tree_r_old_config: t_aux_old.tree_r_last_config,
+            old_replica_path: sector_key_path.to_path_buf(),
+            tree_d_new_config,
+            tree_r_new_config: tree_r_last_new_config,
+            replica_path: replica_path.to_path_buf(),
+        };
+
+        let setup_params_compound = compound_proof::SetupParams {
+            vanilla_params: SetupParams {
+                sector_bytes: u64::from(config.sector_size),
+            },
+            partitions: Some(partitions),
+            priority: false,
+        };
+        let pub_params_compound = EmptySectorUpdateCompound::<Tree>::setup(&setup_params_compound)?;
+
+        let groth_params = get_empty_sector_update_params::<Tree>(porep_config)?;
+        let multi_proof = EmptySectorUpdateCompound::prove(
+            &pub_params_compound,
+            &public_inputs,
+            &private_inputs,
+            &groth_params,
+        )?;
 
-    info!("generate_empty_sector_update_proof:finish");
+        info!("generate_empty_sector_update_proof:finish");
 
-    Ok(EmptySectorUpdateProof(multi_proof.to_vec()?))
+        Ok(EmptySectorUpdateProof(multi_proof.to_vec()?))
+    })
 }
 
+/// Verifies a compound proof generated by `generate_empty_sector_update_proof`.
+///
+/// # Arguments
+///
+/// * `porep_config` - the porep config the sector key was sealed with.
+/// * `proof_bytes` - the serialized compound proof.
+/// * `comm_r_old` - commitment to the sector key replica.
+/// * `comm_r_new` - commitment to the updated replica.
+/// * `comm_d_new` - commitment to the new data.
 pub fn verify_empty_sector_update_proof<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>>(
     porep_config: PoRepConfig,
     proof_bytes: &[u8],
     comm_r_old: Commitment,
     comm_r_new: Commitment,
     comm_d_new: Commitment,
-) -> Result<bool> {
-    info!("verify_empty_sector_update_proof:start");
-
-    let comm_r_old_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_old)?;
-    let comm_r_new_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_new)?;
-
-    let comm_d_new_safe = DefaultPieceDomain::try_from_bytes(&comm_d_new)?;
-
-    let config = SectorUpdateConfig::from_porep_config(porep_config);
-    let partitions = usize::from(config.update_partitions);
-    let public_inputs: storage_proofs_update::PublicInputs = PublicInputs {
-        k: partitions,
-        comm_r_old: comm_r_old_safe,
-        comm_d_new: comm_d_new_safe,
-        comm_r_new: comm_r_new_safe,
-        h: usize::from(config.h_select),
-    };
-    let setup_params_compound = compound_proof::SetupParams {
-        vanilla_params: SetupParams {
-            sector_bytes: u64::from(config.sector_size),
-        },
-        partitions: Some(partitions),
-        priority: true,
-    };
-    let pub_params_compound = EmptySectorUpdateCompound::<Tree>::setup(&setup_params_compound)?;
-
-    let verifying_key = get_empty_sector_update_verifying_key::<Tree>(porep_config)?;
-    let multi_proof = MultiProof::new_from_bytes(Some(partitions), proof_bytes, &verifying_key)?;
-    let valid =
-        EmptySectorUpdateCompound::verify(&pub_params_compound, &public_inputs, &multi_proof, &())?;
+) -> Result<bool, FilProofsError> {
+    classified(|| {
+        info!("verify_empty_sector_update_proof:start");
+
+        let comm_r_old_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_old)?;
+        let comm_r_new_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_new)?;
+
+        let comm_d_new_safe = DefaultPieceDomain::try_from_bytes(&comm_d_new)?;
+
+        let config = SectorUpdateConfig::from_porep_config(porep_config);
+        let partitions = usize::from(config.update_partitions);
+        let public_inputs: storage_proofs_update::PublicInputs = PublicInputs {
+            k: partitions,
+            comm_r_old: comm_r_old_safe,
+            comm_d_new: comm_d_new_safe,
+            comm_r_new: comm_r_new_safe,
+            h: usize::from(config.h_select),
+        };
+        let setup_params_compound = compound_proof::SetupParams {
+            vanilla_params: SetupParams {
+                sector_bytes: u64::from(config.sector_size),
+            },
+            partitions: Some(partitions),
+            priority: true,
+        };
+        let pub_params_compound = EmptySectorUpdateCompound::<Tree>::setup(&setup_params_compound)?;
+
+        let verifying_key = get_empty_sector_update_verifying_key::<Tree>(porep_config)?;
+        let multi_proof =
+            MultiProof::new_from_bytes(Some(partitions), proof_bytes, &verifying_key)?;
+        let valid = EmptySectorUpdateCompound::verify(
+            &pub_params_compound,
+            &public_inputs,
+            &multi_proof,
+            &(),
+        )?;
 
-    info!("verify_empty_sector_update_proof:finish");
+        info!("verify_empty_sector_update_proof:finish");
 
-    Ok(valid)
+        Ok(valid)
+    })
 }
@@ -1,22 +1,47 @@
+use std::fs::{self, metadata, File, Metadata};
+use std::io::{Seek, SeekFrom};
 use std::mem::size_of;
+use std::path::Path;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
+use bincode::deserialize;
 use blstrs::Scalar as Fr;
 use filecoin_hashers::{Domain, Hasher};
 use fr32::{bytes_into_fr, fr_into_bytes};
-use merkletree::merkle::{get_merkle_tree_leafs, get_merkle_tree_len};
-use storage_proofs_core::merkle::{get_base_tree_count, MerkleTreeTrait};
+use log::warn;
+use merkletree::{
+    merkle::{get_merkle_tree_cache_size, get_merkle_tree_leafs, get_merkle_tree_len},
+    store::StoreConfig,
+};
+use storage_proofs_core::{
+    cache_key::CacheKey,
+    error::Error,
+    merkle::{get_base_tree_count, split_config, MerkleTreeTrait},
+    settings::SETTINGS,
+    util::{default_rows_to_discard, NODE_SIZE},
+};
+use storage_proofs_porep::stacked::TemporaryAux;
 use typenum::Unsigned;
 
-use crate::types::{Commitment, SectorSize};
+use crate::{
+    constants::DefaultPieceHasher,
+    error::{classified, FilProofsError},
+    types::{Commitment, SectorSize},
+};
+
+/// The logical block size a block device holding a replica has to be aligned to.
+const BLOCK_DEVICE_ALIGNMENT: u64 = 512;
 
 pub fn as_safe_commitment<H: Domain, T: AsRef<str>>(
     comm: &[u8; 32],
     commitment_name: T,
-) -> Result<H> {
-    bytes_into_fr(comm)
-        .map(Into::into)
-        .with_context(|| format!("Invalid commitment ({})", commitment_name.as_ref(),))
+) -> Result<H, FilProofsError> {
+    bytes_into_fr(comm).map(Into::into).map_err(|err| {
+        FilProofsError::InvalidInput(
+            anyhow::Error::from(err)
+                .context(format!("Invalid commitment ({})", commitment_name.as_ref(),)),
+        )
+    })
 }
 
 pub fn commitment_from_fr(fr: Fr) -> Commitment {
@@ -27,14 +52,266 @@ pub fn commitment_from_fr(fr: Fr) -> Commitment {
     commitment
 }
 
-pub fn get_base_tree_size<Tree: MerkleTreeTrait>(sector_size: SectorSize) -> Result<usize> {
+pub fn get_base_tree_size<Tree: MerkleTreeTrait>(
+    sector_size: SectorSize,
+) -> Result<usize, FilProofsError> {
     let base_tree_leaves = u64::from(sector_size) as usize
         / size_of::<<Tree::Hasher as Hasher>::Domain>()
         / get_base_tree_count::<Tree>();
 
     get_merkle_tree_len(base_tree_leaves, Tree::Arity::to_usize())
+        .map_err(FilProofsError::InvalidInput)
 }
 
-pub fn get_base_tree_leafs<Tree: MerkleTreeTrait>(base_tree_size: usize) -> Result<usize> {
+pub fn get_base_tree_leafs<Tree: MerkleTreeTrait>(
+    base_tree_size: usize,
+) -> Result<usize, FilProofsError> {
     get_merkle_tree_leafs(base_tree_size, Tree::Arity::to_usize())
+        .map_err(FilProofsError::InvalidInput)
+}
+
+/// Returns the number of rows the base trees of tree_r_last in `cache_dir`, which have
+/// `base_tree_leafs` leaves, were stored without.
+///
+/// A sector may be sealed with another value than the default, see
+/// `seal_pre_commit_phase2_with_rows_to_discard`, which its t_aux records. If there is no readable
+/// t_aux in `cache_dir`, the rows are inferred from the length of the store of the first base
+/// tree instead. If that doesn't exist, or its length matches no number of rows, an error is
+/// returned, since the tree cannot be opened either way.
+pub fn get_tree_r_last_rows_to_discard<Tree: MerkleTreeTrait>(
+    cache_dir: &Path,
+    base_tree_leafs: usize,
+) -> Result<usize, FilProofsError> {
+    classified(|| get_tree_r_last_rows_to_discard_inner::<Tree>(cache_dir, base_tree_leafs))
+}
+
+fn get_tree_r_last_rows_to_discard_inner<Tree: MerkleTreeTrait>(
+    cache_dir: &Path,
+    base_tree_leafs: usize,
+) -> Result<usize> {
+    let t_aux_path = cache_dir.join(CacheKey::TAux.to_string());
+    if let Ok(t_aux_bytes) = fs::read(&t_aux_path) {
+        match deserialize::<TemporaryAux<Tree, DefaultPieceHasher>>(&t_aux_bytes) {
+            Ok(t_aux) => return Ok(t_aux.tree_r_last_config.rows_to_discard),
+            Err(err) => warn!("ignoring t_aux {:?}: {}", t_aux_path, err),
+        }
+    }
+
+    let arity = Tree::Arity::to_usize();
+    let default = default_rows_to_discard(base_tree_leafs, arity);
+    let config = StoreConfig::new(cache_dir, CacheKey::CommRLastTree.to_string(), default);
+    let first_config = split_config(config, get_base_tree_count::<Tree>())?.remove(0);
+    let store_path = StoreConfig::data_path(&first_config.path, &first_config.id);
+    let len = metadata(&store_path)
+        .with_context(|| format!("tree_r_last store {:?} is missing", store_path))?
+        .len();
+
+    let rows = (base_tree_leafs.trailing_zeros() / arity.trailing_zeros()) as usize;
+    (0..=rows)
+        .find(|rows_to_discard| {
+            get_merkle_tree_cache_size(base_tree_leafs, arity, *rows_to_discard)
+                .map_or(false, |size| (size * NODE_SIZE) as u64 == len)
+        })
+        .with_context(|| {
+            format!(
+                "tree_r_last store {:?} has length {}, which matches no number of discarded rows",
+                store_path, len
+            )
+        })
+}
+
+#[cfg(unix)]
+fn is_block_device(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    metadata.file_type().is_block_device()
+}
+
+#[cfg(not(unix))]
+fn is_block_device(_metadata: &Metadata) -> bool {
+    false
+}
+
+/// Returns the length in bytes of the replica of a sector of `sector_size` bytes at
+/// `replica_path`.
+///
+/// For a file, this is its length. A block device doesn't report its length through the metadata,
+/// so a replica on one is taken to be `sector_size` bytes at the start of the device. This is only
+/// accepted with `SETTINGS.block_device_replicas`, and if both the sector and the device are
+/// aligned to 512 bytes and the device is long enough to hold the sector.
+pub fn replica_len(replica_path: &Path, sector_size: SectorSize) -> Result<u64, FilProofsError> {
+    classified(|| replica_len_inner(replica_path, sector_size))
+}
+
+fn replica_len_inner(replica_path: &Path, sector_size: SectorSize) -> Result<u64> {
+    let metadata = metadata(replica_path)
+        .with_context(|| format!("could not read metadata of replica={:?}", replica_path))?;
+    if metadata.is_file() {
+        return Ok(metadata.len());
+    }
+    if !is_block_device(&metadata) {
+        bail!(
+            "replica {:?} is neither a file nor a block device",
+            replica_path
+        );
+    }
+
+    ensure!(
+        SETTINGS.block_device_replicas,
+        "replica {:?} is a block device, which requires block_device_replicas to be set",
+        replica_path
+    );
+    let len = u64::from(sector_size);
+    let device_len = File::open(replica_path)
+        .and_then(|mut device| device.seek(SeekFrom::End(0)))
+        .with_context(|| format!("could not read length of replica={:?}", replica_path))?;
+    ensure!(
+        len % BLOCK_DEVICE_ALIGNMENT == 0 && device_len % BLOCK_DEVICE_ALIGNMENT == 0,
+        "replica {:?} of {} bytes on a device of {} bytes is not aligned to {} bytes",
+        replica_path,
+        len,
+        device_len,
+        BLOCK_DEVICE_ALIGNMENT
+    );
+    if device_len < len {
+        return Err(Error::TruncatedReplica {
+            expected: len,
+            actual: device_len,
+        }
+        .into());
+    }
+
+    Ok(len)
+}
+
+/// Ensures that the replica at `replica_path` holds a whole sector of `sector_size` bytes.
+///
+/// Fails with [`Error::TruncatedReplica`] for shorter files, e.g. after an interrupted copy, which
+/// would otherwise only surface once trees are built or proofs are read past the end of the file.
+/// Block devices are checked as described for [`replica_len`].
+pub fn ensure_replica_len(
+    replica_path: &Path,
+    sector_size: SectorSize,
+) -> Result<(), FilProofsError> {
+    classified(|| {
+        let expected = u64::from(sector_size);
+        let actual = replica_len_inner(replica_path, sector_size)?;
+        if actual < expected {
+            return Err(Error::TruncatedReplica { expected, actual }.into());
+        }
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::marker::PhantomData;
+
+    use bincode::serialize;
+    use storage_proofs_porep::stacked::Labels;
+    use tempfile::tempdir;
+
+    use crate::constants::{SectorShape2KiB, SECTOR_SIZE_2_KIB};
+
+    #[test]
+    fn test_ensure_replica_len() {
+        let dir = tempdir().expect("failed to create temp dir");
+        let replica_path = dir.path().join("replica");
+        let replica = File::create(&replica_path).expect("failed to create replica");
+        let sector_size = SectorSize(SECTOR_SIZE_2_KIB);
+
+        replica
+            .set_len(SECTOR_SIZE_2_KIB)
+            .expect("failed to set replica length");
+        ensure_replica_len(&replica_path, sector_size).expect("whole replica rejected");
+
+        replica
+            .set_len(SECTOR_SIZE_2_KIB - 32)
+            .expect("failed to set replica length");
+        let err =
+            ensure_replica_len(&replica_path, sector_size).expect_err("truncated replica accepted");
+        assert!(matches!(err, FilProofsError::CacheCorrupt(_)));
+        match err.into_inner().downcast_ref::<Error>() {
+            Some(Error::TruncatedReplica { expected, actual }) => {
+                assert_eq!(*expected, SECTOR_SIZE_2_KIB);
+                assert_eq!(*actual, SECTOR_SIZE_2_KIB - 32);
+            }
+            _ => panic!("unexpected error: {:?}", err),
+        }
+
+        assert!(ensure_replica_len(&dir.path().join("missing"), sector_size).is_err());
+        assert!(ensure_replica_len(dir.path(), sector_size).is_err());
+    }
+
+    #[test]
+    fn test_get_tree_r_last_rows_to_discard() {
+        let dir = tempdir().expect("failed to create temp dir");
+        let leafs = SECTOR_SIZE_2_KIB as usize / NODE_SIZE;
+        let rows_to_discard =
+            || get_tree_r_last_rows_to_discard::<SectorShape2KiB>(dir.path(), leafs);
+
+        assert!(rows_to_discard().is_err());
+
+        let store_path = StoreConfig::data_path(dir.path(), &CacheKey::CommRLastTree.to_string());
+        for rows in 0..2 {
+            let size = get_merkle_tree_cache_size(leafs, 8, rows).expect("cache size failure");
+            File::create(&store_path)
+                .and_then(|store| store.set_len((size * NODE_SIZE) as u64))
+                .expect("failed to create store");
+            assert_eq!(
+                rows_to_discard().expect("get_tree_r_last_rows_to_discard failure"),
+                rows
+            );
+        }
+
+        File::create(&store_path)
+            .and_then(|store| store.set_len(3))
+            .expect("failed to create store");
+        assert!(rows_to_discard().is_err());
+
+        // t_aux takes precedence over the length of the store, ...
+        let t_aux_path = dir.path().join(CacheKey::TAux.to_string());
+        let t_aux = TemporaryAux::<SectorShape2KiB, DefaultPieceHasher> {
+            labels: Labels::new(Vec::new()),
+            tree_d_config: StoreConfig::new(dir.path(), CacheKey::CommDTree.to_string(), 0),
+            tree_r_last_config: StoreConfig::new(
+                dir.path(),
+                CacheKey::CommRLastTree.to_string(),
+                1,
+            ),
+            tree_c_config: StoreConfig::new(dir.path(), CacheKey::CommCTree.to_string(), 1),
+            _g: PhantomData,
+        };
+        fs::write(
+            &t_aux_path,
+            serialize(&t_aux).expect("failed to serialize t_aux"),
+        )
+        .expect("failed to write t_aux");
+        assert_eq!(
+            rows_to_discard().expect("get_tree_r_last_rows_to_discard failure"),
+            1
+        );
+
+        // ... which is only the fallback if t_aux cannot be read.
+        fs::write(&t_aux_path, [0u8; 3]).expect("failed to write t_aux");
+        assert!(rows_to_discard().is_err());
+    }
+
+    #[test]
+    fn test_replica_len() {
+        let dir = tempdir().expect("failed to create temp dir");
+        let replica_path = dir.path().join("replica");
+        let replica = File::create(&replica_path).expect("failed to create replica");
+        replica.set_len(100).expect("failed to set replica length");
+
+        // The length of a file is its own, not the sector size.
+        assert_eq!(
+            replica_len(&replica_path, SectorSize(SECTOR_SIZE_2_KIB)).expect("replica_len failure"),
+            100
+        );
+    }
 }
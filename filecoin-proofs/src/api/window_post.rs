@@ -1,178 +1,437 @@
-use std::collections::BTreeMap;
-
-use anyhow::{ensure, Context, Result};
-use filecoin_hashers::Hasher;
-use log::info;
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use bellperson::groth16;
+use blstrs::Bls12;
+use filecoin_hashers::{Domain, Hasher};
+use log::{error, info};
+use rayon::prelude::*;
 use storage_proofs_core::{
     compound_proof::{self, CompoundProof},
+    error::Error as StorageProofsError,
     merkle::MerkleTreeTrait,
     multi_proof::MultiProof,
     sector::SectorId,
+    settings::SETTINGS,
 };
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+use storage_proofs_porep::stacked::install_on_core_groups;
 use storage_proofs_post::fallback::{
     self, FallbackPoSt, FallbackPoStCompound, PrivateSector, PublicSector,
 };
 
 use crate::{
     api::{
-        as_safe_commitment, get_partitions_for_window_post, partition_vanilla_proofs,
-        single_partition_vanilla_proofs,
+        as_safe_commitment, generate_fallback_sector_challenges, generate_single_vanilla_proof,
+        get_partitions_for_window_post, partition_vanilla_proofs, single_partition_vanilla_proofs,
     },
     caches::{get_post_params, get_post_verifying_key},
+    error::{classified, ensure_input, FilProofsError},
     parameters::window_post_setup_params,
     types::{
-        ChallengeSeed, FallbackPoStSectorProof, PoStConfig, PrivateReplicaInfo, ProverId,
-        PublicReplicaInfo, SnarkProof,
+        ChallengeSeed, FallbackPoStSectorProof, PartitionVerification, PoStConfig,
+        PrivateReplicaInfo, ProverId, PublicReplicaInfo, SnarkProof, WindowPoStFragment,
+        WindowPoStVerification,
     },
     PartitionSnarkProof, PoStType,
 };
 
 /// Generates a Window proof-of-spacetime with provided vanilla proofs.
+///
+/// The vanilla proofs may be passed in any order, they are proven in ascending sector id order,
+/// like the sectors of [`generate_window_post`].
 pub fn generate_window_post_with_vanilla<Tree: 'static + MerkleTreeTrait>(
     post_config: &PoStConfig,
     randomness: &ChallengeSeed,
     prover_id: ProverId,
-    vanilla_proofs: Vec<FallbackPoStSectorProof<Tree>>,
-) -> Result<SnarkProof> {
-    info!("generate_window_post_with_vanilla:start");
-    ensure!(
-        post_config.typ == PoStType::Window,
-        "invalid post config type"
-    );
-
-    let randomness_safe: <Tree::Hasher as Hasher>::Domain =
-        as_safe_commitment(randomness, "randomness")?;
-    let prover_id_safe: <Tree::Hasher as Hasher>::Domain =
-        as_safe_commitment(&prover_id, "prover_id")?;
-
-    let vanilla_params = window_post_setup_params(post_config);
-    let partitions = get_partitions_for_window_post(vanilla_proofs.len(), post_config);
-
-    let setup_params = compound_proof::SetupParams {
-        vanilla_params,
-        partitions,
-        priority: post_config.priority,
-    };
-
-    let partitions = partitions.unwrap_or(1);
-
-    let pub_params: compound_proof::PublicParams<'_, FallbackPoSt<'_, Tree>> =
-        FallbackPoStCompound::setup(&setup_params)?;
-    let groth_params = get_post_params::<Tree>(post_config)?;
-
-    let mut pub_sectors = Vec::with_capacity(vanilla_proofs.len());
-    for vanilla_proof in &vanilla_proofs {
-        pub_sectors.push(PublicSector {
-            id: vanilla_proof.sector_id,
-            comm_r: vanilla_proof.comm_r,
-        });
-    }
-
-    let pub_inputs = fallback::PublicInputs {
-        randomness: randomness_safe,
-        prover_id: prover_id_safe,
-        sectors: pub_sectors,
-        k: None,
-    };
+    mut vanilla_proofs: Vec<FallbackPoStSectorProof<Tree>>,
+) -> Result<SnarkProof, FilProofsError> {
+    classified(|| {
+        info!("generate_window_post_with_vanilla:start");
+        ensure_input!(
+            post_config.typ == PoStType::Window,
+            "invalid post config type"
+        );
+        sort_vanilla_proofs(&mut vanilla_proofs)?;
+
+        let randomness_safe: <Tree::Hasher as Hasher>::Domain =
+            as_safe_commitment(randomness, "randomness")?;
+        let prover_id_safe: <Tree::Hasher as Hasher>::Domain =
+            as_safe_commitment(&prover_id, "prover_id")?;
+
+        let vanilla_params = window_post_setup_params(post_config);
+        let partitions = get_partitions_for_window_post(vanilla_proofs.len(), post_config);
+
+        let setup_params = compound_proof::SetupParams {
+            vanilla_params,
+            partitions,
+            priority: post_config.priority,
+        };
+
+        let partitions = partitions.unwrap_or(1);
+
+        let pub_params: compound_proof::PublicParams<'_, FallbackPoSt<'_, Tree>> =
+            FallbackPoStCompound::setup(&setup_params)?;
+        let groth_params = get_post_params::<Tree>(post_config)?;
+
+        let mut pub_sectors = Vec::with_capacity(vanilla_proofs.len());
+        for vanilla_proof in &vanilla_proofs {
+            pub_sectors.push(PublicSector {
+                id: vanilla_proof.sector_id,
+                comm_r: vanilla_proof.comm_r,
+            });
+        }
+
+        let pub_inputs = fallback::PublicInputs {
+            randomness: randomness_safe,
+            prover_id: prover_id_safe,
+            sectors: pub_sectors,
+            k: None,
+        };
+
+        let partitioned_proofs = partition_vanilla_proofs(
+            post_config,
+            &pub_params.vanilla_params,
+            &pub_inputs,
+            partitions,
+            &vanilla_proofs,
+        )?;
 
-    let partitioned_proofs = partition_vanilla_proofs(
-        post_config,
-        &pub_params.vanilla_params,
-        &pub_inputs,
-        partitions,
-        &vanilla_proofs,
-    )?;
+        let proof = FallbackPoStCompound::prove_with_vanilla(
+            &pub_params,
+            &pub_inputs,
+            partitioned_proofs,
+            &groth_params,
+        )?;
 
-    let proof = FallbackPoStCompound::prove_with_vanilla(
-        &pub_params,
-        &pub_inputs,
-        partitioned_proofs,
-        &groth_params,
-    )?;
+        info!("generate_window_post_with_vanilla:finish");
 
-    info!("generate_window_post_with_vanilla:finish");
+        proof.to_vec()
+    })
+}
 
-    proof.to_vec()
+/// Generates a Window proof-of-spacetime from the vanilla proofs of `fragments`, which were each
+/// generated for some of its sectors, e.g. on the machines storing them.
+///
+/// Every fragment has to be for the same randomness, prover id and sectors, and together they have
+/// to hold exactly one vanilla proof of each of the sectors. The vanilla proofs are verified
+/// against the challenges of their sector before proving, so that a fragment generated for other
+/// sectors is reported as such instead of failing the proof. The proof is a regular Window PoSt,
+/// which [`verify_window_post`] verifies.
+///
+/// # Arguments
+///
+/// * `post_config` - the config of the proof.
+/// * `fragments` - the vanilla proofs to prove, in any order.
+pub fn merge_window_post_fragments<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    fragments: Vec<WindowPoStFragment<Tree>>,
+) -> Result<SnarkProof, FilProofsError> {
+    classified(|| {
+        info!(
+            "merge_window_post_fragments:start: {} fragments",
+            fragments.len()
+        );
+        ensure_input!(
+            post_config.typ == PoStType::Window,
+            "invalid post config type"
+        );
+        ensure_input!(!fragments.is_empty(), "no fragments to merge");
+
+        let randomness = fragments[0].randomness;
+        let prover_id = fragments[0].prover_id;
+        let sectors: BTreeSet<SectorId> = fragments[0].sectors.iter().copied().collect();
+        for (i, fragment) in fragments.iter().enumerate() {
+            ensure_input!(
+                fragment.randomness == randomness,
+                "fragment {} is for other randomness",
+                i
+            );
+            ensure_input!(
+                fragment.prover_id == prover_id,
+                "fragment {} is for another prover id",
+                i
+            );
+            ensure_input!(
+                fragment.sectors.len() == sectors.len()
+                    && fragment
+                        .sectors
+                        .iter()
+                        .all(|sector| sectors.contains(sector)),
+                "fragment {} is for other sectors",
+                i
+            );
+        }
+
+        let sector_ids: Vec<SectorId> = sectors.iter().copied().collect();
+        let randomness_safe = as_safe_commitment(&randomness, "randomness")?;
+        let vanilla_params = window_post_setup_params(post_config);
+
+        let mut proven = BTreeSet::new();
+        let mut vanilla_proofs = Vec::with_capacity(sector_ids.len());
+        for (i, fragment) in fragments.into_iter().enumerate() {
+            for vanilla_proof in fragment.vanilla_proofs {
+                let sector_id = vanilla_proof.sector_id;
+                let sector_index = sector_ids.binary_search(&sector_id).map_err(|_| {
+                    anyhow!(
+                        "fragment {} proves sector {:?}, which is not proven",
+                        i,
+                        sector_id
+                    )
+                })?;
+                ensure_input!(
+                    proven.insert(sector_id),
+                    "fragment {} proves sector {:?}, which is already proven",
+                    i,
+                    sector_id
+                );
+                ensure_input!(
+                    vanilla_proof.vanilla_proof.sectors.len() == 1,
+                    "vanilla proof of sector {:?} in fragment {} does not prove a single sector",
+                    sector_id,
+                    i
+                );
+
+                let verification = fallback::verify_sector_proof(
+                    &vanilla_params,
+                    &randomness_safe,
+                    sector_index,
+                    &PublicSector {
+                        id: sector_id,
+                        comm_r: vanilla_proof.comm_r,
+                    },
+                    &vanilla_proof.vanilla_proof.sectors[0],
+                )?;
+                ensure!(
+                    verification.is_valid(),
+                    "vanilla proof of sector {:?} in fragment {} is invalid",
+                    sector_id,
+                    i
+                );
+
+                vanilla_proofs.push(vanilla_proof);
+            }
+        }
+        if let Some(sector_id) = sectors.difference(&proven).next() {
+            bail!("no fragment proves sector {:?}", sector_id);
+        }
+
+        let proof = generate_window_post_with_vanilla::<Tree>(
+            post_config,
+            &randomness,
+            prover_id,
+            vanilla_proofs,
+        )?;
+
+        info!("merge_window_post_fragments:finish");
+
+        Ok(proof)
+    })
 }
 
 /// Generates a Window proof-of-spacetime.
+///
+/// The sectors are challenged and proven in ascending sector id order, the order of `replicas`,
+/// which is also the order in which [`verify_window_post`] expects them.
 pub fn generate_window_post<Tree: 'static + MerkleTreeTrait>(
     post_config: &PoStConfig,
     randomness: &ChallengeSeed,
     replicas: &BTreeMap<SectorId, PrivateReplicaInfo<Tree>>,
     prover_id: ProverId,
-) -> Result<SnarkProof> {
-    info!("generate_window_post:start");
-    ensure!(
-        post_config.typ == PoStType::Window,
-        "invalid post config type"
-    );
-
-    let randomness_safe = as_safe_commitment(randomness, "randomness")?;
-    let prover_id_safe = as_safe_commitment(&prover_id, "prover_id")?;
-
-    let vanilla_params = window_post_setup_params(post_config);
-    let partitions = get_partitions_for_window_post(replicas.len(), post_config);
-
-    let sector_count = vanilla_params.sector_count;
-    let setup_params = compound_proof::SetupParams {
-        vanilla_params,
-        partitions,
-        priority: post_config.priority,
-    };
-
-    let pub_params: compound_proof::PublicParams<'_, FallbackPoSt<'_, Tree>> =
-        FallbackPoStCompound::setup(&setup_params)?;
-    let groth_params = get_post_params::<Tree>(post_config)?;
+) -> Result<SnarkProof, FilProofsError> {
+    classified(|| {
+        info!("generate_window_post:start");
+        ensure_input!(
+            post_config.typ == PoStType::Window,
+            "invalid post config type"
+        );
+
+        let batch_size = SETTINGS.post_challenge_batch;
+        if batch_size > 0 && replicas.len() > batch_size {
+            info!(
+                "generate_window_post: proving {} sectors in batches of {}",
+                replicas.len(),
+                batch_size
+            );
+            let vanilla_proofs = generate_window_post_vanilla_proofs::<Tree>(
+                post_config,
+                randomness,
+                replicas,
+                prover_id,
+                batch_size,
+            )?;
+            let proof = generate_window_post_with_vanilla::<Tree>(
+                post_config,
+                randomness,
+                prover_id,
+                vanilla_proofs,
+            )?;
+
+            info!("generate_window_post:finish");
+
+            return Ok(proof);
+        }
+
+        let randomness_safe = as_safe_commitment(randomness, "randomness")?;
+        let prover_id_safe = as_safe_commitment(&prover_id, "prover_id")?;
+
+        let vanilla_params = window_post_setup_params(post_config);
+        let partitions = get_partitions_for_window_post(replicas.len(), post_config);
+
+        let sector_count = vanilla_params.sector_count;
+        let setup_params = compound_proof::SetupParams {
+            vanilla_params,
+            partitions,
+            priority: post_config.priority,
+        };
+
+        let pub_params: compound_proof::PublicParams<'_, FallbackPoSt<'_, Tree>> =
+            FallbackPoStCompound::setup(&setup_params)?;
+        let groth_params = get_post_params::<Tree>(post_config)?;
+
+        let trees: Vec<_> = replicas
+            .iter()
+            .map(|(sector_id, replica)| {
+                replica
+                    .merkle_tree(post_config.sector_size)
+                    .with_context(|| {
+                        format!("generate_window_post: merkle_tree failed: {:?}", sector_id)
+                    })
+            })
+            .collect::<Result<_>>()?;
 
-    let trees: Vec<_> = replicas
-        .iter()
-        .map(|(sector_id, replica)| {
-            replica
-                .merkle_tree(post_config.sector_size)
-                .with_context(|| {
-                    format!("generate_window_post: merkle_tree failed: {:?}", sector_id)
-                })
-        })
-        .collect::<Result<_>>()?;
+        let mut pub_sectors = Vec::with_capacity(sector_count);
+        let mut priv_sectors = Vec::with_capacity(sector_count);
 
-    let mut pub_sectors = Vec::with_capacity(sector_count);
-    let mut priv_sectors = Vec::with_capacity(sector_count);
+        for ((sector_id, replica), tree) in replicas.iter().zip(trees.iter()) {
+            let comm_r = replica.safe_comm_r().with_context(|| {
+                format!("generate_window_post: safe_comm_r failed: {:?}", sector_id)
+            })?;
+            let comm_c = replica.safe_comm_c();
+            let comm_r_last = replica.safe_comm_r_last();
 
-    for ((sector_id, replica), tree) in replicas.iter().zip(trees.iter()) {
-        let comm_r = replica.safe_comm_r().with_context(|| {
-            format!("generate_window_post: safe_comm_r failed: {:?}", sector_id)
-        })?;
-        let comm_c = replica.safe_comm_c();
-        let comm_r_last = replica.safe_comm_r_last();
+            pub_sectors.push(PublicSector {
+                id: *sector_id,
+                comm_r,
+            });
+            priv_sectors.push(PrivateSector {
+                tree,
+                comm_c,
+                comm_r_last,
+                rows_to_discard: Some(replica.rows_to_discard(post_config.sector_size)?),
+            });
+        }
+
+        let pub_inputs = fallback::PublicInputs {
+            randomness: randomness_safe,
+            prover_id: prover_id_safe,
+            sectors: pub_sectors,
+            k: None,
+        };
+
+        let priv_inputs = fallback::PrivateInputs::<Tree> {
+            sectors: &priv_sectors,
+        };
+
+        let proof =
+            FallbackPoStCompound::prove(&pub_params, &pub_inputs, &priv_inputs, &groth_params)?;
+
+        info!("generate_window_post:finish");
+
+        proof.to_vec()
+    })
+}
 
-        pub_sectors.push(PublicSector {
-            id: *sector_id,
-            comm_r,
-        });
-        priv_sectors.push(PrivateSector {
-            tree,
-            comm_c,
-            comm_r_last,
+/// Generates the vanilla proofs of a Window proof-of-spacetime for `replicas`, keeping the trees
+/// of at most `batch_size` sectors loaded at once.
+///
+/// The proofs are returned in sector order and do not depend on `batch_size`, they can be passed
+/// to [`generate_window_post_with_vanilla`] as they are. If the proofs of some sectors fail, all
+/// other batches are still proven, and the error lists every failed sector, like the one of
+/// [`generate_window_post`].
+///
+/// With the `multicore-sdr` or `multicore-sdr-affinity` feature, the proving threads are bound to
+/// the cores of at most `multicore_sdr_post_core_groups` core groups that are not checked out by
+/// sealing, and run unbound if there are none.
+pub fn generate_window_post_vanilla_proofs<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    replicas: &BTreeMap<SectorId, PrivateReplicaInfo<Tree>>,
+    prover_id: ProverId,
+    batch_size: usize,
+) -> Result<Vec<FallbackPoStSectorProof<Tree>>, FilProofsError> {
+    classified(|| {
+        info!("generate_window_post_vanilla_proofs:start");
+        ensure_input!(
+            post_config.typ == PoStType::Window,
+            "invalid post config type"
+        );
+        ensure_input!(batch_size > 0, "batch size must be greater than zero");
+
+        let sector_ids: Vec<SectorId> = replicas.keys().copied().collect();
+        let challenges = generate_fallback_sector_challenges::<Tree>(
+            post_config,
+            randomness,
+            &sector_ids,
+            prover_id,
+        )?;
+
+        let replicas: Vec<_> = replicas.iter().collect();
+        let mut vanilla_proofs = Vec::with_capacity(replicas.len());
+        let mut faulty_sectors = Vec::new();
+        install_vanilla_workers(batch_size.min(replicas.len()), || {
+            for batch in replicas.chunks(batch_size) {
+                let batch_proofs: Vec<_> = batch
+                    .par_iter()
+                    .map(|(sector_id, replica)| {
+                        generate_single_vanilla_proof::<Tree>(
+                            post_config,
+                            **sector_id,
+                            replica,
+                            &challenges[*sector_id],
+                        )
+                    })
+                    .collect();
+                // The trees of this batch are dropped before the next one is loaded.
+                for ((sector_id, _), proof) in batch.iter().zip(batch_proofs) {
+                    match proof {
+                        Ok(proof) => vanilla_proofs.push(proof),
+                        Err(err) => {
+                            error!("faulty sector: {:?} ({:?})", sector_id, err);
+                            faulty_sectors.push(**sector_id);
+                        }
+                    }
+                }
+            }
         });
-    }
-
-    let pub_inputs = fallback::PublicInputs {
-        randomness: randomness_safe,
-        prover_id: prover_id_safe,
-        sectors: pub_sectors,
-        k: None,
-    };
+        if !faulty_sectors.is_empty() {
+            return Err(StorageProofsError::FaultySectors(faulty_sectors).into());
+        }
 
-    let priv_inputs = fallback::PrivateInputs::<Tree> {
-        sectors: &priv_sectors,
-    };
+        info!("generate_window_post_vanilla_proofs:finish");
 
-    let proof = FallbackPoStCompound::prove(&pub_params, &pub_inputs, &priv_inputs, &groth_params)?;
+        Ok(vanilla_proofs)
+    })
+}
 
-    info!("generate_window_post:finish");
+/// Runs the vanilla proving of `f` on workers bound to at most `multicore_sdr_post_core_groups`
+/// free core groups, if any.
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+fn install_vanilla_workers<F, T>(max_threads: usize, f: F) -> T
+where
+    F: FnOnce() -> T + Send,
+    T: Send,
+{
+    install_on_core_groups(SETTINGS.multicore_sdr_post_core_groups, max_threads, f)
+}
 
-    proof.to_vec()
+#[cfg(not(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity")))]
+fn install_vanilla_workers<F, T>(_max_threads: usize, f: F) -> T
+where
+    F: FnOnce() -> T + Send,
+    T: Send,
+{
+    f()
 }
 
 /// Verifies a window proof-of-spacetime.
@@ -182,10 +441,65 @@ pub fn verify_window_post<Tree: 'static + MerkleTreeTrait>(
     replicas: &BTreeMap<SectorId, PublicReplicaInfo>,
     prover_id: ProverId,
     proof: &[u8],
-) -> Result<bool> {
-    info!("verify_window_post:start");
+) -> Result<bool, FilProofsError> {
+    classified(|| {
+        info!("verify_window_post:start");
+
+        let is_valid = verify_window_post_inner::<Tree>(
+            post_config,
+            randomness,
+            replicas,
+            prover_id,
+            proof,
+            None,
+        )?;
+
+        info!("verify_window_post:finish");
+
+        Ok(is_valid)
+    })
+}
 
-    ensure!(
+/// Verifies a window proof-of-spacetime against the given `verifying_key`, instead of the one
+/// from the parameter cache.
+///
+/// This doesn't access the disk. The caller is responsible for passing the verifying key matching
+/// `post_config`.
+pub fn verify_window_post_with_verifying_key<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    verifying_key: &groth16::PreparedVerifyingKey<Bls12>,
+    randomness: &ChallengeSeed,
+    replicas: &BTreeMap<SectorId, PublicReplicaInfo>,
+    prover_id: ProverId,
+    proof: &[u8],
+) -> Result<bool, FilProofsError> {
+    classified(|| {
+        info!("verify_window_post_with_verifying_key:start");
+
+        let is_valid = verify_window_post_inner::<Tree>(
+            post_config,
+            randomness,
+            replicas,
+            prover_id,
+            proof,
+            Some(verifying_key),
+        )?;
+
+        info!("verify_window_post_with_verifying_key:finish");
+
+        Ok(is_valid)
+    })
+}
+
+fn verify_window_post_inner<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    replicas: &BTreeMap<SectorId, PublicReplicaInfo>,
+    prover_id: ProverId,
+    proof: &[u8],
+    verifying_key: Option<&groth16::PreparedVerifyingKey<Bls12>>,
+) -> Result<bool> {
+    ensure_input!(
         post_config.typ == PoStType::Window,
         "invalid post config type"
     );
@@ -204,29 +518,23 @@ pub fn verify_window_post<Tree: 'static + MerkleTreeTrait>(
     let pub_params: compound_proof::PublicParams<'_, FallbackPoSt<'_, Tree>> =
         FallbackPoStCompound::setup(&setup_params)?;
 
-    let pub_sectors: Vec<_> = replicas
-        .iter()
-        .map(|(sector_id, replica)| {
-            let comm_r = replica.safe_comm_r().with_context(|| {
-                format!("verify_window_post: safe_comm_r failed: {:?}", sector_id)
-            })?;
-            Ok(PublicSector {
-                id: *sector_id,
-                comm_r,
-            })
-        })
-        .collect::<Result<_>>()?;
-
     let pub_inputs = fallback::PublicInputs {
         randomness: randomness_safe,
         prover_id: prover_id_safe,
-        sectors: pub_sectors,
+        sectors: window_post_public_sectors(replicas)?,
         k: None,
     };
 
     let is_valid = {
-        let verifying_key = get_post_verifying_key::<Tree>(post_config)?;
-        let multi_proof = MultiProof::new_from_reader(partitions, proof, &verifying_key)?;
+        let loaded_verifying_key;
+        let verifying_key = match verifying_key {
+            Some(verifying_key) => verifying_key,
+            None => {
+                loaded_verifying_key = get_post_verifying_key::<Tree>(post_config)?;
+                &*loaded_verifying_key
+            }
+        };
+        let multi_proof = MultiProof::new_from_reader(partitions, proof, verifying_key)?;
 
         FallbackPoStCompound::verify(
             &pub_params,
@@ -237,77 +545,232 @@ pub fn verify_window_post<Tree: 'static + MerkleTreeTrait>(
             },
         )?
     };
-    if !is_valid {
-        return Ok(false);
-    }
+    Ok(is_valid)
+}
 
-    info!("verify_window_post:finish");
+/// Verifies a window proof-of-spacetime like [`verify_window_post`], but reports the outcome of
+/// every partition, and of every sector of `vanilla_proofs`, instead of a single bool.
+///
+/// The circuit proofs only reveal whether a partition is valid, not which of its sectors made it
+/// invalid. To narrow this down to the sectors and challenges, a prover can pass the vanilla
+/// proofs the proof was generated from, which are each verified against `replicas` and their
+/// challenges. The partitions are verified one at a time, which is slower than
+/// [`verify_window_post`], so this is meant for diagnosing a proof which did not verify.
+///
+/// # Arguments
+///
+/// * `post_config` - the config of the proof.
+/// * `randomness` - the randomness the sectors were challenged with.
+/// * `replicas` - the proven sectors and their `comm_r`.
+/// * `prover_id` - the prover-id which proved the sectors.
+/// * `proof` - the proof to verify.
+/// * `vanilla_proofs` - the vanilla proofs of any of the sectors, in any order.
+pub fn verify_window_post_detailed<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    replicas: &BTreeMap<SectorId, PublicReplicaInfo>,
+    prover_id: ProverId,
+    proof: &[u8],
+    vanilla_proofs: Option<&[FallbackPoStSectorProof<Tree>]>,
+) -> Result<WindowPoStVerification, FilProofsError> {
+    classified(|| {
+        info!("verify_window_post_detailed:start");
+        ensure_input!(
+            post_config.typ == PoStType::Window,
+            "invalid post config type"
+        );
+
+        let randomness_safe = as_safe_commitment(randomness, "randomness")?;
+        let prover_id_safe = as_safe_commitment(&prover_id, "prover_id")?;
+
+        let vanilla_params = window_post_setup_params(post_config);
+        let partitions = get_partitions_for_window_post(replicas.len(), post_config);
+
+        let setup_params = compound_proof::SetupParams {
+            vanilla_params,
+            partitions,
+            priority: false,
+        };
+        let pub_params: compound_proof::PublicParams<'_, FallbackPoSt<'_, Tree>> =
+            FallbackPoStCompound::setup(&setup_params)?;
+
+        let pub_inputs = fallback::PublicInputs {
+            randomness: randomness_safe,
+            prover_id: prover_id_safe,
+            sectors: window_post_public_sectors(replicas)?,
+            k: None,
+        };
+
+        let verifying_key = get_post_verifying_key::<Tree>(post_config)?;
+        let multi_proof = MultiProof::new_from_reader(partitions, proof, &verifying_key)?;
+        let requirements = fallback::ChallengeRequirements {
+            minimum_challenge_count: post_config.challenge_count * post_config.sector_count,
+        };
+
+        let sector_ids: Vec<SectorId> = replicas.keys().copied().collect();
+        let sectors_per_partition = pub_params.vanilla_params.sector_count;
+        let partitions = (0..partitions.unwrap_or(1))
+            .map(|k| {
+                Ok(PartitionVerification {
+                    sectors: sector_ids
+                        .iter()
+                        .skip(k * sectors_per_partition)
+                        .take(sectors_per_partition)
+                        .copied()
+                        .collect(),
+                    is_valid: FallbackPoStCompound::verify_partition(
+                        &pub_params,
+                        &pub_inputs,
+                        &multi_proof,
+                        &requirements,
+                        k,
+                    )?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let mut sectors = BTreeMap::new();
+        for vanilla_proof in vanilla_proofs.unwrap_or_default() {
+            let sector_id = vanilla_proof.sector_id;
+            // The challenges of a sector depend on its position among the proven sectors.
+            let sector_index = sector_ids
+                .binary_search(&sector_id)
+                .map_err(|_| anyhow!("vanilla proof for sector {:?} is not proven", sector_id))?;
+            ensure_input!(
+                vanilla_proof.vanilla_proof.sectors.len() == 1,
+                "vanilla proof of sector {:?} does not prove a single sector",
+                sector_id
+            );
+
+            let verification = fallback::verify_sector_proof(
+                &pub_params.vanilla_params,
+                &randomness_safe,
+                sector_index,
+                &pub_inputs.sectors[sector_index],
+                &vanilla_proof.vanilla_proof.sectors[0],
+            )?;
+            ensure_input!(
+                sectors.insert(sector_id, verification).is_none(),
+                "duplicate vanilla proof for sector {:?}",
+                sector_id
+            );
+        }
+
+        let verification = WindowPoStVerification {
+            partitions,
+            sectors,
+        };
+
+        info!(
+            "verify_window_post_detailed:finish: {}",
+            verification.is_valid()
+        );
+
+        Ok(verification)
+    })
+}
 
-    Ok(true)
+/// Returns the public sectors of a Window PoSt over `replicas`.
+fn window_post_public_sectors<D: Domain>(
+    replicas: &BTreeMap<SectorId, PublicReplicaInfo>,
+) -> Result<Vec<PublicSector<D>>> {
+    replicas
+        .iter()
+        .map(|(sector_id, replica)| {
+            let comm_r = replica.safe_comm_r().with_context(|| {
+                format!("verify_window_post: safe_comm_r failed: {:?}", sector_id)
+            })?;
+            Ok(PublicSector {
+                id: *sector_id,
+                comm_r,
+            })
+        })
+        .collect()
 }
 
 /// Generates a Window proof-of-spacetime with provided vanilla proofs of a single partition.
+///
+/// The vanilla proofs may be passed in any order, they are proven in ascending sector id order.
 pub fn generate_single_window_post_with_vanilla<Tree: 'static + MerkleTreeTrait>(
     post_config: &PoStConfig,
     randomness: &ChallengeSeed,
     prover_id: ProverId,
-    vanilla_proofs: Vec<FallbackPoStSectorProof<Tree>>,
+    mut vanilla_proofs: Vec<FallbackPoStSectorProof<Tree>>,
     partition_index: usize,
-) -> Result<PartitionSnarkProof> {
-    info!("generate_single_window_post_with_vanilla:start");
-    ensure!(
-        post_config.typ == PoStType::Window,
-        "invalid post config type"
-    );
-
-    let randomness_safe: <Tree::Hasher as Hasher>::Domain =
-        as_safe_commitment(randomness, "randomness")?;
-    let prover_id_safe: <Tree::Hasher as Hasher>::Domain =
-        as_safe_commitment(&prover_id, "prover_id")?;
+) -> Result<PartitionSnarkProof, FilProofsError> {
+    classified(|| {
+        info!("generate_single_window_post_with_vanilla:start");
+        ensure_input!(
+            post_config.typ == PoStType::Window,
+            "invalid post config type"
+        );
+        sort_vanilla_proofs(&mut vanilla_proofs)?;
+
+        let randomness_safe: <Tree::Hasher as Hasher>::Domain =
+            as_safe_commitment(randomness, "randomness")?;
+        let prover_id_safe: <Tree::Hasher as Hasher>::Domain =
+            as_safe_commitment(&prover_id, "prover_id")?;
+
+        let vanilla_params = window_post_setup_params(post_config);
+        let partitions = get_partitions_for_window_post(vanilla_proofs.len(), post_config);
+
+        let setup_params = compound_proof::SetupParams {
+            vanilla_params,
+            partitions,
+            priority: post_config.priority,
+        };
+
+        let pub_params: compound_proof::PublicParams<'_, FallbackPoSt<'_, Tree>> =
+            FallbackPoStCompound::setup(&setup_params)?;
+        let groth_params = get_post_params::<Tree>(post_config)?;
+
+        let mut pub_sectors = Vec::with_capacity(vanilla_proofs.len());
+        for vanilla_proof in &vanilla_proofs {
+            pub_sectors.push(PublicSector {
+                id: vanilla_proof.sector_id,
+                comm_r: vanilla_proof.comm_r,
+            });
+        }
+
+        let pub_inputs = fallback::PublicInputs {
+            randomness: randomness_safe,
+            prover_id: prover_id_safe,
+            sectors: pub_sectors,
+            k: Some(partition_index),
+        };
+
+        let partitioned_proofs = single_partition_vanilla_proofs(
+            post_config,
+            &pub_params.vanilla_params,
+            &pub_inputs,
+            &vanilla_proofs,
+        )?;
 
-    let vanilla_params = window_post_setup_params(post_config);
-    let partitions = get_partitions_for_window_post(vanilla_proofs.len(), post_config);
+        let proof = FallbackPoStCompound::prove_with_vanilla(
+            &pub_params,
+            &pub_inputs,
+            vec![partitioned_proofs],
+            &groth_params,
+        )?;
 
-    let setup_params = compound_proof::SetupParams {
-        vanilla_params,
-        partitions,
-        priority: post_config.priority,
-    };
+        info!("generate_single_window_post_with_vanilla:finish");
 
-    let pub_params: compound_proof::PublicParams<'_, FallbackPoSt<'_, Tree>> =
-        FallbackPoStCompound::setup(&setup_params)?;
-    let groth_params = get_post_params::<Tree>(post_config)?;
+        proof.to_vec().map(PartitionSnarkProof)
+    })
+}
 
-    let mut pub_sectors = Vec::with_capacity(vanilla_proofs.len());
-    for vanilla_proof in &vanilla_proofs {
-        pub_sectors.push(PublicSector {
-            id: vanilla_proof.sector_id,
-            comm_r: vanilla_proof.comm_r,
-        });
+/// Sorts `vanilla_proofs` into the canonical order of the sectors of a Window PoSt, by ascending
+/// sector id.
+fn sort_vanilla_proofs<Tree: MerkleTreeTrait>(
+    vanilla_proofs: &mut [FallbackPoStSectorProof<Tree>],
+) -> Result<()> {
+    vanilla_proofs.sort_by_key(|proof| proof.sector_id);
+    if let Some(pair) = vanilla_proofs
+        .windows(2)
+        .find(|pair| pair[0].sector_id == pair[1].sector_id)
+    {
+        bail!("duplicate vanilla proof for sector {:?}", pair[0].sector_id);
     }
 
-    let pub_inputs = fallback::PublicInputs {
-        randomness: randomness_safe,
-        prover_id: prover_id_safe,
-        sectors: pub_sectors,
-        k: Some(partition_index),
-    };
-
-    let partitioned_proofs = single_partition_vanilla_proofs(
-        post_config,
-        &pub_params.vanilla_params,
-        &pub_inputs,
-        &vanilla_proofs,
-    )?;
-
-    let proof = FallbackPoStCompound::prove_with_vanilla(
-        &pub_params,
-        &pub_inputs,
-        vec![partitioned_proofs],
-        &groth_params,
-    )?;
-
-    info!("generate_single_window_post_with_vanilla:finish");
-
-    proof.to_vec().map(PartitionSnarkProof)
+    Ok(())
 }
@@ -1,24 +1,31 @@
-use anyhow::{ensure, Context, Result};
+use anyhow::{Context, Result};
+use bellperson::groth16;
+use blstrs::Bls12;
 use filecoin_hashers::Hasher;
 use log::info;
 use storage_proofs_core::{
+    api_version::ApiVersion,
     compound_proof::{self, CompoundProof},
     merkle::MerkleTreeTrait,
     multi_proof::MultiProof,
     sector::SectorId,
 };
 use storage_proofs_post::fallback::{
-    self, generate_sector_challenges, FallbackPoSt, FallbackPoStCompound, PrivateSector,
-    PublicSector,
+    self, generate_leaf_challenge, generate_sector_challenges, FallbackPoSt, FallbackPoStCompound,
+    PrivateSector, PublicSector,
 };
 
 use crate::{
     api::{as_safe_commitment, partition_vanilla_proofs},
     caches::{get_post_params, get_post_verifying_key},
+    constants::{
+        DefaultTreeDomain, LAYERS, WINNING_POST_CHALLENGE_COUNT, WINNING_POST_SECTOR_COUNT,
+    },
+    error::{classified, ensure_input, FilProofsError},
     parameters::winning_post_setup_params,
     types::{
         ChallengeSeed, Commitment, FallbackPoStSectorProof, PoStConfig, PrivateReplicaInfo,
-        ProverId, PublicReplicaInfo, SnarkProof,
+        ProverId, PublicReplicaInfo, SectorSize, SnarkProof,
     },
     PoStType,
 };
@@ -29,69 +36,71 @@ pub fn generate_winning_post_with_vanilla<Tree: 'static + MerkleTreeTrait>(
     randomness: &ChallengeSeed,
     prover_id: ProverId,
     vanilla_proofs: Vec<FallbackPoStSectorProof<Tree>>,
-) -> Result<SnarkProof> {
-    info!("generate_winning_post_with_vanilla:start");
-    ensure!(
-        post_config.typ == PoStType::Winning,
-        "invalid post config type"
-    );
-
-    ensure!(
-        vanilla_proofs.len() == post_config.sector_count,
-        "invalid amount of vanilla proofs"
-    );
-
-    let randomness_safe: <Tree::Hasher as Hasher>::Domain =
-        as_safe_commitment(randomness, "randomness")?;
-    let prover_id_safe: <Tree::Hasher as Hasher>::Domain =
-        as_safe_commitment(&prover_id, "prover_id")?;
+) -> Result<SnarkProof, FilProofsError> {
+    classified(|| {
+        info!("generate_winning_post_with_vanilla:start");
+        ensure_input!(
+            post_config.typ == PoStType::Winning,
+            "invalid post config type"
+        );
+
+        ensure_input!(
+            vanilla_proofs.len() == post_config.sector_count,
+            "invalid amount of vanilla proofs"
+        );
+
+        let randomness_safe: <Tree::Hasher as Hasher>::Domain =
+            as_safe_commitment(randomness, "randomness")?;
+        let prover_id_safe: <Tree::Hasher as Hasher>::Domain =
+            as_safe_commitment(&prover_id, "prover_id")?;
+
+        let vanilla_params = winning_post_setup_params(post_config)?;
+
+        let setup_params = compound_proof::SetupParams {
+            vanilla_params,
+            partitions: None,
+            priority: post_config.priority,
+        };
+        let pub_params: compound_proof::PublicParams<'_, FallbackPoSt<'_, Tree>> =
+            FallbackPoStCompound::setup(&setup_params)?;
+        let groth_params = get_post_params::<Tree>(post_config)?;
+
+        let mut pub_sectors = Vec::with_capacity(vanilla_proofs.len());
+        for vanilla_proof in &vanilla_proofs {
+            pub_sectors.push(PublicSector {
+                id: vanilla_proof.sector_id,
+                comm_r: vanilla_proof.comm_r,
+            });
+        }
 
-    let vanilla_params = winning_post_setup_params(post_config)?;
+        let pub_inputs = fallback::PublicInputs {
+            randomness: randomness_safe,
+            prover_id: prover_id_safe,
+            sectors: pub_sectors,
+            k: None,
+        };
+
+        let partitions = pub_params.partitions.unwrap_or(1);
+        let partitioned_proofs = partition_vanilla_proofs(
+            post_config,
+            &pub_params.vanilla_params,
+            &pub_inputs,
+            partitions,
+            &vanilla_proofs,
+        )?;
 
-    let setup_params = compound_proof::SetupParams {
-        vanilla_params,
-        partitions: None,
-        priority: post_config.priority,
-    };
-    let pub_params: compound_proof::PublicParams<'_, FallbackPoSt<'_, Tree>> =
-        FallbackPoStCompound::setup(&setup_params)?;
-    let groth_params = get_post_params::<Tree>(post_config)?;
-
-    let mut pub_sectors = Vec::with_capacity(vanilla_proofs.len());
-    for vanilla_proof in &vanilla_proofs {
-        pub_sectors.push(PublicSector {
-            id: vanilla_proof.sector_id,
-            comm_r: vanilla_proof.comm_r,
-        });
-    }
+        let proof = FallbackPoStCompound::prove_with_vanilla(
+            &pub_params,
+            &pub_inputs,
+            partitioned_proofs,
+            &groth_params,
+        )?;
+        let proof = proof.to_vec()?;
 
-    let pub_inputs = fallback::PublicInputs {
-        randomness: randomness_safe,
-        prover_id: prover_id_safe,
-        sectors: pub_sectors,
-        k: None,
-    };
+        info!("generate_winning_post_with_vanilla:finish");
 
-    let partitions = pub_params.partitions.unwrap_or(1);
-    let partitioned_proofs = partition_vanilla_proofs(
-        post_config,
-        &pub_params.vanilla_params,
-        &pub_inputs,
-        partitions,
-        &vanilla_proofs,
-    )?;
-
-    let proof = FallbackPoStCompound::prove_with_vanilla(
-        &pub_params,
-        &pub_inputs,
-        partitioned_proofs,
-        &groth_params,
-    )?;
-    let proof = proof.to_vec()?;
-
-    info!("generate_winning_post_with_vanilla:finish");
-
-    Ok(proof)
+        Ok(proof)
+    })
 }
 
 /// Generates a Winning proof-of-spacetime.
@@ -100,87 +109,94 @@ pub fn generate_winning_post<Tree: 'static + MerkleTreeTrait>(
     randomness: &ChallengeSeed,
     replicas: &[(SectorId, PrivateReplicaInfo<Tree>)],
     prover_id: ProverId,
-) -> Result<SnarkProof> {
-    info!("generate_winning_post:start");
-    ensure!(
-        post_config.typ == PoStType::Winning,
-        "invalid post config type"
-    );
-
-    ensure!(
-        replicas.len() == post_config.sector_count,
-        "invalid amount of replicas"
-    );
-
-    let randomness_safe: <Tree::Hasher as Hasher>::Domain =
-        as_safe_commitment(randomness, "randomness")?;
-    let prover_id_safe: <Tree::Hasher as Hasher>::Domain =
-        as_safe_commitment(&prover_id, "prover_id")?;
-
-    let vanilla_params = winning_post_setup_params(post_config)?;
-    let param_sector_count = vanilla_params.sector_count;
-
-    let setup_params = compound_proof::SetupParams {
-        vanilla_params,
-        partitions: None,
-        priority: post_config.priority,
-    };
-    let pub_params: compound_proof::PublicParams<'_, FallbackPoSt<'_, Tree>> =
-        FallbackPoStCompound::setup(&setup_params)?;
-    let groth_params = get_post_params::<Tree>(post_config)?;
-
-    let trees = replicas
-        .iter()
-        .map(|(sector_id, replica)| {
-            replica
-                .merkle_tree(post_config.sector_size)
-                .with_context(|| {
-                    format!("generate_winning_post: merkle_tree failed: {:?}", sector_id)
-                })
-        })
-        .collect::<Result<Vec<_>>>()?;
-
-    let mut pub_sectors = Vec::with_capacity(param_sector_count);
-    let mut priv_sectors = Vec::with_capacity(param_sector_count);
-
-    for _ in 0..param_sector_count {
-        for ((sector_id, replica), tree) in replicas.iter().zip(trees.iter()) {
-            let comm_r = replica.safe_comm_r().with_context(|| {
-                format!("generate_winning_post: safe_comm_r failed: {:?}", sector_id)
-            })?;
-            let comm_c = replica.safe_comm_c();
-            let comm_r_last = replica.safe_comm_r_last();
-
-            pub_sectors.push(PublicSector::<<Tree::Hasher as Hasher>::Domain> {
-                id: *sector_id,
-                comm_r,
-            });
-            priv_sectors.push(PrivateSector {
-                tree,
-                comm_c,
-                comm_r_last,
-            });
+) -> Result<SnarkProof, FilProofsError> {
+    classified(|| {
+        info!("generate_winning_post:start");
+        ensure_input!(
+            post_config.typ == PoStType::Winning,
+            "invalid post config type"
+        );
+
+        ensure_input!(
+            replicas.len() == post_config.sector_count,
+            "invalid amount of replicas"
+        );
+
+        let randomness_safe: <Tree::Hasher as Hasher>::Domain =
+            as_safe_commitment(randomness, "randomness")?;
+        let prover_id_safe: <Tree::Hasher as Hasher>::Domain =
+            as_safe_commitment(&prover_id, "prover_id")?;
+
+        let vanilla_params = winning_post_setup_params(post_config)?;
+        let param_sector_count = vanilla_params.sector_count;
+
+        let setup_params = compound_proof::SetupParams {
+            vanilla_params,
+            partitions: None,
+            priority: post_config.priority,
+        };
+        let pub_params: compound_proof::PublicParams<'_, FallbackPoSt<'_, Tree>> =
+            FallbackPoStCompound::setup(&setup_params)?;
+        let groth_params = get_post_params::<Tree>(post_config)?;
+
+        let trees = replicas
+            .iter()
+            .map(|(sector_id, replica)| {
+                replica
+                    .merkle_tree(post_config.sector_size)
+                    .with_context(|| {
+                        format!("generate_winning_post: merkle_tree failed: {:?}", sector_id)
+                    })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut pub_sectors = Vec::with_capacity(param_sector_count);
+        let mut priv_sectors = Vec::with_capacity(param_sector_count);
+
+        for _ in 0..param_sector_count {
+            for ((sector_id, replica), tree) in replicas.iter().zip(trees.iter()) {
+                let comm_r = replica.safe_comm_r().with_context(|| {
+                    format!("generate_winning_post: safe_comm_r failed: {:?}", sector_id)
+                })?;
+                let comm_c = replica.safe_comm_c();
+                let comm_r_last = replica.safe_comm_r_last();
+
+                pub_sectors.push(PublicSector::<<Tree::Hasher as Hasher>::Domain> {
+                    id: *sector_id,
+                    comm_r,
+                });
+                priv_sectors.push(PrivateSector {
+                    tree,
+                    comm_c,
+                    comm_r_last,
+                    rows_to_discard: Some(replica.rows_to_discard(post_config.sector_size)?),
+                });
+            }
         }
-    }
 
-    let pub_inputs = fallback::PublicInputs::<<Tree::Hasher as Hasher>::Domain> {
-        randomness: randomness_safe,
-        prover_id: prover_id_safe,
-        sectors: pub_sectors,
-        k: None,
-    };
+        let pub_inputs = fallback::PublicInputs::<<Tree::Hasher as Hasher>::Domain> {
+            randomness: randomness_safe,
+            prover_id: prover_id_safe,
+            sectors: pub_sectors,
+            k: None,
+        };
 
-    let priv_inputs = fallback::PrivateInputs::<Tree> {
-        sectors: &priv_sectors,
-    };
+        let priv_inputs = fallback::PrivateInputs::<Tree> {
+            sectors: &priv_sectors,
+        };
 
-    let proof =
-        FallbackPoStCompound::<Tree>::prove(&pub_params, &pub_inputs, &priv_inputs, &groth_params)?;
-    let proof = proof.to_vec()?;
+        let proof = FallbackPoStCompound::<Tree>::prove(
+            &pub_params,
+            &pub_inputs,
+            &priv_inputs,
+            &groth_params,
+        )?;
+        let proof = proof.to_vec()?;
 
-    info!("generate_winning_post:finish");
+        info!("generate_winning_post:finish");
 
-    Ok(proof)
+        Ok(proof)
+    })
 }
 
 /// Given some randomness and the length of available sectors, generates the challenged sector.
@@ -192,29 +208,95 @@ pub fn generate_winning_post_sector_challenge<Tree: MerkleTreeTrait>(
     randomness: &ChallengeSeed,
     sector_set_size: u64,
     prover_id: Commitment,
-) -> Result<Vec<u64>> {
-    info!("generate_winning_post_sector_challenge:start");
-    ensure!(sector_set_size != 0, "empty sector set is invalid");
-    ensure!(
-        post_config.typ == PoStType::Winning,
-        "invalid post config type"
-    );
-
-    let prover_id_safe: <Tree::Hasher as Hasher>::Domain =
-        as_safe_commitment(&prover_id, "prover_id")?;
-
-    let randomness_safe: <Tree::Hasher as Hasher>::Domain =
-        as_safe_commitment(randomness, "randomness")?;
-    let result = generate_sector_challenges(
-        randomness_safe,
-        post_config.sector_count,
-        sector_set_size,
-        prover_id_safe,
-    );
-
-    info!("generate_winning_post_sector_challenge:finish");
+) -> Result<Vec<u64>, FilProofsError> {
+    classified(|| {
+        info!("generate_winning_post_sector_challenge:start");
+        ensure_input!(sector_set_size != 0, "empty sector set is invalid");
+        ensure_input!(
+            post_config.typ == PoStType::Winning,
+            "invalid post config type"
+        );
+
+        let prover_id_safe: <Tree::Hasher as Hasher>::Domain =
+            as_safe_commitment(&prover_id, "prover_id")?;
+
+        let randomness_safe: <Tree::Hasher as Hasher>::Domain =
+            as_safe_commitment(randomness, "randomness")?;
+        let result = generate_sector_challenges(
+            randomness_safe,
+            post_config.sector_count,
+            sector_set_size,
+            prover_id_safe,
+        );
+
+        info!("generate_winning_post_sector_challenge:finish");
+
+        result
+    })
+}
 
-    result
+/// Returns the leaves of the sector `sector_id` challenged by a Winning PoSt for `randomness`, in
+/// the order they are proven.
+///
+/// The challenges are derived exactly like `generate_winning_post` derives them, so a verifier or
+/// simulator can reproduce which leaves a proof opens without having the replica.
+///
+/// # Arguments
+///
+/// * `randomness` - the randomness from the chain, as passed to `generate_winning_post`.
+/// * `sector_id` - the challenged sector, see `generate_winning_post_sector_challenge`.
+/// * `sector_size` - one of the supported sector sizes.
+pub fn winning_post_challenges(
+    randomness: &ChallengeSeed,
+    sector_id: SectorId,
+    sector_size: SectorSize,
+) -> Result<Vec<u64>, FilProofsError> {
+    classified(|| {
+        info!("winning_post_challenges:start: {:?}", sector_id);
+        ensure_input!(
+            LAYERS
+                .read()
+                .expect("LAYERS poisoned")
+                .contains_key(&u64::from(sector_size)),
+            "unsupported sector size {}",
+            u64::from(sector_size)
+        );
+
+        let post_config = PoStConfig {
+            sector_size,
+            challenge_count: WINNING_POST_CHALLENGE_COUNT,
+            sector_count: WINNING_POST_SECTOR_COUNT,
+            typ: PoStType::Winning,
+            priority: false,
+            api_version: ApiVersion::V1_1_0,
+        };
+        let setup_params = winning_post_setup_params(&post_config)?;
+        let vanilla_params = fallback::PublicParams {
+            sector_size: setup_params.sector_size,
+            challenge_count: setup_params.challenge_count,
+            sector_count: setup_params.sector_count,
+            api_version: setup_params.api_version,
+        };
+        let randomness_safe: DefaultTreeDomain = as_safe_commitment(randomness, "randomness")?;
+
+        // The sector is proven once per vanilla sector, with the challenges of each following the
+        // ones of the previous.
+        let challenge_count = vanilla_params.sector_count * vanilla_params.challenge_count;
+        let challenges = (0..challenge_count as u64)
+            .map(|challenge_index| {
+                generate_leaf_challenge(
+                    &vanilla_params,
+                    randomness_safe,
+                    sector_id.into(),
+                    challenge_index,
+                )
+            })
+            .collect();
+
+        info!("winning_post_challenges:finish: {:?}", sector_id);
+
+        Ok(challenges)
+    })
 }
 
 /// Verifies a winning proof-of-spacetime.
@@ -228,14 +310,69 @@ pub fn verify_winning_post<Tree: 'static + MerkleTreeTrait>(
     replicas: &[(SectorId, PublicReplicaInfo)],
     prover_id: ProverId,
     proof: &[u8],
-) -> Result<bool> {
-    info!("verify_winning_post:start");
+) -> Result<bool, FilProofsError> {
+    classified(|| {
+        info!("verify_winning_post:start");
+
+        let is_valid = verify_winning_post_inner::<Tree>(
+            post_config,
+            randomness,
+            replicas,
+            prover_id,
+            proof,
+            None,
+        )?;
+
+        info!("verify_winning_post:finish");
+
+        Ok(is_valid)
+    })
+}
+
+/// Verifies a winning proof-of-spacetime against the given `verifying_key`, instead of the one
+/// from the parameter cache.
+///
+/// This doesn't access the disk. The caller is responsible for passing the verifying key matching
+/// `post_config`, the same requirements as for `verify_winning_post` apply to `replicas`.
+pub fn verify_winning_post_with_verifying_key<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    verifying_key: &groth16::PreparedVerifyingKey<Bls12>,
+    randomness: &ChallengeSeed,
+    replicas: &[(SectorId, PublicReplicaInfo)],
+    prover_id: ProverId,
+    proof: &[u8],
+) -> Result<bool, FilProofsError> {
+    classified(|| {
+        info!("verify_winning_post_with_verifying_key:start");
+
+        let is_valid = verify_winning_post_inner::<Tree>(
+            post_config,
+            randomness,
+            replicas,
+            prover_id,
+            proof,
+            Some(verifying_key),
+        )?;
+
+        info!("verify_winning_post_with_verifying_key:finish");
+
+        Ok(is_valid)
+    })
+}
 
-    ensure!(
+fn verify_winning_post_inner<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    replicas: &[(SectorId, PublicReplicaInfo)],
+    prover_id: ProverId,
+    proof: &[u8],
+    verifying_key: Option<&groth16::PreparedVerifyingKey<Bls12>>,
+) -> Result<bool> {
+    ensure_input!(
         post_config.typ == PoStType::Winning,
         "invalid post config type"
     );
-    ensure!(
+    ensure_input!(
         post_config.sector_count == replicas.len(),
         "invalid amount of replicas provided"
     );
@@ -277,9 +414,16 @@ pub fn verify_winning_post<Tree: 'static + MerkleTreeTrait>(
     };
 
     let is_valid = {
-        let verifying_key = get_post_verifying_key::<Tree>(post_config)?;
-
-        let single_proof = MultiProof::new_from_reader(None, proof, &verifying_key)?;
+        let loaded_verifying_key;
+        let verifying_key = match verifying_key {
+            Some(verifying_key) => verifying_key,
+            None => {
+                loaded_verifying_key = get_post_verifying_key::<Tree>(post_config)?;
+                &*loaded_verifying_key
+            }
+        };
+
+        let single_proof = MultiProof::new_from_reader(None, proof, verifying_key)?;
         if single_proof.len() != 1 {
             return Ok(false);
         }
@@ -294,11 +438,5 @@ pub fn verify_winning_post<Tree: 'static + MerkleTreeTrait>(
         )?
     };
 
-    if !is_valid {
-        return Ok(false);
-    }
-
-    info!("verify_winning_post:finish");
-
-    Ok(true)
+    Ok(is_valid)
 }
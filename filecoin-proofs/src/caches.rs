@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::sync::{Arc, Mutex};
 
 use anyhow::Result;
@@ -8,8 +9,13 @@ use lazy_static::lazy_static;
 use log::{info, trace};
 use once_cell::sync::OnceCell;
 use rand::rngs::OsRng;
-use storage_proofs_core::{compound_proof::CompoundProof, merkle::MerkleTreeTrait};
-use storage_proofs_porep::stacked::{StackedCompound, StackedDrg};
+use storage_proofs_core::{
+    compound_proof::CompoundProof,
+    merkle::MerkleTreeTrait,
+    parameter_cache::{get_verifying_key_data, CacheableParameters},
+    settings::SETTINGS,
+};
+use storage_proofs_porep::stacked::{StackedCircuit, StackedCompound, StackedDrg};
 use storage_proofs_post::fallback::{FallbackPoSt, FallbackPoStCircuit, FallbackPoStCompound};
 use storage_proofs_update::{
     circuit::EmptySectorUpdateCircuit, compound::EmptySectorUpdateCompound, constants::TreeRHasher,
@@ -28,8 +34,6 @@ type Bls12ProverSRSKey = groth16::aggregate::ProverSRS<Bls12>;
 type Bls12VerifierSRSKey = groth16::aggregate::VerifierSRS<Bls12>;
 
 type Cache<G> = HashMap<String, Arc<G>>;
-type GrothMemCache = Cache<Bls12GrothParams>;
-type VerifyingKeyMemCache = Cache<Bls12PreparedVerifyingKey>;
 
 const FIP0013_MIN_SNARKS: usize = 64;
 const FIP0013_MAX_SNARKS: usize = 8192;
@@ -43,8 +47,14 @@ const SRS_IDENTIFIER: &str = "srs-key";
 const SRS_VERIFIER_IDENTIFIER: &str = "srs-verifying-key";
 
 lazy_static! {
-    static ref GROTH_PARAM_MEMORY_CACHE: Mutex<GrothMemCache> = Default::default();
-    static ref VERIFYING_KEY_MEMORY_CACHE: Mutex<VerifyingKeyMemCache> = Default::default();
+    static ref GROTH_PARAM_MEMORY_CACHE: LruCache<Bls12GrothParams> = LruCache::with_limits(
+        SETTINGS.param_cache_max_entries,
+        SETTINGS.param_cache_max_bytes,
+        // The parameters are mapped, so this is an upper bound of the memory they take.
+        |params| params.params.len() as u64,
+    );
+    static ref VERIFYING_KEY_MEMORY_CACHE: LruCache<Bls12PreparedVerifyingKey> =
+        LruCache::new(SETTINGS.verifying_key_cache_size);
     static ref SRS_KEY_MEMORY_CACHE: SRSCache<Bls12ProverSRSKey> =
         SRSCache::with_defaults(SRS_IDENTIFIER);
     static ref SRS_VERIFIER_KEY_MEMORY_CACHE: SRSCache<Bls12VerifierSRSKey> =
@@ -102,6 +112,158 @@ impl<G> SRSCache<G> {
     }
 }
 
+/// A bounded cache which evicts the least recently used entry once more than `capacity`
+/// entries, or more than `max_bytes` bytes as weighed by `weigh`, are held.
+///
+/// Every entry is initialized at most once: the generator runs outside of the cache lock, so
+/// lookups of other keys are not blocked by it, while concurrent lookups of the same key wait
+/// for the one initialization in progress instead of running the generator again.
+pub struct LruCache<G> {
+    capacity: usize,
+    max_bytes: u64,
+    weigh: fn(&G) -> u64,
+    state: Mutex<LruState<G>>,
+}
+
+impl<G: fmt::Debug> fmt::Debug for LruCache<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LruCache")
+            .field("capacity", &self.capacity)
+            .field("max_bytes", &self.max_bytes)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+struct LruState<G> {
+    /// Monotonic counter used to order entries by last use.
+    tick: u64,
+    entries: HashMap<String, LruEntry<G>>,
+}
+
+#[derive(Debug)]
+struct LruEntry<G> {
+    last_used: u64,
+    /// The weight of the value, 0 until it is initialized.
+    bytes: u64,
+    cell: Arc<OnceCell<Arc<G>>>,
+}
+
+impl<G> LruState<G> {
+    /// Evicts the least recently used entries other than `key` until the limits are kept.
+    fn evict(&mut self, key: &str, capacity: usize, max_bytes: u64) {
+        loop {
+            let bytes: u64 = self.entries.values().map(|entry| entry.bytes).sum();
+            if self.entries.len() <= capacity && (max_bytes == 0 || bytes <= max_bytes) {
+                break;
+            }
+            let oldest = self
+                .entries
+                .iter()
+                .filter(|(k, _)| k.as_str() != key)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(k, _)| k.clone());
+            match oldest {
+                Some(oldest) => {
+                    trace!("evicting {} from memory cache", oldest);
+                    self.entries.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl<G> LruCache<G> {
+    /// Creates an empty cache holding at most `capacity` entries (and at least one).
+    pub fn new(capacity: usize) -> Self {
+        Self::with_limits(capacity.max(1), 0, |_| 0)
+    }
+
+    /// Creates an empty cache holding at most `max_entries` entries, which weigh at most
+    /// `max_bytes` in total. A limit of 0 means no limit. The entry which was looked up last is
+    /// never evicted, even if it weighs more than `max_bytes` on its own.
+    pub fn with_limits(max_entries: usize, max_bytes: u64, weigh: fn(&G) -> u64) -> Self {
+        LruCache {
+            capacity: if max_entries == 0 {
+                usize::MAX
+            } else {
+                max_entries
+            },
+            max_bytes,
+            weigh,
+            state: Mutex::new(LruState {
+                tick: 0,
+                entries: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Returns the entry for `key`, running `generator` to create it if it is not present.
+    pub fn get_or_init<F>(&self, key: &str, generator: F) -> Result<Arc<G>>
+    where
+        F: FnOnce() -> Result<G>,
+    {
+        let cell = {
+            let mut state = self.state.lock().expect("poisoned cache");
+            state.tick += 1;
+            let tick = state.tick;
+
+            let entry = state
+                .entries
+                .entry(key.to_string())
+                .or_insert_with(|| LruEntry {
+                    last_used: tick,
+                    bytes: 0,
+                    cell: Default::default(),
+                });
+            entry.last_used = tick;
+            let cell = entry.cell.clone();
+
+            state.evict(key, self.capacity, self.max_bytes);
+
+            cell
+        };
+
+        let mut initialized = false;
+        let entry = cell.get_or_try_init(|| -> Result<Arc<G>> {
+            initialized = true;
+            Ok(Arc::new(generator()?))
+        })?;
+
+        // Only the value's weight can exceed the byte limit, which is known once it exists.
+        if initialized && self.max_bytes > 0 {
+            let mut state = self.state.lock().expect("poisoned cache");
+            if let Some(held) = state.entries.get_mut(key) {
+                // The entry may have been evicted, or cleared and created again, meanwhile.
+                if Arc::ptr_eq(&held.cell, &cell) {
+                    held.bytes = (self.weigh)(entry);
+                    state.evict(key, self.capacity, self.max_bytes);
+                }
+            }
+        }
+
+        Ok(entry.clone())
+    }
+
+    /// Drops all entries. Values which are still in use stay valid, and are created again once
+    /// they are looked up.
+    pub fn clear(&self) {
+        self.state.lock().expect("poisoned cache").entries.clear();
+    }
+
+    /// Returns the number of entries currently held.
+    pub fn len(&self) -> usize {
+        self.state.lock().expect("poisoned cache").entries.len()
+    }
+
+    /// Returns true if the cache holds no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 pub fn cache_lookup<F, G>(
     cache_ref: &Mutex<Cache<G>>,
     identifier: String,
@@ -150,14 +312,36 @@ where
     panic!("unknown identifier {}", identifier);
 }
 
+/// Looks up Groth parameters in the in-process parameter cache, running `generator` to map them
+/// on a miss.
+///
+/// The number of held parameters and the bytes they map are bounded by
+/// `SETTINGS.param_cache_max_entries` and `SETTINGS.param_cache_max_bytes`, if set.
 #[inline]
 pub fn lookup_groth_params<F>(identifier: String, generator: F) -> Result<Arc<Bls12GrothParams>>
 where
     F: FnOnce() -> Result<Bls12GrothParams>,
 {
-    cache_lookup(&*GROTH_PARAM_MEMORY_CACHE, identifier, generator)
+    info!("trying parameters memory cache for: {}", &identifier);
+    GROTH_PARAM_MEMORY_CACHE.get_or_init(&identifier, generator)
 }
 
+/// Drops all Groth parameters and prepared verifying keys held in memory, which are loaded again
+/// once they are needed. Those still in use by a proof or verification stay valid until it is
+/// done.
+pub fn clear_param_cache() {
+    info!("clearing the parameter and verifying key memory caches");
+    GROTH_PARAM_MEMORY_CACHE.clear();
+    VERIFYING_KEY_MEMORY_CACHE.clear();
+}
+
+/// Looks up a prepared verifying key in the in-process verifying key cache, running
+/// `generator` to load and prepare it on a miss.
+///
+/// `identifier` is expected to be the cache identifier of the parameter set. Keys belonging
+/// to published parameters are held under the digest of their verifying key file, so that
+/// distinct parameter sets never share an entry; the number of held keys is bounded by
+/// `SETTINGS.verifying_key_cache_size`.
 #[inline]
 pub fn lookup_verifying_key<F>(
     identifier: String,
@@ -166,8 +350,12 @@ pub fn lookup_verifying_key<F>(
 where
     F: FnOnce() -> Result<Bls12PreparedVerifyingKey>,
 {
-    let vk_identifier = format!("{}-verifying-key", &identifier);
-    cache_lookup(&*VERIFYING_KEY_MEMORY_CACHE, vk_identifier, generator)
+    let vk_identifier = match get_verifying_key_data(&identifier) {
+        Some(data) => format!("{}-verifying-key", data.digest),
+        None => format!("{}-verifying-key", &identifier),
+    };
+    trace!("trying verifying key memory cache for: {}", &vk_identifier);
+    VERIFYING_KEY_MEMORY_CACHE.get_or_init(&vk_identifier, generator)
 }
 
 #[inline]
@@ -309,10 +497,10 @@ pub fn get_stacked_verifying_key<Tree: 'static + MerkleTreeTrait>(
     };
 
     lookup_verifying_key(
-        format!(
-            "STACKED[{}]",
-            usize::from(PaddedBytesAmount::from(porep_config))
-        ),
+        <StackedCompound<Tree, DefaultPieceHasher> as CacheableParameters<
+            StackedCircuit<'_, Tree, DefaultPieceHasher>,
+            _,
+        >>::cache_identifier(&public_params),
         vk_generator,
     )
 }
@@ -332,13 +520,15 @@ pub fn get_post_verifying_key<Tree: 'static + MerkleTreeTrait>(
                 Ok(prepare_verifying_key(&vk))
             };
 
-            Ok(lookup_verifying_key(
-                format!(
-                    "WINNING_POST[{}]",
-                    usize::from(post_config.padded_sector_size())
-                ),
-                vk_generator,
-            )?)
+            Ok(
+                lookup_verifying_key(
+                    <FallbackPoStCompound<Tree> as CacheableParameters<
+                        FallbackPoStCircuit<Tree>,
+                        _,
+                    >>::cache_identifier(&post_public_params),
+                    vk_generator,
+                )?,
+            )
         }
         PoStType::Window => {
             let post_public_params = window_post_public_params::<Tree>(post_config)?;
@@ -351,13 +541,15 @@ pub fn get_post_verifying_key<Tree: 'static + MerkleTreeTrait>(
                 Ok(prepare_verifying_key(&vk))
             };
 
-            Ok(lookup_verifying_key(
-                format!(
-                    "WINDOW_POST[{}]",
-                    usize::from(post_config.padded_sector_size())
-                ),
-                vk_generator,
-            )?)
+            Ok(
+                lookup_verifying_key(
+                    <FallbackPoStCompound<Tree> as CacheableParameters<
+                        FallbackPoStCircuit<Tree>,
+                        _,
+                    >>::cache_identifier(&post_public_params),
+                    vk_generator,
+                )?,
+            )
         }
     }
 }
@@ -447,10 +639,89 @@ pub fn get_empty_sector_update_verifying_key<
     };
 
     lookup_verifying_key(
-        format!(
-            "SECTOR-UPDATE[{}]",
-            usize::from(PaddedBytesAmount::from(porep_config))
-        ),
+        <EmptySectorUpdateCompound<Tree> as CacheableParameters<
+            EmptySectorUpdateCircuit<Tree>,
+            _,
+        >>::cache_identifier(&public_params),
         vk_generator,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_lru_cache_evicts_least_recently_used() {
+        let cache = LruCache::<usize>::new(2);
+
+        let a = cache.get_or_init("a", || Ok(1)).expect("get a");
+        let _ = cache.get_or_init("b", || Ok(2)).expect("get b");
+        // Touch "a" so that "b" becomes the least recently used entry.
+        let _ = cache.get_or_init("a", || Ok(10)).expect("get a");
+        let _ = cache.get_or_init("c", || Ok(3)).expect("get c");
+        assert_eq!(cache.len(), 2);
+
+        assert_eq!(*a, 1);
+        assert_eq!(*cache.get_or_init("a", || Ok(10)).expect("get a"), 1);
+        // "b" was evicted and gets regenerated.
+        assert_eq!(*cache.get_or_init("b", || Ok(20)).expect("get b"), 20);
+    }
+
+    #[test]
+    fn test_lru_cache_initializes_once() {
+        let cache = LruCache::<usize>::new(4);
+        let calls = AtomicUsize::new(0);
+
+        rayon::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|_| {
+                    let value = cache
+                        .get_or_init("key", || {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            Ok(42)
+                        })
+                        .expect("get key");
+                    assert_eq!(*value, 42);
+                });
+            }
+        });
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_lru_cache_evicts_by_weight() {
+        let cache = LruCache::<u64>::with_limits(0, 10, |value| *value);
+
+        let _ = cache.get_or_init("a", || Ok(4)).expect("get a");
+        let _ = cache.get_or_init("b", || Ok(4)).expect("get b");
+        assert_eq!(cache.len(), 2);
+        // "c" does not fit next to both, so the least recently used "a" is evicted.
+        let _ = cache.get_or_init("c", || Ok(4)).expect("get c");
+        assert_eq!(cache.len(), 2);
+        assert_eq!(*cache.get_or_init("b", || Ok(0)).expect("get b"), 4);
+        assert_eq!(*cache.get_or_init("a", || Ok(5)).expect("get a"), 5);
+
+        // An entry heavier than the limit is still held, on its own.
+        let _ = cache.get_or_init("d", || Ok(20)).expect("get d");
+        assert_eq!(cache.len(), 1);
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(*cache.get_or_init("d", || Ok(1)).expect("get d"), 1);
+    }
+
+    #[test]
+    fn test_lru_cache_retries_failed_init() {
+        let cache = LruCache::<usize>::new(1);
+
+        assert!(cache
+            .get_or_init("key", || Err(anyhow::anyhow!("failed")))
+            .is_err());
+        assert_eq!(*cache.get_or_init("key", || Ok(7)).expect("get key"), 7);
+    }
+}
@@ -0,0 +1,208 @@
+use std::io;
+
+use bellperson::SynthesisError;
+use storage_proofs_core::error::Error as StorageProofsError;
+
+/// The error returned by the public API.
+///
+/// Every variant wraps the underlying `anyhow::Error`, which displays and chains its sources
+/// exactly as before, so only the variant is new: it tells callers what kind of failure occurred
+/// without matching on messages, e.g. to retry an operation after an I/O error but give up on
+/// invalid input.
+#[derive(Debug, thiserror::Error)]
+pub enum FilProofsError {
+    /// Reading or writing a file failed.
+    #[error(transparent)]
+    Io(anyhow::Error),
+    /// The arguments do not describe a valid operation, e.g. a malformed commitment or proof.
+    #[error(transparent)]
+    InvalidInput(anyhow::Error),
+    /// A sector cache or parameter file exists, but its contents are not valid.
+    #[error(transparent)]
+    CacheCorrupt(anyhow::Error),
+    /// No GPU could be used.
+    #[error(transparent)]
+    GpuUnavailable(anyhow::Error),
+    /// Any other failure.
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl FilProofsError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            FilProofsError::Io(_) => FilProofsError::Io,
+            FilProofsError::InvalidInput(_) => FilProofsError::InvalidInput,
+            FilProofsError::CacheCorrupt(_) => FilProofsError::CacheCorrupt,
+            FilProofsError::GpuUnavailable(_) => FilProofsError::GpuUnavailable,
+            FilProofsError::Other(_) => FilProofsError::Other,
+        }
+    }
+
+    /// Returns the underlying error.
+    pub fn into_inner(self) -> anyhow::Error {
+        match self {
+            FilProofsError::Io(err)
+            | FilProofsError::InvalidInput(err)
+            | FilProofsError::CacheCorrupt(err)
+            | FilProofsError::GpuUnavailable(err)
+            | FilProofsError::Other(err) => err,
+        }
+    }
+}
+
+impl From<anyhow::Error> for FilProofsError {
+    /// Classifies `err` by the first cause in its chain that has a known kind.
+    fn from(err: anyhow::Error) -> Self {
+        // Errors of nested API calls are already classified, and only need to be unwrapped if
+        // no context was added to them.
+        let is_classified = err
+            .chain()
+            .next()
+            .map_or(false, |outermost| outermost.is::<FilProofsError>());
+        let err = if is_classified {
+            match err.downcast::<FilProofsError>() {
+                Ok(classified) => return classified,
+                Err(err) => err,
+            }
+        } else {
+            err
+        };
+
+        match err.chain().find_map(error_kind) {
+            Some(kind) => kind(err),
+            None => FilProofsError::Other(err),
+        }
+    }
+}
+
+/// The variant constructor for a single cause, or `None` if its kind is unknown.
+type ErrorKind = fn(anyhow::Error) -> FilProofsError;
+
+fn error_kind(cause: &(dyn std::error::Error + 'static)) -> Option<ErrorKind> {
+    if let Some(classified) = cause.downcast_ref::<FilProofsError>() {
+        return Some(classified.kind());
+    }
+    if cause.is::<io::Error>() {
+        return Some(FilProofsError::Io);
+    }
+    if cause.is::<bincode::Error>() || cause.is::<bincode::ErrorKind>() {
+        return Some(FilProofsError::CacheCorrupt);
+    }
+    if let Some(SynthesisError::IoError(_)) = cause.downcast_ref::<SynthesisError>() {
+        return Some(FilProofsError::Io);
+    }
+
+    cause
+        .downcast_ref::<StorageProofsError>()
+        .and_then(storage_proofs_error_kind)
+}
+
+/// Returns the variant for the errors of `storage_proofs_core`, or `None` for those which only
+/// wrap another error.
+fn storage_proofs_error_kind(err: &StorageProofsError) -> Option<ErrorKind> {
+    match err {
+        StorageProofsError::Io(_) => Some(FilProofsError::Io),
+        StorageProofsError::MalformedMerkleTree
+        | StorageProofsError::MerkleTreeGenerationError(_)
+        | StorageProofsError::InvalidParameters(_)
+        | StorageProofsError::TruncatedReplica { .. } => Some(FilProofsError::CacheCorrupt),
+        StorageProofsError::BadPieceCommitment
+        | StorageProofsError::OutOfBounds(_, _)
+        | StorageProofsError::InvalidMerkleTreeArgs(_, _, _)
+        | StorageProofsError::InvalidCommitment
+        | StorageProofsError::MalformedInput
+        | StorageProofsError::InvalidInputSize
+        | StorageProofsError::UnalignedPiece
+        | StorageProofsError::InvalidPieceLayout { .. }
+        | StorageProofsError::MissingPrivateInput(_, _)
+        | StorageProofsError::FaultySectors(_) => Some(FilProofsError::InvalidInput),
+        StorageProofsError::GpuUnavailable(_) => Some(FilProofsError::GpuUnavailable),
+        StorageProofsError::Synthesis(_)
+        | StorageProofsError::Serde(_)
+        | StorageProofsError::Unclassified(_) => None,
+    }
+}
+
+/// Runs the body of a public API function, classifying the error it fails with.
+pub(crate) fn classified<T>(f: impl FnOnce() -> anyhow::Result<T>) -> Result<T, FilProofsError> {
+    f().map_err(FilProofsError::from)
+}
+
+/// Like `ensure!`, but fails with `FilProofsError::InvalidInput`, for the checks of the arguments
+/// of the public API. It can be used in functions returning either an `anyhow::Result` or a
+/// `Result` with a `FilProofsError`.
+macro_rules! ensure_input {
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            let err = ::anyhow::anyhow!($($arg)+);
+            return Err($crate::error::FilProofsError::InvalidInput(err).into());
+        }
+    };
+}
+
+pub(crate) use ensure_input;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn test_classification() {
+        let io = anyhow::Error::from(io::Error::new(io::ErrorKind::NotFound, "missing"))
+            .context("could not read file t_aux");
+        let err = FilProofsError::from(io);
+        assert!(matches!(err, FilProofsError::Io(_)));
+        // The message and the chain are those of the wrapped error.
+        assert_eq!(err.to_string(), "could not read file t_aux");
+        assert_eq!(
+            std::error::Error::source(&err).map(ToString::to_string),
+            Some("missing".to_string())
+        );
+
+        let truncated: anyhow::Result<()> = Err(StorageProofsError::TruncatedReplica {
+            expected: 2048,
+            actual: 1024,
+        }
+        .into());
+        let err = FilProofsError::from(
+            truncated
+                .context("seal_commit_phase1")
+                .expect_err("context removed the error"),
+        );
+        assert!(matches!(err, FilProofsError::CacheCorrupt(_)));
+
+        let err = FilProofsError::from(anyhow::Error::from(StorageProofsError::InvalidCommitment));
+        assert!(matches!(err, FilProofsError::InvalidInput(_)));
+
+        let err = FilProofsError::from(anyhow!("something else"));
+        assert!(matches!(err, FilProofsError::Other(_)));
+
+        // Nested API errors keep their kind, and the context added to them.
+        let nested = anyhow::Error::from(FilProofsError::GpuUnavailable(anyhow!("no GPU")));
+        assert!(matches!(
+            FilProofsError::from(nested),
+            FilProofsError::GpuUnavailable(_)
+        ));
+        let nested = anyhow::Error::from(FilProofsError::GpuUnavailable(anyhow!("no GPU")))
+            .context("seal_commit_phase2");
+        let err = FilProofsError::from(nested);
+        assert!(matches!(err, FilProofsError::GpuUnavailable(_)));
+        assert_eq!(err.to_string(), "seal_commit_phase2");
+    }
+
+    #[test]
+    fn test_ensure_input() {
+        fn check(value: usize) -> anyhow::Result<usize> {
+            ensure_input!(value > 0, "value must not be 0");
+            Ok(value)
+        }
+
+        assert_eq!(check(1).expect("check failed"), 1);
+        let err = FilProofsError::from(check(0).expect_err("0 accepted"));
+        assert!(matches!(err, FilProofsError::InvalidInput(_)));
+        assert_eq!(err.to_string(), "value must not be 0");
+    }
+}
@@ -12,8 +12,10 @@ pub mod types;
 
 mod api;
 mod commitment_reader;
+mod error;
 
 pub use api::*;
 pub use commitment_reader::*;
 pub use constants::*;
+pub use error::FilProofsError;
 pub use types::*;
@@ -5,11 +5,12 @@ use std::iter::Iterator;
 use std::sync::Mutex;
 
 use anyhow::{ensure, Context, Result};
+use cid::{multihash::Multihash, Cid, Version};
 use filecoin_hashers::{HashFunction, Hasher};
 use fr32::Fr32Reader;
 use lazy_static::lazy_static;
 use log::trace;
-use storage_proofs_core::util::NODE_SIZE;
+use storage_proofs_core::{error::Error as StorageProofsError, util::NODE_SIZE};
 
 use crate::{
     commitment_reader::CommitmentReader,
@@ -34,6 +35,51 @@ pub fn verify_pieces(
     Ok(&comm_d_calculated == comm_d)
 }
 
+/// Ensures that `piece_infos` can be packed into a sector of `sector_size` in the given order.
+///
+/// Every piece has to be a power of two in size once padded, and at least the minimum piece size.
+/// Pieces are placed one after the other, each starting at the next multiple of its padded size,
+/// as `add_piece` writes them, and the last piece has to end within the sector. The error names the
+/// first piece that violates these rules.
+pub fn validate_piece_layout(piece_infos: &[PieceInfo], sector_size: SectorSize) -> Result<()> {
+    let sector_size = u64::from(sector_size);
+    let minimum_size = u64::from(PaddedBytesAmount::from(UnpaddedBytesAmount(
+        MINIMUM_PIECE_SIZE,
+    )));
+
+    let invalid = |index, reason| StorageProofsError::InvalidPieceLayout { index, reason };
+
+    let mut end = 0;
+    for (index, piece_info) in piece_infos.iter().enumerate() {
+        let size = u64::from(PaddedBytesAmount::from(piece_info.size));
+        ensure!(
+            size.is_power_of_two() && size >= minimum_size,
+            invalid(
+                index,
+                format!(
+                    "padded size {} is not a power of two of at least {} bytes",
+                    size, minimum_size
+                )
+            )
+        );
+
+        let start = (end + size - 1) / size * size;
+        ensure!(
+            start + size <= sector_size,
+            invalid(
+                index,
+                format!(
+                    "placed at padded offset {}, it ends after the sector of {} bytes",
+                    start, sector_size
+                )
+            )
+        );
+        end = start + size;
+    }
+
+    Ok(())
+}
+
 lazy_static! {
     static ref COMMITMENTS: Mutex<HashMap<SectorSize, Commitment>> = Mutex::new(HashMap::new());
 }
@@ -61,6 +107,70 @@ impl Read for EmptySource {
     }
 }
 
+/// A source of `size` bytes, the bytes of `source` followed by zeros once it ends.
+///
+/// This pads a source whose length is not known in advance, e.g. a network stream, to a piece of
+/// `size` bytes while it is read, so that it doesn't have to be copied first to learn its length.
+/// Reading fails with `InvalidData` if `source` has more than `size` bytes.
+#[derive(Debug)]
+pub struct ZeroPaddedSource<R> {
+    source: R,
+    size: u64,
+    read: u64,
+    source_bytes: u64,
+    source_done: bool,
+}
+
+impl<R: Read> ZeroPaddedSource<R> {
+    pub fn new(source: R, size: u64) -> Self {
+        ZeroPaddedSource {
+            source,
+            size,
+            read: 0,
+            source_bytes: 0,
+            source_done: false,
+        }
+    }
+
+    /// Returns the number of bytes read from `source` so far, without the zeros after it.
+    pub fn source_bytes(&self) -> u64 {
+        self.source_bytes
+    }
+}
+
+impl<R: Read> Read for ZeroPaddedSource<R> {
+    fn read(&mut self, target: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.size - self.read;
+        if remaining == 0 {
+            if !self.source_done {
+                if self.source.read(&mut [0u8])? != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("source has more than {} bytes", self.size),
+                    ));
+                }
+                self.source_done = true;
+            }
+            return Ok(0);
+        }
+
+        let target = &mut target[..min(remaining, target.len() as u64) as usize];
+        let mut n = 0;
+        if !self.source_done {
+            n = self.source.read(target)?;
+            self.source_done = n == 0 && !target.is_empty();
+            self.source_bytes += n as u64;
+        }
+        if self.source_done {
+            target.fill(0);
+            n = target.len();
+        }
+        self.read += n as u64;
+
+        Ok(n)
+    }
+}
+
 fn empty_comm_d(sector_size: SectorSize) -> Commitment {
     let map = &mut *COMMITMENTS.lock().expect("COMMITMENTS poisoned");
 
@@ -352,3 +462,54 @@ pub fn get_aligned_source<T: Read>(
         with_alignment(source, piece_alignment),
     )
 }
+
+/// The multicodec of the CIDs of unsealed data commitments, i.e. of pieces and `comm_d`.
+pub const FIL_COMMITMENT_UNSEALED: u64 = 0xf101;
+
+/// The multihash code of the hash function used for piece commitments: sha256 truncated to 254
+/// bits, over fr32 padded data.
+pub const SHA2_256_TRUNC254_PADDED: u64 = 0x1012;
+
+/// Extracts the piece commitment (or `comm_d`) from a piece CID.
+///
+/// Fails if `cid` is not a CIDv1 with the `fil-commitment-unsealed` codec and a
+/// `sha2-256-trunc254-padded` multihash of 32 bytes.
+pub fn piece_cid_to_comm(cid: &Cid) -> Result<Commitment> {
+    ensure!(
+        cid.version() == Version::V1,
+        "piece cid must be a CIDv1, got {:?}",
+        cid.version()
+    );
+    ensure!(
+        cid.codec() == FIL_COMMITMENT_UNSEALED,
+        "piece cid has codec {:#x}, expected fil-commitment-unsealed ({:#x})",
+        cid.codec(),
+        FIL_COMMITMENT_UNSEALED
+    );
+
+    let hash = cid.hash();
+    ensure!(
+        hash.code() == SHA2_256_TRUNC254_PADDED,
+        "piece cid has hash {:#x}, expected sha2-256-trunc254-padded ({:#x})",
+        hash.code(),
+        SHA2_256_TRUNC254_PADDED
+    );
+    ensure!(
+        hash.digest().len() == 32,
+        "piece cid digest has {} bytes, expected 32",
+        hash.digest().len()
+    );
+
+    let mut comm = [0u8; 32];
+    comm.copy_from_slice(hash.digest());
+
+    Ok(comm)
+}
+
+/// Wraps a piece commitment (or `comm_d`) into its piece CID.
+pub fn comm_to_piece_cid(comm: &Commitment) -> Result<Cid> {
+    let hash = Multihash::wrap(SHA2_256_TRUNC254_PADDED, comm)
+        .context("failed to wrap commitment into a multihash")?;
+
+    Ok(Cid::new_v1(FIL_COMMITMENT_UNSEALED, hash))
+}
@@ -0,0 +1,282 @@
+use std::path::{Path, PathBuf};
+
+use storage_proofs_core::cache_key::CacheKey;
+
+use crate::types::{Commitment, StoreConfig};
+
+/// The role of a file in a sector's cache directory, see `describe_cache`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheArtifactRole {
+    /// The labels of a layer, counted from 1.
+    Layer(usize),
+    /// The digest of the labels of a layer, which marks it as complete for an interrupted
+    /// labeling to resume from.
+    LayerDigest(usize),
+    /// The labels of all layers, consolidated into one file.
+    Layers,
+    TreeD,
+    /// tree_c, or the base tree at this index if tree_c is split.
+    TreeC(Option<usize>),
+    /// The cached rows of tree_r_last, or of the base tree at this index if tree_r_last is split.
+    TreeRLast(Option<usize>),
+    /// The progress of building a base tree of tree_c or tree_r_last, which lets an interrupted
+    /// pre commit phase 2 reuse the base trees it completed.
+    TreeProgress,
+    PAux,
+    TAux,
+    /// A file left behind by an interrupted write, e.g. of the labels.
+    Temporary,
+    /// A file which is not a sealing artifact.
+    Unknown,
+}
+
+impl CacheArtifactRole {
+    /// Returns the role of the file `file_name` in a cache directory.
+    pub fn from_file_name(file_name: &str) -> Self {
+        if file_name == CacheKey::PAux.to_string() {
+            return CacheArtifactRole::PAux;
+        }
+        if file_name == CacheKey::TAux.to_string() {
+            return CacheArtifactRole::TAux;
+        }
+        if Path::new(file_name)
+            .extension()
+            .map_or(false, |ext| ext == "tmp")
+        {
+            return CacheArtifactRole::Temporary;
+        }
+
+        // Stores are named by their id, the way merkletree names them.
+        let marker = "{id}";
+        let sample = StoreConfig::data_path(&PathBuf::new(), marker);
+        let sample = sample.to_string_lossy();
+        let (prefix, suffix) = sample.split_at(sample.find(marker).expect("marker is in the path"));
+        let suffix = &suffix[marker.len()..];
+        let id = match file_name
+            .strip_prefix(prefix)
+            .and_then(|id| id.strip_suffix(suffix))
+        {
+            Some(id) => id,
+            None => {
+                // The digest of a layer and the progress of a tree are named like the layer or
+                // the tree, with another extension.
+                let store = |extension: &str| {
+                    file_name.strip_suffix(extension).map(|stem| {
+                        CacheArtifactRole::from_file_name(&format!("{}{}", stem, suffix))
+                    })
+                };
+                return match (store(".digest"), store(".progress")) {
+                    (Some(CacheArtifactRole::Layer(layer)), _) => {
+                        CacheArtifactRole::LayerDigest(layer)
+                    }
+                    (_, Some(CacheArtifactRole::TreeC(_)))
+                    | (_, Some(CacheArtifactRole::TreeRLast(_))) => CacheArtifactRole::TreeProgress,
+                    _ => CacheArtifactRole::Unknown,
+                };
+            }
+        };
+
+        let split_index = |key: CacheKey| -> Option<Option<usize>> {
+            let key = key.to_string();
+            if id == key {
+                return Some(None);
+            }
+            id.strip_prefix(&key)?
+                .strip_prefix('-')?
+                .parse()
+                .ok()
+                .map(Some)
+        };
+        if id == CacheKey::LabelLayers.to_string() {
+            CacheArtifactRole::Layers
+        } else if id == CacheKey::CommDTree.to_string() {
+            CacheArtifactRole::TreeD
+        } else if let Some(index) = split_index(CacheKey::CommCTree) {
+            CacheArtifactRole::TreeC(index)
+        } else if let Some(index) = split_index(CacheKey::CommRLastTree) {
+            CacheArtifactRole::TreeRLast(index)
+        } else if let Some(layer) = id
+            .strip_prefix("layer-")
+            .and_then(|layer| layer.parse::<usize>().ok())
+            .filter(|layer| *layer > 0)
+        {
+            CacheArtifactRole::Layer(layer)
+        } else {
+            CacheArtifactRole::Unknown
+        }
+    }
+
+    /// Whether PoSt reads this file, i.e. whether it has to be kept for as long as the sector is
+    /// proven.
+    pub fn required_for_post(&self) -> bool {
+        matches!(
+            self,
+            CacheArtifactRole::PAux | CacheArtifactRole::TreeRLast(_)
+        )
+    }
+}
+
+/// A file in a sector's cache directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheArtifact {
+    pub path: PathBuf,
+    pub role: CacheArtifactRole,
+    /// The size in bytes.
+    pub size: u64,
+    pub required_for_post: bool,
+}
+
+/// The files in a sector's cache directory, as returned by `describe_cache`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheManifest {
+    pub cache_dir: PathBuf,
+    /// The files sorted by path.
+    pub artifacts: Vec<CacheArtifact>,
+}
+
+impl CacheManifest {
+    /// Returns the artifacts which PoSt reads.
+    pub fn required_for_post(&self) -> impl Iterator<Item = &CacheArtifact> {
+        self.artifacts
+            .iter()
+            .filter(|artifact| artifact.required_for_post)
+    }
+
+    /// Returns the artifacts with `role`.
+    pub fn by_role(&self, role: CacheArtifactRole) -> impl Iterator<Item = &CacheArtifact> {
+        self.artifacts
+            .iter()
+            .filter(move |artifact| artifact.role == role)
+    }
+
+    /// The total size of all artifacts in bytes.
+    pub fn size(&self) -> u64 {
+        self.artifacts.iter().map(|artifact| artifact.size).sum()
+    }
+}
+
+/// The first difference between two cache directories, see `compare_caches`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CacheDivergence {
+    /// The file is only in one of the directories, the first one if `in_a` is set.
+    Missing { file_name: String, in_a: bool },
+    /// The file has `size_a` bytes in the first directory and `size_b` in the second one.
+    Size {
+        file_name: String,
+        size_a: u64,
+        size_b: u64,
+    },
+    /// The file first differs at byte `offset`.
+    Content { file_name: String, offset: u64 },
+    /// t_aux describes different layers or trees, apart from the cache directory it names.
+    TAux,
+}
+
+/// The result of comparing two cache directories with `compare_caches`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheComparison {
+    pub manifest_a: CacheManifest,
+    pub manifest_b: CacheManifest,
+    /// The comm_r the trees of each directory commit to, `None` if the directory has no p_aux
+    /// or its trees do not match it.
+    pub comm_r_a: Option<Commitment>,
+    pub comm_r_b: Option<Commitment>,
+    /// The first difference in the order of the file names, `None` if the directories are
+    /// identical.
+    pub divergence: Option<CacheDivergence>,
+}
+
+impl CacheComparison {
+    /// Whether both directories hold the same files with the same contents.
+    pub fn is_identical(&self) -> bool {
+        self.divergence.is_none()
+    }
+
+    /// Whether the trees of both directories commit to the same comm_r, even if other files
+    /// differ.
+    pub fn is_same_comm_r(&self) -> bool {
+        self.comm_r_a.is_some() && self.comm_r_a == self.comm_r_b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_file_name(id: &str) -> String {
+        StoreConfig::data_path(&PathBuf::new(), id)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// The name of a file next to a store, the way the digest of a layer is named.
+    fn sibling_file_name(id: &str, extension: &str) -> String {
+        Path::new(&store_file_name(id))
+            .with_extension(extension)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_cache_artifact_role_from_file_name() {
+        let role = |name: &str| CacheArtifactRole::from_file_name(name);
+
+        assert_eq!(role("p_aux"), CacheArtifactRole::PAux);
+        assert_eq!(role("t_aux"), CacheArtifactRole::TAux);
+        assert_eq!(role(&store_file_name("tree-d")), CacheArtifactRole::TreeD);
+        assert_eq!(
+            role(&store_file_name("tree-c")),
+            CacheArtifactRole::TreeC(None)
+        );
+        assert_eq!(
+            role(&store_file_name("tree-c-3")),
+            CacheArtifactRole::TreeC(Some(3))
+        );
+        assert_eq!(
+            role(&store_file_name("tree-r-last-7")),
+            CacheArtifactRole::TreeRLast(Some(7))
+        );
+        assert_eq!(
+            role(&store_file_name(&CacheKey::label_layer(11))),
+            CacheArtifactRole::Layer(11)
+        );
+        assert_eq!(role(&store_file_name("layers")), CacheArtifactRole::Layers);
+        assert_eq!(role("layers.tmp"), CacheArtifactRole::Temporary);
+        assert_eq!(
+            role(&sibling_file_name(&CacheKey::label_layer(3), "digest")),
+            CacheArtifactRole::LayerDigest(3)
+        );
+        assert_eq!(
+            role(&sibling_file_name("tree-c-2", "progress")),
+            CacheArtifactRole::TreeProgress
+        );
+        assert_eq!(
+            role(&sibling_file_name("tree-r-last", "progress")),
+            CacheArtifactRole::TreeProgress
+        );
+
+        assert_eq!(
+            role(&store_file_name("layer-0")),
+            CacheArtifactRole::Unknown
+        );
+        assert_eq!(
+            role(&store_file_name("tree-c-x")),
+            CacheArtifactRole::Unknown
+        );
+        assert_eq!(role("tree-c"), CacheArtifactRole::Unknown);
+        assert_eq!(
+            role(&sibling_file_name("tree-d", "digest")),
+            CacheArtifactRole::Unknown
+        );
+        assert_eq!(
+            role(&sibling_file_name(&CacheKey::label_layer(3), "progress")),
+            CacheArtifactRole::Unknown
+        );
+        assert_eq!(role("notes.txt"), CacheArtifactRole::Unknown);
+
+        assert!(CacheArtifactRole::PAux.required_for_post());
+        assert!(CacheArtifactRole::TreeRLast(None).required_for_post());
+        assert!(!CacheArtifactRole::TAux.required_for_post());
+        assert!(!CacheArtifactRole::TreeC(None).required_for_post());
+    }
+}
@@ -0,0 +1,11 @@
+/// Selects which sealing artifacts `cleanup_seal_cache` keeps in a sector's cache directory.
+///
+/// Both policies keep `tree-r-last` and `p_aux`, which are all that PoSt needs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheRetention {
+    /// Also keep `t_aux`, so the sector can still be updated (snap deals) later on. This deletes
+    /// the same files as `clear_cache`.
+    KeepForUpdate,
+    /// Also delete `t_aux`, keeping only what is required to prove the sector during PoSt.
+    KeepForPoSt,
+}
@@ -1,7 +1,13 @@
 pub use merkletree::store::StoreConfig;
 pub use storage_proofs_core::merkle::{MerkleProof, MerkleTreeTrait};
-pub use storage_proofs_porep::stacked::{Labels, PersistentAux, TemporaryAux};
-
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+pub use storage_proofs_porep::stacked::{BufferPool, CoreIndex};
+pub use storage_proofs_porep::stacked::{
+    GpuBatchSizes, GpuOptions, GpuSelection, Labels, ParentCacheProgress, PersistentAux,
+    TemporaryAux,
+};
+
+use blstrs::Scalar as Fr;
 use filecoin_hashers::Hasher;
 use serde::{Deserialize, Serialize};
 use storage_proofs_core::{merkle::BinaryMerkleTree, sector::SectorId};
@@ -11,37 +17,49 @@ use storage_proofs_post::fallback;
 use crate::constants::DefaultPieceHasher;
 
 mod bytes_amount;
+mod cache_manifest;
+mod cache_retention;
 mod hselect;
 mod piece_info;
 mod porep_config;
+mod porep_id;
 mod porep_proof_partitions;
 mod post_config;
 mod post_proof_partitions;
+mod post_verification;
 mod private_replica_info;
 mod public_replica_info;
 mod sector_class;
 mod sector_size;
 mod sector_update_config;
+mod tree_info;
 mod update_proof_partitions;
 
 pub use bytes_amount::*;
+pub use cache_manifest::*;
+pub use cache_retention::*;
 pub use hselect::*;
 pub use piece_info::*;
 pub use porep_config::*;
+pub use porep_id::*;
 pub use porep_proof_partitions::*;
 pub use post_config::*;
 pub use post_proof_partitions::*;
+pub use post_verification::*;
 pub use private_replica_info::*;
 pub use public_replica_info::*;
 pub use sector_class::*;
 pub use sector_size::*;
 pub use sector_update_config::*;
+pub use tree_info::*;
 pub use update_proof_partitions::*;
 
 pub type Commitment = [u8; 32];
 pub type ChallengeSeed = [u8; 32];
 pub type ProverId = [u8; 32];
 pub type Ticket = [u8; 32];
+/// A replica id as a field element, as derived from the prover id, sector id, ticket and `comm_d`.
+pub type ReplicaId = Fr;
 pub type DataTree = BinaryMerkleTree<DefaultPieceHasher>;
 
 /// Arity for oct trees, used for comm_r_last.
@@ -72,6 +90,18 @@ pub struct SealCommitPhase1Output<Tree: MerkleTreeTrait> {
     pub ticket: Ticket,
 }
 
+/// The public inputs of a seal proof, which the verify functions take as separate arguments, see
+/// `validate_public_inputs`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SealPublicInputs {
+    pub comm_r: Commitment,
+    pub comm_d: Commitment,
+    pub prover_id: ProverId,
+    pub sector_id: SectorId,
+    pub ticket: Ticket,
+    pub seed: Ticket,
+}
+
 #[derive(Clone, Debug)]
 pub struct SealCommitOutput {
     pub proof: Vec<u8>,
@@ -115,6 +145,22 @@ pub struct FallbackPoStSectorProof<Tree: MerkleTreeTrait> {
     pub vanilla_proof: VanillaProof<Tree>, // Has comm_c, comm_r_last, inclusion_proofs
 }
 
+/// The vanilla proofs of some of the sectors of a Window PoSt, e.g. of those stored on one
+/// machine, which `merge_window_post_fragments` proves together with the other fragments.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WindowPoStFragment<Tree: MerkleTreeTrait> {
+    pub randomness: ChallengeSeed,
+    pub prover_id: ProverId,
+    /// All sectors of the Window PoSt, not only those of this fragment, as the challenges of a
+    /// sector depend on its position among them.
+    pub sectors: Vec<SectorId>,
+    #[serde(bound(
+        serialize = "FallbackPoStSectorProof<Tree>: Serialize",
+        deserialize = "FallbackPoStSectorProof<Tree>: Deserialize<'de>"
+    ))]
+    pub vanilla_proofs: Vec<FallbackPoStSectorProof<Tree>>,
+}
+
 pub struct EmptySectorUpdateEncoded {
     pub comm_r_new: Commitment,
     pub comm_r_last_new: Commitment,
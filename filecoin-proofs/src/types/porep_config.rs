@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 
-use anyhow::Result;
+use anyhow::{anyhow, ensure, Result};
 use storage_proofs_core::{
     api_version::ApiVersion,
     merkle::MerkleTreeTrait,
@@ -10,11 +10,15 @@ use storage_proofs_core::{
     },
 };
 use storage_proofs_porep::stacked::{StackedCircuit, StackedCompound};
+use typenum::Unsigned;
 
 use crate::{
-    constants::DefaultPieceHasher,
+    constants::{DefaultPieceHasher, POREP_PARTITIONS},
     parameters::public_params,
-    types::{PaddedBytesAmount, PoRepProofPartitions, SectorSize, UnpaddedBytesAmount},
+    types::{
+        PaddedBytesAmount, PoRepId, PoRepNetwork, PoRepProofPartitions, SectorSize,
+        UnpaddedBytesAmount,
+    },
 };
 
 #[derive(Clone, Copy, Debug)]
@@ -54,6 +58,42 @@ impl From<PoRepConfig> for SectorSize {
 }
 
 impl PoRepConfig {
+    /// Returns a builder which validates the combination of sector size, `porep_id` and api
+    /// version before creating the config.
+    pub fn builder() -> PoRepConfigBuilder {
+        PoRepConfigBuilder::default()
+    }
+
+    /// Returns the number of partitions a seal proof for this config consists of.
+    pub fn partition_count(&self) -> usize {
+        self.partitions.into()
+    }
+
+    /// Ensures that the proof type encoded in `porep_id` matches the configured sector size and
+    /// api version.
+    pub fn validate_porep_id(&self) -> Result<()> {
+        let id = PoRepId(self.porep_id);
+        id.validate(self.sector_size)?;
+        id.validate_api_version(self.api_version)
+    }
+
+    /// Ensures that `Tree` builds tree_c with the arity of tree_r_last if `porep_id` encodes a
+    /// registered proof type. Only unregistered proof types may use another tree_c arity, see
+    /// `MerkleTreeTrait::TreeCArity`.
+    pub fn validate_tree_c_arity<Tree: MerkleTreeTrait>(&self) -> Result<()> {
+        let id = PoRepId(self.porep_id);
+        ensure!(
+            id.network() == PoRepNetwork::Unregistered
+                || Tree::TreeCArity::to_usize() == Tree::Arity::to_usize(),
+            "porep_id of proof type {} requires a tree_c arity of {}, but it is {}",
+            id.proof_type(),
+            Tree::Arity::to_usize(),
+            Tree::TreeCArity::to_usize()
+        );
+
+        Ok(())
+    }
+
     /// Returns the cache identifier as used by `storage-proofs::parameter_cache`.
     pub fn get_cache_identifier<Tree: 'static + MerkleTreeTrait>(&self) -> Result<String> {
         let params = public_params::<Tree>(
@@ -86,3 +126,219 @@ impl PoRepConfig {
         Ok(parameter_cache_params_path(&id))
     }
 }
+
+/// Builds a [`PoRepConfig`], see [`PoRepConfig::builder`].
+///
+/// The sector size and `porep_id` are required. The api version defaults to the one of the
+/// registered proof type in `porep_id`, and the partitions default to the ones of the sector size.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PoRepConfigBuilder {
+    sector_size: Option<SectorSize>,
+    partitions: Option<PoRepProofPartitions>,
+    porep_id: Option<[u8; 32]>,
+    api_version: Option<ApiVersion>,
+}
+
+impl PoRepConfigBuilder {
+    pub fn sector_size(mut self, sector_size: SectorSize) -> Self {
+        self.sector_size = Some(sector_size);
+        self
+    }
+
+    pub fn partitions(mut self, partitions: PoRepProofPartitions) -> Self {
+        self.partitions = Some(partitions);
+        self
+    }
+
+    pub fn porep_id(mut self, porep_id: [u8; 32]) -> Self {
+        self.porep_id = Some(porep_id);
+        self
+    }
+
+    pub fn api_version(mut self, api_version: ApiVersion) -> Self {
+        self.api_version = Some(api_version);
+        self
+    }
+
+    pub fn build(self) -> Result<PoRepConfig> {
+        let sector_size = self
+            .sector_size
+            .ok_or_else(|| anyhow!("PoRepConfig requires a sector size"))?;
+        let porep_id = self
+            .porep_id
+            .ok_or_else(|| anyhow!("PoRepConfig requires a porep_id"))?;
+
+        let default_partitions = POREP_PARTITIONS
+            .read()
+            .expect("POREP_PARTITIONS poisoned")
+            .get(&u64::from(sector_size))
+            .copied();
+        let partitions = match (self.partitions, default_partitions) {
+            (Some(partitions), _) => partitions,
+            (None, Some(partitions)) => PoRepProofPartitions(partitions),
+            (None, None) => {
+                return Err(anyhow!(
+                    "unsupported sector size {}",
+                    u64::from(sector_size)
+                ))
+            }
+        };
+        ensure!(
+            usize::from(partitions) > 0,
+            "PoRepConfig requires at least one partition"
+        );
+
+        let id = PoRepId(porep_id);
+        id.validate(sector_size)?;
+        let api_version = match (self.api_version, id.api_version()) {
+            (Some(api_version), _) => {
+                id.validate_api_version(api_version)?;
+                api_version
+            }
+            (None, Some(expected)) => expected,
+            (None, None) => {
+                return Err(anyhow!(
+                    "PoRepConfig requires an api version for the unregistered proof type {}",
+                    id.proof_type()
+                ))
+            }
+        };
+
+        Ok(PoRepConfig {
+            sector_size,
+            partitions,
+            porep_id,
+            api_version,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use generic_array::typenum::U2;
+    use storage_proofs_core::merkle::WithTreeCArity;
+
+    use crate::constants::{
+        SectorShape2KiB, SectorShape32GiB, SectorShape32KiB, SectorShape4KiB, SECTOR_SIZE_2_KIB,
+        SECTOR_SIZE_32_GIB,
+    };
+
+    #[test]
+    fn test_builder_defaults() {
+        let config = PoRepConfig::builder()
+            .sector_size(SectorSize(SECTOR_SIZE_32_GIB))
+            .porep_id(PoRepId::new(8, 0).into())
+            .build()
+            .expect("build failed");
+        assert_eq!(config.partition_count(), 10);
+        assert_eq!(config.api_version, ApiVersion::V1_1_0);
+
+        let config = PoRepConfig::builder()
+            .sector_size(SectorSize(SECTOR_SIZE_2_KIB))
+            .porep_id(PoRepId::new(0, 0).into())
+            .build()
+            .expect("build failed");
+        assert_eq!(config.partition_count(), 1);
+        assert_eq!(config.api_version, ApiVersion::V1_0_0);
+    }
+
+    #[test]
+    fn test_builder_validation() {
+        // Missing fields.
+        assert!(PoRepConfig::builder()
+            .porep_id(PoRepId::new(5, 0).into())
+            .build()
+            .is_err());
+        assert!(PoRepConfig::builder()
+            .sector_size(SectorSize(SECTOR_SIZE_2_KIB))
+            .build()
+            .is_err());
+
+        // The proof type is for another sector size.
+        assert!(PoRepConfig::builder()
+            .sector_size(SectorSize(SECTOR_SIZE_2_KIB))
+            .porep_id(PoRepId::new(8, 0).into())
+            .build()
+            .is_err());
+
+        // The proof type is for another api version.
+        assert!(PoRepConfig::builder()
+            .sector_size(SectorSize(SECTOR_SIZE_2_KIB))
+            .porep_id(PoRepId::new(5, 0).into())
+            .api_version(ApiVersion::V1_0_0)
+            .build()
+            .is_err());
+
+        // Unsupported sector size.
+        assert!(PoRepConfig::builder()
+            .sector_size(SectorSize(3 << 10))
+            .porep_id([127; 32])
+            .api_version(ApiVersion::V1_1_0)
+            .build()
+            .is_err());
+
+        // Unregistered proof types need an explicit api version.
+        assert!(PoRepConfig::builder()
+            .sector_size(SectorSize(SECTOR_SIZE_2_KIB))
+            .porep_id([127; 32])
+            .build()
+            .is_err());
+        assert!(PoRepConfig::builder()
+            .sector_size(SectorSize(SECTOR_SIZE_2_KIB))
+            .porep_id([127; 32])
+            .api_version(ApiVersion::V1_0_0)
+            .build()
+            .is_ok());
+    }
+
+    fn assert_mainnet_tree_c<Tree: MerkleTreeTrait>(display: &str) {
+        assert_eq!(Tree::TreeCArity::to_usize(), Tree::Arity::to_usize());
+        assert_eq!(Tree::display(), display);
+    }
+
+    #[test]
+    fn test_mainnet_shapes_keep_tree_c_arity() {
+        // The parameter identifiers of the mainnet shapes depend on `display`.
+        assert_mainnet_tree_c::<SectorShape2KiB>("merkletree-poseidon_hasher-8-0-0");
+        assert_mainnet_tree_c::<SectorShape4KiB>("merkletree-poseidon_hasher-8-2-0");
+        assert_mainnet_tree_c::<SectorShape32GiB>("merkletree-poseidon_hasher-8-8-0");
+        assert_mainnet_tree_c::<SectorShape32KiB>("merkletree-poseidon_hasher-8-8-2");
+
+        assert_eq!(
+            WithTreeCArity::<SectorShape2KiB, U2>::display(),
+            "merkletree-poseidon_hasher-8-0-0-c2"
+        );
+    }
+
+    #[test]
+    fn test_validate_tree_c_arity() {
+        type SectorShape2KiBTreeC2 = WithTreeCArity<SectorShape2KiB, U2>;
+
+        let registered = PoRepConfig::builder()
+            .sector_size(SectorSize(SECTOR_SIZE_2_KIB))
+            .porep_id(PoRepId::new(5, 0).into())
+            .build()
+            .expect("build failed");
+        assert!(registered
+            .validate_tree_c_arity::<SectorShape2KiB>()
+            .is_ok());
+        assert!(registered
+            .validate_tree_c_arity::<SectorShape2KiBTreeC2>()
+            .is_err());
+
+        let unregistered = PoRepConfig::builder()
+            .sector_size(SectorSize(SECTOR_SIZE_2_KIB))
+            .porep_id([127; 32])
+            .api_version(ApiVersion::V1_1_0)
+            .build()
+            .expect("build failed");
+        assert!(unregistered
+            .validate_tree_c_arity::<SectorShape2KiB>()
+            .is_ok());
+        assert!(unregistered
+            .validate_tree_c_arity::<SectorShape2KiBTreeC2>()
+            .is_ok());
+    }
+}
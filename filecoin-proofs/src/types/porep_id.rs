@@ -0,0 +1,200 @@
+use std::convert::TryInto;
+
+use anyhow::{ensure, Result};
+use storage_proofs_core::api_version::ApiVersion;
+
+use crate::{
+    constants::{
+        SECTOR_SIZE_2_KIB, SECTOR_SIZE_32_GIB, SECTOR_SIZE_512_MIB, SECTOR_SIZE_64_GIB,
+        SECTOR_SIZE_8_MIB,
+    },
+    types::SectorSize,
+};
+
+/// The sector sizes of the registered seal proof types, indexed by proof type. Ids `0..=4` are
+/// the `V1` proofs, `5..=9` the `V1_1` proofs.
+const REGISTERED_SEAL_PROOF_SECTOR_SIZES: [u64; 10] = [
+    SECTOR_SIZE_2_KIB,
+    SECTOR_SIZE_8_MIB,
+    SECTOR_SIZE_512_MIB,
+    SECTOR_SIZE_32_GIB,
+    SECTOR_SIZE_64_GIB,
+    SECTOR_SIZE_2_KIB,
+    SECTOR_SIZE_8_MIB,
+    SECTOR_SIZE_512_MIB,
+    SECTOR_SIZE_32_GIB,
+    SECTOR_SIZE_64_GIB,
+];
+
+/// The network family a `PoRepId` belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoRepNetwork {
+    /// The id encodes one of the seal proof types registered on the Filecoin networks. Mainnet
+    /// and the testnets share these ids.
+    Filecoin,
+    /// The id encodes an unregistered proof type, e.g. one of the arbitrary ids used in tests and
+    /// benchmarks.
+    Unregistered,
+}
+
+/// A `porep_id`, which encodes the registered seal proof type in its first 8 bytes and a nonce in
+/// the following 8 bytes, both little-endian. The remaining bytes are zero for all registered
+/// ids.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PoRepId(pub [u8; 32]);
+
+impl From<[u8; 32]> for PoRepId {
+    fn from(bytes: [u8; 32]) -> Self {
+        PoRepId(bytes)
+    }
+}
+
+impl From<PoRepId> for [u8; 32] {
+    fn from(id: PoRepId) -> Self {
+        id.0
+    }
+}
+
+impl PoRepId {
+    /// Creates the id for the given registered seal proof type and nonce.
+    pub fn new(proof_type: u64, nonce: u64) -> Self {
+        let mut bytes = [0u8; 32];
+        bytes[..8].copy_from_slice(&proof_type.to_le_bytes());
+        bytes[8..16].copy_from_slice(&nonce.to_le_bytes());
+        PoRepId(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Returns the encoded registered seal proof type.
+    pub fn proof_type(&self) -> u64 {
+        u64::from_le_bytes(
+            self.0[..8]
+                .try_into()
+                .expect("8 bytes is always a valid u64"),
+        )
+    }
+
+    /// Returns the encoded nonce.
+    pub fn nonce(&self) -> u64 {
+        u64::from_le_bytes(
+            self.0[8..16]
+                .try_into()
+                .expect("8 bytes is always a valid u64"),
+        )
+    }
+
+    /// Returns the sector size of the encoded proof type, or `None` if it is not registered.
+    pub fn sector_size(&self) -> Option<SectorSize> {
+        REGISTERED_SEAL_PROOF_SECTOR_SIZES
+            .get(self.proof_type() as usize)
+            .map(|size| SectorSize(*size))
+    }
+
+    /// Returns the api version of the encoded proof type, or `None` if it is not registered.
+    pub fn api_version(&self) -> Option<ApiVersion> {
+        match self.proof_type() {
+            0..=4 => Some(ApiVersion::V1_0_0),
+            5..=9 => Some(ApiVersion::V1_1_0),
+            _ => None,
+        }
+    }
+
+    pub fn network(&self) -> PoRepNetwork {
+        if self.sector_size().is_some() {
+            PoRepNetwork::Filecoin
+        } else {
+            PoRepNetwork::Unregistered
+        }
+    }
+
+    /// Ensures that the id can be used to seal sectors of `sector_size`. Ids of unregistered proof
+    /// types are accepted for any sector size.
+    pub fn validate(&self, sector_size: SectorSize) -> Result<()> {
+        if let Some(expected) = self.sector_size() {
+            ensure!(
+                expected == sector_size,
+                "porep_id of proof type {} is for sector size {}, but the sector size is {}",
+                self.proof_type(),
+                u64::from(expected),
+                u64::from(sector_size),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Ensures that proofs of the encoded proof type are produced and verified with the logic of
+    /// `api_version`. Ids of unregistered proof types are accepted for any api version.
+    pub fn validate_api_version(&self, api_version: ApiVersion) -> Result<()> {
+        if let Some(expected) = self.api_version() {
+            ensure!(
+                expected == api_version,
+                "porep_id of proof type {} is for api version {}, but the api version is {}",
+                self.proof_type(),
+                expected,
+                api_version
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::constants::SECTOR_SIZE_4_KIB;
+
+    #[test]
+    fn test_porep_id_fields() {
+        let id = PoRepId::new(8, 3);
+        assert_eq!(id.proof_type(), 8);
+        assert_eq!(id.nonce(), 3);
+        assert_eq!(id.sector_size(), Some(SectorSize(SECTOR_SIZE_32_GIB)));
+        assert_eq!(id.network(), PoRepNetwork::Filecoin);
+        assert_eq!(id.api_version(), Some(ApiVersion::V1_1_0));
+        assert_eq!(PoRepId::new(3, 0).api_version(), Some(ApiVersion::V1_0_0));
+        assert_eq!(PoRepId::from(<[u8; 32]>::from(id)), id);
+
+        let arbitrary = PoRepId([127; 32]);
+        assert_eq!(arbitrary.sector_size(), None);
+        assert_eq!(arbitrary.network(), PoRepNetwork::Unregistered);
+        assert_eq!(arbitrary.api_version(), None);
+    }
+
+    #[test]
+    fn test_porep_id_validate() {
+        assert!(PoRepId::new(0, 0)
+            .validate(SectorSize(SECTOR_SIZE_2_KIB))
+            .is_ok());
+        assert!(PoRepId::new(5, 0)
+            .validate(SectorSize(SECTOR_SIZE_2_KIB))
+            .is_ok());
+        assert!(PoRepId::new(8, 0)
+            .validate(SectorSize(SECTOR_SIZE_64_GIB))
+            .is_err());
+        assert!(PoRepId([127; 32])
+            .validate(SectorSize(SECTOR_SIZE_4_KIB))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_porep_id_validate_api_version() {
+        assert!(PoRepId::new(0, 0)
+            .validate_api_version(ApiVersion::V1_0_0)
+            .is_ok());
+        assert!(PoRepId::new(0, 0)
+            .validate_api_version(ApiVersion::V1_1_0)
+            .is_err());
+        assert!(PoRepId::new(9, 0)
+            .validate_api_version(ApiVersion::V1_0_0)
+            .is_err());
+        assert!(PoRepId([127; 32])
+            .validate_api_version(ApiVersion::V1_0_0)
+            .is_ok());
+    }
+}
@@ -0,0 +1,44 @@
+use std::collections::BTreeMap;
+
+use storage_proofs_core::sector::SectorId;
+pub use storage_proofs_post::fallback::SectorProofVerification;
+
+/// The outcome of verifying the circuit proof of one partition of a Window PoSt.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartitionVerification {
+    /// The sectors proven by the partition, by ascending sector id.
+    pub sectors: Vec<SectorId>,
+    pub is_valid: bool,
+}
+
+/// The outcome of verifying a Window PoSt, as returned by `verify_window_post_detailed`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WindowPoStVerification {
+    /// Every partition, in order.
+    pub partitions: Vec<PartitionVerification>,
+    /// The outcome of verifying the vanilla proof of each sector, for the sectors whose vanilla
+    /// proofs were passed.
+    pub sectors: BTreeMap<SectorId, SectorProofVerification>,
+}
+
+impl WindowPoStVerification {
+    /// Whether the proof is valid, which is whether all of its partitions are.
+    pub fn is_valid(&self) -> bool {
+        self.partitions.iter().all(|partition| partition.is_valid)
+    }
+
+    /// Returns the partitions whose circuit proof is invalid.
+    pub fn invalid_partitions(&self) -> impl Iterator<Item = &PartitionVerification> {
+        self.partitions
+            .iter()
+            .filter(|partition| !partition.is_valid)
+    }
+
+    /// Returns the sectors whose vanilla proof is invalid.
+    pub fn faulty_sectors(&self) -> impl Iterator<Item = SectorId> + '_ {
+        self.sectors
+            .iter()
+            .filter(|(_, verification)| !verification.is_valid())
+            .map(|(sector_id, _)| *sector_id)
+    }
+}
@@ -16,11 +16,13 @@ use storage_proofs_core::{
         create_tree, get_base_tree_count, split_config_and_replica, MerkleTreeTrait,
         MerkleTreeWrapper,
     },
-    util::default_rows_to_discard,
 };
 
 use crate::{
-    api::{as_safe_commitment, get_base_tree_leafs, get_base_tree_size},
+    api::{
+        as_safe_commitment, ensure_replica_len, get_base_tree_leafs, get_base_tree_size,
+        get_tree_r_last_rows_to_discard,
+    },
     types::{Commitment, PersistentAux, SectorSize},
 };
 
@@ -116,7 +118,7 @@ impl<Tree: 'static + MerkleTreeTrait> PrivateReplicaInfo<Tree> {
     }
 
     pub fn safe_comm_r(&self) -> Result<<Tree::Hasher as Hasher>::Domain> {
-        as_safe_commitment(&self.comm_r, "comm_r")
+        Ok(as_safe_commitment(&self.comm_r, "comm_r")?)
     }
 
     pub fn safe_comm_c(&self) -> <Tree::Hasher as Hasher>::Domain {
@@ -127,6 +129,18 @@ impl<Tree: 'static + MerkleTreeTrait> PrivateReplicaInfo<Tree> {
         self.aux.comm_r_last
     }
 
+    /// Returns the number of rows the base trees of tree_r_last of this replica were stored
+    /// without, see `get_tree_r_last_rows_to_discard`.
+    pub fn rows_to_discard(&self, sector_size: SectorSize) -> Result<usize> {
+        let base_tree_size = get_base_tree_size::<Tree>(sector_size)?;
+        let base_tree_leafs = get_base_tree_leafs::<Tree>(base_tree_size)?;
+
+        Ok(get_tree_r_last_rows_to_discard::<Tree>(
+            self.cache_dir_path(),
+            base_tree_leafs,
+        )?)
+    }
+
     /// Generate the merkle tree of this particular replica.
     pub fn merkle_tree(
         &self,
@@ -140,13 +154,17 @@ impl<Tree: 'static + MerkleTreeTrait> PrivateReplicaInfo<Tree> {
             Tree::TopTreeArity,
         >,
     > {
+        ensure_replica_len(self.replica_path(), sector_size)?;
+
         let base_tree_size = get_base_tree_size::<Tree>(sector_size)?;
         let base_tree_leafs = get_base_tree_leafs::<Tree>(base_tree_size)?;
+        let rows_to_discard =
+            get_tree_r_last_rows_to_discard::<Tree>(self.cache_dir_path(), base_tree_leafs)?;
         trace!(
             "post: base tree size {}, base tree leafs {}, rows_to_discard {}, arities [{}, {}, {}]",
             base_tree_size,
             base_tree_leafs,
-            default_rows_to_discard(base_tree_leafs, Tree::Arity::to_usize()),
+            rows_to_discard,
             Tree::Arity::to_usize(),
             Tree::SubTreeArity::to_usize(),
             Tree::TopTreeArity::to_usize(),
@@ -155,7 +173,7 @@ impl<Tree: 'static + MerkleTreeTrait> PrivateReplicaInfo<Tree> {
         let mut config = StoreConfig::new(
             self.cache_dir_path(),
             CacheKey::CommRLastTree.to_string(),
-            default_rows_to_discard(base_tree_leafs, Tree::Arity::to_usize()),
+            rows_to_discard,
         );
         config.size = Some(base_tree_size);
 
@@ -33,6 +33,6 @@ impl PublicReplicaInfo {
     }
 
     pub fn safe_comm_r<T: Domain>(&self) -> Result<T> {
-        as_safe_commitment(&self.comm_r, "comm_r")
+        Ok(as_safe_commitment(&self.comm_r, "comm_r")?)
     }
 }
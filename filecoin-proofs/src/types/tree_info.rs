@@ -0,0 +1,19 @@
+/// The dimensions of tree_c and tree_r_last of a sector, as returned by `tree_info`.
+///
+/// Both trees are split into `base_tree_count` base trees of the same arity, which are joined by
+/// the sub and top trees of the sector shape.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TreeInfo {
+    /// The number of leaves, one per node of the sector.
+    pub leafs: usize,
+    pub base_tree_count: usize,
+    /// The number of rows from the leaves to the root, both included. A merkle proof has one
+    /// element less than this.
+    pub height: usize,
+    /// The number of nodes tree_c stores, in all base trees.
+    pub tree_c_nodes: usize,
+    /// The number of nodes tree_r_last stores, in all base trees. Its lowest rows are discarded
+    /// and read from the replica instead, see `rows_to_discard`, so this is only a fraction of
+    /// `tree_c_nodes`.
+    pub tree_r_last_nodes: usize,
+}
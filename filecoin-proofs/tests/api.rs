@@ -3,44 +3,66 @@ use std::fs::{metadata, read_dir, remove_file, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::Once;
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+use std::time::Duration;
 
 use anyhow::{ensure, Context, Error, Result};
 use bellperson::groth16;
-use bincode::serialize;
+use bincode::{deserialize, serialize};
 use blstrs::{Bls12, Scalar as Fr};
 use ff::Field;
-use filecoin_hashers::Hasher;
+use filecoin_hashers::{Domain, Hasher};
 use filecoin_proofs::{
-    add_piece, aggregate_seal_commit_proofs, clear_cache, compute_comm_d, decode_from, encode_into,
-    fauxrep_aux, generate_empty_sector_update_proof,
+    add_piece, aggregate_seal_commit_proofs,
+    caches::{get_post_verifying_key, get_stacked_verifying_key},
+    cleanup_seal_cache, clear_cache, compare_caches, compute_comm_d, decode_from, encode_into,
+    expected_cache_size, fauxrep_aux, finalize_comm_r, generate_empty_sector_update_proof,
     generate_empty_sector_update_proof_with_vanilla, generate_fallback_sector_challenges,
     generate_partition_proofs, generate_piece_commitment, generate_single_partition_proof,
     generate_single_vanilla_proof, generate_single_window_post_with_vanilla, generate_window_post,
-    generate_window_post_with_vanilla, generate_winning_post,
+    generate_window_post_vanilla_proofs, generate_window_post_with_vanilla, generate_winning_post,
     generate_winning_post_sector_challenge, generate_winning_post_with_vanilla,
-    get_num_partition_for_fallback_post, get_seal_inputs, merge_window_post_partition_proofs,
-    remove_encoded_data, seal_commit_phase1, seal_commit_phase2, seal_pre_commit_phase1,
-    seal_pre_commit_phase2, unseal_range, validate_cache_for_commit,
-    validate_cache_for_precommit_phase2, verify_aggregate_seal_commit_proofs,
-    verify_empty_sector_update_proof, verify_partition_proofs, verify_seal,
-    verify_single_partition_proof, verify_window_post, verify_winning_post, Commitment,
-    DefaultTreeDomain, MerkleTreeTrait, PaddedBytesAmount, PieceInfo, PoRepConfig,
-    PoRepProofPartitions, PoStConfig, PoStType, PrivateReplicaInfo, ProverId, PublicReplicaInfo,
-    SealCommitOutput, SealPreCommitOutput, SealPreCommitPhase1Output, SectorShape16KiB,
-    SectorShape2KiB, SectorShape32KiB, SectorShape4KiB, SectorSize, SectorUpdateConfig,
-    UnpaddedByteIndex, UnpaddedBytesAmount, POREP_PARTITIONS, SECTOR_SIZE_16_KIB,
-    SECTOR_SIZE_2_KIB, SECTOR_SIZE_32_KIB, SECTOR_SIZE_4_KIB, WINDOW_POST_CHALLENGE_COUNT,
-    WINDOW_POST_SECTOR_COUNT, WINNING_POST_CHALLENGE_COUNT, WINNING_POST_SECTOR_COUNT,
+    get_num_partition_for_fallback_post, get_seal_inputs, merge_window_post_fragments,
+    merge_window_post_partition_proofs, porep_challenged_nodes, rebuild_tree_c,
+    rebuild_tree_r_last, reconstruct_aux, remove_encoded_data, repair_replica_node, seal_cc_sector,
+    seal_commit_phase1, seal_commit_phase1_with_replica_id, seal_commit_phase2,
+    seal_pre_commit_phase1, seal_pre_commit_phase1_with_replica_id, seal_pre_commit_phase2,
+    seal_pre_commit_phase2_to_writer, seal_pre_commit_phase2_with_roots, tree_info, unseal_range,
+    validate_cache_for_commit, validate_cache_for_precommit_phase2,
+    verify_aggregate_seal_commit_proofs, verify_empty_sector_update_proof, verify_partition_proofs,
+    verify_seal, verify_seal_partition, verify_seal_with_replica_id,
+    verify_seal_with_verifying_key, verify_single_partition_proof, verify_vanilla_seal,
+    verify_window_post, verify_window_post_detailed, verify_window_post_with_verifying_key,
+    verify_winning_post, verify_winning_post_with_verifying_key, warmup_verifying_keys,
+    winning_post_challenges, CacheDivergence, CacheRetention, Commitment, DefaultPieceDomain,
+    DefaultTreeDomain, FallbackPoStSectorProof, GpuOptions, MerkleTreeTrait, PaddedBytesAmount,
+    PieceInfo, PoRepConfig, PoRepId, PoRepNetwork, PoRepProofPartitions, PoStConfig, PoStType,
+    PrivateReplicaInfo, ProverId, PublicReplicaInfo, SealCommitOutput, SealPreCommitOutput,
+    SealPreCommitPhase1Output, SectorShape16KiB, SectorShape2KiB, SectorShape32KiB,
+    SectorShape4KiB, SectorSize, SectorUpdateConfig, UnpaddedByteIndex, UnpaddedBytesAmount,
+    WindowPoStFragment, POREP_PARTITIONS, SECTOR_SIZE_16_KIB, SECTOR_SIZE_2_KIB,
+    SECTOR_SIZE_32_KIB, SECTOR_SIZE_4_KIB, WINDOW_POST_CHALLENGE_COUNT, WINDOW_POST_SECTOR_COUNT,
+    WINNING_POST_CHALLENGE_COUNT, WINNING_POST_SECTOR_COUNT,
 };
 use fr32::bytes_into_fr;
 use log::info;
 use memmap::MmapOptions;
+use merkletree::store::StoreConfig;
 use rand::{random, Rng, SeedableRng};
 use rand_xorshift::XorShiftRng;
-use storage_proofs_core::{api_version::ApiVersion, is_legacy_porep_id, sector::SectorId};
+use storage_proofs_core::{
+    api_version::ApiVersion, cache_key::CacheKey, is_legacy_porep_id, merkle::MerkleProofTrait,
+    sector::SectorId,
+};
+use storage_proofs_porep::stacked::{generate_replica_id, PersistentAux, PublicInputs, Tau};
 use storage_proofs_update::constants::TreeRHasher;
 use tempfile::{tempdir, NamedTempFile, TempDir};
 
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+use filecoin_proofs::{
+    seal_pre_commit_phase1_with_buffer_pool, seal_pre_commit_phase1_with_core_subset,
+    seal_pre_commit_phase1_with_priority, BufferPool, CoreIndex,
+};
 #[cfg(feature = "big-tests")]
 use filecoin_proofs::{
     SectorShape32GiB, SectorShape512MiB, SectorShape64GiB, SECTOR_SIZE_32_GIB, SECTOR_SIZE_512_MIB,
@@ -81,21 +103,919 @@ fn test_seal_lifecycle_2kib_porep_id_v1_base_8() -> Result<()> {
 fn test_seal_lifecycle_2kib_porep_id_v1_1_base_8() -> Result<()> {
     let porep_id_v1_1: u64 = 5; // This is a RegisteredSealProof value
 
-    let mut porep_id = [0u8; 32];
-    porep_id[..8].copy_from_slice(&porep_id_v1_1.to_le_bytes());
-    assert!(!is_legacy_porep_id(porep_id));
-    seal_lifecycle::<SectorShape2KiB>(SECTOR_SIZE_2_KIB, &porep_id, ApiVersion::V1_1_0)
-}
+    let mut porep_id = [0u8; 32];
+    porep_id[..8].copy_from_slice(&porep_id_v1_1.to_le_bytes());
+    assert!(!is_legacy_porep_id(porep_id));
+    seal_lifecycle::<SectorShape2KiB>(SECTOR_SIZE_2_KIB, &porep_id, ApiVersion::V1_1_0)
+}
+
+#[test]
+#[ignore]
+fn test_seal_lifecycle_upgrade_2kib_porep_id_v1_1_base_8() -> Result<()> {
+    let porep_id_v1_1: u64 = 5; // This is a RegisteredSealProof value
+
+    let mut porep_id = [0u8; 32];
+    porep_id[..8].copy_from_slice(&porep_id_v1_1.to_le_bytes());
+    assert!(!is_legacy_porep_id(porep_id));
+    seal_lifecycle_upgrade::<SectorShape2KiB>(SECTOR_SIZE_2_KIB, &porep_id, ApiVersion::V1_1_0)
+}
+
+#[test]
+fn test_repair_replica_node_2kib() -> Result<()> {
+    init_logger();
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+
+    let (mut piece_file, _piece_bytes) = generate_piece_file(SECTOR_SIZE_2_KIB)?;
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir().expect("failed to create temp dir");
+
+    let config = porep_config(
+        SECTOR_SIZE_2_KIB,
+        ARBITRARY_POREP_ID_V1_1_0,
+        ApiVersion::V1_1_0,
+    );
+    let prover_id = [9u8; 32];
+    let ticket = rng.gen();
+    let sector_id = rng.gen::<u64>().into();
+
+    let (_piece_infos, phase1_output) = run_seal_pre_commit_phase1::<SectorShape2KiB>(
+        config,
+        prover_id,
+        sector_id,
+        ticket,
+        &cache_dir,
+        &mut piece_file,
+        &sealed_sector_file,
+    )?;
+    let pre_commit_output = seal_pre_commit_phase2(
+        config,
+        phase1_output,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+    let comm_r = pre_commit_output.comm_r;
+
+    let original = std::fs::read(sealed_sector_file.path())?;
+    let corrupt = |nodes: &[usize]| -> Result<()> {
+        let mut replica = OpenOptions::new()
+            .write(true)
+            .open(sealed_sector_file.path())?;
+        for node in nodes {
+            replica.seek(SeekFrom::Start((*node * 32) as u64))?;
+            replica.write_all(&[0xaa; 4])?;
+        }
+        Ok(())
+    };
+    let repair = |node: usize| {
+        repair_replica_node::<SectorShape2KiB>(
+            config,
+            cache_dir.path(),
+            sealed_sector_file.path(),
+            comm_r,
+            node,
+        )
+    };
+
+    // Nodes which are not corrupted are refused.
+    assert!(repair(3).is_err());
+
+    corrupt(&[3])?;
+    repair(3)?;
+    assert_eq!(std::fs::read(sealed_sector_file.path())?, original);
+
+    // More corrupted nodes than the default of 1 are refused.
+    corrupt(&[5, 40])?;
+    assert!(repair(5).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_reconstruct_aux_2kib() -> Result<()> {
+    init_logger();
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+
+    let (mut piece_file, _piece_bytes) = generate_piece_file(SECTOR_SIZE_2_KIB)?;
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir().expect("failed to create temp dir");
+
+    let config = porep_config(
+        SECTOR_SIZE_2_KIB,
+        ARBITRARY_POREP_ID_V1_1_0,
+        ApiVersion::V1_1_0,
+    );
+    let (_piece_infos, phase1_output) = run_seal_pre_commit_phase1::<SectorShape2KiB>(
+        config,
+        [9u8; 32],
+        rng.gen::<u64>().into(),
+        rng.gen(),
+        &cache_dir,
+        &mut piece_file,
+        &sealed_sector_file,
+    )?;
+    let pre_commit_output = seal_pre_commit_phase2(
+        config,
+        phase1_output,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+    let comm_r = pre_commit_output.comm_r;
+
+    let p_aux_path = cache_dir.path().join(CacheKey::PAux.to_string());
+    let t_aux_path = cache_dir.path().join(CacheKey::TAux.to_string());
+    let p_aux = std::fs::read(&p_aux_path)?;
+    let t_aux = std::fs::read(&t_aux_path)?;
+    remove_file(&p_aux_path)?;
+    remove_file(&t_aux_path)?;
+
+    let reconstruct = |comm_r: Commitment| {
+        reconstruct_aux::<SectorShape2KiB>(
+            config.sector_size,
+            cache_dir.path(),
+            sealed_sector_file.path(),
+            comm_r,
+        )
+    };
+
+    // A comm_r which does not match the trees is refused, and nothing is written.
+    assert!(reconstruct([0; 32]).is_err());
+    assert!(!p_aux_path.exists());
+    assert!(!t_aux_path.exists());
+
+    reconstruct(comm_r)?;
+    assert_eq!(std::fs::read(&p_aux_path)?, p_aux);
+    assert_eq!(std::fs::read(&t_aux_path)?, t_aux);
+    validate_cache_for_commit::<_, _, SectorShape2KiB>(
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+
+    // Without tree_c, comm_c is lost.
+    remove_file(StoreConfig::data_path(
+        &cache_dir.path().to_path_buf(),
+        &CacheKey::CommCTree.to_string(),
+    ))?;
+    assert!(reconstruct(comm_r).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_rebuild_trees_2kib() -> Result<()> {
+    init_logger();
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+
+    let (mut piece_file, _piece_bytes) = generate_piece_file(SECTOR_SIZE_2_KIB)?;
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir().expect("failed to create temp dir");
+
+    let config = porep_config(
+        SECTOR_SIZE_2_KIB,
+        ARBITRARY_POREP_ID_V1_1_0,
+        ApiVersion::V1_1_0,
+    );
+    let (_piece_infos, phase1_output) = run_seal_pre_commit_phase1::<SectorShape2KiB>(
+        config,
+        [9u8; 32],
+        rng.gen::<u64>().into(),
+        rng.gen(),
+        &cache_dir,
+        &mut piece_file,
+        &sealed_sector_file,
+    )?;
+    let pre_commit_output = seal_pre_commit_phase2(
+        config,
+        phase1_output,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+    let comm_r = pre_commit_output.comm_r;
+
+    let cache_path = cache_dir.path().to_path_buf();
+    let corrupt = |path: &Path| -> Result<Vec<u8>> {
+        let bytes = std::fs::read(path)?;
+        let mut corrupted = bytes.clone();
+        corrupted[100] ^= 1;
+        std::fs::write(path, &corrupted)?;
+        Ok(bytes)
+    };
+
+    let tree_c_path = PathBuf::from(StoreConfig::data_path(
+        &cache_path,
+        &CacheKey::CommCTree.to_string(),
+    ));
+    let tree_c = corrupt(&tree_c_path)?;
+    assert_eq!(
+        rebuild_tree_c::<SectorShape2KiB>(cache_dir.path(), config.sector_size)?,
+        comm_r
+    );
+    assert_eq!(std::fs::read(&tree_c_path)?, tree_c);
+
+    let tree_r_last_path = PathBuf::from(StoreConfig::data_path(
+        &cache_path,
+        &CacheKey::CommRLastTree.to_string(),
+    ));
+    let tree_r_last = corrupt(&tree_r_last_path)?;
+    let rebuild_r_last = || {
+        rebuild_tree_r_last::<SectorShape2KiB>(
+            cache_dir.path(),
+            sealed_sector_file.path(),
+            config.sector_size,
+        )
+    };
+    assert_eq!(rebuild_r_last()?, comm_r);
+    assert_eq!(std::fs::read(&tree_r_last_path)?, tree_r_last);
+    validate_cache_for_commit::<_, _, SectorShape2KiB>(
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+
+    // A tree which does not match p_aux does not replace the existing one.
+    corrupt(sealed_sector_file.path())?;
+    assert!(rebuild_r_last().is_err());
+    assert_eq!(std::fs::read(&tree_r_last_path)?, tree_r_last);
+    assert!(!cache_dir.path().join("rebuild.tmp").exists());
+
+    // The layers are needed for tree_c, and p_aux for both trees.
+    remove_file(StoreConfig::data_path(
+        &cache_path,
+        &CacheKey::label_layer(1),
+    ))?;
+    assert!(rebuild_tree_c::<SectorShape2KiB>(cache_dir.path(), config.sector_size).is_err());
+    assert_eq!(std::fs::read(&tree_c_path)?, tree_c);
+    remove_file(cache_dir.path().join(CacheKey::PAux.to_string()))?;
+    assert!(rebuild_r_last().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_verify_vanilla_seal_2kib() -> Result<()> {
+    init_logger();
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+
+    let (mut piece_file, _piece_bytes) = generate_piece_file(SECTOR_SIZE_2_KIB)?;
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir().expect("failed to create temp dir");
+
+    let config = porep_config(
+        SECTOR_SIZE_2_KIB,
+        ARBITRARY_POREP_ID_V1_1_0,
+        ApiVersion::V1_1_0,
+    );
+    let prover_id = [9u8; 32];
+    let ticket = rng.gen();
+    let sector_id = rng.gen::<u64>().into();
+
+    let (_piece_infos, phase1_output) = run_seal_pre_commit_phase1::<SectorShape2KiB>(
+        config,
+        prover_id,
+        sector_id,
+        ticket,
+        &cache_dir,
+        &mut piece_file,
+        &sealed_sector_file,
+    )?;
+    let pre_commit_output = seal_pre_commit_phase2(
+        config,
+        phase1_output,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+
+    let comm_d = DefaultPieceDomain::try_from_bytes(&pre_commit_output.comm_d)?;
+    let mut public_inputs = PublicInputs::<DefaultTreeDomain, DefaultPieceDomain> {
+        replica_id: generate_replica_id::<<SectorShape2KiB as MerkleTreeTrait>::Hasher, _>(
+            &prover_id,
+            sector_id.into(),
+            &ticket,
+            comm_d,
+            &config.porep_id,
+        ),
+        tau: Some(Tau {
+            comm_r: DefaultTreeDomain::try_from_bytes(&pre_commit_output.comm_r)?,
+            comm_d,
+        }),
+        seed: rng.gen(),
+        k: None,
+    };
+    let verify = |public_inputs: &PublicInputs<DefaultTreeDomain, DefaultPieceDomain>| {
+        verify_vanilla_seal::<SectorShape2KiB>(
+            config,
+            cache_dir.path(),
+            sealed_sector_file.path(),
+            public_inputs,
+        )
+    };
+
+    assert!(verify(&public_inputs)?);
+
+    let tau = public_inputs.tau;
+    public_inputs.tau = Some(Tau {
+        comm_r: DefaultTreeDomain::random(&mut rng),
+        comm_d,
+    });
+    assert!(!verify(&public_inputs)?);
+    public_inputs.tau = tau;
+
+    // The layers cannot be proven for another replica id.
+    public_inputs.replica_id = DefaultTreeDomain::random(&mut rng);
+    assert!(verify(&public_inputs).is_err());
+
+    public_inputs.tau = None;
+    assert!(verify(&public_inputs).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_seal_with_replica_id_2kib() -> Result<()> {
+    init_logger();
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+
+    let config = porep_config(
+        SECTOR_SIZE_2_KIB,
+        ARBITRARY_POREP_ID_V1_1_0,
+        ApiVersion::V1_1_0,
+    );
+    let replica_id = DefaultTreeDomain::random(&mut rng);
+    let seed = rng.gen();
+
+    let staged_sector_file = NamedTempFile::new()?;
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir().expect("failed to create temp dir");
+    let phase1_output = seal_pre_commit_phase1_with_replica_id::<_, _, _, SectorShape2KiB>(
+        config,
+        cache_dir.path(),
+        staged_sector_file.path(),
+        sealed_sector_file.path(),
+        replica_id.into(),
+        &[],
+    )?;
+    let pre_commit_output = seal_pre_commit_phase2(
+        config,
+        phase1_output,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+
+    let commit_phase1_output = seal_commit_phase1_with_replica_id::<_, SectorShape2KiB>(
+        config,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+        replica_id.into(),
+        seed,
+        pre_commit_output.clone(),
+        &[],
+    )?;
+    assert_eq!(commit_phase1_output.replica_id, replica_id);
+    assert_eq!(commit_phase1_output.ticket, [0; 32]);
+
+    let public_inputs = PublicInputs::<DefaultTreeDomain, DefaultPieceDomain> {
+        replica_id,
+        tau: Some(Tau {
+            comm_r: DefaultTreeDomain::try_from_bytes(&pre_commit_output.comm_r)?,
+            comm_d: DefaultPieceDomain::try_from_bytes(&pre_commit_output.comm_d)?,
+        }),
+        seed,
+        k: None,
+    };
+    assert!(verify_vanilla_seal::<SectorShape2KiB>(
+        config,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+        &public_inputs,
+    )?);
+
+    // A given replica id is never accepted for a registered porep id.
+    let registered_config = PoRepConfig {
+        porep_id: PoRepId::new(5, 0).0,
+        ..config
+    };
+    assert_eq!(
+        PoRepId(registered_config.porep_id).network(),
+        PoRepNetwork::Filecoin
+    );
+    let other_cache_dir = tempdir().expect("failed to create temp dir");
+    assert!(
+        seal_pre_commit_phase1_with_replica_id::<_, _, _, SectorShape2KiB>(
+            registered_config,
+            other_cache_dir.path(),
+            staged_sector_file.path(),
+            NamedTempFile::new()?.path(),
+            replica_id.into(),
+            &[],
+        )
+        .is_err()
+    );
+    assert!(seal_commit_phase1_with_replica_id::<_, SectorShape2KiB>(
+        registered_config,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+        replica_id.into(),
+        seed,
+        pre_commit_output,
+        &[],
+    )
+    .is_err());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+fn test_seal_pre_commit_phase1_with_core_subset_2kib() -> Result<()> {
+    init_logger();
+    let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+
+    let config = porep_config(
+        SECTOR_SIZE_2_KIB,
+        ARBITRARY_POREP_ID_V1_1_0,
+        ApiVersion::V1_1_0,
+    );
+    let prover_id = rng.gen();
+    let sector_id = rng.gen::<u64>().into();
+    let ticket = rng.gen();
+
+    let seal = |core_subset: Option<Vec<CoreIndex>>| -> Result<Commitment> {
+        let staged_sector_file = NamedTempFile::new()?;
+        let sealed_sector_file = NamedTempFile::new()?;
+        let cache_dir = tempdir().expect("failed to create temp dir");
+        let phase1_output = seal_pre_commit_phase1_with_core_subset::<_, _, _, SectorShape2KiB>(
+            config,
+            cache_dir.path(),
+            staged_sector_file.path(),
+            sealed_sector_file.path(),
+            prover_id,
+            sector_id,
+            ticket,
+            &[],
+            core_subset,
+        )?;
+        let pre_commit_output = seal_pre_commit_phase2(
+            config,
+            phase1_output,
+            cache_dir.path(),
+            sealed_sector_file.path(),
+        )?;
+
+        Ok(pre_commit_output.comm_r)
+    };
+
+    // The cores only decide where the labels are computed, not what they are.
+    let comm_r = seal(None)?;
+    assert_eq!(seal(Some(vec![CoreIndex::new(0)?]))?, comm_r);
+
+    assert!(seal(Some(Vec::new())).is_err());
+    assert!(CoreIndex::new(usize::MAX).is_err());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+fn test_seal_pre_commit_phase1_with_priority_2kib() -> Result<()> {
+    init_logger();
+    let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+
+    let config = porep_config(
+        SECTOR_SIZE_2_KIB,
+        ARBITRARY_POREP_ID_V1_1_0,
+        ApiVersion::V1_1_0,
+    );
+    let prover_id = rng.gen();
+    let sector_id = rng.gen::<u64>().into();
+    let ticket = rng.gen();
+
+    let seal = |priority: u8, timeout: Duration| -> Result<Commitment> {
+        let staged_sector_file = NamedTempFile::new()?;
+        let sealed_sector_file = NamedTempFile::new()?;
+        let cache_dir = tempdir().expect("failed to create temp dir");
+        let phase1_output = seal_pre_commit_phase1_with_priority::<_, _, _, SectorShape2KiB>(
+            config,
+            cache_dir.path(),
+            staged_sector_file.path(),
+            sealed_sector_file.path(),
+            prover_id,
+            sector_id,
+            ticket,
+            &[],
+            priority,
+            timeout,
+        )?;
+        let pre_commit_output = seal_pre_commit_phase2(
+            config,
+            phase1_output,
+            cache_dir.path(),
+            sealed_sector_file.path(),
+        )?;
+
+        Ok(pre_commit_output.comm_r)
+    };
+
+    // Whether the labeling waits for a core group does not change the labels.
+    let comm_r = seal(0, Duration::from_secs(0))?;
+    assert_eq!(seal(u8::MAX, Duration::from_secs(10))?, comm_r);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+fn test_seal_pre_commit_phase1_with_buffer_pool_2kib() -> Result<()> {
+    init_logger();
+    let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+
+    let config = porep_config(
+        SECTOR_SIZE_2_KIB,
+        ARBITRARY_POREP_ID_V1_1_0,
+        ApiVersion::V1_1_0,
+    );
+    let prover_id = rng.gen();
+    let sector_id = rng.gen::<u64>().into();
+    let ticket = rng.gen();
+
+    let seal = |buffer_pool: &BufferPool| -> Result<Commitment> {
+        let staged_sector_file = NamedTempFile::new()?;
+        let sealed_sector_file = NamedTempFile::new()?;
+        let cache_dir = tempdir().expect("failed to create temp dir");
+        let phase1_output = seal_pre_commit_phase1_with_buffer_pool::<_, _, _, SectorShape2KiB>(
+            config,
+            cache_dir.path(),
+            staged_sector_file.path(),
+            sealed_sector_file.path(),
+            prover_id,
+            sector_id,
+            ticket,
+            &[],
+            buffer_pool,
+        )?;
+        let pre_commit_output = seal_pre_commit_phase2(
+            config,
+            phase1_output,
+            cache_dir.path(),
+            sealed_sector_file.path(),
+        )?;
+
+        Ok(pre_commit_output.comm_r)
+    };
+
+    let buffer_pool = BufferPool::new(SECTOR_SIZE_2_KIB, 1)?;
+    let comm_r = seal(&buffer_pool)?;
+    // The buffers are back in the pool and are reused for the next sector.
+    assert_eq!(buffer_pool.available(), 2);
+    assert_eq!(seal(&buffer_pool)?, comm_r);
+    assert_eq!(buffer_pool.available(), 2);
+
+    assert!(seal(&BufferPool::new(SECTOR_SIZE_4_KIB, 1)?).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_seal_cc_sector_2kib() -> Result<()> {
+    init_logger();
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+
+    let config = porep_config(
+        SECTOR_SIZE_2_KIB,
+        ARBITRARY_POREP_ID_V1_1_0,
+        ApiVersion::V1_1_0,
+    );
+    let prover_id = [9u8; 32];
+    let ticket = rng.gen();
+    let sector_id = rng.gen::<u64>().into();
+
+    // The generic path, for an empty staged sector which is padded with zeros.
+    let staged_sector_file = NamedTempFile::new()?;
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir().expect("failed to create temp dir");
+    let phase1_output = seal_pre_commit_phase1::<_, _, _, SectorShape2KiB>(
+        config,
+        cache_dir.path(),
+        staged_sector_file.path(),
+        sealed_sector_file.path(),
+        prover_id,
+        sector_id,
+        ticket,
+        &[],
+    )?;
+
+    let cc_sealed_sector_file = NamedTempFile::new()?;
+    let cc_cache_dir = tempdir().expect("failed to create temp dir");
+    let cc_phase1_output = seal_cc_sector::<_, _, SectorShape2KiB>(
+        config,
+        cc_cache_dir.path(),
+        cc_sealed_sector_file.path(),
+        prover_id,
+        sector_id,
+        ticket,
+    )?;
+
+    assert_eq!(cc_phase1_output.comm_d, phase1_output.comm_d);
+    assert_eq!(
+        cc_phase1_output.comm_d,
+        compute_comm_d(config.sector_size, &[])?
+    );
+    assert_eq!(cc_phase1_output.config.size, phase1_output.config.size);
+    let tree_d = |cache_dir: &TempDir| {
+        std::fs::read(StoreConfig::data_path(
+            &cache_dir.path().to_path_buf(),
+            &CacheKey::CommDTree.to_string(),
+        ))
+    };
+    assert_eq!(tree_d(&cc_cache_dir)?, tree_d(&cache_dir)?);
+
+    let pre_commit_output = seal_pre_commit_phase2(
+        config,
+        phase1_output,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+    let cc_pre_commit_output = seal_pre_commit_phase2(
+        config,
+        cc_phase1_output,
+        cc_cache_dir.path(),
+        cc_sealed_sector_file.path(),
+    )?;
+    assert_eq!(cc_pre_commit_output.comm_r, pre_commit_output.comm_r);
+    assert_eq!(cc_pre_commit_output.comm_d, pre_commit_output.comm_d);
+    assert_eq!(
+        std::fs::read(cc_sealed_sector_file.path())?,
+        std::fs::read(sealed_sector_file.path())?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_compare_caches_2kib() -> Result<()> {
+    init_logger();
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+
+    let (mut piece_file, _piece_bytes) = generate_piece_file(SECTOR_SIZE_2_KIB)?;
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir().expect("failed to create temp dir");
+
+    let config = porep_config(
+        SECTOR_SIZE_2_KIB,
+        ARBITRARY_POREP_ID_V1_1_0,
+        ApiVersion::V1_1_0,
+    );
+    let (_piece_infos, phase1_output) = run_seal_pre_commit_phase1::<SectorShape2KiB>(
+        config,
+        [9u8; 32],
+        rng.gen::<u64>().into(),
+        rng.gen(),
+        &cache_dir,
+        &mut piece_file,
+        &sealed_sector_file,
+    )?;
+    let pre_commit_output = seal_pre_commit_phase2(
+        config,
+        phase1_output,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+
+    let copy_dir = tempdir().expect("failed to create temp dir");
+    for entry in read_dir(cache_dir.path())? {
+        let path = entry?.path();
+        std::fs::copy(
+            &path,
+            copy_dir
+                .path()
+                .join(path.file_name().expect("no file name")),
+        )?;
+    }
+
+    let comparison = compare_caches::<SectorShape2KiB>(cache_dir.path(), copy_dir.path())?;
+    assert!(comparison.is_identical());
+    assert!(comparison.is_same_comm_r());
+    assert_eq!(comparison.comm_r_a, Some(pre_commit_output.comm_r));
+
+    let tree_c_path = PathBuf::from(StoreConfig::data_path(
+        &copy_dir.path().to_path_buf(),
+        &CacheKey::CommCTree.to_string(),
+    ));
+    let mut tree_c = std::fs::read(&tree_c_path)?;
+    tree_c[100] ^= 1;
+    std::fs::write(&tree_c_path, &tree_c)?;
+    let comparison = compare_caches::<SectorShape2KiB>(cache_dir.path(), copy_dir.path())?;
+    assert_eq!(
+        comparison.divergence,
+        Some(CacheDivergence::Content {
+            file_name: tree_c_path
+                .file_name()
+                .expect("no file name")
+                .to_string_lossy()
+                .into_owned(),
+            offset: 100,
+        })
+    );
+    // tree_c no longer hashes into the comm_c of p_aux, so the copy commits to no comm_r.
+    assert_eq!(comparison.comm_r_a, Some(pre_commit_output.comm_r));
+    assert_eq!(comparison.comm_r_b, None);
+    assert!(!comparison.is_same_comm_r());
+    std::fs::copy(
+        StoreConfig::data_path(
+            &cache_dir.path().to_path_buf(),
+            &CacheKey::CommCTree.to_string(),
+        ),
+        &tree_c_path,
+    )?;
+
+    remove_file(copy_dir.path().join(CacheKey::PAux.to_string()))?;
+    let comparison = compare_caches::<SectorShape2KiB>(cache_dir.path(), copy_dir.path())?;
+    assert_eq!(
+        comparison.divergence,
+        Some(CacheDivergence::Missing {
+            file_name: CacheKey::PAux.to_string(),
+            in_a: true,
+        })
+    );
+    assert_eq!(comparison.comm_r_b, None);
+    assert!(!comparison.is_same_comm_r());
+
+    Ok(())
+}
+
+#[test]
+fn test_seal_pre_commit_phase2_to_writer_2kib() -> Result<()> {
+    init_logger();
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+
+    let (mut piece_file, _piece_bytes) = generate_piece_file(SECTOR_SIZE_2_KIB)?;
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir().expect("failed to create temp dir");
+
+    let config = porep_config(
+        SECTOR_SIZE_2_KIB,
+        ARBITRARY_POREP_ID_V1_1_0,
+        ApiVersion::V1_1_0,
+    );
+    let (_piece_infos, phase1_output) = run_seal_pre_commit_phase1::<SectorShape2KiB>(
+        config,
+        [9u8; 32],
+        rng.gen::<u64>().into(),
+        rng.gen(),
+        &cache_dir,
+        &mut piece_file,
+        &sealed_sector_file,
+    )?;
+    let phase1_output_copy: SealPreCommitPhase1Output<SectorShape2KiB> =
+        deserialize(&serialize(&phase1_output)?)?;
+
+    // Seal a copy of the sector with `seal_pre_commit_phase2` to compare against.
+    let copy_dir = tempdir().expect("failed to create temp dir");
+    for entry in read_dir(cache_dir.path())? {
+        let path = entry?.path();
+        std::fs::copy(
+            &path,
+            copy_dir
+                .path()
+                .join(path.file_name().expect("no file name")),
+        )?;
+    }
+    let copy_sector_file = NamedTempFile::new()?;
+    std::fs::copy(sealed_sector_file.path(), copy_sector_file.path())?;
+
+    let mut streamed = std::io::Cursor::new(Vec::new());
+    let streamed_output = seal_pre_commit_phase2_to_writer(
+        config,
+        phase1_output,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+        &mut streamed,
+    )?;
+    // The sector is encoded in place and then streamed.
+    assert_eq!(
+        streamed.get_ref(),
+        &std::fs::read(sealed_sector_file.path())?
+    );
+
+    let output = seal_pre_commit_phase2(
+        config,
+        phase1_output_copy,
+        copy_dir.path(),
+        copy_sector_file.path(),
+    )?;
+    assert_eq!(streamed_output.comm_r, output.comm_r);
+    assert_eq!(streamed_output.comm_d, output.comm_d);
+    assert_eq!(
+        streamed.into_inner(),
+        std::fs::read(copy_sector_file.path())?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_expected_cache_size_2kib_base_8() -> Result<()> {
+    expected_cache_size_matches::<SectorShape2KiB>(SECTOR_SIZE_2_KIB)
+}
+
+#[test]
+fn test_expected_cache_size_4kib_sub_8_2() -> Result<()> {
+    expected_cache_size_matches::<SectorShape4KiB>(SECTOR_SIZE_4_KIB)
+}
+
+#[test]
+fn test_tree_info() -> Result<()> {
+    let info = tree_info(SectorSize(SECTOR_SIZE_2_KIB), 8, None)?;
+    assert_eq!(info.leafs, 64);
+    assert_eq!(info.base_tree_count, 1);
+    assert_eq!(info.height, 3);
+    assert_eq!(info.tree_c_nodes, 64 + 8 + 1);
+
+    let info = tree_info(SectorSize(SECTOR_SIZE_2_KIB), 2, None)?;
+    assert_eq!(info.height, 7);
+    assert_eq!(info.tree_c_nodes, 127);
+
+    // 32GiB: 8 base trees of 8^9 leaves each, joined by an 8-ary sub tree.
+    let info = tree_info(SectorSize(1 << 35), 8, None)?;
+    assert_eq!(info.leafs, 1 << 30);
+    assert_eq!(info.base_tree_count, 8);
+    assert_eq!(info.height, 11);
+    assert_eq!(info.tree_c_nodes, 8 * ((1 << 30) - 1) / 7);
+    assert!(info.tree_r_last_nodes < info.tree_c_nodes / 8);
+
+    // 64GiB: a top tree joins 2 of those sub trees.
+    let info = tree_info(SectorSize(1 << 36), 8, None)?;
+    assert_eq!(info.base_tree_count, 16);
+    assert_eq!(info.height, 12);
+
+    assert!(tree_info(SectorSize(SECTOR_SIZE_2_KIB), 3, None).is_err());
+    assert!(tree_info(SectorSize(SECTOR_SIZE_2_KIB), 16, None).is_err());
+    assert!(tree_info(SectorSize(1 << 20), 8, None).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_warmup_verifying_keys() -> Result<()> {
+    init_logger();
+
+    let configs = [
+        porep_config(
+            SECTOR_SIZE_2_KIB,
+            ARBITRARY_POREP_ID_V1_1_0,
+            ApiVersion::V1_1_0,
+        ),
+        porep_config(
+            SECTOR_SIZE_4_KIB,
+            ARBITRARY_POREP_ID_V1_1_0,
+            ApiVersion::V1_1_0,
+        ),
+    ];
+    warmup_verifying_keys(&[])?;
+    // Configs sharing a key are loaded once.
+    warmup_verifying_keys(&[configs[0], configs[1], configs[0]])?;
+
+    Ok(())
+}
+
+fn expected_cache_size_matches<Tree: 'static + MerkleTreeTrait>(sector_size: u64) -> Result<()> {
+    init_logger();
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+
+    let (mut piece_file, _piece_bytes) = generate_piece_file(sector_size)?;
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir().expect("failed to create temp dir");
+
+    let config = porep_config(sector_size, ARBITRARY_POREP_ID_V1_1_0, ApiVersion::V1_1_0);
+    let expected = expected_cache_size::<Tree>(config, None)?;
+
+    let (_piece_infos, phase1_output) = run_seal_pre_commit_phase1::<Tree>(
+        config,
+        [9u8; 32],
+        rng.gen::<u64>().into(),
+        rng.gen(),
+        &cache_dir,
+        &mut piece_file,
+        &sealed_sector_file,
+    )?;
+    seal_pre_commit_phase2(
+        config,
+        phase1_output,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+
+    let mut actual = 0;
+    for entry in read_dir(cache_dir.path())? {
+        actual += entry?.metadata()?.len();
+    }
 
-#[test]
-#[ignore]
-fn test_seal_lifecycle_upgrade_2kib_porep_id_v1_1_base_8() -> Result<()> {
-    let porep_id_v1_1: u64 = 5; // This is a RegisteredSealProof value
+    // Only t_aux is overestimated, since its size depends on the length of the cache path.
+    assert!(
+        actual <= expected && expected - actual <= 32 * 1024,
+        "expected {} bytes, found {}",
+        expected,
+        actual
+    );
 
-    let mut porep_id = [0u8; 32];
-    porep_id[..8].copy_from_slice(&porep_id_v1_1.to_le_bytes());
-    assert!(!is_legacy_porep_id(porep_id));
-    seal_lifecycle_upgrade::<SectorShape2KiB>(SECTOR_SIZE_2_KIB, &porep_id, ApiVersion::V1_1_0)
+    Ok(())
 }
 
 #[test]
@@ -462,6 +1382,115 @@ fn test_seal_proof_aggregation_818_32kib_porep_id_v1_1_base_8() -> Result<()> {
     Ok(())
 }
 
+#[test]
+#[ignore]
+fn test_seal_proof_aggregation_invalid_2kib_porep_id_v1_1_base_8() -> Result<()> {
+    let porep_id = ARBITRARY_POREP_ID_V1_1_0;
+    let api_version = ApiVersion::V1_1_0;
+    let config = porep_config(SECTOR_SIZE_2_KIB, porep_id, api_version);
+
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+    let prover_fr: DefaultTreeDomain = Fr::random(&mut rng).into();
+    let mut prover_id = [0u8; 32];
+    prover_id.copy_from_slice(AsRef::<[u8]>::as_ref(&prover_fr));
+
+    let (output_a, inputs_a, seed_a, comm_r_a) = create_seal_for_aggregation::<_, SectorShape2KiB>(
+        &mut rng,
+        SECTOR_SIZE_2_KIB,
+        prover_id,
+        &porep_id,
+        api_version,
+    )?;
+    let (output_b, inputs_b, seed_b, comm_r_b) = create_seal_for_aggregation::<_, SectorShape2KiB>(
+        &mut rng,
+        SECTOR_SIZE_2_KIB,
+        prover_id,
+        &porep_id,
+        api_version,
+    )?;
+
+    // Three proofs are padded to four.
+    let commit_outputs = vec![output_a.clone(), output_b, output_a];
+    let comm_rs = vec![comm_r_a, comm_r_b, comm_r_a];
+    let seeds = vec![seed_a, seed_b, seed_a];
+    let commit_inputs: Vec<Vec<Fr>> = vec![inputs_a.clone(), inputs_b.clone(), inputs_a]
+        .into_iter()
+        .flatten()
+        .collect();
+
+    // Aggregating the same proofs twice produces the same aggregate.
+    let aggregate_proof =
+        aggregate_seal_commit_proofs::<SectorShape2KiB>(config, &comm_rs, &seeds, &commit_outputs)?;
+    assert_eq!(
+        aggregate_seal_commit_proofs::<SectorShape2KiB>(config, &comm_rs, &seeds, &commit_outputs)?,
+        aggregate_proof
+    );
+    assert!(verify_aggregate_seal_commit_proofs::<SectorShape2KiB>(
+        config,
+        aggregate_proof.clone(),
+        &comm_rs,
+        &seeds,
+        commit_inputs.clone(),
+    )?);
+
+    // Checking the third proof, and the fourth which pads it, against the inputs of sector b
+    // makes the aggregate invalid.
+    let mismatched_inputs: Vec<Vec<Fr>> = commit_inputs[..2 * inputs_b.len()]
+        .iter()
+        .cloned()
+        .chain(inputs_b)
+        .collect();
+    assert!(!verify_aggregate_seal_commit_proofs::<SectorShape2KiB>(
+        config,
+        aggregate_proof.clone(),
+        &comm_rs,
+        &seeds,
+        mismatched_inputs,
+    )?);
+
+    // Malformed inputs are errors rather than invalid proofs.
+    let mut zero_comm_rs = comm_rs.clone();
+    zero_comm_rs[1] = [0; 32];
+    assert!(verify_aggregate_seal_commit_proofs::<SectorShape2KiB>(
+        config,
+        aggregate_proof.clone(),
+        &zero_comm_rs,
+        &seeds,
+        commit_inputs.clone(),
+    )
+    .is_err());
+    assert!(verify_aggregate_seal_commit_proofs::<SectorShape2KiB>(
+        config,
+        aggregate_proof.clone(),
+        &comm_rs,
+        &seeds,
+        commit_inputs[1..].to_vec(),
+    )
+    .is_err());
+
+    // A different transcript of comm_rs and seeds is rejected as well.
+    let mut swapped_seeds = seeds.clone();
+    swapped_seeds.swap(0, 1);
+    assert!(!verify_aggregate_seal_commit_proofs::<SectorShape2KiB>(
+        config,
+        aggregate_proof,
+        &comm_rs,
+        &swapped_seeds,
+        commit_inputs,
+    )?);
+
+    // The comm_rs and seeds have to match the proofs one to one.
+    assert!(aggregate_seal_commit_proofs::<SectorShape2KiB>(
+        config,
+        &comm_rs[..2],
+        &seeds[..2],
+        &commit_outputs
+    )
+    .is_err());
+
+    Ok(())
+}
+
 //#[test]
 //#[ignore]
 //fn test_seal_proof_aggregation_818_32gib_porep_id_v1_1_base_8() -> Result<()> {
@@ -546,13 +1575,15 @@ fn aggregate_proofs<Tree: 'static + MerkleTreeTrait>(
     let config = porep_config(sector_size, *porep_id, api_version);
     let aggregate_proof =
         aggregate_seal_commit_proofs::<Tree>(config, &comm_rs, &seeds, commit_outputs.as_slice())?;
-    verify_aggregate_seal_commit_proofs::<Tree>(
+    let verified = verify_aggregate_seal_commit_proofs::<Tree>(
         config,
         aggregate_proof,
         &comm_rs,
         &seeds,
         commit_inputs,
-    )
+    )?;
+
+    Ok(verified)
 }
 
 fn get_layer_file_paths(cache_dir: &tempfile::TempDir) -> Vec<PathBuf> {
@@ -780,6 +1811,51 @@ fn test_winning_post_32kib_top_8_8_2() -> Result<()> {
     winning_post::<SectorShape32KiB>(SECTOR_SIZE_32_KIB, true, ApiVersion::V1_1_0)
 }
 
+#[test]
+fn test_winning_post_challenges() -> Result<()> {
+    let randomness = [1u8; 32];
+    let sector_id = SectorId::from(42);
+
+    let challenges =
+        winning_post_challenges(&randomness, sector_id, SectorSize(SECTOR_SIZE_2_KIB))?;
+    assert_eq!(challenges.len(), WINNING_POST_CHALLENGE_COUNT);
+    assert_eq!(&challenges[..8], &[17, 4, 59, 61, 32, 29, 45, 3]);
+    assert_eq!(challenges[WINNING_POST_CHALLENGE_COUNT - 1], 22);
+
+    let challenges = winning_post_challenges(&randomness, sector_id, SectorSize(1 << 35))?;
+    assert_eq!(
+        &challenges[..4],
+        &[604_370_577, 219_822_788, 698_450_683, 468_422_461]
+    );
+
+    // The same leaves are challenged for the vanilla proofs of a Winning PoSt.
+    let config = PoStConfig {
+        sector_size: SECTOR_SIZE_2_KIB.into(),
+        sector_count: WINNING_POST_SECTOR_COUNT,
+        challenge_count: WINNING_POST_CHALLENGE_COUNT,
+        typ: PoStType::Winning,
+        priority: false,
+        api_version: ApiVersion::V1_1_0,
+    };
+    let vanilla_challenges = generate_fallback_sector_challenges::<SectorShape2KiB>(
+        &config,
+        &randomness,
+        &[sector_id],
+        [0; 32],
+    )?;
+    assert_eq!(
+        winning_post_challenges(&randomness, sector_id, SectorSize(SECTOR_SIZE_2_KIB))?,
+        vanilla_challenges[&sector_id]
+    );
+
+    assert!(winning_post_challenges(&randomness, sector_id, SectorSize(1000)).is_err());
+    assert!(
+        winning_post_challenges(&[0xff; 32], sector_id, SectorSize(SECTOR_SIZE_2_KIB)).is_err()
+    );
+
+    Ok(())
+}
+
 #[test]
 fn test_winning_post_empty_sector_challenge() -> Result<()> {
     let mut rng = XorShiftRng::from_seed(TEST_SEED);
@@ -855,6 +1931,12 @@ fn winning_post<Tree: 'static + MerkleTreeTrait>(
             api_version,
         )?
     };
+
+    // Only what PoSt needs is required from here on.
+    cleanup_seal_cache::<Tree>(cache_dir.path(), CacheRetention::KeepForPoSt)?;
+    assert!(!cache_dir.path().join(CacheKey::TAux.to_string()).exists());
+    assert!(cache_dir.path().join(CacheKey::PAux.to_string()).exists());
+
     let sector_count = WINNING_POST_SECTOR_COUNT;
 
     let random_fr: DefaultTreeDomain = Fr::random(&mut rng).into();
@@ -921,6 +2003,17 @@ fn winning_post<Tree: 'static + MerkleTreeTrait>(
         verify_winning_post::<Tree>(&config, &randomness, &pub_replicas[..], prover_id, &proof)?;
     assert!(valid, "proof did not verify");
 
+    let verifying_key = get_post_verifying_key::<Tree>(&config)?;
+    let valid = verify_winning_post_with_verifying_key::<Tree>(
+        &config,
+        &verifying_key,
+        &randomness,
+        &pub_replicas[..],
+        prover_id,
+        &proof,
+    )?;
+    assert!(valid, "proof did not verify with verifying key");
+
     replica.close()?;
 
     Ok(())
@@ -1433,6 +2526,17 @@ fn window_post<Tree: 'static + MerkleTreeTrait>(
     let valid = verify_window_post::<Tree>(&config, &randomness, &pub_replicas, prover_id, &proof)?;
     assert!(valid, "proof did not verify");
 
+    let verifying_key = get_post_verifying_key::<Tree>(&config)?;
+    let valid = verify_window_post_with_verifying_key::<Tree>(
+        &config,
+        &verifying_key,
+        &randomness,
+        &pub_replicas,
+        prover_id,
+        &proof,
+    )?;
+    assert!(valid, "proof did not verify with verifying key");
+
     // 2)
     let replica_sectors = priv_replicas
         .iter()
@@ -1456,13 +2560,147 @@ fn window_post<Tree: 'static + MerkleTreeTrait>(
         vanilla_proofs.push(single_proof);
     }
 
-    let proof =
-        generate_window_post_with_vanilla::<Tree>(&config, &randomness, prover_id, vanilla_proofs)?;
+    // The vanilla proofs don't depend on how many sectors are proven at once.
+    for batch_size in &[1, 2, total_sector_count] {
+        let batched_proofs = generate_window_post_vanilla_proofs::<Tree>(
+            &config,
+            &randomness,
+            &priv_replicas,
+            prover_id,
+            *batch_size,
+        )?;
+        assert_eq!(
+            serialize(&batched_proofs)?,
+            serialize(&vanilla_proofs)?,
+            "batch size {}",
+            batch_size
+        );
+    }
+
+    // The sectors are proven in ascending sector id order, whatever order they are passed in.
+    let mut shuffled_sectors = replica_sectors.clone();
+    shuffled_sectors.reverse();
+    let shuffled_challenges = generate_fallback_sector_challenges::<Tree>(
+        &config,
+        &randomness,
+        &shuffled_sectors,
+        prover_id,
+    )?;
+    assert_eq!(shuffled_challenges, challenges);
+
+    let verification = verify_window_post_detailed::<Tree>(
+        &config,
+        &randomness,
+        &pub_replicas,
+        prover_id,
+        &proof,
+        Some(&vanilla_proofs),
+    )?;
+    assert!(verification.is_valid(), "detailed proof did not verify");
+    assert_eq!(
+        verification.partitions.len(),
+        get_num_partition_for_fallback_post(&config, total_sector_count)
+    );
+    assert_eq!(
+        verification
+            .partitions
+            .iter()
+            .flat_map(|partition| partition.sectors.iter().copied())
+            .collect::<Vec<_>>(),
+        replica_sectors
+    );
+    assert_eq!(verification.sectors.len(), total_sector_count);
+    assert_eq!(verification.faulty_sectors().count(), 0);
+
+    // Only the partition and the sector with the wrong comm_r fail, and all of its challenges
+    // are still valid.
+    let faulty_sector = *replica_sectors.last().expect("no sectors");
+    let mut faulty_replicas = pub_replicas.clone();
+    faulty_replicas.insert(faulty_sector, PublicReplicaInfo::new([1; 32])?);
+    let verification = verify_window_post_detailed::<Tree>(
+        &config,
+        &randomness,
+        &faulty_replicas,
+        prover_id,
+        &proof,
+        Some(&vanilla_proofs),
+    )?;
+    assert!(!verification.is_valid());
+    let invalid_partitions: Vec<_> = verification.invalid_partitions().collect();
+    assert_eq!(invalid_partitions.len(), 1);
+    assert!(invalid_partitions[0].sectors.contains(&faulty_sector));
+    assert_eq!(
+        verification.faulty_sectors().collect::<Vec<_>>(),
+        vec![faulty_sector]
+    );
+    let sector_verification = &verification.sectors[&faulty_sector];
+    assert!(!sector_verification.comm_r_valid);
+    assert!(sector_verification.challenges.iter().all(|valid| *valid));
+    assert!(!verify_window_post::<Tree>(
+        &config,
+        &randomness,
+        &faulty_replicas,
+        prover_id,
+        &proof
+    )?);
+
+    let mut shuffled_proofs = vanilla_proofs;
+    shuffled_proofs.reverse();
+    let proof = generate_window_post_with_vanilla::<Tree>(
+        &config,
+        &randomness,
+        prover_id,
+        shuffled_proofs,
+    )?;
     /////////////////////////////////////////////
 
     let valid = verify_window_post::<Tree>(&config, &randomness, &pub_replicas, prover_id, &proof)?;
     assert!(valid, "proof did not verify");
 
+    // Vanilla proofs generated on different machines are proven together.
+    let fragment = |vanilla_proofs: Vec<FallbackPoStSectorProof<Tree>>| WindowPoStFragment {
+        randomness,
+        prover_id,
+        sectors: replica_sectors.clone(),
+        vanilla_proofs,
+    };
+    let vanilla_proofs = generate_window_post_vanilla_proofs::<Tree>(
+        &config,
+        &randomness,
+        &priv_replicas,
+        prover_id,
+        total_sector_count,
+    )?;
+    let mut fragments = vec![fragment(Vec::new()), fragment(Vec::new())];
+    for (i, vanilla_proof) in vanilla_proofs.into_iter().enumerate() {
+        fragments[i % 2].vanilla_proofs.push(vanilla_proof);
+    }
+    let proof = merge_window_post_fragments(&config, fragments)?;
+    let valid = verify_window_post::<Tree>(&config, &randomness, &pub_replicas, prover_id, &proof)?;
+    assert!(valid, "merged proof did not verify");
+
+    let other_randomness = WindowPoStFragment {
+        randomness: [1; 32],
+        ..fragment(Vec::new())
+    };
+    assert!(
+        merge_window_post_fragments(&config, vec![fragment(Vec::new()), other_randomness]).is_err()
+    );
+    assert!(merge_window_post_fragments(&config, vec![fragment(Vec::new())]).is_err());
+    let (sector_id, replica) = priv_replicas.iter().next().expect("no sectors");
+    let duplicated = (0..2)
+        .map(|_| {
+            generate_single_vanilla_proof::<Tree>(
+                &config,
+                *sector_id,
+                replica,
+                &challenges[sector_id],
+            )
+            .map(|vanilla_proof| fragment(vec![vanilla_proof]))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    assert!(merge_window_post_fragments(&config, duplicated).is_err());
+
     Ok(())
 }
 
@@ -1481,6 +2719,12 @@ fn generate_piece_file(sector_size: u64) -> Result<(NamedTempFile, Vec<u8>)> {
     Ok((piece_file, piece_bytes))
 }
 
+fn domain_commitment<D: Domain>(domain: D) -> Commitment {
+    let mut commitment = [0; 32];
+    commitment.copy_from_slice(domain.as_ref());
+    commitment
+}
+
 fn porep_config(sector_size: u64, porep_id: [u8; 32], api_version: ApiVersion) -> PoRepConfig {
     PoRepConfig {
         sector_size: SectorSize(sector_size),
@@ -1567,6 +2811,26 @@ fn generate_proof<Tree: 'static + MerkleTreeTrait>(
 
     clear_cache::<Tree>(cache_dir_path)?;
 
+    let public_inputs = PublicInputs::<<Tree::Hasher as Hasher>::Domain, DefaultPieceDomain> {
+        replica_id: phase1_output.replica_id,
+        tau: Some(Tau {
+            comm_r: <Tree::Hasher as Hasher>::Domain::try_from_bytes(&phase1_output.comm_r)?,
+            comm_d: DefaultPieceDomain::try_from_bytes(&phase1_output.comm_d)?,
+        }),
+        seed,
+        k: None,
+    };
+    for (k, partition_proofs) in phase1_output.vanilla_proofs.iter().enumerate() {
+        let challenged: Vec<usize> = partition_proofs
+            .iter()
+            .map(|proof| proof.comm_d_proofs.path_index())
+            .collect();
+        assert_eq!(
+            porep_challenged_nodes::<Tree>(config, &public_inputs, k)?,
+            challenged
+        );
+    }
+
     ensure!(
         seed == phase1_output.seed,
         "seed and phase1 output seed do not match"
@@ -1650,6 +2914,95 @@ fn unseal<Tree: 'static + MerkleTreeTrait>(
         &commit_output.proof,
     )?;
     assert!(verified, "failed to verify valid seal");
+
+    let verifying_key = get_stacked_verifying_key::<Tree>(config)?;
+    let verified = verify_seal_with_verifying_key::<Tree>(
+        config,
+        &verifying_key,
+        comm_r,
+        comm_d,
+        prover_id,
+        sector_id,
+        ticket,
+        seed,
+        &commit_output.proof,
+    )?;
+    assert!(verified, "failed to verify valid seal with verifying key");
+
+    // Registered proof types are only verified with the logic of their own api version.
+    if PoRepId::from(config.porep_id).api_version().is_some() {
+        let other_api_version = match config.api_version {
+            ApiVersion::V1_0_0 => ApiVersion::V1_1_0,
+            ApiVersion::V1_1_0 => ApiVersion::V1_0_0,
+        };
+        assert!(verify_seal::<Tree>(
+            PoRepConfig {
+                api_version: other_api_version,
+                ..config
+            },
+            comm_r,
+            comm_d,
+            prover_id,
+            sector_id,
+            ticket,
+            seed,
+            &commit_output.proof,
+        )
+        .is_err());
+    }
+
+    for partition_index in 0..config.partition_count() {
+        let verified = verify_seal_partition::<Tree>(
+            config,
+            comm_r,
+            comm_d,
+            prover_id,
+            sector_id,
+            ticket,
+            seed,
+            &commit_output.proof,
+            partition_index,
+        )?;
+        assert!(verified, "failed to verify partition {}", partition_index);
+    }
+    assert!(verify_seal_partition::<Tree>(
+        config,
+        comm_r,
+        comm_d,
+        prover_id,
+        sector_id,
+        ticket,
+        seed,
+        &commit_output.proof,
+        config.partition_count(),
+    )
+    .is_err());
+
+    let replica_id: Fr = generate_replica_id::<Tree::Hasher, _>(
+        &prover_id,
+        sector_id.into(),
+        &ticket,
+        comm_d,
+        &config.porep_id,
+    )
+    .into();
+    let verified = verify_seal_with_replica_id::<Tree>(
+        config,
+        comm_r,
+        comm_d,
+        replica_id,
+        seed,
+        &commit_output.proof,
+    );
+    if PoRepId(config.porep_id).network() == PoRepNetwork::Unregistered {
+        assert!(verified?, "failed to verify valid seal with replica id");
+    } else {
+        assert!(
+            verified.is_err(),
+            "replica id must not be accepted for registered porep ids"
+        );
+    }
+
     Ok(())
 }
 
@@ -1722,17 +3075,36 @@ fn create_seal<R: Rng, Tree: 'static + MerkleTreeTrait>(
         &sealed_sector_file,
     )?;
 
-    let pre_commit_output = seal_pre_commit_phase2(
+    // The output is reported as soon as the trees are built, before phase 2 returns it.
+    let mut early_output = None;
+    let pre_commit_output = seal_pre_commit_phase2_with_roots(
         config,
         phase1_output,
         cache_dir.path(),
         sealed_sector_file.path(),
+        &GpuOptions::default(),
+        &mut |output| early_output = Some(output.clone()),
     )?;
 
     let comm_r = pre_commit_output.comm_r;
+    let early_output = early_output.expect("the roots were not reported");
+    assert_eq!(early_output.comm_r, comm_r);
+    assert_eq!(early_output.comm_d, pre_commit_output.comm_d);
 
     validate_cache_for_commit::<_, _, Tree>(cache_dir.path(), sealed_sector_file.path())?;
 
+    // comm_r can be recomputed from the roots persisted in p_aux.
+    let p_aux: PersistentAux<<Tree::Hasher as Hasher>::Domain> = deserialize(&std::fs::read(
+        cache_dir.path().join(CacheKey::PAux.to_string()),
+    )?)?;
+    assert_eq!(
+        finalize_comm_r::<Tree>(
+            domain_commitment(p_aux.comm_c),
+            domain_commitment(p_aux.comm_r_last)
+        )?,
+        comm_r
+    );
+
     if skip_proof {
         clear_cache::<Tree>(cache_dir.path())?;
     } else {
@@ -2007,6 +3379,38 @@ fn create_seal_for_upgrade<R: Rng, Tree: 'static + MerkleTreeTrait<Hasher = Tree
     )?;
     ensure!(valid, "Compound proof failed to verify");
 
+    // Proving against a comm_r_old that doesn't match the sector key fails before proving.
+    let mismatched = generate_empty_sector_update_proof::<Tree>(
+        porep_config,
+        encoded.comm_r_new, /* not the sector key's comm_r */
+        encoded.comm_r_new,
+        encoded.comm_d_new,
+        sealed_sector_file.path(),
+        cache_dir.path(),
+        new_sealed_sector_file.path(),
+        new_cache_dir.path(),
+    );
+    ensure!(
+        mismatched.is_err(),
+        "Proving with a mismatched comm_r_old should fail"
+    );
+
+    // So does proving with a sector key which is not the replica comm_r_old commits to.
+    let mismatched = generate_empty_sector_update_proof::<Tree>(
+        porep_config,
+        comm_r,
+        encoded.comm_r_new,
+        encoded.comm_d_new,
+        new_sealed_sector_file.path(), /* not the sector key */
+        cache_dir.path(),
+        new_sealed_sector_file.path(),
+        new_cache_dir.path(),
+    );
+    ensure!(
+        mismatched.is_err(),
+        "Proving with a mismatched sector key should fail"
+    );
+
     let decoded_sector_file = NamedTempFile::new()?;
     // New replica (new_sealed_sector_file) is currently 0 bytes --
     // set a length here to ensure proper mmap later.  Lotus will
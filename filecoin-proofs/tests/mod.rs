@@ -3,8 +3,9 @@ use std::panic::panic_any;
 use blstrs::Scalar as Fr;
 use ff::Field;
 use filecoin_proofs::{
-    as_safe_commitment, verify_seal, DefaultOctLCTree, DefaultTreeDomain, PoRepConfig,
-    PoRepProofPartitions, SectorSize, POREP_PARTITIONS, SECTOR_SIZE_2_KIB, TEST_SEED,
+    as_safe_commitment, validate_public_inputs, verify_seal, DefaultOctLCTree, DefaultTreeDomain,
+    PoRepConfig, PoRepProofPartitions, SealPublicInputs, SectorSize, POREP_PARTITIONS,
+    SECTOR_SIZE_2_KIB, TEST_SEED,
 };
 use fr32::bytes_into_fr;
 use rand::SeedableRng;
@@ -145,6 +146,77 @@ fn test_verify_seal_fr32_validation() {
     }
 }
 
+#[test]
+fn test_validate_public_inputs() {
+    let porep_config = PoRepConfig {
+        sector_size: SectorSize(SECTOR_SIZE_2_KIB),
+        partitions: PoRepProofPartitions(
+            *POREP_PARTITIONS
+                .read()
+                .expect("POREP_PARTITIONS poisoned")
+                .get(&SECTOR_SIZE_2_KIB)
+                .expect("unknown sector size"),
+        ),
+        porep_id: [87; 32],
+        api_version: ApiVersion::V1_1_0,
+    };
+    let pub_inputs = SealPublicInputs {
+        comm_r: [1; 32],
+        comm_d: [2; 32],
+        prover_id: [0; 32],
+        sector_id: SectorId::from(0),
+        ticket: [0; 32],
+        seed: [0; 32],
+    };
+    validate_public_inputs(&pub_inputs, porep_config).expect("valid public inputs");
+
+    let error = |pub_inputs: SealPublicInputs, porep_config: PoRepConfig| {
+        validate_public_inputs(&pub_inputs, porep_config)
+            .expect_err("invalid public inputs passed")
+            .to_string()
+    };
+    assert_eq!(
+        error(
+            SealPublicInputs {
+                comm_r: [0; 32],
+                ..pub_inputs
+            },
+            porep_config
+        ),
+        "Invalid all zero commitment (comm_r)"
+    );
+    assert_eq!(
+        error(
+            SealPublicInputs {
+                comm_d: [255; 32],
+                ..pub_inputs
+            },
+            porep_config
+        ),
+        "Invalid commitment (comm_d)"
+    );
+    assert_eq!(
+        error(
+            pub_inputs,
+            PoRepConfig {
+                sector_size: SectorSize(3 * SECTOR_SIZE_2_KIB),
+                ..porep_config
+            }
+        ),
+        format!("Unsupported sector size {}", 3 * SECTOR_SIZE_2_KIB)
+    );
+    assert_eq!(
+        error(
+            pub_inputs,
+            PoRepConfig {
+                partitions: PoRepProofPartitions(0),
+                ..porep_config
+            }
+        ),
+        "Invalid partition count 0"
+    );
+}
+
 #[test]
 fn test_random_domain_element() {
     let mut rng = XorShiftRng::from_seed(TEST_SEED);
@@ -1,13 +1,16 @@
-use std::io::{Cursor, Read};
+use std::convert::TryFrom;
+use std::io::{sink, Cursor, Read};
 use std::iter::Iterator;
 
 use anyhow::Result;
 use blstrs::Scalar as Fr;
+use cid::{multihash::Multihash, Cid};
 use filecoin_proofs::{
-    add_piece, commitment_from_fr,
+    add_piece, add_piece_streaming, commitment_from_fr,
     pieces::{
-        compute_comm_d, get_piece_alignment, get_piece_start_byte, piece_hash, verify_pieces,
-        zero_padding, EmptySource, PieceAlignment,
+        comm_to_piece_cid, compute_comm_d, get_piece_alignment, get_piece_start_byte,
+        piece_cid_to_comm, piece_hash, validate_piece_layout, verify_pieces, zero_padding,
+        EmptySource, PieceAlignment, FIL_COMMITMENT_UNSEALED, SHA2_256_TRUNC254_PADDED,
     },
     Commitment, DataTree, DefaultPieceHasher, PaddedBytesAmount, PieceInfo, SectorSize,
     UnpaddedByteIndex, UnpaddedBytesAmount, DRG_DEGREE, EXP_DEGREE, TEST_SEED,
@@ -29,6 +32,90 @@ fn test_empty_source() {
     assert_eq!(target, vec![0u8; 12]);
 }
 
+#[test]
+fn test_add_piece_streaming() -> Result<()> {
+    let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+    let piece_size = UnpaddedBytesAmount(1016);
+    let piece_lengths = [UnpaddedBytesAmount(127)];
+    let mut payload = vec![0u8; 500];
+    rng.fill_bytes(&mut payload);
+
+    // A slice is read without seeking, and its length isn't passed on.
+    let mut streamed = Vec::new();
+    let (piece_info, written, read) =
+        add_piece_streaming(&payload[..], &mut streamed, piece_size, &piece_lengths)?;
+    assert_eq!(read, UnpaddedBytesAmount(500));
+
+    let mut padded_payload = payload.clone();
+    padded_payload.resize(1016, 0);
+    let mut expected = Vec::new();
+    let expected_result = add_piece(
+        Cursor::new(&padded_payload),
+        &mut expected,
+        piece_size,
+        &piece_lengths,
+    )?;
+    assert_eq!((piece_info, written), expected_result);
+    assert_eq!(streamed, expected);
+
+    // The whole piece may come from the source.
+    padded_payload[1015] = 1;
+    let (_, _, read) =
+        add_piece_streaming(&padded_payload[..], sink(), piece_size, &piece_lengths)?;
+    assert_eq!(read, piece_size);
+
+    padded_payload.push(0);
+    assert!(add_piece_streaming(&padded_payload[..], sink(), piece_size, &piece_lengths).is_err());
+    assert!(add_piece_streaming(&[0u8; 0][..], sink(), piece_size, &piece_lengths).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_validate_piece_layout() {
+    let piece = |padded: u64| PieceInfo {
+        commitment: [0; 32],
+        size: UnpaddedBytesAmount::from(PaddedBytesAmount(padded)),
+    };
+    let sector_size = SectorSize(2048);
+
+    assert!(validate_piece_layout(&[], sector_size).is_ok());
+    assert!(validate_piece_layout(&[piece(2048)], sector_size).is_ok());
+    assert!(validate_piece_layout(
+        &[piece(128), piece(128), piece(256), piece(512), piece(1024)],
+        sector_size
+    )
+    .is_ok());
+
+    let invalid_piece = |pieces: &[PieceInfo]| {
+        validate_piece_layout(pieces, sector_size)
+            .expect_err("invalid layout was accepted")
+            .downcast::<storage_proofs_core::error::Error>()
+            .map(|err| match err {
+                storage_proofs_core::error::Error::InvalidPieceLayout { index, .. } => index,
+                err => panic!("unexpected error {}", err),
+            })
+            .expect("not a piece layout error")
+    };
+    // Not a power of two.
+    assert_eq!(invalid_piece(&[piece(128), piece(384)]), 1);
+    // Smaller than the minimum piece size.
+    assert_eq!(invalid_piece(&[piece(64)]), 0);
+    // Not a multiple of the unpadded minimum piece size.
+    let unaligned = PieceInfo {
+        commitment: [0; 32],
+        size: UnpaddedBytesAmount(100),
+    };
+    assert_eq!(invalid_piece(&[piece(128), unaligned]), 1);
+    // The 1024 byte piece is placed at offset 1024, which leaves no room for the last piece.
+    assert_eq!(invalid_piece(&[piece(128), piece(1024), piece(128)]), 2);
+    // The 512 byte piece is placed at offset 512, so the 1024 byte piece would start at 2048.
+    assert_eq!(
+        invalid_piece(&[piece(128), piece(512), piece(128), piece(1024)]),
+        3
+    );
+}
+
 #[test]
 fn test_compute_comm_d_empty() {
     let comm_d =
@@ -413,6 +500,43 @@ fn build_sector(
     Ok((comm_d, piece_infos))
 }
 
+#[test]
+fn test_piece_cid_roundtrip() -> Result<()> {
+    let comm_d = compute_comm_d(SectorSize(2048), &[])?;
+
+    let cid = comm_to_piece_cid(&comm_d)?;
+    let bytes = cid.to_bytes();
+    // CIDv1, varint fil-commitment-unsealed, varint sha2-256-trunc254-padded, 32 byte digest.
+    assert_eq!(&bytes[..7], &[0x01, 0x81, 0xe2, 0x03, 0x92, 0x20, 0x20]);
+    assert_eq!(&bytes[7..], &comm_d[..]);
+
+    assert_eq!(piece_cid_to_comm(&cid)?, comm_d);
+    assert_eq!(piece_cid_to_comm(&Cid::try_from(&bytes[..])?)?, comm_d);
+
+    Ok(())
+}
+
+#[test]
+fn test_piece_cid_rejects_mismatches() -> Result<()> {
+    let comm = [7u8; 32];
+
+    // fil-commitment-sealed is used for comm_r, not for pieces.
+    let sealed = Cid::new_v1(0xf102, Multihash::wrap(SHA2_256_TRUNC254_PADDED, &comm)?);
+    assert!(piece_cid_to_comm(&sealed).is_err());
+
+    // Plain sha2-256.
+    let sha256 = Cid::new_v1(FIL_COMMITMENT_UNSEALED, Multihash::wrap(0x12, &comm)?);
+    assert!(piece_cid_to_comm(&sha256).is_err());
+
+    let short = Cid::new_v1(
+        FIL_COMMITMENT_UNSEALED,
+        Multihash::wrap(SHA2_256_TRUNC254_PADDED, &comm[..31])?,
+    );
+    assert!(piece_cid_to_comm(&short).is_err());
+
+    Ok(())
+}
+
 fn prev_power_of_two(mut x: u32) -> u32 {
     x |= x >> 1;
     x |= x >> 2;
@@ -10,19 +10,19 @@ use byte_slice_cast::AsByteSlice;
 /// The number of Frs per Block.
 const NUM_FRS_PER_BLOCK: usize = 4;
 /// The amount of bits in an Fr when not padded.
-const IN_BITS_FR: usize = 254;
+pub(crate) const IN_BITS_FR: usize = 254;
 /// The amount of bits in an Fr when padded.
-const OUT_BITS_FR: usize = 256;
+pub(crate) const OUT_BITS_FR: usize = 256;
 
-const NUM_BYTES_IN_BLOCK: usize = NUM_FRS_PER_BLOCK * IN_BITS_FR / 8;
-const NUM_BYTES_OUT_BLOCK: usize = NUM_FRS_PER_BLOCK * OUT_BITS_FR / 8;
+pub(crate) const NUM_BYTES_IN_BLOCK: usize = NUM_FRS_PER_BLOCK * IN_BITS_FR / 8;
+pub(crate) const NUM_BYTES_OUT_BLOCK: usize = NUM_FRS_PER_BLOCK * OUT_BITS_FR / 8;
 
-const NUM_U128S_PER_BLOCK: usize = NUM_BYTES_OUT_BLOCK / size_of::<u128>();
+pub(crate) const NUM_U128S_PER_BLOCK: usize = NUM_BYTES_OUT_BLOCK / size_of::<u128>();
 
 const MASK_SKIP_HIGH_2: u128 = 0b0011_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111;
 
 #[repr(align(16))]
-struct AlignedBuffer([u8; NUM_BYTES_IN_BLOCK + 1]);
+pub(crate) struct AlignedBuffer(pub(crate) [u8; NUM_BYTES_IN_BLOCK + 1]);
 
 /// An `io::Reader` that converts unpadded input into valid `Fr32` padded output.
 pub struct Fr32Reader<R> {
@@ -71,30 +71,7 @@ impl<R: Read> Fr32Reader<R> {
 
     /// Processes a single block in in_buffer, writing the result to out_buffer.
     fn process_block(&mut self) {
-        let in_buffer: &[u128] = {
-            #[cfg(target_arch = "aarch64")]
-            // Safety: This is safe because the struct/data is aligned on
-            // a 16 byte boundary and can therefore be casted from u128
-            // to u8 without alignment safety issues.
-            unsafe {
-                &mut (*(&self.in_buffer.0 as *const [u8] as *mut [u128]))
-            }
-            #[cfg(not(target_arch = "aarch64"))]
-            self.in_buffer.0.as_slice_of::<u128>().unwrap()
-        };
-        let out = &mut self.out_buffer;
-
-        // 0..254
-        {
-            out[0] = in_buffer[0];
-            out[1] = in_buffer[1] & MASK_SKIP_HIGH_2;
-        }
-        // 254..508
-        process_fr!(&in_buffer[1..], out[2], out[3], 2);
-        // 508..762
-        process_fr!(&in_buffer[3..], out[4], out[5], 4);
-        // 762..1016
-        process_fr!(&in_buffer[5..], out[6], out[7], 6);
+        pad_block(&self.in_buffer, &mut self.out_buffer);
 
         // Reset buffer offset.
         self.out_offset = 0;
@@ -127,10 +104,38 @@ impl<R: Read> Fr32Reader<R> {
     }
 }
 
+/// Pads a single block of `NUM_BYTES_IN_BLOCK` unpadded bytes in `in_buffer` into
+/// `NUM_BYTES_OUT_BLOCK` padded bytes in `out`.
+pub(crate) fn pad_block(in_buffer: &AlignedBuffer, out: &mut [u128; NUM_U128S_PER_BLOCK]) {
+    let in_buffer: &[u128] = {
+        #[cfg(target_arch = "aarch64")]
+        // Safety: This is safe because the struct/data is aligned on
+        // a 16 byte boundary and can therefore be casted from u128
+        // to u8 without alignment safety issues.
+        unsafe {
+            &*(&in_buffer.0 as *const [u8] as *const [u128])
+        }
+        #[cfg(not(target_arch = "aarch64"))]
+        in_buffer.0.as_slice_of::<u128>().unwrap()
+    };
+
+    // 0..254
+    {
+        out[0] = in_buffer[0];
+        out[1] = in_buffer[1] & MASK_SKIP_HIGH_2;
+    }
+    // 254..508
+    process_fr!(&in_buffer[1..], out[2], out[3], 2);
+    // 508..762
+    process_fr!(&in_buffer[3..], out[4], out[5], 4);
+    // 762..1016
+    process_fr!(&in_buffer[5..], out[6], out[7], 6);
+}
+
 /// Division of x by y, rounding up.
 /// x must be > 0
 #[inline]
-const fn div_ceil(x: usize, y: usize) -> usize {
+pub(crate) const fn div_ceil(x: usize, y: usize) -> usize {
     1 + ((x - 1) / y)
 }
 
@@ -0,0 +1,202 @@
+use std::io::{self, Write};
+
+use byte_slice_cast::AsByteSlice;
+
+use crate::reader::{
+    div_ceil, pad_block, AlignedBuffer, IN_BITS_FR, NUM_BYTES_IN_BLOCK, NUM_BYTES_OUT_BLOCK,
+    NUM_U128S_PER_BLOCK, OUT_BITS_FR,
+};
+
+/// An `io::Writer` that converts unpadded input into valid `Fr32` padded output, writing it to
+/// the wrapped `target`.
+///
+/// This is the write-side counterpart of [`Fr32Reader`](crate::Fr32Reader) and produces exactly
+/// the same bytes for the same input.
+///
+/// Byte accounting: every full block of 127 unpadded bytes written results in 128 padded bytes
+/// written to `target`. A trailing partial block of `n` bytes is only padded and written by
+/// [`Fr32Writer::finish`], and results in `ceil(n * 8 / 254) * 32` padded bytes. Dropping the
+/// writer without calling `finish` discards any trailing partial block.
+pub struct Fr32Writer<W: Write> {
+    /// The target receiving the padded bytes.
+    target: W,
+    /// Currently accumulated block.
+    /// This is padded to 128 bytes to allow reading all values as `u128`s, but only the first
+    /// 127 bytes are ever valid.
+    in_buffer: AlignedBuffer,
+    /// The number of valid bytes in `in_buffer`.
+    in_len: usize,
+    /// Currently writing out block.
+    out_buffer: [u128; NUM_U128S_PER_BLOCK],
+    /// The number of unpadded bytes accepted so far.
+    unpadded_bytes: u64,
+    /// The number of padded bytes written to `target` so far.
+    padded_bytes: u64,
+}
+
+impl<W: Write> Fr32Writer<W> {
+    pub fn new(target: W) -> Self {
+        Fr32Writer {
+            target,
+            in_buffer: AlignedBuffer([0; NUM_BYTES_IN_BLOCK + 1]),
+            in_len: 0,
+            out_buffer: [0; NUM_U128S_PER_BLOCK],
+            unpadded_bytes: 0,
+            padded_bytes: 0,
+        }
+    }
+
+    /// Returns the number of unpadded bytes accepted so far.
+    pub fn unpadded_bytes(&self) -> u64 {
+        self.unpadded_bytes
+    }
+
+    /// Returns the number of padded bytes written to the target so far. This does not include
+    /// the bytes of a trailing partial block, which are only written by `finish`.
+    pub fn padded_bytes(&self) -> u64 {
+        self.padded_bytes
+    }
+
+    /// Pads and writes out the trailing partial block (if any), flushes the target and returns
+    /// it.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.in_len > 0 {
+            let frs = div_ceil(self.in_len * 8, IN_BITS_FR);
+            self.write_block(frs * (OUT_BITS_FR / 8))?;
+        }
+        self.target.flush()?;
+
+        Ok(self.target)
+    }
+
+    /// Pads the current block and writes the first `len` padded bytes to the target.
+    fn write_block(&mut self, len: usize) -> io::Result<()> {
+        // Clear unfilled memory.
+        for val in &mut self.in_buffer.0[self.in_len..NUM_BYTES_IN_BLOCK] {
+            *val = 0;
+        }
+
+        pad_block(&self.in_buffer, &mut self.out_buffer);
+        self.target
+            .write_all(&self.out_buffer.as_byte_slice()[..len])?;
+
+        self.padded_bytes += len as u64;
+        self.in_len = 0;
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Fr32Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+
+        while written < buf.len() {
+            let len = (NUM_BYTES_IN_BLOCK - self.in_len).min(buf.len() - written);
+            self.in_buffer.0[self.in_len..self.in_len + len]
+                .copy_from_slice(&buf[written..written + len]);
+            self.in_len += len;
+            written += len;
+
+            if self.in_len == NUM_BYTES_IN_BLOCK {
+                self.write_block(NUM_BYTES_OUT_BLOCK)?;
+            }
+        }
+        self.unpadded_bytes += written as u64;
+
+        Ok(written)
+    }
+
+    /// Flushes the target. A trailing partial block is not written, as its padding depends on
+    /// whether more data follows; use `finish` for that.
+    fn flush(&mut self) -> io::Result<()> {
+        self.target.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Cursor, Read};
+
+    use pretty_assertions::assert_eq;
+    use rand::{Rng, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+
+    use crate::{to_padded_bytes, Fr32Reader};
+
+    const TEST_SEED: [u8; 16] = [
+        0x59, 0x62, 0xbe, 0x5d, 0x76, 0x3d, 0x31, 0x8d, 0x17, 0xdb, 0x37, 0x32, 0x54, 0x06, 0xbc,
+        0xe5,
+    ];
+
+    fn pad_with_reader(data: &[u8]) -> Vec<u8> {
+        let mut padded = Vec::new();
+        Fr32Reader::new(Cursor::new(data))
+            .read_to_end(&mut padded)
+            .expect("in-memory read failed");
+        padded
+    }
+
+    #[test]
+    fn test_writer_matches_reader() {
+        let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+
+        for len in (1usize..=3 * 127 + 5).chain(vec![1016, 1017, 2032, 4064 + 31]) {
+            let data: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+
+            let mut writer = Fr32Writer::new(Vec::new());
+            writer.write_all(&data).expect("in-memory write failed");
+            assert_eq!(writer.unpadded_bytes(), len as u64);
+            assert_eq!(writer.padded_bytes(), (len / 127 * 128) as u64);
+
+            let padded = writer.finish().expect("finish failed");
+            assert_eq!(padded, pad_with_reader(&data), "len {}", len);
+        }
+    }
+
+    #[test]
+    fn test_writer_chunked_writes() {
+        let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+        let data: Vec<u8> = (0..1000).map(|_| rng.gen()).collect();
+        let expected = pad_with_reader(&data);
+
+        for chunk_size in &[1, 3, 31, 126, 127, 128, 500] {
+            let mut writer = Fr32Writer::new(Vec::new());
+            for chunk in data.chunks(*chunk_size) {
+                writer.write_all(chunk).expect("in-memory write failed");
+            }
+            let padded = writer.finish().expect("finish failed");
+            assert_eq!(padded, expected, "chunk size {}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn test_writer_byte_accounting() {
+        // Full blocks map 127 -> 128 bytes.
+        for blocks in 1..4 {
+            let data = vec![0xffu8; blocks * 127];
+            let mut writer = Fr32Writer::new(Vec::new());
+            writer.write_all(&data).expect("in-memory write failed");
+            let padded = writer.finish().expect("finish failed");
+            assert_eq!(padded.len(), blocks * 128);
+            assert_eq!(padded.len(), to_padded_bytes(data.len()));
+        }
+
+        // A trailing partial block is rounded up to whole Fr32s.
+        for (len, expected) in &[(1, 32), (31, 32), (32, 64), (63, 64), (64, 96), (96, 128)] {
+            let mut writer = Fr32Writer::new(Vec::new());
+            writer
+                .write_all(&vec![0xffu8; *len])
+                .expect("in-memory write failed");
+            assert_eq!(writer.padded_bytes(), 0);
+            let padded = writer.finish().expect("finish failed");
+            assert_eq!(padded.len(), *expected, "len {}", len);
+        }
+
+        // Empty input produces no output.
+        let writer = Fr32Writer::new(Vec::new());
+        assert!(writer.finish().expect("finish failed").is_empty());
+    }
+}
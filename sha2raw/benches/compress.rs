@@ -0,0 +1,33 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use rand::{RngCore, SeedableRng};
+use rand_xorshift::XorShiftRng;
+use sha2raw::Implementation;
+
+/// About as many blocks as are compressed for a label: the replica id and node, and the parents.
+const BLOCKS: usize = 20;
+
+fn compress_benchmark(c: &mut Criterion) {
+    let rng = &mut XorShiftRng::from_seed([
+        0x59, 0x62, 0xbe, 0x5d, 0x76, 0x3d, 0x31, 0x8d, 0x17, 0xdb, 0x37, 0x32, 0x54, 0x06, 0xbc,
+        0xe5,
+    ]);
+    let mut data = vec![0u8; 64 * BLOCKS];
+    rng.fill_bytes(&mut data);
+
+    let mut group = c.benchmark_group("compress256");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    // Only the implementations the CPU supports are measured, the speedup is their throughput
+    // relative to `portable`.
+    for name in &["portable", "asm", "sha", "avx512"] {
+        if let Some(implementation) = Implementation::from_name(name) {
+            group.bench_function(*name, |b| {
+                let mut state = [0u32; 8];
+                b.iter(|| implementation.compress256_bytes(black_box(&mut state), black_box(&data)))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, compress_benchmark);
+criterion_main!(benches);
@@ -4,14 +4,18 @@
 
 #![deny(clippy::all, clippy::perf, clippy::correctness)]
 #![allow(clippy::unreadable_literal)]
+#![cfg_attr(feature = "avx512", feature(stdsimd, avx512_target_feature))]
 
 pub use digest::Digest;
 
 mod consts;
 mod platform;
 mod sha256;
+#[cfg(all(feature = "avx512", any(target_arch = "x86", target_arch = "x86_64")))]
+mod sha256_avx512;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 mod sha256_intrinsics;
 mod sha256_utils;
 
+pub use platform::{implementation, set_implementation, Implementation};
 pub use sha256::Sha256;
@@ -1,3 +1,7 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[cfg(all(feature = "avx512", any(target_arch = "x86", target_arch = "x86_64")))]
+use crate::sha256_avx512;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use crate::sha256_intrinsics;
 use crate::sha256_utils;
@@ -10,20 +14,75 @@ enum Platform {
     Asm,
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     Sha,
+    #[cfg(all(feature = "avx512", any(target_arch = "x86", target_arch = "x86_64")))]
+    Avx512,
 }
 
-#[derive(Clone, Copy, Debug)]
+impl Platform {
+    fn to_u8(self) -> u8 {
+        match self {
+            Platform::Portable => 1,
+            #[cfg(feature = "asm")]
+            Platform::Asm => 2,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Platform::Sha => 3,
+            #[cfg(all(feature = "avx512", any(target_arch = "x86", target_arch = "x86_64")))]
+            Platform::Avx512 => 4,
+        }
+    }
+
+    fn from_u8(val: u8) -> Option<Self> {
+        match val {
+            1 => Some(Platform::Portable),
+            #[cfg(feature = "asm")]
+            2 => Some(Platform::Asm),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            3 => Some(Platform::Sha),
+            #[cfg(all(feature = "avx512", any(target_arch = "x86", target_arch = "x86_64")))]
+            4 => Some(Platform::Avx512),
+            _ => None,
+        }
+    }
+}
+
+/// The SHA-256 compression function implementation in use.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct Implementation(Platform);
 
+/// The process wide implementation, `UNSELECTED` until first use or `set_implementation`.
+static SELECTED: AtomicU8 = AtomicU8::new(UNSELECTED);
+const UNSELECTED: u8 = 0;
+
+/// Returns the implementation used by [`Sha256`](crate::Sha256). Unless one was set with
+/// [`set_implementation`], this is the fastest implementation supported by the running CPU.
+#[inline]
+pub fn implementation() -> Implementation {
+    if let Some(platform) = Platform::from_u8(SELECTED.load(Ordering::Relaxed)) {
+        return Implementation(platform);
+    }
+
+    let detected = Implementation::detect();
+    SELECTED.store(detected.0.to_u8(), Ordering::Relaxed);
+    detected
+}
+
+/// Forces the implementation used by [`Sha256`](crate::Sha256) for the rest of the process,
+/// e.g. to get reproducible benchmarks.
+pub fn set_implementation(implementation: Implementation) {
+    SELECTED.store(implementation.0.to_u8(), Ordering::Relaxed);
+}
+
 impl Implementation {
     pub fn detect() -> Self {
         // Try the different implementations in order of how fast/modern they are.
-        #[cfg(target_arch = "x86_64")]
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
         {
             if let Some(sha_impl) = Self::sha_if_supported() {
                 return sha_impl;
             }
         }
+        // The AVX-512 implementation is not benchmarked against the others yet, so it is never
+        // detected, only used if forced with `from_name`.
         #[cfg(feature = "asm")]
         {
             if let Some(asm_impl) = Self::asm_if_supported() {
@@ -34,16 +93,44 @@ impl Implementation {
         Self::portable()
     }
 
+    /// Returns the implementation with the given name (`portable`, `asm`, `sha` or `avx512`), if it
+    /// is both compiled in and supported by the running CPU.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "portable" => Some(Self::portable()),
+            #[cfg(feature = "asm")]
+            "asm" => Self::asm_if_supported(),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            "sha" => Self::sha_if_supported(),
+            #[cfg(all(feature = "avx512", any(target_arch = "x86", target_arch = "x86_64")))]
+            "avx512" => Self::avx512_if_supported(),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of this implementation, as accepted by `from_name`.
+    pub fn name(self) -> &'static str {
+        match self.0 {
+            Platform::Portable => "portable",
+            #[cfg(feature = "asm")]
+            Platform::Asm => "asm",
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Platform::Sha => "sha",
+            #[cfg(all(feature = "avx512", any(target_arch = "x86", target_arch = "x86_64")))]
+            Platform::Avx512 => "avx512",
+        }
+    }
+
     pub fn portable() -> Self {
         Implementation(Platform::Portable)
     }
 
-    #[cfg(target_arch = "x86_64")]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     #[allow(unreachable_code)]
     pub fn sha_if_supported() -> Option<Self> {
         // Use raw_cpuid instead of is_x86_feature_detected, to ensure the check
         // never happens at compile time.
-        let is_runtime_ok = cpuid_bool::cpuid_bool!("sha");
+        let is_runtime_ok = cpuid_bool::cpuid_bool!("sha", "sse2", "ssse3", "sse4.1");
 
         #[cfg(target_feature = "sha")]
         {
@@ -60,8 +147,29 @@ impl Implementation {
         None
     }
 
+    /// Returns the AVX-512 implementation, if the CPU supports AVX-512F and AVX-512VL. It is not
+    /// picked by `detect`.
+    #[cfg(all(feature = "avx512", any(target_arch = "x86", target_arch = "x86_64")))]
+    pub fn avx512_if_supported() -> Option<Self> {
+        // Unlike `cpuid`, this also checks that the OS saves the AVX-512 registers.
+        if is_x86_feature_detected!("avx512f") && is_x86_feature_detected!("avx512vl") {
+            return Some(Implementation(Platform::Avx512));
+        }
+
+        None
+    }
+
+    /// Returns the implementation of `sha2-asm`. On aarch64, it uses the SHA2 instructions of the
+    /// ARMv8 crypto extensions, so it is only returned if the CPU has them.
     #[cfg(feature = "asm")]
     pub fn asm_if_supported() -> Option<Self> {
+        #[cfg(target_arch = "aarch64")]
+        {
+            if !aarch64_sha2_supported() {
+                return None;
+            }
+        }
+
         Some(Implementation(Platform::Asm))
     }
 
@@ -75,6 +183,10 @@ impl Implementation {
             Platform::Sha => {
                 unsafe { sha256_intrinsics::compress256(state, blocks) };
             }
+            #[cfg(all(feature = "avx512", any(target_arch = "x86", target_arch = "x86_64")))]
+            Platform::Avx512 => {
+                unsafe { sha256_avx512::compress256(state, blocks) };
+            }
             #[cfg(feature = "asm")]
             Platform::Asm => {
                 let mut buffer = [0u8; 64];
@@ -86,4 +198,88 @@ impl Implementation {
             }
         }
     }
+
+    /// Like `compress256`, but takes the blocks as one contiguous slice, whose length must be a
+    /// multiple of 64 bytes.
+    #[inline]
+    pub fn compress256_bytes(self, state: &mut [u32; 8], data: &[u8]) {
+        const MAX_BLOCKS: usize = 16;
+        assert_eq!(data.len() % 64, 0, "invalid data length");
+
+        let mut halves: [&[u8]; 2 * MAX_BLOCKS] = [&data[..0]; 2 * MAX_BLOCKS];
+        for chunk in data.chunks(64 * MAX_BLOCKS) {
+            let count = chunk.len() / 32;
+            for (half, bytes) in halves.iter_mut().zip(chunk.chunks(32)) {
+                *half = bytes;
+            }
+            self.compress256(state, &halves[..count]);
+        }
+    }
+}
+
+/// Returns whether the CPU has the SHA2 instructions, as the kernel reports them.
+#[cfg(all(feature = "asm", target_arch = "aarch64", target_os = "linux"))]
+fn aarch64_sha2_supported() -> bool {
+    unsafe { libc::getauxval(libc::AT_HWCAP) & libc::HWCAP_SHA2 != 0 }
+}
+
+/// Every aarch64 CPU of a Mac has the SHA2 instructions.
+#[cfg(all(feature = "asm", target_arch = "aarch64", target_os = "macos"))]
+fn aarch64_sha2_supported() -> bool {
+    true
+}
+
+/// Without a way to detect them, the SHA2 instructions are not used.
+#[cfg(all(
+    feature = "asm",
+    target_arch = "aarch64",
+    not(any(target_os = "linux", target_os = "macos"))
+))]
+fn aarch64_sha2_supported() -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{RngCore, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+
+    use crate::consts::H256;
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(
+            Implementation::from_name("portable"),
+            Some(Implementation::portable())
+        );
+        assert_eq!(Implementation::from_name("avx9000"), None);
+
+        let detected = Implementation::detect();
+        assert_eq!(Implementation::from_name(detected.name()), Some(detected));
+        assert_ne!(detected.name(), "avx512");
+    }
+
+    #[test]
+    fn test_implementations_agree() {
+        let rng = &mut XorShiftRng::from_seed([
+            0x59, 0x62, 0xbe, 0x5d, 0x76, 0x3d, 0x31, 0x8d, 0x17, 0xdb, 0x37, 0x32, 0x54, 0x06,
+            0xbc, 0xe5,
+        ]);
+        let mut data = vec![0u8; 64 * 20];
+        rng.fill_bytes(&mut data);
+
+        let mut expected = H256;
+        let halves = data.chunks(32).collect::<Vec<_>>();
+        Implementation::portable().compress256(&mut expected, &halves);
+
+        for name in &["portable", "asm", "sha", "avx512"] {
+            if let Some(implementation) = Implementation::from_name(name) {
+                let mut state = H256;
+                implementation.compress256_bytes(&mut state, &data);
+                assert_eq!(state, expected, "{}", name);
+            }
+        }
+    }
 }
@@ -1,11 +1,6 @@
 use byteorder::{ByteOrder, BE};
-use lazy_static::lazy_static;
 
-use crate::{consts::H256, platform::Implementation};
-
-lazy_static! {
-    static ref IMPL: Implementation = Implementation::detect();
-}
+use crate::{consts::H256, platform::implementation};
 
 #[derive(Clone)]
 pub struct Sha256 {
@@ -38,7 +33,7 @@ impl Sha256 {
 
         self.len += (blocks.len() as u64) << 8;
 
-        IMPL.compress256(&mut self.state, blocks);
+        implementation().compress256(&mut self.state, blocks);
     }
 
     pub fn finish(mut self) -> [u8; 32] {
@@ -52,7 +47,7 @@ impl Sha256 {
         let l = self.len;
         block1[32 - 8..].copy_from_slice(&l.to_be_bytes()[..]);
 
-        IMPL.compress256(&mut self.state, &[&block0[..], &block1[..]][..]);
+        implementation().compress256(&mut self.state, &[&block0[..], &block1[..]][..]);
 
         let mut out = [0u8; 32];
         BE::write_u32_into(&self.state, &mut out);
@@ -71,7 +66,7 @@ impl Sha256 {
         let l = self.len + 256;
         block1[32 - 8..].copy_from_slice(&l.to_be_bytes()[..]);
 
-        IMPL.compress256(&mut self.state, &[block0, &block1[..]][..]);
+        implementation().compress256(&mut self.state, &[block0, &block1[..]][..]);
 
         let mut out = [0u8; 32];
         BE::write_u32_into(&self.state, &mut out);
@@ -0,0 +1,130 @@
+#![allow(clippy::many_single_char_names)]
+#![allow(clippy::cast_ptr_alignment)] // Safe to cast without alignment checks as the loads and stores do not require alignment.
+
+#[cfg(target_arch = "x86")]
+use std::arch::x86;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64 as x86;
+
+use x86::{
+    __m128i, _mm_add_epi32, _mm_alignr_epi8, _mm_loadu_si128, _mm_mask_add_epi32, _mm_ror_epi32,
+    _mm_set_epi64x, _mm_shuffle_epi32, _mm_shuffle_epi8, _mm_srli_epi32, _mm_storeu_si128,
+    _mm_ternarylogic_epi32,
+};
+
+use crate::consts::K32;
+
+/// `a ^ b ^ c` as a `vpternlogd` truth table.
+const XOR3: i32 = 0x96;
+
+/// Computes the next 4 words of the message schedule from the previous 16, `w0` being the oldest.
+///
+/// The rotates of the sigma functions are single AVX-512VL instructions. As the last two of the
+/// new words depend on the first two, sigma 1 is applied in two masked steps.
+#[inline]
+#[target_feature(enable = "avx512f,avx512vl")]
+unsafe fn schedule(w0: __m128i, w1: __m128i, w2: __m128i, w3: __m128i) -> __m128i {
+    // w[t - 15], w[t - 7]
+    let w15 = _mm_alignr_epi8(w1, w0, 4);
+    let w7 = _mm_alignr_epi8(w3, w2, 4);
+
+    let sigma0 = _mm_ternarylogic_epi32(
+        _mm_ror_epi32(w15, 7),
+        _mm_ror_epi32(w15, 18),
+        _mm_srli_epi32(w15, 3),
+        XOR3,
+    );
+    let sum = _mm_add_epi32(_mm_add_epi32(w0, w7), sigma0);
+
+    // w[t - 2] and w[t - 1] for the first two words, ...
+    let w2 = _mm_shuffle_epi32(w3, 0xEE);
+    let sum = _mm_mask_add_epi32(sum, 0b0011, sum, sigma1(w2));
+    // ... and the two new ones for the last two.
+    let w2 = _mm_shuffle_epi32(sum, 0x44);
+    _mm_mask_add_epi32(sum, 0b1100, sum, sigma1(w2))
+}
+
+#[inline]
+#[target_feature(enable = "avx512f,avx512vl")]
+unsafe fn sigma1(x: __m128i) -> __m128i {
+    _mm_ternarylogic_epi32(
+        _mm_ror_epi32(x, 17),
+        _mm_ror_epi32(x, 19),
+        _mm_srli_epi32(x, 10),
+        XOR3,
+    )
+}
+
+/// Process a block with the SHA-256 algorithm.
+///
+/// The message schedule is computed 4 words at a time with AVX-512VL, the rounds are scalar. This
+/// is meant for CPUs with AVX-512 but without the SHA extensions. As it is not benchmarked against
+/// the other implementations yet, `Implementation::detect` never picks it.
+///
+/// AVX-512 is enabled for this function only, so that the intrinsics are inlined into it even if
+/// the crate is not compiled for a CPU with them. It must only be called once it was detected at
+/// runtime, see `Implementation::avx512_if_supported`.
+#[target_feature(enable = "avx512f,avx512vl")]
+pub unsafe fn compress256(state: &mut [u32; 8], blocks: &[&[u8]]) {
+    assert_eq!(blocks.len() % 2, 0);
+
+    #[allow(non_snake_case)]
+    let MASK: __m128i = _mm_set_epi64x(
+        0x0c0d_0e0f_0809_0a0bu64 as i64,
+        0x0405_0607_0001_0203u64 as i64,
+    );
+
+    // The message schedule words, with the round constants added.
+    let mut wk = [0u32; 64];
+
+    for block in blocks.chunks(2) {
+        let mut w = [
+            _mm_shuffle_epi8(_mm_loadu_si128(block[0].as_ptr() as *const __m128i), MASK),
+            _mm_shuffle_epi8(
+                _mm_loadu_si128(block[0].as_ptr().add(16) as *const __m128i),
+                MASK,
+            ),
+            _mm_shuffle_epi8(_mm_loadu_si128(block[1].as_ptr() as *const __m128i), MASK),
+            _mm_shuffle_epi8(
+                _mm_loadu_si128(block[1].as_ptr().add(16) as *const __m128i),
+                MASK,
+            ),
+        ];
+
+        for t in (0..64).step_by(4) {
+            if t >= 16 {
+                let next = schedule(w[0], w[1], w[2], w[3]);
+                w = [w[1], w[2], w[3], next];
+            }
+            let k = _mm_loadu_si128(K32.as_ptr().add(t) as *const __m128i);
+            let i = (t / 4).min(3);
+            _mm_storeu_si128(
+                wk.as_mut_ptr().add(t) as *mut __m128i,
+                _mm_add_epi32(w[i], k),
+            );
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+        for wk in wk.iter() {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(*wk);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *s = s.wrapping_add(*v);
+        }
+    }
+}
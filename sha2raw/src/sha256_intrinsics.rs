@@ -14,7 +14,11 @@ use x86::{
 
 /// Process a block with the SHA-256 algorithm.
 /// Based on https://github.com/noloader/SHA-Intrinsics/blob/master/sha256-x86.c
-#[inline(always)]
+///
+/// The SHA extensions are enabled for this function only, so that the intrinsics are inlined
+/// into it even if the crate is not compiled for a CPU with them. It must only be called once
+/// they were detected at runtime, see `Implementation::sha_if_supported`.
+#[target_feature(enable = "sha,sse2,ssse3,sse4.1")]
 pub unsafe fn compress256(state: &mut [u32; 8], blocks: &[&[u8]]) {
     assert_eq!(blocks.len() % 2, 0);
 
@@ -7,6 +7,8 @@ pub enum CacheKey {
     CommDTree,
     CommCTree,
     CommRLastTree,
+    /// All label layers, once consolidated into one file.
+    LabelLayers,
 }
 
 impl Display for CacheKey {
@@ -17,6 +19,7 @@ impl Display for CacheKey {
             CacheKey::CommDTree => write!(f, "tree-d"),
             CacheKey::CommCTree => write!(f, "tree-c"),
             CacheKey::CommRLastTree => write!(f, "tree-r-last"),
+            CacheKey::LabelLayers => write!(f, "layers"),
         }
     }
 }
@@ -169,6 +169,47 @@ where
         Ok(res)
     }
 
+    /// Verifies only the circuit proof of partition `k` of `multi_proof`. A multi-proof is valid
+    /// exactly when each of its partitions verifies, so partitions may be checked independently.
+    fn verify_partition<'b>(
+        public_params: &PublicParams<'a, S>,
+        public_inputs: &S::PublicInputs,
+        multi_proof: &MultiProof<'b>,
+        requirements: &S::Requirements,
+        k: usize,
+    ) -> Result<bool> {
+        ensure!(
+            multi_proof.circuit_proofs.len() == Self::partition_count(public_params),
+            "Inconsistent inputs"
+        );
+        ensure!(
+            k < multi_proof.circuit_proofs.len(),
+            "partition index {} out of range for {} partitions",
+            k,
+            multi_proof.circuit_proofs.len()
+        );
+
+        let vanilla_public_params = &public_params.vanilla_params;
+        let pvk = &multi_proof.verifying_key;
+
+        if !<S as ProofScheme>::satisfies_requirements(
+            &public_params.vanilla_params,
+            requirements,
+            multi_proof.circuit_proofs.len(),
+        ) {
+            return Ok(false);
+        }
+
+        let inputs = Self::generate_public_inputs(public_inputs, vanilla_public_params, Some(k))?;
+        let res = verify_proofs_batch(
+            pvk,
+            &mut OsRng,
+            &[&multi_proof.circuit_proofs[k]],
+            &[inputs],
+        )?;
+        Ok(res)
+    }
+
     /// Efficiently verify multiple proofs.
     fn batch_verify<'b>(
         public_params: &PublicParams<'a, S>,
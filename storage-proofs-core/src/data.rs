@@ -175,6 +175,20 @@ impl<'a> Data<'a> {
         Ok(())
     }
 
+    /// Whether the data is mapped from a file, so that changes to it outlive the process.
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.raw, Some(RawData::Mmap(_)))
+    }
+
+    /// Flushes `len` bytes at `offset` to the file the data is mapped from, if it is.
+    pub fn flush_range(&self, offset: usize, len: usize) -> Result<()> {
+        if let Some(RawData::Mmap(raw)) = &self.raw {
+            raw.flush_range(offset, len)?;
+        }
+
+        Ok(())
+    }
+
     /// Drops the actual data, if we can recover it.
     pub fn drop_data(&mut self) -> Result<()> {
         if let Some(ref p) = self.path {
@@ -41,6 +41,12 @@ pub enum Error {
     FaultySectors(Vec<SectorId>),
     #[error("Invalid parameters file: {}", _0)]
     InvalidParameters(String),
+    #[error("replica is truncated: expected {expected} bytes, found {actual}")]
+    TruncatedReplica { expected: u64, actual: u64 },
+    #[error("invalid layout of piece {index}: {reason}")]
+    InvalidPieceLayout { index: usize, reason: String },
+    #[error("GPU unavailable: {}", _0)]
+    GpuUnavailable(String),
 }
 
 impl From<Box<dyn Any + Send>> for Error {
@@ -23,6 +23,16 @@ pub type MerkleStore<T> = DiskStore<T>;
 pub type DiskTree<H, U, V, W> = MerkleTreeWrapper<H, DiskStore<<H as Hasher>::Domain>, U, V, W>;
 pub type LCTree<H, U, V, W> = MerkleTreeWrapper<H, LCStore<<H as Hasher>::Domain>, U, V, W>;
 
+/// The tree shape `Tree` with a tree_c of base arity `C`, see `MerkleTreeTrait::TreeCArity`.
+pub type WithTreeCArity<Tree, C> = MerkleTreeWrapper<
+    <Tree as MerkleTreeTrait>::Hasher,
+    <Tree as MerkleTreeTrait>::Store,
+    <Tree as MerkleTreeTrait>::Arity,
+    <Tree as MerkleTreeTrait>::SubTreeArity,
+    <Tree as MerkleTreeTrait>::TopTreeArity,
+    C,
+>;
+
 pub type MerkleTree<H, U> = DiskTree<H, U, U0, U0>;
 pub type LCMerkleTree<H, U> = LCTree<H, U, U0, U0>;
 
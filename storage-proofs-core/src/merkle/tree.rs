@@ -21,6 +21,9 @@ pub trait MerkleTreeTrait: Send + Sync + Debug {
     type Arity: 'static + PoseidonArity;
     type SubTreeArity: 'static + PoseidonArity;
     type TopTreeArity: 'static + PoseidonArity;
+    /// The base arity of the column tree (tree_c) a porep builds next to a tree of this shape. It
+    /// is `Arity` unless a `MerkleTreeWrapper` is given a different one.
+    type TreeCArity: 'static + PoseidonArity;
     type Hasher: 'static + Hasher;
     type Store: Store<<Self::Hasher as Hasher>::Domain>;
     type Proof: MerkleProofTrait<
@@ -51,15 +54,22 @@ pub trait MerkleTreeTrait: Send + Sync + Debug {
     ) -> Self;
 }
 
+/// A merkle tree of base arity `U`, sub-tree arity `V` and top-tree arity `W`.
+///
+/// `C` is the base arity of the tree_c a porep builds next to a tree_r_last of this shape, see
+/// `MerkleTreeTrait::TreeCArity`. It does not change the tree itself, and is only meant for
+/// non-mainnet sector shapes; mainnet shapes leave it at its default `U`.
 pub struct MerkleTreeWrapper<
     H: Hasher,
     S: Store<<H as Hasher>::Domain>,
     U: PoseidonArity,
     V: PoseidonArity = U0,
     W: PoseidonArity = U0,
+    C: PoseidonArity = U,
 > {
     pub inner: MerkleTree<<H as Hasher>::Domain, <H as Hasher>::Function, S, U, V, W>,
     pub h: PhantomData<H>,
+    pub c: PhantomData<C>,
 }
 
 impl<
@@ -68,23 +78,32 @@ impl<
         U: 'static + PoseidonArity,
         V: 'static + PoseidonArity,
         W: 'static + PoseidonArity,
-    > MerkleTreeTrait for MerkleTreeWrapper<H, S, U, V, W>
+        C: 'static + PoseidonArity,
+    > MerkleTreeTrait for MerkleTreeWrapper<H, S, U, V, W, C>
 {
     type Arity = U;
     type SubTreeArity = V;
     type TopTreeArity = W;
+    type TreeCArity = C;
     type Hasher = H;
     type Store = S;
     type Proof = MerkleProof<Self::Hasher, Self::Arity, Self::SubTreeArity, Self::TopTreeArity>;
 
     fn display() -> String {
-        format!(
+        let name = format!(
             "merkletree-{}-{}-{}-{}",
             H::name(),
             U::to_usize(),
             V::to_usize(),
             W::to_usize()
-        )
+        );
+        // Only name a tree_c arity which differs from the base arity, so that the parameter
+        // identifiers of the mainnet shapes stay the same.
+        if C::to_usize() == U::to_usize() {
+            name
+        } else {
+            format!("{}-c{}", name, C::to_usize())
+        }
     }
 
     fn root(&self) -> <Self::Hasher as Hasher>::Domain {
@@ -139,13 +158,15 @@ impl<
         U: PoseidonArity,
         V: PoseidonArity,
         W: PoseidonArity,
+        C: PoseidonArity,
     > From<MerkleTree<<H as Hasher>::Domain, <H as Hasher>::Function, S, U, V, W>>
-    for MerkleTreeWrapper<H, S, U, V, W>
+    for MerkleTreeWrapper<H, S, U, V, W, C>
 {
     fn from(tree: MerkleTree<<H as Hasher>::Domain, <H as Hasher>::Function, S, U, V, W>) -> Self {
         Self {
             inner: tree,
             h: Default::default(),
+            c: Default::default(),
         }
     }
 }
@@ -313,7 +334,8 @@ impl<
         BaseArity: PoseidonArity,
         SubTreeArity: PoseidonArity,
         TopTreeArity: PoseidonArity,
-    > Debug for MerkleTreeWrapper<H, S, BaseArity, SubTreeArity, TopTreeArity>
+        TreeCArity: PoseidonArity,
+    > Debug for MerkleTreeWrapper<H, S, BaseArity, SubTreeArity, TopTreeArity, TreeCArity>
 {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_struct("MerkleTreeWrapper")
@@ -329,7 +351,8 @@ impl<
         BaseArity: PoseidonArity,
         SubTreeArity: PoseidonArity,
         TopTreeArity: PoseidonArity,
-    > Deref for MerkleTreeWrapper<H, S, BaseArity, SubTreeArity, TopTreeArity>
+        TreeCArity: PoseidonArity,
+    > Deref for MerkleTreeWrapper<H, S, BaseArity, SubTreeArity, TopTreeArity, TreeCArity>
 {
     type Target = MerkleTree<H::Domain, H::Function, S, BaseArity, SubTreeArity, TopTreeArity>;
 
@@ -344,7 +367,8 @@ impl<
         BaseArity: PoseidonArity,
         SubTreeArity: PoseidonArity,
         TopTreeArity: PoseidonArity,
-    > DerefMut for MerkleTreeWrapper<H, S, BaseArity, SubTreeArity, TopTreeArity>
+        TreeCArity: PoseidonArity,
+    > DerefMut for MerkleTreeWrapper<H, S, BaseArity, SubTreeArity, TopTreeArity, TreeCArity>
 {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.inner
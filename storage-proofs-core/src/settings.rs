@@ -11,6 +11,9 @@ lazy_static! {
 const SETTINGS_PATH: &str = "./rust-fil-proofs.config.toml";
 const PREFIX: &str = "FIL_PROOFS";
 
+/// The smallest production sector size (32GiB), in bytes.
+const PRODUCTION_SECTOR_SIZE_MIN: u64 = 1 << 35;
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Settings {
@@ -19,17 +22,55 @@ pub struct Settings {
     pub use_gpu_column_builder: bool,
     pub max_gpu_column_batch_size: u32,
     pub column_write_batch_size: u32,
+    pub tree_c_low_memory: bool,
     pub use_gpu_tree_builder: bool,
     pub max_gpu_tree_batch_size: u32,
+    pub gpu_oom_fallback_cpu: bool,
+    pub gpu_queue_depth: u32,
+    pub auto_gpu_batch_sizes: bool,
+    pub gpu_memory_budget_percent: u32,
     pub rows_to_discard: u32,
     pub sdr_parents_cache_size: u32,
+    pub computed_parents_cache_size: u32,
     pub window_post_synthesis_num_cpus: u32,
+    pub post_challenge_batch: usize,
+    pub repair_max_corrupted_nodes: usize,
+    pub block_device_replicas: bool,
+    pub skip_piece_layout_validation: bool,
+    pub consolidate_layer_files: bool,
+    pub cache_compression: bool,
+    pub enforce_memory_check: bool,
+    pub use_huge_pages: bool,
+    pub parent_cache_huge_pages: bool,
     pub parameter_cache: String,
     pub parent_cache: String,
+    pub parent_cache_shm_dir: String,
+    pub scratch_dir: Option<String>,
+    pub async_writes: bool,
     pub use_multicore_sdr: bool,
     pub multicore_sdr_producers: usize,
     pub multicore_sdr_producer_stride: u64,
     pub multicore_sdr_lookahead: usize,
+    pub multicore_sdr_dedicated_consumer: bool,
+    pub multicore_sdr_checkout_policy: String,
+    pub multicore_sdr_consumer_smt_sibling: bool,
+    pub multicore_sdr_core_group_pools: String,
+    pub multicore_sdr_intra_group_order: String,
+    pub multicore_sdr_core_list: String,
+    pub multicore_sdr_core_lock_dir: String,
+    pub multicore_sdr_use_efficiency_cores: bool,
+    pub multicore_sdr_log_core_stats: bool,
+    pub multicore_sdr_tree_builder_cores: usize,
+    pub multicore_sdr_post_core_groups: usize,
+    pub multicore_sdr_numa_parent_cache: bool,
+    pub multicore_sdr_single_core_fallback: bool,
+    pub labeling_watchdog_secs: u64,
+    pub sha256_implementation: String,
+    pub verifying_key_cache_size: usize,
+    pub param_cache_max_entries: usize,
+    pub param_cache_max_bytes: u64,
+    pub cache_io_retries: u32,
+    pub cache_io_backoff_ms: u64,
 }
 
 impl Default for Settings {
@@ -40,20 +81,58 @@ impl Default for Settings {
             use_gpu_column_builder: false,
             max_gpu_column_batch_size: 400_000,
             column_write_batch_size: 262_144,
+            tree_c_low_memory: false,
             use_gpu_tree_builder: false,
             max_gpu_tree_batch_size: 700_000,
+            gpu_oom_fallback_cpu: false,
+            gpu_queue_depth: 2,
+            auto_gpu_batch_sizes: false,
+            gpu_memory_budget_percent: 80,
             rows_to_discard: 2,
             sdr_parents_cache_size: 2_048,
+            computed_parents_cache_size: 0,
             window_post_synthesis_num_cpus: num_cpus::get() as u32,
+            post_challenge_batch: 0,
+            repair_max_corrupted_nodes: 1,
+            block_device_replicas: false,
+            skip_piece_layout_validation: false,
+            consolidate_layer_files: false,
+            cache_compression: false,
+            enforce_memory_check: false,
+            use_huge_pages: false,
+            parent_cache_huge_pages: false,
             // `parameter_cache` does not use the cache() mechanism because it is now used
             // for durable, canonical Groth parameters and verifying keys.
             // The name is retained for backwards compatibility.
             parameter_cache: "/var/tmp/filecoin-proof-parameters/".to_string(),
             parent_cache: cache("filecoin-parents"),
+            parent_cache_shm_dir: String::new(),
+            scratch_dir: None,
+            async_writes: false,
             use_multicore_sdr: false,
             multicore_sdr_producers: 3,
             multicore_sdr_producer_stride: 128,
             multicore_sdr_lookahead: 800,
+            multicore_sdr_dedicated_consumer: false,
+            multicore_sdr_checkout_policy: "first_free".to_string(),
+            multicore_sdr_consumer_smt_sibling: false,
+            multicore_sdr_core_group_pools: "".to_string(),
+            multicore_sdr_intra_group_order: "ascending".to_string(),
+            multicore_sdr_core_list: "".to_string(),
+            multicore_sdr_core_lock_dir: "".to_string(),
+            multicore_sdr_use_efficiency_cores: true,
+            multicore_sdr_log_core_stats: false,
+            multicore_sdr_tree_builder_cores: 0,
+            multicore_sdr_post_core_groups: 1,
+            multicore_sdr_numa_parent_cache: false,
+            multicore_sdr_single_core_fallback: false,
+            labeling_watchdog_secs: 0,
+            sha256_implementation: "auto".to_string(),
+            verifying_key_cache_size: 128,
+            param_cache_max_entries: 0,
+            param_cache_max_bytes: 0,
+            cache_io_retries: 3,
+            cache_io_backoff_ms: 100,
         }
     }
 }
@@ -99,4 +178,79 @@ impl Settings {
 
         s.try_into()
     }
+
+    /// Returns the default settings tuned for sealing sectors of `sector_size` bytes.
+    ///
+    /// The result is meant as a base that callers can apply their own overrides to. Only the
+    /// tuning knobs differ from `Settings::default()`; paths and verification flags are kept.
+    ///
+    /// - Production sectors (32GiB and up) enable multicore SDR with 3 producers, a producer
+    ///   stride of 128 nodes and a lookahead of 800 nodes. A layer is too large to keep in
+    ///   cache, so labeling is bound by the latency of fetching parents, which the producers
+    ///   hide by prefetching ahead of the consumer.
+    /// - 64GiB sectors use the same settings as 32GiB sectors. Labeling a node costs the same,
+    ///   only the layers are twice as long, and their trees are split into twice as many base
+    ///   trees of the same depth, so the producer counts and GPU batch sizes carry over. What
+    ///   doubles is the memory labeling needs, see `labeling_memory`.
+    /// - Test sectors (everything smaller) use the single-threaded labeling path with a single
+    ///   producer. Layers are small enough that the parents stay hot in cache, and the cost of
+    ///   spawning and pinning producer threads per layer outweighs what they save.
+    ///
+    /// The SDR parents cache window (`sdr_parents_cache_size`) is left at its default for all
+    /// sizes, as it is already clamped to the number of nodes in a layer.
+    pub fn profile_for(sector_size: u64) -> Settings {
+        let mut settings = Settings::default();
+
+        if sector_size >= PRODUCTION_SECTOR_SIZE_MIN {
+            settings.use_multicore_sdr = true;
+            settings.multicore_sdr_producers = 3;
+            settings.multicore_sdr_producer_stride = 128;
+            settings.multicore_sdr_lookahead = 800;
+        } else {
+            settings.use_multicore_sdr = false;
+            settings.multicore_sdr_producers = 1;
+        }
+
+        settings
+    }
+
+    /// Returns the number of bytes of memory labeling a sector of `sector_size` bytes needs.
+    ///
+    /// Both labeling paths keep the layer being labeled and the previous layer, which its
+    /// expander parents are read from, in memory, so this is twice the sector size. Labeling warns
+    /// on machines with less memory available, or fails with `enforce_memory_check`.
+    pub fn labeling_memory(sector_size: u64) -> u64 {
+        2 * sector_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_for() {
+        for sector_size in &[2048u64, 1 << 23, 1 << 29] {
+            let settings = Settings::profile_for(*sector_size);
+            assert!(!settings.use_multicore_sdr);
+            assert_eq!(settings.multicore_sdr_producers, 1);
+        }
+
+        for sector_size in &[1u64 << 35, 1 << 36] {
+            let settings = Settings::profile_for(*sector_size);
+            assert!(settings.use_multicore_sdr);
+            assert_eq!(settings.multicore_sdr_producers, 3);
+            assert_eq!(settings.multicore_sdr_lookahead, 800);
+        }
+        assert_eq!(Settings::labeling_memory(1 << 36), 1 << 37);
+
+        // Settings that are not tuning knobs are left at their defaults.
+        let default = Settings::default();
+        let settings = Settings::profile_for(1 << 35);
+        assert_eq!(settings.parent_cache, default.parent_cache);
+        assert_eq!(
+            settings.sdr_parents_cache_size,
+            default.sdr_parents_cache_size
+        );
+    }
 }
@@ -1,4 +1,7 @@
 use std::cmp::min;
+use std::io;
+use std::thread;
+use std::time::Duration;
 
 use anyhow::ensure;
 use bellperson::{
@@ -6,6 +9,7 @@ use bellperson::{
     ConstraintSystem, SynthesisError,
 };
 use ff::PrimeField;
+use log::warn;
 use merkletree::merkle::get_merkle_tree_row_count;
 
 use crate::{error::Error, settings::SETTINGS};
@@ -150,6 +154,12 @@ pub fn reverse_bit_numbering(bits: Vec<Boolean>) -> Vec<Boolean> {
 
 // If the tree is large enough to use the default value (per-arity), use it.  If it's too small to cache anything (i.e. not enough rows), don't discard any.
 pub fn default_rows_to_discard(leafs: usize, arity: usize) -> usize {
+    rows_to_discard(leafs, arity, None)
+}
+
+/// Like `default_rows_to_discard`, but trees whose arity has no fixed value discard `configured`
+/// rows instead of the `rows_to_discard` setting, if given, as far as the tree allows.
+pub fn rows_to_discard(leafs: usize, arity: usize, configured: Option<usize>) -> usize {
     let row_count = get_merkle_tree_row_count(leafs, arity);
     if row_count <= 2 {
         // If a tree only has a root row and/or base, there is
@@ -166,7 +176,7 @@ pub fn default_rows_to_discard(leafs: usize, arity: usize) -> usize {
 
     // This configurable setting is for a default oct-tree
     // rows_to_discard value, which defaults to 2.
-    let rows_to_discard = SETTINGS.rows_to_discard as usize;
+    let rows_to_discard = configured.unwrap_or(SETTINGS.rows_to_discard as usize);
 
     // Discard at most 'constant value' rows (coded below,
     // differing by arity) while respecting the max number that
@@ -178,6 +188,56 @@ pub fn default_rows_to_discard(leafs: usize, arity: usize) -> usize {
     }
 }
 
+/// Runs `f`, which opens or reads a cache file, and retries it up to `SETTINGS.cache_io_retries`
+/// times if it fails with a transient I/O error. The n-th retry waits
+/// `n * SETTINGS.cache_io_backoff_ms` milliseconds first. Any other error, e.g. a missing file, is
+/// returned right away.
+pub fn retry_transient_io<T>(f: impl FnMut() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    retry_transient_io_with(
+        SETTINGS.cache_io_retries,
+        Duration::from_millis(SETTINGS.cache_io_backoff_ms),
+        f,
+    )
+}
+
+fn retry_transient_io_with<T>(
+    retries: u32,
+    backoff: Duration,
+    mut f: impl FnMut() -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(err) if attempt < retries && is_transient_io_error(&err) => {
+                attempt += 1;
+                warn!(
+                    "transient I/O error, retry {} of {}: {:#}",
+                    attempt, retries, err
+                );
+                thread::sleep(backoff * attempt);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Returns true if an I/O error in the chain of `err` is likely to go away by itself, i.e. the
+/// call was interrupted (`EINTR`), would block (`EAGAIN`) or timed out.
+fn is_transient_io_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        let io_err = match cause.downcast_ref::<Error>() {
+            Some(Error::Io(io_err)) => Some(io_err),
+            _ => cause.downcast_ref::<io::Error>(),
+        };
+        io_err.map_or(false, |io_err| {
+            matches!(
+                io_err.kind(),
+                io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            )
+        })
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -332,4 +392,57 @@ mod tests {
             "circuit and non circuit do not match"
         );
     }
+
+    #[test]
+    fn test_retry_transient_io() {
+        let backoff = Duration::from_millis(1);
+
+        let mut calls = 0;
+        let value = retry_transient_io_with(3, backoff, || {
+            calls += 1;
+            if calls < 3 {
+                Err(
+                    anyhow::Error::from(io::Error::new(io::ErrorKind::Interrupted, "interrupted"))
+                        .context("could not open cache"),
+                )
+            } else {
+                Ok(calls)
+            }
+        })
+        .expect("transient errors were not retried");
+        assert_eq!(value, 3);
+
+        // Retries are given up on after the configured count.
+        let mut calls = 0;
+        let res: anyhow::Result<()> = retry_transient_io_with(2, backoff, || {
+            calls += 1;
+            Err(Error::Io(io::Error::new(io::ErrorKind::TimedOut, "timed out")).into())
+        });
+        assert!(res.is_err());
+        assert_eq!(calls, 3);
+
+        // Missing files fail immediately.
+        let mut calls = 0;
+        let res: anyhow::Result<()> = retry_transient_io_with(3, backoff, || {
+            calls += 1;
+            Err(io::Error::new(io::ErrorKind::NotFound, "missing").into())
+        });
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_rows_to_discard() {
+        // An oct tree of 8^6 leaves has 7 rows, of which at most 5 can be discarded.
+        let leafs = 1 << 18;
+        assert_eq!(
+            rows_to_discard(leafs, 8, None),
+            default_rows_to_discard(leafs, 8)
+        );
+        assert_eq!(rows_to_discard(leafs, 8, Some(4)), 4);
+        assert_eq!(rows_to_discard(leafs, 8, Some(9)), 5);
+
+        // Binary trees always discard their fixed number of rows.
+        assert_eq!(rows_to_discard(1 << 20, 2, Some(3)), 7);
+    }
 }
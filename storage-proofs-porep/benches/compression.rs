@@ -0,0 +1,64 @@
+use std::fs;
+use std::path::Path;
+
+use blstrs::Scalar as Fr;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use ff::Field;
+use fr32::fr_into_bytes;
+use rand::thread_rng;
+use storage_proofs_porep::stacked::{compress_cache_file, decompress_cache_file};
+use tempfile::tempdir;
+
+/// The size of the benchmarked layer, 16MiB.
+const LAYER_BYTES: usize = 16 * 1024 * 1024;
+
+fn compression_benchmark(c: &mut Criterion) {
+    let mut rng = thread_rng();
+    // Labels are field elements, so random ones compress about as well as real layers.
+    let labels: Vec<u8> = (0..LAYER_BYTES / 32)
+        .flat_map(|_| fr_into_bytes(&Fr::random(&mut rng)))
+        .collect();
+
+    let dir = tempdir().unwrap();
+    let raw_path = dir.path().join("layer.dat");
+    let compressed_path = dir.path().join("layer-compressed.dat");
+    fs::write(&raw_path, &labels).unwrap();
+    fs::write(&compressed_path, &labels).unwrap();
+    compress_cache_file(&compressed_path).unwrap();
+
+    let compressed_bytes = fs::metadata(&compressed_path).unwrap().len();
+    println!(
+        "compressed {} bytes of labels to {} bytes ({:.1}%)",
+        LAYER_BYTES,
+        compressed_bytes,
+        compressed_bytes as f64 * 100.0 / LAYER_BYTES as f64
+    );
+
+    let mut group = c.benchmark_group("compression");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(LAYER_BYTES as u64));
+
+    let path = dir.path().join("layer-bench.dat");
+    let copy_to_path = |from: &Path| fs::copy(from, &path).unwrap();
+
+    group.bench_function("compress", |b| {
+        b.iter_batched(
+            || copy_to_path(&raw_path),
+            |_| compress_cache_file(&path).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.bench_function("decompress", |b| {
+        b.iter_batched(
+            || copy_to_path(&compressed_path),
+            |_| decompress_cache_file(&path).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, compression_benchmark);
+criterion_main!(benches);
@@ -12,6 +12,11 @@ use storage_proofs_core::{error::Result, merkle::BinaryMerkleTree, proof::ProofS
 pub mod drg;
 pub mod stacked;
 
+/// The tracing version the spans are recorded with, so that dependents can add their own spans
+/// to the same subscriber.
+#[cfg(feature = "tracing")]
+pub use tracing;
+
 mod encode;
 
 pub const MAX_LEGACY_POREP_REGISTERED_PROOF_ID: u64 = 4;
@@ -33,7 +33,7 @@ impl<
     /// Create an empty `ColumnProof`, used in `blank_circuit`s.
     pub fn empty<
         S: Store<H::Domain>,
-        Tree: MerkleTreeTrait<Hasher = H, Store = S, Arity = U, SubTreeArity = V, TopTreeArity = W>,
+        Tree: MerkleTreeTrait<Hasher = H, Store = S, TreeCArity = U, SubTreeArity = V, TopTreeArity = W>,
     >(
         params: &PublicParams<Tree>,
     ) -> Self {
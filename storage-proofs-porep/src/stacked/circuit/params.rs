@@ -29,9 +29,16 @@ type TreeAuthPath<T> = AuthPath<
     <T as MerkleTreeTrait>::TopTreeArity,
 >;
 
+type TreeCAuthPath<T> = AuthPath<
+    <T as MerkleTreeTrait>::Hasher,
+    <T as MerkleTreeTrait>::TreeCArity,
+    <T as MerkleTreeTrait>::SubTreeArity,
+    <T as MerkleTreeTrait>::TopTreeArity,
+>;
+
 type TreeColumnProof<T> = ColumnProof<
     <T as MerkleTreeTrait>::Hasher,
-    <T as MerkleTreeTrait>::Arity,
+    <T as MerkleTreeTrait>::TreeCArity,
     <T as MerkleTreeTrait>::SubTreeArity,
     <T as MerkleTreeTrait>::TopTreeArity,
 >;
@@ -48,7 +55,7 @@ pub struct Proof<Tree: MerkleTreeTrait, G: Hasher> {
     /// Inclusion path of the challenged replica node in tree R.
     pub comm_r_last_path: TreeAuthPath<Tree>,
     /// Inclusion path of the column hash of the challenged node  in tree C.
-    pub comm_c_path: TreeAuthPath<Tree>,
+    pub comm_c_path: TreeCAuthPath<Tree>,
     /// Column proofs for the drg parents.
     pub drg_parents_proofs: Vec<TreeColumnProof<Tree>>,
     /// Column proofs for the expander parents.
@@ -0,0 +1,180 @@
+//! Writing of the layers and trees, see [`write_file`].
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+#[cfg(all(target_os = "linux", feature = "io-uring-writes"))]
+use log::warn;
+#[cfg(all(target_os = "linux", feature = "io-uring-writes"))]
+use storage_proofs_core::settings::SETTINGS;
+
+/// Writes `data` into the file at `path`, which is created or truncated.
+///
+/// With `async_writes` on Linux and the `io-uring-writes` feature, the data is submitted to
+/// io_uring in chunks which are written concurrently, which keeps a file system with a high
+/// latency per write busy. If io_uring is not available, e.g. on older kernels, and in other
+/// builds, it is written with a single `write_all`. Callers which must not wait for the write run it on another thread, see
+/// `create_label::store_layer_while`.
+pub(crate) fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+
+    #[cfg(all(target_os = "linux", feature = "io-uring-writes"))]
+    {
+        if SETTINGS.async_writes {
+            match io_uring::IoUring::new(uring::QUEUE_DEPTH) {
+                Ok(mut ring) => return uring::write_all(&mut ring, &file, data),
+                Err(err) => warn!("io_uring unavailable, writing {:?} directly: {}", path, err),
+            }
+        }
+    }
+
+    file.write_all(data)
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring-writes"))]
+mod uring {
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::thread;
+    use std::time::Duration;
+
+    use io_uring::{opcode, types, IoUring};
+
+    /// The number of chunks which are written at the same time.
+    pub(super) const QUEUE_DEPTH: u32 = 16;
+
+    /// The size of the chunks the data is written in.
+    const CHUNK_SIZE: usize = 4 << 20;
+
+    /// How often waiting for the writes in flight may fail in a row before giving up on them.
+    const MAX_WAIT_FAILURES: usize = 100;
+
+    /// How long to wait before waiting for the writes in flight again after it failed.
+    const WAIT_FAILURE_BACKOFF: Duration = Duration::from_millis(10);
+
+    /// Writes `data` from the start of `file` through `ring`.
+    ///
+    /// The kernel reads from `data` until a write completes, so all writes in flight are waited
+    /// for before returning, even if one of them or waiting for them failed. Only if waiting for
+    /// them keeps failing, the error is returned with writes in flight, which are cancelled once
+    /// the caller drops `ring`.
+    pub(super) fn write_all(ring: &mut IoUring, file: &File, data: &[u8]) -> io::Result<()> {
+        let fd = types::Fd(file.as_raw_fd());
+        // The offset and length of the ranges which still have to be written, last first.
+        let mut pending: Vec<(usize, usize)> = (0..data.len())
+            .step_by(CHUNK_SIZE)
+            .map(|offset| (offset, CHUNK_SIZE.min(data.len() - offset)))
+            .rev()
+            .collect();
+        // The length of the writes in flight, by their offset.
+        let mut in_flight: HashMap<u64, usize> = HashMap::new();
+        let mut result = Ok(());
+        let mut wait_failures = 0;
+
+        while !in_flight.is_empty() || (result.is_ok() && !pending.is_empty()) {
+            while result.is_ok() && in_flight.len() < QUEUE_DEPTH as usize {
+                let (offset, len) = match pending.pop() {
+                    Some(range) => range,
+                    None => break,
+                };
+                let entry = opcode::Write::new(fd, data[offset..].as_ptr(), len as u32)
+                    .offset(offset as _)
+                    .build()
+                    .user_data(offset as u64);
+                // Safety: `data` outlives the write, as it is waited for below.
+                if unsafe { ring.submission().push(&entry) }.is_err() {
+                    pending.push((offset, len));
+                    break;
+                }
+                in_flight.insert(offset as u64, len);
+            }
+
+            if let Err(err) = ring.submit_and_wait(1) {
+                if err.kind() != io::ErrorKind::Interrupted {
+                    wait_failures += 1;
+                    if wait_failures > MAX_WAIT_FAILURES {
+                        return Err(io::Error::new(
+                            err.kind(),
+                            format!(
+                                "io_uring failed with {} writes in flight: {}",
+                                in_flight.len(),
+                                err
+                            ),
+                        ));
+                    }
+                    if result.is_ok() {
+                        result = Err(err);
+                    }
+                    thread::sleep(WAIT_FAILURE_BACKOFF);
+                }
+                // Writes may have completed anyway, which are collected below, and no more are
+                // submitted after an error.
+            } else {
+                wait_failures = 0;
+            }
+
+            let completed: Vec<(u64, i32)> = ring
+                .completion()
+                .map(|entry| (entry.user_data(), entry.result()))
+                .collect();
+            for (offset, written) in completed {
+                let len = in_flight
+                    .remove(&offset)
+                    .expect("completed write was submitted");
+                if written < 0 {
+                    let err = io::Error::from_raw_os_error(-written);
+                    if err.kind() == io::ErrorKind::Interrupted {
+                        pending.push((offset as usize, len));
+                    } else if result.is_ok() {
+                        result = Err(err);
+                    }
+                } else if written == 0 {
+                    if result.is_ok() {
+                        result = Err(io::Error::from(io::ErrorKind::WriteZero));
+                    }
+                } else if (written as usize) < len {
+                    pending.push((offset as usize + written as usize, len - written as usize));
+                }
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use rand::{Rng, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+    use storage_proofs_core::TEST_SEED;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_write_file() {
+        let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+        let dir = tempdir().expect("tempdir failure");
+        let path = dir.path().join("data");
+
+        // More than one chunk, with a partial one at the end.
+        let data: Vec<u8> = (0..(9 << 20) + 123).map(|_| rng.gen()).collect();
+        fs::write(&path, vec![1u8; data.len() * 2]).expect("failed to write file");
+        write_file(&path, &data).expect("write_file failure");
+        assert!(fs::read(&path).expect("failed to read file") == data);
+
+        #[cfg(all(target_os = "linux", feature = "io-uring-writes"))]
+        {
+            if let Ok(mut ring) = io_uring::IoUring::new(uring::QUEUE_DEPTH) {
+                let file = File::create(&path).expect("failed to create file");
+                uring::write_all(&mut ring, &file, &data).expect("write_all failure");
+                assert!(fs::read(&path).expect("failed to read file") == data);
+            }
+        }
+    }
+}
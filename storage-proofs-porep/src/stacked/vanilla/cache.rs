@@ -1,16 +1,18 @@
 use std::collections::{BTreeMap, HashSet};
-use std::fs::{remove_file, File};
-use std::io;
+use std::fs::{self, remove_file, File, OpenOptions};
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, ensure, Context};
 use byteorder::{ByteOrder, LittleEndian};
 use filecoin_hashers::Hasher;
+use fs2::FileExt;
 use lazy_static::lazy_static;
-use log::{info, trace};
+use log::{info, trace, warn};
 use mapr::{Mmap, MmapOptions};
-use rayon::prelude::{IndexedParallelIterator, ParallelIterator, ParallelSliceMut};
+use rayon::prelude::{IndexedParallelIterator, ParallelIterator, ParallelSlice, ParallelSliceMut};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use storage_proofs_core::{
@@ -18,14 +20,27 @@ use storage_proofs_core::{
     error::Result,
     parameter_cache::{with_exclusive_lock, LockedFile, ParameterSetMetadata, VERSION},
     settings::SETTINGS,
-    util::NODE_SIZE,
+    util::{retry_transient_io, NODE_SIZE},
 };
 
-use crate::stacked::vanilla::graph::{StackedGraph, DEGREE};
+use crate::stacked::vanilla::{
+    graph::{StackedGraph, DEGREE},
+    memory_handling::advise_huge_pages,
+    parents_source::ParentsCacheCounters,
+};
 
 /// u32 = 4 bytes
 const NODE_BYTES: usize = 4;
 
+/// The number of nodes of a chunk of the parent cache which has its own checksum, i.e. 56MiB.
+const CHECKSUM_CHUNK_NODES: usize = 1 << 20;
+
+/// Identifies a checksums file, followed by the nodes per chunk, the number of chunks and the
+/// SHA-256 digest of every chunk.
+const CHECKSUMS_MAGIC: &[u8; 8] = b"FILPCCS1";
+
+const CHECKSUMS_HEADER_BYTES: usize = CHECKSUMS_MAGIC.len() + 16;
+
 pub const PARENT_CACHE_DATA: &str = include_str!("../../../parent_cache.json");
 
 pub type ParentCacheDataMap = BTreeMap<String, ParentCacheData>;
@@ -42,16 +57,50 @@ lazy_static! {
     static ref PARENT_CACHE_ACCESS_LOCK: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
 }
 
+/// The progress of generating a parent cache, see [`ParentCache::new_with_progress`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParentCacheProgress {
+    pub nodes_done: u32,
+    pub nodes_total: u32,
+    /// The time since the generation started.
+    pub elapsed: Duration,
+}
+
+impl ParentCacheProgress {
+    /// Estimates the time until the generation completes from the rate so far, or `None` if no
+    /// nodes are done yet.
+    pub fn estimated_remaining(&self) -> Option<Duration> {
+        if self.nodes_done == 0 {
+            return None;
+        }
+        let nodes_left = self.nodes_total.saturating_sub(self.nodes_done);
+        Some(
+            self.elapsed
+                .mul_f64(nodes_left as f64 / self.nodes_done as f64),
+        )
+    }
+}
+
+/// Receives the progress of a parent cache generation every `interval` nodes.
+#[derive(Clone, Copy)]
+pub struct ParentCacheProgressCallback<'a> {
+    pub interval: u32,
+    pub callback: &'a (dyn Fn(ParentCacheProgress) + Sync),
+}
+
 // StackedGraph will hold two different (but related) `ParentCache`,
 #[derive(Debug)]
 pub struct ParentCache {
-    /// Disk path for the cache.
+    /// Path of the cache which is read, the copy in `parent_cache_shm_dir` if there is one.
     pub path: PathBuf,
+    /// Disk path for the cache, which its checksums are next to.
+    pub disk_path: PathBuf,
     /// The total number of cache entries.
     num_cache_entries: u32,
     cache: CacheData,
     pub sector_size: usize,
     pub digest: String,
+    pub(crate) counters: ParentsCacheCounters,
 }
 
 #[derive(Debug)]
@@ -86,6 +135,9 @@ impl CacheData {
                 .map(self.file.as_ref())
                 .context("could not shift mmap}")?
         };
+        if SETTINGS.parent_cache_huge_pages {
+            advise_huge_pages(&self.data);
+        }
         self.offset = new_offset;
 
         Ok(())
@@ -120,8 +172,10 @@ impl CacheData {
     fn open(offset: u32, len: u32, path: &Path) -> Result<Self> {
         let min_cache_size = (offset + len) as usize * DEGREE * NODE_BYTES;
 
-        let file = LockedFile::open_shared_read(path)
-            .with_context(|| format!("could not open path={}", path.display()))?;
+        let file = retry_transient_io(|| {
+            LockedFile::open_shared_read(path)
+                .with_context(|| format!("could not open path={}", path.display()))
+        })?;
 
         let actual_len = file.as_ref().metadata()?.len();
         if actual_len < min_cache_size as u64 {
@@ -133,13 +187,16 @@ impl CacheData {
             );
         }
 
-        let data = unsafe {
+        let data = retry_transient_io(|| unsafe {
             MmapOptions::new()
                 .offset((offset as usize * DEGREE * NODE_BYTES) as u64)
                 .len(len as usize * DEGREE * NODE_BYTES)
                 .map(file.as_ref())
-                .with_context(|| format!("could not mmap path={}", path.display()))?
-        };
+                .with_context(|| format!("could not mmap path={}", path.display()))
+        })?;
+        if SETTINGS.parent_cache_huge_pages {
+            advise_huge_pages(&data);
+        }
 
         Ok(Self {
             data,
@@ -152,6 +209,21 @@ impl CacheData {
 
 impl ParentCache {
     pub fn new<H, G>(len: u32, cache_entries: u32, graph: &StackedGraph<H, G>) -> Result<Self>
+    where
+        H: Hasher,
+        G: Graph<H> + ParameterSetMetadata + Send + Sync,
+    {
+        Self::new_with_progress(len, cache_entries, graph, None)
+    }
+
+    /// Like [`ParentCache::new`], but reports the progress to `progress` if the cache has to be
+    /// generated. Nothing is reported for a cache which exists already.
+    pub fn new_with_progress<H, G>(
+        len: u32,
+        cache_entries: u32,
+        graph: &StackedGraph<H, G>,
+        progress: Option<ParentCacheProgressCallback<'_>>,
+    ) -> Result<Self>
     where
         H: Hasher,
         G: Graph<H> + ParameterSetMetadata + Send + Sync,
@@ -162,7 +234,7 @@ impl ParentCache {
             .lock()
             .expect("parent cache generation lock failed");
 
-        if path.exists() {
+        let cache = if path.exists() {
             // If the cache file exists and we've got the lock, generation has previously been
             // completed.  Insert that it no longer needs generation at this point unconditionally.
             if generated.get(&generation_key).is_none() {
@@ -170,7 +242,7 @@ impl ParentCache {
             }
             Self::open(len, cache_entries, graph, &path)
         } else {
-            match Self::generate(len, cache_entries, graph, &path) {
+            match Self::generate_inner(len, cache_entries, graph, &path, progress) {
                 Ok(c) => {
                     generated.insert(generation_key);
 
@@ -187,7 +259,67 @@ impl ParentCache {
                     }
                 }
             }
+        }?;
+
+        cache.into_shared_memory()
+    }
+
+    /// Reads the cache from its copy in `parent_cache_shm_dir` from now on, if that is set. The
+    /// copy is made by the first process which needs it, and mapped by all others, so the cache is
+    /// only held in memory once per machine. If the copy fails, the cache is read from disk.
+    fn into_shared_memory(mut self) -> Result<Self> {
+        if SETTINGS.parent_cache_shm_dir.is_empty() {
+            return Ok(self);
+        }
+
+        let file_name = self
+            .path
+            .file_name()
+            .context("parent cache path has no file name")?;
+        let shared_path = Path::new(&SETTINGS.parent_cache_shm_dir).join(file_name);
+        if let Err(err) =
+            copy_verified_parent_cache(&self.path, &shared_path, SETTINGS.verify_cache)
+        {
+            warn!(
+                "failed to copy the parent cache to {}, reading {}: {:?}",
+                shared_path.display(),
+                self.path.display(),
+                err
+            );
+            return Ok(self);
+        }
+
+        self.cache = CacheData::open(0, self.cache.len, &shared_path)?;
+        // `disk_path` stays the cache on disk, which e.g. the NUMA local copies are made of.
+        self.path = shared_path;
+
+        Ok(self)
+    }
+
+    /// Reads the whole cache once, so that all of it is in the page cache, or in shared memory
+    /// with `parent_cache_shm_dir`, before the first labeling reads it. Returns the number of
+    /// bytes read.
+    pub fn prewarm(&self) -> Result<u64> {
+        let mut file = File::open(&self.path)
+            .with_context(|| format!("could not open path={}", self.path.display()))?;
+        let mut buf = vec![0u8; 1 << 20];
+        let mut total = 0;
+        loop {
+            let read = file
+                .read(&mut buf)
+                .with_context(|| format!("could not read path={}", self.path.display()))?;
+            if read == 0 {
+                break;
+            }
+            total += read as u64;
         }
+        info!(
+            "parent cache: prewarmed {} bytes of {}",
+            total,
+            self.path.display()
+        );
+
+        Ok(total)
     }
 
     /// Opens an existing cache from disk.  If the verify_cache option
@@ -236,6 +368,25 @@ impl ParentCache {
         );
 
         if verify_cache {
+            // Damaged chunks are regenerated first, so that the digest below only fails for damage
+            // which the checksums do not cover. The checksums are a local file, so the cache is
+            // still compared to the digest of the manifest, whether its chunks match them or not.
+            let has_checksums = match Self::repair_chunks(graph, path)? {
+                Some(repaired) => {
+                    if !repaired.is_empty() {
+                        info!(
+                            "[open] parent cache: regenerated damaged chunks {:?}",
+                            repaired
+                        );
+                    }
+                    true
+                }
+                None => {
+                    info!("[open] parent cache: no chunk checksums, verifying the whole cache");
+                    false
+                }
+            };
+
             // Always check all of the data for integrity checks, even
             // if we're only opening a portion of it.
             let mut hasher = Sha256::new();
@@ -247,7 +398,6 @@ impl ParentCache {
                     .with_context(|| format!("could not mmap path={}", path.display()))?
             };
             hasher.update(&data);
-            drop(data);
 
             let hash = hasher.finalize();
             digest_hex = hash.iter().map(|x| format!("{:01$x}", x, 2)).collect();
@@ -269,11 +419,16 @@ impl ParentCache {
 
                 if digest_hex == parent_cache_data.digest {
                     info!("[open] parent cache: cache is verified!");
+                    // A cache from before the checksums gets them, now that it is verified.
+                    if !has_checksums {
+                        write_chunk_checksums(path, &data, CHECKSUM_CHUNK_NODES)?;
+                    }
                 } else {
                     info!(
                         "[!!!] Parent cache digest mismatch detected.  Regenerating {}",
                         path.display()
                     );
+                    drop(data);
                     // delete invalid cache
                     remove_file(path)?;
                     ensure!(
@@ -290,9 +445,11 @@ impl ParentCache {
         Ok(ParentCache {
             cache: CacheData::open(0, len, path)?,
             path: path.to_path_buf(),
+            disk_path: path.to_path_buf(),
             num_cache_entries: cache_entries,
             sector_size: graph.size() * NODE_SIZE,
             digest: digest_hex,
+            counters: ParentsCacheCounters::default(),
         })
     }
 
@@ -303,6 +460,20 @@ impl ParentCache {
         graph: &StackedGraph<H, G>,
         path: &Path,
     ) -> Result<Self>
+    where
+        H: Hasher,
+        G: Graph<H> + ParameterSetMetadata + Send + Sync,
+    {
+        Self::generate_inner(len, cache_entries, graph, path, None)
+    }
+
+    fn generate_inner<H, G>(
+        len: u32,
+        cache_entries: u32,
+        graph: &StackedGraph<H, G>,
+        path: &Path,
+        progress: Option<ParentCacheProgressCallback<'_>>,
+    ) -> Result<Self>
     where
         H: Hasher,
         G: Graph<H> + ParameterSetMetadata + Send + Sync,
@@ -323,18 +494,31 @@ impl ParentCache {
                     .with_context(|| format!("could not mmap path={}", path.display()))?
             };
 
-            data.par_chunks_mut(DEGREE * NODE_BYTES)
+            // The nodes are generated in parallel within blocks of the progress interval, so
+            // that the progress is reported in between blocks without any synchronization.
+            let start = Instant::now();
+            let block_nodes = match progress {
+                Some(progress) => {
+                    ensure!(progress.interval > 0, "progress interval must not be zero");
+                    progress.interval as usize
+                }
+                None => cache_entries.max(1) as usize,
+            };
+            for (block, block_data) in data
+                .chunks_mut(block_nodes * DEGREE * NODE_BYTES)
                 .enumerate()
-                .try_for_each(|(node, entry)| -> Result<()> {
-                    let mut parents = [0u32; DEGREE];
-                    graph
-                        .base_graph()
-                        .parents(node, &mut parents[..BASE_DEGREE])?;
-                    graph.generate_expanded_parents(node, &mut parents[BASE_DEGREE..]);
-
-                    LittleEndian::write_u32_into(&parents, entry);
-                    Ok(())
-                })?;
+            {
+                let first_node = block * block_nodes;
+                generate_parents(graph, block_data, first_node)?;
+
+                if let Some(progress) = progress {
+                    (progress.callback)(ParentCacheProgress {
+                        nodes_done: (first_node + block_data.len() / (DEGREE * NODE_BYTES)) as u32,
+                        nodes_total: cache_entries,
+                        elapsed: start.elapsed(),
+                    });
+                }
+            }
 
             info!("parent cache: generated");
             data.flush().context("failed to flush parent cache")?;
@@ -364,6 +548,7 @@ impl ParentCache {
                 }
             };
 
+            write_chunk_checksums(path, &data, CHECKSUM_CHUNK_NODES)?;
             drop(data);
 
             info!("parent cache: written to disk");
@@ -373,17 +558,120 @@ impl ParentCache {
         Ok(ParentCache {
             cache: CacheData::open(0, len, path)?,
             path: path.to_path_buf(),
+            disk_path: path.to_path_buf(),
             num_cache_entries: cache_entries,
             sector_size,
             digest: digest_hex,
+            counters: ParentsCacheCounters::default(),
         })
     }
 
+    /// Compares every chunk of the cache at `path` with its checksum, see
+    /// [`write_chunk_checksums`], and regenerates the damaged chunks in place. Returns the indexes
+    /// of the regenerated chunks, or `None` if the cache has no valid checksums, so that it can only
+    /// be verified as a whole.
+    ///
+    /// The cache is only written to while this holds an exclusive lock on it. If another process
+    /// has it open, or this one for another labeling, the damaged chunks are not regenerated and
+    /// `None` is returned as well.
+    fn repair_chunks<H, G>(graph: &StackedGraph<H, G>, path: &Path) -> Result<Option<Vec<usize>>>
+    where
+        H: Hasher,
+        G: Graph<H> + ParameterSetMetadata + Send + Sync,
+    {
+        let (chunk_nodes, checksums) = match read_chunk_checksums(path) {
+            Some(checksums) => checksums,
+            None => return Ok(None),
+        };
+        let chunk_bytes = chunk_nodes * DEGREE * NODE_BYTES;
+        let damaged_chunks = |data: &[u8]| -> Option<Vec<usize>> {
+            if checksums.len() != (data.len() + chunk_bytes - 1) / chunk_bytes {
+                warn!(
+                    "parent cache: the checksums of {} are for a cache of another size",
+                    path.display()
+                );
+                return None;
+            }
+
+            Some(
+                chunk_checksums(data, chunk_nodes)
+                    .iter()
+                    .zip(&checksums)
+                    .enumerate()
+                    .filter(|(_, (actual, expected))| actual != expected)
+                    .map(|(chunk, _)| chunk)
+                    .collect(),
+            )
+        };
+
+        // The cache is checked under a shared lock, as other processes may be reading it.
+        {
+            let file = LockedFile::open_shared_read(path)
+                .with_context(|| format!("could not open path={}", path.display()))?;
+            let data = unsafe {
+                MmapOptions::new()
+                    .map(file.as_ref())
+                    .with_context(|| format!("could not mmap path={}", path.display()))?
+            };
+            match damaged_chunks(&data) {
+                Some(damaged) if damaged.is_empty() => return Ok(Some(damaged)),
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("could not open path={}", path.display()))?;
+        if let Err(err) = file.try_lock_exclusive() {
+            warn!(
+                "parent cache: {} is in use, not regenerating its damaged chunks: {}",
+                path.display(),
+                err
+            );
+            return Ok(None);
+        }
+        let mut data = unsafe {
+            MmapOptions::new()
+                .map_mut(&file)
+                .with_context(|| format!("could not mmap path={}", path.display()))?
+        };
+
+        // Another process may have repaired the cache before the lock was taken.
+        let damaged = match damaged_chunks(&data) {
+            Some(damaged) => damaged,
+            None => return Ok(None),
+        };
+        for chunk in &damaged {
+            warn!(
+                "parent cache: chunk {} of {} is damaged, regenerating it",
+                chunk,
+                path.display()
+            );
+            let start = chunk * chunk_bytes;
+            let end = (start + chunk_bytes).min(data.len());
+            generate_parents(graph, &mut data[start..end], chunk * chunk_nodes)?;
+        }
+
+        if !damaged.is_empty() {
+            data.flush().context("failed to flush parent cache")?;
+            // The chunks are regenerated as they were, unless it was the checksum which is
+            // damaged, so the checksums are written again.
+            write_chunk_checksums(path, &data, chunk_nodes)?;
+        }
+
+        Ok(Some(damaged))
+    }
+
     /// Read a single cache element at position `node`.
     pub fn read(&mut self, node: u32) -> Result<[u32; DEGREE]> {
         if self.cache.contains(node) {
+            self.counters.hit();
             return Ok(self.cache.read(node));
         }
+        self.counters.miss();
 
         // not in memory, shift cache
         ensure!(
@@ -398,16 +686,120 @@ impl ParentCache {
         let new_offset =
             (self.num_cache_entries - self.cache.len).min(self.cache.offset + self.cache.len);
         self.cache.shift(new_offset)?;
+        self.counters.evict();
 
         Ok(self.cache.read(node))
     }
 
     /// Resets the partial cache to the beginning.
     pub fn reset(&mut self) -> Result<()> {
+        if self.cache.offset != 0 {
+            self.counters.evict();
+        }
         self.cache.reset()
     }
 }
 
+/// Writes the parents of the nodes from `first_node` on into `data`, in parallel.
+fn generate_parents<H, G>(
+    graph: &StackedGraph<H, G>,
+    data: &mut [u8],
+    first_node: usize,
+) -> Result<()>
+where
+    H: Hasher,
+    G: Graph<H> + ParameterSetMetadata + Send + Sync,
+{
+    data.par_chunks_mut(DEGREE * NODE_BYTES)
+        .enumerate()
+        .try_for_each(|(i, entry)| -> Result<()> {
+            let node = first_node + i;
+            let mut parents = [0u32; DEGREE];
+            graph
+                .base_graph()
+                .parents(node, &mut parents[..BASE_DEGREE])?;
+            graph.generate_expanded_parents(node, &mut parents[BASE_DEGREE..]);
+
+            LittleEndian::write_u32_into(&parents, entry);
+            Ok(())
+        })
+}
+
+/// Returns the path of the chunk checksums of the parent cache at `path`.
+fn checksums_path(path: &Path) -> PathBuf {
+    path.with_extension("checksums")
+}
+
+/// Returns the SHA-256 digest of every chunk of `chunk_nodes` nodes of the parent cache `data`,
+/// which are hashed in parallel.
+fn chunk_checksums(data: &[u8], chunk_nodes: usize) -> Vec<[u8; 32]> {
+    data.par_chunks(chunk_nodes * DEGREE * NODE_BYTES)
+        .map(|chunk| {
+            let mut checksum = [0u8; 32];
+            checksum.copy_from_slice(&Sha256::digest(chunk));
+            checksum
+        })
+        .collect()
+}
+
+/// Writes the checksum of every chunk of `chunk_nodes` nodes of the parent cache `data` next to
+/// the cache at `path`. Unlike the digest of the whole cache, they tell which part of a damaged
+/// cache has to be regenerated, see `ParentCache::repair_chunks`.
+fn write_chunk_checksums(path: &Path, data: &[u8], chunk_nodes: usize) -> Result<()> {
+    let checksums = chunk_checksums(data, chunk_nodes);
+    let mut file_data = Vec::with_capacity(CHECKSUMS_HEADER_BYTES + checksums.len() * 32);
+    file_data.extend_from_slice(CHECKSUMS_MAGIC);
+    file_data.extend_from_slice(&(chunk_nodes as u64).to_le_bytes());
+    file_data.extend_from_slice(&(checksums.len() as u64).to_le_bytes());
+    for checksum in &checksums {
+        file_data.extend_from_slice(checksum);
+    }
+
+    let checksums_path = checksums_path(path);
+    let tmp_path = checksums_path.with_extension("checksums.tmp");
+    fs::write(&tmp_path, &file_data)
+        .with_context(|| format!("could not write {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, &checksums_path)
+        .with_context(|| format!("could not rename {}", tmp_path.display()))?;
+
+    Ok(())
+}
+
+/// Reads the chunk size and the checksums written by [`write_chunk_checksums`] for the parent cache
+/// at `path`, `None` if there are none or they are damaged.
+fn read_chunk_checksums(path: &Path) -> Option<(usize, Vec<[u8; 32]>)> {
+    let checksums_path = checksums_path(path);
+    let file_data = fs::read(&checksums_path).ok()?;
+    let valid = file_data.len() >= CHECKSUMS_HEADER_BYTES
+        && &file_data[..CHECKSUMS_MAGIC.len()] == CHECKSUMS_MAGIC;
+    let (chunk_nodes, count) = if valid {
+        (
+            LittleEndian::read_u64(&file_data[8..16]) as usize,
+            LittleEndian::read_u64(&file_data[16..24]) as usize,
+        )
+    } else {
+        (0, 0)
+    };
+    if chunk_nodes == 0 || count.checked_mul(32) != Some(file_data.len() - CHECKSUMS_HEADER_BYTES) {
+        warn!(
+            "parent cache: ignoring damaged checksums {}",
+            checksums_path.display()
+        );
+        return None;
+    }
+
+    let checksums = file_data[CHECKSUMS_HEADER_BYTES..]
+        .chunks(32)
+        .map(|chunk| {
+            let mut checksum = [0u8; 32];
+            checksum.copy_from_slice(chunk);
+            checksum
+        })
+        .collect();
+
+    Some((chunk_nodes, checksums))
+}
+
 fn parent_cache_dir_name() -> String {
     SETTINGS.parent_cache.clone()
 }
@@ -426,6 +818,109 @@ fn get_parent_cache_data(path: &Path) -> Option<&ParentCacheData> {
     PARENT_CACHE.get(&parent_cache_id(path))
 }
 
+/// Returns the path of the copy of the parent cache at `path` for the NUMA node `node`, see
+/// [`numa_local_parent_cache`].
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+pub(crate) fn numa_parent_cache_path(path: &Path, node: usize) -> PathBuf {
+    path.with_extension(format!("numa{}.cache", node))
+}
+
+/// Returns the copy of the parent cache at `path` for the NUMA node `node`, which is copied first
+/// if it does not exist yet.
+///
+/// The pages of a file are cached on the NUMA node of the thread which reads them first, so all
+/// labelings reading one parent cache read it from the memory of one node. A copy is meant to be
+/// written and read only by threads whose memory is bound to its node, so that its pages are
+/// cached there. It takes as much disk space as the parent cache itself.
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+pub(crate) fn numa_local_parent_cache(path: &Path, node: usize) -> Result<PathBuf> {
+    let copy_path = numa_parent_cache_path(path, node);
+    info!("using the parent cache for NUMA node {}", node);
+    copy_verified_parent_cache(path, &copy_path, SETTINGS.verify_cache)?;
+
+    Ok(copy_path)
+}
+
+/// Copies the parent cache at `path` to `copy_path` unless it is there already. With `verify`,
+/// which callers take from `verify_cache`, an existing copy is compared to the chunk checksums of
+/// the cache, and copied again if it does not match them, e.g. because it was copied from a
+/// damaged cache which was repaired since.
+fn copy_verified_parent_cache(path: &Path, copy_path: &Path, verify: bool) -> Result<()> {
+    copy_parent_cache(path, copy_path)?;
+    if !verify {
+        return Ok(());
+    }
+
+    if let Some((chunk_nodes, checksums)) = read_chunk_checksums(path) {
+        let file = File::open(copy_path)?;
+        let data = unsafe {
+            MmapOptions::new()
+                .map(&file)
+                .with_context(|| format!("could not mmap path={}", copy_path.display()))?
+        };
+        if chunk_checksums(&data, chunk_nodes) != checksums {
+            info!(
+                "parent cache: {} does not match its checksums, copying it again",
+                copy_path.display()
+            );
+            drop(data);
+            remove_file(copy_path)?;
+            copy_parent_cache(path, copy_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies the parent cache at `path` to `copy_path`, unless another process did already. A copy
+/// in progress is waited for, and an incomplete copy is an error.
+fn copy_parent_cache(path: &Path, copy_path: &Path) -> Result<()> {
+    let len = fs::metadata(path)
+        .with_context(|| format!("could not read metadata of {}", path.display()))?
+        .len();
+
+    if !copy_path.exists() {
+        match LockedFile::open_exclusive(&copy_path) {
+            Ok(mut copy) => {
+                info!(
+                    "copying parent cache {} to {}",
+                    path.display(),
+                    copy_path.display()
+                );
+                let copied = File::open(path)
+                    .and_then(|mut file| io::copy(&mut file, &mut copy))
+                    .and_then(|_| copy.as_ref().sync_data());
+                if let Err(err) = copied {
+                    drop(copy);
+                    let _ = remove_file(&copy_path);
+                    return Err(err).with_context(|| {
+                        format!("could not copy parent cache to {}", copy_path.display())
+                    });
+                }
+            }
+            // Another labeling is copying it, the lock below waits for it to be done.
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("could not create {}", copy_path.display()))
+            }
+        }
+    }
+
+    let copy = LockedFile::open_shared_read(&copy_path)
+        .with_context(|| format!("could not open {}", copy_path.display()))?;
+    let copy_len = copy.as_ref().metadata()?.len();
+    ensure!(
+        copy_len == len,
+        "parent cache copy {} has {} bytes, expected {}, remove it to copy it again",
+        copy_path.display(),
+        copy_len,
+        len
+    );
+
+    Ok(())
+}
+
 fn cache_path<H, G>(cache_entries: u32, graph: &StackedGraph<H, G>) -> PathBuf
 where
     H: Hasher,
@@ -491,6 +986,63 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_generate_with_progress() {
+        init_logger();
+        let nodes = 64u32;
+        let graph = StackedBucketGraph::<PoseidonHasher>::new_stacked(
+            nodes as usize,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            [0u8; 32],
+            ApiVersion::V1_1_0,
+        )
+        .expect("new_stacked failure");
+
+        let dir = tempfile::tempdir().expect("tempdir failure");
+        let path = dir.path().join("parent.cache");
+        let reports = Mutex::new(Vec::new());
+        let callback = |progress: ParentCacheProgress| {
+            reports.lock().expect("reports poisoned").push(progress);
+        };
+        let mut cache = ParentCache::generate_inner(
+            nodes,
+            nodes,
+            &graph,
+            &path,
+            Some(ParentCacheProgressCallback {
+                interval: 24,
+                callback: &callback,
+            }),
+        )
+        .expect("parent cache generate failure");
+
+        assert_eq!(
+            cache.prewarm().expect("prewarm failure"),
+            (nodes as usize * DEGREE * NODE_BYTES) as u64
+        );
+
+        let reports = reports.into_inner().expect("reports poisoned");
+        let done: Vec<u32> = reports.iter().map(|progress| progress.nodes_done).collect();
+        assert_eq!(done, vec![24, 48, 64]);
+        assert!(reports.iter().all(|progress| progress.nodes_total == nodes));
+        assert_eq!(
+            reports[2].estimated_remaining(),
+            Some(Duration::from_secs(0))
+        );
+
+        for node in 0..nodes {
+            let mut expected_parents = [0; DEGREE];
+            graph
+                .parents(node as usize, &mut expected_parents)
+                .expect("graph parents failure");
+            assert_eq!(
+                cache.read(node).expect("cache read failure"),
+                expected_parents
+            );
+        }
+    }
+
     #[test]
     #[cfg(feature = "isolated-testing")]
     fn test_parallel_generation_and_read_partial_range_v1_0() {
@@ -630,4 +1182,118 @@ mod tests {
             assert_eq!(expected_parents, parents);
         }
     }
+
+    #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+    #[test]
+    fn test_numa_local_parent_cache() {
+        let dir = tempfile::tempdir().expect("tempdir failure");
+        let path = dir.path().join("v28-sdr-parent-test.cache");
+        let data: Vec<u8> = (0..4096u32).map(|i| i as u8).collect();
+        fs::write(&path, &data).expect("failed to write parent cache");
+
+        let copy_path = numa_local_parent_cache(&path, 1).expect("numa_local_parent_cache failure");
+        assert_eq!(copy_path, numa_parent_cache_path(&path, 1));
+        assert_ne!(copy_path, numa_parent_cache_path(&path, 0));
+        assert_eq!(fs::read(&copy_path).expect("failed to read copy"), data);
+
+        // An existing copy is used as it is, unless it is incomplete.
+        assert_eq!(
+            numa_local_parent_cache(&path, 1).expect("numa_local_parent_cache failure"),
+            copy_path
+        );
+        fs::write(&copy_path, &data[..100]).expect("failed to truncate copy");
+        assert!(numa_local_parent_cache(&path, 1).is_err());
+        assert!(numa_local_parent_cache(&dir.path().join("missing.cache"), 0).is_err());
+    }
+
+    #[test]
+    fn test_copy_verified_parent_cache() {
+        let dir = tempfile::tempdir().expect("tempdir failure");
+        let path = dir.path().join("v28-sdr-parent-test.cache");
+        let copy_path = dir.path().join("v28-sdr-parent-test.copy.cache");
+        let data: Vec<u8> = (0..DEGREE * NODE_BYTES * 8).map(|i| i as u8).collect();
+        fs::write(&path, &data).expect("failed to write parent cache");
+        write_chunk_checksums(&path, &data, 2).expect("failed to write checksums");
+
+        copy_verified_parent_cache(&path, &copy_path, true).expect("copy failure");
+        assert_eq!(fs::read(&copy_path).expect("failed to read copy"), data);
+
+        // A copy of the same length which does not match the checksums is only replaced when it
+        // is verified.
+        let mut stale = data.clone();
+        stale[DEGREE * NODE_BYTES * 5] ^= 1;
+        fs::write(&copy_path, &stale).expect("failed to write copy");
+        copy_verified_parent_cache(&path, &copy_path, false).expect("copy failure");
+        assert_eq!(fs::read(&copy_path).expect("failed to read copy"), stale);
+        copy_verified_parent_cache(&path, &copy_path, true).expect("copy failure");
+        assert_eq!(fs::read(&copy_path).expect("failed to read copy"), data);
+    }
+
+    #[test]
+    fn test_repair_chunks() {
+        init_logger();
+        let nodes = 64u32;
+        let graph = StackedBucketGraph::<PoseidonHasher>::new_stacked(
+            nodes as usize,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            [0u8; 32],
+            ApiVersion::V1_1_0,
+        )
+        .expect("new_stacked failure");
+
+        let dir = tempfile::tempdir().expect("tempdir failure");
+        let path = dir.path().join("parent.cache");
+        ParentCache::generate(nodes, nodes, &graph, &path).expect("parent cache generate failure");
+        let expected = fs::read(&path).expect("failed to read parent cache");
+        assert_eq!(
+            read_chunk_checksums(&path).expect("no checksums").1.len(),
+            1
+        );
+
+        // Chunks of 16 nodes, so that a damaged node only regenerates its chunk.
+        write_chunk_checksums(&path, &expected, 16).expect("write_chunk_checksums failure");
+        assert_eq!(
+            ParentCache::repair_chunks(&graph, &path).expect("repair_chunks failure"),
+            Some(Vec::new())
+        );
+
+        let mut damaged = expected.clone();
+        for node in [5usize, 40, 41].iter() {
+            damaged[node * DEGREE * NODE_BYTES] ^= 0xff;
+        }
+        fs::write(&path, &damaged).expect("failed to damage parent cache");
+        // A cache which is in use is not written to.
+        {
+            let _reader = LockedFile::open_shared_read(&path).expect("failed to lock cache");
+            assert_eq!(
+                ParentCache::repair_chunks(&graph, &path).expect("repair_chunks failure"),
+                None
+            );
+            assert_eq!(
+                fs::read(&path).expect("failed to read parent cache"),
+                damaged
+            );
+        }
+        assert_eq!(
+            ParentCache::repair_chunks(&graph, &path).expect("repair_chunks failure"),
+            Some(vec![0, 2])
+        );
+        assert_eq!(
+            fs::read(&path).expect("failed to read parent cache"),
+            expected
+        );
+
+        // Without valid checksums, the cache can only be verified as a whole.
+        fs::write(checksums_path(&path), b"damaged").expect("failed to damage checksums");
+        assert_eq!(
+            ParentCache::repair_chunks(&graph, &path).expect("repair_chunks failure"),
+            None
+        );
+        fs::remove_file(checksums_path(&path)).expect("failed to remove checksums");
+        assert_eq!(
+            ParentCache::repair_chunks(&graph, &path).expect("repair_chunks failure"),
+            None
+        );
+    }
 }
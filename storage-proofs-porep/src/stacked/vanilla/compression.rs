@@ -0,0 +1,339 @@
+//! Experimental zstd compression of the label layers of a sector, see [`compress_layers`].
+//!
+//! Real labels are SHA-256 digests, which do not get smaller with zstd, so this saves no disk space
+//! on real sectors and is off by default.
+//!
+//! Compressing needs the `cache-compression` feature. Compressed files are recognized by their
+//! header either way, so that a build without the feature fails to read them instead of reading
+//! the zstd stream as labels.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "cache-compression")]
+use std::{
+    fs,
+    io::{BufReader, BufWriter, Write},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+#[cfg(not(feature = "cache-compression"))]
+use anyhow::bail;
+#[cfg(feature = "cache-compression")]
+use anyhow::ensure;
+use anyhow::Context;
+#[cfg(feature = "cache-compression")]
+use byteorder::{ByteOrder, LittleEndian};
+use fs2::FileExt;
+#[cfg(feature = "cache-compression")]
+use log::info;
+use merkletree::store::StoreConfig;
+use storage_proofs_core::error::Result;
+
+use crate::stacked::vanilla::layer_file::layer_file_path;
+
+/// Identifies a compressed file, followed by its uncompressed length and the zstd stream.
+const COMPRESSED_MAGIC: &[u8; 8] = b"FILZSTD1";
+
+#[cfg(feature = "cache-compression")]
+const HEADER_BYTES: usize = COMPRESSED_MAGIC.len() + 8;
+
+/// The zstd level, which favors speed over ratio since the layers are mostly random.
+#[cfg(feature = "cache-compression")]
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Numbers the temporary files of this process, see [`replace_file`].
+#[cfg(feature = "cache-compression")]
+static TMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns whether the file at `path` was compressed by [`compress_cache_file`].
+pub fn is_compressed(path: &Path) -> Result<bool> {
+    let mut file = File::open(path).with_context(|| format!("could not open {:?}", path))?;
+    let mut magic = [0u8; 8];
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == COMPRESSED_MAGIC),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err).with_context(|| format!("could not read {:?}", path)),
+    }
+}
+
+/// Replaces the file at `path` with a compressed copy, unless it already is one.
+///
+/// The copy is written next to the file first, so it is only replaced once the copy is complete.
+#[cfg(feature = "cache-compression")]
+pub fn compress_cache_file(path: &Path) -> Result<()> {
+    let _lock = lock_file(path)?;
+    if is_compressed(path)? {
+        return Ok(());
+    }
+
+    let len = fs::metadata(path)
+        .with_context(|| format!("could not read metadata of {:?}", path))?
+        .len();
+    let mut header = [0u8; HEADER_BYTES];
+    header[..COMPRESSED_MAGIC.len()].copy_from_slice(COMPRESSED_MAGIC);
+    LittleEndian::write_u64(&mut header[COMPRESSED_MAGIC.len()..], len);
+
+    replace_file(path, |reader, writer| {
+        writer.write_all(&header)?;
+        let mut encoder = zstd::Encoder::new(writer, COMPRESSION_LEVEL)?;
+        io::copy(reader, &mut encoder)?;
+        encoder.finish()?.flush()
+    })?;
+
+    info!(
+        "compressed {:?} from {} to {} bytes",
+        path,
+        len,
+        fs::metadata(path)?.len()
+    );
+
+    Ok(())
+}
+
+/// Replaces the file at `path` with its uncompressed contents, if it was compressed. Returns
+/// whether it was.
+///
+/// Concurrent callers wait for the first one to decompress the file, and then find it
+/// decompressed.
+pub fn decompress_cache_file(path: &Path) -> Result<bool> {
+    if !is_compressed(path)? {
+        return Ok(false);
+    }
+    let _lock = lock_file(path)?;
+    if !is_compressed(path)? {
+        return Ok(false);
+    }
+
+    decompress_locked(path)
+}
+
+#[cfg(not(feature = "cache-compression"))]
+fn decompress_locked(path: &Path) -> Result<bool> {
+    bail!(
+        "{:?} is compressed, which needs the cache-compression feature to be read",
+        path
+    );
+}
+
+#[cfg(feature = "cache-compression")]
+fn decompress_locked(path: &Path) -> Result<bool> {
+    let mut expected = 0;
+    replace_file(path, |reader, writer| {
+        let mut header = [0u8; HEADER_BYTES];
+        reader.read_exact(&mut header)?;
+        expected = LittleEndian::read_u64(&header[COMPRESSED_MAGIC.len()..]);
+        let mut decoder = zstd::Decoder::new(reader)?;
+        io::copy(&mut decoder, writer)?;
+        writer.flush()
+    })?;
+
+    let len = fs::metadata(path)?.len();
+    ensure!(
+        len == expected,
+        "decompressed {:?} to {} bytes, expected {}",
+        path,
+        len,
+        expected
+    );
+    info!("decompressed {:?} to {} bytes", path, len);
+
+    Ok(true)
+}
+
+/// Takes an exclusive lock on the file at `path`, which is released once the returned file is
+/// dropped.
+///
+/// [`replace_file`] renames another file over `path` while the lock is held, so whoever waited
+/// for the lock has to look at `path` again rather than at the file they locked.
+fn lock_file(path: &Path) -> Result<File> {
+    let file = File::open(path).with_context(|| format!("could not open {:?}", path))?;
+    file.lock_exclusive()
+        .with_context(|| format!("could not lock {:?}", path))?;
+
+    Ok(file)
+}
+
+/// Removes the file at its path once dropped, unless it was renamed.
+#[cfg(feature = "cache-compression")]
+struct TmpFile(Option<PathBuf>);
+
+#[cfg(feature = "cache-compression")]
+impl Drop for TmpFile {
+    fn drop(&mut self) {
+        if let Some(path) = self.0.take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Writes the contents `copy` produces from the file at `path` into a temporary file, which then
+/// replaces it.
+///
+/// The name of the temporary file is unique to the call, and the file is removed again if the
+/// copy fails or panics.
+#[cfg(feature = "cache-compression")]
+fn replace_file<F>(path: &Path, copy: F) -> Result<()>
+where
+    F: FnOnce(&mut BufReader<File>, &mut BufWriter<File>) -> io::Result<()>,
+{
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("{:?} has no file name", path))?
+        .to_string_lossy();
+    let tmp_path = path.with_file_name(format!(
+        "{}.{}.{}.tmp",
+        file_name,
+        process::id(),
+        TMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut tmp = TmpFile(Some(tmp_path.clone()));
+
+    let mut reader =
+        BufReader::new(File::open(path).with_context(|| format!("could not open {:?}", path))?);
+    let mut writer = BufWriter::new(
+        File::create(&tmp_path).with_context(|| format!("could not create {:?}", tmp_path))?,
+    );
+    copy(&mut reader, &mut writer).with_context(|| format!("could not rewrite {:?}", path))?;
+    writer.flush()?;
+    writer.get_ref().sync_data()?;
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("could not rename {:?} to {:?}", tmp_path, path))?;
+    tmp.0 = None;
+
+    Ok(())
+}
+
+/// Returns the files holding the layers stored as `configs`, which is the layer file if they were
+/// consolidated.
+fn layer_paths(configs: &[StoreConfig]) -> Vec<PathBuf> {
+    if let Some(config) = configs.first() {
+        let path = layer_file_path(&config.path);
+        if path.exists() {
+            return vec![path];
+        }
+    }
+
+    configs
+        .iter()
+        .map(|config| PathBuf::from(StoreConfig::data_path(&config.path, &config.id)))
+        .collect()
+}
+
+/// Compresses the label layers stored as `configs`, one file at a time.
+///
+/// Every reader of the layers decompresses them again before opening them, see
+/// [`decompress_layers`], so compression trades the disk space of cold sectors for the time to
+/// decompress them once they are read. The trees are not compressed, as they are read at random,
+/// so neither `comm_r` nor PoSt, which only reads tree_r_last, are affected.
+#[cfg(feature = "cache-compression")]
+pub fn compress_layers(configs: &[StoreConfig]) -> Result<()> {
+    for path in layer_paths(configs) {
+        compress_cache_file(&path)?;
+    }
+
+    Ok(())
+}
+
+/// Decompresses those of the label layers stored as `configs` which were compressed by
+/// [`compress_layers`], in place.
+pub fn decompress_layers(configs: &[StoreConfig]) -> Result<()> {
+    for path in layer_paths(configs) {
+        if path.exists() {
+            decompress_cache_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "cache-compression"))]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use rand::{Rng, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+    use storage_proofs_core::{cache_key::CacheKey, TEST_SEED};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_compress_layers() {
+        let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+        let cache_dir = tempdir().expect("tempdir failure");
+
+        let mut configs = Vec::new();
+        let mut layers = Vec::new();
+        for layer in 1..=2 {
+            let config = StoreConfig::new(cache_dir.path(), CacheKey::label_layer(layer), 0);
+            // Half of every layer is zeros, so that it compresses.
+            let labels: Vec<u8> = (0..4096)
+                .map(|i| if i % 2 == 0 { rng.gen() } else { 0 })
+                .collect();
+            fs::write(StoreConfig::data_path(&config.path, &config.id), &labels)
+                .expect("failed to write layer");
+            configs.push(config);
+            layers.push(labels);
+        }
+
+        compress_layers(&configs).expect("compress_layers failure");
+        // Compressing twice leaves the compressed files as they are.
+        compress_layers(&configs).expect("compress_layers failure");
+        for (config, labels) in configs.iter().zip(&layers) {
+            let path = PathBuf::from(StoreConfig::data_path(&config.path, &config.id));
+            assert!(is_compressed(&path).expect("is_compressed failure"));
+            assert!(fs::metadata(&path).expect("metadata failure").len() < labels.len() as u64);
+        }
+
+        decompress_layers(&configs).expect("decompress_layers failure");
+        for (config, labels) in configs.iter().zip(&layers) {
+            let path = PathBuf::from(StoreConfig::data_path(&config.path, &config.id));
+            assert!(!is_compressed(&path).expect("is_compressed failure"));
+            assert_eq!(&fs::read(&path).expect("failed to read layer"), labels);
+        }
+        // No temporary file is left behind.
+        assert_eq!(
+            fs::read_dir(cache_dir.path())
+                .expect("read_dir failure")
+                .count(),
+            configs.len()
+        );
+    }
+
+    #[test]
+    fn test_decompress_concurrently() {
+        let cache_dir = tempdir().expect("tempdir failure");
+        let path = cache_dir.path().join("layer.dat");
+        let labels = vec![7u8; 4096];
+        fs::write(&path, &labels).expect("failed to write layer");
+        compress_cache_file(&path).expect("compress_cache_file failure");
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let path = path.clone();
+                thread::spawn(move || decompress_cache_file(&path))
+            })
+            .collect();
+        let decompressed = readers
+            .into_iter()
+            .map(|reader| {
+                reader
+                    .join()
+                    .expect("reader panicked")
+                    .expect("decompress_cache_file failure")
+            })
+            .filter(|&decompressed| decompressed)
+            .count();
+        // Exactly one of the readers decompressed the file, the others found it decompressed.
+        assert_eq!(decompressed, 1);
+        assert_eq!(fs::read(&path).expect("failed to read layer"), labels);
+        assert_eq!(
+            fs::read_dir(cache_dir.path())
+                .expect("read_dir failure")
+                .count(),
+            1
+        );
+    }
+}
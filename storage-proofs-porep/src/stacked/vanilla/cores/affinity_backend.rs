@@ -0,0 +1,460 @@
+//! The core binding without hwloc, see the `multicore-sdr-affinity` feature.
+//!
+//! Every processing unit the process may run on is treated as a core of its own, so the core
+//! groups are consecutive processing units, which may include SMT siblings, and there are no
+//! caches to group by. Threads are bound with `sched_setaffinity` on Linux and
+//! `SetThreadGroupAffinity` on Windows, which bind them to a CPU but leave the placement of their
+//! memory to the kernel. On other systems, the threads are not bound at all.
+//!
+//! Windows splits machines with more than 64 logical processors into processor groups, and a
+//! thread can only run in one group at a time. The processing units are numbered
+//! `group * 64 + number` here, and every thread is bound to one of them in its group. The cores
+//! are grouped by the NUMA node Windows reports for them, and a bound thread's ideal processor
+//! is set to its processing unit, so that the kernel keeps scheduling it there and allocates its
+//! memory from that node.
+
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::{ensure, Result};
+use lazy_static::lazy_static;
+
+#[cfg(target_os = "windows")]
+use winapi::winnt::{GROUP_AFFINITY, PROCESSOR_NUMBER};
+
+use super::{CoreDomain, CoreIndex, CoreKind, CoreTopology, ThreadId};
+
+lazy_static! {
+    static ref TOPOLOGY: Mutex<AffinityTopology> = Mutex::new(AffinityTopology::new());
+}
+
+/// The processing units a thread is bound to, by their OS index.
+pub type Binding = Vec<usize>;
+
+/// The processing units the process may run on, which are the cores of this backend.
+#[derive(Debug)]
+pub struct AffinityTopology {
+    pus: Vec<usize>,
+    /// The NUMA node of every processing unit, `None` if it is unknown.
+    numa_nodes: Vec<Option<usize>>,
+}
+
+impl AffinityTopology {
+    fn new() -> Self {
+        // Without an affinity mask, the processing units are assumed to be numbered from 0.
+        let pus: Vec<usize> = process_affinity().unwrap_or_else(|| (0..num_cpus::get()).collect());
+        let numa_nodes = pus.iter().map(|pu| numa_node(*pu)).collect();
+
+        AffinityTopology { pus, numa_nodes }
+    }
+
+    fn pu(&self, index: CoreIndex) -> Result<usize> {
+        ensure!(
+            index.0 < self.pus.len(),
+            "idx ({}) out of range for {} cores",
+            index.0,
+            self.pus.len()
+        );
+
+        Ok(self.pus[index.0])
+    }
+}
+
+/// Locks the topology of the machine.
+///
+/// The affinity mask of the process is read once, when the topology is first used, so before any
+/// thread is bound with it. The mask of a bound thread, which may be the main one, only has the
+/// core it is bound to.
+pub fn topology() -> MutexGuard<'static, AffinityTopology> {
+    TOPOLOGY.lock().expect("poisoned lock")
+}
+
+impl CoreTopology for AffinityTopology {
+    fn core_count(&self) -> Result<usize> {
+        Ok(self.pus.len())
+    }
+
+    fn pu_count(&self, index: CoreIndex) -> Result<usize> {
+        self.pu(index).map(|_| 1)
+    }
+
+    fn numa_memory(&self) -> Result<Vec<(usize, u64)>> {
+        Ok(numa_memory())
+    }
+
+    fn core_domain(&self, index: CoreIndex) -> Result<CoreDomain> {
+        self.pu(index)?;
+
+        Ok(CoreDomain {
+            numa_node: self.numa_nodes[index.0],
+            l3_cache: None,
+        })
+    }
+
+    fn is_allowed(&self, index: CoreIndex) -> Result<bool> {
+        // The cores are those of the affinity mask in the first place.
+        self.pu(index).map(|_| true)
+    }
+
+    fn core_kind(&self, index: CoreIndex) -> Result<CoreKind> {
+        self.pu(index).map(|_| CoreKind::Performance)
+    }
+}
+
+/// Returns the processing unit to bind to for the core at `index`. There are no SMT siblings to
+/// bind to, as every processing unit is a core of its own.
+pub fn core_binding(
+    topo: &AffinityTopology,
+    index: CoreIndex,
+    smt_sibling: bool,
+) -> Result<Binding> {
+    ensure!(!smt_sibling, "core at index {} has no SMT sibling", index.0);
+
+    Ok(vec![topo.pu(index)?])
+}
+
+/// Returns the binding to all processing units the process may run on, which are the cores of
+/// this backend.
+pub fn process_binding(topo: &AffinityTopology) -> Option<Binding> {
+    Some(topo.pus.clone()).filter(|pus| !pus.is_empty())
+}
+
+/// Returns the binding of the current thread, `None` if it cannot be read. Only the current
+/// thread can be bound by this backend, which is the one `bind_core` binds.
+pub fn thread_binding(_topo: &AffinityTopology, _tid: ThreadId) -> Option<Binding> {
+    current_affinity()
+}
+
+/// Binds the current thread to `binding`, and prefers the memory of its NUMA node with
+/// `bind_memory` where the system needs to be told to. Returns a description of every binding
+/// which failed.
+pub fn set_thread_binding(
+    _topo: &mut AffinityTopology,
+    _tid: ThreadId,
+    binding: &Binding,
+    bind_memory: bool,
+) -> Vec<String> {
+    let mut failures = Vec::new();
+    if let Err(err) = set_current_affinity(binding) {
+        failures.push(format!("failed to bind CPU: {:?}", err));
+    }
+    if bind_memory {
+        if let Err(err) = prefer_local_memory(binding) {
+            failures.push(format!("failed to bind memory: {:?}", err));
+        }
+    }
+
+    failures
+}
+
+/// Returns the affinity mask of the process, as `sched_getaffinity` reports it for the main
+/// thread. It is only read by `AffinityTopology::new`, see `topology`.
+#[cfg(target_os = "linux")]
+fn process_affinity() -> Option<Vec<usize>> {
+    linux_affinity(unsafe { libc::getpid() })
+}
+
+#[cfg(target_os = "linux")]
+fn current_affinity() -> Option<Vec<usize>> {
+    linux_affinity(0)
+}
+
+#[cfg(target_os = "linux")]
+fn linux_affinity(pid: libc::pid_t) -> Option<Vec<usize>> {
+    let mut mask: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let res =
+        unsafe { libc::sched_getaffinity(pid, std::mem::size_of::<libc::cpu_set_t>(), &mut mask) };
+    if res != 0 {
+        return None;
+    }
+
+    Some(
+        (0..libc::CPU_SETSIZE as usize)
+            .filter(|pu| unsafe { libc::CPU_ISSET(*pu, &mask) })
+            .collect(),
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn set_current_affinity(pus: &[usize]) -> io::Result<()> {
+    let mut mask: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for pu in pus {
+        unsafe { libc::CPU_SET(*pu, &mut mask) };
+    }
+    let res = unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mask) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// The number of processing units of a processor group, i.e. the bits of an affinity mask.
+#[cfg(target_os = "windows")]
+const GROUP_PUS: usize = 64;
+
+#[cfg(target_os = "windows")]
+fn processor_number(pu: usize) -> PROCESSOR_NUMBER {
+    let mut number: PROCESSOR_NUMBER = unsafe { std::mem::zeroed() };
+    number.Group = (pu / GROUP_PUS) as _;
+    number.Number = (pu % GROUP_PUS) as _;
+
+    number
+}
+
+/// Returns the processing units of the affinity mask `mask` of the processor group `group`.
+#[cfg(target_os = "windows")]
+fn group_pus(group: usize, mask: usize) -> Vec<usize> {
+    (0..GROUP_PUS)
+        .filter(|number| mask & (1 << number) != 0)
+        .map(|number| group * GROUP_PUS + number)
+        .collect()
+}
+
+/// Returns the processing units of all processor groups. A process can only restrict its
+/// affinity within the group it starts in, e.g. with `start /affinity`, so if the affinity mask
+/// of that group has fewer than all of its processing units, only those of the mask are returned.
+#[cfg(target_os = "windows")]
+fn process_affinity() -> Option<Vec<usize>> {
+    let groups = unsafe { kernel32::GetActiveProcessorGroupCount() } as usize;
+    if groups == 0 {
+        return None;
+    }
+    let full_mask =
+        |group: usize| match unsafe { kernel32::GetActiveProcessorCount(group as _) } as usize {
+            count if count >= GROUP_PUS => !0,
+            count => (1 << count) - 1,
+        };
+
+    let (mut process, mut system) = (0, 0);
+    let (mut group_count, mut group) = (1, 0);
+    let restricted = unsafe {
+        let handle = kernel32::GetCurrentProcess();
+        kernel32::GetProcessAffinityMask(handle, &mut process, &mut system) != 0
+            && kernel32::GetProcessGroupAffinity(handle, &mut group_count, &mut group) != 0
+    } && process as usize != full_mask(group as usize);
+    if restricted {
+        return Some(group_pus(group as usize, process as usize));
+    }
+
+    Some(
+        (0..groups)
+            .flat_map(|group| group_pus(group, full_mask(group)))
+            .collect(),
+    )
+}
+
+#[cfg(target_os = "windows")]
+fn current_affinity() -> Option<Vec<usize>> {
+    let mut affinity: GROUP_AFFINITY = unsafe { std::mem::zeroed() };
+    let res =
+        unsafe { kernel32::GetThreadGroupAffinity(kernel32::GetCurrentThread(), &mut affinity) };
+    if res == 0 {
+        return None;
+    }
+
+    Some(group_pus(affinity.Group as usize, affinity.Mask as usize))
+}
+
+#[cfg(target_os = "windows")]
+fn set_current_affinity(pus: &[usize]) -> io::Result<()> {
+    let group = match pus.first() {
+        Some(pu) => pu / GROUP_PUS,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no processing units to bind to",
+            ))
+        }
+    };
+    if pus.iter().any(|pu| pu / GROUP_PUS != group) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the processing units are in more than one processor group",
+        ));
+    }
+
+    let mut affinity: GROUP_AFFINITY = unsafe { std::mem::zeroed() };
+    affinity.Group = group as _;
+    affinity.Mask = pus
+        .iter()
+        .fold(0usize, |mask, pu| mask | 1 << (pu % GROUP_PUS)) as _;
+    let res = unsafe {
+        kernel32::SetThreadGroupAffinity(
+            kernel32::GetCurrentThread(),
+            &affinity,
+            std::ptr::null_mut(),
+        )
+    };
+    if res == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+fn numa_node(pu: usize) -> Option<usize> {
+    let mut number = processor_number(pu);
+    let mut node = 0;
+    let res = unsafe { kernel32::GetNumaProcessorNodeEx(&mut number, &mut node) };
+    if res == 0 {
+        return None;
+    }
+
+    Some(node as usize)
+}
+
+/// Returns the memory of every NUMA node. Windows only reports the memory which is available,
+/// rather than all memory of a node.
+#[cfg(target_os = "windows")]
+fn numa_memory() -> Vec<(usize, u64)> {
+    let mut highest = 0;
+    if unsafe { kernel32::GetNumaHighestNodeNumber(&mut highest) } == 0 {
+        return Vec::new();
+    }
+
+    (0..=highest as usize)
+        .filter_map(|node| {
+            let mut bytes = 0;
+            let res = unsafe { kernel32::GetNumaAvailableMemoryNodeEx(node as _, &mut bytes) };
+            if res == 0 {
+                return None;
+            }
+
+            Some((node, bytes as u64))
+        })
+        .collect()
+}
+
+/// Makes the first of `pus` the ideal processor of the current thread. Windows allocates the
+/// memory of a thread from the NUMA node of the processor it runs on where it can, and keeps
+/// scheduling it on its ideal processor.
+#[cfg(target_os = "windows")]
+fn prefer_local_memory(pus: &[usize]) -> io::Result<()> {
+    let mut number = match pus.first() {
+        Some(pu) => processor_number(*pu),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no processing units to prefer",
+            ))
+        }
+    };
+    let res = unsafe {
+        kernel32::SetThreadIdealProcessorEx(
+            kernel32::GetCurrentThread(),
+            &mut number,
+            std::ptr::null_mut(),
+        )
+    };
+    if res == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn process_affinity() -> Option<Vec<usize>> {
+    None
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn current_affinity() -> Option<Vec<usize>> {
+    None
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn set_current_affinity(_pus: &[usize]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "binding threads is not supported on this system",
+    ))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn numa_node(_pu: usize) -> Option<usize> {
+    None
+}
+
+#[cfg(not(target_os = "windows"))]
+fn numa_memory() -> Vec<(usize, u64)> {
+    Vec::new()
+}
+
+/// Linux allocates the memory a thread touches from the NUMA node it runs on by default, so
+/// binding it to a processing unit is all it takes.
+#[cfg(not(target_os = "windows"))]
+fn prefer_local_memory(_pus: &[usize]) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::core_groups;
+
+    #[test]
+    fn test_affinity_topology() {
+        // A process restricted to processing units 2-3 and 6-9.
+        let topo = AffinityTopology {
+            pus: vec![2, 3, 6, 7, 8, 9],
+            numa_nodes: vec![None; 6],
+        };
+        assert_eq!(topo.core_count().expect("core_count failure"), 6);
+        assert_eq!(
+            core_binding(&topo, CoreIndex(2), false).expect("core_binding failure"),
+            vec![6]
+        );
+        assert!(core_binding(&topo, CoreIndex(2), true).is_err());
+        assert!(core_binding(&topo, CoreIndex(6), false).is_err());
+
+        // The groups are consecutive cores, whichever processing units they are, the last group
+        // first as with hwloc.
+        let groups = core_groups(&topo, 3, true).expect("core_groups failure");
+        let groups: Vec<Vec<usize>> = groups
+            .into_iter()
+            .map(|group| {
+                group
+                    .into_inner()
+                    .expect("poisoned lock")
+                    .iter()
+                    .map(|core| core.0)
+                    .collect()
+            })
+            .collect();
+        assert_eq!(groups, vec![vec![3, 4, 5], vec![0, 1, 2]]);
+    }
+
+    #[test]
+    fn test_affinity_topology_numa() {
+        // Two processor groups of a NUMA node each, numbered from 0 and 64.
+        let topo = AffinityTopology {
+            pus: vec![0, 1, 2, 3, 4, 64, 65, 66, 67, 68],
+            numa_nodes: [vec![Some(0); 5], vec![Some(1); 5]].concat(),
+        };
+        assert_eq!(
+            topo.core_domain(CoreIndex(6))
+                .expect("core_domain failure")
+                .numa_node,
+            Some(1)
+        );
+
+        // No group spans the NUMA nodes, so the last core of every node is left out.
+        let groups: Vec<Vec<usize>> = core_groups(&topo, 2, true)
+            .expect("core_groups failure")
+            .into_iter()
+            .map(|group| {
+                group
+                    .into_inner()
+                    .expect("poisoned lock")
+                    .iter()
+                    .map(|core| core.0)
+                    .collect()
+            })
+            .collect();
+        assert_eq!(groups, vec![vec![7, 8], vec![5, 6], vec![2, 3], vec![0, 1]]);
+    }
+}
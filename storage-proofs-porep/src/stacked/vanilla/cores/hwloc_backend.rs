@@ -0,0 +1,262 @@
+//! The core binding with the topology hwloc reports, see the `multicore-sdr` feature.
+
+use std::fs;
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::{ensure, format_err, Result};
+use hwloc::{Bitmap, ObjectType, Topology, TopologyObject, CPUBIND_THREAD};
+use lazy_static::lazy_static;
+use log::{debug, warn};
+
+use super::{parse_core_list, CoreDomain, CoreIndex, CoreKind, CoreTopology, ThreadId};
+
+lazy_static! {
+    static ref TOPOLOGY: Mutex<Topology> = Mutex::new(Topology::new());
+    /// The OS indexes of the processing units in the affinity mask of the process, sorted, `None`
+    /// if it cannot be read. It is read along with the topology, see `topology`.
+    static ref PROCESS_AFFINITY: Option<Vec<usize>> = process_affinity();
+}
+
+/// The processing units a thread is bound to.
+pub type Binding = Bitmap;
+
+/// Locks the topology of the machine.
+///
+/// The affinity mask of the process is read before the topology is first used, so before any
+/// thread is bound with it. The mask of a bound thread, which may be the main one, only has the
+/// core it is bound to.
+pub fn topology() -> MutexGuard<'static, Topology> {
+    lazy_static::initialize(&PROCESS_AFFINITY);
+    TOPOLOGY.lock().expect("poisoned lock")
+}
+
+impl CoreTopology for Topology {
+    fn core_count(&self) -> Result<usize> {
+        self.objects_with_type(&ObjectType::Core)
+            .map(|all_cores| all_cores.len())
+            .map_err(|err| format_err!("failed to get cores: {:?}", err))
+    }
+
+    fn pu_count(&self, index: CoreIndex) -> Result<usize> {
+        let cpuset = usable_cpuset(get_core_by_index(self, index)?, index)?;
+
+        Ok(cpuset.weight().max(0) as usize)
+    }
+
+    fn numa_memory(&self) -> Result<Vec<(usize, u64)>> {
+        let nodes = self
+            .objects_with_type(&ObjectType::NUMANode)
+            .map_err(|err| format_err!("failed to get NUMA nodes: {:?}", err))?;
+
+        Ok(nodes
+            .iter()
+            .map(|node| (node.logical_index() as usize, node.memory().local_memory()))
+            .collect())
+    }
+
+    fn core_domain(&self, index: CoreIndex) -> Result<CoreDomain> {
+        let mut domain = CoreDomain::default();
+        let mut ancestor = get_core_by_index(self, index)?.parent();
+        while let Some(object) = ancestor {
+            match object.object_type() {
+                ObjectType::NUMANode if domain.numa_node.is_none() => {
+                    domain.numa_node = Some(object.logical_index() as usize);
+                }
+                ObjectType::Cache
+                    if domain.l3_cache.is_none()
+                        && object
+                            .cache_attributes()
+                            .map_or(false, |cache| cache.depth() == 3) =>
+                {
+                    domain.l3_cache = Some(object.logical_index() as usize);
+                }
+                _ => {}
+            }
+            ancestor = object.parent();
+        }
+
+        Ok(domain)
+    }
+
+    fn is_allowed(&self, index: CoreIndex) -> Result<bool> {
+        Ok(!usable_cpuset(get_core_by_index(self, index)?, index)?.is_empty())
+    }
+
+    fn core_kind(&self, index: CoreIndex) -> Result<CoreKind> {
+        let cpuset = get_core_by_index(self, index)?
+            .cpuset()
+            .ok_or_else(|| format_err!("no cpuset for core at index {}", index.0))?;
+        let efficiency = (cpuset.first().max(0)..=cpuset.last())
+            .any(|pu| cpuset.is_set(pu as u32) && EFFICIENCY_PUS.contains(&(pu as usize)));
+
+        Ok(if efficiency {
+            CoreKind::Efficiency
+        } else {
+            CoreKind::Performance
+        })
+    }
+}
+
+/// The sysfs file listing the processing units of the efficiency cores of a hybrid Intel CPU,
+/// which Linux reports as the PMU of the `cpu_atom` cores.
+const EFFICIENCY_PUS_PATH: &str = "/sys/devices/cpu_atom/cpus";
+
+lazy_static! {
+    /// The OS indexes of the processing units of the efficiency cores, empty if the CPU is not
+    /// hybrid or does not report them.
+    static ref EFFICIENCY_PUS: Vec<usize> = match fs::read_to_string(EFFICIENCY_PUS_PATH) {
+        Ok(pus) => parse_core_list(pus.trim()).unwrap_or_else(|err| {
+            warn!(
+                "invalid {}, using all cores as performance cores: {:?}",
+                EFFICIENCY_PUS_PATH, err
+            );
+            Vec::new()
+        }),
+        Err(_) => Vec::new(),
+    };
+}
+
+/// Returns the processing units of `core` the process may be bound to: those hwloc allows, which
+/// are also in the affinity mask of the process.
+fn usable_cpuset(core: &TopologyObject, index: CoreIndex) -> Result<Bitmap> {
+    let cpuset = core
+        .allowed_cpuset()
+        .ok_or_else(|| format_err!("no allowed cpuset for core at index {}", index.0))?;
+
+    Ok(in_process_cpuset(cpuset))
+}
+
+/// Removes the processing units outside the affinity mask of the process from `cpuset`.
+fn in_process_cpuset(mut cpuset: Bitmap) -> Bitmap {
+    for pu in cpuset.first().max(0)..=cpuset.last() {
+        if cpuset.is_set(pu as u32) && !in_process_affinity(pu as usize) {
+            cpuset.unset(pu as u32);
+        }
+    }
+
+    cpuset
+}
+
+/// Returns whether the processing unit with the OS index `pu` is in the affinity mask of the
+/// process.
+fn in_process_affinity(pu: usize) -> bool {
+    // Without a mask, only the cpuset hwloc allows restricts the cores.
+    PROCESS_AFFINITY
+        .as_ref()
+        .map_or(true, |pus| pus.binary_search(&pu).is_ok())
+}
+
+/// Returns the affinity mask of the process, as `sched_getaffinity` reports it for the main
+/// thread.
+#[cfg(target_os = "linux")]
+fn process_affinity() -> Option<Vec<usize>> {
+    let mut mask: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let res = unsafe {
+        libc::sched_getaffinity(
+            libc::getpid(),
+            std::mem::size_of::<libc::cpu_set_t>(),
+            &mut mask,
+        )
+    };
+    if res != 0 {
+        return None;
+    }
+
+    Some(
+        (0..libc::CPU_SETSIZE as usize)
+            .filter(|pu| unsafe { libc::CPU_ISSET(*pu, &mask) })
+            .collect(),
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_affinity() -> Option<Vec<usize>> {
+    None
+}
+
+fn get_core_by_index(topo: &Topology, index: CoreIndex) -> Result<&TopologyObject> {
+    let idx = index.0;
+
+    match topo.objects_with_type(&ObjectType::Core) {
+        Ok(all_cores) if idx < all_cores.len() => Ok(all_cores[idx]),
+        Ok(all_cores) => Err(format_err!(
+            "idx ({}) out of range for {} cores",
+            idx,
+            all_cores.len()
+        )),
+        _e => Err(format_err!("failed to get core by index {}", idx,)),
+    }
+}
+
+/// Returns the processing unit to bind to for the core at `index`: its first one, or its second
+/// one, i.e. its SMT sibling, with `smt_sibling`.
+pub fn core_binding(topo: &Topology, index: CoreIndex, smt_sibling: bool) -> Result<Binding> {
+    let core = get_core_by_index(topo, index)
+        .map_err(|err| format_err!("failed to get core at index {}: {:?}", index.0, err))?;
+
+    let cpuset = usable_cpuset(core, index)?;
+    debug!("allowed cpuset: {:?}", cpuset);
+    ensure!(
+        !cpuset.is_empty(),
+        "core at index {} is outside the cpuset of the process",
+        index.0
+    );
+    let mut bind_to = cpuset;
+
+    if smt_sibling {
+        ensure!(
+            bind_to.weight() > 1,
+            "core at index {} has no SMT sibling",
+            index.0
+        );
+        let first = bind_to.first();
+        bind_to.unset(first as u32);
+    }
+
+    // Get only one logical processor (in case the core is SMT/hyper-threaded).
+    bind_to.singlify();
+
+    Ok(bind_to)
+}
+
+/// Returns the binding to all processing units the process may be bound to, `None` if there are
+/// none.
+pub fn process_binding(topo: &Topology) -> Option<Binding> {
+    topo.object_at_root()
+        .allowed_cpuset()
+        .map(in_process_cpuset)
+        .filter(|cpuset| !cpuset.is_empty())
+}
+
+/// Returns the binding of the thread `tid`, `None` if it cannot be read.
+pub fn thread_binding(topo: &Topology, tid: ThreadId) -> Option<Binding> {
+    topo.get_cpubind_for_thread(tid, CPUBIND_THREAD)
+}
+
+/// Binds the thread `tid` to `binding`, and its memory allocations to the NUMA node of `binding`
+/// with `bind_memory`, or to the default policy without it. Returns a description of every
+/// binding which failed.
+pub fn set_thread_binding(
+    topo: &mut Topology,
+    tid: ThreadId,
+    binding: &Binding,
+    bind_memory: bool,
+) -> Vec<String> {
+    let mut failures = Vec::new();
+    // Modified by long 20210708
+    if let Err(err) = topo.set_cpubind_for_thread(tid, binding.clone(), CPUBIND_THREAD) {
+        failures.push(format!("failed to bind CPU: {:?}", err));
+    }
+
+    // Added by long 20210708
+    let policy = if bind_memory {
+        hwloc::MEMBIND_BIND
+    } else {
+        hwloc::MEMBIND_DEFAULT
+    };
+    if let Err(err) = topo.set_membind(binding.clone(), policy, hwloc::MEMBIND_THREAD) {
+        failures.push(format!("failed to bind memory: {:?}", err));
+    }
+
+    failures
+}
@@ -0,0 +1,2163 @@
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::mem;
+use std::ops::{Deref, Range};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, format_err, Context, Result};
+use fs2::FileExt;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use storage_proofs_core::settings::SETTINGS;
+
+#[cfg(not(feature = "multicore-sdr"))]
+mod affinity_backend;
+#[cfg(feature = "multicore-sdr")]
+mod hwloc_backend;
+
+#[cfg(not(feature = "multicore-sdr"))]
+use affinity_backend as backend;
+#[cfg(feature = "multicore-sdr")]
+use hwloc_backend as backend;
+
+use backend::{
+    core_binding, process_binding, set_thread_binding, thread_binding, topology, Binding,
+};
+
+type CoreGroup = Vec<CoreIndex>;
+lazy_static! {
+    // pub static ref CORE_GROUPS: Option<Vec<Mutex<CoreGroup>>> = {
+    pub static ref CORE_GROUPS: Vec<Mutex<CoreGroup>> = {
+        let num_producers = &SETTINGS.multicore_sdr_producers;
+        let cores_per_unit = num_producers + 1;
+
+        let topo = topology();
+        match numa_summary(&*topo) {
+            Ok(summary) => info!("{}", summary),
+            Err(err) => warn!("failed to get NUMA nodes: {:?}", err),
+        }
+        let core_list = SETTINGS.multicore_sdr_core_list.trim();
+        let listed = if core_list.is_empty() {
+            None
+        } else {
+            core_groups_from_list(&*topo, core_list, cores_per_unit)
+                .map_err(|err| warn!("invalid core list, grouping all cores: {:?}", err))
+                .ok()
+        };
+        listed.unwrap_or_else(|| {
+            core_groups(&*topo, cores_per_unit, SETTINGS.multicore_sdr_use_efficiency_cores)
+                .expect("failed to build core groups")
+        })
+    };
+    /// When each group of `CORE_GROUPS` was last checked out, as a tick of `CHECKOUT_CLOCK`, 0 if
+    /// it never was.
+    static ref CORE_GROUPS_LAST_USED: Vec<AtomicU64> =
+        CORE_GROUPS.iter().map(|_| AtomicU64::new(0)).collect();
+    /// The pools `CORE_GROUPS` are split into, see `core_group_pools`.
+    static ref CORE_GROUP_POOLS: CoreGroupPools =
+        core_group_pools(&SETTINGS.multicore_sdr_core_group_pools, CORE_GROUPS.len())
+            .unwrap_or_else(|err| {
+                warn!("invalid core group pools, using a single pool: {:?}", err);
+                CoreGroupPools::single(CORE_GROUPS.len())
+            });
+    /// Whether each visible core has an SMT sibling, see `smt_siblings`.
+    static ref SMT_SIBLINGS: Vec<bool> = {
+        let topo = topology();
+        smt_siblings(&*topo).unwrap_or_else(|err| {
+            warn!("failed to find SMT siblings: {:?}", err);
+            Vec::new()
+        })
+    };
+}
+
+/// The groups of `CORE_GROUPS` split into pools by sector size, see the
+/// `multicore_sdr_core_group_pools` setting.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CoreGroupPools {
+    /// The groups reserved for the sector sizes with a pool of their own.
+    sizes: BTreeMap<u64, Range<usize>>,
+    /// The groups of all other sector sizes.
+    default: Range<usize>,
+}
+
+impl CoreGroupPools {
+    /// A single pool of all `group_count` groups.
+    fn single(group_count: usize) -> Self {
+        CoreGroupPools {
+            sizes: BTreeMap::new(),
+            default: 0..group_count,
+        }
+    }
+
+    /// Returns the groups to check out for sectors of `sector_size` bytes, the default pool for
+    /// `None`.
+    fn pool_for(&self, sector_size: Option<u64>) -> Range<usize> {
+        sector_size
+            .and_then(|sector_size| self.sizes.get(&sector_size))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+/// Splits `group_count` groups into the pools described by `spec`, a comma separated list of
+/// `<sector size>:<groups>` pairs. The pools take the groups in the order of the list, and the
+/// groups left over are the default pool.
+fn core_group_pools(spec: &str, group_count: usize) -> Result<CoreGroupPools> {
+    let mut sizes = BTreeMap::new();
+    let mut start = 0;
+    for pool in spec
+        .split(',')
+        .map(str::trim)
+        .filter(|pool| !pool.is_empty())
+    {
+        let mut parts = pool.splitn(2, ':');
+        let sector_size = parts.next().unwrap_or_default().trim();
+        let groups = parts
+            .next()
+            .ok_or_else(|| format_err!("core group pool '{}' has no group count", pool))?
+            .trim();
+        let sector_size: u64 = sector_size
+            .parse()
+            .map_err(|err| format_err!("invalid sector size in '{}': {}", pool, err))?;
+        let groups: usize = groups
+            .parse()
+            .map_err(|err| format_err!("invalid group count in '{}': {}", pool, err))?;
+        ensure!(groups > 0, "core group pool '{}' has no groups", pool);
+
+        let end = start + groups;
+        ensure!(
+            end <= group_count,
+            "core group pools need more than the {} core groups",
+            group_count
+        );
+        ensure!(
+            sizes.insert(sector_size, start..end).is_none(),
+            "sector size {} has more than one core group pool",
+            sector_size
+        );
+        start = end;
+    }
+
+    Ok(CoreGroupPools {
+        sizes,
+        default: start..group_count,
+    })
+}
+
+/// Called with the core and the error whenever `bind_core` fails to bind the CPU or the memory of
+/// a thread, or returns an error as there is no such core to bind to, see `set_on_bind_failure`.
+pub type BindFailureCallback = fn(CoreIndex, &str);
+
+lazy_static! {
+    /// The callback set with `set_on_bind_failure`.
+    static ref ON_BIND_FAILURE: RwLock<Option<BindFailureCallback>> = RwLock::new(None);
+    /// The name and the innermost binding of every thread bound by `bind_core`, see
+    /// `current_core_bindings`.
+    static ref THREAD_BINDINGS: Mutex<HashMap<thread::ThreadId, (String, CoreIndex)>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Sets the callback invoked when binding a thread to a core fails, or removes it with `None`.
+///
+/// Binding failures are not errors, the thread keeps running unbound and the failure is logged as
+/// a warning. The callback lets a caller which relies on the binding react to it, e.g. count the
+/// failures or abort the labeling. It is called after the warning is logged, from the thread which
+/// failed to bind, and may not bind threads itself.
+pub fn set_on_bind_failure(callback: Option<BindFailureCallback>) {
+    *ON_BIND_FAILURE.write().expect("poisoned lock") = callback;
+}
+
+fn report_bind_failure(core_index: CoreIndex, error: &str) {
+    warn!("error in bind_core, {}", error);
+    BIND_FAILURES.fetch_add(1, Ordering::Relaxed);
+    if let Some(callback) = *ON_BIND_FAILURE.read().expect("poisoned lock") {
+        callback(core_index, error);
+    }
+}
+
+/// Counts the checkouts of core groups, see `CORE_GROUPS_LAST_USED`.
+static CHECKOUT_CLOCK: AtomicU64 = AtomicU64::new(0);
+
+/// The counters behind `stats`.
+static CHECKED_OUT_GROUPS: AtomicUsize = AtomicUsize::new(0);
+static CHECKOUT_FAILURES: AtomicU64 = AtomicU64::new(0);
+static BIND_FAILURES: AtomicU64 = AtomicU64::new(0);
+static UNBOUND_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// What the core scheduling did since the process started, see `stats`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CoreStats {
+    /// The number of groups in `CORE_GROUPS`.
+    pub groups: usize,
+    /// How many groups are checked out right now.
+    pub checked_out: usize,
+    /// How many checkouts found no free group. The labelings which asked for them run unbound.
+    pub checkout_failures: u64,
+    /// How often binding a thread to a core failed, see `set_on_bind_failure`.
+    pub bind_failures: u64,
+    /// How long labelings ran without a core group, summed over the labelings running at the
+    /// same time.
+    pub unbound: Duration,
+}
+
+/// Returns the counters of the core scheduling, e.g. for an operator to see when labelings run
+/// without bound cores. The counters are read one at a time while other threads may update them.
+pub fn stats() -> CoreStats {
+    CoreStats {
+        groups: CORE_GROUPS.len(),
+        checked_out: CHECKED_OUT_GROUPS.load(Ordering::Relaxed),
+        checkout_failures: CHECKOUT_FAILURES.load(Ordering::Relaxed),
+        bind_failures: BIND_FAILURES.load(Ordering::Relaxed),
+        unbound: Duration::from_nanos(UNBOUND_NANOS.load(Ordering::Relaxed)),
+    }
+}
+
+/// Counts `guard` as a failed checkout if there is none.
+fn count_checkout(guard: Option<CoreGroupGuard>) -> Option<CoreGroupGuard> {
+    if guard.is_none() {
+        CHECKOUT_FAILURES.fetch_add(1, Ordering::Relaxed);
+    }
+
+    guard
+}
+
+/// Tracks a labeling from `track_labeling` until it is dropped: how long it runs without a core
+/// group, and the summary logged at its end with `multicore_sdr_log_core_stats`.
+#[derive(Debug)]
+pub(crate) struct LabelingTracker {
+    unbound_since: Option<Instant>,
+}
+
+/// Starts tracking a labeling, which runs on `core_group`, or unbound without one.
+pub(crate) fn track_labeling(core_group: Option<&CoreGroupGuard>) -> LabelingTracker {
+    LabelingTracker {
+        unbound_since: core_group.is_none().then(Instant::now),
+    }
+}
+
+impl Drop for LabelingTracker {
+    fn drop(&mut self) {
+        if let Some(since) = self.unbound_since {
+            let nanos = since.elapsed().as_nanos().min(u64::MAX as u128) as u64;
+            UNBOUND_NANOS.fetch_add(nanos, Ordering::Relaxed);
+        }
+
+        if SETTINGS.multicore_sdr_log_core_stats {
+            let stats = stats();
+            info!(
+                "core stats: {} of {} core groups checked out, {} checkouts without a free group, \
+                 {} bind failures, {:?} labeling without a core group",
+                stats.checked_out,
+                stats.groups,
+                stats.checkout_failures,
+                stats.bind_failures,
+                stats.unbound
+            );
+        }
+    }
+}
+
+/// Which free core group `checkout_core_group` takes, see the `multicore_sdr_checkout_policy`
+/// setting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckoutPolicy {
+    /// The first free group.
+    FirstFree,
+    /// The free group which was checked out least recently.
+    Lru,
+}
+
+impl CheckoutPolicy {
+    /// Returns the policy named `name`, as it is written in the settings.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "first_free" => Some(CheckoutPolicy::FirstFree),
+            "lru" => Some(CheckoutPolicy::Lru),
+            _ => None,
+        }
+    }
+
+    fn from_settings() -> Self {
+        let name = SETTINGS.multicore_sdr_checkout_policy.as_str();
+        Self::from_name(name).unwrap_or_else(|| {
+            warn!(
+                "unknown core group checkout policy '{}', using first_free",
+                name
+            );
+            CheckoutPolicy::FirstFree
+        })
+    }
+}
+
+/// The order in which the producers are bound to the cores of their group, see the
+/// `multicore_sdr_intra_group_order` setting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntraGroupOrder {
+    /// The first producer is bound to the lowest producer core.
+    Ascending,
+    /// The first producer is bound to the highest producer core.
+    Descending,
+}
+
+impl IntraGroupOrder {
+    /// Returns the order named `name`, as it is written in the settings.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ascending" => Some(IntraGroupOrder::Ascending),
+            "descending" => Some(IntraGroupOrder::Descending),
+            _ => None,
+        }
+    }
+
+    fn from_settings() -> Self {
+        let name = SETTINGS.multicore_sdr_intra_group_order.as_str();
+        Self::from_name(name).unwrap_or_else(|| {
+            warn!("unknown intra group order '{}', using ascending", name);
+            IntraGroupOrder::Ascending
+        })
+    }
+}
+
+/// The parts of the machine topology the core groups are built from. The production code uses the
+/// topology of the backend, i.e. hwloc's with the `multicore-sdr` feature, tests can describe a
+/// machine of their own.
+pub trait CoreTopology {
+    /// Returns the number of visible cores.
+    fn core_count(&self) -> Result<usize>;
+
+    /// Returns the number of processing units, i.e. hardware threads, of the core at `index`.
+    fn pu_count(&self, index: CoreIndex) -> Result<usize>;
+
+    /// Returns the logical index and the local memory in bytes of every NUMA node.
+    fn numa_memory(&self) -> Result<Vec<(usize, u64)>>;
+
+    /// Returns the NUMA node and the L3 cache of the core at `index`.
+    fn core_domain(&self, index: CoreIndex) -> Result<CoreDomain>;
+
+    /// Returns whether the process may run on the core at `index`, i.e. whether any of its
+    /// processing units is in the cpuset of the process, which a container may restrict to a
+    /// subset of the machine.
+    fn is_allowed(&self, index: CoreIndex) -> Result<bool>;
+
+    /// Returns whether the core at `index` is a performance or an efficiency core.
+    fn core_kind(&self, index: CoreIndex) -> Result<CoreKind>;
+}
+
+/// The kind of a core of a hybrid CPU, e.g. of Intel's Alder Lake. All cores of other CPUs are
+/// performance cores.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CoreKind {
+    Performance,
+    Efficiency,
+}
+
+/// Where a core is in the machine, by the logical indexes of its NUMA node and its L3 cache.
+/// Either is `None` if the topology does not have them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CoreDomain {
+    pub numa_node: Option<usize>,
+    pub l3_cache: Option<usize>,
+}
+
+/// Returns the logical index and the local memory in bytes of every NUMA node of the machine,
+/// e.g. to check whether a sector's layers fit into the memory of the node its core group
+/// binds the memory to. It is empty if the topology does not have any NUMA nodes, which without
+/// hwloc is only known on Windows.
+pub fn numa_memory() -> Vec<(usize, u64)> {
+    topology().numa_memory().unwrap_or_else(|err| {
+        warn!("{:?}", err);
+        Vec::new()
+    })
+}
+
+/// Describes the NUMA nodes of `topo` and their memory, which is logged when the core groups are
+/// built.
+fn numa_summary(topo: &dyn CoreTopology) -> Result<String> {
+    let nodes = topo.numa_memory()?;
+    let total: u64 = nodes.iter().map(|(_, memory)| memory).sum();
+    let mut summary = format!(
+        "{} cores, {} NUMA nodes with {} bytes of memory",
+        topo.core_count()?,
+        nodes.len(),
+        total
+    );
+    for (node, memory) in &nodes {
+        summary.push_str(&format!(", node {}: {} bytes", node, memory));
+    }
+
+    Ok(summary)
+}
+
+/// Returns whether each core of `topo` has an SMT sibling, i.e. more than one processing unit
+/// which the consumer can be bound to, see `CoreGroupGuard::consumer_smt_sibling`.
+fn smt_siblings(topo: &dyn CoreTopology) -> Result<Vec<bool>> {
+    (0..topo.core_count()?)
+        .map(|i| Ok(topo.pu_count(CoreIndex(i))? > 1))
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// `CoreIndex` is a simple wrapper type for indexes into the set of vixible cores. A `CoreIndex` should only ever be
+/// created with a value known to be less than the number of visible cores.
+pub struct CoreIndex(usize);
+
+impl CoreIndex {
+    /// Returns the index of a visible core, failing if there are not more than `index` cores.
+    pub fn new(index: usize) -> Result<Self> {
+        let core_count = core_count()?;
+        ensure!(
+            index < core_count,
+            "core index {} is out of range for {} cores",
+            index,
+            core_count
+        );
+
+        Ok(CoreIndex(index))
+    }
+}
+
+/// The cores of a checked out group.
+#[derive(Debug)]
+enum GroupCores {
+    /// A group of `CORE_GROUPS`, locked until the guard is dropped.
+    Shared(MutexGuard<'static, CoreGroup>),
+    /// A group built by `core_group_from_subset`.
+    Owned(CoreGroup),
+}
+
+impl Deref for GroupCores {
+    type Target = CoreGroup;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            GroupCores::Shared(guard) => guard,
+            GroupCores::Owned(cores) => cores,
+        }
+    }
+}
+
+/// A checked out core group. The group stays checked out until the guard is dropped.
+///
+/// The guard decides which core of the group each labeling thread is bound to. By default the
+/// consumer (the main hashing thread) uses the first core and producer `i` uses core `i + 1`.
+/// With a dedicated consumer, the last core of the group is reserved for the consumer and the
+/// producers are bound to the cores before it. With `consumer_smt_sibling`, the consumer shares
+/// the core of the first producer instead. With `IntraGroupOrder::Descending`, the producers take
+/// their cores from the highest to the lowest.
+#[derive(Debug)]
+pub struct CoreGroupGuard {
+    guard: GroupCores,
+    index: Option<usize>,
+    dedicated_consumer: bool,
+    consumer_smt_sibling: Option<CoreIndex>,
+    producer_order: IntraGroupOrder,
+    /// The most producer threads the group has cores for, `None` if it has cores for as many as
+    /// configured.
+    max_producers: Option<usize>,
+    /// The lock files reserving the cores in `multicore_sdr_core_lock_dir`, see `reserve_cores`.
+    reservation: Vec<File>,
+}
+
+impl CoreGroupGuard {
+    fn new(guard: MutexGuard<'static, CoreGroup>, index: usize, dedicated_consumer: bool) -> Self {
+        CHECKED_OUT_GROUPS.fetch_add(1, Ordering::Relaxed);
+        Self::with_cores(GroupCores::Shared(guard), Some(index), dedicated_consumer)
+    }
+
+    fn with_cores(guard: GroupCores, index: Option<usize>, dedicated_consumer: bool) -> Self {
+        // A dedicated consumer core only makes sense if there is at least one core left for the
+        // producers.
+        let dedicated_consumer = dedicated_consumer && guard.len() > 1;
+
+        CoreGroupGuard {
+            guard,
+            index,
+            dedicated_consumer,
+            consumer_smt_sibling: None,
+            producer_order: IntraGroupOrder::Ascending,
+            max_producers: None,
+            reservation: Vec::new(),
+        }
+    }
+
+    fn with_reservation(mut self, reservation: Vec<File>) -> Self {
+        self.reservation = reservation;
+        self
+    }
+
+    /// Binds the producers to the cores of the group in `order`.
+    fn with_producer_order(mut self, order: IntraGroupOrder) -> Self {
+        self.producer_order = order;
+        self
+    }
+
+    /// Limits the producer threads labeling on the group to `max_producers`.
+    fn with_max_producers(mut self, max_producers: usize) -> Self {
+        self.max_producers = Some(max_producers);
+        self
+    }
+
+    /// Moves the consumer to the SMT sibling of the first producer's core, if that core has one
+    /// according to `smt_siblings`.
+    fn share_producer_core(mut self, smt_siblings: &[bool]) -> Self {
+        self.consumer_smt_sibling = self
+            .producer_core(0)
+            .copied()
+            .filter(|core| smt_siblings.get(core.0).copied().unwrap_or(false));
+
+        self
+    }
+
+    /// Returns the index of the group in `CORE_GROUPS`, `None` for a group built by
+    /// `core_group_from_subset`.
+    pub fn index(&self) -> Option<usize> {
+        self.index
+    }
+
+    /// Returns the NUMA node of the consumer core, which the memory of the labeling is bound to,
+    /// `None` if the topology has no NUMA nodes.
+    pub fn numa_node(&self) -> Option<usize> {
+        let core = *self.consumer_core()?;
+        topology().core_domain(core).ok()?.numa_node
+    }
+
+    /// Returns whether the last core of the group is reserved exclusively for the consumer.
+    pub fn has_dedicated_consumer(&self) -> bool {
+        self.dedicated_consumer
+    }
+
+    /// Returns the core the consumer thread should be bound to.
+    pub fn consumer_core(&self) -> Option<&CoreIndex> {
+        if self.dedicated_consumer {
+            self.guard.last()
+        } else {
+            self.guard.first()
+        }
+    }
+
+    /// Returns the core whose SMT sibling the consumer thread should be bound to with
+    /// `bind_smt_sibling`, instead of binding it to `consumer_core`. This is the core of the first
+    /// producer if `multicore_sdr_consumer_smt_sibling` is set and the core has an SMT sibling.
+    pub fn consumer_smt_sibling(&self) -> Option<&CoreIndex> {
+        self.consumer_smt_sibling.as_ref()
+    }
+
+    /// Returns how many of `producers` producer threads label on the group: all of them, unless the
+    /// group was built by `core_group_from_subset` with fewer cores for them, so that the others do
+    /// not run unbound on the cores the caller reserved for other work.
+    pub fn producer_count(&self, producers: usize) -> usize {
+        self.max_producers
+            .map_or(producers, |max_producers| producers.min(max_producers))
+    }
+
+    /// Returns the core the producer thread with the given index should be bound to, if any.
+    pub fn producer_core(&self, producer: usize) -> Option<&CoreIndex> {
+        let cores = if self.dedicated_consumer {
+            &self.guard[..self.guard.len() - 1]
+        } else {
+            self.guard.get(1..).unwrap_or_default()
+        };
+
+        match self.producer_order {
+            IntraGroupOrder::Ascending => cores.get(producer),
+            IntraGroupOrder::Descending => cores.iter().rev().nth(producer),
+        }
+    }
+}
+
+impl Deref for CoreGroupGuard {
+    type Target = CoreGroup;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl Drop for CoreGroupGuard {
+    fn drop(&mut self) {
+        // The cores are released to the other processes before the group is released in this
+        // one, so that a waiter woken up below does not find them still reserved.
+        self.reservation.clear();
+        if let GroupCores::Shared(_) = self.guard {
+            // The group is unlocked before the waiters are woken up, so that they find it free.
+            drop(mem::replace(&mut self.guard, GroupCores::Owned(Vec::new())));
+            CHECKED_OUT_GROUPS.fetch_sub(1, Ordering::Relaxed);
+            // Taking the lock makes sure that a waiter which just found no free group is waiting
+            // by now.
+            drop(CHECKOUT_WAITERS.lock().expect("poisoned lock"));
+            GROUP_RELEASED.notify_all();
+        }
+    }
+}
+
+lazy_static! {
+    /// The callers waiting in `checkout_core_group_blocking`.
+    static ref CHECKOUT_WAITERS: Mutex<CheckoutWaiters> = Mutex::new(CheckoutWaiters::default());
+    /// Notified whenever a group of `CORE_GROUPS` is released, or a waiter gives up.
+    static ref GROUP_RELEASED: Condvar = Condvar::new();
+}
+
+/// The callers waiting for a group of a pool, and the order they get one in.
+#[derive(Debug, Default)]
+struct CheckoutWaiters {
+    /// The ticket, the pool and the priority of every waiter.
+    waiting: Vec<(u64, Range<usize>, u8)>,
+    next_ticket: u64,
+}
+
+impl CheckoutWaiters {
+    fn add(&mut self, pool: Range<usize>, priority: u8) -> u64 {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        self.waiting.push((ticket, pool, priority));
+
+        ticket
+    }
+
+    fn remove(&mut self, ticket: u64) {
+        self.waiting.retain(|(t, _, _)| *t != ticket);
+    }
+
+    /// Whether the waiter `ticket` is the next one to check out a group of its pool: the waiter
+    /// with the highest priority, and of those the one waiting longest.
+    fn is_next(&self, ticket: u64) -> bool {
+        let pool = match self.waiting.iter().find(|(t, _, _)| *t == ticket) {
+            Some((_, pool, _)) => pool,
+            None => return false,
+        };
+
+        self.waiting
+            .iter()
+            .filter(|(_, p, _)| p == pool)
+            .max_by_key(|(t, _, priority)| (*priority, Reverse(*t)))
+            .map_or(false, |(t, _, _)| *t == ticket)
+    }
+}
+
+/// Like `checkout_core_group_for`, but waits up to `timeout` for a group to be released if none
+/// is free, instead of returning `None` right away, which would leave the labeling unbound.
+///
+/// The callers waiting for the same pool get the released groups in the order of their
+/// `priority`, the highest first, and callers with the same priority in the order they started
+/// waiting. `checkout_core_group` and `checkout_core_group_for` do not wait, and may take a group
+/// before a waiter does. A group is only released once its labeling is done, running labelings
+/// are never preempted.
+pub fn checkout_core_group_blocking(
+    sector_size: u64,
+    priority: u8,
+    timeout: Duration,
+) -> Option<CoreGroupGuard> {
+    let pool = CORE_GROUP_POOLS.pool_for(Some(sector_size));
+    let deadline = Instant::now() + timeout;
+
+    let mut waiters = CHECKOUT_WAITERS.lock().expect("poisoned lock");
+    let ticket = waiters.add(pool.clone(), priority);
+    let guard = loop {
+        if waiters.is_next(ticket) {
+            if let Some(guard) = checkout_pool(pool.clone()) {
+                break Some(guard);
+            }
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            debug!("no core group released within {:?}, giving up", timeout);
+            break None;
+        }
+        let mut wait = deadline - now;
+        if core_lock_dir().is_some() {
+            // Other processes releasing their reservations do not notify the waiters.
+            wait = wait.min(RESERVATION_POLL_INTERVAL);
+        }
+        waiters = GROUP_RELEASED
+            .wait_timeout(waiters, wait)
+            .expect("poisoned lock")
+            .0;
+    };
+    waiters.remove(ticket);
+    drop(waiters);
+    // The next waiter may be able to check out a group now.
+    GROUP_RELEASED.notify_all();
+
+    count_checkout(guard)
+}
+
+/// Checks out a free group of the default pool, see `checkout_core_group_for`.
+pub fn checkout_core_group() -> Option<CoreGroupGuard> {
+    count_checkout(checkout_pool(CORE_GROUP_POOLS.pool_for(None)))
+}
+
+/// Checks out a free group of the pool of `sector_size`, which is the default pool unless the
+/// `multicore_sdr_core_group_pools` setting reserves groups for sectors of this size. Without
+/// pools, all sector sizes check out any of `CORE_GROUPS`.
+pub fn checkout_core_group_for(sector_size: u64) -> Option<CoreGroupGuard> {
+    count_checkout(checkout_pool(CORE_GROUP_POOLS.pool_for(Some(sector_size))))
+}
+
+/// Returns whether the pool of `sector_size` has any groups, free or not, see
+/// `checkout_core_group_for`.
+pub(crate) fn has_core_groups_for(sector_size: u64) -> bool {
+    !CORE_GROUP_POOLS.pool_for(Some(sector_size)).is_empty()
+}
+
+/// Releases a checked out group, so that it can be checked out again.
+///
+/// This is the same as dropping the guard, but gives callers which hold the guard across a
+/// language boundary, e.g. behind FFI, an explicit point to release it at.
+pub fn release_core_group(guard: CoreGroupGuard) {
+    debug!("releasing core group {:?}", guard.index());
+    drop(guard);
+}
+
+/// Returns whether the group of `CORE_GROUPS` at `index` is checked out, `false` if there is no
+/// such group. This is meant for diagnostics: the group may be checked out or released right
+/// after, and a checkout at the same time may find the group locked by the check.
+pub fn is_group_checked_out(index: usize) -> bool {
+    CORE_GROUPS.get(index).map_or(false, is_locked)
+}
+
+fn is_locked(group: &Mutex<CoreGroup>) -> bool {
+    matches!(group.try_lock(), Err(TryLockError::WouldBlock))
+}
+
+fn checkout_pool(pool: Range<usize>) -> Option<CoreGroupGuard> {
+    // match &*CORE_GROUPS {
+    //     Some(groups) => {
+    //         for (i, group) in groups.iter().enumerate() {
+    //             match group.try_lock() {
+    //                 Ok(guard) => {
+    //                     debug!("checked out core group {}", i);
+    //                     return Some(guard);
+    //                 }
+    //                 Err(_) => debug!("core group {} locked, could not checkout", i),
+    //             }
+    //         }
+    //         None
+    //     }
+    //     None => None,
+    // }
+    let guard = checkout_from(
+        &CORE_GROUPS,
+        pool,
+        &CORE_GROUPS_LAST_USED,
+        CheckoutPolicy::from_settings(),
+        SETTINGS.multicore_sdr_dedicated_consumer,
+        core_lock_dir(),
+    )?
+    // The order decides which producer comes first, so it is set before the consumer follows it.
+    .with_producer_order(IntraGroupOrder::from_settings());
+
+    if SETTINGS.multicore_sdr_consumer_smt_sibling {
+        Some(guard.share_producer_core(&SMT_SIBLINGS))
+    } else {
+        Some(guard)
+    }
+}
+
+/// Checks out one of the groups of `pool` according to `policy`, and records the checkout in
+/// `last_used`.
+fn checkout_from(
+    groups: &'static [Mutex<CoreGroup>],
+    pool: Range<usize>,
+    last_used: &[AtomicU64],
+    policy: CheckoutPolicy,
+    dedicated_consumer: bool,
+    lock_dir: Option<&Path>,
+) -> Option<CoreGroupGuard> {
+    let mut order: Vec<usize> = pool.collect();
+    if policy == CheckoutPolicy::Lru {
+        // The sort is stable, so groups which were used equally long ago are tried in order.
+        order.sort_by_key(|&i| last_used[i].load(Ordering::SeqCst));
+    }
+
+    for i in order {
+        match groups[i].try_lock() {
+            Ok(guard) => {
+                let reservation = match lock_dir.map(|dir| reserve_cores(dir, &guard)) {
+                    Some(Ok(Some(files))) => files,
+                    Some(Ok(None)) => {
+                        debug!("core group {} reserved by another process", i);
+                        continue;
+                    }
+                    Some(Err(err)) => {
+                        warn!(
+                            "could not reserve core group {}, checking it out unreserved: {:#}",
+                            i, err
+                        );
+                        Vec::new()
+                    }
+                    None => Vec::new(),
+                };
+                debug!("checked out core group {}", i);
+                let tick = CHECKOUT_CLOCK.fetch_add(1, Ordering::SeqCst) + 1;
+                last_used[i].store(tick, Ordering::SeqCst);
+                return Some(
+                    CoreGroupGuard::new(guard, i, dedicated_consumer).with_reservation(reservation),
+                );
+            }
+            Err(_) => debug!("core group {} locked, could not checkout", i),
+        }
+    }
+    None
+}
+
+/// How often `checkout_core_group_blocking` tries again while the cores are reserved by other
+/// processes, which cannot notify it when they release them.
+const RESERVATION_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The directory the cores are reserved in, `None` if `multicore_sdr_core_lock_dir` is not set.
+fn core_lock_dir() -> Option<&'static Path> {
+    Some(Path::new(&SETTINGS.multicore_sdr_core_lock_dir)).filter(|dir| !dir.as_os_str().is_empty())
+}
+
+fn core_lock_path(dir: &Path, core: CoreIndex) -> PathBuf {
+    dir.join(format!("core-{}.lock", core.0))
+}
+
+/// Reserves `cores` machine-wide with an exclusive file lock on the lock file of every core in
+/// `dir`, which all processes sharing the machine use. Returns `None` if another process holds
+/// any of them, in which case none of them stay locked.
+///
+/// The locks are held until the returned files are dropped, and are released by the kernel if
+/// the process exits.
+fn reserve_cores(dir: &Path, cores: &[CoreIndex]) -> Result<Option<Vec<File>>> {
+    fs::create_dir_all(dir).with_context(|| format!("could not create {:?}", dir))?;
+
+    let mut files = Vec::with_capacity(cores.len());
+    for core in cores {
+        let path = core_lock_path(dir, *core);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .with_context(|| format!("could not open {:?}", path))?;
+        if let Err(err) = file.try_lock_exclusive() {
+            if err.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+                return Ok(None);
+            }
+            return Err(err).with_context(|| format!("could not lock {:?}", path));
+        }
+        files.push(file);
+    }
+
+    Ok(Some(files))
+}
+
+/// Builds a core group of exactly `cores`, for a caller which reserved the other cores for other
+/// work.
+///
+/// The group is not one of `CORE_GROUPS` and does not lock any of them, so the cores may be bound
+/// by another labeling at the same time. It is up to the caller to keep them apart. The group
+/// limits the producers to one for every core but the consumer's, or to a single one if it only
+/// has one core, see `CoreGroupGuard::producer_count`.
+pub fn core_group_from_subset(cores: Vec<CoreIndex>) -> Result<CoreGroupGuard> {
+    ensure!(!cores.is_empty(), "the core subset is empty");
+    let core_count = core_count()?;
+    for (i, core) in cores.iter().enumerate() {
+        ensure!(
+            core.0 < core_count,
+            "core index {} is out of range for {} cores",
+            core.0,
+            core_count
+        );
+        ensure!(
+            !cores[..i].contains(core),
+            "core index {} is in the core subset more than once",
+            core.0
+        );
+    }
+    debug!("built core group from subset {:?}", cores);
+    let max_producers = (cores.len() - 1).max(1);
+
+    Ok(CoreGroupGuard::with_cores(
+        GroupCores::Owned(cores),
+        None,
+        SETTINGS.multicore_sdr_dedicated_consumer,
+    )
+    .with_producer_order(IntraGroupOrder::from_settings())
+    .with_max_producers(max_producers))
+}
+
+thread_local! {
+    /// The binding of a worker thread started by `install_on_core_groups`, reverted when the
+    /// thread exits.
+    static WORKER_BINDING: RefCell<Option<Cleanup>> = RefCell::new(None);
+
+    /// The cores of the thread pool a worker thread started by `install_on_core_groups` is in.
+    static WORKER_CORES: RefCell<Option<Arc<Vec<CoreIndex>>>> = RefCell::new(None);
+
+    /// The bindings `bind_core` made on the current thread and did not revert yet.
+    static BINDINGS: RefCell<BindingStack<Binding>> = RefCell::new(BindingStack::default());
+}
+
+/// The bindings of a thread, innermost last, so that nested bindings revert to the binding
+/// around them, and the outermost one to the binding the thread had before.
+#[derive(Debug)]
+struct BindingStack<T> {
+    /// The binding before the outermost binding on the stack.
+    original: Option<T>,
+    /// The id, the core and the binding of every binding on the stack.
+    bindings: Vec<(u64, CoreIndex, T)>,
+    next_id: u64,
+}
+
+impl<T> Default for BindingStack<T> {
+    fn default() -> Self {
+        BindingStack {
+            original: None,
+            bindings: Vec::new(),
+            next_id: 0,
+        }
+    }
+}
+
+/// What to revert a thread's binding to when one of its bindings is removed from the stack.
+#[derive(Debug, PartialEq)]
+enum Restore<T> {
+    /// The binding is still covered by a binding nested in it, which stays in place.
+    Nothing,
+    /// The binding around the removed one.
+    Binding(T),
+    /// The binding before the outermost binding, `None` if it is unknown.
+    Original(Option<T>),
+}
+
+impl<T: Clone> BindingStack<T> {
+    /// Pushes `binding`, nested in the current ones. `prior` is the current binding of the
+    /// thread, which is kept if the stack is empty. Returns the id to remove the binding with.
+    fn push(&mut self, prior: Option<T>, core_index: CoreIndex, binding: T) -> u64 {
+        if self.bindings.is_empty() {
+            self.original = prior;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.bindings.push((id, core_index, binding));
+
+        id
+    }
+
+    /// Returns the core of the innermost binding.
+    fn current(&self) -> Option<CoreIndex> {
+        self.bindings.last().map(|(_, core_index, _)| *core_index)
+    }
+
+    /// Removes the binding `id`, which need not be the innermost one.
+    fn remove(&mut self, id: u64) -> Restore<T> {
+        let position = match self.bindings.iter().position(|(i, _, _)| *i == id) {
+            Some(position) => position,
+            None => return Restore::Nothing,
+        };
+        self.bindings.remove(position);
+        if position < self.bindings.len() {
+            return Restore::Nothing;
+        }
+
+        match self.bindings.last() {
+            Some((_, _, binding)) => Restore::Binding(binding.clone()),
+            None => Restore::Original(self.original.take()),
+        }
+    }
+}
+
+/// Runs `f` on a thread pool whose threads are bound to the cores of the core groups that are
+/// free at the time of the call, the same way the SDR labeling threads are bound.
+///
+/// Groups are checked out until they provide at least `max_threads` cores, `max_groups` are
+/// checked out or none is left, and stay checked out until `f` returns. If no group is free, `f`
+/// runs unbound on the global rayon pool instead.
+pub fn install_on_core_groups<F, T>(max_groups: usize, max_threads: usize, f: F) -> T
+where
+    F: FnOnce() -> T + Send,
+    T: Send,
+{
+    let mut groups = Vec::new();
+    let mut cores = Vec::new();
+    // The groups are checked out uncounted, as running out of free groups is expected here and
+    // only a labeling without one counts as a failed checkout.
+    while groups.len() < max_groups && cores.len() < max_threads {
+        match checkout_pool(CORE_GROUP_POOLS.pool_for(None)) {
+            Some(group) => {
+                cores.extend(group.iter().copied());
+                groups.push(group);
+            }
+            None => break,
+        }
+    }
+
+    if cores.is_empty() {
+        debug!("no core group free, running unbound");
+        return f();
+    }
+    debug!(
+        "running on {} cores of {} core groups",
+        cores.len(),
+        groups.len()
+    );
+
+    let num_threads = cores.len();
+    let cores = Arc::new(cores);
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .start_handler(move |i| {
+            WORKER_CORES.with(|worker_cores| *worker_cores.borrow_mut() = Some(cores.clone()));
+            match bind_core(cores[i]) {
+                Ok(cleanup) => WORKER_BINDING.with(|binding| *binding.borrow_mut() = Some(cleanup)),
+                Err(err) => warn!("failed to bind worker {}: {:?}", i, err),
+            }
+        })
+        .build();
+
+    // The pool is dropped with its arm, so its workers are unbound before the groups are released.
+    let result = match pool {
+        Ok(pool) => pool.install(f),
+        Err(err) => {
+            warn!(
+                "failed to build bound thread pool, running unbound: {:?}",
+                err
+            );
+            f()
+        }
+    };
+    debug!("releasing {} core groups", groups.len());
+    drop(groups);
+
+    result
+}
+
+/// Returns the cores of the thread pool of `install_on_core_groups` the current thread is a worker
+/// of, `None` if it is not one. Threads which `f` starts outside of the pool can be bound to them
+/// with `bind_core`.
+pub fn current_pool_cores() -> Option<Arc<Vec<CoreIndex>>> {
+    WORKER_CORES.with(|cores| cores.borrow().clone())
+}
+
+#[cfg(not(target_os = "windows"))]
+pub type ThreadId = libc::pthread_t;
+
+#[cfg(target_os = "windows")]
+pub type ThreadId = winapi::winnt::HANDLE;
+
+/// Helper method to get the thread id through libc, with current rust stable (1.5.0) its not
+/// possible otherwise I think.
+#[cfg(not(target_os = "windows"))]
+fn get_thread_id() -> ThreadId {
+    unsafe { libc::pthread_self() }
+}
+
+#[cfg(target_os = "windows")]
+fn get_thread_id() -> ThreadId {
+    unsafe { kernel32::GetCurrentThread() }
+}
+
+/// Reverts a binding made by `bind_core` when dropped. Bindings may be nested on a thread: the
+/// innermost binding stays in place until it is dropped, and dropping the outermost one restores
+/// the binding the thread had before the first `bind_core`.
+pub struct Cleanup {
+    tid: ThreadId,
+    thread: thread::ThreadId,
+    id: u64,
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        // The stack is gone if the thread is exiting, there is nothing to revert then.
+        let (restore, current) = BINDINGS
+            .try_with(|bindings| {
+                let mut bindings = bindings.borrow_mut();
+                let restore = bindings.remove(self.id);
+                (restore, bindings.current())
+            })
+            .unwrap_or((Restore::Nothing, None));
+
+        {
+            let mut thread_bindings = THREAD_BINDINGS.lock().expect("poisoned lock");
+            match current {
+                Some(core_index) => {
+                    if let Some(binding) = thread_bindings.get_mut(&self.thread) {
+                        binding.1 = core_index;
+                    }
+                }
+                None => {
+                    thread_bindings.remove(&self.thread);
+                }
+            }
+        }
+
+        match restore {
+            Restore::Binding(binding) => {
+                set_thread_binding(&mut topology(), self.tid, &binding, true);
+            }
+            Restore::Original(Some(prior)) => {
+                set_thread_binding(&mut topology(), self.tid, &prior, false);
+            }
+            Restore::Original(None) | Restore::Nothing => {}
+        }
+    }
+}
+
+pub fn bind_core(core_index: CoreIndex) -> Result<Cleanup> {
+    bind(core_index, false)
+}
+
+/// Returns the core the current thread is bound to by `bind_core`, the innermost one if the
+/// bindings are nested.
+pub fn current_core_binding() -> Option<CoreIndex> {
+    BINDINGS.with(|bindings| bindings.borrow().current())
+}
+
+/// Returns the name and the core of every thread which is bound by `bind_core`, like
+/// `current_core_binding` returns them for the current thread, sorted by name. Threads without a
+/// name are named by their id.
+pub fn current_core_bindings() -> Vec<(String, CoreIndex)> {
+    let mut bindings: Vec<_> = THREAD_BINDINGS
+        .lock()
+        .expect("poisoned lock")
+        .values()
+        .cloned()
+        .collect();
+    bindings.sort_by(|a, b| a.0.cmp(&b.0));
+
+    bindings
+}
+
+/// Lets the current thread run on every core of the process again, and places its memory with the
+/// default policy. A thread inherits the binding of the thread which spawned it, so a thread
+/// spawned by a labeling thread which is bound with `bind_core` would share its core otherwise.
+/// It must not be bound with `bind_core` itself.
+pub fn unbind_current_thread() -> Result<()> {
+    let tid = get_thread_id();
+    let mut locked_topo = topology();
+    let binding = process_binding(&locked_topo)
+        .ok_or_else(|| format_err!("no processing units to unbind the thread to"))?;
+    let failures = set_thread_binding(&mut locked_topo, tid, &binding, false);
+    ensure!(
+        failures.is_empty(),
+        "failed to unbind the thread: {}",
+        failures.join(", ")
+    );
+
+    Ok(())
+}
+
+/// Binds the current thread to the SMT sibling of the core `core_index`, i.e. to its second
+/// processing unit, while `bind_core` binds to the first one. It fails if the core has no SMT
+/// sibling.
+pub fn bind_smt_sibling(core_index: CoreIndex) -> Result<Cleanup> {
+    bind(core_index, true)
+}
+
+fn bind(core_index: CoreIndex, smt_sibling: bool) -> Result<Cleanup> {
+    let tid = get_thread_id();
+    let mut locked_topo = topology();
+    let bind_to = match core_binding(&locked_topo, core_index, smt_sibling) {
+        Ok(bind_to) => bind_to,
+        Err(err) => {
+            drop(locked_topo);
+            report_bind_failure(core_index, &format!("{:?}", err));
+            return Err(err);
+        }
+    };
+
+    // Thread binding before explicit set.
+    let before = thread_binding(&locked_topo, tid);
+
+    debug!("binding to {:?}", bind_to);
+    let failures = set_thread_binding(&mut locked_topo, tid, &bind_to, true);
+
+    // The callback may use the topology, so it is only called once it is unlocked.
+    drop(locked_topo);
+    for failure in &failures {
+        report_bind_failure(core_index, failure);
+    }
+
+    let id = BINDINGS.with(|bindings| bindings.borrow_mut().push(before, core_index, bind_to));
+    let current = thread::current();
+    let name = current
+        .name()
+        .map(String::from)
+        .unwrap_or_else(|| format!("{:?}", current.id()));
+    THREAD_BINDINGS
+        .lock()
+        .expect("poisoned lock")
+        .insert(current.id(), (name, core_index));
+
+    Ok(Cleanup {
+        tid,
+        thread: current.id(),
+        id,
+    })
+}
+
+fn core_count() -> Result<usize> {
+    topology().core_count()
+}
+
+/// Splits the cores of `topo` into groups of `cores_per_unit` cores, the last group first. Only
+/// the cores the process is allowed to run on are grouped, see `CoreTopology::is_allowed`.
+///
+/// The groups are formed within the L3 cache domains of the NUMA nodes, so that the threads of a
+/// group share their caches and memory. If the L3 caches have fewer cores than a group, the groups
+/// are only kept within the NUMA nodes, and if the nodes have fewer cores too, or the topology is
+/// flat, the groups are consecutive cores. The cores left over after the last full group of a
+/// domain are not used.
+///
+/// No group mixes performance and efficiency cores, so that the producers of a group keep up with
+/// each other. The groups of performance cores come first, so they are checked out first, and
+/// the groups of efficiency cores are only formed with `efficiency_cores`.
+// fn core_groups(cores_per_unit: usize) -> Option<Vec<Mutex<Vec<CoreIndex>>>> {
+fn core_groups(
+    topo: &dyn CoreTopology,
+    cores_per_unit: usize,
+    efficiency_cores: bool,
+) -> Result<Vec<Mutex<Vec<CoreIndex>>>> {
+    let core_count = topo.core_count()?;
+    let domains = (0..core_count)
+        .map(|i| topo.core_domain(CoreIndex(i)))
+        .collect::<Result<Vec<_>>>()
+        .unwrap_or_else(|err| {
+            warn!(
+                "failed to get core domains, grouping consecutive cores: {:?}",
+                err
+            );
+            vec![CoreDomain::default(); core_count]
+        });
+
+    let allowed = (0..core_count)
+        .map(|i| topo.is_allowed(CoreIndex(i)))
+        .collect::<Result<Vec<_>>>()
+        .unwrap_or_else(|err| {
+            warn!(
+                "failed to get the allowed cores, grouping all cores: {:?}",
+                err
+            );
+            vec![true; core_count]
+        });
+    let disallowed = allowed.iter().filter(|allowed| !**allowed).count();
+    if disallowed > 0 {
+        info!(
+            "{} of {} cores are outside the cpuset of the process and not grouped",
+            disallowed, core_count
+        );
+    }
+
+    let kinds = (0..core_count)
+        .map(|i| topo.core_kind(CoreIndex(i)))
+        .collect::<Result<Vec<_>>>()
+        .unwrap_or_else(|err| {
+            warn!(
+                "failed to get the core kinds, using all cores as performance cores: {:?}",
+                err
+            );
+            vec![CoreKind::Performance; core_count]
+        });
+    let efficiency = kinds
+        .iter()
+        .filter(|kind| **kind == CoreKind::Efficiency)
+        .count();
+    if efficiency > 0 {
+        info!(
+            "{} of {} cores are efficiency cores, {}",
+            efficiency,
+            core_count,
+            if efficiency_cores {
+                "grouped after the performance cores"
+            } else {
+                "not grouped"
+            }
+        );
+    }
+
+    let group_kind = |kind: CoreKind| {
+        let cores = || {
+            domains
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| allowed[*i] && kinds[*i] == kind)
+                .map(|(i, domain)| (CoreIndex(i), domain))
+        };
+        let mut core_groups = group_by_domain(cores(), cores_per_unit);
+        if core_groups.is_empty() {
+            core_groups = group_by_domain(
+                cores().map(|(core, domain)| (core, domain.numa_node)),
+                cores_per_unit,
+            );
+        }
+        if core_groups.is_empty() {
+            core_groups = group_by_domain(cores().map(|(core, _)| (core, ())), cores_per_unit);
+        }
+        core_groups
+    };
+    let mut core_groups = group_kind(CoreKind::Performance);
+    if efficiency_cores {
+        core_groups.extend(group_kind(CoreKind::Efficiency));
+    }
+    debug!("core groups: {:?}", core_groups);
+
+    // Some(
+    Ok(core_groups
+        .iter()
+        .map(|group| Mutex::new(group.clone()))
+        .collect::<Vec<_>>())
+    // )
+}
+
+/// Groups the cores of `cores_per_unit` cores listed in `core_list`, see the
+/// `multicore_sdr_core_list` setting, the last group first. The groups are formed from runs of
+/// consecutive cores, and the cores left over after the last full group of a run are not used. A
+/// list without a run long enough for a single group is an error.
+fn core_groups_from_list(
+    topo: &dyn CoreTopology,
+    core_list: &str,
+    cores_per_unit: usize,
+) -> Result<Vec<Mutex<CoreGroup>>> {
+    let core_count = topo.core_count()?;
+    let cores = parse_core_list(core_list)?;
+    for core in &cores {
+        ensure!(
+            *core < core_count,
+            "core index {} is out of range for {} cores",
+            core,
+            core_count
+        );
+        ensure!(
+            topo.is_allowed(CoreIndex(*core))?,
+            "core index {} is outside the cpuset of the process",
+            core
+        );
+    }
+
+    let mut run = 0;
+    let runs = cores.iter().enumerate().map(|(i, core)| {
+        if i > 0 && cores[i - 1] + 1 != *core {
+            run += 1;
+        }
+        (CoreIndex(*core), run)
+    });
+    let core_groups = group_by_domain(runs, cores_per_unit);
+    debug!("core groups from core list: {:?}", core_groups);
+    ensure!(
+        !core_groups.is_empty(),
+        "core list '{}' has no run of {} consecutive cores",
+        core_list,
+        cores_per_unit
+    );
+
+    Ok(core_groups.into_iter().map(Mutex::new).collect())
+}
+
+/// Parses a comma separated list of core indexes and inclusive ranges of them, e.g. `0-5,12-17`.
+fn parse_core_list(core_list: &str) -> Result<Vec<usize>> {
+    let parse = |index: &str| -> Result<usize> {
+        index
+            .trim()
+            .parse()
+            .map_err(|err| format_err!("invalid core index '{}': {}", index.trim(), err))
+    };
+
+    let mut cores: Vec<usize> = Vec::new();
+    for item in core_list.split(',').map(str::trim) {
+        ensure!(!item.is_empty(), "empty entry in core list '{}'", core_list);
+        let (first, last) = match item.find('-') {
+            Some(dash) => (parse(&item[..dash])?, parse(&item[dash + 1..])?),
+            None => (parse(item)?, parse(item)?),
+        };
+        ensure!(first <= last, "invalid core range '{}'", item);
+        for core in first..=last {
+            ensure!(
+                !cores.contains(&core),
+                "core index {} is in the core list more than once",
+                core
+            );
+            cores.push(core);
+        }
+    }
+
+    Ok(cores)
+}
+
+/// Splits `cores`, each with its domain, into groups of `cores_per_unit` cores of the same domain,
+/// in the order of `cores`. The groups are returned the last first.
+fn group_by_domain<K: Ord>(
+    cores: impl IntoIterator<Item = (CoreIndex, K)>,
+    cores_per_unit: usize,
+) -> Vec<CoreGroup> {
+    let mut cores_by_domain: BTreeMap<K, CoreGroup> = BTreeMap::new();
+    for (core, domain) in cores {
+        cores_by_domain.entry(domain).or_default().push(core);
+    }
+
+    let mut groups: Vec<CoreGroup> = cores_by_domain
+        .values()
+        .flat_map(|cores| {
+            cores
+                .chunks_exact(cores_per_unit)
+                .map(<[CoreIndex]>::to_vec)
+        })
+        .collect();
+    groups.reverse();
+
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A machine of `numa_nodes` NUMA nodes with `cores_per_node` cores each, numbered node by
+    /// node the way hwloc numbers them.
+    struct SyntheticTopology {
+        numa_nodes: usize,
+        cores_per_node: usize,
+        /// The cores with two processing units, all others have one.
+        smt_cores: Vec<usize>,
+        /// The number of consecutive cores sharing an L3 cache, `None` if there is none.
+        cores_per_l3: Option<usize>,
+        /// The cores outside the cpuset of the process, as in a container.
+        disallowed_cores: Vec<usize>,
+        /// The efficiency cores of a hybrid CPU, all others are performance cores.
+        efficiency_cores: Vec<usize>,
+    }
+
+    impl CoreTopology for SyntheticTopology {
+        fn core_count(&self) -> Result<usize> {
+            Ok(self.numa_nodes * self.cores_per_node)
+        }
+
+        fn pu_count(&self, index: CoreIndex) -> Result<usize> {
+            ensure!(index.0 < self.core_count()?, "no core {}", index.0);
+            Ok(if self.smt_cores.contains(&index.0) {
+                2
+            } else {
+                1
+            })
+        }
+
+        fn numa_memory(&self) -> Result<Vec<(usize, u64)>> {
+            Ok((0..self.numa_nodes).map(|node| (node, 1 << 36)).collect())
+        }
+
+        fn core_domain(&self, index: CoreIndex) -> Result<CoreDomain> {
+            ensure!(index.0 < self.core_count()?, "no core {}", index.0);
+            Ok(CoreDomain {
+                numa_node: Some(index.0 / self.cores_per_node),
+                l3_cache: self.cores_per_l3.map(|cores| index.0 / cores),
+            })
+        }
+
+        fn is_allowed(&self, index: CoreIndex) -> Result<bool> {
+            ensure!(index.0 < self.core_count()?, "no core {}", index.0);
+            Ok(!self.disallowed_cores.contains(&index.0))
+        }
+
+        fn core_kind(&self, index: CoreIndex) -> Result<CoreKind> {
+            ensure!(index.0 < self.core_count()?, "no core {}", index.0);
+            Ok(if self.efficiency_cores.contains(&index.0) {
+                CoreKind::Efficiency
+            } else {
+                CoreKind::Performance
+            })
+        }
+    }
+
+    fn group_cores(groups: Vec<Mutex<CoreGroup>>) -> Vec<Vec<usize>> {
+        groups
+            .into_iter()
+            .map(|group| {
+                let group = group.into_inner().expect("poisoned lock");
+                group.iter().map(|core| core.0).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_core_groups_numa_aware() {
+        let mut topo = SyntheticTopology {
+            numa_nodes: 2,
+            cores_per_node: 8,
+            smt_cores: Vec::new(),
+            cores_per_l3: Some(4),
+            disallowed_cores: Vec::new(),
+            efficiency_cores: Vec::new(),
+        };
+
+        // No group spans two L3 caches.
+        let groups = group_cores(core_groups(&topo, 3, true).expect("core_groups failure"));
+        assert_eq!(
+            groups,
+            vec![
+                vec![12, 13, 14],
+                vec![8, 9, 10],
+                vec![4, 5, 6],
+                vec![0, 1, 2],
+            ]
+        );
+
+        // A group does not fit into an L3 cache, but into a NUMA node.
+        let groups = group_cores(core_groups(&topo, 6, true).expect("core_groups failure"));
+        assert_eq!(
+            groups,
+            vec![vec![8, 9, 10, 11, 12, 13], vec![0, 1, 2, 3, 4, 5]]
+        );
+
+        // A group does not fit into a NUMA node either.
+        let groups = group_cores(core_groups(&topo, 9, true).expect("core_groups failure"));
+        assert_eq!(groups, vec![(0..9).collect::<Vec<_>>()]);
+
+        // A flat topology is grouped by consecutive cores.
+        topo.numa_nodes = 1;
+        topo.cores_per_node = 7;
+        topo.cores_per_l3 = None;
+        let groups = group_cores(core_groups(&topo, 3, true).expect("core_groups failure"));
+        assert_eq!(groups, vec![vec![3, 4, 5], vec![0, 1, 2]]);
+    }
+
+    #[test]
+    fn test_core_groups_restricted_cpuset() {
+        // A container limited to cores 4-11, which span both NUMA nodes.
+        let mut topo = SyntheticTopology {
+            numa_nodes: 2,
+            cores_per_node: 8,
+            smt_cores: Vec::new(),
+            cores_per_l3: Some(4),
+            disallowed_cores: (0..4).chain(12..16).collect(),
+            efficiency_cores: Vec::new(),
+        };
+        let groups = group_cores(core_groups(&topo, 3, true).expect("core_groups failure"));
+        assert_eq!(groups, vec![vec![8, 9, 10], vec![4, 5, 6]]);
+        let groups = group_cores(core_groups(&topo, 4, true).expect("core_groups failure"));
+        assert_eq!(groups, vec![vec![8, 9, 10, 11], vec![4, 5, 6, 7]]);
+
+        // The cores left in an L3 cache are too few for a group, so the groups are formed within
+        // the NUMA nodes, from the allowed cores only.
+        topo.disallowed_cores = vec![1, 3, 5, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+        let groups = group_cores(core_groups(&topo, 3, true).expect("core_groups failure"));
+        assert_eq!(groups, vec![vec![0, 2, 4]]);
+
+        // No group at all if the cpuset is smaller than a group.
+        topo.disallowed_cores = (1..16).collect();
+        assert!(core_groups(&topo, 2, true)
+            .expect("core_groups failure")
+            .is_empty());
+
+        // A core list may not name cores outside the cpuset.
+        topo.disallowed_cores = vec![3];
+        assert_eq!(
+            group_cores(core_groups_from_list(&topo, "0-2", 3).expect("core list failure")),
+            vec![vec![0, 1, 2]]
+        );
+        assert!(core_groups_from_list(&topo, "0-5", 3).is_err());
+    }
+
+    #[test]
+    fn test_core_groups_hybrid() {
+        // A hybrid CPU with 8 performance cores followed by 8 efficiency cores, which share an L3
+        // cache in groups of 4.
+        let topo = SyntheticTopology {
+            numa_nodes: 1,
+            cores_per_node: 16,
+            smt_cores: Vec::new(),
+            cores_per_l3: Some(4),
+            disallowed_cores: Vec::new(),
+            efficiency_cores: (8..16).collect(),
+        };
+
+        // The groups of performance cores come first, and no group mixes the kinds.
+        let groups = group_cores(core_groups(&topo, 3, true).expect("core_groups failure"));
+        assert_eq!(
+            groups,
+            vec![
+                vec![4, 5, 6],
+                vec![0, 1, 2],
+                vec![12, 13, 14],
+                vec![8, 9, 10],
+            ]
+        );
+        let groups = group_cores(core_groups(&topo, 6, true).expect("core_groups failure"));
+        assert_eq!(
+            groups,
+            vec![vec![0, 1, 2, 3, 4, 5], vec![8, 9, 10, 11, 12, 13]]
+        );
+
+        // Without the efficiency cores, only the performance cores are grouped.
+        let groups = group_cores(core_groups(&topo, 3, false).expect("core_groups failure"));
+        assert_eq!(groups, vec![vec![4, 5, 6], vec![0, 1, 2]]);
+        assert!(core_groups(&topo, 9, false)
+            .expect("core_groups failure")
+            .is_empty());
+    }
+
+    #[test]
+    fn test_core_groups_from_list() {
+        let topo = SyntheticTopology {
+            numa_nodes: 2,
+            cores_per_node: 16,
+            smt_cores: Vec::new(),
+            cores_per_l3: None,
+            disallowed_cores: Vec::new(),
+            efficiency_cores: Vec::new(),
+        };
+        let groups_of = |core_list: &str, cores_per_unit: usize| {
+            group_cores(
+                core_groups_from_list(&topo, core_list, cores_per_unit)
+                    .expect("core_groups_from_list failure"),
+            )
+        };
+
+        // No group spans the gap between the runs.
+        assert_eq!(
+            groups_of("0-5,12-17", 4),
+            vec![vec![12, 13, 14, 15], vec![0, 1, 2, 3]]
+        );
+        assert_eq!(
+            groups_of("0-5, 12-17", 6),
+            vec![vec![12, 13, 14, 15, 16, 17], vec![0, 1, 2, 3, 4, 5]]
+        );
+        // Adjacent entries form one run, in the order of the list.
+        assert_eq!(groups_of("4,5,6-7", 2), vec![vec![6, 7], vec![4, 5]]);
+        assert_eq!(groups_of("9-11,3", 2), vec![vec![9, 10]]);
+
+        assert!(core_groups_from_list(&topo, "30-33", 2).is_err());
+        assert!(core_groups_from_list(&topo, "0,2,4,6", 2).is_err());
+        assert!(core_groups_from_list(&topo, "0-3,2", 2).is_err());
+        assert!(core_groups_from_list(&topo, "3-0", 2).is_err());
+        assert!(core_groups_from_list(&topo, "0,,1", 2).is_err());
+        assert!(core_groups_from_list(&topo, "a-b", 2).is_err());
+    }
+
+    #[test]
+    fn test_cores() {
+        let topo = topology();
+        core_groups(&*topo, 2, true).expect("core_groups failure");
+    }
+
+    #[test]
+    fn test_numa_memory() {
+        let topo = SyntheticTopology {
+            numa_nodes: 2,
+            cores_per_node: 8,
+            smt_cores: Vec::new(),
+            cores_per_l3: None,
+            disallowed_cores: Vec::new(),
+            efficiency_cores: Vec::new(),
+        };
+        assert_eq!(
+            numa_summary(&topo).expect("numa_summary failure"),
+            "16 cores, 2 NUMA nodes with 137438953472 bytes of memory, \
+             node 0: 68719476736 bytes, node 1: 68719476736 bytes"
+        );
+
+        let nodes = numa_memory();
+        assert_eq!(
+            nodes.iter().map(|(node, _)| *node).collect::<Vec<_>>(),
+            (0..nodes.len()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_core_groups_synthetic_topology() {
+        let topo = SyntheticTopology {
+            numa_nodes: 2,
+            cores_per_node: 8,
+            smt_cores: Vec::new(),
+            cores_per_l3: None,
+            disallowed_cores: Vec::new(),
+            efficiency_cores: Vec::new(),
+        };
+
+        // Groups which divide the cores of a node are the same as consecutive cores.
+        let groups = group_cores(core_groups(&topo, 4, true).expect("core_groups failure"));
+        assert_eq!(
+            groups,
+            vec![
+                vec![12, 13, 14, 15],
+                vec![8, 9, 10, 11],
+                vec![4, 5, 6, 7],
+                vec![0, 1, 2, 3],
+            ]
+        );
+
+        // The last cores of a node do not fill a group and are left out.
+        let groups = group_cores(core_groups(&topo, 3, true).expect("core_groups failure"));
+        assert_eq!(
+            groups,
+            vec![
+                vec![11, 12, 13],
+                vec![8, 9, 10],
+                vec![3, 4, 5],
+                vec![0, 1, 2],
+            ]
+        );
+
+        assert!(group_cores(core_groups(&topo, 17, true).expect("core_groups failure")).is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "isolated-testing")]
+    // This test should not be run while other tests are running, as
+    // the cores we're working with may otherwise be busy and cause a
+    // failure.
+    fn test_checkout_cores() {
+        let checkout1 = checkout_core_group();
+        dbg!(&checkout1);
+        let checkout2 = checkout_core_group();
+        dbg!(&checkout2);
+
+        // This test might fail if run on a machine with fewer than four cores.
+        match (checkout1, checkout2) {
+            (Some(c1), Some(c2)) => assert!(*c1 != *c2),
+            _ => panic!("failed to get two checkouts"),
+        }
+    }
+
+    #[test]
+    fn test_install_on_core_groups() {
+        use rayon::prelude::*;
+
+        // The work runs to completion whether or not a group is free.
+        let sum: u64 = install_on_core_groups(usize::MAX, 4, || (0..1000u64).into_par_iter().sum());
+        assert_eq!(sum, 499_500);
+
+        // The workers know the cores of their pool, if there is one, and other threads do not.
+        assert!(current_pool_cores().is_none());
+        let cores: Vec<Option<usize>> = install_on_core_groups(1, 4, || {
+            (0..8)
+                .into_par_iter()
+                .map(|_| current_pool_cores().map(|cores| cores.len()))
+                .collect()
+        });
+        assert!(cores.iter().all(|len| *len == cores[0]));
+
+        // Without any group to check out, the work runs unbound.
+        assert!(install_on_core_groups(0, 4, current_pool_cores).is_none());
+    }
+
+    #[test]
+    fn test_core_group_guard_dedicated_consumer() {
+        let group = Box::leak(Box::new(Mutex::new(
+            (0..4).map(CoreIndex).collect::<CoreGroup>(),
+        )));
+
+        {
+            let guard = CoreGroupGuard::new(group.lock().expect("poisoned lock"), 0, false);
+            assert!(!guard.has_dedicated_consumer());
+            assert_eq!(guard.consumer_core(), Some(&CoreIndex(0)));
+            assert_eq!(guard.producer_core(0), Some(&CoreIndex(1)));
+            assert_eq!(guard.producer_core(2), Some(&CoreIndex(3)));
+            assert_eq!(guard.producer_core(3), None);
+        }
+
+        {
+            let guard = CoreGroupGuard::new(group.lock().expect("poisoned lock"), 0, true);
+            assert!(guard.has_dedicated_consumer());
+            assert_eq!(guard.consumer_core(), Some(&CoreIndex(3)));
+            assert_eq!(guard.producer_core(0), Some(&CoreIndex(0)));
+            assert_eq!(guard.producer_core(2), Some(&CoreIndex(2)));
+            assert_eq!(guard.producer_core(3), None);
+        }
+
+        // A single core group has no room for a dedicated consumer.
+        let single = Box::leak(Box::new(Mutex::new(vec![CoreIndex(0)])));
+        let guard = CoreGroupGuard::new(single.lock().expect("poisoned lock"), 0, true);
+        assert!(!guard.has_dedicated_consumer());
+        assert_eq!(guard.consumer_core(), Some(&CoreIndex(0)));
+        assert_eq!(guard.producer_core(0), None);
+    }
+
+    #[test]
+    fn test_checkout_policy() {
+        assert_eq!(
+            CheckoutPolicy::from_name("first_free"),
+            Some(CheckoutPolicy::FirstFree)
+        );
+        assert_eq!(CheckoutPolicy::from_name("lru"), Some(CheckoutPolicy::Lru));
+        assert_eq!(CheckoutPolicy::from_name("random"), None);
+
+        let groups: &'static [Mutex<CoreGroup>] = Box::leak(
+            (0..3)
+                .map(|i| Mutex::new(vec![CoreIndex(i)]))
+                .collect::<Vec<_>>()
+                .into_boxed_slice(),
+        );
+        let last_used: Vec<AtomicU64> = groups.iter().map(|_| AtomicU64::new(0)).collect();
+        let checkout = |policy| {
+            checkout_from(groups, 0..groups.len(), &last_used, policy, false, None)
+                .and_then(|guard| guard.index())
+                .expect("no group checked out")
+        };
+
+        // One labeling at a time keeps taking the first group.
+        for _ in 0..4 {
+            assert_eq!(checkout(CheckoutPolicy::FirstFree), 0);
+        }
+
+        // The least recently used group is taken, so the checkouts cycle through all groups.
+        let order: Vec<usize> = (0..6).map(|_| checkout(CheckoutPolicy::Lru)).collect();
+        assert_eq!(order, vec![1, 2, 0, 1, 2, 0]);
+
+        // A checked out group is skipped, even if it is the least recently used one.
+        let held = groups[1].lock().expect("poisoned lock");
+        assert_eq!(checkout(CheckoutPolicy::Lru), 2);
+        assert_eq!(checkout(CheckoutPolicy::Lru), 0);
+        drop(held);
+        assert_eq!(checkout(CheckoutPolicy::Lru), 1);
+    }
+
+    #[test]
+    fn test_checkout_waiters() {
+        let mut waiters = CheckoutWaiters::default();
+        let low = waiters.add(0..2, 0);
+        let first_high = waiters.add(0..2, 5);
+        let second_high = waiters.add(0..2, 5);
+        let other_pool = waiters.add(2..3, 0);
+
+        // The highest priority goes first, in the order of waiting, and the pools do not wait
+        // for each other.
+        assert!(waiters.is_next(first_high));
+        assert!(!waiters.is_next(second_high));
+        assert!(!waiters.is_next(low));
+        assert!(waiters.is_next(other_pool));
+
+        waiters.remove(first_high);
+        assert!(waiters.is_next(second_high));
+        waiters.remove(second_high);
+        assert!(waiters.is_next(low));
+        waiters.remove(low);
+        assert!(!waiters.is_next(low));
+    }
+
+    #[test]
+    fn test_stats() {
+        // Other tests check out and bind concurrently, so only the counters which never go down
+        // are compared.
+        let before = stats();
+        assert_eq!(before.groups, CORE_GROUPS.len());
+
+        assert!(count_checkout(None).is_none());
+        {
+            let _tracker = track_labeling(None);
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let after = stats();
+        assert!(after.checkout_failures > before.checkout_failures);
+        assert!(after.unbound >= before.unbound + Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_release_core_group() {
+        let groups: &'static [Mutex<CoreGroup>] =
+            Box::leak(vec![Mutex::new(vec![CoreIndex(0)])].into_boxed_slice());
+        let last_used = vec![AtomicU64::new(0)];
+        assert!(!is_locked(&groups[0]));
+
+        let guard = checkout_from(
+            groups,
+            0..1,
+            &last_used,
+            CheckoutPolicy::FirstFree,
+            false,
+            None,
+        )
+        .expect("no group checked out");
+        assert!(is_locked(&groups[0]));
+        release_core_group(guard);
+        assert!(!is_locked(&groups[0]));
+        assert!(checkout_from(
+            groups,
+            0..1,
+            &last_used,
+            CheckoutPolicy::FirstFree,
+            false,
+            None
+        )
+        .is_some());
+
+        assert!(!is_group_checked_out(usize::MAX));
+    }
+
+    #[test]
+    fn test_reserve_cores() {
+        let dir = tempfile::tempdir().expect("tempdir failure");
+        // The directory is created by the first checkout.
+        let lock_dir = dir.path().join("cores");
+        let cores = |indexes: &[usize]| indexes.iter().map(|&i| CoreIndex(i)).collect::<Vec<_>>();
+        // Two processes forming the same groups, which only share the lock directory.
+        let process_groups = || -> &'static [Mutex<CoreGroup>] {
+            Box::leak(Box::new([
+                Mutex::new(cores(&[0, 1])),
+                Mutex::new(cores(&[2, 3])),
+            ]))
+        };
+        let (groups_a, groups_b) = (process_groups(), process_groups());
+        let last_used: Vec<AtomicU64> = (0..2).map(|_| AtomicU64::new(0)).collect();
+        let checkout = |groups| {
+            checkout_from(
+                groups,
+                0..2,
+                &last_used,
+                CheckoutPolicy::FirstFree,
+                false,
+                Some(&lock_dir),
+            )
+        };
+
+        let guard_a = checkout(groups_a).expect("no group checked out");
+        assert_eq!(guard_a.index(), Some(0));
+        assert!(core_lock_path(&lock_dir, CoreIndex(1)).exists());
+        // The first group of the other process is free in it, but its cores are reserved.
+        let guard_b = checkout(groups_b).expect("no group checked out");
+        assert_eq!(guard_b.index(), Some(1));
+        assert!(!is_locked(&groups_b[0]));
+        assert!(checkout(groups_b).is_none());
+
+        drop(guard_a);
+        assert_eq!(checkout(groups_b).and_then(|guard| guard.index()), Some(0));
+        // A group overlapping a reserved one is not checked out either.
+        assert!(reserve_cores(&lock_dir, &cores(&[4, 3]))
+            .expect("reserve_cores failure")
+            .is_none());
+        assert!(reserve_cores(&lock_dir, &cores(&[4]))
+            .expect("reserve_cores failure")
+            .is_some());
+    }
+
+    #[test]
+    fn test_core_group_pools() {
+        assert_eq!(
+            core_group_pools("", 4).expect("core_group_pools failure"),
+            CoreGroupPools::single(4)
+        );
+
+        let pools = core_group_pools("34359738368:2, 2048:1", 4).expect("core_group_pools failure");
+        assert_eq!(pools.pool_for(Some(1 << 35)), 0..2);
+        assert_eq!(pools.pool_for(Some(2048)), 2..3);
+        assert_eq!(pools.pool_for(Some(1 << 23)), 3..4);
+        assert_eq!(pools.pool_for(None), 3..4);
+
+        // The pools may take all groups, which leaves the default pool empty.
+        let pools = core_group_pools("2048:4", 4).expect("core_group_pools failure");
+        assert!(pools.pool_for(None).is_empty());
+
+        assert!(core_group_pools("2048:5", 4).is_err());
+        assert!(core_group_pools("2048:1,2048:1", 4).is_err());
+        assert!(core_group_pools("2048:0", 4).is_err());
+        assert!(core_group_pools("2KiB:1", 4).is_err());
+        assert!(core_group_pools("2048", 4).is_err());
+
+        // Only the groups of the pool are checked out.
+        let groups: &'static [Mutex<CoreGroup>] = Box::leak(
+            (0..3)
+                .map(|i| Mutex::new(vec![CoreIndex(i)]))
+                .collect::<Vec<_>>()
+                .into_boxed_slice(),
+        );
+        let last_used: Vec<AtomicU64> = groups.iter().map(|_| AtomicU64::new(0)).collect();
+        let guard = checkout_from(
+            groups,
+            1..2,
+            &last_used,
+            CheckoutPolicy::FirstFree,
+            false,
+            None,
+        )
+        .expect("no group checked out");
+        assert_eq!(guard.index(), Some(1));
+        assert!(checkout_from(
+            groups,
+            1..2,
+            &last_used,
+            CheckoutPolicy::FirstFree,
+            false,
+            None
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_intra_group_order() {
+        assert_eq!(
+            IntraGroupOrder::from_name("ascending"),
+            Some(IntraGroupOrder::Ascending)
+        );
+        assert_eq!(
+            IntraGroupOrder::from_name("descending"),
+            Some(IntraGroupOrder::Descending)
+        );
+        assert_eq!(IntraGroupOrder::from_name("random"), None);
+
+        let group = Box::leak(Box::new(Mutex::new(
+            (0..4).map(CoreIndex).collect::<CoreGroup>(),
+        )));
+        let producer_cores = |guard: &CoreGroupGuard| -> Vec<usize> {
+            (0..4)
+                .filter_map(|i| guard.producer_core(i))
+                .map(|core| core.0)
+                .collect()
+        };
+
+        {
+            let guard = CoreGroupGuard::new(group.lock().expect("poisoned lock"), 0, false)
+                .with_producer_order(IntraGroupOrder::Descending);
+            assert_eq!(guard.consumer_core(), Some(&CoreIndex(0)));
+            assert_eq!(producer_cores(&guard), vec![3, 2, 1]);
+        }
+
+        let guard = CoreGroupGuard::new(group.lock().expect("poisoned lock"), 0, true)
+            .with_producer_order(IntraGroupOrder::Descending);
+        assert_eq!(guard.consumer_core(), Some(&CoreIndex(3)));
+        assert_eq!(producer_cores(&guard), vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn test_consumer_smt_sibling() {
+        let topo = SyntheticTopology {
+            numa_nodes: 1,
+            cores_per_node: 4,
+            smt_cores: vec![1, 3],
+            cores_per_l3: None,
+            disallowed_cores: Vec::new(),
+            efficiency_cores: Vec::new(),
+        };
+        let siblings = smt_siblings(&topo).expect("smt_siblings failure");
+        assert_eq!(siblings, vec![false, true, false, true]);
+
+        let group = Box::leak(Box::new(Mutex::new(
+            (0..4).map(CoreIndex).collect::<CoreGroup>(),
+        )));
+
+        // The first producer runs on core 1, which has an SMT sibling.
+        {
+            let guard = CoreGroupGuard::new(group.lock().expect("poisoned lock"), 0, false);
+            assert_eq!(guard.consumer_smt_sibling(), None);
+            let guard = guard.share_producer_core(&siblings);
+            assert_eq!(guard.consumer_smt_sibling(), Some(&CoreIndex(1)));
+            assert_eq!(guard.producer_core(0), Some(&CoreIndex(1)));
+        }
+
+        // With a dedicated consumer, the first producer runs on core 0, which has none.
+        let guard = CoreGroupGuard::new(group.lock().expect("poisoned lock"), 0, true)
+            .share_producer_core(&siblings);
+        assert_eq!(guard.consumer_smt_sibling(), None);
+        assert_eq!(guard.consumer_core(), Some(&CoreIndex(3)));
+    }
+
+    #[test]
+    fn test_on_bind_failure() {
+        use std::sync::atomic::AtomicUsize;
+
+        static FAILURES: AtomicUsize = AtomicUsize::new(0);
+        static BIND_FAILURES: AtomicUsize = AtomicUsize::new(0);
+        // Other tests may fail to bind at the same time, so only the failures of this test are
+        // counted.
+        fn count_failure(core_index: CoreIndex, error: &str) {
+            if core_index == CoreIndex(usize::MAX) {
+                if error == "test failure" {
+                    FAILURES.fetch_add(1, Ordering::SeqCst);
+                } else {
+                    BIND_FAILURES.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+
+        report_bind_failure(CoreIndex(usize::MAX), "test failure");
+        assert_eq!(FAILURES.load(Ordering::SeqCst), 0);
+
+        set_on_bind_failure(Some(count_failure));
+        report_bind_failure(CoreIndex(usize::MAX), "test failure");
+        report_bind_failure(CoreIndex(usize::MAX), "test failure");
+        assert_eq!(FAILURES.load(Ordering::SeqCst), 2);
+
+        // A core which cannot be bound to at all is reported as well.
+        assert!(bind_core(CoreIndex(usize::MAX)).is_err());
+        assert_eq!(BIND_FAILURES.load(Ordering::SeqCst), 1);
+
+        set_on_bind_failure(None);
+        report_bind_failure(CoreIndex(usize::MAX), "test failure");
+        assert_eq!(FAILURES.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_binding_stack() {
+        let mut stack = BindingStack::default();
+        assert_eq!(stack.current(), None);
+        let outer = stack.push(Some("original"), CoreIndex(0), "outer");
+        let inner = stack.push(Some("outer"), CoreIndex(1), "inner");
+        assert_eq!(stack.current(), Some(CoreIndex(1)));
+        assert_eq!(stack.remove(inner), Restore::Binding("outer"));
+        assert_eq!(stack.current(), Some(CoreIndex(0)));
+        assert_eq!(stack.remove(outer), Restore::Original(Some("original")));
+        assert_eq!(stack.remove(outer), Restore::Nothing);
+        assert_eq!(stack.current(), None);
+
+        // The inner binding stays in place while the outer one is removed first, and then
+        // reverts to the original binding.
+        let outer = stack.push(Some("original"), CoreIndex(0), "outer");
+        let inner = stack.push(Some("outer"), CoreIndex(1), "inner");
+        assert_eq!(stack.remove(outer), Restore::Nothing);
+        assert_eq!(stack.current(), Some(CoreIndex(1)));
+        assert_eq!(stack.remove(inner), Restore::Original(Some("original")));
+    }
+
+    #[test]
+    fn test_nested_bind_core() {
+        let binding = || thread_binding(&topology(), get_thread_id());
+
+        let original = binding();
+        let last = CoreIndex::new(core_count().expect("core_count failure") - 1)
+            .expect("CoreIndex::new failure");
+        let outer = bind_core(CoreIndex(0)).expect("bind_core failure");
+        let outer_binding = binding();
+        let inner = bind_core(last).expect("bind_core failure");
+
+        assert_eq!(current_core_binding(), Some(last));
+        let name = thread::current().name().map(String::from);
+        let bound = |core_index: CoreIndex| {
+            current_core_bindings()
+                .iter()
+                .any(|(thread, core)| Some(thread) == name.as_ref() && *core == core_index)
+        };
+        assert!(bound(last));
+
+        drop(inner);
+        assert_eq!(binding(), outer_binding);
+        assert_eq!(current_core_binding(), Some(CoreIndex(0)));
+        assert!(bound(CoreIndex(0)));
+        drop(outer);
+        assert_eq!(binding(), original);
+        assert_eq!(current_core_binding(), None);
+        assert!(!bound(CoreIndex(0)));
+        assert!(BINDINGS.with(|bindings| bindings.borrow().bindings.is_empty()));
+    }
+
+    #[test]
+    fn test_core_group_from_subset() {
+        let core_count = core_count().expect("core_count failure");
+        assert!(CoreIndex::new(core_count).is_err());
+
+        let first = CoreIndex::new(0).expect("CoreIndex::new failure");
+        let group = core_group_from_subset(vec![first]).expect("core_group_from_subset failure");
+        assert_eq!(group.index(), None);
+        assert_eq!(*group, vec![first]);
+        assert_eq!(group.consumer_core(), Some(&first));
+        // The only producer shares the core of the consumer.
+        assert_eq!(group.producer_count(3), 1);
+
+        if core_count >= 3 {
+            let cores = vec![CoreIndex(0), CoreIndex(1), CoreIndex(2)];
+            let group = core_group_from_subset(cores).expect("core_group_from_subset failure");
+            assert_eq!(group.producer_count(5), 2);
+            assert_eq!(group.producer_count(1), 1);
+        }
+
+        assert!(core_group_from_subset(Vec::new()).is_err());
+        assert!(core_group_from_subset(vec![first, first]).is_err());
+        assert!(core_group_from_subset(vec![CoreIndex(core_count)]).is_err());
+    }
+}
@@ -0,0 +1,306 @@
+//! Labeling on a GPU with OpenCL, see [`create_labels_for_encoding_batch`].
+//!
+//! The labels of a layer depend on the labels before them, so every sector is labeled by a single
+//! GPU thread, one node after the other. Such a thread is a lot slower than a CPU core with the
+//! SHA extensions, the GPU only makes up for it by labeling many sectors at once. So there is no
+//! variant for a single sector, which is labeled faster on the CPU.
+
+use std::marker::PhantomData;
+use std::mem;
+
+use anyhow::{ensure, Context};
+use log::info;
+use merkletree::store::StoreConfig;
+use rust_gpu_tools::opencl;
+use storage_proofs_core::{
+    drgraph::Graph,
+    error::{Error, Result},
+    merkle::MerkleTreeTrait,
+    util::NODE_SIZE,
+};
+
+use crate::stacked::vanilla::{
+    create_label::{prepare_layers, read_layer, write_layer, write_layer_digest},
+    gpu::{default_device, device_lock, GpuSelection},
+    graph::{StackedBucketGraph, DEGREE},
+    params::Labels,
+    parents_source::ParentsSource,
+    proof::LayerState,
+};
+
+const SDR_KERNEL: &str = include_str!("sdr.cl");
+
+/// The number of nodes labeled by one run of the kernel, whose parents are uploaded at once. A
+/// run labels them one after the other, so it is kept short enough for the watchdog of drivers
+/// which abort long running kernels.
+const WINDOW_NODES: usize = 1 << 14;
+
+/// The labels are held on the GPU as the big endian words of their bytes.
+const LABEL_WORDS: usize = NODE_SIZE / 4;
+
+/// Labels the `sectors`, given by their replica id and the config their layers are stored with,
+/// which all use `graph`, at once on the GPU selected by `gpu`, the first one of several. The
+/// labels are the same as the ones of `single::create_labels_for_encoding`, and the labeling
+/// resumes at the first layer which is not complete for all sectors.
+///
+/// Both layers of every sector are held in GPU memory, and every layer is read back into a host
+/// buffer for the whole batch before it is written, so a batch needs two sectors of GPU memory
+/// and one sector of host memory per sector. OpenCL only reports the total memory of a device,
+/// which the batch is checked against, so a device which is used by others may still fail to
+/// allocate it.
+#[allow(clippy::type_complexity)]
+pub fn create_labels_for_encoding_batch<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]>>(
+    graph: &StackedBucketGraph<Tree::Hasher>,
+    parents_cache: &mut dyn ParentsSource,
+    layers: usize,
+    sectors: Vec<(T, StoreConfig)>,
+    gpu: &GpuSelection,
+) -> Result<Vec<(Labels<Tree>, Vec<LayerState>)>> {
+    ensure!(!sectors.is_empty(), "no sectors to label");
+    let nodes = graph.size();
+    ensure!(
+        nodes <= u32::MAX as usize,
+        "{} nodes are too many to label on the GPU",
+        nodes
+    );
+    let layer_size = nodes * NODE_SIZE;
+    let layer_words = nodes * LABEL_WORDS;
+    let window_nodes = WINDOW_NODES.min(nodes);
+
+    let device = gpu
+        .device()?
+        .or_else(default_device)
+        .ok_or_else(|| Error::GpuUnavailable("no GPU found to label on".to_string()))?;
+    let opencl_device = device.opencl_device().ok_or_else(|| {
+        Error::GpuUnavailable(format!("{} does not support OpenCL", device.name()))
+    })?;
+    let required = 2 * sectors.len() as u64 * layer_size as u64
+        + (window_nodes * DEGREE * mem::size_of::<u32>()) as u64;
+    if device.memory() < required {
+        return Err(Error::GpuUnavailable(format!(
+            "labeling {} sectors needs {} bytes of GPU memory, {} has {}",
+            sectors.len(),
+            required,
+            device.name(),
+            device.memory()
+        ))
+        .into());
+    }
+
+    info!(
+        "generate labels of {} sectors on {}",
+        sectors.len(),
+        device.name()
+    );
+    let layer_states: Vec<Vec<LayerState>> = sectors
+        .iter()
+        .map(|(replica_id, config)| {
+            prepare_layers::<Tree>(graph, config, layers, replica_id.as_ref())
+        })
+        .collect();
+    let first_layer = layer_states
+        .iter()
+        .map(|states| {
+            states
+                .iter()
+                .position(|state| !state.generated)
+                .unwrap_or(layers)
+        })
+        .min()
+        .expect("there are sectors")
+        + 1;
+
+    let gpu_lock = device_lock(Some(device));
+    let _gpu_lock = gpu_lock.lock().expect("failed to get gpu lock");
+    let program = opencl::Program::from_opencl(opencl_device, SDR_KERNEL)?;
+    let replica_ids: Vec<u32> = sectors
+        .iter()
+        .flat_map(|(replica_id, _)| be_words(replica_id.as_ref()))
+        .collect();
+    let replica_ids = program.create_buffer_from_slice(&replica_ids)?;
+    let (mut layer, mut exp_layer) = unsafe {
+        (
+            program.create_buffer::<u32>(sectors.len() * layer_words)?,
+            program.create_buffer::<u32>(sectors.len() * layer_words)?,
+        )
+    };
+    let mut parents = unsafe { program.create_buffer::<u32>(window_nodes * DEGREE)? };
+
+    let mut words = vec![0u32; sectors.len() * layer_words];
+    let mut labels = vec![0u8; layer_size];
+    let mut window = vec![0u32; window_nodes * DEGREE];
+
+    if first_layer > 1 && first_layer <= layers {
+        info!("uploading layer {}", first_layer - 1);
+        for (states, words) in layer_states.iter().zip(words.chunks_mut(layer_words)) {
+            read_layer(&states[first_layer - 2].config, &mut labels)?;
+            for (word, bytes) in words.iter_mut().zip(labels.chunks(4)) {
+                *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            }
+        }
+        program.write_from_buffer(&mut exp_layer, &words)?;
+    }
+
+    for layer_index in first_layer..=layers {
+        info!("generating layer: {}", layer_index);
+
+        parents_cache.reset()?;
+        for first_node in (0..nodes).step_by(window_nodes) {
+            let nodes_in_window = window_nodes.min(nodes - first_node);
+            for (i, node_parents) in window.chunks_mut(DEGREE).take(nodes_in_window).enumerate() {
+                node_parents.copy_from_slice(&parents_cache.parents((first_node + i) as u32)?);
+            }
+            program.write_from_buffer(&mut parents, &window)?;
+
+            program
+                .create_kernel("sdr_label_window", sectors.len(), 1)?
+                .arg(&layer)
+                .arg(&exp_layer)
+                .arg(&parents)
+                .arg(&replica_ids)
+                .arg(&(sectors.len() as u32))
+                .arg(&(nodes as u32))
+                .arg(&(first_node as u32))
+                .arg(&(nodes_in_window as u32))
+                .arg(&(layer_index as u32))
+                .run()?;
+        }
+
+        info!("  storing labels on disk");
+        program.read_into_buffer(&layer, &mut words)?;
+        for (((replica_id, _), states), words) in sectors
+            .iter()
+            .zip(&layer_states)
+            .zip(words.chunks(layer_words))
+        {
+            let layer_state = &states[layer_index - 1];
+            // A sector which resumes at a later layer already has this one.
+            if layer_state.generated {
+                continue;
+            }
+            for (bytes, word) in labels.chunks_mut(4).zip(words) {
+                bytes.copy_from_slice(&word.to_be_bytes());
+            }
+            write_layer(&labels, &layer_state.config).context("failed to store labels")?;
+            write_layer_digest(
+                &labels,
+                &layer_state.config,
+                replica_id.as_ref(),
+                layer_index,
+            )?;
+        }
+
+        mem::swap(&mut layer, &mut exp_layer);
+    }
+
+    Ok(layer_states
+        .into_iter()
+        .map(|states| {
+            (
+                Labels::<Tree> {
+                    labels: states.iter().map(|s| s.config.clone()).collect(),
+                    _h: PhantomData,
+                },
+                states,
+            )
+        })
+        .collect())
+}
+
+/// Returns the big endian words of `bytes`.
+fn be_words(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks(4)
+        .map(|word| u32::from_be_bytes([word[0], word[1], word[2], word[3]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use filecoin_hashers::poseidon::PoseidonHasher;
+    use generic_array::typenum::{U0, U8};
+    use rust_gpu_tools::Device;
+    use storage_proofs_core::{
+        api_version::ApiVersion, cache_key::CacheKey, drgraph::BASE_DEGREE, merkle::LCTree,
+    };
+    use tempfile::tempdir;
+
+    use crate::stacked::vanilla::{create_label::single, graph::EXP_DEGREE};
+
+    #[test]
+    fn test_gpu_labels_match_cpu() {
+        type Tree = LCTree<PoseidonHasher, U8, U0, U0>;
+
+        assert!(
+            !Device::all().is_empty(),
+            "the gpu-sdr feature is enabled, but no GPU was found to test it on"
+        );
+
+        let layers = 3;
+        let nodes = 64;
+        let graph = StackedBucketGraph::<PoseidonHasher>::new_stacked(
+            nodes,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            [7; 32],
+            ApiVersion::V1_1_0,
+        )
+        .expect("stacked bucket graph new failed");
+
+        let read_layers = |labels: &Labels<Tree>| -> Vec<Vec<u8>> {
+            labels
+                .labels
+                .iter()
+                .map(|label| {
+                    fs::read(StoreConfig::data_path(&label.path, &label.id))
+                        .expect("failed to read layer")
+                })
+                .collect()
+        };
+
+        let replica_ids = [[1u8; 32], [2u8; 32]];
+        let cache_dirs = [
+            tempdir().expect("tempdir failure"),
+            tempdir().expect("tempdir failure"),
+        ];
+        let config = |dir: &tempfile::TempDir| {
+            StoreConfig::new(
+                dir.path(),
+                CacheKey::CommDTree.to_string(),
+                nodes.trailing_zeros() as usize,
+            )
+        };
+
+        let sectors = replica_ids
+            .iter()
+            .zip(&cache_dirs)
+            .map(|(replica_id, dir)| (replica_id, config(dir)))
+            .collect();
+        let labeled = create_labels_for_encoding_batch::<Tree, _>(
+            &graph,
+            &mut graph.parent_cache().expect("parent_cache failed"),
+            layers,
+            sectors,
+            &GpuSelection::Default,
+        )
+        .expect("create_labels_for_encoding_batch failed");
+
+        for (replica_id, (labels, _)) in replica_ids.iter().zip(&labeled) {
+            let cpu_dir = tempdir().expect("tempdir failure");
+            let (cpu_labels, _) = single::create_labels_for_encoding::<Tree, _>(
+                &graph,
+                &mut graph.parent_cache().expect("parent_cache failed"),
+                layers,
+                replica_id,
+                config(&cpu_dir),
+            )
+            .expect("create_labels_for_encoding failed");
+
+            assert_eq!(read_layers(labels), read_layers(&cpu_labels));
+        }
+    }
+}
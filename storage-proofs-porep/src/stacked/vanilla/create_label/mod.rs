@@ -1,41 +1,115 @@
 use std::fs::{self, create_dir_all, remove_file, rename, File};
-use std::io::{self, BufReader};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use anyhow::Context;
+use anyhow::{format_err, Context};
 use filecoin_hashers::Hasher;
 use log::{info, warn};
 use merkletree::{merkle::Element, store::StoreConfig};
+use sha2::{Digest, Sha256};
+use sha2raw::{implementation, set_implementation, Implementation};
 use storage_proofs_core::{
-    cache_key::CacheKey, drgraph::Graph, error::Result, merkle::MerkleTreeTrait,
+    cache_key::CacheKey, drgraph::Graph, error::Result, merkle::MerkleTreeTrait, settings::SETTINGS,
 };
 
-use crate::stacked::vanilla::{proof::LayerState, StackedBucketGraph};
+use crate::stacked::vanilla::{async_write::write_file, proof::LayerState, StackedBucketGraph};
 
-#[cfg(feature = "multicore-sdr")]
+#[cfg(feature = "gpu-sdr")]
+pub mod gpu;
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
 pub mod multi;
 pub mod single;
 
+/// Selects the SHA-256 implementation used for labeling according to the `sha256_implementation`
+/// setting. With `auto`, the fastest implementation supported by the CPU is used. Otherwise the
+/// named implementation is forced, and it is an error if it is not available.
+pub fn select_sha256_implementation() -> Result<()> {
+    let name = SETTINGS.sha256_implementation.as_str();
+    if name != "auto" {
+        let forced = Implementation::from_name(name)
+            .ok_or_else(|| format_err!("SHA-256 implementation '{}' is not available", name))?;
+        set_implementation(forced);
+    }
+    info!("using {} SHA-256 implementation", implementation().name());
+
+    Ok(())
+}
+
+/// The counters behind `labeling_stats`.
+static SINGLE_CORE_LABELINGS: AtomicU64 = AtomicU64::new(0);
+static MULTI_CORE_LABELINGS: AtomicU64 = AtomicU64::new(0);
+
+/// How sectors were labeled for sealing since the process started, see `labeling_stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LabelingStats {
+    /// The number of sectors labeled on a single core.
+    pub single_core: u64,
+    /// The number of sectors labeled by multicore SDR, whether on a core group or unbound.
+    pub multi_core: u64,
+    /// The SHA-256 implementation the labels are hashed with, see `select_sha256_implementation`.
+    pub sha256_implementation: &'static str,
+}
+
+/// Returns how sectors were labeled for sealing since the process started, e.g. to confirm that
+/// multicore SDR is used where it is configured.
+pub fn labeling_stats() -> LabelingStats {
+    LabelingStats {
+        single_core: SINGLE_CORE_LABELINGS.load(Ordering::Relaxed),
+        multi_core: MULTI_CORE_LABELINGS.load(Ordering::Relaxed),
+        sha256_implementation: implementation().name(),
+    }
+}
+
+/// Counts a sector labeled for sealing, by multicore SDR if `multi_core` is set.
+pub(crate) fn count_labeling(multi_core: bool) {
+    if multi_core {
+        MULTI_CORE_LABELINGS.fetch_add(1, Ordering::Relaxed);
+    } else {
+        SINGLE_CORE_LABELINGS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
 /// Prepares the necessary `StoreConfig`s with which the layers are stored.
 /// Also checks for already existing layers and marks them as such.
+///
+/// This is what lets an interrupted labeling resume: the layers from the first one on which is
+/// not complete, see `is_layer_complete`, are generated again, and the ones before it are read
+/// from disk. Every layer is read once to check its digest.
 pub fn prepare_layers<Tree: 'static + MerkleTreeTrait>(
     graph: &StackedBucketGraph<Tree::Hasher>,
     config: &StoreConfig,
     layers: usize,
+    replica_id: &[u8],
 ) -> Vec<LayerState> {
     let label_configs = (1..=layers).map(|layer| {
         StoreConfig::from_config(config, CacheKey::label_layer(layer), Some(graph.size()))
     });
 
     let mut states = Vec::with_capacity(layers);
+    let mut resumed = false;
     for (layer, label_config) in (1..=layers).zip(label_configs) {
         // Clear possible left over tmp files
         remove_tmp_layer(&label_config);
 
         // Check if this layer is already on disk
-        let generated = is_layer_written::<Tree>(graph, &label_config).unwrap_or_default();
+        let generated = !resumed
+            && is_layer_complete::<Tree>(graph, &label_config, replica_id, layer).unwrap_or_else(
+                |err| {
+                    warn!("failed to check layer {}: {:?}", layer, err);
+                    false
+                },
+            );
         if generated {
             // succesful load
             info!("found valid labels for layer {}", layer);
+        } else {
+            if !resumed && layer > 1 {
+                info!("resuming labeling at layer {}", layer);
+            }
+            resumed = true;
+            // The layer is written again, and its digest must not outlive the old contents.
+            remove_layer_digest(&label_config);
         }
 
         states.push(LayerState {
@@ -48,19 +122,186 @@ pub fn prepare_layers<Tree: 'static + MerkleTreeTrait>(
 }
 
 /// Stores a layer atomically on disk, by writing first to `.tmp` and then renaming.
+///
+/// The `.tmp` file is written to `SETTINGS.scratch_dir` if set, and next to the layer otherwise.
+/// It is removed again if storing the layer fails.
 pub fn write_layer(data: &[u8], config: &StoreConfig) -> Result<()> {
     let data_path = StoreConfig::data_path(&config.path, &config.id);
-    let tmp_data_path = data_path.with_extension(".tmp");
+    let tmp_data_path = tmp_layer_path(&data_path);
 
     if let Some(parent) = data_path.parent() {
         create_dir_all(parent).context("failed to create parent directories")?;
     }
-    fs::write(&tmp_data_path, data).context("failed to write layer data")?;
-    rename(tmp_data_path, data_path).context("failed to rename tmp data")?;
+    if let Some(parent) = tmp_data_path.parent() {
+        create_dir_all(parent).context("failed to create scratch directory")?;
+    }
+
+    let result = write_file(&tmp_data_path, data)
+        .context("failed to write layer data")
+        .and_then(|_| move_layer(&tmp_data_path, &data_path));
+    if result.is_err() && tmp_data_path.exists() {
+        if let Err(err) = remove_file(&tmp_data_path) {
+            warn!("failed to delete tmp file: {}", err);
+        }
+    }
+
+    result
+}
+
+/// Stores the labels `data` of `layer` as `config` with `write_layer`, and records their digest.
+pub fn store_layer(
+    data: &[u8],
+    config: &StoreConfig,
+    replica_id: &[u8],
+    layer: usize,
+) -> Result<()> {
+    info!("  storing labels on disk");
+    write_layer(data, config).context("failed to store labels")?;
+    write_layer_digest(data, config, replica_id, layer)?;
+    info!("  generated layer {} store with id {}", layer, config.id);
+
+    Ok(())
+}
+
+/// Runs `label` while the labels `data` of `layer` are stored with `store_layer` on another
+/// thread, so that labeling the next layer, which only reads `data`, does not wait for the disk.
+/// Returns once both are done.
+///
+/// The storing thread runs on any core of the process, rather than on the core the calling thread
+/// may be bound to, which labeling keeps busy.
+pub fn store_layer_while<R>(
+    data: &[u8],
+    config: &StoreConfig,
+    replica_id: &[u8],
+    layer: usize,
+    label: impl FnOnce() -> R,
+) -> Result<R> {
+    crossbeam::thread::scope(|s| {
+        let store = s.spawn(|_| {
+            #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+            {
+                if let Err(err) = crate::stacked::vanilla::cores::unbind_current_thread() {
+                    warn!(
+                        "failed to unbind the thread storing layer {}: {:?}",
+                        layer, err
+                    );
+                }
+            }
+            store_layer(data, config, replica_id, layer)
+        });
+        let labeled = label();
+        store
+            .join()
+            .expect("failed to join storing the layer")
+            .map(|_| labeled)
+    })
+    .expect("failed to store the layer")
+}
+
+/// Returns the path of the file holding the digest of the layer stored as `config`, see
+/// `write_layer_digest`.
+pub fn layer_digest_path(config: &StoreConfig) -> PathBuf {
+    StoreConfig::data_path(&config.path, &config.id).with_extension("digest")
+}
+
+/// Hashes the labels of `layer`, read from `data`, together with `replica_id` and the layer, so
+/// that the digest of a layer does not match the layer of another sector or another layer.
+fn layer_digest<R: Read>(replica_id: &[u8], layer: usize, mut data: R) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(replica_id);
+    hasher.update(&(layer as u64).to_le_bytes());
+
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        let read = data.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Records the digest of the labels `data` of `layer`, which were just stored as `config` with
+/// `write_layer`. It marks the layer as complete, so the digest is only written once the layer is
+/// in place, and written atomically itself.
+pub fn write_layer_digest(
+    data: &[u8],
+    config: &StoreConfig,
+    replica_id: &[u8],
+    layer: usize,
+) -> Result<()> {
+    let digest = layer_digest(replica_id, layer, data)?;
+    let path = layer_digest_path(config);
+    let tmp_path = path.with_extension("digest.tmp");
+    fs::write(&tmp_path, digest)
+        .with_context(|| format!("failed to write layer digest {:?}", tmp_path))?;
+    rename(&tmp_path, &path)
+        .with_context(|| format!("failed to rename layer digest {:?}", tmp_path))?;
+
+    Ok(())
+}
+
+/// Removes the digest of the layer stored as `config`, if there is one.
+pub fn remove_layer_digest(config: &StoreConfig) {
+    let path = layer_digest_path(config);
+    if path.exists() {
+        if let Err(err) = remove_file(&path) {
+            warn!("failed to delete layer digest {:?}: {}", path, err);
+        }
+    }
+}
+
+/// Returns the path of the `.tmp` file a layer is written to before it is moved into place.
+///
+/// Files in the scratch dir are shared by all sectors, so their name is made unique by the
+/// hash of the layer path.
+fn tmp_layer_path(data_path: &Path) -> PathBuf {
+    let tmp_data_path = data_path.with_extension(".tmp");
+
+    match &SETTINGS.scratch_dir {
+        Some(scratch_dir) => {
+            let digest = Sha256::digest(data_path.to_string_lossy().as_bytes());
+            let file_name = tmp_data_path
+                .file_name()
+                .expect("layer path has a file name")
+                .to_string_lossy();
+
+            Path::new(scratch_dir).join(format!("{}-{}", hex::encode(&digest[..8]), file_name))
+        }
+        None => tmp_data_path,
+    }
+}
+
+/// Moves the `.tmp` file into place. If the scratch dir is on a different file system than the
+/// layer, this falls back to copying, after which the `.tmp` file is removed.
+fn move_layer(tmp_data_path: &Path, data_path: &Path) -> Result<()> {
+    match rename(tmp_data_path, data_path) {
+        Ok(()) => return Ok(()),
+        Err(err) if !is_cross_device(&err) => {
+            return Err(err).context("failed to rename tmp data");
+        }
+        Err(_) => {}
+    }
+
+    fs::copy(tmp_data_path, data_path).context("failed to copy tmp data")?;
+    remove_file(tmp_data_path).context("failed to remove tmp data")?;
 
     Ok(())
 }
 
+/// Returns whether a rename failed because the paths are on different file systems.
+#[cfg(unix)]
+fn is_cross_device(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EXDEV)
+}
+
+#[cfg(windows)]
+fn is_cross_device(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(winapi::winerror::ERROR_NOT_SAME_DEVICE as i32)
+}
+
 /// Reads a layer from disk, into the provided slice.
 pub fn read_layer(config: &StoreConfig, mut data: &mut [u8]) -> Result<()> {
     let data_path = StoreConfig::data_path(&config.path, &config.id);
@@ -73,7 +314,7 @@ pub fn read_layer(config: &StoreConfig, mut data: &mut [u8]) -> Result<()> {
 
 pub fn remove_tmp_layer(config: &StoreConfig) {
     let data_path = StoreConfig::data_path(&config.path, &config.id);
-    let tmp_data_path = data_path.with_extension(".tmp");
+    let tmp_data_path = tmp_layer_path(&data_path);
     if tmp_data_path.exists() {
         if let Err(err) = remove_file(tmp_data_path) {
             warn!("failed to delete tmp file: {}", err);
@@ -81,6 +322,39 @@ pub fn remove_tmp_layer(config: &StoreConfig) {
     }
 }
 
+/// Removes the `.tmp` files in `SETTINGS.scratch_dir` which layers of `cache_dir` are written to,
+/// e.g. those an interrupted labeling left behind. The files are matched by the hash of the layer
+/// path, so `cache_dir` has to be given as it was when the layers were written.
+pub fn remove_scratch_layers(cache_dir: &Path) -> Result<()> {
+    let scratch_dir = match &SETTINGS.scratch_dir {
+        Some(scratch_dir) if Path::new(scratch_dir).is_dir() => Path::new(scratch_dir),
+        _ => return Ok(()),
+    };
+
+    for entry in fs::read_dir(scratch_dir)
+        .with_context(|| format!("could not read scratch dir={:?}", scratch_dir))?
+    {
+        let path = entry?.path();
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        // The name is the hash of the layer path and the name of the `.tmp` file next to it.
+        let stem = match file_name
+            .split_once('-')
+            .and_then(|(_, name)| name.strip_suffix("..tmp"))
+        {
+            Some(stem) => stem,
+            None => continue,
+        };
+        if tmp_layer_path(&cache_dir.join(format!("{}.dat", stem))) == path {
+            remove_file(&path).with_context(|| format!("could not remove path={:?}", path))?;
+        }
+    }
+
+    Ok(())
+}
+
 /// Checks if the given layer is already written and of the right size.
 pub fn is_layer_written<Tree: 'static + MerkleTreeTrait>(
     graph: &StackedBucketGraph<Tree::Hasher>,
@@ -101,3 +375,33 @@ pub fn is_layer_written<Tree: 'static + MerkleTreeTrait>(
 
     Ok(true)
 }
+
+/// Checks if the given layer is written, see `is_layer_written`, and its labels match the digest
+/// `write_layer_digest` recorded for `replica_id`. A layer without a digest, e.g. one whose
+/// labeling was interrupted before it was recorded, is not complete.
+pub fn is_layer_complete<Tree: 'static + MerkleTreeTrait>(
+    graph: &StackedBucketGraph<Tree::Hasher>,
+    config: &StoreConfig,
+    replica_id: &[u8],
+    layer: usize,
+) -> Result<bool> {
+    if !is_layer_written::<Tree>(graph, config)? {
+        return Ok(false);
+    }
+    let expected = match fs::read_to_string(layer_digest_path(config)) {
+        Ok(expected) => expected,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err).context("failed to read layer digest"),
+    };
+
+    let data_path = StoreConfig::data_path(&config.path, &config.id);
+    let file = File::open(&data_path).context("failed to open layer")?;
+    let digest =
+        layer_digest(replica_id, layer, BufReader::new(file)).context("failed to hash layer")?;
+    if digest != expected.trim() {
+        warn!("labels of layer {} do not match their digest", layer);
+        return Ok(false);
+    }
+
+    Ok(true)
+}
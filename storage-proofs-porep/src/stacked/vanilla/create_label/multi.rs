@@ -1,22 +1,19 @@
 use std::convert::TryInto;
 use std::marker::PhantomData;
 use std::mem::{self, size_of};
+use std::path::{Path, PathBuf};
 use std::sync::{
-    atomic::{AtomicU64, Ordering::SeqCst},
-    Arc, MutexGuard,
+    atomic::{AtomicBool, AtomicU64, Ordering::SeqCst},
+    Arc,
 };
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
-use byte_slice_cast::{AsByteSlice, AsMutSliceOf};
+use anyhow::{ensure, Result};
+use byte_slice_cast::{AsByteSlice, AsMutSliceOf, AsSliceOf};
 use filecoin_hashers::Hasher;
-use generic_array::{
-    typenum::{Unsigned, U64},
-    GenericArray,
-};
-use log::{debug, info};
-use mapr::MmapMut;
+use generic_array::typenum::Unsigned;
+use log::{debug, info, warn};
 use merkletree::store::{DiskStore, Store, StoreConfig};
 use storage_proofs_core::{
     cache_key::CacheKey,
@@ -27,11 +24,14 @@ use storage_proofs_core::{
 };
 
 use crate::stacked::vanilla::{
-    cache::ParentCache,
-    cores::{bind_core, checkout_core_group, CoreIndex},
-    create_label::{prepare_layers, read_layer, write_layer},
+    cache::{numa_local_parent_cache, ParentCache},
+    cores::{
+        bind_core, bind_smt_sibling, checkout_core_group_for, current_core_bindings,
+        track_labeling, CoreGroupGuard,
+    },
+    create_label::{count_labeling, prepare_layers, read_layer, store_layer, store_layer_while},
     graph::{StackedBucketGraph, DEGREE, EXP_DEGREE},
-    memory_handling::{setup_create_label_memory, CacheReader},
+    memory_handling::{setup_create_label_memory, BufferPool, CacheReader},
     params::{Labels, LabelsCache},
     proof::LayerState,
     utils::{memset, prepare_block, BitMask, RingBuf, UnsafeSlice},
@@ -39,6 +39,10 @@ use crate::stacked::vanilla::{
 
 const MIN_BASE_PARENT_NODE: u64 = 2000;
 
+/// The largest `multicore_sdr_lookahead` accepted. Every node of the lookahead buffer takes
+/// 512 bytes, the parents of the node and a SHA block, so this bounds the buffer at 32MiB.
+const MAX_MULTICORE_SDR_LOOKAHEAD: usize = 1 << 16;
+
 const NODE_WORDS: usize = NODE_SIZE / size_of::<u32>();
 const SHA_BLOCK_SIZE: usize = 64;
 
@@ -59,7 +63,7 @@ fn fill_buffer(
     parents_cache: &CacheReader<u32>,
     mut cur_parent: &[u32], // parents for this node
     layer_labels: &UnsafeSlice<'_, u32>,
-    exp_labels: Option<&UnsafeSlice<'_, u32>>, // None for layer0
+    exp_labels: Option<&[u32]>, // None for layer0
     buf: &mut [u8],
     base_parent_missing: &mut BitMask,
 ) {
@@ -111,10 +115,8 @@ fn fill_buffer(
     if let Some(exp_labels) = exp_labels {
         // Read from each of the expander parent nodes
         for k in BASE_DEGREE..DEGREE {
-            let parent_data = unsafe {
-                let offset = cur_parent[0] as usize * NODE_WORDS;
-                &exp_labels.as_slice()[offset..offset + NODE_WORDS]
-            };
+            let offset = cur_parent[0] as usize * NODE_WORDS;
+            let parent_data = &exp_labels[offset..offset + NODE_WORDS];
             let a = SHA_BLOCK_SIZE + (NODE_SIZE * k);
             buf[a..a + NODE_SIZE].copy_from_slice(parent_data.as_byte_slice());
             cur_parent = &cur_parent[1..];
@@ -141,7 +143,7 @@ fn fill_buffer(
 fn create_label_runner(
     parents_cache: &CacheReader<u32>,
     layer_labels: &UnsafeSlice<'_, u32>,
-    exp_labels: Option<&UnsafeSlice<'_, u32>>, // None for layer 0
+    exp_labels: Option<&[u32]>, // None for layer 0
     num_nodes: u64,
     cur_producer: &AtomicU64,
     cur_awaiting: &AtomicU64,
@@ -200,29 +202,80 @@ fn create_label_runner(
     }
 }
 
+/// Ensures that `lookahead` nodes of parents can be loaded ahead of the consumer by producers
+/// which each load `producer_stride` nodes at a time.
+///
+/// A producer only publishes its nodes once all of them are loaded, and it cannot load a node more
+/// than `lookahead` nodes ahead of the consumer, so a lookahead shorter than the stride deadlocks.
+fn check_lookahead(lookahead: usize, producer_stride: u64) -> Result<()> {
+    ensure!(
+        lookahead as u64 >= producer_stride && lookahead <= MAX_MULTICORE_SDR_LOOKAHEAD,
+        "multicore_sdr_lookahead {} is out of range, it has to be between the producer stride {} \
+         and {}",
+        lookahead,
+        producer_stride,
+        MAX_MULTICORE_SDR_LOOKAHEAD
+    );
+    Ok(())
+}
+
+/// Returns the parent cache which the labeling on `core_group` reads: the copy for the NUMA node of
+/// the group with `multicore_sdr_numa_parent_cache`, else the one `parents_cache` reads, which it
+/// falls back to if the copy fails. The copy is made next to the cache on disk, not to its copy in
+/// `parent_cache_shm_dir`, which would take shared memory of the size of the cache per node.
+fn local_parent_cache(parents_cache: &ParentCache, core_group: Option<&CoreGroupGuard>) -> PathBuf {
+    let path = &parents_cache.path;
+    if !SETTINGS.multicore_sdr_numa_parent_cache {
+        return path.to_path_buf();
+    }
+
+    match core_group.and_then(|group| group.numa_node()) {
+        Some(node) => {
+            numa_local_parent_cache(&parents_cache.disk_path, node).unwrap_or_else(|err| {
+                warn!(
+                    "failed to copy the parent cache for NUMA node {}, using {}: {:?}",
+                    node,
+                    path.display(),
+                    err
+                );
+                path.to_path_buf()
+            })
+        }
+        None => path.to_path_buf(),
+    }
+}
+
+/// Returns the number of nodes a producer loads at once, which must not exceed the number of nodes
+/// in parents_cache's window. If it does, the process will deadlock with producers and consumers
+/// waiting for each other.
+fn producer_stride(parents_cache: &CacheReader<u32>) -> u64 {
+    SETTINGS
+        .multicore_sdr_producer_stride
+        .min(parents_cache.window_nodes() as u64)
+}
+
 fn create_layer_labels(
     parents_cache: &CacheReader<u32>,
     replica_id: &[u8],
-    layer_labels: &mut MmapMut,
-    exp_labels: Option<&mut MmapMut>,
+    layer_labels: &mut [u8],
+    exp_labels: Option<&[u8]>,
     num_nodes: u64,
     cur_layer: u32,
-    core_group: Arc<Option<MutexGuard<'_, Vec<CoreIndex>>>>,
+    core_group: Arc<Option<CoreGroupGuard>>,
+    num_producers: usize,
 ) {
     info!("Creating labels for layer {}", cur_layer);
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!(
+        "label_layer",
+        layer = cur_layer,
+        core_group = ?core_group.as_ref().as_ref().and_then(|group| group.index())
+    )
+    .entered();
     // num_producers is the number of producer threads
-    let (lookahead, num_producers, producer_stride) = {
-        let settings = &SETTINGS;
-        let lookahead = settings.multicore_sdr_lookahead;
-        let num_producers = settings.multicore_sdr_producers;
-        // NOTE: Stride must not exceed the number of nodes in parents_cache's window. If it does, the process will deadlock
-        // with producers and consumers waiting for each other.
-        let producer_stride = settings
-            .multicore_sdr_producer_stride
-            .min(parents_cache.window_nodes() as u64);
-
-        (lookahead, num_producers, producer_stride)
-    };
+    // Checked by `check_lookahead` before any layer is labeled.
+    let lookahead = SETTINGS.multicore_sdr_lookahead;
+    let producer_stride = producer_stride(parents_cache);
 
     const BYTES_PER_NODE: usize = (NODE_SIZE * DEGREE) + SHA_BLOCK_SIZE;
 
@@ -245,24 +298,39 @@ fn create_layer_labels(
             .as_mut_slice_of::<u32>()
             .expect("failed as mut slice of"),
     );
-    let exp_labels = exp_labels.map(|m| {
-        UnsafeSlice::from_slice(m.as_mut_slice_of::<u32>().expect("failed as mut slice of"))
-    });
+    let exp_labels = exp_labels.map(|m| m.as_slice_of::<u32>().expect("failed as slice of"));
     let base_parent_missing = UnsafeSlice::from_slice(&mut base_parent_missing);
 
+    // Set once the consumer labeled the layer, which stops the watchdog.
+    let layer_done = AtomicBool::new(false);
+
     crossbeam::thread::scope(|s| {
+        let watchdog = if SETTINGS.labeling_watchdog_secs > 0 {
+            let cur_producer = &cur_producer;
+            let layer_done = &layer_done;
+            Some(s.spawn(move |_| {
+                watch_layer(
+                    cur_layer,
+                    cur_producer,
+                    layer_done,
+                    Duration::from_secs(SETTINGS.labeling_watchdog_secs),
+                )
+            }))
+        } else {
+            None
+        };
+
         let mut runners = Vec::with_capacity(num_producers);
 
         for i in 0..num_producers {
             let layer_labels = &layer_labels;
-            let exp_labels = exp_labels.as_ref();
             let cur_producer = &cur_producer;
             let cur_awaiting = &cur_awaiting;
             let ring_buf = &ring_buf;
             let base_parent_missing = &base_parent_missing;
 
             let core_index = if let Some(cg) = &*core_group {
-                cg.get(i + 1)
+                cg.producer_core(i)
             } else {
                 None
             };
@@ -378,27 +446,8 @@ fn create_layer_labels(
                     compress256!(cur_node_ptr, &buf[64..], 1);
                 } else {
                     // Two rounds of all parents
-                    let blocks = [
-                        *GenericArray::<u8, U64>::from_slice(&buf[64..128]),
-                        *GenericArray::<u8, U64>::from_slice(&buf[128..192]),
-                        *GenericArray::<u8, U64>::from_slice(&buf[192..256]),
-                        *GenericArray::<u8, U64>::from_slice(&buf[256..320]),
-                        *GenericArray::<u8, U64>::from_slice(&buf[320..384]),
-                        *GenericArray::<u8, U64>::from_slice(&buf[384..448]),
-                        *GenericArray::<u8, U64>::from_slice(&buf[448..512]),
-                    ];
-                    sha2::compress256(
-                        (&mut cur_node_ptr[..8])
-                            .try_into()
-                            .expect("compress failed"),
-                        &blocks,
-                    );
-                    sha2::compress256(
-                        (&mut cur_node_ptr[..8])
-                            .try_into()
-                            .expect("compress failed"),
-                        &blocks,
-                    );
+                    compress256!(cur_node_ptr, &buf[64..], 7);
+                    compress256!(cur_node_ptr, &buf[64..], 7);
 
                     // Final round is only nine parents
                     memset(&mut buf[352..384], 0); // Zero out upper half of last block
@@ -426,14 +475,54 @@ fn create_layer_labels(
         }
 
         debug!("PRODUCER NOT READY: {} times", count_not_ready);
+        layer_done.store(true, SeqCst);
 
         for runner in runners {
             runner.join().expect("join failed");
         }
+        if let Some(watchdog) = watchdog {
+            watchdog.join().expect("join failed");
+        }
     })
     .expect("crossbeam scope failure");
 }
 
+/// Warns whenever the producers of layer `cur_layer` prepared no node, i.e. `cur_producer` did
+/// not change, for `timeout`, until `layer_done` is set. As the producers wait for the consumer
+/// once the lookahead is full, this also catches a stuck consumer.
+fn watch_layer(
+    cur_layer: u32,
+    cur_producer: &AtomicU64,
+    layer_done: &AtomicBool,
+    timeout: Duration,
+) {
+    // The watchdog checks often enough not to hold up the end of the layer.
+    let poll = timeout.min(Duration::from_millis(100));
+    let mut last_node = cur_producer.load(SeqCst);
+    let mut last_progress = Instant::now();
+
+    while !layer_done.load(SeqCst) {
+        thread::sleep(poll);
+        let node = cur_producer.load(SeqCst);
+        if node != last_node {
+            last_node = node;
+            last_progress = Instant::now();
+        } else if last_progress.elapsed() >= timeout {
+            warn!(
+                "labeling layer {} made no progress for {:?}, the producers are at node {}. \
+                 Core bindings: {:?}. Attach a debugger for backtraces, e.g. \
+                 `gdb -p {} -batch -ex 'thread apply all bt'`",
+                cur_layer,
+                last_progress.elapsed(),
+                node,
+                current_core_bindings(),
+                std::process::id(),
+            );
+            last_progress = Instant::now();
+        }
+    }
+}
+
 #[allow(clippy::type_complexity)]
 pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]>>(
     graph: &StackedBucketGraph<Tree::Hasher>,
@@ -441,10 +530,97 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
     layers: usize,
     replica_id: T,
     config: StoreConfig,
+) -> Result<(Labels<Tree>, Vec<LayerState>)> {
+    create_labels_for_encoding_with_producers(
+        graph,
+        parents_cache,
+        layers,
+        replica_id,
+        config,
+        checkout_core_group_for((graph.size() * NODE_SIZE) as u64),
+        None,
+        SETTINGS.multicore_sdr_producers,
+    )
+}
+
+/// Like `create_labels_for_encoding`, but labels with the layer buffers of `buffer_pool` instead
+/// of allocating them.
+#[allow(clippy::type_complexity)]
+pub fn create_labels_for_encoding_with_buffer_pool<
+    Tree: 'static + MerkleTreeTrait,
+    T: AsRef<[u8]>,
+>(
+    graph: &StackedBucketGraph<Tree::Hasher>,
+    parents_cache: &ParentCache,
+    layers: usize,
+    replica_id: T,
+    config: StoreConfig,
+    buffer_pool: &BufferPool,
+) -> Result<(Labels<Tree>, Vec<LayerState>)> {
+    create_labels_for_encoding_with_producers(
+        graph,
+        parents_cache,
+        layers,
+        replica_id,
+        config,
+        checkout_core_group_for((graph.size() * NODE_SIZE) as u64),
+        Some(buffer_pool),
+        SETTINGS.multicore_sdr_producers,
+    )
+}
+
+/// Like `create_labels_for_encoding`, but binds the labeling threads to the cores of
+/// `core_group` instead of checking out one of `CORE_GROUPS`, and runs them unbound without one.
+#[allow(clippy::type_complexity)]
+pub fn create_labels_for_encoding_on_core_group<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]>>(
+    graph: &StackedBucketGraph<Tree::Hasher>,
+    parents_cache: &ParentCache,
+    layers: usize,
+    replica_id: T,
+    config: StoreConfig,
+    core_group: Option<CoreGroupGuard>,
+) -> Result<(Labels<Tree>, Vec<LayerState>)> {
+    let num_producers = core_group
+        .as_ref()
+        .map_or(SETTINGS.multicore_sdr_producers, |core_group| {
+            core_group.producer_count(SETTINGS.multicore_sdr_producers)
+        });
+    if num_producers < SETTINGS.multicore_sdr_producers {
+        info!(
+            "labeling with {} of {} producers, as the core group has no cores for the others",
+            num_producers, SETTINGS.multicore_sdr_producers
+        );
+    }
+
+    create_labels_for_encoding_with_producers(
+        graph,
+        parents_cache,
+        layers,
+        replica_id,
+        config,
+        core_group,
+        None,
+        num_producers,
+    )
+}
+
+/// Like `create_labels_for_encoding`, but with an explicit core group, buffer pool and number of
+/// producer threads. The labels must not depend on any of them.
+#[allow(clippy::type_complexity)]
+fn create_labels_for_encoding_with_producers<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]>>(
+    graph: &StackedBucketGraph<Tree::Hasher>,
+    parents_cache: &ParentCache,
+    layers: usize,
+    replica_id: T,
+    config: StoreConfig,
+    core_group: Option<CoreGroupGuard>,
+    buffer_pool: Option<&BufferPool>,
+    num_producers: usize,
 ) -> Result<(Labels<Tree>, Vec<LayerState>)> {
     info!("create labels");
+    count_labeling(true);
 
-    let layer_states = prepare_layers::<Tree>(graph, &config, layers);
+    let layer_states = prepare_layers::<Tree>(graph, &config, layers, replica_id.as_ref());
 
     let sector_size = graph.size() * NODE_SIZE;
     let node_count = graph.size() as u64;
@@ -452,24 +628,45 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
 
     let default_cache_size = DEGREE * 4 * cache_window_nodes;
 
-    let core_group = Arc::new(checkout_core_group());
+    // Dropped last, once the group is released.
+    let _tracker = track_labeling(core_group.as_ref());
+    let core_group = Arc::new(core_group);
 
     // When `_cleanup_handle` is dropped, the previous binding of thread will be restored.
     let _cleanup_handle = (*core_group).as_ref().map(|group| {
         // This could fail, but we will ignore the error if so.
         // It will be logged as a warning by `bind_core`.
-        debug!("binding core in main thread");
-        group.get(0).map(|core_index| bind_core(*core_index))
+        debug!(
+            "binding core in main thread (dedicated consumer core: {}, SMT sibling: {:?})",
+            group.has_dedicated_consumer(),
+            group.consumer_smt_sibling()
+        );
+        match group.consumer_smt_sibling() {
+            Some(core_index) => Some(bind_smt_sibling(*core_index)),
+            None => group
+                .consumer_core()
+                .map(|core_index| bind_core(*core_index)),
+        }
     });
 
+    // The consumer is bound already, so a copy of the parent cache is made on its NUMA node.
+    let parents_cache_path = local_parent_cache(parents_cache, (*core_group).as_ref());
     // NOTE: this means we currently keep 2x sector size around, to improve speed
     let (parents_cache, mut layer_labels, mut exp_labels) = setup_create_label_memory(
         sector_size,
         DEGREE,
         Some(default_cache_size as usize),
-        &parents_cache.path,
+        &parents_cache_path,
+        buffer_pool,
+    )?;
+    check_lookahead(
+        SETTINGS.multicore_sdr_lookahead,
+        producer_stride(&parents_cache),
     )?;
 
+    // With `async_writes`, the previous layer, which is held in `exp_labels`, is stored while the
+    // next one is labeled.
+    let mut unstored: Option<(usize, &LayerState)> = None;
     for (layer, layer_state) in (1..=layers).zip(layer_states.iter()) {
         info!("Layer {}", layer);
 
@@ -487,19 +684,28 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
             parents_cache.finish_reset()?;
         }
 
-        create_layer_labels(
-            &parents_cache,
-            replica_id.as_ref(),
-            &mut layer_labels,
-            if layer == 1 {
-                None
-            } else {
-                Some(&mut exp_labels)
-            },
-            node_count,
-            layer as u32,
-            core_group.clone(),
-        );
+        let label = || {
+            create_layer_labels(
+                &parents_cache,
+                replica_id.as_ref(),
+                &mut layer_labels,
+                if layer == 1 { None } else { Some(&*exp_labels) },
+                node_count,
+                layer as u32,
+                core_group.clone(),
+                num_producers,
+            )
+        };
+        match unstored.take() {
+            Some((prev_layer, prev_state)) => store_layer_while(
+                &exp_labels,
+                &prev_state.config,
+                replica_id.as_ref(),
+                prev_layer,
+                label,
+            )?,
+            None => label(),
+        }
 
         // Cache reset happens in two parts.
         // The first part (the start) happens after each layer but the last.
@@ -508,16 +714,10 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
         }
 
         mem::swap(&mut layer_labels, &mut exp_labels);
-        {
-            let layer_config = &layer_state.config;
-
-            info!("  storing labels on disk");
-            write_layer(&exp_labels, layer_config).context("failed to store labels")?;
-
-            info!(
-                "  generated layer {} store with id {}",
-                layer, layer_config.id
-            );
+        if SETTINGS.async_writes && layer != layers {
+            unstored = Some((layer, layer_state));
+        } else {
+            store_layer(&exp_labels, &layer_state.config, replica_id.as_ref(), layer)?;
         }
     }
 
@@ -537,6 +737,27 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
     layers: usize,
     replica_id: T,
     config: StoreConfig,
+) -> Result<LabelsCache<Tree>> {
+    create_labels_for_decoding_with_producers(
+        graph,
+        parents_cache,
+        layers,
+        replica_id,
+        config,
+        SETTINGS.multicore_sdr_producers,
+    )
+}
+
+/// Like `create_labels_for_decoding`, but with an explicit number of producer threads. The
+/// labels must not depend on it.
+#[allow(clippy::type_complexity)]
+fn create_labels_for_decoding_with_producers<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]>>(
+    graph: &StackedBucketGraph<Tree::Hasher>,
+    parents_cache: &ParentCache,
+    layers: usize,
+    replica_id: T,
+    config: StoreConfig,
+    num_producers: usize,
 ) -> Result<LabelsCache<Tree>> {
     info!("create labels");
 
@@ -550,22 +771,41 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
 
     let default_cache_size = DEGREE * 4 * cache_window_nodes;
 
-    let core_group = Arc::new(checkout_core_group());
+    let core_group = checkout_core_group_for(sector_size as u64);
+    // Dropped last, once the group is released.
+    let _tracker = track_labeling(core_group.as_ref());
+    let core_group = Arc::new(core_group);
 
     // When `_cleanup_handle` is dropped, the previous binding of thread will be restored.
     let _cleanup_handle = (*core_group).as_ref().map(|group| {
         // This could fail, but we will ignore the error if so.
         // It will be logged as a warning by `bind_core`.
-        debug!("binding core in main thread");
-        group.get(0).map(|core_index| bind_core(*core_index))
+        debug!(
+            "binding core in main thread (dedicated consumer core: {}, SMT sibling: {:?})",
+            group.has_dedicated_consumer(),
+            group.consumer_smt_sibling()
+        );
+        match group.consumer_smt_sibling() {
+            Some(core_index) => Some(bind_smt_sibling(*core_index)),
+            None => group
+                .consumer_core()
+                .map(|core_index| bind_core(*core_index)),
+        }
     });
 
+    // The consumer is bound already, so a copy of the parent cache is made on its NUMA node.
+    let parents_cache_path = local_parent_cache(parents_cache, (*core_group).as_ref());
     // NOTE: this means we currently keep 2x sector size around, to improve speed
     let (parents_cache, mut layer_labels, mut exp_labels) = setup_create_label_memory(
         sector_size,
         DEGREE,
         Some(default_cache_size as usize),
-        &parents_cache.path,
+        &parents_cache_path,
+        None,
+    )?;
+    check_lookahead(
+        SETTINGS.multicore_sdr_lookahead,
+        producer_stride(&parents_cache),
     )?;
 
     for layer in 1..=layers {
@@ -581,14 +821,11 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
             &parents_cache,
             replica_id.as_ref(),
             &mut layer_labels,
-            if layer == 1 {
-                None
-            } else {
-                Some(&mut exp_labels)
-            },
+            if layer == 1 { None } else { Some(&*exp_labels) },
             node_count,
             layer as u32,
             core_group.clone(),
+            num_producers,
         );
 
         // Cache reset happens in two parts.
@@ -628,13 +865,18 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
         "Invalid amount of layers encoded expected"
     );
 
-    Ok(LabelsCache::<Tree> { labels })
+    Ok(LabelsCache::<Tree> {
+        labels,
+        layer_file: None,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::fs;
+
     use blstrs::Scalar as Fr;
     use ff::PrimeField;
     use filecoin_hashers::poseidon::PoseidonHasher;
@@ -642,6 +884,35 @@ mod tests {
     use storage_proofs_core::{api_version::ApiVersion, merkle::LCTree};
     use tempfile::tempdir;
 
+    #[test]
+    fn test_check_lookahead() {
+        assert!(check_lookahead(800, 128).is_ok());
+        assert!(check_lookahead(128, 128).is_ok());
+        assert!(check_lookahead(MAX_MULTICORE_SDR_LOOKAHEAD, 128).is_ok());
+
+        assert!(check_lookahead(0, 128).is_err());
+        assert!(check_lookahead(127, 128).is_err());
+        assert!(check_lookahead(MAX_MULTICORE_SDR_LOOKAHEAD + 1, 128).is_err());
+    }
+
+    #[test]
+    fn test_watch_layer() {
+        let cur_producer = AtomicU64::new(0);
+        let layer_done = AtomicBool::new(false);
+
+        // The watchdog keeps watching a stuck layer, and stops once the layer is done.
+        crossbeam::thread::scope(|s| {
+            let watchdog =
+                s.spawn(|_| watch_layer(1, &cur_producer, &layer_done, Duration::from_millis(10)));
+            thread::sleep(Duration::from_millis(50));
+            cur_producer.store(100, SeqCst);
+            thread::sleep(Duration::from_millis(50));
+            layer_done.store(true, SeqCst);
+            watchdog.join().expect("join failed");
+        })
+        .expect("crossbeam scope failure");
+    }
+
     #[test]
     fn test_create_labels() {
         let layers = 11;
@@ -713,6 +984,94 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_create_labels_independent_of_producers() {
+        type Tree = LCTree<PoseidonHasher, U8, U0, U2>;
+
+        let layers = 2;
+        let nodes = (1 << 20) / NODE_SIZE;
+        let replica_id = [9u8; 32];
+
+        let graph = StackedBucketGraph::<PoseidonHasher>::new(
+            None,
+            nodes,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            [123; 32],
+            ApiVersion::V1_1_0,
+        )
+        .expect("stacked bucket graph new failed");
+        let cache = graph.parent_cache().expect("parent_cache failed");
+        // The buffers are reused as they are by every labeling, which must not change the labels.
+        let buffer_pool =
+            BufferPool::new((nodes * NODE_SIZE) as u64, 1).expect("BufferPool::new failure");
+
+        let mut expected = None;
+        for num_producers in &[1, 2, 3, 5] {
+            let encoding_dir = tempdir().expect("tempdir failure");
+            let config = StoreConfig::new(
+                encoding_dir.path(),
+                CacheKey::CommDTree.to_string(),
+                nodes.trailing_zeros() as usize,
+            );
+            let (labels, _) = create_labels_for_encoding_with_producers::<Tree, _>(
+                &graph,
+                &cache,
+                layers,
+                replica_id,
+                config,
+                checkout_core_group_for((nodes * NODE_SIZE) as u64),
+                Some(&buffer_pool),
+                *num_producers,
+            )
+            .expect("create_labels_for_encoding failed");
+            let encoded: Vec<Vec<u8>> = labels
+                .labels
+                .iter()
+                .map(|label| {
+                    fs::read(StoreConfig::data_path(&label.path, &label.id))
+                        .expect("failed to read layer")
+                })
+                .collect();
+
+            let decoding_dir = tempdir().expect("tempdir failure");
+            let config = StoreConfig::new(
+                decoding_dir.path(),
+                CacheKey::CommDTree.to_string(),
+                nodes.trailing_zeros() as usize,
+            );
+            let decoded: Vec<_> = create_labels_for_decoding_with_producers::<Tree, _>(
+                &graph,
+                &cache,
+                layers,
+                replica_id,
+                config,
+                *num_producers,
+            )
+            .expect("create_labels_for_decoding failed")
+            .labels
+            .iter()
+            .map(|store| store.read_range(0..nodes).expect("read_range failed"))
+            .collect();
+
+            match &expected {
+                None => expected = Some((encoded, decoded)),
+                Some((expected_encoded, expected_decoded)) => {
+                    assert!(
+                        expected_encoded == &encoded,
+                        "encoding labels differ with {} producers",
+                        num_producers
+                    );
+                    assert!(
+                        expected_decoded == &decoded,
+                        "decoding labels differ with {} producers",
+                        num_producers
+                    );
+                }
+            }
+        }
+    }
+
     fn test_create_labels_aux(
         sector_size: usize,
         layers: usize,
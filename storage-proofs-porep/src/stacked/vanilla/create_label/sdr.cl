@@ -0,0 +1,164 @@
+// SDR labeling of a window of nodes, for every sector of a batch, see `gpu.rs`.
+//
+// A label is held as the eight big endian words of its bytes, which are the words SHA-256 reads
+// and writes, so that labels are hashed and stored without swapping bytes.
+
+#define BASE_DEGREE 6
+#define DEGREE 14
+#define LABEL_WORDS 8
+// The parents of a node are hashed as 37 labels, after the replica id and the node.
+#define PARENT_PIECES 37
+// The length in bits of the hashed data of a node with parents, and of one without.
+#define PARENTS_BITS 9984
+#define HEADER_BITS 512
+
+__constant uint IV[8] = {
+  0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+  0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+};
+
+__constant uint K[64] = {
+  0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+  0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+  0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+  0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+  0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+  0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+  0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+  0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+};
+
+#define ROTR(x, n) rotate((x), (uint)(32 - (n)))
+
+void sha256_compress(uint *state, const uint *block) {
+  uint w[64];
+  for (int i = 0; i < 16; i++) {
+    w[i] = block[i];
+  }
+  for (int i = 16; i < 64; i++) {
+    uint s0 = ROTR(w[i - 15], 7) ^ ROTR(w[i - 15], 18) ^ (w[i - 15] >> 3);
+    uint s1 = ROTR(w[i - 2], 17) ^ ROTR(w[i - 2], 19) ^ (w[i - 2] >> 10);
+    w[i] = w[i - 16] + s0 + w[i - 7] + s1;
+  }
+
+  uint a = state[0], b = state[1], c = state[2], d = state[3];
+  uint e = state[4], f = state[5], g = state[6], h = state[7];
+  for (int i = 0; i < 64; i++) {
+    uint t1 = h + (ROTR(e, 6) ^ ROTR(e, 11) ^ ROTR(e, 25)) + ((e & f) ^ (~e & g)) + K[i] + w[i];
+    uint t2 = (ROTR(a, 2) ^ ROTR(a, 13) ^ ROTR(a, 22)) + ((a & b) ^ (a & c) ^ (b & c));
+    h = g;
+    g = f;
+    f = e;
+    e = d + t1;
+    d = c;
+    c = b;
+    b = a;
+    a = t1 + t2;
+  }
+
+  state[0] += a;
+  state[1] += b;
+  state[2] += c;
+  state[3] += d;
+  state[4] += e;
+  state[5] += f;
+  state[6] += g;
+  state[7] += h;
+}
+
+void load_label(uint *dst, __global const uint *label) {
+  for (int i = 0; i < LABEL_WORDS; i++) {
+    dst[i] = label[i];
+  }
+}
+
+// Fills the second half of `block` with the padding of data of `bits` bits, which ends halfway
+// through the block.
+void pad_block(uint *block, uint bits) {
+  block[8] = 0x80000000;
+  for (int i = 9; i < 15; i++) {
+    block[i] = 0;
+  }
+  block[15] = bits;
+}
+
+// Returns the label of the parent which is hashed as `piece`. The base parents are hashed six
+// times on the first layer. On the other layers, all parents are hashed twice, followed by the
+// first nine once more, and the expander parents are read from the previous layer.
+__global const uint *parent_label(uint piece, __global const uint *node_parents,
+                                  __global const uint *labels, __global const uint *exp_labels,
+                                  uint layer_index) {
+  uint parent;
+  if (layer_index == 1) {
+    parent = piece % BASE_DEGREE;
+  } else {
+    parent = piece < 2 * DEGREE ? piece % DEGREE : piece - 2 * DEGREE;
+  }
+  __global const uint *source = parent < BASE_DEGREE ? labels : exp_labels;
+
+  return source + (ulong)node_parents[parent] * LABEL_WORDS;
+}
+
+// Labels the nodes `first_node..first_node + window_nodes` of the sector of this work item.
+// `parents` holds the parents of the window, `layer` and `exp_layer` the labels of the current
+// and the previous layer of every sector, and `replica_ids` the replica id of every sector.
+__kernel void sdr_label_window(__global uint *layer, __global const uint *exp_layer,
+                               __global const uint *parents, __global const uint *replica_ids,
+                               uint sectors, uint sector_nodes, uint first_node,
+                               uint window_nodes, uint layer_index) {
+  uint sector = get_global_id(0);
+  if (sector >= sectors) {
+    return;
+  }
+
+  ulong sector_offset = (ulong)sector * sector_nodes * LABEL_WORDS;
+  __global uint *labels = layer + sector_offset;
+  __global const uint *exp_labels = exp_layer + sector_offset;
+
+  uint block[16];
+  uint state[8];
+  for (uint node = first_node; node < first_node + window_nodes; node++) {
+    for (int i = 0; i < 8; i++) {
+      state[i] = IV[i];
+    }
+
+    // The replica id, followed by the layer and the node as a 64-bit integer.
+    load_label(block, replica_ids + sector * LABEL_WORDS);
+    block[8] = layer_index;
+    block[9] = 0;
+    block[10] = node;
+    for (int i = 11; i < 16; i++) {
+      block[i] = 0;
+    }
+    sha256_compress(state, block);
+
+    if (node == 0) {
+      for (int i = 0; i < 16; i++) {
+        block[i] = 0;
+      }
+      block[0] = 0x80000000;
+      block[15] = HEADER_BITS;
+      sha256_compress(state, block);
+    } else {
+      __global const uint *node_parents = parents + (ulong)(node - first_node) * DEGREE;
+      for (uint piece = 0; piece + 1 < PARENT_PIECES; piece += 2) {
+        load_label(block, parent_label(piece, node_parents, labels, exp_labels, layer_index));
+        load_label(block + LABEL_WORDS,
+                   parent_label(piece + 1, node_parents, labels, exp_labels, layer_index));
+        sha256_compress(state, block);
+      }
+      load_label(block,
+                 parent_label(PARENT_PIECES - 1, node_parents, labels, exp_labels, layer_index));
+      pad_block(block, PARENTS_BITS);
+      sha256_compress(state, block);
+    }
+
+    // Strip the last two bits, so that the label is an element of Fr.
+    state[7] &= 0xffffff3f;
+
+    __global uint *label = labels + (ulong)node * LABEL_WORDS;
+    for (int i = 0; i < LABEL_WORDS; i++) {
+      label[i] = state[i];
+    }
+  }
+}
@@ -1,7 +1,7 @@
 use std::marker::PhantomData;
 use std::mem;
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 use filecoin_hashers::Hasher;
 use generic_array::typenum::Unsigned;
 use log::info;
@@ -10,12 +10,15 @@ use sha2raw::Sha256;
 use storage_proofs_core::{
     drgraph::Graph,
     merkle::MerkleTreeTrait,
+    settings::SETTINGS,
     util::{data_at_node_offset, NODE_SIZE},
 };
 
 use crate::stacked::vanilla::{
-    cache::ParentCache,
-    create_label::{prepare_layers, read_layer, write_layer},
+    create_label::{
+        count_labeling, prepare_layers, read_layer, store_layer, store_layer_while, write_layer,
+    },
+    parents_source::ParentsSource,
     proof::LayerState,
     Labels, LabelsCache, StackedBucketGraph,
 };
@@ -23,20 +26,24 @@ use crate::stacked::vanilla::{
 #[allow(clippy::type_complexity)]
 pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]>>(
     graph: &StackedBucketGraph<Tree::Hasher>,
-    parents_cache: &mut ParentCache,
+    parents_cache: &mut dyn ParentsSource,
     layers: usize,
     replica_id: T,
     config: StoreConfig,
 ) -> Result<(Labels<Tree>, Vec<LayerState>)> {
     info!("generate labels");
+    count_labeling(false);
 
-    let layer_states = prepare_layers::<Tree>(graph, &config, layers);
+    let layer_states = prepare_layers::<Tree>(graph, &config, layers, replica_id.as_ref());
 
     let layer_size = graph.size() * NODE_SIZE;
     // NOTE: this means we currently keep 2x sector size around, to improve speed.
     let mut layer_labels = vec![0u8; layer_size]; // Buffer for labels of the current layer
     let mut exp_labels = vec![0u8; layer_size]; // Buffer for labels of the previous layer, needed for expander parents
 
+    // With `async_writes`, the previous layer, which is held in `exp_labels`, is stored while the
+    // next one is labeled.
+    let mut unstored: Option<(usize, &LayerState)> = None;
     for (layer, layer_state) in (1..=layers).zip(layer_states.iter()) {
         info!("generating layer: {}", layer);
         if layer_state.generated {
@@ -49,44 +56,54 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
 
         parents_cache.reset()?;
 
-        if layer == 1 {
-            for node in 0..graph.size() {
-                create_label(
-                    graph,
-                    Some(parents_cache),
-                    &replica_id,
-                    &mut layer_labels,
-                    layer,
-                    node,
-                )?;
-            }
-        } else {
-            for node in 0..graph.size() {
-                create_label_exp(
-                    graph,
-                    Some(parents_cache),
-                    &replica_id,
-                    &exp_labels,
-                    &mut layer_labels,
-                    layer,
-                    node,
-                )?;
+        let label = || -> Result<()> {
+            if layer == 1 {
+                for node in 0..graph.size() {
+                    create_label(
+                        graph,
+                        Some(&mut *parents_cache),
+                        &replica_id,
+                        &mut layer_labels,
+                        layer,
+                        node,
+                    )?;
+                }
+            } else {
+                for node in 0..graph.size() {
+                    create_label_exp(
+                        graph,
+                        Some(&mut *parents_cache),
+                        &replica_id,
+                        &exp_labels,
+                        &mut layer_labels,
+                        layer,
+                        node,
+                    )?;
+                }
             }
-        }
-
-        // Write the result to disk to avoid keeping it in memory all the time.
-        let layer_config = &layer_state.config;
 
-        info!("  storing labels on disk");
-        write_layer(&layer_labels, layer_config).context("failed to store labels")?;
-
-        info!(
-            "  generated layer {} store with id {}",
-            layer, layer_config.id
-        );
+            Ok(())
+        };
+        match unstored.take() {
+            Some((prev_layer, prev_state)) => store_layer_while(
+                &exp_labels,
+                &prev_state.config,
+                replica_id.as_ref(),
+                prev_layer,
+                label,
+            )??,
+            None => label()?,
+        }
 
         info!("  setting exp parents");
         mem::swap(&mut layer_labels, &mut exp_labels);
+
+        // Write the result to disk to avoid keeping it in memory all the time.
+        if SETTINGS.async_writes && layer != layers {
+            unstored = Some((layer, layer_state));
+        } else {
+            store_layer(&exp_labels, &layer_state.config, replica_id.as_ref(), layer)?;
+        }
     }
 
     Ok((
@@ -101,7 +118,7 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
 #[allow(clippy::type_complexity)]
 pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]>>(
     graph: &StackedBucketGraph<Tree::Hasher>,
-    parents_cache: &mut ParentCache,
+    parents_cache: &mut dyn ParentsSource,
     layers: usize,
     replica_id: T,
     config: StoreConfig,
@@ -125,7 +142,7 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
             for node in 0..graph.size() {
                 create_label(
                     graph,
-                    Some(parents_cache),
+                    Some(&mut *parents_cache),
                     &replica_id,
                     &mut layer_labels,
                     layer,
@@ -136,7 +153,7 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
             for node in 0..graph.size() {
                 create_label_exp(
                     graph,
-                    Some(parents_cache),
+                    Some(&mut *parents_cache),
                     &replica_id,
                     &exp_labels,
                     &mut layer_labels,
@@ -167,12 +184,15 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
         "Invalid amount of layers encoded expected"
     );
 
-    Ok(LabelsCache::<Tree> { labels })
+    Ok(LabelsCache::<Tree> {
+        labels,
+        layer_file: None,
+    })
 }
 
 pub fn create_label<H: Hasher, T: AsRef<[u8]>>(
     graph: &StackedBucketGraph<H>,
-    cache: Option<&mut ParentCache>,
+    cache: Option<&mut dyn ParentsSource>,
     replica_id: T,
     layer_labels: &mut [u8],
     layer_index: usize,
@@ -209,7 +229,7 @@ pub fn create_label<H: Hasher, T: AsRef<[u8]>>(
 
 pub fn create_label_exp<H: Hasher, T: AsRef<[u8]>>(
     graph: &StackedBucketGraph<H>,
-    cache: Option<&mut ParentCache>,
+    cache: Option<&mut dyn ParentsSource>,
     replica_id: T,
     exp_parents_data: &[u8],
     layer_labels: &mut [u8],
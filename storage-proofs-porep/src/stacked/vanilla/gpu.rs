@@ -0,0 +1,726 @@
+//! Selection of the GPUs which build tree_c and tree_r_last, and of the batch sizes they hash with.
+
+use std::collections::BTreeMap;
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+use std::collections::{HashMap, VecDeque};
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+use std::convert::TryFrom;
+use std::sync::Mutex;
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Condvar,
+};
+
+use anyhow::ensure;
+use lazy_static::lazy_static;
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+use log::info;
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+use rust_gpu_tools::{Device, DeviceUuid};
+use storage_proofs_core::error::{Error, Result};
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+use storage_proofs_core::{settings::SETTINGS, util::NODE_SIZE};
+
+/// The GPUs to build the trees of a sector on, if the GPU tree builders are enabled.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum GpuSelection {
+    /// The device neptune picks, which can be configured for the whole process through the
+    /// environment of the GPU backends.
+    Default,
+    /// The device at this position in the list of all detected devices.
+    Index(u32),
+    /// The device with this UUID, e.g. as listed by `nvidia-smi -L`.
+    Uuid(String),
+    /// All detected devices, which share the base trees of tree_c and tree_r_last between them.
+    All,
+    /// These devices, each selected by `Index` or `Uuid`, which share the base trees of tree_c
+    /// and tree_r_last between them.
+    Multiple(Vec<GpuSelection>),
+}
+
+impl Default for GpuSelection {
+    fn default() -> Self {
+        GpuSelection::Default
+    }
+}
+
+/// How the trees of a sector are built on the GPU, if the GPU tree builders are enabled.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct GpuOptions {
+    /// The GPUs to build the trees on.
+    pub selection: GpuSelection,
+    /// The batch sizes to build the trees with.
+    pub batch_sizes: GpuBatchSizes,
+}
+
+impl From<GpuSelection> for GpuOptions {
+    fn from(selection: GpuSelection) -> Self {
+        GpuOptions {
+            selection,
+            batch_sizes: GpuBatchSizes::default(),
+        }
+    }
+}
+
+/// The number of nodes the GPU tree builders hash at once. A size which is not given is derived
+/// from the memory of the device with `auto_gpu_batch_sizes`, and taken from
+/// `max_gpu_column_batch_size` and `max_gpu_tree_batch_size` otherwise.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct GpuBatchSizes {
+    /// The number of columns hashed at once when building tree_c.
+    pub column: Option<usize>,
+    /// The number of nodes hashed at once when building the levels of tree_c and tree_r_last.
+    pub tree: Option<usize>,
+}
+
+impl GpuBatchSizes {
+    /// Fails if a batch size is 0, with which the trees would never be built.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.column != Some(0),
+            "the column batch size must not be 0"
+        );
+        ensure!(self.tree != Some(0), "the tree batch size must not be 0");
+
+        Ok(())
+    }
+}
+
+/// Where the base trees of tree_c and tree_r_last were built since the process started, see
+/// `tree_builder_stats`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TreeBuilderStats {
+    /// The number of base trees built on the CPU. This includes those of a GPU tree builder which
+    /// found no GPU, or fell back to the CPU after the GPU ran out of memory.
+    pub cpu: u64,
+    /// The number of base trees each GPU built, by the name of the GPU.
+    pub gpus: BTreeMap<String, u64>,
+}
+
+lazy_static! {
+    static ref TREE_BUILDER_STATS: Mutex<TreeBuilderStats> =
+        Mutex::new(TreeBuilderStats::default());
+}
+
+/// Returns where the base trees of tree_c and tree_r_last were built since the process started,
+/// e.g. to confirm that the trees are built on the configured GPUs.
+pub fn tree_builder_stats() -> TreeBuilderStats {
+    TREE_BUILDER_STATS
+        .lock()
+        .expect("TREE_BUILDER_STATS poisoned")
+        .clone()
+}
+
+/// Counts a base tree built on the GPU named `gpu`, or on the CPU if it is `None`.
+pub(crate) fn count_base_tree(gpu: Option<&str>) {
+    let mut stats = TREE_BUILDER_STATS
+        .lock()
+        .expect("TREE_BUILDER_STATS poisoned");
+    match gpu {
+        Some(name) => *stats.gpus.entry(name.to_string()).or_default() += 1,
+        None => stats.cpu += 1,
+    }
+}
+
+/// Returns the names of the detected GPUs, on which bellperson proves the SNARKs. Without any, it
+/// proves on the CPU.
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+pub fn detected_gpus() -> Vec<String> {
+    Device::all().iter().map(|device| device.name()).collect()
+}
+
+#[cfg(not(any(feature = "cuda", feature = "opencl")))]
+pub fn detected_gpus() -> Vec<String> {
+    Vec::new()
+}
+
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+impl GpuBatchSizes {
+    /// Returns the column and the tree batch size to build base trees of tree_c with `nodes_count`
+    /// leaves on `device`.
+    pub(crate) fn tree_c(
+        &self,
+        device: Option<&Device>,
+        column_arity: usize,
+        tree_arity: usize,
+        nodes_count: usize,
+    ) -> (usize, usize) {
+        // The builder holds both of its batchers on the device, so each gets half of the budget.
+        let column = self.column.unwrap_or_else(|| {
+            batch_size_from_memory(device, 2, column_arity, nodes_count)
+                .unwrap_or(SETTINGS.max_gpu_column_batch_size as usize)
+        });
+        let tree = self.tree.unwrap_or_else(|| {
+            batch_size_from_memory(device, 2, tree_arity, nodes_count)
+                .unwrap_or(SETTINGS.max_gpu_tree_batch_size as usize)
+        });
+
+        (column, tree)
+    }
+
+    /// Returns the batch size to build base trees of tree_r_last with `nodes_count` leaves on
+    /// `device`.
+    pub(crate) fn tree_r_last(
+        &self,
+        device: Option<&Device>,
+        tree_arity: usize,
+        nodes_count: usize,
+    ) -> usize {
+        self.tree.unwrap_or_else(|| {
+            batch_size_from_memory(device, 1, tree_arity, nodes_count)
+                .unwrap_or(SETTINGS.max_gpu_tree_batch_size as usize)
+        })
+    }
+}
+
+/// Returns how many nodes of `arity` inputs a batcher on `device`, or on the one neptune picks if
+/// it is `None`, can hash at once with `1 / share` of the memory budget, at most `nodes_count`.
+/// Returns `None` if the batch sizes are not derived from memory, or if there is no device.
+///
+/// The budget is the `gpu_memory_budget_percent` of the free memory of the device where the
+/// backend reports it, which is only CUDA, and of its total memory otherwise, which leaves room
+/// for e.g. other processes using the device. The inputs of a batch are uploaded in one buffer,
+/// so a batch is also kept below the largest buffer the device can allocate.
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+fn batch_size_from_memory(
+    device: Option<&Device>,
+    share: u64,
+    arity: usize,
+    nodes_count: usize,
+) -> Option<usize> {
+    if !SETTINGS.auto_gpu_batch_sizes {
+        return None;
+    }
+    let device = device.or_else(default_device)?;
+
+    let memory = free_memory(device).unwrap_or_else(|| device.memory());
+    let budget = memory / 100 * u64::from(SETTINGS.gpu_memory_budget_percent.min(100)) / share;
+    // Every node of a batch is uploaded with its inputs, and its hash is read back.
+    let node_memory = ((arity + 1) * NODE_SIZE) as u64;
+    let max_alloc_nodes =
+        max_alloc_size(device).map_or(u64::MAX, |max_alloc| max_alloc / (arity * NODE_SIZE) as u64);
+    let batch_size =
+        ((budget / node_memory).min(max_alloc_nodes) as usize).clamp(1, nodes_count.max(1));
+    info!(
+        "hashing batches of {} nodes of arity {} on {} with {} bytes of memory",
+        batch_size,
+        arity,
+        device.name(),
+        memory
+    );
+
+    Some(batch_size)
+}
+
+/// Returns the memory of `device` which is free, `None` if the backend does not tell.
+#[cfg(feature = "cuda")]
+fn free_memory(device: &Device) -> Option<u64> {
+    use cuda_driver_sys::{
+        cuCtxPopCurrent_v2, cuCtxPushCurrent_v2, cuDeviceGet, cuDeviceGetCount, cuDeviceGetUuid,
+        cuDevicePrimaryCtxRelease, cuDevicePrimaryCtxRetain, cuInit, cuMemGetInfo_v2, CUresult,
+        CUuuid,
+    };
+
+    device.cuda_device()?;
+    let uuid = device.uuid()?;
+    unsafe {
+        if cuInit(0) != CUresult::CUDA_SUCCESS {
+            return None;
+        }
+        let mut count = 0;
+        if cuDeviceGetCount(&mut count) != CUresult::CUDA_SUCCESS {
+            return None;
+        }
+        for ordinal in 0..count {
+            let mut cu_device = 0;
+            let mut cu_uuid = CUuuid { bytes: [0; 16] };
+            if cuDeviceGet(&mut cu_device, ordinal) != CUresult::CUDA_SUCCESS
+                || cuDeviceGetUuid(&mut cu_uuid, cu_device) != CUresult::CUDA_SUCCESS
+            {
+                continue;
+            }
+            let mut bytes = [0u8; 16];
+            for (byte, cu_byte) in bytes.iter_mut().zip(cu_uuid.bytes.iter()) {
+                *byte = *cu_byte as u8;
+            }
+            if DeviceUuid::from(bytes) != uuid {
+                continue;
+            }
+
+            // The primary context is the one rust-gpu-tools uses as well, so retaining it does
+            // not take the memory of another context.
+            let mut context = std::ptr::null_mut();
+            if cuDevicePrimaryCtxRetain(&mut context, cu_device) != CUresult::CUDA_SUCCESS {
+                return None;
+            }
+            let (mut free, mut total) = (0, 0);
+            let mut result = cuCtxPushCurrent_v2(context);
+            if result == CUresult::CUDA_SUCCESS {
+                result = cuMemGetInfo_v2(&mut free, &mut total);
+                let mut popped = std::ptr::null_mut();
+                cuCtxPopCurrent_v2(&mut popped);
+            }
+            cuDevicePrimaryCtxRelease(cu_device);
+
+            return if result == CUresult::CUDA_SUCCESS {
+                Some(free as u64)
+            } else {
+                None
+            };
+        }
+    }
+
+    None
+}
+
+#[cfg(all(feature = "opencl", not(feature = "cuda")))]
+fn free_memory(_device: &Device) -> Option<u64> {
+    None
+}
+
+/// Returns the largest buffer `device` can allocate, `None` if it is not limited below its memory
+/// or the backend does not tell. OpenCL devices often only allocate a quarter of their memory at
+/// once (`CL_DEVICE_MAX_MEM_ALLOC_SIZE`).
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+fn max_alloc_size(device: &Device) -> Option<u64> {
+    #[cfg(feature = "opencl")]
+    {
+        if let Some(opencl_device) = device.opencl_device() {
+            return opencl3::device::Device::new(opencl_device.cl_device_id())
+                .max_mem_alloc_size()
+                .ok();
+        }
+    }
+    #[cfg(not(feature = "opencl"))]
+    let _ = device;
+
+    None
+}
+
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+lazy_static! {
+    /// Ensures that only one `TreeBuilder` or `ColumnTreeBuilder` uses a GPU at a time, by only
+    /// instantiating at most one per device at a time.
+    static ref GPU_LOCKS: Mutex<HashMap<usize, Arc<Mutex<()>>>> = Mutex::new(HashMap::new());
+}
+
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+impl GpuSelection {
+    /// Returns the selected device, or `None` to let neptune pick one. Fails if no device matches
+    /// the selection. Of several devices, the first one is returned.
+    pub(crate) fn device(&self) -> Result<Option<&'static Device>> {
+        match self {
+            GpuSelection::Default => Ok(None),
+            GpuSelection::All | GpuSelection::Multiple(_) => Ok(self.devices()?.first().copied()),
+            GpuSelection::Index(index) => {
+                let devices = Device::all();
+                let device = devices.get(*index as usize).copied().ok_or_else(|| {
+                    Error::GpuUnavailable(format!(
+                        "no GPU with index {}, {} found",
+                        index,
+                        devices.len()
+                    ))
+                })?;
+                Ok(Some(device))
+            }
+            GpuSelection::Uuid(uuid) => {
+                let parsed = DeviceUuid::try_from(uuid.as_str()).map_err(|err| {
+                    Error::GpuUnavailable(format!("invalid GPU UUID {}: {:?}", uuid, err))
+                })?;
+                let device = Device::by_uuid(parsed)
+                    .ok_or_else(|| Error::GpuUnavailable(format!("no GPU with UUID {}", uuid)))?;
+                Ok(Some(device))
+            }
+        }
+    }
+
+    /// Returns all selected devices, which is empty to let neptune pick one. Fails if a device
+    /// does not match the selection, or is selected twice.
+    pub(crate) fn devices(&self) -> Result<Vec<&'static Device>> {
+        match self {
+            GpuSelection::All => {
+                let devices = Device::all();
+                if devices.is_empty() {
+                    return Err(Error::GpuUnavailable("no GPU found".to_string()).into());
+                }
+                Ok(devices)
+            }
+            GpuSelection::Multiple(selections) => {
+                if selections.is_empty() {
+                    return Err(Error::GpuUnavailable("no GPU selected".to_string()).into());
+                }
+                let mut devices: Vec<&'static Device> = Vec::with_capacity(selections.len());
+                for selection in selections {
+                    let device = match selection {
+                        GpuSelection::Index(_) | GpuSelection::Uuid(_) => selection
+                            .device()?
+                            .expect("an index or UUID selects a device"),
+                        _ => {
+                            return Err(Error::GpuUnavailable(format!(
+                                "{:?} cannot be one of several selected GPUs",
+                                selection
+                            ))
+                            .into())
+                        }
+                    };
+                    if devices
+                        .iter()
+                        .any(|selected| std::ptr::eq(*selected, device))
+                    {
+                        return Err(Error::GpuUnavailable(format!(
+                            "{} is selected more than once",
+                            device.name()
+                        ))
+                        .into());
+                    }
+                    devices.push(device);
+                }
+                Ok(devices)
+            }
+            _ => Ok(self.device()?.into_iter().collect()),
+        }
+    }
+}
+
+/// Ensures that the selected device exists. Without GPU support, only the default selection is
+/// valid, which builds the trees on the CPU.
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+pub(crate) fn ensure_available(gpu: &GpuSelection) -> Result<()> {
+    gpu.devices().map(|_| ())
+}
+
+#[cfg(not(any(feature = "cuda", feature = "opencl")))]
+pub(crate) fn ensure_available(gpu: &GpuSelection) -> Result<()> {
+    if *gpu != GpuSelection::Default {
+        return Err(Error::GpuUnavailable(format!(
+            "{:?} was selected, but GPU support is not compiled in",
+            gpu
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Returns the device neptune builds on if none is selected, which is the first detected one, or
+/// `None` if there is no device.
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+pub(crate) fn default_device() -> Option<&'static Device> {
+    Device::all().first().copied()
+}
+
+/// Returns the name of the GPU a tree builder on `device`, or on the one neptune picks if it is
+/// `None`, hashes on. Returns `None` if the builder has no batcher, as neptune then hashes on the
+/// CPU.
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+pub(crate) fn builder_gpu(device: Option<&'static Device>, has_batcher: bool) -> Option<String> {
+    if !has_batcher {
+        return None;
+    }
+
+    device.or_else(default_device).map(|device| device.name())
+}
+
+/// Returns the lock which has to be held while a tree builder uses `device`, or the device
+/// neptune picks if it is `None`, so that both share the lock of the same physical device.
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+pub(crate) fn device_lock(device: Option<&'static Device>) -> Arc<Mutex<()>> {
+    // Devices are static, so their address identifies them. No device lives at address zero.
+    let key = device
+        .or_else(default_device)
+        .map_or(0, |device| device as *const Device as usize);
+    GPU_LOCKS
+        .lock()
+        .expect("GPU_LOCKS poisoned")
+        .entry(key)
+        .or_default()
+        .clone()
+}
+
+/// Hands out the base trees of a tree to the devices which share them. A device claims the next
+/// base tree whenever it has room in its queue, so a faster device ends up building more of them.
+///
+/// A base tree is claimed until the device completes it. If the device fails, its claims are
+/// returned to the queue and built by the remaining devices, which keep waiting for work while
+/// any other device has base trees in flight.
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+pub(crate) struct ShardQueue {
+    len: usize,
+    state: Mutex<ShardState>,
+    changed: Condvar,
+    /// The number of base trees each device claimed and did not return.
+    claimed: Vec<AtomicUsize>,
+}
+
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+struct ShardState {
+    /// The indices of the base trees which no device claimed, or which a failed device returned.
+    pending: VecDeque<usize>,
+    /// The base trees each device claimed and did not complete yet.
+    in_flight: Vec<Vec<usize>>,
+    failed: Vec<bool>,
+}
+
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+impl ShardQueue {
+    pub(crate) fn new(base_trees: Vec<usize>, devices: usize) -> Self {
+        ShardQueue {
+            len: base_trees.len(),
+            state: Mutex::new(ShardState {
+                pending: base_trees.into(),
+                in_flight: vec![Vec::new(); devices],
+                failed: vec![false; devices],
+            }),
+            changed: Condvar::new(),
+            claimed: (0..devices).map(|_| AtomicUsize::new(0)).collect(),
+        }
+    }
+
+    /// The number of base trees to build.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the index of the next base tree for the device at `device` to build, or `None` if
+    /// the device failed or no base tree is left for it. While other devices have base trees in
+    /// flight, this waits, as they may fail and return them.
+    pub(crate) fn claim(&self, device: usize) -> Option<usize> {
+        let mut state = self.state.lock().expect("ShardQueue poisoned");
+        loop {
+            if state.failed[device] {
+                return None;
+            }
+            if let Some(base_tree) = state.pending.pop_front() {
+                state.in_flight[device].push(base_tree);
+                self.claimed[device].fetch_add(1, Ordering::SeqCst);
+                return Some(base_tree);
+            }
+            let others_in_flight = (0..state.in_flight.len())
+                .any(|other| other != device && !state.in_flight[other].is_empty());
+            if !others_in_flight {
+                return None;
+            }
+            state = self.changed.wait(state).expect("ShardQueue poisoned");
+        }
+    }
+
+    /// Records that the device at `device` built `base_tree`. Returns `false` if the device failed
+    /// in the meantime, so that the base tree was returned to the queue and is not to be written.
+    pub(crate) fn complete(&self, device: usize, base_tree: usize) -> bool {
+        let mut state = self.state.lock().expect("ShardQueue poisoned");
+        let in_flight = &mut state.in_flight[device];
+        let completed = match in_flight.iter().position(|claimed| *claimed == base_tree) {
+            Some(index) => {
+                in_flight.swap_remove(index);
+                true
+            }
+            None => false,
+        };
+        self.changed.notify_all();
+
+        completed
+    }
+
+    /// Records that the device at `device` failed, and returns the base trees it did not complete
+    /// to the queue, for the other devices to build.
+    pub(crate) fn fail(&self, device: usize) {
+        let mut state = self.state.lock().expect("ShardQueue poisoned");
+        if state.failed[device] {
+            return;
+        }
+        state.failed[device] = true;
+        let returned = std::mem::take(&mut state.in_flight[device]);
+        if !returned.is_empty() {
+            info!(
+                "returning base trees {:?} of a failed GPU to the queue",
+                returned
+            );
+        }
+        self.claimed[device].fetch_sub(returned.len(), Ordering::SeqCst);
+        for base_tree in returned.into_iter().rev() {
+            state.pending.push_front(base_tree);
+        }
+        self.changed.notify_all();
+    }
+
+    /// The number of base trees which were claimed and not returned.
+    pub(crate) fn claimed(&self) -> usize {
+        self.claimed
+            .iter()
+            .map(|claimed| claimed.load(Ordering::SeqCst))
+            .sum()
+    }
+
+    /// Logs how many base trees of `tree` each of `devices` built.
+    pub(crate) fn log_shares(&self, tree: &str, devices: &[&'static Device]) {
+        for (device, claimed) in devices.iter().zip(&self.claimed) {
+            info!(
+                "{} built {} base trees of {}",
+                device.name(),
+                claimed.load(Ordering::SeqCst),
+                tree
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gpu_batch_sizes_validate() {
+        assert!(GpuBatchSizes::default().validate().is_ok());
+        let batch_sizes = GpuBatchSizes {
+            column: Some(1),
+            tree: Some(1),
+        };
+        assert!(batch_sizes.validate().is_ok());
+        assert!(GpuBatchSizes {
+            column: Some(0),
+            ..batch_sizes
+        }
+        .validate()
+        .is_err());
+        assert!(GpuBatchSizes {
+            tree: Some(0),
+            ..batch_sizes
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_tree_builder_stats() {
+        // Other tests build trees at the same time, so only the counts of this GPU are known.
+        let gpu = "test_tree_builder_stats";
+        let cpu = tree_builder_stats().cpu;
+        count_base_tree(Some(gpu));
+        count_base_tree(Some(gpu));
+        count_base_tree(None);
+
+        let stats = tree_builder_stats();
+        assert_eq!(stats.gpus.get(gpu), Some(&2));
+        assert!(stats.cpu > cpu);
+    }
+
+    #[test]
+    fn test_gpu_selection_unavailable() {
+        assert!(ensure_available(&GpuSelection::default()).is_ok());
+
+        let err = ensure_available(&GpuSelection::Index(u32::MAX))
+            .expect_err("a GPU with the largest index was found");
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::GpuUnavailable(_))
+        ));
+
+        let err = ensure_available(&GpuSelection::Uuid("not-a-uuid".to_string()))
+            .expect_err("an invalid UUID was accepted");
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::GpuUnavailable(_))
+        ));
+
+        let err = ensure_available(&GpuSelection::Multiple(vec![]))
+            .expect_err("an empty list of GPUs was accepted");
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::GpuUnavailable(_))
+        ));
+    }
+
+    #[cfg(any(feature = "cuda", feature = "opencl"))]
+    #[test]
+    fn test_default_device_shares_its_lock() {
+        if let Some(device) = default_device() {
+            assert!(Arc::ptr_eq(&device_lock(None), &device_lock(Some(device))));
+        }
+        for device in Device::all().into_iter().skip(1) {
+            assert!(!Arc::ptr_eq(&device_lock(None), &device_lock(Some(device))));
+        }
+    }
+
+    #[cfg(any(feature = "cuda", feature = "opencl"))]
+    #[test]
+    fn test_gpu_batch_sizes() {
+        let batch_sizes = GpuBatchSizes {
+            column: Some(5),
+            tree: Some(7),
+        };
+        assert_eq!(batch_sizes.tree_c(None, 11, 8, 1 << 20), (5, 7));
+        assert_eq!(batch_sizes.tree_r_last(None, 8, 1 << 20), 7);
+
+        // Only sizes which are derived from memory depend on the device.
+        let defaults = GpuBatchSizes::default();
+        if !SETTINGS.auto_gpu_batch_sizes {
+            assert_eq!(
+                defaults.tree_c(None, 11, 8, 1 << 20),
+                (
+                    SETTINGS.max_gpu_column_batch_size as usize,
+                    SETTINGS.max_gpu_tree_batch_size as usize
+                )
+            );
+        }
+    }
+
+    #[cfg(any(feature = "cuda", feature = "opencl"))]
+    #[test]
+    fn test_shard_queue_claims_every_base_tree_once() {
+        use std::collections::BTreeSet;
+        use std::thread;
+
+        let queue = ShardQueue::new((0..100).filter(|i| i % 3 != 0).collect(), 4);
+        let claimed: Vec<Vec<usize>> = crossbeam::thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|device| {
+                    let queue = &queue;
+                    s.spawn(move |_| {
+                        let mut claimed = Vec::new();
+                        while let Some(base_tree) = queue.claim(device) {
+                            claimed.push(base_tree);
+                            thread::yield_now();
+                            assert!(queue.complete(device, base_tree));
+                        }
+                        claimed
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("failed to join"))
+                .collect()
+        })
+        .expect("scope failure");
+
+        let all: Vec<usize> = claimed.iter().flatten().copied().collect();
+        let unique: BTreeSet<usize> = all.iter().copied().collect();
+        assert_eq!(all.len(), queue.len());
+        assert_eq!(unique, (0..100).filter(|i| i % 3 != 0).collect());
+        assert_eq!(queue.claimed(), queue.len());
+        assert_eq!(queue.claim(0), None);
+    }
+
+    #[cfg(any(feature = "cuda", feature = "opencl"))]
+    #[test]
+    fn test_shard_queue_returns_the_claims_of_failed_devices() {
+        let queue = ShardQueue::new(vec![0, 1, 2], 2);
+        assert_eq!(queue.claim(0), Some(0));
+        assert_eq!(queue.claim(0), Some(1));
+        assert_eq!(queue.claim(1), Some(2));
+
+        queue.fail(0);
+        assert_eq!(queue.claimed(), 1);
+        assert!(!queue.complete(0, 1));
+        assert_eq!(queue.claim(0), None);
+
+        // The other device builds the returned base trees in their order.
+        assert_eq!(queue.claim(1), Some(0));
+        assert!(queue.complete(1, 0));
+        assert_eq!(queue.claim(1), Some(1));
+        assert!(queue.complete(1, 1));
+        assert!(queue.complete(1, 2));
+        assert_eq!(queue.claim(1), None);
+        assert_eq!(queue.claimed(), queue.len());
+    }
+}
@@ -21,7 +21,10 @@ use storage_proofs_core::{
     PoRepID,
 };
 
-use crate::stacked::vanilla::cache::ParentCache;
+use crate::stacked::vanilla::{
+    cache::{ParentCache, ParentCacheProgressCallback},
+    parents_source::ParentsSource,
+};
 
 /// The expansion degree used for Stacked Graphs.
 pub const EXP_DEGREE: usize = 8;
@@ -134,6 +137,15 @@ where
 
     /// Returns a reference to the parent cache.
     pub fn parent_cache(&self) -> Result<ParentCache> {
+        self.parent_cache_with_progress(None)
+    }
+
+    /// Returns a reference to the parent cache, and reports the progress to `progress` if it has
+    /// to be generated.
+    pub fn parent_cache_with_progress(
+        &self,
+        progress: Option<ParentCacheProgressCallback<'_>>,
+    ) -> Result<ParentCache> {
         // Number of nodes to be cached in memory
         let default_cache_size = SETTINGS.sdr_parents_cache_size;
         let cache_entries = self.size() as u32;
@@ -141,7 +153,7 @@ where
 
         info!("using parent_cache[{} / {}]", cache_size, cache_entries);
 
-        ParentCache::new(cache_size, cache_entries, self)
+        ParentCache::new_with_progress(cache_size, cache_entries, self, progress)
     }
     pub fn copy_parents_data_exp(
         &self,
@@ -149,10 +161,10 @@ where
         base_data: &[u8],
         exp_data: &[u8],
         hasher: Sha256,
-        mut cache: Option<&mut ParentCache>,
+        mut cache: Option<&mut dyn ParentsSource>,
     ) -> Result<[u8; 32]> {
         if let Some(ref mut cache) = cache {
-            let cache_parents = cache.read(node as u32)?;
+            let cache_parents = cache.parents(node as u32)?;
             Ok(self.copy_parents_data_inner_exp(&cache_parents, base_data, exp_data, hasher))
         } else {
             let mut cache_parents = [0u32; DEGREE];
@@ -168,10 +180,10 @@ where
         node: u32,
         base_data: &[u8],
         hasher: Sha256,
-        mut cache: Option<&mut ParentCache>,
+        mut cache: Option<&mut dyn ParentsSource>,
     ) -> Result<[u8; 32]> {
         if let Some(ref mut cache) = cache {
-            let cache_parents = cache.read(node as u32)?;
+            let cache_parents = cache.parents(node as u32)?;
             Ok(self.copy_parents_data_inner(&cache_parents, base_data, hasher))
         } else {
             let mut cache_parents = [0u32; DEGREE];
@@ -0,0 +1,269 @@
+//! The label layers of a sector consolidated into a single file, see [`consolidate_layer_files`].
+
+use std::fs::{self, File};
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context};
+use byteorder::{ByteOrder, LittleEndian};
+use filecoin_hashers::Domain;
+use log::info;
+use mapr::{Mmap, MmapOptions};
+use merkletree::store::StoreConfig;
+use storage_proofs_core::{
+    cache_key::CacheKey,
+    error::Result,
+    util::{retry_transient_io, NODE_SIZE},
+};
+
+/// Identifies a layer file, followed by the number of layers.
+const LAYER_FILE_MAGIC: &[u8; 8] = b"FILLAYRS";
+
+/// The offset and length of a layer in the index.
+const INDEX_ENTRY_BYTES: usize = 16;
+
+/// Returns the path of the layer file in `cache_dir`.
+pub fn layer_file_path(cache_dir: &Path) -> PathBuf {
+    PathBuf::from(StoreConfig::data_path(
+        &cache_dir.to_path_buf(),
+        &CacheKey::LabelLayers.to_string(),
+    ))
+}
+
+fn header_bytes(layers: usize) -> usize {
+    LAYER_FILE_MAGIC.len() + 8 + layers * INDEX_ENTRY_BYTES
+}
+
+/// Concatenates the label layers stored as `configs` into the layer file of their cache
+/// directory, and removes the layer files.
+///
+/// The layer file starts with an index of the offset and length of every layer, followed by the
+/// layers in order. The layer files are only removed once the layer file is synced and renamed
+/// into place, so this needs the space of all layers once more, and an interruption leaves either
+/// the layer files or the layer file behind.
+pub fn consolidate_layer_files(configs: &[StoreConfig]) -> Result<PathBuf> {
+    ensure!(!configs.is_empty(), "no layers to consolidate");
+    let cache_dir = &configs[0].path;
+    ensure!(
+        configs.iter().all(|config| config.path == *cache_dir),
+        "the layers are not in one cache directory"
+    );
+
+    let layer_paths: Vec<PathBuf> = configs
+        .iter()
+        .map(|config| PathBuf::from(StoreConfig::data_path(&config.path, &config.id)))
+        .collect();
+
+    let mut header = vec![0u8; header_bytes(configs.len())];
+    header[..LAYER_FILE_MAGIC.len()].copy_from_slice(LAYER_FILE_MAGIC);
+    LittleEndian::write_u64(&mut header[8..16], configs.len() as u64);
+    let mut offset = header.len() as u64;
+    for (i, (config, layer_path)) in configs.iter().zip(&layer_paths).enumerate() {
+        let len = fs::metadata(layer_path)
+            .with_context(|| format!("could not read metadata of layer {:?}", layer_path))?
+            .len();
+        let expected = config.size.context("layer config has no size")? * NODE_SIZE;
+        ensure!(
+            len == expected as u64,
+            "layer {:?} has {} bytes, expected {}",
+            layer_path,
+            len,
+            expected
+        );
+
+        let entry = 16 + i * INDEX_ENTRY_BYTES;
+        LittleEndian::write_u64(&mut header[entry..entry + 8], offset);
+        LittleEndian::write_u64(&mut header[entry + 8..entry + 16], len);
+        offset += len;
+    }
+
+    let path = layer_file_path(cache_dir);
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)
+        .with_context(|| format!("could not create layer file {:?}", tmp_path))?;
+    io::Write::write_all(&mut file, &header)?;
+    for layer_path in &layer_paths {
+        let mut layer = File::open(layer_path)
+            .with_context(|| format!("could not open layer {:?}", layer_path))?;
+        io::copy(&mut layer, &mut file)
+            .with_context(|| format!("could not copy layer {:?}", layer_path))?;
+    }
+    file.sync_all()
+        .with_context(|| format!("could not sync layer file {:?}", tmp_path))?;
+    drop(file);
+    fs::rename(&tmp_path, &path)
+        .with_context(|| format!("could not rename layer file {:?}", tmp_path))?;
+    // The rename is only durable once the directory is synced.
+    File::open(cache_dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("could not sync cache directory {:?}", cache_dir))?;
+
+    for layer_path in &layer_paths {
+        fs::remove_file(layer_path)
+            .with_context(|| format!("could not remove layer {:?}", layer_path))?;
+    }
+
+    info!(
+        "consolidated {} layers into {:?}",
+        configs.len(),
+        path.display()
+    );
+
+    Ok(path)
+}
+
+/// Reads the label layers of a layer file written by [`consolidate_layer_files`].
+#[derive(Debug)]
+pub struct LayerFile {
+    data: Mmap,
+    /// The byte range of every layer.
+    layers: Vec<Range<usize>>,
+}
+
+impl LayerFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        let data = retry_transient_io(|| {
+            let file = File::open(path)
+                .with_context(|| format!("could not open layer file {:?}", path))?;
+            let data = unsafe { MmapOptions::new().map(&file) }
+                .with_context(|| format!("could not mmap layer file {:?}", path))?;
+            Ok(data)
+        })?;
+
+        ensure!(
+            data.len() >= header_bytes(0) && data[..LAYER_FILE_MAGIC.len()] == LAYER_FILE_MAGIC[..],
+            "{:?} is not a layer file",
+            path
+        );
+        let count = LittleEndian::read_u64(&data[8..16]) as usize;
+        let index_end = count
+            .checked_mul(INDEX_ENTRY_BYTES)
+            .and_then(|index| index.checked_add(header_bytes(0)))
+            .filter(|end| *end <= data.len())
+            .with_context(|| format!("the index of layer file {:?} is truncated", path))?;
+
+        let mut layers = Vec::with_capacity(count);
+        for i in 0..count {
+            let entry = 16 + i * INDEX_ENTRY_BYTES;
+            let offset = LittleEndian::read_u64(&data[entry..entry + 8]) as usize;
+            let len = LittleEndian::read_u64(&data[entry + 8..entry + 16]) as usize;
+            let end = offset.checked_add(len).filter(|end| *end <= data.len());
+            ensure!(
+                offset >= index_end && end.is_some() && len % NODE_SIZE == 0,
+                "layer {} of layer file {:?} is out of bounds",
+                i + 1,
+                path
+            );
+            layers.push(offset..offset + len);
+        }
+
+        Ok(LayerFile { data, layers })
+    }
+
+    /// How many layers are available.
+    pub fn layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Returns the labels of `layer`, which is counted from 1 like the layers of `Labels`.
+    pub fn layer(&self, layer: usize) -> Result<&[u8]> {
+        ensure!(
+            layer != 0 && layer <= self.layers(),
+            "Layer {} is not available (only {} layers available)",
+            layer,
+            self.layers()
+        );
+
+        Ok(&self.data[self.layers[layer - 1].clone()])
+    }
+
+    /// Returns the label of `node` in `layer`.
+    pub fn read_at<D: Domain>(&self, layer: usize, node: usize) -> Result<D> {
+        let labels = self.layer(layer)?;
+        let start = node * NODE_SIZE;
+        ensure!(
+            start + NODE_SIZE <= labels.len(),
+            "node {} is out of range for layer {}",
+            node,
+            layer
+        );
+
+        D::try_from_bytes(&labels[start..start + NODE_SIZE])
+    }
+
+    /// Returns the labels of `nodes` in `layer`.
+    pub fn read_range<D: Domain>(&self, layer: usize, nodes: Range<usize>) -> Result<Vec<D>> {
+        let labels = self.layer(layer)?;
+        ensure!(
+            nodes.start <= nodes.end && nodes.end * NODE_SIZE <= labels.len(),
+            "nodes {:?} are out of range for layer {}",
+            nodes,
+            layer
+        );
+
+        labels[nodes.start * NODE_SIZE..nodes.end * NODE_SIZE]
+            .chunks(NODE_SIZE)
+            .map(D::try_from_bytes)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use filecoin_hashers::poseidon::PoseidonDomain;
+    use rand::{Rng, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+    use storage_proofs_core::TEST_SEED;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_consolidate_layer_files() {
+        let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+        let cache_dir = tempdir().expect("tempdir failure");
+        let nodes = 64;
+
+        let mut configs = Vec::new();
+        let mut layers = Vec::new();
+        for layer in 1..=3 {
+            let config = StoreConfig::new(cache_dir.path(), CacheKey::label_layer(layer), 0);
+            let config = StoreConfig::from_config(&config, config.id.clone(), Some(nodes));
+            let labels: Vec<u8> = (0..nodes * NODE_SIZE).map(|_| rng.gen()).collect();
+            fs::write(StoreConfig::data_path(&config.path, &config.id), &labels)
+                .expect("failed to write layer");
+            configs.push(config);
+            layers.push(labels);
+        }
+
+        let path = consolidate_layer_files(&configs).expect("consolidate_layer_files failure");
+        assert_eq!(path, layer_file_path(cache_dir.path()));
+        for config in &configs {
+            assert!(!Path::new(&StoreConfig::data_path(&config.path, &config.id)).exists());
+        }
+
+        let layer_file = LayerFile::open(&path).expect("LayerFile::open failure");
+        assert_eq!(layer_file.layers(), 3);
+        for (i, labels) in layers.iter().enumerate() {
+            assert_eq!(layer_file.layer(i + 1).expect("layer failure"), &labels[..]);
+        }
+
+        let node: PoseidonDomain = layer_file.read_at(2, 5).expect("read_at failure");
+        assert_eq!(node.as_ref(), &layers[1][5 * NODE_SIZE..6 * NODE_SIZE]);
+        let nodes: Vec<PoseidonDomain> = layer_file
+            .read_range(3, 10..12)
+            .expect("read_range failure");
+        assert_eq!(
+            nodes[1].as_ref(),
+            &layers[2][11 * NODE_SIZE..12 * NODE_SIZE]
+        );
+
+        assert!(layer_file.layer(0).is_err());
+        assert!(layer_file.layer(4).is_err());
+        assert!(layer_file.read_at::<PoseidonDomain>(1, nodes).is_err());
+        assert!(layer_file
+            .read_range::<PoseidonDomain>(1, nodes - 1..nodes + 1)
+            .is_err());
+    }
+}
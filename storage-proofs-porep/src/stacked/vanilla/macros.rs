@@ -66,33 +66,10 @@ macro_rules! prefetch {
 // Used in multicore sdr only
 #[allow(unused_macros)]
 macro_rules! compress256 {
-    ($state:expr, $buf:expr, 1) => {
-        let blocks = [*GenericArray::<u8, U64>::from_slice(&$buf[..64])];
-        sha2::compress256((&mut $state[..8]).try_into().unwrap(), &blocks[..]);
-    };
-    ($state:expr, $buf:expr, 2) => {
-        let blocks = [
-            *GenericArray::<u8, U64>::from_slice(&$buf[..64]),
-            *GenericArray::<u8, U64>::from_slice(&$buf[64..128]),
-        ];
-        sha2::compress256((&mut $state[..8]).try_into().unwrap(), &blocks[..]);
-    };
-    ($state:expr, $buf:expr, 3) => {
-        let blocks = [
-            *GenericArray::<u8, U64>::from_slice(&$buf[..64]),
-            *GenericArray::<u8, U64>::from_slice(&$buf[64..128]),
-            *GenericArray::<u8, U64>::from_slice(&$buf[128..192]),
-        ];
-        sha2::compress256((&mut $state[..8]).try_into().unwrap(), &blocks[..]);
-    };
-    ($state:expr, $buf:expr, 5) => {
-        let blocks = [
-            *GenericArray::<u8, U64>::from_slice(&$buf[..64]),
-            *GenericArray::<u8, U64>::from_slice(&$buf[64..128]),
-            *GenericArray::<u8, U64>::from_slice(&$buf[128..192]),
-            *GenericArray::<u8, U64>::from_slice(&$buf[192..256]),
-            *GenericArray::<u8, U64>::from_slice(&$buf[256..320]),
-        ];
-        sha2::compress256((&mut $state[..8]).try_into().unwrap(), &blocks[..]);
+    ($state:expr, $buf:expr, $num_blocks:expr) => {
+        sha2raw::implementation().compress256_bytes(
+            (&mut $state[..8]).try_into().unwrap(),
+            &$buf[..$num_blocks * 64],
+        );
     };
 }
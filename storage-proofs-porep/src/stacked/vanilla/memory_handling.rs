@@ -3,18 +3,23 @@ use std::fs::File;
 use std::hint::spin_loop;
 use std::marker::{PhantomData, Sync};
 use std::mem::size_of;
+use std::ops::{Deref, DerefMut};
 use std::path::Path;
 use std::slice;
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use byte_slice_cast::{AsSliceOf, FromByteSlice};
-use log::{info, warn};
+use log::{debug, info, warn};
 use mapr::{Mmap, MmapMut, MmapOptions};
+use storage_proofs_core::{settings::SETTINGS, util::NODE_SIZE};
 
 pub struct CacheReader<T> {
     file: File,
     bufs: UnsafeCell<[Mmap; 2]>,
+    /// Whether the windows are advised to be backed by transparent huge pages.
+    huge_pages: bool,
     size: usize,
     degree: usize,
     window_size: usize,
@@ -115,11 +120,13 @@ impl<T: FromByteSlice> CacheReader<T> {
             }
         };
 
-        let buf0 = Self::map_buf(0, window_size, &file)?;
-        let buf1 = Self::map_buf(window_size as u64, window_size, &file)?;
+        let huge_pages = SETTINGS.parent_cache_huge_pages;
+        let buf0 = Self::map_buf(0, window_size, &file, huge_pages)?;
+        let buf1 = Self::map_buf(window_size as u64, window_size, &file, huge_pages)?;
         Ok(Self {
             file,
             bufs: UnsafeCell::new([buf0, buf1]),
+            huge_pages,
             size,
             degree,
             window_size,
@@ -172,14 +179,19 @@ impl<T: FromByteSlice> CacheReader<T> {
     }
 
     pub fn start_reset(&self) -> Result<()> {
-        let buf0 = Self::map_buf(0, self.window_size, &self.file)?;
+        let buf0 = Self::map_buf(0, self.window_size, &self.file, self.huge_pages)?;
         let bufs = unsafe { self.get_mut_bufs() };
         bufs[0] = buf0;
         Ok(())
     }
 
     pub fn finish_reset(&self) -> Result<()> {
-        let buf1 = Self::map_buf(self.window_size as u64, self.window_size, &self.file)?;
+        let buf1 = Self::map_buf(
+            self.window_size as u64,
+            self.window_size,
+            &self.file,
+            self.huge_pages,
+        )?;
         let bufs = unsafe { self.get_mut_bufs() };
         bufs[1] = buf1;
         self.cursor.store(0);
@@ -187,15 +199,15 @@ impl<T: FromByteSlice> CacheReader<T> {
         Ok(())
     }
 
-    fn map_buf(offset: u64, len: usize, file: &File) -> Result<Mmap> {
-        unsafe {
-            MmapOptions::new()
-                .offset(offset)
-                .len(len)
-                .private()
-                .map(file)
-                .map_err(|e| e.into())
+    /// Maps a window of the cache. It is mapped shared and read-only, so that every process
+    /// labeling with the cache maps the same pages of the page cache, or of shared memory.
+    fn map_buf(offset: u64, len: usize, file: &File, huge_pages: bool) -> Result<Mmap> {
+        let buf = unsafe { MmapOptions::new().offset(offset).len(len).map(file)? };
+        if huge_pages {
+            advise_huge_pages(&buf);
         }
+
+        Ok(buf)
     }
 
     #[inline]
@@ -266,6 +278,7 @@ impl<T: FromByteSlice> CacheReader<T> {
             (new_window * self.window_size) as u64,
             self.window_size as usize,
             &self.file,
+            self.huge_pages,
         )
         .expect("map_buf failed");
 
@@ -275,36 +288,448 @@ impl<T: FromByteSlice> CacheReader<T> {
     }
 }
 
-fn allocate_layer(sector_size: usize) -> Result<MmapMut> {
-    match MmapOptions::new()
-        .len(sector_size)
-        .private()
-        .clone()
-        .lock()
-        .map_anon()
-        .and_then(|mut layer| {
-            layer.mlock()?;
+/// The memory holding the labels of a layer while it is labeled.
+#[derive(Debug)]
+pub enum LayerBuffer {
+    Mmap(MmapMut),
+    HugePages(HugePages),
+}
+
+impl Deref for LayerBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            LayerBuffer::Mmap(layer) => layer,
+            LayerBuffer::HugePages(layer) => layer,
+        }
+    }
+}
+
+impl DerefMut for LayerBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            LayerBuffer::Mmap(layer) => layer,
+            LayerBuffer::HugePages(layer) => layer,
+        }
+    }
+}
+
+/// An anonymous mapping backed by explicit 2MiB huge pages, which have to be reserved by the
+/// system, e.g. through `/proc/sys/vm/nr_hugepages`.
+#[derive(Debug)]
+pub struct HugePages {
+    ptr: *mut u8,
+    /// The length requested, the mapping is rounded up to whole huge pages.
+    len: usize,
+}
+
+// The mapping is owned exclusively, like a `MmapMut`.
+unsafe impl Send for HugePages {}
+unsafe impl Sync for HugePages {}
+
+const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+impl HugePages {
+    fn mapped_len(len: usize) -> usize {
+        (len + HUGE_PAGE_SIZE - 1) / HUGE_PAGE_SIZE * HUGE_PAGE_SIZE
+    }
+
+    /// Maps `len` bytes of huge pages. All pages are faulted in while mapping, so they are placed
+    /// on the NUMA node the memory of the calling thread is bound to.
+    #[cfg(target_os = "linux")]
+    fn new(len: usize) -> Result<Self> {
+        // MAP_HUGE_2MB, the page size is encoded as its log2 above MAP_HUGE_SHIFT.
+        const MAP_HUGE_2MB: libc::c_int = 21 << 26;
+
+        anyhow::ensure!(len > 0, "cannot map zero bytes of huge pages");
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                Self::mapped_len(len),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE
+                    | libc::MAP_ANONYMOUS
+                    | libc::MAP_HUGETLB
+                    | MAP_HUGE_2MB
+                    | libc::MAP_POPULATE,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok(HugePages {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn new(_len: usize) -> Result<Self> {
+        anyhow::bail!("huge pages are only supported on Linux")
+    }
+}
+
+impl Deref for HugePages {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for HugePages {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for HugePages {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, Self::mapped_len(self.len));
+        }
+    }
+}
+
+/// Advises the kernel to back the mapping `buf` with transparent huge pages. It only does if they
+/// are enabled in `/sys/kernel/mm/transparent_hugepage/enabled` (`madvise` or `always`), and, for
+/// a mapping of a file, if the kernel supports huge pages for the page cache of read-only files.
+/// Returns whether the advice was accepted, which does not mean that huge pages are used.
+pub(crate) fn advise_huge_pages(buf: &[u8]) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        if buf.is_empty() {
+            return false;
+        }
+        // A mapping at an offset starts within a page, madvise needs the start of the page.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let start = buf.as_ptr() as usize;
+        let aligned = start - start % page_size;
+        let res = unsafe {
+            libc::madvise(
+                aligned as *mut libc::c_void,
+                buf.len() + start - aligned,
+                libc::MADV_HUGEPAGE,
+            )
+        };
+        if res != 0 {
+            debug!(
+                "failed to advise huge pages: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+
+        res == 0
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = buf;
+        false
+    }
+}
+
+/// Maps an anonymous buffer of `len` bytes, advised to be backed by transparent huge pages if
+/// `huge_pages` is set. It is locked in memory if the permissions allow it.
+fn map_anon(len: usize, huge_pages: bool) -> Result<MmapMut> {
+    let map = || -> std::io::Result<MmapMut> {
+        if huge_pages {
+            // MAP_LOCKED would fault the pages in before they are advised, so the buffer is only
+            // locked, and faulted in, afterwards.
+            let layer = MmapOptions::new().len(len).private().map_anon()?;
+            advise_huge_pages(&layer);
             Ok(layer)
-        }) {
+        } else {
+            MmapOptions::new()
+                .len(len)
+                .private()
+                .clone()
+                .lock()
+                .map_anon()
+        }
+    };
+
+    match map().and_then(|mut layer| {
+        layer.mlock()?;
+        Ok(layer)
+    }) {
         Ok(layer) => Ok(layer),
         Err(err) => {
             // fallback to not locked if permissions are not available
             warn!("failed to lock map {:?}, falling back", err);
-            let layer = MmapOptions::new().len(sector_size).private().map_anon()?;
+            let layer = MmapOptions::new().len(len).private().map_anon()?;
+            if huge_pages {
+                advise_huge_pages(&layer);
+            }
             Ok(layer)
         }
     }
 }
 
+/// Allocates the buffer for one layer, from huge pages if `huge_pages` is set: from explicit huge
+/// pages if enough of them are reserved, else from transparent huge pages if the kernel has them.
+/// The caller's memory binding decides on which NUMA node the buffer is placed, as the pages are
+/// faulted in right away, so the buffers are allocated after the labeling thread is bound to its
+/// core group.
+fn allocate_layer(sector_size: usize, huge_pages: bool) -> Result<LayerBuffer> {
+    if huge_pages {
+        match HugePages::new(sector_size) {
+            Ok(layer) => return Ok(LayerBuffer::HugePages(layer)),
+            Err(err) => warn!(
+                "failed to map huge pages {:?}, falling back to transparent huge pages",
+                err
+            ),
+        }
+    }
+
+    Ok(LayerBuffer::Mmap(map_anon(sector_size, huge_pages)?))
+}
+
+/// Layer buffers for labeling sectors of one size, which are reused by every labeling they are
+/// passed to instead of being allocated and faulted in for every sector.
+///
+/// The pool never grows beyond the buffers it was created with: a labeling takes both of its
+/// buffers at once, and waits for another one to return its buffers if there are not two left,
+/// so that the pool bounds the memory of the labelings sharing it.
+///
+/// Clones share the buffers. The buffers are placed on the NUMA node the memory of the thread
+/// creating the pool is bound to, not on the one of the core group labeling with them.
+#[derive(Clone, Debug)]
+pub struct BufferPool {
+    sector_size: usize,
+    buffers: Arc<PoolBuffers>,
+}
+
+/// The buffers of a `BufferPool` which are not borrowed.
+#[derive(Debug)]
+struct PoolBuffers {
+    free: Mutex<Vec<LayerBuffer>>,
+    /// Notified whenever buffers are returned.
+    returned: Condvar,
+}
+
+impl BufferPool {
+    /// Allocates the buffers for labeling `sectors` sectors of `sector_size` bytes at the same
+    /// time, two per sector, from huge pages if `use_huge_pages` is set.
+    pub fn new(sector_size: u64, sectors: usize) -> Result<Self> {
+        ensure!(
+            sector_size > 0 && sector_size as usize % NODE_SIZE == 0,
+            "invalid sector size {} for a buffer pool",
+            sector_size
+        );
+        ensure!(sectors > 0, "a buffer pool needs buffers for one sector");
+        let sector_size = sector_size as usize;
+        let buffers = (0..2 * sectors)
+            .map(|_| allocate_layer(sector_size, SETTINGS.use_huge_pages))
+            .collect::<Result<_>>()?;
+        info!(
+            "allocated buffer pool of {} buffers of {} bytes",
+            2 * sectors,
+            sector_size
+        );
+
+        Ok(BufferPool {
+            sector_size,
+            buffers: Arc::new(PoolBuffers {
+                free: Mutex::new(buffers),
+                returned: Condvar::new(),
+            }),
+        })
+    }
+
+    /// The size in bytes of the sectors the buffers are for.
+    pub fn sector_size(&self) -> u64 {
+        self.sector_size as u64
+    }
+
+    /// The number of buffers which are not borrowed by a labeling.
+    pub fn available(&self) -> usize {
+        self.buffers.free.lock().expect("poisoned lock").len()
+    }
+
+    /// Borrows the two buffers for labeling a sector of `sector_size` bytes, waiting until two
+    /// are returned if fewer are left.
+    fn borrow_pair(&self, sector_size: usize) -> Result<(LabelBuffer, LabelBuffer)> {
+        ensure!(
+            sector_size == self.sector_size,
+            "buffer pool is for sectors of {} bytes, not {}",
+            self.sector_size,
+            sector_size
+        );
+
+        let mut free = self.buffers.free.lock().expect("poisoned lock");
+        if free.len() < 2 {
+            debug!("buffer pool is empty, waiting for buffers to be returned");
+        }
+        while free.len() < 2 {
+            free = self.buffers.returned.wait(free).expect("poisoned lock");
+        }
+        let mut borrow = || LabelBuffer {
+            buffer: free.pop(),
+            pool: Some(self.clone()),
+        };
+
+        Ok((borrow(), borrow()))
+    }
+}
+
+/// A layer buffer used for labeling, which is returned to its pool when it is dropped, if it was
+/// borrowed from one.
+#[derive(Debug)]
+pub struct LabelBuffer {
+    buffer: Option<LayerBuffer>,
+    pool: Option<BufferPool>,
+}
+
+impl Deref for LabelBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buffer.as_ref().expect("buffer is only taken on drop")
+    }
+}
+
+impl DerefMut for LabelBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buffer.as_mut().expect("buffer is only taken on drop")
+    }
+}
+
+impl Drop for LabelBuffer {
+    fn drop(&mut self) {
+        if let (Some(pool), Some(buffer)) = (self.pool.take(), self.buffer.take()) {
+            pool.buffers
+                .free
+                .lock()
+                .expect("poisoned lock")
+                .push(buffer);
+            pool.buffers.returned.notify_all();
+        }
+    }
+}
+
+/// Returns the two buffers for the layers of labeling, borrowed from `buffer_pool` if it is set.
+fn layer_buffers(
+    sector_size: usize,
+    buffer_pool: Option<&BufferPool>,
+) -> Result<(LabelBuffer, LabelBuffer)> {
+    if let Some(pool) = buffer_pool {
+        return pool.borrow_pair(sector_size);
+    }
+
+    let allocate = || -> Result<LabelBuffer> {
+        Ok(LabelBuffer {
+            buffer: Some(allocate_layer(sector_size, SETTINGS.use_huge_pages)?),
+            pool: None,
+        })
+    };
+
+    Ok((allocate()?, allocate()?))
+}
+
 pub fn setup_create_label_memory(
     sector_size: usize,
     degree: usize,
     window_size: Option<usize>,
     cache_path: &Path,
-) -> Result<(CacheReader<u32>, MmapMut, MmapMut)> {
+    buffer_pool: Option<&BufferPool>,
+) -> Result<(CacheReader<u32>, LabelBuffer, LabelBuffer)> {
     let parents_cache = CacheReader::new(cache_path, window_size, degree)?;
-    let layer_labels = allocate_layer(sector_size)?;
-    let exp_labels = allocate_layer(sector_size)?;
+    let (layer_labels, exp_labels) = layer_buffers(sector_size, buffer_pool)?;
 
     Ok((parents_cache, layer_labels, exp_labels))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_allocate_layer() {
+        // Whether huge pages are available depends on the machine, both have to work the same.
+        for huge_pages in [false, true].iter() {
+            let len = 3 * HUGE_PAGE_SIZE + 4096;
+            let mut layer = allocate_layer(len, *huge_pages).expect("allocate_layer failure");
+            assert_eq!(layer.len(), len);
+            assert!(layer.iter().all(|byte| *byte == 0));
+
+            layer[len - 1] = 7;
+            let mut other = allocate_layer(len, *huge_pages).expect("allocate_layer failure");
+            std::mem::swap(&mut layer, &mut other);
+            assert_eq!(other[len - 1], 7);
+            assert_eq!(layer[len - 1], 0);
+        }
+    }
+
+    #[test]
+    fn test_map_buf_huge_pages() {
+        let dir = tempfile::tempdir().expect("tempdir failure");
+        let path = dir.path().join("parents");
+        let data: Vec<u8> = (0..4 * HUGE_PAGE_SIZE).map(|i| i as u8).collect();
+        std::fs::write(&path, &data).expect("failed to write parents");
+        let file = File::open(&path).expect("failed to open parents");
+
+        // Whether the advice is taken depends on the kernel, the data is the same either way.
+        for huge_pages in [false, true].iter() {
+            let offset = HUGE_PAGE_SIZE + 4096;
+            let buf =
+                CacheReader::<u32>::map_buf(offset as u64, HUGE_PAGE_SIZE, &file, *huge_pages)
+                    .expect("map_buf failure");
+            assert_eq!(&buf[..], &data[offset..offset + HUGE_PAGE_SIZE]);
+        }
+    }
+
+    #[test]
+    fn test_buffer_pool() {
+        let len = 4096;
+        let pool = BufferPool::new(len as u64, 1).expect("BufferPool::new failure");
+        assert_eq!(pool.available(), 2);
+        assert!(BufferPool::new(len as u64 + 1, 1).is_err());
+        assert!(BufferPool::new(len as u64, 0).is_err());
+
+        let (receiver, waiter) = {
+            let (mut buffer, _exp) =
+                layer_buffers(len, Some(&pool)).expect("layer_buffers failure");
+            assert_eq!(buffer.len(), len);
+            buffer[0] = 7;
+            assert_eq!(pool.available(), 0);
+
+            // The pool is only for sectors of its size.
+            assert!(layer_buffers(2 * len, Some(&pool)).is_err());
+
+            // A second labeling waits for the buffers of the first one, the pool does not grow.
+            let (sender, receiver) = mpsc::channel();
+            let waiting_pool = pool.clone();
+            let waiter = thread::spawn(move || {
+                let buffers = layer_buffers(len, Some(&waiting_pool));
+                sender.send(()).expect("failed to send");
+                buffers.map(|_| ())
+            });
+            assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+
+            (receiver, waiter)
+        };
+        // Once they are returned, it gets them.
+        receiver
+            .recv_timeout(Duration::from_secs(10))
+            .expect("waiting labeling got no buffers");
+        waiter
+            .join()
+            .expect("waiter panicked")
+            .expect("layer_buffers failure");
+        assert_eq!(pool.available(), 2);
+
+        // Buffers are reused as they are.
+        let (first, second) = layer_buffers(len, Some(&pool)).expect("layer_buffers failure");
+        assert!(first[0] == 7 || second[0] == 7);
+    }
+}
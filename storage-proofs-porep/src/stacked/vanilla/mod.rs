@@ -4,29 +4,53 @@ mod macros;
 pub mod create_label;
 pub(crate) mod hash;
 
+mod async_write;
 mod cache;
 mod challenges;
 mod column;
 mod column_proof;
-#[cfg(feature = "multicore-sdr")]
+mod compression;
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
 mod cores;
 mod encoding_proof;
+mod gpu;
 mod graph;
 mod labeling_proof;
-#[cfg(feature = "multicore-sdr")]
+mod layer_file;
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
 mod memory_handling;
 mod params;
+mod parents_source;
 mod porep;
 mod proof;
 mod proof_scheme;
-#[cfg(feature = "multicore-sdr")]
+mod tree_progress;
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
 mod utils;
 
+pub use cache::{ParentCacheProgress, ParentCacheProgressCallback};
 pub use challenges::{ChallengeRequirements, LayerChallenges};
 pub use column::Column;
 pub use column_proof::ColumnProof;
+#[cfg(feature = "cache-compression")]
+pub use compression::{compress_cache_file, compress_layers};
+pub use compression::{decompress_cache_file, decompress_layers, is_compressed};
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+pub use cores::{
+    checkout_core_group, checkout_core_group_for, current_core_binding, current_core_bindings,
+    install_on_core_groups, is_group_checked_out, numa_memory, release_core_group,
+    set_on_bind_failure, stats as core_stats, BindFailureCallback, CoreGroupGuard, CoreIndex,
+    CoreStats,
+};
 pub use encoding_proof::EncodingProof;
+pub use gpu::{
+    detected_gpus, tree_builder_stats, GpuBatchSizes, GpuOptions, GpuSelection, TreeBuilderStats,
+};
 pub use graph::{StackedBucketGraph, StackedGraph, EXP_DEGREE};
 pub use labeling_proof::LabelingProof;
+pub use layer_file::{consolidate_layer_files, layer_file_path, LayerFile};
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+pub use memory_handling::BufferPool;
 pub use params::*;
+pub use parents_source::{Computed, FileCache, ParentsCacheStats, ParentsSource};
 pub use proof::{StackedDrg, TreeRElementData, TOTAL_PARENTS};
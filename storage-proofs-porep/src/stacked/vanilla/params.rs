@@ -1,9 +1,10 @@
 use std::fs::remove_file;
 use std::marker::PhantomData;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 
-use anyhow::Context;
-use filecoin_hashers::{Domain, Hasher};
+use anyhow::{bail, ensure, Context};
+use filecoin_hashers::{Domain, HashFunction, Hasher};
 use fr32::bytes_into_fr_repr_safe;
 use generic_array::typenum::{Unsigned, U2};
 use log::trace;
@@ -23,10 +24,14 @@ use storage_proofs_core::{
         MerkleProofTrait, MerkleTreeTrait,
     },
     parameter_cache::ParameterSetMetadata,
-    util::data_at_node,
+    util::{data_at_node, retry_transient_io, NODE_SIZE},
 };
 
 use crate::stacked::vanilla::{
+    compression::{decompress_cache_file, decompress_layers},
+    create_label::remove_layer_digest,
+    layer_file::{layer_file_path, LayerFile},
+    tree_progress::remove_progress,
     Column, ColumnProof, EncodingProof, LabelingProof, LayerChallenges, StackedBucketGraph,
 };
 
@@ -156,11 +161,11 @@ pub struct Proof<Tree: MerkleTreeTrait, G: Hasher> {
     pub comm_r_last_proof:
         MerkleProof<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>,
     #[serde(bound(
-        serialize = "ReplicaColumnProof<MerkleProof<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>,>: Serialize",
-        deserialize = "ReplicaColumnProof<MerkleProof<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>: Deserialize<'de>"
+        serialize = "ReplicaColumnProof<MerkleProof<Tree::Hasher, Tree::TreeCArity, Tree::SubTreeArity, Tree::TopTreeArity>,>: Serialize",
+        deserialize = "ReplicaColumnProof<MerkleProof<Tree::Hasher, Tree::TreeCArity, Tree::SubTreeArity, Tree::TopTreeArity>>: Deserialize<'de>"
     ))]
     pub replica_column_proofs: ReplicaColumnProof<
-        MerkleProof<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>,
+        MerkleProof<Tree::Hasher, Tree::TreeCArity, Tree::SubTreeArity, Tree::TopTreeArity>,
     >,
     #[serde(bound(
         serialize = "LabelingProof<Tree::Hasher>: Serialize",
@@ -393,7 +398,10 @@ impl<Tree: MerkleTreeTrait, G: Hasher> TemporaryAux<Tree, G> {
         layer: usize,
         node_index: u32,
     ) -> Result<<Tree::Hasher as Hasher>::Domain> {
-        self.labels_for_layer(layer)?.read_at(node_index as usize)
+        match self.labels.layer_file()? {
+            Some(layer_file) => layer_file.read_at(layer, node_index as usize),
+            None => self.labels_for_layer(layer)?.read_at(node_index as usize),
+        }
     }
 
     pub fn column(&self, column_index: u32) -> Result<Column<Tree::Hasher>> {
@@ -410,19 +418,19 @@ impl<Tree: MerkleTreeTrait, G: Hasher> TemporaryAux<Tree, G> {
         let delete_tree_c_store = |config: &StoreConfig, tree_c_size: usize| -> Result<()> {
             let tree_c_store = DiskStore::<<Tree::Hasher as Hasher>::Domain>::new_from_disk(
                 tree_c_size,
-                Tree::Arity::to_usize(),
+                Tree::TreeCArity::to_usize(),
                 config,
             )
             .context("tree_c")?;
             // Note: from_data_store requires the base tree leaf count
             let tree_c = DiskTree::<
                 Tree::Hasher,
-                Tree::Arity,
+                Tree::TreeCArity,
                 Tree::SubTreeArity,
                 Tree::TopTreeArity,
             >::from_data_store(
                 tree_c_store,
-                get_merkle_tree_leafs(tree_c_size, Tree::Arity::to_usize())?,
+                get_merkle_tree_leafs(tree_c_size, Tree::TreeCArity::to_usize())?,
             )
             .context("tree_c")?;
             tree_c.delete(config.clone()).context("tree_c")?;
@@ -468,16 +476,25 @@ impl<Tree: MerkleTreeTrait, G: Hasher> TemporaryAux<Tree, G> {
                     .with_context(|| format!("Failed to delete {:?}", &tree_c_path))?
             }
         }
+        for config in &configs {
+            remove_progress(config);
+        }
         trace!("tree c deleted");
 
         for i in 0..t_aux.labels.labels.len() {
             let cur_config = t_aux.labels.labels[i].clone();
+            remove_layer_digest(&cur_config);
             if cached(&cur_config) {
                 DiskStore::<<Tree::Hasher as Hasher>::Domain>::delete(cur_config)
                     .with_context(|| format!("labels {}", i))?;
                 trace!("layer {} deleted", i);
             }
         }
+        if let Some(layer_file_path) = t_aux.labels.layer_file_path() {
+            remove_file(&layer_file_path)
+                .with_context(|| format!("Failed to delete {:?}", &layer_file_path))?;
+            trace!("layer file deleted");
+        }
 
         Ok(())
     }
@@ -496,7 +513,7 @@ pub struct TemporaryAuxCache<Tree: MerkleTreeTrait, G: Hasher> {
     // StoreConfig for later use (i.e. proof generation).
     pub tree_r_last_config_rows_to_discard: usize,
 
-    pub tree_c: DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>,
+    pub tree_c: DiskTree<Tree::Hasher, Tree::TreeCArity, Tree::SubTreeArity, Tree::TopTreeArity>,
     pub t_aux: TemporaryAux<Tree, G>,
     pub replica_path: PathBuf,
 }
@@ -511,9 +528,10 @@ impl<Tree: MerkleTreeTrait, G: Hasher> TemporaryAuxCache<Tree, G> {
             tree_d_size,
             tree_d_leafs,
         );
-        let tree_d_store: DiskStore<G::Domain> =
+        let tree_d_store: DiskStore<G::Domain> = retry_transient_io(|| {
             DiskStore::new_from_disk(tree_d_size, BINARY_ARITY, &t_aux.tree_d_config)
-                .context("tree_d_store")?;
+        })
+        .context("tree_d_store")?;
         let tree_d =
             BinaryMerkleTree::<G>::from_data_store(tree_d_store, tree_d_leafs).context("tree_d")?;
 
@@ -526,11 +544,13 @@ impl<Tree: MerkleTreeTrait, G: Hasher> TemporaryAuxCache<Tree, G> {
             "Instantiating tree c [count {}] with size {} and arity {}",
             tree_count,
             tree_c_size,
-            Tree::Arity::to_usize(),
+            Tree::TreeCArity::to_usize(),
         );
-        let tree_c = create_disk_tree::<
-            DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>,
-        >(tree_c_size, &configs)?;
+        let tree_c = retry_transient_io(|| {
+            create_disk_tree::<
+                DiskTree<Tree::Hasher, Tree::TreeCArity, Tree::SubTreeArity, Tree::TopTreeArity>,
+            >(tree_c_size, &configs)
+        })?;
 
         // tree_r_last_size stored in the config is the base tree size
         let tree_r_last_size = t_aux.tree_r_last_config.size.expect("config size failure");
@@ -550,9 +570,11 @@ impl<Tree: MerkleTreeTrait, G: Hasher> TemporaryAuxCache<Tree, G> {
             Tree::SubTreeArity::to_usize(),
             Tree::TopTreeArity::to_usize(),
         );
-        let tree_r_last = create_lc_tree::<
-            LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>,
-        >(tree_r_last_size, &configs, &replica_config)?;
+        let tree_r_last = retry_transient_io(|| {
+            create_lc_tree::<
+                LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>,
+            >(tree_r_last_size, &configs, &replica_config)
+        })?;
 
         Ok(TemporaryAuxCache {
             labels: LabelsCache::new(&t_aux.labels).context("labels_cache")?,
@@ -565,7 +587,10 @@ impl<Tree: MerkleTreeTrait, G: Hasher> TemporaryAuxCache<Tree, G> {
         })
     }
 
-    pub fn labels_for_layer(&self, layer: usize) -> &DiskStore<<Tree::Hasher as Hasher>::Domain> {
+    pub fn labels_for_layer(
+        &self,
+        layer: usize,
+    ) -> Result<&DiskStore<<Tree::Hasher as Hasher>::Domain>> {
         self.labels.labels_for_layer(layer)
     }
 
@@ -574,7 +599,7 @@ impl<Tree: MerkleTreeTrait, G: Hasher> TemporaryAuxCache<Tree, G> {
         layer: usize,
         node_index: u32,
     ) -> Result<<Tree::Hasher as Hasher>::Domain> {
-        self.labels_for_layer(layer).read_at(node_index as usize)
+        self.labels.read_at(layer, node_index as usize)
     }
 
     pub fn column(&self, column_index: u32) -> Result<Column<Tree::Hasher>> {
@@ -620,11 +645,37 @@ impl<Tree: MerkleTreeTrait> Labels<Tree> {
     }
 
     pub fn verify_stores(&self, callback: VerifyCallback, cache_dir: &Path) -> Result<()> {
-        let updated_path_labels = self.labels.clone();
-        let required_configs = get_base_tree_count::<Tree>();
-        for mut label in updated_path_labels {
+        let mut updated_path_labels = self.labels.clone();
+        for label in &mut updated_path_labels {
             label.path = cache_dir.to_path_buf();
-            callback(&label, BINARY_ARITY, required_configs)?;
+        }
+        decompress_layers(&updated_path_labels)?;
+
+        let layer_file_path = layer_file_path(cache_dir);
+        if layer_file_path.exists() {
+            let layer_file = LayerFile::open(&layer_file_path)?;
+            ensure!(
+                layer_file.layers() == self.len(),
+                "layer file {:?} has {} layers, expected {}",
+                layer_file_path,
+                layer_file.layers(),
+                self.len()
+            );
+            for (i, label) in self.labels.iter().enumerate() {
+                let expected = label.size.context("layer config has no size")? * NODE_SIZE;
+                ensure!(
+                    layer_file.layer(i + 1)?.len() == expected,
+                    "layer {} in layer file {:?} has the wrong size",
+                    i + 1,
+                    layer_file_path
+                );
+            }
+            return Ok(());
+        }
+
+        let required_configs = get_base_tree_count::<Tree>();
+        for label in &updated_path_labels {
+            callback(label, BINARY_ARITY, required_configs)?;
         }
 
         Ok(())
@@ -645,12 +696,22 @@ impl<Tree: MerkleTreeTrait> Labels<Tree> {
         let row_index = layer - 1;
         let config = self.labels[row_index].clone();
         assert!(config.size.is_some());
+        if let Some(layer_file_path) = self.layer_file_path() {
+            bail!(
+                "layer {} is consolidated into {:?}, it cannot be opened as a store",
+                layer,
+                layer_file_path
+            );
+        }
+        decompress_layers(&self.labels[row_index..=row_index])?;
 
-        DiskStore::new_from_disk(
-            config.size.expect("config size failure"),
-            Tree::Arity::to_usize(),
-            &config,
-        )
+        retry_transient_io(|| {
+            DiskStore::new_from_disk(
+                config.size.expect("config size failure"),
+                Tree::Arity::to_usize(),
+                &config,
+            )
+        })
     }
 
     /// Returns label for the last layer.
@@ -663,18 +724,47 @@ impl<Tree: MerkleTreeTrait> Labels<Tree> {
         self.labels.len()
     }
 
+    /// Returns the path of the file the layers were consolidated into, if they were.
+    pub fn layer_file_path(&self) -> Option<PathBuf> {
+        self.labels
+            .first()
+            .map(|label| layer_file_path(&label.path))
+            .filter(|path| path.exists())
+    }
+
+    /// Opens the file the layers were consolidated into, if they were, decompressing it first if
+    /// it was compressed.
+    pub fn layer_file(&self) -> Result<Option<LayerFile>> {
+        self.layer_file_path()
+            .map(|path| {
+                decompress_cache_file(&path)?;
+                LayerFile::open(&path)
+            })
+            .transpose()
+    }
+
     /// Build the column for the given node.
     pub fn column(&self, node: u32) -> Result<Column<Tree::Hasher>> {
+        if let Some(layer_file) = self.layer_file()? {
+            let rows = (1..=layer_file.layers())
+                .map(|layer| layer_file.read_at(layer, node as usize))
+                .collect::<Result<_>>()?;
+            return Column::new(node, rows);
+        }
+        decompress_layers(&self.labels)?;
+
         let rows = self
             .labels
             .iter()
             .map(|label| {
                 assert!(label.size.is_some());
-                let store = DiskStore::new_from_disk(
-                    label.size.expect("label size failure"),
-                    Tree::Arity::to_usize(),
-                    label,
-                )?;
+                let store = retry_transient_io(|| {
+                    DiskStore::new_from_disk(
+                        label.size.expect("label size failure"),
+                        Tree::Arity::to_usize(),
+                        label,
+                    )
+                })?;
                 store.read_at(node as usize)
             })
             .collect::<Result<_>>()?;
@@ -692,11 +782,20 @@ impl<Tree: MerkleTreeTrait> Labels<Tree> {
 
 #[derive(Debug)]
 pub struct LabelsCache<Tree: MerkleTreeTrait> {
+    /// The stores of the layers, empty if they were consolidated into `layer_file`.
     pub labels: Vec<DiskStore<<Tree::Hasher as Hasher>::Domain>>,
+    pub layer_file: Option<LayerFile>,
 }
 
 impl<Tree: MerkleTreeTrait> LabelsCache<Tree> {
     pub fn new(labels: &Labels<Tree>) -> Result<Self> {
+        if let Some(layer_file) = labels.layer_file()? {
+            return Ok(LabelsCache {
+                labels: Vec::new(),
+                layer_file: Some(layer_file),
+            });
+        }
+
         let mut disk_store_labels: Vec<DiskStore<<Tree::Hasher as Hasher>::Domain>> =
             Vec::with_capacity(labels.len());
         for i in 0..labels.len() {
@@ -705,20 +804,35 @@ impl<Tree: MerkleTreeTrait> LabelsCache<Tree> {
 
         Ok(LabelsCache {
             labels: disk_store_labels,
+            layer_file: None,
         })
     }
 
     pub fn len(&self) -> usize {
-        self.labels.len()
+        match self.layer_file {
+            Some(ref layer_file) => layer_file.layers(),
+            None => self.labels.len(),
+        }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.labels.is_empty()
+        self.len() == 0
     }
 
-    pub fn labels_for_layer(&self, layer: usize) -> &DiskStore<<Tree::Hasher as Hasher>::Domain> {
-        assert!(layer != 0, "Layer cannot be 0");
-        assert!(
+    /// Returns the store of `layer`.
+    ///
+    /// Fails if the layers were consolidated into one file, use `read_at`, `read_range` or
+    /// `read_range_into` to read them independently of how they are stored.
+    pub fn labels_for_layer(
+        &self,
+        layer: usize,
+    ) -> Result<&DiskStore<<Tree::Hasher as Hasher>::Domain>> {
+        ensure!(
+            self.layer_file.is_none(),
+            "the layers are consolidated, they have no stores"
+        );
+        ensure!(layer != 0, "Layer cannot be 0");
+        ensure!(
             layer <= self.layers(),
             "Layer {} is not available (only {} layers available)",
             layer,
@@ -726,25 +840,75 @@ impl<Tree: MerkleTreeTrait> LabelsCache<Tree> {
         );
 
         let row_index = layer - 1;
-        &self.labels[row_index]
+        Ok(&self.labels[row_index])
     }
 
     /// Returns the labels on the last layer.
     pub fn labels_for_last_layer(&self) -> Result<&DiskStore<<Tree::Hasher as Hasher>::Domain>> {
+        ensure!(
+            self.layer_file.is_none(),
+            "the layers are consolidated, they have no stores"
+        );
         Ok(&self.labels[self.labels.len() - 1])
     }
 
     /// How many layers are available.
     fn layers(&self) -> usize {
-        self.labels.len()
+        self.len()
+    }
+
+    /// Returns the label of `node` in `layer`.
+    pub fn read_at(&self, layer: usize, node: usize) -> Result<<Tree::Hasher as Hasher>::Domain> {
+        match self.layer_file {
+            Some(ref layer_file) => layer_file.read_at(layer, node),
+            None => self.labels_for_layer(layer)?.read_at(node),
+        }
+    }
+
+    /// Returns the labels of `nodes` in `layer`.
+    pub fn read_range(
+        &self,
+        layer: usize,
+        nodes: Range<usize>,
+    ) -> Result<Vec<<Tree::Hasher as Hasher>::Domain>> {
+        match self.layer_file {
+            Some(ref layer_file) => layer_file.read_range(layer, nodes),
+            None => self.labels_for_layer(layer)?.read_range(nodes),
+        }
+    }
+
+    /// Copies the bytes of the labels of the nodes `start..end` in `layer` into `buf`.
+    pub fn read_range_into(
+        &self,
+        layer: usize,
+        start: usize,
+        end: usize,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        match self.layer_file {
+            Some(ref layer_file) => {
+                let labels = layer_file.layer(layer)?;
+                ensure!(
+                    start <= end && end * NODE_SIZE <= labels.len(),
+                    "nodes {}..{} are out of range for layer {}",
+                    start,
+                    end,
+                    layer
+                );
+                buf[..(end - start) * NODE_SIZE]
+                    .copy_from_slice(&labels[start * NODE_SIZE..end * NODE_SIZE]);
+                Ok(())
+            }
+            None => self
+                .labels_for_layer(layer)?
+                .read_range_into(start, end, buf),
+        }
     }
 
     /// Build the column for the given node.
     pub fn column(&self, node: u32) -> Result<Column<Tree::Hasher>> {
-        let rows = self
-            .labels
-            .iter()
-            .map(|labels| labels.read_at(node as usize))
+        let rows = (1..=self.layers())
+            .map(|layer| self.read_at(layer, node as usize))
             .collect::<Result<_>>()?;
 
         Column::new(node, rows)
@@ -755,6 +919,12 @@ pub fn get_node<H: Hasher>(data: &[u8], index: usize) -> Result<H::Domain> {
     H::Domain::try_from_bytes(data_at_node(data, index).expect("invalid node math"))
 }
 
+/// Returns `comm_r = H(comm_c || comm_r_last)`, the commitment to a replica given the roots of its
+/// tree_c and tree_r_last.
+pub fn finalize_comm_r<H: Hasher>(comm_c: &H::Domain, comm_r_last: &H::Domain) -> H::Domain {
+    H::Function::hash2(comm_c, comm_r_last)
+}
+
 /// Generate the replica id as expected for Stacked DRG.
 pub fn generate_replica_id<H: Hasher, T: AsRef<[u8]>>(
     prover_id: &[u8; 32],
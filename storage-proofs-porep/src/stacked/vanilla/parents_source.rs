@@ -0,0 +1,264 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::ensure;
+use filecoin_hashers::Hasher;
+use storage_proofs_core::{drgraph::Graph, error::Result};
+
+use crate::stacked::vanilla::{
+    cache::ParentCache,
+    graph::{StackedBucketGraph, DEGREE},
+};
+
+/// Provides the parents of the nodes being labeled.
+///
+/// Within a layer, parents are requested in ascending node order. `reset` is called before every
+/// layer, after which the parents are requested starting from the first node again.
+pub trait ParentsSource {
+    fn parents(&mut self, node: u32) -> Result<[u32; DEGREE]>;
+
+    fn reset(&mut self) -> Result<()>;
+
+    /// Returns how often the requested parents were held in memory since the source was created.
+    fn parents_cache_stats(&self) -> ParentsCacheStats;
+}
+
+/// How effective the in-memory part of a [`ParentsSource`] is.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ParentsCacheStats {
+    /// Requests for parents which were held in memory.
+    pub hits: u64,
+    /// Requests for parents which had to be read from the parent cache file or computed.
+    pub misses: u64,
+    /// How often parents held in memory were dropped to make room for others. For the file
+    /// cache, this counts the unmapped windows.
+    pub evictions: u64,
+}
+
+/// The counters behind [`ParentsCacheStats`]. They are atomic so that they can be read through a
+/// shared reference while the source is in use.
+#[derive(Debug, Default)]
+pub(crate) struct ParentsCacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl ParentsCacheCounters {
+    pub(crate) fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn evict(&self) {
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> ParentsCacheStats {
+        ParentsCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Reads the parents from the on-disk parent cache. This is the default source.
+pub type FileCache = ParentCache;
+
+impl ParentsSource for ParentCache {
+    fn parents(&mut self, node: u32) -> Result<[u32; DEGREE]> {
+        self.read(node)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        ParentCache::reset(self)
+    }
+
+    fn parents_cache_stats(&self) -> ParentsCacheStats {
+        self.counters.stats()
+    }
+}
+
+/// Computes the parents from the graph when they are requested, a window of `capacity` nodes at
+/// a time.
+///
+/// As the parents are requested in ascending node order, the window starts at the first node
+/// whose parents are not held, and the following requests are served from it until one falls
+/// outside.
+///
+/// This needs neither the parent cache file nor the memory to map it, at the cost of computing
+/// the parents of every node once per layer, unless the window holds all nodes of the graph, in
+/// which case they are computed once and reused for every layer.
+#[derive(Debug)]
+pub struct Computed<'a, H: Hasher> {
+    graph: &'a StackedBucketGraph<H>,
+    capacity: usize,
+    /// The first node of the window.
+    offset: u32,
+    /// The parents of the nodes from `offset` on.
+    window: Vec<[u32; DEGREE]>,
+    counters: ParentsCacheCounters,
+}
+
+impl<'a, H: Hasher> Computed<'a, H> {
+    /// Creates a source for `graph` which keeps the parents of at most `capacity` nodes (and at
+    /// least one) in memory.
+    pub fn new(graph: &'a StackedBucketGraph<H>, capacity: usize) -> Self {
+        Computed {
+            graph,
+            capacity: capacity.max(1),
+            offset: 0,
+            window: Vec::new(),
+            counters: ParentsCacheCounters::default(),
+        }
+    }
+
+    /// Returns the number of nodes whose parents are currently held in memory.
+    pub fn len(&self) -> usize {
+        self.window.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.window.is_empty()
+    }
+
+    fn contains(&self, node: u32) -> bool {
+        node >= self.offset && ((node - self.offset) as usize) < self.window.len()
+    }
+
+    /// Replaces the window with one starting at `node`.
+    fn shift(&mut self, node: u32) -> Result<()> {
+        let end = (node as usize + self.capacity).min(self.graph.size());
+        ensure!(
+            (node as usize) < end,
+            "node {} is out of range for {} nodes",
+            node,
+            self.graph.size()
+        );
+        if !self.window.is_empty() {
+            self.counters.evict();
+        }
+
+        self.window.clear();
+        for n in node as usize..end {
+            let mut parents = [0u32; DEGREE];
+            self.graph.parents(n, &mut parents)?;
+            self.window.push(parents);
+        }
+        self.offset = node;
+
+        Ok(())
+    }
+}
+
+impl<'a, H: Hasher> ParentsSource for Computed<'a, H> {
+    fn parents(&mut self, node: u32) -> Result<[u32; DEGREE]> {
+        if self.contains(node) {
+            self.counters.hit();
+        } else {
+            self.counters.miss();
+            self.shift(node)?;
+        }
+
+        Ok(self.window[(node - self.offset) as usize])
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // The computed parents stay valid across layers, so the window is kept in case it holds
+        // the first nodes.
+        Ok(())
+    }
+
+    fn parents_cache_stats(&self) -> ParentsCacheStats {
+        self.counters.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use filecoin_hashers::poseidon::PoseidonHasher;
+    use storage_proofs_core::{api_version::ApiVersion, drgraph::BASE_DEGREE};
+
+    use crate::stacked::vanilla::graph::EXP_DEGREE;
+
+    #[test]
+    fn test_computed_matches_file_cache() {
+        let nodes = 1 << 10;
+        let graph = StackedBucketGraph::<PoseidonHasher>::new(
+            None,
+            nodes,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            [7; 32],
+            ApiVersion::V1_1_0,
+        )
+        .expect("stacked bucket graph new failed");
+
+        let mut file_cache: FileCache = graph.parent_cache().expect("parent_cache failed");
+        let mut computed = Computed::new(&graph, 16);
+
+        for _layer in 0..2 {
+            ParentsSource::reset(&mut file_cache).expect("reset failed");
+            ParentsSource::reset(&mut computed).expect("reset failed");
+            for node in 0..nodes as u32 {
+                assert_eq!(
+                    ParentsSource::parents(&mut file_cache, node).expect("file cache failed"),
+                    computed.parents(node).expect("computed failed"),
+                    "node {}",
+                    node
+                );
+            }
+            assert_eq!(computed.len(), 16);
+        }
+
+        let file_stats = file_cache.parents_cache_stats();
+        assert_eq!(file_stats.hits + file_stats.misses, 2 * nodes as u64);
+        let computed_stats = computed.parents_cache_stats();
+        assert_eq!(
+            computed_stats.hits + computed_stats.misses,
+            2 * nodes as u64
+        );
+        // Every miss but the first drops the previous window.
+        assert_eq!(computed_stats.misses, 2 * nodes as u64 / 16);
+        assert_eq!(computed_stats.evictions, computed_stats.misses - 1);
+    }
+
+    #[test]
+    fn test_computed_reuses_full_window() {
+        let nodes = 64;
+        let graph = StackedBucketGraph::<PoseidonHasher>::new(
+            None,
+            nodes,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            [7; 32],
+            ApiVersion::V1_1_0,
+        )
+        .expect("stacked bucket graph new failed");
+        let mut computed = Computed::new(&graph, 2 * nodes);
+
+        for _layer in 0..2 {
+            ParentsSource::reset(&mut computed).expect("reset failed");
+            for node in 0..nodes as u32 {
+                computed.parents(node).expect("computed failed");
+            }
+        }
+
+        // The window is clamped to the graph, and the second layer is served from it entirely.
+        assert_eq!(computed.len(), nodes);
+        assert_eq!(
+            computed.parents_cache_stats(),
+            ParentsCacheStats {
+                hits: 2 * nodes as u64 - 1,
+                misses: 1,
+                evictions: 0,
+            }
+        );
+        assert!(computed.parents(nodes as u32).is_err());
+    }
+}
@@ -10,6 +10,7 @@ use storage_proofs_core::{
 
 use crate::{
     stacked::vanilla::{
+        gpu::GpuOptions,
         params::{PersistentAux, PublicParams, Tau, TemporaryAux},
         proof::StackedDrg,
     },
@@ -41,6 +42,7 @@ impl<'a, 'c, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> PoRep<'a, Tre
             data_tree,
             config,
             replica_path,
+            &GpuOptions::default(),
         )?;
 
         Ok((tau, (p_aux, t_aux)))
@@ -3,19 +3,21 @@ use std::fs;
 use std::marker::PhantomData;
 use std::panic::panic_any;
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+use std::time::Duration;
 
-use anyhow::Context;
+use anyhow::{ensure, Context};
 use bincode::deserialize;
 use blstrs::Scalar as Fr;
 use fdlimit::raise_fd_limit;
 use ff::PrimeField;
-use filecoin_hashers::{poseidon::PoseidonHasher, Domain, HashFunction, Hasher, PoseidonArity};
+use filecoin_hashers::{poseidon::PoseidonHasher, Domain, Hasher, PoseidonArity};
 use generic_array::typenum::{Unsigned, U0, U11, U2, U8};
 use lazy_static::lazy_static;
 use log::{error, info, trace, warn};
+use mapr::MmapOptions;
 use merkletree::{
-    merkle::{get_merkle_tree_len, is_merkle_tree_size_valid},
+    merkle::{get_merkle_tree_leafs, get_merkle_tree_len, is_merkle_tree_size_valid},
     store::{DiskStore, Store, StoreConfig},
 };
 use rayon::prelude::{
@@ -32,23 +34,40 @@ use storage_proofs_core::{
         split_config_and_replica, BinaryMerkleTree, DiskTree, LCTree, MerkleProofTrait, MerkleTree,
         MerkleTreeTrait,
     },
-    settings::SETTINGS,
-    util::{default_rows_to_discard, NODE_SIZE},
+    settings::{Settings, SETTINGS},
+    util::{self, default_rows_to_discard, NODE_SIZE},
 };
 use yastl::Pool;
 
+#[cfg(feature = "cache-compression")]
+use crate::stacked::vanilla::compression::compress_layers;
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+use crate::stacked::vanilla::{
+    cores::{
+        bind_core, checkout_core_group_blocking, checkout_core_group_for, core_group_from_subset,
+        current_pool_cores, has_core_groups_for, install_on_core_groups, Cleanup, CoreIndex,
+    },
+    memory_handling::BufferPool,
+};
 use crate::{
     encode::{decode, encode, encode_fr},
     stacked::vanilla::{
         challenges::LayerChallenges,
         column::Column,
         create_label,
+        gpu::{self, GpuOptions},
         graph::StackedBucketGraph,
         hash::hash_single_column,
+        layer_file::consolidate_layer_files,
         params::{
-            get_node, Labels, LabelsCache, PersistentAux, Proof, PublicInputs, PublicParams,
-            ReplicaColumnProof, Tau, TemporaryAux, TemporaryAuxCache, TransformedLayers,
-            BINARY_ARITY,
+            finalize_comm_r, get_node, Labels, LabelsCache, PersistentAux, Proof, PublicInputs,
+            PublicParams, ReplicaColumnProof, Tau, TemporaryAux, TemporaryAuxCache,
+            TransformedLayers, BINARY_ARITY,
+        },
+        parents_source::{Computed, ParentsSource},
+        tree_progress::{
+            is_reusable, mark_complete, remove_progress, start_encoding, tree_c_inputs,
+            tree_r_last_inputs, TreeResume,
         },
         EncodingProof, LabelingProof,
     },
@@ -58,12 +77,6 @@ use crate::{
 pub const TOTAL_PARENTS: usize = 37;
 
 lazy_static! {
-    /// Ensure that only one `TreeBuilder` or `ColumnTreeBuilder` uses the GPU at a time.
-    /// Curently, this is accomplished by only instantiating at most one at a time.
-    /// It might be possible to relax this constraint, but in that case, only one builder
-    /// should actually be active at any given time, so the mutex should still be used.
-    static ref GPU_LOCK: Mutex<()> = Mutex::new(());
-
     static ref THREAD_POOL: Pool = Pool::new(num_cpus::get());
 }
 
@@ -93,7 +106,300 @@ pub type PrepareTreeRDataCallback<Tree: 'static + MerkleTreeTrait> =
         end: usize,
     ) -> Result<TreeRElementData<Tree>>;
 
+/// The GPU tree builder could not be created. No data has been touched at that point, so the tree
+/// can still be built on the CPU instead.
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+#[derive(Debug)]
+struct GpuBuilderCreationError(String);
+
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+impl std::fmt::Display for GpuBuilderCreationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed to create GPU tree builder: {}", self.0)
+    }
+}
+
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+impl std::error::Error for GpuBuilderCreationError {}
+
+/// Returns whether `err` was caused by the GPU running out of memory. The GPU backends only
+/// report this through their error messages, so those are matched here.
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+fn is_gpu_out_of_memory(err: &anyhow::Error) -> bool {
+    let msg = format!("{:?}", err).to_lowercase();
+    [
+        "out of memory",
+        "out_of_memory",
+        "out_of_resources",
+        "mem_object_allocation_failure",
+    ]
+    .iter()
+    .any(|pattern| msg.contains(pattern))
+}
+
+/// Returns the error to report for a tree which several GPUs failed to build, out of the `errors`
+/// they failed with. A GPU whose builder could not be created leaves its base trees to the other
+/// GPUs, so a failure while building is preferred, as it is what kept the tree from being built.
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+fn first_gpu_error(mut errors: Vec<anyhow::Error>, tree: &str) -> anyhow::Error {
+    match errors
+        .iter()
+        .position(|err| !err.is::<GpuBuilderCreationError>())
+    {
+        Some(index) => errors.swap_remove(index),
+        None if !errors.is_empty() => errors.swap_remove(0),
+        None => anyhow::format_err!("no GPU built all base trees of {}", tree),
+    }
+}
+
+/// Creates the batcher of a GPU tree builder on `device`. Without a selected device, neptune picks
+/// one, and the tree is built on the CPU if it finds none.
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+fn gpu_batcher<A: PoseidonArity>(
+    device: Option<&'static rust_gpu_tools::Device>,
+    max_batch_size: usize,
+) -> Result<Option<neptune::batch_hasher::Batcher<A>>> {
+    use neptune::batch_hasher::Batcher;
+
+    match device {
+        Some(device) => match Batcher::new(device, max_batch_size) {
+            Ok(batcher) => Ok(Some(batcher)),
+            Err(err) => Err(GpuBuilderCreationError(format!("{:?}", err)).into()),
+        },
+        None => match Batcher::pick_gpu(max_batch_size) {
+            Ok(batcher) => Ok(Some(batcher)),
+            Err(err) => {
+                warn!("no GPU found, falling back to CPU tree builder: {}", err);
+                Ok(None)
+            }
+        },
+    }
+}
+
+/// The memory the steps of sealing a sector need at their peak, see `estimate_seal_resources`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SealResources {
+    /// The layer buffers labeling allocates, see `Settings::labeling_memory`.
+    labeling_memory: u64,
+    /// The column hashes of a base tree, which tree_c holds at once unless it is built in low
+    /// memory mode.
+    tree_c_memory: u64,
+    /// The memory the machine has available right now, `None` if it is unknown.
+    available_memory: Option<u64>,
+}
+
+/// Estimates the memory sealing a sector of `nodes_count` nodes needs, and how much is available.
+fn estimate_seal_resources<Tree: MerkleTreeTrait>(nodes_count: usize) -> SealResources {
+    let base_tree_nodes = nodes_count / get_base_tree_count::<Tree>();
+
+    SealResources {
+        labeling_memory: Settings::labeling_memory((nodes_count * NODE_SIZE) as u64),
+        tree_c_memory: (base_tree_nodes * std::mem::size_of::<<Tree::Hasher as Hasher>::Domain>())
+            as u64,
+        available_memory: available_memory(),
+    }
+}
+
+/// Returns whether tree_c should be built without holding a base tree's column hashes in memory,
+/// either because it was requested or because `estimate_seal_resources` predicts that they would
+/// not fit into the available memory.
+fn use_tree_c_low_memory<Tree: MerkleTreeTrait>(base_tree_nodes: usize) -> bool {
+    if SETTINGS.tree_c_low_memory {
+        return true;
+    }
+
+    let resources =
+        estimate_seal_resources::<Tree>(base_tree_nodes * get_base_tree_count::<Tree>());
+    match resources.available_memory {
+        Some(available) if resources.tree_c_memory > available => {
+            warn!(
+                "column hashes need {} bytes, but only {} bytes are available, building tree_c in low memory mode",
+                resources.tree_c_memory, available
+            );
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The file the kernel reports the memory of the machine in.
+#[cfg(target_os = "linux")]
+const MEMINFO_PATH: &str = "/proc/meminfo";
+
+/// Returns the memory which can be allocated without swapping, if it can be determined. This is
+/// `MemAvailable`, which also counts the page cache the kernel can reclaim, e.g. the parent cache
+/// and the layers, rather than only the memory which is free.
+#[cfg(target_os = "linux")]
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string(MEMINFO_PATH).ok()?;
+    meminfo_bytes(&meminfo, "MemAvailable")
+}
+
+/// Returns the size of the `key` entry of the contents `meminfo` of `/proc/meminfo` in bytes.
+#[cfg(target_os = "linux")]
+fn meminfo_bytes(meminfo: &str, key: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        if parts.next()? != format!("{}:", key) {
+            return None;
+        }
+        let value: u64 = parts.next()?.parse().ok()?;
+
+        match parts.next() {
+            Some("kB") => Some(value * 1024),
+            None => Some(value),
+            Some(_) => None,
+        }
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn available_memory() -> Option<u64> {
+    None
+}
+
+/// Returns the physical memory of the machine, if it can be determined.
+#[cfg(target_os = "linux")]
+fn total_memory() -> Option<u64> {
+    let (pages, page_size) = unsafe {
+        (
+            libc::sysconf(libc::_SC_PHYS_PAGES),
+            libc::sysconf(libc::_SC_PAGESIZE),
+        )
+    };
+    if pages < 0 || page_size < 0 {
+        return None;
+    }
+
+    Some(pages as u64 * page_size as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn total_memory() -> Option<u64> {
+    None
+}
+
+/// Warns if the machine has less memory available than the layer buffers of labeling a sector of
+/// `nodes_count` nodes take, or fails with `enforce_memory_check`, rather than letting it swap or
+/// run out of memory hours into the seal. Decoding and the labeling with a `BufferPool`, whose
+/// buffers are allocated already, are not checked.
+fn check_labeling_memory<Tree: MerkleTreeTrait>(nodes_count: usize) -> Result<()> {
+    let resources = estimate_seal_resources::<Tree>(nodes_count);
+    let available = match resources.available_memory.or_else(total_memory) {
+        Some(available) => available,
+        None => return Ok(()),
+    };
+    if resources.labeling_memory <= available {
+        return Ok(());
+    }
+
+    ensure!(
+        !SETTINGS.enforce_memory_check,
+        "labeling a sector of {} bytes needs {} bytes of memory, but only {} bytes are available",
+        nodes_count * NODE_SIZE,
+        resources.labeling_memory,
+        available
+    );
+    warn!(
+        "labeling a sector of {} bytes needs {} bytes of memory, but only {} bytes are available, \
+         it may swap",
+        nodes_count * NODE_SIZE,
+        resources.labeling_memory,
+        available
+    );
+
+    Ok(())
+}
+
+/// Runs `f` with the parents source used by single core replication: parents are computed on the
+/// fly if `computed_parents_cache_size` is set, otherwise they are read from the parent cache file.
+/// The hits and misses of the source are logged once `f` returns.
+fn with_parents_source<H: Hasher, R>(
+    graph: &StackedBucketGraph<H>,
+    f: impl FnOnce(&mut dyn ParentsSource) -> Result<R>,
+) -> Result<R> {
+    if SETTINGS.computed_parents_cache_size > 0 {
+        info!(
+            "computing parents on the fly (cache size: {})",
+            SETTINGS.computed_parents_cache_size
+        );
+        let mut computed = Computed::new(graph, SETTINGS.computed_parents_cache_size as usize);
+        let res = f(&mut computed);
+        log_parents_cache_stats(&computed);
+        res
+    } else {
+        let mut file_cache = graph.parent_cache()?;
+        let res = f(&mut file_cache);
+        log_parents_cache_stats(&file_cache);
+        res
+    }
+}
+
+fn log_parents_cache_stats(parents: &dyn ParentsSource) {
+    let stats = parents.parents_cache_stats();
+    info!(
+        "parents cache: {} hits, {} misses, {} evictions",
+        stats.hits, stats.misses, stats.evictions
+    );
+}
+
+/// Logs that the labeling runs on multiple cores, which always read the parents from the parent
+/// cache file.
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+fn log_multicore_replication() {
+    info!("multi core replication");
+    if SETTINGS.computed_parents_cache_size > 0 {
+        warn!(
+            "computed_parents_cache_size is ignored, multi core replication reads the parent \
+             cache file"
+        );
+    }
+}
+
+/// Runs the tree building of `f` on a thread pool bound to at least
+/// `multicore_sdr_tree_builder_cores` cores of free core groups, or unbound if the setting is 0 or
+/// no group is free.
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+fn install_tree_builders<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T + Send,
+    T: Send,
+{
+    match SETTINGS.multicore_sdr_tree_builder_cores {
+        0 => f(),
+        cores => install_on_core_groups(usize::MAX, cores, f),
+    }
+}
+
+#[cfg(not(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity")))]
+fn install_tree_builders<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T + Send,
+    T: Send,
+{
+    f()
+}
+
+/// Binds the column hasher of `chunk`, which runs on `THREAD_POOL` rather than on the pool of
+/// `install_tree_builders`, to one of the `cores` of that pool. The binding is reverted once the
+/// returned value is dropped.
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+fn bind_column_hasher(cores: Option<&[CoreIndex]>, chunk: usize) -> Option<Cleanup> {
+    let cores = cores.filter(|cores| !cores.is_empty())?;
+    bind_core(cores[chunk % cores.len()])
+        .map_err(|err| warn!("failed to bind column hasher {}: {:?}", chunk, err))
+        .ok()
+}
+
 impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tree, G> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "prove_layers",
+            skip_all,
+            fields(layers = layers, partitions = partition_count)
+        )
+    )]
     #[allow(clippy::too_many_arguments)]
     pub(crate) fn prove_layers(
         graph: &StackedBucketGraph<Tree::Hasher>,
@@ -111,9 +417,9 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
 
         // Sanity checks on restored trees.
         assert!(pub_inputs.tau.is_some());
-        assert_eq!(
-            pub_inputs.tau.as_ref().expect("as_ref failure").comm_d,
-            t_aux.tree_d.root()
+        ensure!(
+            pub_inputs.tau.as_ref().expect("as_ref failure").comm_d == t_aux.tree_d.root(),
+            "comm_d does not match tree_d"
         );
 
         let get_drg_parents_columns = |x: usize| -> Result<Vec<Column<Tree::Hasher>>> {
@@ -258,7 +564,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
 
                             {
                                 let labeled_node = rcp.c_x.get_node_at_layer(layer)?;
-                                assert!(
+                                ensure!(
                                     proof.verify(&pub_inputs.replica_id, labeled_node),
                                     "Invalid encoding proof generated at layer {}",
                                     layer,
@@ -325,93 +631,237 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
     }
 
     /// Generates the layers as needed for encoding.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "labeling",
+            skip_all,
+            fields(nodes = graph.size(), layers = layer_challenges.layers())
+        )
+    )]
     pub fn generate_labels_for_encoding(
         graph: &StackedBucketGraph<Tree::Hasher>,
         layer_challenges: &LayerChallenges,
         replica_id: &<Tree::Hasher as Hasher>::Domain,
         config: StoreConfig,
     ) -> Result<(Labels<Tree>, Vec<LayerState>)> {
-        let mut parent_cache = graph.parent_cache()?;
+        create_label::select_sha256_implementation()?;
+        check_labeling_memory::<Tree>(graph.size())?;
 
-        #[cfg(feature = "multicore-sdr")]
+        #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
         {
             if SETTINGS.use_multicore_sdr {
-                info!("multi core replication");
-                create_label::multi::create_labels_for_encoding(
+                let parents_cache = graph.parent_cache()?;
+                let sector_size = (graph.size() * NODE_SIZE) as u64;
+                match checkout_core_group_for(sector_size) {
+                    Some(core_group) => {
+                        log_multicore_replication();
+                        return create_label::multi::create_labels_for_encoding_on_core_group(
+                            graph,
+                            &parents_cache,
+                            layer_challenges.layers(),
+                            replica_id,
+                            config,
+                            Some(core_group),
+                        );
+                    }
+                    // The labeling threads would compete with the ones of the labelings which
+                    // hold the groups, which is often slower than labeling on a single core.
+                    // Without any groups, there is nothing to compete with.
+                    None if SETTINGS.multicore_sdr_single_core_fallback
+                        && has_core_groups_for(sector_size) =>
+                    {
+                        info!("no core group is free, falling back to single core replication");
+                    }
+                    // The checkout failed already, so the labeling runs unbound rather than
+                    // checking out again.
+                    None => {
+                        log_multicore_replication();
+                        return create_label::multi::create_labels_for_encoding_on_core_group(
+                            graph,
+                            &parents_cache,
+                            layer_challenges.layers(),
+                            replica_id,
+                            config,
+                            None,
+                        );
+                    }
+                }
+            }
+        }
+
+        info!("single core replication");
+        with_parents_source(graph, |parents| {
+            create_label::single::create_labels_for_encoding(
+                graph,
+                parents,
+                layer_challenges.layers(),
+                replica_id,
+                config,
+            )
+        })
+    }
+
+    /// Like `generate_labels_for_encoding`, but binds the labeling threads to `cores` instead of a
+    /// group of `CORE_GROUPS`. The multi core labeling is used even if `use_multicore_sdr` is not
+    /// set.
+    #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+    pub fn generate_labels_for_encoding_on_cores(
+        graph: &StackedBucketGraph<Tree::Hasher>,
+        layer_challenges: &LayerChallenges,
+        replica_id: &<Tree::Hasher as Hasher>::Domain,
+        config: StoreConfig,
+        cores: Vec<CoreIndex>,
+    ) -> Result<(Labels<Tree>, Vec<LayerState>)> {
+        create_label::select_sha256_implementation()?;
+        check_labeling_memory::<Tree>(graph.size())?;
+
+        let core_group = core_group_from_subset(cores)?;
+        info!("multi core replication on cores {:?}", &*core_group);
+        create_label::multi::create_labels_for_encoding_on_core_group(
+            graph,
+            &graph.parent_cache()?,
+            layer_challenges.layers(),
+            replica_id,
+            config,
+            Some(core_group),
+        )
+    }
+
+    /// Like `generate_labels_for_encoding`, but labels with the layer buffers of `buffer_pool`
+    /// instead of allocating them. The multi core labeling is used even if `use_multicore_sdr` is
+    /// not set, as only it labels in layer buffers.
+    #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+    pub fn generate_labels_for_encoding_with_buffer_pool(
+        graph: &StackedBucketGraph<Tree::Hasher>,
+        layer_challenges: &LayerChallenges,
+        replica_id: &<Tree::Hasher as Hasher>::Domain,
+        config: StoreConfig,
+        buffer_pool: &BufferPool,
+    ) -> Result<(Labels<Tree>, Vec<LayerState>)> {
+        create_label::select_sha256_implementation()?;
+
+        info!("multi core replication with buffer pool");
+        create_label::multi::create_labels_for_encoding_with_buffer_pool(
+            graph,
+            &graph.parent_cache()?,
+            layer_challenges.layers(),
+            replica_id,
+            config,
+            buffer_pool,
+        )
+    }
+
+    /// Like `generate_labels_for_encoding`, but waits up to `timeout` for a group of
+    /// `CORE_GROUPS` to be released if none is free, ahead of the waiters with a lower `priority`,
+    /// see `checkout_core_group_blocking`. If none is released in time, the labeling runs unbound,
+    /// or on a single core with `multicore_sdr_single_core_fallback`. The multi core labeling is
+    /// used even if `use_multicore_sdr` is not set.
+    #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+    pub fn generate_labels_for_encoding_with_priority(
+        graph: &StackedBucketGraph<Tree::Hasher>,
+        layer_challenges: &LayerChallenges,
+        replica_id: &<Tree::Hasher as Hasher>::Domain,
+        config: StoreConfig,
+        priority: u8,
+        timeout: Duration,
+    ) -> Result<(Labels<Tree>, Vec<LayerState>)> {
+        create_label::select_sha256_implementation()?;
+        check_labeling_memory::<Tree>(graph.size())?;
+
+        let parents_cache = graph.parent_cache()?;
+        let sector_size = (graph.size() * NODE_SIZE) as u64;
+        match checkout_core_group_blocking(sector_size, priority, timeout) {
+            Some(core_group) => {
+                info!(
+                    "multi core replication with priority {} on cores {:?}",
+                    priority, &*core_group
+                );
+                create_label::multi::create_labels_for_encoding_on_core_group(
                     graph,
-                    &parent_cache,
+                    &parents_cache,
                     layer_challenges.layers(),
                     replica_id,
                     config,
+                    Some(core_group),
                 )
-            } else {
-                info!("single core replication");
-                create_label::single::create_labels_for_encoding(
+            }
+            None if SETTINGS.multicore_sdr_single_core_fallback
+                && has_core_groups_for(sector_size) =>
+            {
+                warn!(
+                    "no core group released within {:?}, labeling with priority {} on a single core",
+                    timeout, priority
+                );
+                drop(parents_cache);
+                with_parents_source(graph, |parents| {
+                    create_label::single::create_labels_for_encoding(
+                        graph,
+                        parents,
+                        layer_challenges.layers(),
+                        replica_id,
+                        config,
+                    )
+                })
+            }
+            None => {
+                warn!(
+                    "no core group released within {:?}, labeling with priority {} unbound",
+                    timeout, priority
+                );
+                create_label::multi::create_labels_for_encoding_on_core_group(
                     graph,
-                    &mut parent_cache,
+                    &parents_cache,
                     layer_challenges.layers(),
                     replica_id,
                     config,
+                    None,
                 )
             }
         }
-
-        #[cfg(not(feature = "multicore-sdr"))]
-        {
-            info!("single core replication");
-            create_label::single::create_labels_for_encoding(
-                graph,
-                &mut parent_cache,
-                layer_challenges.layers(),
-                replica_id,
-                config,
-            )
-        }
     }
 
     /// Generates the layers, as needed for decoding.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "labeling",
+            skip_all,
+            fields(nodes = graph.size(), layers = layer_challenges.layers())
+        )
+    )]
     pub fn generate_labels_for_decoding(
         graph: &StackedBucketGraph<Tree::Hasher>,
         layer_challenges: &LayerChallenges,
         replica_id: &<Tree::Hasher as Hasher>::Domain,
         config: StoreConfig,
     ) -> Result<LabelsCache<Tree>> {
-        let mut parent_cache = graph.parent_cache()?;
+        create_label::select_sha256_implementation()?;
 
-        #[cfg(feature = "multicore-sdr")]
+        #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
         {
             if SETTINGS.use_multicore_sdr {
-                info!("multi core replication");
-                create_label::multi::create_labels_for_decoding(
-                    graph,
-                    &parent_cache,
-                    layer_challenges.layers(),
-                    replica_id,
-                    config,
-                )
-            } else {
-                info!("single core replication");
-                create_label::single::create_labels_for_decoding(
+                log_multicore_replication();
+                return create_label::multi::create_labels_for_decoding(
                     graph,
-                    &mut parent_cache,
+                    &graph.parent_cache()?,
                     layer_challenges.layers(),
                     replica_id,
                     config,
-                )
+                );
             }
         }
 
-        #[cfg(not(feature = "multicore-sdr"))]
-        {
-            info!("single core replication");
+        info!("single core replication");
+        with_parents_source(graph, |parents| {
             create_label::single::create_labels_for_decoding(
                 graph,
-                &mut parent_cache,
+                parents,
                 layer_challenges.layers(),
                 replica_id,
                 config,
             )
-        }
+        })
     }
 
     // NOTE: Unlike
@@ -452,25 +902,70 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
     }
 
     #[cfg(any(feature = "cuda", feature = "opencl"))]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "tree_c",
+            skip_all,
+            fields(nodes = nodes_count, trees = tree_count)
+        )
+    )]
     fn generate_tree_c<ColumnArity, TreeArity>(
         layers: usize,
         nodes_count: usize,
         tree_count: usize,
         configs: Vec<StoreConfig>,
         labels: &LabelsCache<Tree>,
-    ) -> Result<DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
+        resume: Option<&TreeResume>,
+        gpu: &GpuOptions,
+    ) -> Result<DiskTree<Tree::Hasher, Tree::TreeCArity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         ColumnArity: 'static + PoseidonArity,
         TreeArity: PoseidonArity,
     {
         if Self::use_gpu_column_builder() {
-            Self::generate_tree_c_gpu::<ColumnArity, TreeArity>(
-                layers,
-                nodes_count,
-                tree_count,
-                configs,
-                labels,
-            )
+            let devices = gpu.selection.devices()?;
+            let built = if devices.len() > 1 {
+                Self::generate_tree_c_multi_gpu::<ColumnArity, TreeArity>(
+                    layers,
+                    nodes_count,
+                    tree_count,
+                    configs.clone(),
+                    labels,
+                    resume,
+                    &devices,
+                    gpu.batch_sizes,
+                )
+            } else {
+                Self::generate_tree_c_gpu::<ColumnArity, TreeArity>(
+                    layers,
+                    nodes_count,
+                    tree_count,
+                    configs.clone(),
+                    labels,
+                    resume,
+                    gpu.selection.device()?,
+                    gpu.batch_sizes,
+                )
+            };
+            // Building tree_c only reads the labels, so it can always be retried on the CPU.
+            match built {
+                Err(err) if SETTINGS.gpu_oom_fallback_cpu && is_gpu_out_of_memory(&err) => {
+                    warn!(
+                        "GPU ran out of memory building tree_c, falling back to CPU: {:?}",
+                        err
+                    );
+                    Self::generate_tree_c_cpu::<ColumnArity, TreeArity>(
+                        layers,
+                        nodes_count,
+                        tree_count,
+                        configs,
+                        labels,
+                        resume,
+                    )
+                }
+                res => res,
+            }
         } else {
             Self::generate_tree_c_cpu::<ColumnArity, TreeArity>(
                 layers,
@@ -478,18 +973,29 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 tree_count,
                 configs,
                 labels,
+                resume,
             )
         }
     }
 
     #[cfg(not(any(feature = "cuda", feature = "opencl")))]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "tree_c",
+            skip_all,
+            fields(nodes = nodes_count, trees = tree_count)
+        )
+    )]
     fn generate_tree_c<ColumnArity, TreeArity>(
         layers: usize,
         nodes_count: usize,
         tree_count: usize,
         configs: Vec<StoreConfig>,
         labels: &LabelsCache<Tree>,
-    ) -> Result<DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
+        resume: Option<&TreeResume>,
+        _gpu: &GpuOptions,
+    ) -> Result<DiskTree<Tree::Hasher, Tree::TreeCArity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         ColumnArity: 'static + PoseidonArity,
         TreeArity: PoseidonArity,
@@ -500,10 +1006,66 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             tree_count,
             configs,
             labels,
+            resume,
         )
     }
 
-    #[allow(clippy::needless_range_loop)]
+    /// Builds tree_c with the column arity matching `layers`, and returns its root. With `resume`,
+    /// the base trees recorded as complete are reused.
+    #[allow(clippy::too_many_arguments)]
+    fn generate_tree_c_root(
+        layers: usize,
+        nodes_count: usize,
+        tree_count: usize,
+        configs: Vec<StoreConfig>,
+        labels: &LabelsCache<Tree>,
+        resume: Option<&TreeResume>,
+        gpu: &GpuOptions,
+    ) -> Result<<Tree::Hasher as Hasher>::Domain> {
+        let tree_c_root = match layers {
+            2 => {
+                let tree_c = Self::generate_tree_c::<U2, Tree::TreeCArity>(
+                    layers,
+                    nodes_count,
+                    tree_count,
+                    configs,
+                    labels,
+                    resume,
+                    gpu,
+                )?;
+                tree_c.root()
+            }
+            8 => {
+                let tree_c = Self::generate_tree_c::<U8, Tree::TreeCArity>(
+                    layers,
+                    nodes_count,
+                    tree_count,
+                    configs,
+                    labels,
+                    resume,
+                    gpu,
+                )?;
+                tree_c.root()
+            }
+            11 => {
+                let tree_c = Self::generate_tree_c::<U11, Tree::TreeCArity>(
+                    layers,
+                    nodes_count,
+                    tree_count,
+                    configs,
+                    labels,
+                    resume,
+                    gpu,
+                )?;
+                tree_c.root()
+            }
+            _ => panic_any("Unsupported column arity"),
+        };
+
+        Ok(tree_c_root)
+    }
+
+    #[allow(clippy::needless_range_loop, clippy::too_many_arguments)]
     #[cfg(any(feature = "cuda", feature = "opencl"))]
     fn generate_tree_c_gpu<ColumnArity, TreeArity>(
         layers: usize,
@@ -511,21 +1073,20 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         tree_count: usize,
         configs: Vec<StoreConfig>,
         labels: &LabelsCache<Tree>,
-    ) -> Result<DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
+        resume: Option<&TreeResume>,
+        device: Option<&'static rust_gpu_tools::Device>,
+        batch_sizes: gpu::GpuBatchSizes,
+    ) -> Result<DiskTree<Tree::Hasher, Tree::TreeCArity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         ColumnArity: 'static + PoseidonArity,
         TreeArity: PoseidonArity,
     {
         use std::cmp::min;
         use std::sync::mpsc::sync_channel as channel;
-        use std::sync::{Arc, RwLock};
 
-        use fr32::fr_into_bytes;
+        use anyhow::format_err;
         use generic_array::GenericArray;
-        use neptune::{
-            batch_hasher::Batcher,
-            column_tree_builder::{ColumnTreeBuilder, ColumnTreeBuilderTrait},
-        };
+        use neptune::column_tree_builder::{ColumnTreeBuilder, ColumnTreeBuilderTrait};
 
         info!("generating tree c using the GPU");
         // Build the tree for CommC
@@ -540,21 +1101,50 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             //
             // Override these values with care using environment variables:
             // FIL_PROOFS_MAX_GPU_COLUMN_BATCH_SIZE, FIL_PROOFS_MAX_GPU_TREE_BATCH_SIZE, and
-            // FIL_PROOFS_COLUMN_WRITE_BATCH_SIZE respectively.
-            let max_gpu_column_batch_size = SETTINGS.max_gpu_column_batch_size as usize;
-            let max_gpu_tree_batch_size = SETTINGS.max_gpu_tree_batch_size as usize;
+            // FIL_PROOFS_COLUMN_WRITE_BATCH_SIZE respectively. The batch sizes are derived from
+            // the memory of the device with FIL_PROOFS_AUTO_GPU_BATCH_SIZES instead, and
+            // `batch_sizes` overrides them for this tree.
+            let (max_gpu_column_batch_size, max_gpu_tree_batch_size) = batch_sizes.tree_c(
+                device,
+                ColumnArity::to_usize(),
+                TreeArity::to_usize(),
+                nodes_count,
+            );
             let column_write_batch_size = SETTINGS.column_write_batch_size as usize;
 
+            // The base trees which are built, the others are reused.
+            let pending: Vec<usize> = (0..configs.len())
+                .filter(|i| !is_reusable(resume, &configs[*i]))
+                .collect();
+            if pending.is_empty() {
+                info!("reusing all base trees of tree_c");
+                return create_disk_tree::<
+                    DiskTree<
+                        Tree::Hasher,
+                        Tree::TreeCArity,
+                        Tree::SubTreeArity,
+                        Tree::TopTreeArity,
+                    >,
+                >(configs[0].size.expect("config size failure"), &configs);
+            }
+            if resume.is_some() {
+                for i in &pending {
+                    remove_progress(&configs[*i]);
+                }
+            }
+            let pending = &pending; // Don't move pending into closures below.
+
             // This channel will receive batches of columns and add them to the ColumnTreeBuilder.
             let (builder_tx, builder_rx) = channel(0);
 
-            let config_count = configs.len(); // Don't move config into closure below.
+            let mut result = Ok(());
             THREAD_POOL.scoped(|s| {
-                // This channel will receive the finished tree data to be written to disk.
-                let (writer_tx, writer_rx) = channel::<(Vec<Fr>, Vec<Fr>)>(0);
+                // This channel will receive the finished tree data to be written to disk, or the
+                // error the GPU failed with.
+                let (writer_tx, writer_rx) = channel::<Result<(Vec<Fr>, Vec<Fr>)>>(0);
 
                 s.execute(move || {
-                    for i in 0..config_count {
+                    for &i in pending {
                         let mut node_index = 0;
                         let builder_tx = builder_tx.clone();
                         while node_index != nodes_count {
@@ -567,45 +1157,12 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                                 chunked_nodes_count,
                             );
 
-                            let columns: Vec<GenericArray<Fr, ColumnArity>> = {
-                                use fr32::bytes_into_fr;
-
-                                // Allocate layer data array and insert a placeholder for each layer.
-                                let mut layer_data: Vec<Vec<u8>> =
-                                    vec![
-                                        vec![0u8; chunked_nodes_count * std::mem::size_of::<Fr>()];
-                                        layers
-                                    ];
-
-                                // gather all layer data.
-                                for (layer_index, mut layer_bytes) in
-                                    layer_data.iter_mut().enumerate()
-                                {
-                                    let store = labels.labels_for_layer(layer_index + 1);
-                                    let start = (i * nodes_count) + node_index;
-                                    let end = start + chunked_nodes_count;
-
-                                    store
-                                        .read_range_into(start, end, &mut layer_bytes)
-                                        .expect("failed to read store range");
-                                }
-
-                                (0..chunked_nodes_count)
-                                    .into_par_iter()
-                                    .map(|index| {
-                                        (0..layers)
-                                            .map(|layer_index| {
-                                                bytes_into_fr(
-                                                &layer_data[layer_index][std::mem::size_of::<Fr>()
-                                                    * index
-                                                    ..std::mem::size_of::<Fr>() * (index + 1)],
-                                            )
-                                            .expect("Could not create Fr from bytes.")
-                                            })
-                                            .collect::<GenericArray<Fr, ColumnArity>>()
-                                    })
-                                    .collect()
-                            };
+                            let columns = Self::gather_tree_c_columns::<ColumnArity>(
+                                labels,
+                                layers,
+                                (i * nodes_count) + node_index,
+                                chunked_nodes_count,
+                            );
 
                             node_index += chunked_nodes_count;
                             trace!(
@@ -616,53 +1173,74 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                             );
 
                             let is_final = node_index == nodes_count;
-                            builder_tx
-                                .send((columns, is_final))
-                                .expect("failed to send columns");
+                            if builder_tx.send((columns, is_final)).is_err() {
+                                // The builder failed and stopped receiving, it reports the error.
+                                return;
+                            }
                         }
                     }
                 });
                 s.execute(move || {
-                    let _gpu_lock = GPU_LOCK.lock().expect("failed to get gpu lock");
-                    let tree_batcher = match Batcher::pick_gpu(max_gpu_tree_batch_size) {
-                        Ok(b) => Some(b),
-                        Err(err) => {
-                            warn!("no GPU found, falling back to CPU tree builder: {}", err);
-                            None
-                        }
-                    };
-                    let column_batcher = match Batcher::pick_gpu(max_gpu_column_batch_size) {
-                        Ok(b) => Some(b),
+                    let gpu_lock = gpu::device_lock(device);
+                    let _gpu_lock = gpu_lock.lock().expect("failed to get gpu lock");
+                    let batchers =
+                        gpu_batcher(device, max_gpu_tree_batch_size).and_then(|tree_batcher| {
+                            gpu_batcher(device, max_gpu_column_batch_size)
+                                .map(|column_batcher| (tree_batcher, column_batcher))
+                        });
+                    let (tree_batcher, column_batcher) = match batchers {
+                        Ok(batchers) => batchers,
                         Err(err) => {
-                            warn!("no GPU found, falling back to CPU tree builder: {}", err);
-                            None
+                            let _ = writer_tx.send(Err(err));
+                            return;
                         }
                     };
-                    let mut column_tree_builder = ColumnTreeBuilder::<ColumnArity, TreeArity>::new(
-                        column_batcher,
-                        tree_batcher,
-                        nodes_count,
-                    )
-                    .expect("failed to create ColumnTreeBuilder");
+                    let built_on = gpu::builder_gpu(device, tree_batcher.is_some());
+                    let mut column_tree_builder =
+                        match ColumnTreeBuilder::<ColumnArity, TreeArity>::new(
+                            column_batcher,
+                            tree_batcher,
+                            nodes_count,
+                        ) {
+                            Ok(builder) => builder,
+                            Err(err) => {
+                                let _ = writer_tx.send(Err(GpuBuilderCreationError(format!(
+                                    "{:?}",
+                                    err
+                                ))
+                                .into()));
+                                return;
+                            }
+                        };
 
                     // Loop until all trees for all configs have been built.
-                    for i in 0..config_count {
+                    for &i in pending {
                         loop {
                             let (columns, is_final): (Vec<GenericArray<Fr, ColumnArity>>, bool) =
                                 builder_rx.recv().expect("failed to recv columns");
 
                             // Just add non-final column batches.
                             if !is_final {
-                                column_tree_builder
-                                    .add_columns(&columns)
-                                    .expect("failed to add columns");
+                                if let Err(err) = column_tree_builder.add_columns(&columns) {
+                                    let _ = writer_tx
+                                        .send(Err(format_err!("failed to add columns: {:?}", err)));
+                                    return;
+                                }
                                 continue;
                             };
 
                             // If we get here, this is a final column: build a sub-tree.
-                            let (base_data, tree_data) = column_tree_builder
-                                .add_final_columns(&columns)
-                                .expect("failed to add final columns");
+                            let (base_data, tree_data) =
+                                match column_tree_builder.add_final_columns(&columns) {
+                                    Ok(data) => data,
+                                    Err(err) => {
+                                        let _ = writer_tx.send(Err(format_err!(
+                                            "failed to add final columns: {:?}",
+                                            err
+                                        )));
+                                        return;
+                                    }
+                                };
                             trace!(
                                 "base data len {}, tree data len {}",
                                 base_data.len(),
@@ -677,116 +1255,491 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                                 tree_len,
                             );
 
+                            gpu::count_base_tree(built_on.as_deref());
                             writer_tx
-                                .send((base_data, tree_data))
+                                .send(Ok((base_data, tree_data)))
                                 .expect("failed to send base_data, tree_data");
                             break;
                         }
                     }
                 });
 
-                for config in &configs {
-                    let (base_data, tree_data) = writer_rx
+                for config in pending.iter().map(|i| &configs[*i]) {
+                    let (base_data, tree_data) = match writer_rx
                         .recv()
-                        .expect("failed to receive base_data, tree_data for tree_c");
-                    let tree_len = base_data.len() + tree_data.len();
-
-                    assert_eq!(base_data.len(), nodes_count);
-                    assert_eq!(tree_len, config.size.expect("config size failure"));
-
-                    // Persist the base and tree data to disk based using the current store config.
-                    let tree_c_store_path = StoreConfig::data_path(&config.path, &config.id);
-                    let tree_c_store_exists = Path::new(&tree_c_store_path).exists();
-                    trace!(
-                        "tree_c store path {:?} -- exists? {}",
-                        tree_c_store_path,
-                        tree_c_store_exists
+                        .expect("failed to receive base_data, tree_data for tree_c")
+                    {
+                        Ok(data) => data,
+                        Err(err) => {
+                            result = Err(err);
+                            break;
+                        }
+                    };
+                    Self::write_tree_c_store(
+                        config,
+                        &base_data,
+                        &tree_data,
+                        nodes_count,
+                        column_write_batch_size,
                     );
-                    if tree_c_store_exists {
-                        std::fs::remove_file(&tree_c_store_path)
-                            .expect("failed to remove tree_c_store_path");
+
+                    if let Some(resume) = resume {
+                        if let Err(err) = mark_complete(config, &resume.inputs) {
+                            result = Err(err);
+                            break;
+                        }
                     }
+                }
+            });
+            result?;
 
-                    let tree_c_store =
-                        DiskStore::<<Tree::Hasher as Hasher>::Domain>::new_with_config(
-                            tree_len,
-                            Tree::Arity::to_usize(),
-                            config.clone(),
-                        )
-                        .expect("failed to create DiskStore for base tree data");
-
-                    let store = Arc::new(RwLock::new(tree_c_store));
-                    let batch_size = min(base_data.len(), column_write_batch_size);
-                    let flatten_and_write_store = |data: &Vec<Fr>, offset| {
-                        data.into_par_iter()
-                            .chunks(batch_size)
-                            .enumerate()
-                            .try_for_each(|(index, fr_elements)| {
-                                let mut buf = Vec::with_capacity(batch_size * NODE_SIZE);
-
-                                for fr in fr_elements {
-                                    buf.extend(fr_into_bytes(fr));
-                                }
-                                store
-                                    .write()
-                                    .expect("failed to access store for write")
-                                    .copy_from_slice(&buf[..], offset + (batch_size * index))
-                            })
-                    };
+            create_disk_tree::<
+                DiskTree<Tree::Hasher, Tree::TreeCArity, Tree::SubTreeArity, Tree::TopTreeArity>,
+            >(configs[0].size.expect("config size failure"), &configs)
+        })
+    }
 
-                    trace!(
-                        "flattening tree_c base data of {} nodes using batch size {}",
-                        base_data.len(),
-                        batch_size
+    /// Like `generate_tree_c_gpu`, but shares the base trees between `devices`, see
+    /// `ShardQueue`.
+    ///
+    /// Every device has a thread which gathers the columns of the base trees it claims, and one
+    /// which builds them, with up to `gpu_queue_depth` batches of columns queued in between. The
+    /// base trees are written here, while the devices build the next ones. A device which fails
+    /// returns its claims for the others to build.
+    #[allow(clippy::too_many_arguments)]
+    #[cfg(any(feature = "cuda", feature = "opencl"))]
+    fn generate_tree_c_multi_gpu<ColumnArity, TreeArity>(
+        layers: usize,
+        nodes_count: usize,
+        tree_count: usize,
+        configs: Vec<StoreConfig>,
+        labels: &LabelsCache<Tree>,
+        resume: Option<&TreeResume>,
+        devices: &[&'static rust_gpu_tools::Device],
+        batch_sizes: gpu::GpuBatchSizes,
+    ) -> Result<DiskTree<Tree::Hasher, Tree::TreeCArity, Tree::SubTreeArity, Tree::TopTreeArity>>
+    where
+        ColumnArity: 'static + PoseidonArity,
+        TreeArity: PoseidonArity,
+    {
+        use std::cmp::min;
+        use std::sync::mpsc::sync_channel as channel;
+
+        use anyhow::format_err;
+        use generic_array::GenericArray;
+        use neptune::column_tree_builder::{ColumnTreeBuilder, ColumnTreeBuilderTrait};
+
+        info!("generating tree c using {} GPUs", devices.len());
+        measure_op(Operation::GenerateTreeC, || {
+            let column_write_batch_size = SETTINGS.column_write_batch_size as usize;
+            let queue_depth = SETTINGS.gpu_queue_depth as usize;
+
+            let pending: Vec<usize> = (0..configs.len())
+                .filter(|i| !is_reusable(resume, &configs[*i]))
+                .collect();
+            if pending.is_empty() {
+                info!("reusing all base trees of tree_c");
+                return create_disk_tree::<
+                    DiskTree<
+                        Tree::Hasher,
+                        Tree::TreeCArity,
+                        Tree::SubTreeArity,
+                        Tree::TopTreeArity,
+                    >,
+                >(configs[0].size.expect("config size failure"), &configs);
+            }
+            if resume.is_some() {
+                for i in &pending {
+                    remove_progress(&configs[*i]);
+                }
+            }
+            let queue = gpu::ShardQueue::new(pending, devices.len());
+            let queue = &queue; // Don't move the queue into closures below.
+
+            let mut errors = Vec::new();
+            let mut built = 0;
+            THREAD_POOL.scoped(|s| {
+                // This channel will receive the finished base trees to be written to disk, or the
+                // errors the devices failed with.
+                let (writer_tx, writer_rx) =
+                    channel::<Result<(usize, Vec<Fr>, Vec<Fr>)>>(devices.len());
+
+                for (d, &device) in devices.iter().enumerate() {
+                    let (max_gpu_column_batch_size, max_gpu_tree_batch_size) = batch_sizes.tree_c(
+                        Some(device),
+                        ColumnArity::to_usize(),
+                        TreeArity::to_usize(),
+                        nodes_count,
                     );
-                    flatten_and_write_store(&base_data, 0)
-                        .expect("failed to flatten and write store");
-                    trace!("done flattening tree_c base data");
+                    let (builder_tx, builder_rx) =
+                        channel::<(usize, Vec<GenericArray<Fr, ColumnArity>>, bool)>(queue_depth);
+                    // This channel signals whether the builder was created, before any base tree
+                    // is claimed.
+                    let (ready_tx, ready_rx) = channel::<bool>(1);
+                    let writer_tx = writer_tx.clone();
+
+                    // The builder is started first, so that it is not left waiting for a thread
+                    // while its producer waits for it.
+                    s.execute(move || {
+                        let gpu_lock = gpu::device_lock(Some(device));
+                        let _gpu_lock = gpu_lock.lock().expect("failed to get gpu lock");
+                        let builder = gpu_batcher(Some(device), max_gpu_tree_batch_size)
+                            .and_then(|tree_batcher| {
+                                gpu_batcher(Some(device), max_gpu_column_batch_size)
+                                    .map(|column_batcher| (tree_batcher, column_batcher))
+                            })
+                            .and_then(|(tree_batcher, column_batcher)| {
+                                ColumnTreeBuilder::<ColumnArity, TreeArity>::new(
+                                    column_batcher,
+                                    tree_batcher,
+                                    nodes_count,
+                                )
+                                .map_err(|err| GpuBuilderCreationError(format!("{:?}", err)).into())
+                            });
+                        let mut column_tree_builder = match builder {
+                            Ok(builder) => builder,
+                            Err(err) => {
+                                queue.fail(d);
+                                let _ = ready_tx.send(false);
+                                let _ = writer_tx.send(Err(err));
+                                return;
+                            }
+                        };
+                        let _ = ready_tx.send(true);
 
-                    let base_offset = base_data.len();
-                    trace!("flattening tree_c tree data of {} nodes using batch size {} and base offset {}", tree_data.len(), batch_size, base_offset);
-                    flatten_and_write_store(&tree_data, base_offset)
-                        .expect("failed to flatten and write store");
-                    trace!("done flattening tree_c tree data");
+                        // The producer closes the channel once no base tree is left to claim. If
+                        // building fails, the claims of the device are returned to the queue.
+                        for (i, columns, is_final) in builder_rx.iter() {
+                            if !is_final {
+                                if let Err(err) = column_tree_builder.add_columns(&columns) {
+                                    queue.fail(d);
+                                    let _ = writer_tx
+                                        .send(Err(format_err!("failed to add columns: {:?}", err)));
+                                    return;
+                                }
+                                continue;
+                            }
 
-                    trace!("writing tree_c store data");
-                    store
-                        .write()
-                        .expect("failed to access store for sync")
-                        .sync()
-                        .expect("store sync failure");
-                    trace!("done writing tree_c store data");
+                            match column_tree_builder.add_final_columns(&columns) {
+                                Ok((base_data, tree_data)) => {
+                                    if !queue.complete(d, i) {
+                                        continue;
+                                    }
+                                    gpu::count_base_tree(Some(&device.name()));
+                                    info!(
+                                        "persisting base tree_c {}/{} built on {}",
+                                        i + 1,
+                                        tree_count,
+                                        device.name()
+                                    );
+                                    if writer_tx.send(Ok((i, base_data, tree_data))).is_err() {
+                                        return;
+                                    }
+                                }
+                                Err(err) => {
+                                    queue.fail(d);
+                                    let _ = writer_tx.send(Err(format_err!(
+                                        "failed to add final columns: {:?}",
+                                        err
+                                    )));
+                                    return;
+                                }
+                            }
+                        }
+                    });
+                    s.execute(move || {
+                        if !ready_rx.recv().unwrap_or(false) {
+                            return;
+                        }
+
+                        while let Some(i) = queue.claim(d) {
+                            let mut node_index = 0;
+                            while node_index != nodes_count {
+                                let chunked_nodes_count =
+                                    min(nodes_count - node_index, max_gpu_column_batch_size);
+                                let columns = Self::gather_tree_c_columns::<ColumnArity>(
+                                    labels,
+                                    layers,
+                                    (i * nodes_count) + node_index,
+                                    chunked_nodes_count,
+                                );
+                                node_index += chunked_nodes_count;
+
+                                let is_final = node_index == nodes_count;
+                                if builder_tx.send((i, columns, is_final)).is_err() {
+                                    // The builder failed and stopped receiving, it reports the
+                                    // error.
+                                    queue.fail(d);
+                                    return;
+                                }
+                            }
+                        }
+                    });
+                }
+                drop(writer_tx);
+
+                for message in writer_rx.iter() {
+                    let (i, base_data, tree_data) = match message {
+                        Ok(data) => data,
+                        Err(err) => {
+                            warn!("a GPU failed building tree_c: {:?}", err);
+                            errors.push(err);
+                            continue;
+                        }
+                    };
+
+                    let config = &configs[i];
+                    Self::write_tree_c_store(
+                        config,
+                        &base_data,
+                        &tree_data,
+                        nodes_count,
+                        column_write_batch_size,
+                    );
+                    if let Some(resume) = resume {
+                        if let Err(err) = mark_complete(config, &resume.inputs) {
+                            errors.push(err);
+                            continue;
+                        }
+                    }
+                    built += 1;
                 }
             });
+            if built < queue.len() {
+                return Err(first_gpu_error(errors, "tree_c"));
+            }
+            queue.log_shares("tree_c", devices);
 
             create_disk_tree::<
-                DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>,
+                DiskTree<Tree::Hasher, Tree::TreeCArity, Tree::SubTreeArity, Tree::TopTreeArity>,
             >(configs[0].size.expect("config size failure"), &configs)
         })
     }
 
+    /// Returns the columns of the `count` nodes from `start` on, of all `layers` of `labels`.
+    #[cfg(any(feature = "cuda", feature = "opencl"))]
+    fn gather_tree_c_columns<ColumnArity: PoseidonArity>(
+        labels: &LabelsCache<Tree>,
+        layers: usize,
+        start: usize,
+        count: usize,
+    ) -> Vec<generic_array::GenericArray<Fr, ColumnArity>> {
+        use fr32::bytes_into_fr;
+        use generic_array::GenericArray;
+
+        // Allocate layer data array and insert a placeholder for each layer.
+        let mut layer_data: Vec<Vec<u8>> =
+            vec![vec![0u8; count * std::mem::size_of::<Fr>()]; layers];
+
+        // gather all layer data.
+        for (layer_index, mut layer_bytes) in layer_data.iter_mut().enumerate() {
+            labels
+                .read_range_into(layer_index + 1, start, start + count, &mut layer_bytes)
+                .expect("failed to read store range");
+        }
+
+        (0..count)
+            .into_par_iter()
+            .map(|index| {
+                (0..layers)
+                    .map(|layer_index| {
+                        bytes_into_fr(
+                            &layer_data[layer_index][std::mem::size_of::<Fr>() * index
+                                ..std::mem::size_of::<Fr>() * (index + 1)],
+                        )
+                        .expect("Could not create Fr from bytes.")
+                    })
+                    .collect::<GenericArray<Fr, ColumnArity>>()
+            })
+            .collect()
+    }
+
+    /// Persists the base tree of tree_c with `base_data` and `tree_data` to disk as `config`.
+    #[cfg(any(feature = "cuda", feature = "opencl"))]
+    fn write_tree_c_store(
+        config: &StoreConfig,
+        base_data: &[Fr],
+        tree_data: &[Fr],
+        nodes_count: usize,
+        column_write_batch_size: usize,
+    ) {
+        use std::cmp::min;
+        use std::sync::{Arc, RwLock};
+
+        use fr32::fr_into_bytes;
+
+        let tree_len = base_data.len() + tree_data.len();
+
+        assert_eq!(base_data.len(), nodes_count);
+        assert_eq!(tree_len, config.size.expect("config size failure"));
+
+        // Persist the base and tree data to disk based using the current store config.
+        let tree_c_store_path = StoreConfig::data_path(&config.path, &config.id);
+        let tree_c_store_exists = Path::new(&tree_c_store_path).exists();
+        trace!(
+            "tree_c store path {:?} -- exists? {}",
+            tree_c_store_path,
+            tree_c_store_exists
+        );
+        if tree_c_store_exists {
+            std::fs::remove_file(&tree_c_store_path).expect("failed to remove tree_c_store_path");
+        }
+
+        let tree_c_store = DiskStore::<<Tree::Hasher as Hasher>::Domain>::new_with_config(
+            tree_len,
+            Tree::TreeCArity::to_usize(),
+            config.clone(),
+        )
+        .expect("failed to create DiskStore for base tree data");
+
+        let store = Arc::new(RwLock::new(tree_c_store));
+        let batch_size = min(base_data.len(), column_write_batch_size);
+        let flatten_and_write_store = |data: &[Fr], offset| {
+            data.into_par_iter()
+                .chunks(batch_size)
+                .enumerate()
+                .try_for_each(|(index, fr_elements)| {
+                    let mut buf = Vec::with_capacity(batch_size * NODE_SIZE);
+
+                    for fr in fr_elements {
+                        buf.extend(fr_into_bytes(fr));
+                    }
+                    store
+                        .write()
+                        .expect("failed to access store for write")
+                        .copy_from_slice(&buf[..], offset + (batch_size * index))
+                })
+        };
+
+        trace!(
+            "flattening tree_c base data of {} nodes using batch size {}",
+            base_data.len(),
+            batch_size
+        );
+        flatten_and_write_store(base_data, 0).expect("failed to flatten and write store");
+        trace!("done flattening tree_c base data");
+
+        let base_offset = base_data.len();
+        trace!(
+            "flattening tree_c tree data of {} nodes using batch size {} and base offset {}",
+            tree_data.len(),
+            batch_size,
+            base_offset
+        );
+        flatten_and_write_store(tree_data, base_offset).expect("failed to flatten and write store");
+        trace!("done flattening tree_c tree data");
+
+        trace!("writing tree_c store data");
+        store
+            .write()
+            .expect("failed to access store for sync")
+            .sync()
+            .expect("store sync failure");
+        trace!("done writing tree_c store data");
+    }
+
     fn generate_tree_c_cpu<ColumnArity, TreeArity>(
         layers: usize,
         nodes_count: usize,
         tree_count: usize,
         configs: Vec<StoreConfig>,
         labels: &LabelsCache<Tree>,
-    ) -> Result<DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
+        resume: Option<&TreeResume>,
+    ) -> Result<DiskTree<Tree::Hasher, Tree::TreeCArity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         ColumnArity: PoseidonArity,
         TreeArity: PoseidonArity,
     {
-        info!("generating tree c using the CPU");
+        Self::generate_tree_c_cpu_with_mode::<ColumnArity, TreeArity>(
+            layers,
+            nodes_count,
+            tree_count,
+            configs,
+            labels,
+            resume,
+            use_tree_c_low_memory::<Tree>(nodes_count),
+            num_cpus::get(),
+        )
+    }
+
+    /// Builds tree_c on the CPU. With `low_memory` the column hashes are streamed into the on-disk
+    /// base trees instead of being collected in memory first; both modes produce the same tree.
+    ///
+    /// Otherwise the column hashes are split into `threads` chunks, which are hashed in parallel.
+    /// Every column hash is written to its own position, and the base trees are built from the
+    /// hashes in node order, so the tree doesn't depend on the number of threads or on the order
+    /// in which they finish.
+    ///
+    /// With `resume`, the base trees recorded as complete are reused, and every other one is
+    /// recorded as complete once it is stored.
+    #[allow(clippy::too_many_arguments)]
+    fn generate_tree_c_cpu_with_mode<ColumnArity, TreeArity>(
+        layers: usize,
+        nodes_count: usize,
+        tree_count: usize,
+        configs: Vec<StoreConfig>,
+        labels: &LabelsCache<Tree>,
+        resume: Option<&TreeResume>,
+        low_memory: bool,
+        threads: usize,
+    ) -> Result<DiskTree<Tree::Hasher, Tree::TreeCArity, Tree::SubTreeArity, Tree::TopTreeArity>>
+    where
+        ColumnArity: PoseidonArity,
+        TreeArity: PoseidonArity,
+    {
+        info!(
+            "generating tree c using the CPU (low memory: {})",
+            low_memory
+        );
         measure_op(Operation::GenerateTreeC, || {
             info!("Building column hashes");
 
-            let mut trees = Vec::with_capacity(tree_count);
             for (i, config) in configs.iter().enumerate() {
+                if is_reusable(resume, config) {
+                    info!("reusing base tree_c {}/{}", i + 1, tree_count);
+                    continue;
+                }
+                if resume.is_some() {
+                    remove_progress(config);
+                }
+
+                if low_memory {
+                    info!("building base tree_c {}/{}", i + 1, tree_count);
+                    let hashes = (0..nodes_count).into_par_iter().map(|j| {
+                        let data: Vec<_> = (1..=layers)
+                            .map(|layer| {
+                                let el: <Tree::Hasher as Hasher>::Domain = labels
+                                    .read_at(layer, (i * nodes_count) + j)
+                                    .expect("store read_at failure");
+                                el.into()
+                            })
+                            .collect();
+
+                        let hash: <Tree::Hasher as Hasher>::Domain =
+                            hash_single_column(&data).into();
+                        hash
+                    });
+                    DiskTree::<Tree::Hasher, Tree::TreeCArity, U0, U0>::from_par_iter_with_config(
+                        hashes,
+                        config.clone(),
+                    )
+                    .with_context(|| format!("failed tree_c CPU {}/{}", i + 1, tree_count))?;
+                    gpu::count_base_tree(None);
+                    if let Some(resume) = resume {
+                        mark_complete(config, &resume.inputs)?;
+                    }
+                    continue;
+                }
+
                 let mut hashes: Vec<<Tree::Hasher as Hasher>::Domain> =
                     vec![<Tree::Hasher as Hasher>::Domain::default(); nodes_count];
 
+                // The cores the tree builders are bound to, if they are.
+                #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+                let pool_cores = current_pool_cores();
+
                 THREAD_POOL.scoped(|s| {
-                    let n = num_cpus::get();
+                    let n = threads.max(1);
 
                     // only split if we have at least two elements per thread
                     let num_chunks = if n > nodes_count * 2 { 1 } else { n };
@@ -797,14 +1750,24 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                     // calculate all n chunks in parallel
                     for (chunk, hashes_chunk) in hashes.chunks_mut(chunk_size).enumerate() {
                         let labels = &labels;
+                        #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+                        let pool_cores = pool_cores.as_ref().map(|cores| cores.as_slice());
 
                         s.execute(move || {
+                            #[cfg(any(
+                                feature = "multicore-sdr",
+                                feature = "multicore-sdr-affinity"
+                            ))]
+                            let _binding = bind_column_hasher(pool_cores, chunk);
+
                             for (j, hash) in hashes_chunk.iter_mut().enumerate() {
                                 let data: Vec<_> = (1..=layers)
                                     .map(|layer| {
-                                        let store = labels.labels_for_layer(layer);
-                                        let el: <Tree::Hasher as Hasher>::Domain = store
-                                            .read_at((i * nodes_count) + j + chunk * chunk_size)
+                                        let el: <Tree::Hasher as Hasher>::Domain = labels
+                                            .read_at(
+                                                layer,
+                                                (i * nodes_count) + j + chunk * chunk_size,
+                                            )
                                             .expect("store read_at failure");
                                         el.into()
                                     })
@@ -817,22 +1780,32 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 });
 
                 info!("building base tree_c {}/{}", i + 1, tree_count);
-                trees.push(
-                    DiskTree::<Tree::Hasher, Tree::Arity, U0, U0>::from_par_iter_with_config(
-                        hashes.into_par_iter(),
-                        config.clone(),
-                    ),
-                );
+                DiskTree::<Tree::Hasher, Tree::TreeCArity, U0, U0>::from_par_iter_with_config(
+                    hashes.into_par_iter(),
+                    config.clone(),
+                )
+                .with_context(|| format!("failed tree_c CPU {}/{}", i + 1, tree_count))?;
+                gpu::count_base_tree(None);
+                if let Some(resume) = resume {
+                    mark_complete(config, &resume.inputs)?;
+                }
             }
 
-            assert_eq!(tree_count, trees.len());
-
             create_disk_tree::<
-                DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>,
+                DiskTree<Tree::Hasher, Tree::TreeCArity, Tree::SubTreeArity, Tree::TopTreeArity>,
             >(configs[0].size.expect("config size failure"), &configs)
         })
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "encoding",
+            level = "debug",
+            skip_all,
+            fields(start = start, end = end)
+        )
+    )]
     fn prepare_tree_r_data_cpu(
         source: &DiskStore<<Tree::Hasher as Hasher>::Domain>,
         data: Option<&mut Data<'_>>,
@@ -919,7 +1892,16 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
     }
 
     #[cfg(any(feature = "cuda", feature = "opencl"))]
-    pub fn generate_tree_r_last<TreeArity>(
+    #[allow(clippy::too_many_arguments)]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "tree_r_last",
+            skip_all,
+            fields(nodes = nodes_count, trees = tree_count)
+        )
+    )]
+    pub(crate) fn generate_tree_r_last_resumable<TreeArity>(
         data: &mut Data<'_>,
         nodes_count: usize,
         tree_count: usize,
@@ -927,6 +1909,8 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         replica_path: PathBuf,
         source: &DiskStore<<Tree::Hasher as Hasher>::Domain>,
         callback: Option<PrepareTreeRDataCallback<Tree>>,
+        resume: Option<&TreeResume>,
+        gpu: &GpuOptions,
     ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         TreeArity: PoseidonArity,
@@ -937,15 +1921,60 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         };
 
         if Self::use_gpu_tree_builder() {
-            Self::generate_tree_r_last_gpu::<TreeArity>(
-                data,
-                nodes_count,
-                tree_count,
-                tree_r_last_config,
-                replica_path,
-                source,
-                encode_data,
-            )
+            // Building tree_r_last encodes the data in place, so it can only be retried on the CPU
+            // if the GPU failed before any data was encoded, which is when creating the builder.
+            let devices = gpu.selection.devices()?;
+            let built = if devices.len() > 1 {
+                Self::generate_tree_r_last_multi_gpu::<TreeArity>(
+                    data,
+                    nodes_count,
+                    tree_count,
+                    tree_r_last_config.clone(),
+                    replica_path.clone(),
+                    source,
+                    encode_data,
+                    resume,
+                    &devices,
+                    gpu.batch_sizes,
+                )
+            } else {
+                Self::generate_tree_r_last_gpu::<TreeArity>(
+                    data,
+                    nodes_count,
+                    tree_count,
+                    tree_r_last_config.clone(),
+                    replica_path.clone(),
+                    source,
+                    encode_data,
+                    resume,
+                    gpu.selection.device()?,
+                    gpu.batch_sizes,
+                )
+            };
+            match built {
+                Err(err)
+                    if SETTINGS.gpu_oom_fallback_cpu
+                        && callback.is_none()
+                        && err.is::<GpuBuilderCreationError>()
+                        && is_gpu_out_of_memory(&err) =>
+                {
+                    warn!(
+                        "GPU ran out of memory building tree_r_last, falling back to CPU: {:?}",
+                        err
+                    );
+                    Self::generate_tree_r_last_cpu::<TreeArity>(
+                        data,
+                        nodes_count,
+                        tree_count,
+                        tree_r_last_config,
+                        replica_path,
+                        source,
+                        Self::prepare_tree_r_data_cpu,
+                        resume,
+                    )
+                }
+                res => res,
+            }
         } else {
             Self::generate_tree_r_last_cpu::<TreeArity>(
                 data,
@@ -955,12 +1984,22 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 replica_path,
                 source,
                 encode_data,
+                resume,
             )
         }
     }
 
     #[cfg(not(any(feature = "cuda", feature = "opencl")))]
-    pub fn generate_tree_r_last<TreeArity>(
+    #[allow(clippy::too_many_arguments)]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "tree_r_last",
+            skip_all,
+            fields(nodes = nodes_count, trees = tree_count)
+        )
+    )]
+    pub(crate) fn generate_tree_r_last_resumable<TreeArity>(
         data: &mut Data<'_>,
         nodes_count: usize,
         tree_count: usize,
@@ -968,6 +2007,8 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         replica_path: PathBuf,
         source: &DiskStore<<Tree::Hasher as Hasher>::Domain>,
         callback: Option<PrepareTreeRDataCallback<Tree>>,
+        resume: Option<&TreeResume>,
+        _gpu: &GpuOptions,
     ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         TreeArity: PoseidonArity,
@@ -985,10 +2026,41 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             replica_path,
             source,
             encode_data,
+            resume,
+        )
+    }
+
+    /// Encodes `data` with the labels of the last layer `source`, using `callback` if given, and
+    /// builds tree_r_last over it into the stores of `tree_r_last_config`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn generate_tree_r_last<TreeArity>(
+        data: &mut Data<'_>,
+        nodes_count: usize,
+        tree_count: usize,
+        tree_r_last_config: StoreConfig,
+        replica_path: PathBuf,
+        source: &DiskStore<<Tree::Hasher as Hasher>::Domain>,
+        callback: Option<PrepareTreeRDataCallback<Tree>>,
+        gpu: &GpuOptions,
+    ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
+    where
+        TreeArity: PoseidonArity,
+    {
+        Self::generate_tree_r_last_resumable::<TreeArity>(
+            data,
+            nodes_count,
+            tree_count,
+            tree_r_last_config,
+            replica_path,
+            source,
+            callback,
+            None,
+            gpu,
         )
     }
 
     #[cfg(any(feature = "cuda", feature = "opencl"))]
+    #[allow(clippy::too_many_arguments)]
     fn generate_tree_r_last_gpu<TreeArity>(
         data: &mut Data<'_>,
         nodes_count: usize,
@@ -997,21 +2069,21 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         replica_path: PathBuf,
         source: &DiskStore<<Tree::Hasher as Hasher>::Domain>,
         callback: PrepareTreeRDataCallback<Tree>,
+        resume: Option<&TreeResume>,
+        device: Option<&'static rust_gpu_tools::Device>,
+        batch_sizes: gpu::GpuBatchSizes,
     ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         TreeArity: PoseidonArity,
     {
         use std::cmp::min;
-        use std::fs::OpenOptions;
-        use std::io::Write;
         use std::sync::mpsc::sync_channel as channel;
+        use std::thread;
 
-        use fr32::fr_into_bytes;
-        use merkletree::merkle::{get_merkle_tree_cache_size, get_merkle_tree_leafs};
-        use neptune::{
-            batch_hasher::Batcher,
-            tree_builder::{TreeBuilder, TreeBuilderTrait},
-        };
+        use anyhow::format_err;
+        use neptune::tree_builder::{TreeBuilder, TreeBuilderTrait};
+
+        use crate::stacked::vanilla::async_write::write_file;
 
         let (configs, replica_config) = split_config_and_replica(
             tree_r_last_config.clone(),
@@ -1020,21 +2092,57 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             tree_count,
         )?;
 
+        // The base trees which are built, the others are reused along with their encoded range
+        // of the replica.
+        let pending: Vec<usize> = (0..configs.len())
+            .filter(|i| !is_reusable(resume, &configs[*i]))
+            .collect();
+        if pending.is_empty() {
+            info!("reusing all base trees of tree_r_last");
+            return create_lc_tree::<
+                LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>,
+            >(
+                tree_r_last_config.size.expect("config size failure"),
+                &configs,
+                &replica_config,
+            );
+        }
+
         info!("generating tree r last using the GPU");
-        let max_gpu_tree_batch_size = SETTINGS.max_gpu_tree_batch_size as usize;
+        let max_gpu_tree_batch_size =
+            batch_sizes.tree_r_last(device, Tree::Arity::to_usize(), nodes_count);
 
         // This channel will receive batches of leaf nodes and add them to the TreeBuilder.
         let (builder_tx, builder_rx) = channel::<(Vec<Fr>, bool)>(0);
-        let config_count = configs.len(); // Don't move config into closure below.
+        // This channel signals whether the builder was created, before any data is encoded.
+        let (ready_tx, ready_rx) = channel::<bool>(1);
+        let pending = &pending; // Don't move pending or configs into closures below.
         let configs = &configs;
         let tree_r_last_config = &tree_r_last_config;
 
+        let mut result = Ok(());
         THREAD_POOL.scoped(|s| {
-            // This channel will receive the finished tree data to be written to disk.
-            let (writer_tx, writer_rx) = channel::<Vec<Fr>>(0);
+            // This channel will receive the finished tree data to be written to disk, or the
+            // error the GPU failed with.
+            let (writer_tx, writer_rx) = channel::<Result<Vec<Fr>>>(0);
 
             s.execute(move || {
-                for i in 0..config_count {
+                if !ready_rx.recv().unwrap_or(false) {
+                    return;
+                }
+
+                for &i in pending {
+                    if let Some(resume) = resume {
+                        start_encoding(
+                            resume,
+                            &configs[i],
+                            data.as_mut(),
+                            i * nodes_count,
+                            (i + 1) * nodes_count,
+                        )
+                        .expect("failed to record the encoding of tree_r_last data");
+                    }
+
                     let mut node_index = 0;
                     while node_index != nodes_count {
                         let chunked_nodes_count =
@@ -1068,39 +2176,58 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                         );
 
                         let is_final = node_index == nodes_count;
-                        builder_tx
-                            .send((prepared_data, is_final))
-                            .expect("failed to send prepared data");
+                        if is_final && resume.is_some() {
+                            // The range is on disk before its base tree is recorded as complete.
+                            data.flush_range(i * nodes_count * NODE_SIZE, nodes_count * NODE_SIZE)
+                                .expect("failed to flush the encoded data");
+                        }
+                        if builder_tx.send((prepared_data, is_final)).is_err() {
+                            // The builder failed and stopped receiving, it reports the error.
+                            return;
+                        }
                     }
                 }
             });
             s.execute(move || {
-                let _gpu_lock = GPU_LOCK.lock().expect("failed to get gpu lock");
-                let batcher = match Batcher::pick_gpu(max_gpu_tree_batch_size) {
-                    Ok(b) => Some(b),
+                let gpu_lock = gpu::device_lock(device);
+                let _gpu_lock = gpu_lock.lock().expect("failed to get gpu lock");
+                let batcher = match gpu_batcher(device, max_gpu_tree_batch_size) {
+                    Ok(batcher) => batcher,
                     Err(err) => {
-                        warn!("no GPU found, falling back to CPU tree builder: {}", err);
-                        None
+                        let _ = ready_tx.send(false);
+                        let _ = writer_tx.send(Err(err));
+                        return;
                     }
                 };
-                let mut tree_builder = TreeBuilder::<Tree::Arity>::new(
+                let built_on = gpu::builder_gpu(device, batcher.is_some());
+                let mut tree_builder = match TreeBuilder::<Tree::Arity>::new(
                     batcher,
                     nodes_count,
                     tree_r_last_config.rows_to_discard,
-                )
-                .expect("failed to create TreeBuilder");
+                ) {
+                    Ok(builder) => builder,
+                    Err(err) => {
+                        let _ = ready_tx.send(false);
+                        let _ = writer_tx
+                            .send(Err(GpuBuilderCreationError(format!("{:?}", err)).into()));
+                        return;
+                    }
+                };
+                let _ = ready_tx.send(true);
 
                 // Loop until all trees for all configs have been built.
-                for i in 0..config_count {
+                for &i in pending {
                     loop {
                         let (prepared_data, is_final) =
                             builder_rx.recv().expect("failed to recv prepared data");
 
                         // Just add non-final leaf batches.
                         if !is_final {
-                            tree_builder
-                                .add_leaves(&prepared_data)
-                                .expect("failed to add leaves");
+                            if let Err(err) = tree_builder.add_leaves(&prepared_data) {
+                                let _ = writer_tx
+                                    .send(Err(format_err!("failed to add leaves: {:?}", err)));
+                                return;
+                            }
                             continue;
                         };
 
@@ -1110,38 +2237,49 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                             i + 1,
                             tree_count
                         );
-                        let (_, tree_data) = tree_builder
-                            .add_final_leaves(&prepared_data)
-                            .expect("failed to add final leaves");
+                        let (_, tree_data) = match tree_builder.add_final_leaves(&prepared_data) {
+                            Ok(data) => data,
+                            Err(err) => {
+                                let _ = writer_tx.send(Err(format_err!(
+                                    "failed to add final leaves: {:?}",
+                                    err
+                                )));
+                                return;
+                            }
+                        };
 
-                        writer_tx.send(tree_data).expect("failed to send tree_data");
+                        gpu::count_base_tree(built_on.as_deref());
+                        writer_tx
+                            .send(Ok(tree_data))
+                            .expect("failed to send tree_data");
                         break;
                     }
                 }
             });
 
-            for config in configs.iter() {
-                let tree_data = writer_rx
+            let complete = |config: &StoreConfig| {
+                if let Some(resume) = resume {
+                    mark_complete(config, &resume.inputs)
+                        .expect("failed to record base tree_r_last as complete");
+                }
+            };
+
+            // With `async_writes`, a tree is written while the GPU builds the next one.
+            let mut writing: Option<(thread::JoinHandle<std::io::Result<()>>, &StoreConfig)> = None;
+            for config in pending.iter().map(|i| &configs[*i]) {
+                let tree_data = match writer_rx
                     .recv()
-                    .expect("failed to receive tree_data for tree_r_last");
+                    .expect("failed to receive tree_data for tree_r_last")
+                {
+                    Ok(tree_data) => tree_data,
+                    Err(err) => {
+                        result = Err(err);
+                        break;
+                    }
+                };
 
                 let tree_data_len = tree_data.len();
-                let cache_size = get_merkle_tree_cache_size(
-                    get_merkle_tree_leafs(
-                        config.size.expect("config size failure"),
-                        Tree::Arity::to_usize(),
-                    )
-                    .expect("failed to get merkle tree leaves"),
-                    Tree::Arity::to_usize(),
-                    config.rows_to_discard,
-                )
-                .expect("failed to get merkle tree cache size");
-                assert_eq!(tree_data_len, cache_size);
-
-                let flat_tree_data: Vec<_> = tree_data
-                    .into_par_iter()
-                    .flat_map(|el| fr_into_bytes(&el))
-                    .collect();
+                let flat_tree_data = Self::flatten_tree_r_last(config, tree_data);
 
                 // Persist the data to the store based on the current config.
                 let tree_r_last_path = StoreConfig::data_path(&config.path, &config.id);
@@ -1151,21 +2289,361 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                     config.rows_to_discard,
                     tree_r_last_path
                 );
-                let mut f = OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .open(&tree_r_last_path)
-                    .expect("failed to open file for tree_r_last");
-                f.write_all(&flat_tree_data)
-                    .expect("failed to wrote tree_r_last data");
+                if let Some((writing, written)) = writing.take() {
+                    writing
+                        .join()
+                        .expect("failed to join tree_r_last write")
+                        .expect("failed to write tree_r_last data");
+                    complete(written);
+                }
+                if SETTINGS.async_writes {
+                    writing = Some((
+                        thread::spawn(move || write_file(&tree_r_last_path, &flat_tree_data)),
+                        config,
+                    ));
+                } else {
+                    write_file(&tree_r_last_path, &flat_tree_data)
+                        .expect("failed to write tree_r_last data");
+                    complete(config);
+                }
+            }
+            if let Some((writing, written)) = writing {
+                writing
+                    .join()
+                    .expect("failed to join tree_r_last write")
+                    .expect("failed to write tree_r_last data");
+                complete(written);
+            }
+        });
+        result?;
+
+        create_lc_tree::<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>(
+            tree_r_last_config.size.expect("config size failure"),
+            configs,
+            &replica_config,
+        )
+    }
+
+    /// Like `generate_tree_r_last_gpu`, but shares the base trees between `devices`, see
+    /// `ShardQueue`.
+    ///
+    /// Every device has a thread which encodes the ranges of the base trees it claims, and one
+    /// which builds them. Encoding writes to `data`, so only one range is encoded at a time, while
+    /// the devices build the ones encoded before. A device which fails returns its claims for the
+    /// others to build, see `prepare_tree_r_last_range`.
+    #[cfg(any(feature = "cuda", feature = "opencl"))]
+    #[allow(clippy::too_many_arguments)]
+    fn generate_tree_r_last_multi_gpu<TreeArity>(
+        data: &mut Data<'_>,
+        nodes_count: usize,
+        tree_count: usize,
+        tree_r_last_config: StoreConfig,
+        replica_path: PathBuf,
+        source: &DiskStore<<Tree::Hasher as Hasher>::Domain>,
+        callback: PrepareTreeRDataCallback<Tree>,
+        resume: Option<&TreeResume>,
+        devices: &[&'static rust_gpu_tools::Device],
+        batch_sizes: gpu::GpuBatchSizes,
+    ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
+    where
+        TreeArity: PoseidonArity,
+    {
+        use std::cmp::min;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::mpsc::sync_channel as channel;
+        use std::sync::Mutex;
+
+        use anyhow::format_err;
+        use neptune::tree_builder::{TreeBuilder, TreeBuilderTrait};
+
+        use crate::stacked::vanilla::async_write::write_file;
+
+        let (configs, replica_config) = split_config_and_replica(
+            tree_r_last_config.clone(),
+            replica_path,
+            nodes_count,
+            tree_count,
+        )?;
+
+        let pending: Vec<usize> = (0..configs.len())
+            .filter(|i| !is_reusable(resume, &configs[*i]))
+            .collect();
+        if pending.is_empty() {
+            info!("reusing all base trees of tree_r_last");
+            return create_lc_tree::<
+                LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>,
+            >(
+                tree_r_last_config.size.expect("config size failure"),
+                &configs,
+                &replica_config,
+            );
+        }
+
+        info!("generating tree r last using {} GPUs", devices.len());
+        let queue_depth = SETTINGS.gpu_queue_depth as usize;
+
+        let queue = gpu::ShardQueue::new(pending, devices.len());
+        let data = Mutex::new(data);
+        // How many nodes of the range of each base tree are encoded, so that a base tree which
+        // another device claims after a failure is not encoded twice.
+        let encoded: Vec<AtomicUsize> = (0..configs.len()).map(|_| AtomicUsize::new(0)).collect();
+        // Don't move these into closures below.
+        let (queue, data, configs, encoded) = (&queue, &data, &configs, &encoded);
+        let rows_to_discard = tree_r_last_config.rows_to_discard;
+
+        let mut errors = Vec::new();
+        let mut built = 0;
+        THREAD_POOL.scoped(|s| {
+            // This channel will receive the finished base trees to be written to disk, or the
+            // errors the devices failed with.
+            let (writer_tx, writer_rx) = channel::<Result<(usize, Vec<Fr>)>>(devices.len());
+
+            for (d, &device) in devices.iter().enumerate() {
+                let max_gpu_tree_batch_size =
+                    batch_sizes.tree_r_last(Some(device), Tree::Arity::to_usize(), nodes_count);
+                let (builder_tx, builder_rx) = channel::<(usize, Vec<Fr>, bool)>(queue_depth);
+                // This channel signals whether the builder was created, before any data is encoded.
+                let (ready_tx, ready_rx) = channel::<bool>(1);
+                let writer_tx = writer_tx.clone();
+
+                // The builder is started first, so that it is not left waiting for a thread while
+                // its producer waits for it.
+                s.execute(move || {
+                    let gpu_lock = gpu::device_lock(Some(device));
+                    let _gpu_lock = gpu_lock.lock().expect("failed to get gpu lock");
+                    let builder =
+                        gpu_batcher(Some(device), max_gpu_tree_batch_size).and_then(|batcher| {
+                            TreeBuilder::<Tree::Arity>::new(batcher, nodes_count, rows_to_discard)
+                                .map_err(|err| GpuBuilderCreationError(format!("{:?}", err)).into())
+                        });
+                    let mut tree_builder = match builder {
+                        Ok(builder) => builder,
+                        Err(err) => {
+                            queue.fail(d);
+                            let _ = ready_tx.send(false);
+                            let _ = writer_tx.send(Err(err));
+                            return;
+                        }
+                    };
+                    let _ = ready_tx.send(true);
+
+                    // The producer closes the channel once no base tree is left to claim. If
+                    // building fails, the claims of the device are returned to the queue.
+                    for (i, prepared_data, is_final) in builder_rx.iter() {
+                        if !is_final {
+                            if let Err(err) = tree_builder.add_leaves(&prepared_data) {
+                                queue.fail(d);
+                                let _ = writer_tx
+                                    .send(Err(format_err!("failed to add leaves: {:?}", err)));
+                                return;
+                            }
+                            continue;
+                        }
+
+                        info!(
+                            "building base tree_r_last {}/{} on {}",
+                            i + 1,
+                            tree_count,
+                            device.name()
+                        );
+                        match tree_builder.add_final_leaves(&prepared_data) {
+                            Ok((_, tree_data)) => {
+                                if !queue.complete(d, i) {
+                                    continue;
+                                }
+                                gpu::count_base_tree(Some(&device.name()));
+                                if writer_tx.send(Ok((i, tree_data))).is_err() {
+                                    return;
+                                }
+                            }
+                            Err(err) => {
+                                queue.fail(d);
+                                let _ = writer_tx.send(Err(format_err!(
+                                    "failed to add final leaves: {:?}",
+                                    err
+                                )));
+                                return;
+                            }
+                        }
+                    }
+                });
+                let producer_tx = writer_tx.clone();
+                s.execute(move || {
+                    if !ready_rx.recv().unwrap_or(false) {
+                        return;
+                    }
+                    // The claims of the device are returned to the queue, so that another one
+                    // encodes the rest of them.
+                    let fail = |err: anyhow::Error| {
+                        queue.fail(d);
+                        let _ = producer_tx.send(Err(err));
+                    };
+
+                    while let Some(i) = queue.claim(d) {
+                        if let Some(resume) = resume {
+                            let mut data = data.lock().expect("failed to lock tree_r_last data");
+                            if let Err(err) = start_encoding(
+                                resume,
+                                &configs[i],
+                                (**data).as_mut(),
+                                i * nodes_count,
+                                (i + 1) * nodes_count,
+                            ) {
+                                return fail(
+                                    err.context(
+                                        "failed to record the encoding of tree_r_last data",
+                                    ),
+                                );
+                            }
+                            // The range is restored if a failed device encoded some of it.
+                            encoded[i].store(0, Ordering::SeqCst);
+                        }
+
+                        let mut node_index = 0;
+                        while node_index != nodes_count {
+                            let chunked_nodes_count =
+                                min(nodes_count - node_index, max_gpu_tree_batch_size);
+                            let start = (i * nodes_count) + node_index;
+                            let end = start + chunked_nodes_count;
+
+                            let mut data = data.lock().expect("failed to lock tree_r_last data");
+                            let prepared_data = match Self::prepare_tree_r_last_range(
+                                source,
+                                &mut **data,
+                                callback,
+                                &encoded[i],
+                                i * nodes_count,
+                                start,
+                                end,
+                            ) {
+                                Ok(prepared_data) => prepared_data,
+                                Err(err) => return fail(err),
+                            };
+                            node_index += chunked_nodes_count;
+
+                            let is_final = node_index == nodes_count;
+                            if is_final && resume.is_some() {
+                                if let Err(err) = data.flush_range(
+                                    i * nodes_count * NODE_SIZE,
+                                    nodes_count * NODE_SIZE,
+                                ) {
+                                    return fail(format_err!(
+                                        "failed to flush the encoded data: {:?}",
+                                        err
+                                    ));
+                                }
+                            }
+                            drop(data);
+                            if builder_tx.send((i, prepared_data, is_final)).is_err() {
+                                // The builder failed and stopped receiving, it reports the error.
+                                queue.fail(d);
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+            drop(writer_tx);
+
+            for message in writer_rx.iter() {
+                let (i, tree_data) = match message {
+                    Ok(data) => data,
+                    Err(err) => {
+                        warn!("a GPU failed building tree_r_last: {:?}", err);
+                        errors.push(err);
+                        continue;
+                    }
+                };
+
+                let config = &configs[i];
+                let flat_tree_data = Self::flatten_tree_r_last(config, tree_data);
+                let tree_r_last_path = StoreConfig::data_path(&config.path, &config.id);
+                if let Err(err) = write_file(&tree_r_last_path, &flat_tree_data) {
+                    errors.push(err.context("failed to write tree_r_last data"));
+                    continue;
+                }
+                if let Some(resume) = resume {
+                    if let Err(err) = mark_complete(config, &resume.inputs) {
+                        errors.push(err.context("failed to record base tree_r_last as complete"));
+                        continue;
+                    }
+                }
+                built += 1;
+            }
+        });
+        if built < queue.len() {
+            return Err(first_gpu_error(errors, "tree_r_last"));
+        }
+        queue.log_shares("tree_r_last", devices);
+
+        create_lc_tree::<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>(
+            tree_r_last_config.size.expect("config size failure"),
+            configs,
+            &replica_config,
+        )
+    }
+
+    /// Returns the encoded nodes `start..end` of `data`, the range of a base tree of tree_r_last
+    /// starting at node `base_start`. The nodes are encoded with `callback`, except for the first
+    /// `encoded` of the base tree, which a device that failed encoded already and are only read.
+    #[cfg(any(feature = "cuda", feature = "opencl"))]
+    fn prepare_tree_r_last_range(
+        source: &DiskStore<<Tree::Hasher as Hasher>::Domain>,
+        data: &mut Data<'_>,
+        callback: PrepareTreeRDataCallback<Tree>,
+        encoded: &std::sync::atomic::AtomicUsize,
+        base_start: usize,
+        start: usize,
+        end: usize,
+    ) -> Result<Vec<Fr>> {
+        use std::sync::atomic::Ordering;
+
+        use fr32::bytes_into_fr;
+
+        let encoded_end = (base_start + encoded.load(Ordering::SeqCst))
+            .max(start)
+            .min(end);
+        let bytes: &[u8] = (*data).as_ref();
+        let mut prepared_data = bytes[start * NODE_SIZE..encoded_end * NODE_SIZE]
+            .chunks(NODE_SIZE)
+            .map(|node| bytes_into_fr(node).context("failed to read encoded tree_r_last data"))
+            .collect::<Result<Vec<_>>>()?;
+        if encoded_end < end {
+            match callback(source, Some(data), encoded_end, end)
+                .context("failed to prepare tree_r_last data")?
+            {
+                TreeRElementData::FrList(x) => prepared_data.extend(x),
+                _ => anyhow::bail!("fr_list is required"),
             }
-        });
+            encoded.store(end - base_start, Ordering::SeqCst);
+        }
 
-        create_lc_tree::<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>(
-            tree_r_last_config.size.expect("config size failure"),
-            configs,
-            &replica_config,
+        Ok(prepared_data)
+    }
+
+    /// Returns the bytes of the cached rows `tree_data` of the base tree of tree_r_last stored as
+    /// `config`.
+    #[cfg(any(feature = "cuda", feature = "opencl"))]
+    fn flatten_tree_r_last(config: &StoreConfig, tree_data: Vec<Fr>) -> Vec<u8> {
+        use fr32::fr_into_bytes;
+        use merkletree::merkle::get_merkle_tree_cache_size;
+
+        let cache_size = get_merkle_tree_cache_size(
+            get_merkle_tree_leafs(
+                config.size.expect("config size failure"),
+                Tree::Arity::to_usize(),
+            )
+            .expect("failed to get merkle tree leaves"),
+            Tree::Arity::to_usize(),
+            config.rows_to_discard,
         )
+        .expect("failed to get merkle tree cache size");
+        assert_eq!(tree_data.len(), cache_size);
+
+        tree_data
+            .into_par_iter()
+            .flat_map(|el| fr_into_bytes(&el))
+            .collect()
     }
 
     fn generate_tree_r_last_cpu<TreeArity>(
@@ -1176,6 +2654,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         replica_path: PathBuf,
         source: &DiskStore<<Tree::Hasher as Hasher>::Domain>,
         callback: PrepareTreeRDataCallback<Tree>,
+        resume: Option<&TreeResume>,
     ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         TreeArity: PoseidonArity,
@@ -1189,11 +2668,19 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
 
         info!("generating tree r last using the CPU");
 
-        // Note that nodes_count is the count of nodes in each base tree
-        let mut start = 0;
-        let mut end = nodes_count;
-
         for (i, config) in configs.iter().enumerate() {
+            // Note that nodes_count is the count of nodes in each base tree
+            let start = i * nodes_count;
+            let end = start + nodes_count;
+
+            if is_reusable(resume, config) {
+                info!("reusing base tree_r_last {}/{}", i + 1, tree_count);
+                continue;
+            }
+            if let Some(resume) = resume {
+                start_encoding(resume, config, data.as_mut(), start, end)?;
+            }
+
             let encoded_data: Vec<<Tree::Hasher as Hasher>::Domain> =
                 match callback(source, Some(data), start, end)
                     .expect("failed to prepare tree_r_last data")
@@ -1226,9 +2713,12 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 config.clone(),
             )
             .with_context(|| format!("failed tree_r_last CPU {}/{}", i + 1, tree_count))?;
+            gpu::count_base_tree(None);
 
-            start += nodes_count;
-            end += nodes_count;
+            if let Some(resume) = resume {
+                data.flush_range(start * NODE_SIZE, nodes_count * NODE_SIZE)?;
+                mark_complete(config, &resume.inputs)?;
+            }
         }
 
         create_lc_tree::<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>(
@@ -1246,6 +2736,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         data_tree: Option<BinaryMerkleTree<G>>,
         config: StoreConfig,
         replica_path: PathBuf,
+        gpu: &GpuOptions,
     ) -> Result<TransformedLayers<Tree, G>> {
         // Generate key layers.
         let labels = measure_op(Operation::EncodeWindowTimeAll, || {
@@ -1254,18 +2745,27 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         })?
         .0;
 
-        Self::transform_and_replicate_layers_inner(
-            graph,
-            layer_challenges,
-            data,
-            data_tree,
-            config,
-            replica_path,
-            labels,
-        )
+        install_tree_builders(|| {
+            Self::transform_and_replicate_layers_inner(
+                graph,
+                layer_challenges,
+                data,
+                data_tree,
+                config,
+                replica_path,
+                labels,
+                None,
+                gpu,
+                None,
+            )
+        })
         .context("failed to transform")
     }
 
+    /// Builds the trees of the sector from the layers of `label_configs`, encoding `data` into the
+    /// replica. `on_roots` is called with the roots as soon as the trees are built, see
+    /// `replicate_phase2_with_roots`.
+    #[allow(clippy::too_many_arguments, clippy::type_complexity)]
     pub(crate) fn transform_and_replicate_layers_inner(
         graph: &StackedBucketGraph<Tree::Hasher>,
         layer_challenges: &LayerChallenges,
@@ -1274,8 +2774,17 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         config: StoreConfig,
         replica_path: PathBuf,
         label_configs: Labels<Tree>,
+        rows_to_discard: Option<usize>,
+        gpu: &GpuOptions,
+        on_roots: Option<
+            &mut (dyn FnMut(
+                &Tau<<Tree::Hasher as Hasher>::Domain, G::Domain>,
+                &PersistentAux<<Tree::Hasher as Hasher>::Domain>,
+            ) + Send),
+        >,
     ) -> Result<TransformedLayers<Tree, G>> {
         trace!("transform_and_replicate_layers");
+        gpu::ensure_available(&gpu.selection)?;
         let nodes_count = graph.size();
 
         assert_eq!(data.len(), nodes_count * NODE_SIZE);
@@ -1300,6 +2809,13 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         );
         assert!(binary_arity_valid);
         assert!(other_arity_valid);
+        let tree_c_arity = Tree::TreeCArity::to_usize();
+        assert!(
+            is_merkle_tree_size_valid(nodes_count, tree_c_arity),
+            "{} nodes cannot form a tree_c of arity {}",
+            nodes_count,
+            tree_c_arity
+        );
 
         let layers = layer_challenges.layers();
         assert!(layers > 0);
@@ -1320,12 +2836,10 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         );
 
         // A default 'rows_to_discard' value will be chosen for tree_r_last, unless the user overrides this value via the
-        // environment setting (FIL_PROOFS_ROWS_TO_DISCARD).  If this value is specified, no checking is done on it and it may
-        // result in a broken configuration.  Use with caution.  It must be noted that if/when this unchecked value is passed
-        // through merkle_light, merkle_light now does a check that does not allow us to discard more rows than is possible
-        // to discard.
+        // environment setting (FIL_PROOFS_ROWS_TO_DISCARD) or for this sector with `rows_to_discard`.  If this value is
+        // specified, it is only capped at the number of rows the tree can discard.  Use with caution.
         tree_r_last_config.rows_to_discard =
-            default_rows_to_discard(nodes_count, Tree::Arity::to_usize());
+            util::rows_to_discard(nodes_count, Tree::Arity::to_usize(), rows_to_discard);
         trace!(
             "tree_r_last using rows_to_discard={}",
             tree_r_last_config.rows_to_discard
@@ -1334,10 +2848,10 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         let mut tree_c_config = StoreConfig::from_config(
             &config,
             CacheKey::CommCTree.to_string(),
-            Some(get_merkle_tree_len(nodes_count, Tree::Arity::to_usize())?),
+            Some(get_merkle_tree_len(nodes_count, tree_c_arity)?),
         );
         tree_c_config.rows_to_discard =
-            default_rows_to_discard(nodes_count, Tree::Arity::to_usize());
+            util::rows_to_discard(nodes_count, tree_c_arity, rows_to_discard);
 
         let labels =
             LabelsCache::<Tree>::new(&label_configs).context("failed to create labels cache")?;
@@ -1350,42 +2864,25 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             None => error!("Failed to raise the fd limit"),
         };
 
-        let tree_c_root = match layers {
-            2 => {
-                let tree_c = Self::generate_tree_c::<U2, Tree::Arity>(
-                    layers,
-                    nodes_count,
-                    tree_count,
-                    configs,
-                    &labels,
-                )?;
-                tree_c.root()
-            }
-            8 => {
-                let tree_c = Self::generate_tree_c::<U8, Tree::Arity>(
-                    layers,
-                    nodes_count,
-                    tree_count,
-                    configs,
-                    &labels,
-                )?;
-                tree_c.root()
-            }
-            11 => {
-                let tree_c = Self::generate_tree_c::<U11, Tree::Arity>(
-                    layers,
-                    nodes_count,
-                    tree_count,
-                    configs,
-                    &labels,
-                )?;
-                tree_c.root()
-            }
-            _ => panic_any("Unsupported column arity"),
-        };
+        // The base trees completed by an earlier call which was interrupted are reused, as long
+        // as the layers they were built from have not changed since.
+        let tree_c_resume = tree_c_inputs(&label_configs.labels).map(|inputs| TreeResume {
+            inputs,
+            tree_d_config: None,
+        });
+        let tree_c_root = Self::generate_tree_c_root(
+            layers,
+            nodes_count,
+            tree_count,
+            configs,
+            &labels,
+            tree_c_resume.as_ref(),
+            gpu,
+        )?;
         info!("tree_c done");
 
         // Build the MerkleTree over the original data (if needed).
+        let tree_d_stored = data_tree.is_some();
         let tree_d = match data_tree {
             Some(t) => {
                 trace!("using existing original data merkle tree");
@@ -1413,9 +2910,22 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         let last_layer_labels = labels.labels_for_last_layer()?;
         data.ensure_data()?;
 
+        // tree_r_last is only resumed if the encoded replica outlives an interruption, and the
+        // unencoded data can be restored from a tree_d which was not built from the replica.
+        let tree_r_last_resume = if tree_d_stored && data.is_mapped() {
+            tree_r_last_inputs(&label_configs.labels[layers - 1], &tree_d_root).map(|inputs| {
+                TreeResume {
+                    inputs,
+                    tree_d_config: Some(tree_d_config.clone()),
+                }
+            })
+        } else {
+            None
+        };
+
         info!("building tree_r_last");
         let tree_r_last = measure_op(Operation::GenerateTreeRLast, || {
-            Self::generate_tree_r_last::<Tree::Arity>(
+            Self::generate_tree_r_last_resumable::<Tree::Arity>(
                 &mut data,
                 nodes_count,
                 tree_count,
@@ -1423,6 +2933,8 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 replica_path.clone(),
                 last_layer_labels,
                 None,
+                tree_r_last_resume.as_ref(),
+                gpu,
             )
             .context("failed to generate tree_r_last")
         })?;
@@ -1431,21 +2943,39 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         let tree_r_last_root = tree_r_last.root();
         drop(tree_r_last);
 
-        data.drop_data()?;
+        // Both roots are known, so comm_r is reported before the layers are consolidated or
+        // compressed and the replica is flushed.
+        let tau = Tau {
+            comm_d: tree_d_root,
+            comm_r: finalize_comm_r::<Tree::Hasher>(&tree_c_root, &tree_r_last_root),
+        };
+        let p_aux = PersistentAux {
+            comm_c: tree_c_root,
+            comm_r_last: tree_r_last_root,
+        };
+        trace!("comm_r ready: {:?}", tau.comm_r);
+        if let Some(on_roots) = on_roots {
+            on_roots(&tau, &p_aux);
+        }
+
+        // The trees are built, the layers are only read node by node from here on.
+        drop(labels);
+        if SETTINGS.consolidate_layer_files {
+            consolidate_layer_files(&label_configs.labels)
+                .context("failed to consolidate the layer files")?;
+        }
+        if SETTINGS.cache_compression {
+            #[cfg(feature = "cache-compression")]
+            compress_layers(&label_configs.labels).context("failed to compress the layers")?;
+            #[cfg(not(feature = "cache-compression"))]
+            warn!("cache_compression is set, but the cache-compression feature is not enabled");
+        }
 
-        // comm_r = H(comm_c || comm_r_last)
-        let comm_r: <Tree::Hasher as Hasher>::Domain =
-            <Tree::Hasher as Hasher>::Function::hash2(&tree_c_root, &tree_r_last_root);
+        data.drop_data()?;
 
         Ok((
-            Tau {
-                comm_d: tree_d_root,
-                comm_r,
-            },
-            PersistentAux {
-                comm_c: tree_c_root,
-                comm_r_last: tree_r_last_root,
-            },
+            tau,
+            p_aux,
             TemporaryAux {
                 labels: label_configs,
                 tree_d_config,
@@ -1472,8 +3002,86 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         Ok(labels)
     }
 
-    /// Phase2 of replication.
-    #[allow(clippy::type_complexity)]
+    /// Like `replicate_phase1`, but labels on `cores`, see `generate_labels_for_encoding_on_cores`.
+    #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+    pub fn replicate_phase1_on_cores(
+        pp: &'a PublicParams<Tree>,
+        replica_id: &<Tree::Hasher as Hasher>::Domain,
+        config: StoreConfig,
+        cores: Vec<CoreIndex>,
+    ) -> Result<Labels<Tree>> {
+        info!("replicate_phase1_on_cores");
+
+        let labels = measure_op(Operation::EncodeWindowTimeAll, || {
+            Self::generate_labels_for_encoding_on_cores(
+                &pp.graph,
+                &pp.layer_challenges,
+                replica_id,
+                config,
+                cores,
+            )
+        })?
+        .0;
+
+        Ok(labels)
+    }
+
+    /// Like `replicate_phase1`, but labels with the layer buffers of `buffer_pool`, see
+    /// `generate_labels_for_encoding_with_buffer_pool`.
+    #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+    pub fn replicate_phase1_with_buffer_pool(
+        pp: &'a PublicParams<Tree>,
+        replica_id: &<Tree::Hasher as Hasher>::Domain,
+        config: StoreConfig,
+        buffer_pool: &BufferPool,
+    ) -> Result<Labels<Tree>> {
+        info!("replicate_phase1_with_buffer_pool");
+
+        let labels = measure_op(Operation::EncodeWindowTimeAll, || {
+            Self::generate_labels_for_encoding_with_buffer_pool(
+                &pp.graph,
+                &pp.layer_challenges,
+                replica_id,
+                config,
+                buffer_pool,
+            )
+        })?
+        .0;
+
+        Ok(labels)
+    }
+
+    /// Like `replicate_phase1`, but waits for a core group with `priority`, see
+    /// `generate_labels_for_encoding_with_priority`.
+    #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-affinity"))]
+    pub fn replicate_phase1_with_priority(
+        pp: &'a PublicParams<Tree>,
+        replica_id: &<Tree::Hasher as Hasher>::Domain,
+        config: StoreConfig,
+        priority: u8,
+        timeout: Duration,
+    ) -> Result<Labels<Tree>> {
+        info!("replicate_phase1_with_priority");
+
+        let labels = measure_op(Operation::EncodeWindowTimeAll, || {
+            Self::generate_labels_for_encoding_with_priority(
+                &pp.graph,
+                &pp.layer_challenges,
+                replica_id,
+                config,
+                priority,
+                timeout,
+            )
+        })?
+        .0;
+
+        Ok(labels)
+    }
+
+    /// Phase2 of replication, building tree_c and tree_r_last on the GPU selected by `gpu` if the
+    /// GPU tree builders are enabled. Their cached rows are stored without the lowest
+    /// `rows_to_discard` ones, or the ones of the `rows_to_discard` setting if not given.
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
     pub fn replicate_phase2(
         pp: &'a PublicParams<Tree>,
         label_configs: Labels<Tree>,
@@ -1481,25 +3089,160 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         data_tree: BinaryMerkleTree<G>,
         config: StoreConfig,
         replica_path: PathBuf,
+        rows_to_discard: Option<usize>,
+        gpu: &GpuOptions,
     ) -> Result<(
         <Self as PoRep<'a, Tree::Hasher, G>>::Tau,
         <Self as PoRep<'a, Tree::Hasher, G>>::ProverAux,
     )> {
-        info!("replicate_phase2");
-
-        let (tau, paux, taux) = Self::transform_and_replicate_layers_inner(
-            &pp.graph,
-            &pp.layer_challenges,
+        Self::replicate_phase2_with_roots(
+            pp,
+            label_configs,
             data,
-            Some(data_tree),
+            data_tree,
             config,
             replica_path,
-            label_configs,
-        )?;
+            rows_to_discard,
+            gpu,
+            None,
+        )
+    }
+
+    /// Like `replicate_phase2`, but calls `on_roots` with tau and p_aux as soon as tree_c and
+    /// tree_r_last are built. The layers are consolidated or compressed and the replica is flushed
+    /// only after that, so a pipeline can start its next stage on `comm_r` in the meantime.
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    pub fn replicate_phase2_with_roots(
+        pp: &'a PublicParams<Tree>,
+        label_configs: Labels<Tree>,
+        data: Data<'a>,
+        data_tree: BinaryMerkleTree<G>,
+        config: StoreConfig,
+        replica_path: PathBuf,
+        rows_to_discard: Option<usize>,
+        gpu: &GpuOptions,
+        on_roots: Option<
+            &mut (dyn FnMut(
+                &Tau<<Tree::Hasher as Hasher>::Domain, G::Domain>,
+                &PersistentAux<<Tree::Hasher as Hasher>::Domain>,
+            ) + Send),
+        >,
+    ) -> Result<(
+        <Self as PoRep<'a, Tree::Hasher, G>>::Tau,
+        <Self as PoRep<'a, Tree::Hasher, G>>::ProverAux,
+    )> {
+        info!("replicate_phase2");
+
+        let (tau, paux, taux) = install_tree_builders(|| {
+            Self::transform_and_replicate_layers_inner(
+                &pp.graph,
+                &pp.layer_challenges,
+                data,
+                Some(data_tree),
+                config,
+                replica_path,
+                label_configs,
+                rows_to_discard,
+                gpu,
+                on_roots,
+            )
+        })?;
 
         Ok((tau, (paux, taux)))
     }
 
+    /// Builds tree_c again from the layers of `label_configs` into the stores of `tree_c_config`,
+    /// on the GPU selected by `gpu` if the GPU column builder is enabled, and returns its root.
+    ///
+    /// The layers have to be stored separately, as the tree builders cannot read a consolidated
+    /// layer file.
+    pub fn rebuild_tree_c(
+        label_configs: &Labels<Tree>,
+        tree_c_config: StoreConfig,
+        gpu: &GpuOptions,
+    ) -> Result<<Tree::Hasher as Hasher>::Domain> {
+        info!("rebuild_tree_c");
+        gpu::ensure_available(&gpu.selection)?;
+        ensure!(
+            label_configs.layer_file_path().is_none(),
+            "the layers are consolidated, tree_c cannot be rebuilt from them"
+        );
+
+        let tree_count = get_base_tree_count::<Tree>();
+        let nodes_count = get_merkle_tree_leafs(
+            tree_c_config.size.context("tree_c config has no size")?,
+            Tree::TreeCArity::to_usize(),
+        )?;
+        let labels =
+            LabelsCache::<Tree>::new(label_configs).context("failed to open the layers")?;
+        let configs = split_config(tree_c_config, tree_count)?;
+
+        Self::generate_tree_c_root(
+            label_configs.len(),
+            nodes_count,
+            tree_count,
+            configs,
+            &labels,
+            None,
+            gpu,
+        )
+    }
+
+    /// Builds tree_r_last again from the sealed replica at `replica_path` into the stores of
+    /// `tree_r_last_config`, and returns its root.
+    ///
+    /// Unlike `replicate_phase2`, this neither needs nor reads the layers, the replica is already
+    /// encoded and only hashed. It is built on the CPU.
+    pub fn rebuild_tree_r_last(
+        tree_r_last_config: StoreConfig,
+        replica_path: &Path,
+    ) -> Result<<Tree::Hasher as Hasher>::Domain> {
+        info!("rebuild_tree_r_last");
+
+        let tree_count = get_base_tree_count::<Tree>();
+        let tree_r_last_size = tree_r_last_config
+            .size
+            .context("tree_r_last config has no size")?;
+        let nodes_count = get_merkle_tree_leafs(tree_r_last_size, Tree::Arity::to_usize())?;
+        let (configs, replica_config) = split_config_and_replica(
+            tree_r_last_config,
+            replica_path.to_path_buf(),
+            nodes_count,
+            tree_count,
+        )?;
+
+        let replica = fs::File::open(replica_path)
+            .with_context(|| format!("could not open replica_path={:?}", replica_path))?;
+        let replica = unsafe { MmapOptions::new().map(&replica) }
+            .with_context(|| format!("could not mmap replica_path={:?}", replica_path))?;
+        let base_tree_bytes = nodes_count * NODE_SIZE;
+        ensure!(
+            replica.len() >= tree_count * base_tree_bytes,
+            "replica has {} bytes, expected {}",
+            replica.len(),
+            tree_count * base_tree_bytes
+        );
+
+        for (i, (config, offset)) in configs.iter().zip(&replica_config.offsets).enumerate() {
+            info!(
+                "building base tree_r_last with CPU {}/{}",
+                i + 1,
+                tree_count
+            );
+            LCTree::<Tree::Hasher, Tree::Arity, U0, U0>::from_byte_slice_with_config(
+                &replica[*offset..*offset + base_tree_bytes],
+                config.clone(),
+            )
+            .with_context(|| format!("failed tree_r_last CPU {}/{}", i + 1, tree_count))?;
+        }
+
+        let tree_r_last = create_lc_tree::<
+            LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>,
+        >(tree_r_last_size, &configs, &replica_config)?;
+
+        Ok(tree_r_last.root())
+    }
+
     // Assumes data is all zeros.
     // Replica path is used to create configs, but is not read.
     // Instead new zeros are provided (hence the need for replica to be all zeros).
@@ -1535,7 +3278,8 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             info!("generating tree r last using the GPU");
             let max_gpu_tree_batch_size = SETTINGS.max_gpu_tree_batch_size as usize;
 
-            let _gpu_lock = GPU_LOCK.lock().expect("failed to get gpu lock");
+            let gpu_lock = gpu::device_lock(None);
+            let _gpu_lock = gpu_lock.lock().expect("failed to get gpu lock");
             let batcher = match Batcher::pick_gpu(max_gpu_tree_batch_size) {
                 Ok(b) => Some(b),
                 Err(err) => {
@@ -1716,9 +3460,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         let tree_r_last_root = tree_r_last.root();
         drop(tree_r_last);
 
-        // comm_r = H(comm_c || comm_r_last)
-        let comm_r: <Tree::Hasher as Hasher>::Domain =
-            <Tree::Hasher as Hasher>::Function::hash2(&tree_c_root, &tree_r_last_root);
+        let comm_r = finalize_comm_r::<Tree::Hasher>(&tree_c_root, &tree_r_last_root);
 
         let p_aux = PersistentAux {
             comm_c: tree_c_root,
@@ -1743,9 +3485,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
 
         let existing_comm_r_last = existing_p_aux.comm_r_last;
 
-        // comm_r = H(comm_c || comm_r_last)
-        let comm_r: <Tree::Hasher as Hasher>::Domain =
-            <Tree::Hasher as Hasher>::Function::hash2(&tree_c_root, &existing_comm_r_last);
+        let comm_r = finalize_comm_r::<Tree::Hasher>(&tree_c_root, &existing_comm_r_last);
 
         let p_aux = PersistentAux {
             comm_c: tree_c_root,
@@ -1755,3 +3495,236 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         Ok((comm_r, p_aux))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use filecoin_hashers::{poseidon::PoseidonDomain, sha256::Sha256Hasher};
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+    use storage_proofs_core::TEST_SEED;
+    use tempfile::tempdir;
+
+    use crate::stacked::vanilla::{create_label::write_layer, tree_progress};
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_meminfo_bytes() {
+        let meminfo = concat!(
+            "MemTotal:       65536000 kB\n",
+            "MemFree:            1024 kB\n",
+            "MemAvailable:   32768000 kB\n",
+            "HugePages_Total:       0\n",
+        );
+        assert_eq!(
+            meminfo_bytes(meminfo, "MemAvailable"),
+            Some(32768000 * 1024)
+        );
+        assert_eq!(meminfo_bytes(meminfo, "MemFree"), Some(1024 * 1024));
+        assert_eq!(meminfo_bytes(meminfo, "HugePages_Total"), Some(0));
+        assert_eq!(meminfo_bytes(meminfo, "Mem"), None);
+        assert_eq!(meminfo_bytes(meminfo, "SwapTotal"), None);
+    }
+
+    #[test]
+    fn test_tree_c_low_memory_matches_in_memory() {
+        type Tree = DiskTree<PoseidonHasher, U8, U0, U0>;
+
+        let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+        let layers = 2;
+        let nodes_count = 64;
+        let cache_dir = tempdir().expect("tempdir failure");
+        let config = StoreConfig::new(cache_dir.path(), CacheKey::CommDTree.to_string(), 0);
+
+        let mut label_configs = Vec::with_capacity(layers);
+        for layer in 1..=layers {
+            let label_config =
+                StoreConfig::from_config(&config, CacheKey::label_layer(layer), Some(nodes_count));
+            let mut data = Vec::with_capacity(nodes_count * NODE_SIZE);
+            for _ in 0..nodes_count {
+                data.extend(PoseidonDomain::random(rng).into_bytes());
+            }
+            write_layer(&data, &label_config).expect("failed to write layer");
+            label_configs.push(label_config);
+        }
+        let labels =
+            LabelsCache::<Tree>::new(&Labels::new(label_configs)).expect("labels cache failure");
+
+        let mut roots = Vec::new();
+        for low_memory in &[false, true] {
+            let mut tree_c_config = StoreConfig::from_config(
+                &config,
+                format!("{}-{}", CacheKey::CommCTree, low_memory),
+                Some(get_merkle_tree_len(nodes_count, U8::to_usize()).expect("tree len failure")),
+            );
+            tree_c_config.rows_to_discard = default_rows_to_discard(nodes_count, U8::to_usize());
+
+            let tree_c = StackedDrg::<Tree, Sha256Hasher>::generate_tree_c_cpu_with_mode::<U2, U8>(
+                layers,
+                nodes_count,
+                1,
+                vec![tree_c_config],
+                &labels,
+                None,
+                *low_memory,
+                num_cpus::get(),
+            )
+            .expect("failed to generate tree_c");
+            roots.push(tree_c.root());
+        }
+
+        assert_eq!(roots[0], roots[1]);
+    }
+
+    #[test]
+    fn test_tree_c_independent_of_thread_count() {
+        type Tree = DiskTree<PoseidonHasher, U8, U2, U0>;
+
+        let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+        let layers = 2;
+        let tree_count = 2;
+        let nodes_count = 64;
+        let cache_dir = tempdir().expect("tempdir failure");
+        let config = StoreConfig::new(cache_dir.path(), CacheKey::CommDTree.to_string(), 0);
+
+        let mut label_configs = Vec::with_capacity(layers);
+        for layer in 1..=layers {
+            let label_config = StoreConfig::from_config(
+                &config,
+                CacheKey::label_layer(layer),
+                Some(tree_count * nodes_count),
+            );
+            let mut data = Vec::with_capacity(tree_count * nodes_count * NODE_SIZE);
+            for _ in 0..tree_count * nodes_count {
+                data.extend(PoseidonDomain::random(rng).into_bytes());
+            }
+            write_layer(&data, &label_config).expect("failed to write layer");
+            label_configs.push(label_config);
+        }
+        let labels =
+            LabelsCache::<Tree>::new(&Labels::new(label_configs)).expect("labels cache failure");
+
+        let mut roots = Vec::new();
+        for low_memory in &[false, true] {
+            for threads in &[1, 2, 3, 7, 16] {
+                let mut tree_c_config = StoreConfig::from_config(
+                    &config,
+                    format!("{}-{}-{}", CacheKey::CommCTree, low_memory, threads),
+                    Some(
+                        get_merkle_tree_len(nodes_count, U8::to_usize()).expect("tree len failure"),
+                    ),
+                );
+                tree_c_config.rows_to_discard =
+                    default_rows_to_discard(nodes_count, U8::to_usize());
+                let configs =
+                    split_config(tree_c_config, tree_count).expect("split_config failure");
+
+                // The column hashes of the low memory mode are computed on the rayon pool.
+                let pool = rayon::ThreadPoolBuilder::new()
+                    .num_threads(*threads)
+                    .build()
+                    .expect("failed to build thread pool");
+                let tree_c = pool
+                    .install(|| {
+                        StackedDrg::<Tree, Sha256Hasher>::generate_tree_c_cpu_with_mode::<U2, U8>(
+                            layers,
+                            nodes_count,
+                            tree_count,
+                            configs,
+                            &labels,
+                            None,
+                            *low_memory,
+                            *threads,
+                        )
+                    })
+                    .expect("failed to generate tree_c");
+                roots.push(tree_c.root());
+            }
+        }
+
+        assert!(roots.iter().all(|root| *root == roots[0]));
+    }
+
+    #[test]
+    fn test_tree_r_last_resume() {
+        type Tree = LCTree<PoseidonHasher, U8, U2, U0>;
+
+        let rng = &mut XorShiftRng::from_seed(TEST_SEED);
+        let tree_count = 2;
+        let nodes_count = 64;
+        let random_nodes = |rng: &mut XorShiftRng| -> Vec<u8> {
+            (0..tree_count * nodes_count)
+                .flat_map(|_| PoseidonDomain::random(rng).into_bytes())
+                .collect()
+        };
+
+        let labels_dir = tempdir().expect("tempdir failure");
+        let label_config = StoreConfig::new(labels_dir.path(), CacheKey::label_layer(1), 0);
+        write_layer(&random_nodes(rng), &label_config).expect("failed to write layer");
+        let labels = LabelsCache::<Tree>::new(&Labels::new(vec![label_config]))
+            .expect("labels cache failure");
+        let original_data = random_nodes(rng);
+
+        let generate = |cache_dir: &Path, data: &mut Vec<u8>, resume: Option<&TreeResume>| {
+            let mut tree_r_last_config = StoreConfig::new(
+                cache_dir,
+                CacheKey::CommRLastTree.to_string(),
+                default_rows_to_discard(nodes_count, U8::to_usize()),
+            );
+            tree_r_last_config.size =
+                Some(get_merkle_tree_len(nodes_count, U8::to_usize()).expect("tree len failure"));
+            let replica_path = cache_dir.join("replica");
+            fs::write(&replica_path, &original_data).expect("failed to write replica");
+
+            let tree_r_last = StackedDrg::<Tree, Sha256Hasher>::generate_tree_r_last_cpu::<U8>(
+                &mut Data::from(&mut data[..]),
+                nodes_count,
+                tree_count,
+                tree_r_last_config,
+                replica_path,
+                labels
+                    .labels_for_last_layer()
+                    .expect("failed to get last layer"),
+                StackedDrg::<Tree, Sha256Hasher>::prepare_tree_r_data_cpu,
+                resume,
+            )
+            .expect("failed to generate tree_r_last");
+            tree_r_last.root()
+        };
+
+        let expected_dir = tempdir().expect("tempdir failure");
+        let mut expected_data = original_data.clone();
+        let expected_root = generate(expected_dir.path(), &mut expected_data, None);
+
+        // The leaves of tree_d are the unencoded data.
+        let cache_dir = tempdir().expect("tempdir failure");
+        let tree_d_config = StoreConfig::new(cache_dir.path(), CacheKey::CommDTree.to_string(), 0);
+        fs::write(
+            StoreConfig::data_path(&tree_d_config.path, &tree_d_config.id),
+            &original_data,
+        )
+        .expect("failed to write tree_d");
+        let resume = TreeResume {
+            inputs: "inputs".to_string(),
+            tree_d_config: Some(tree_d_config),
+        };
+
+        let mut data = original_data.clone();
+        let root = generate(cache_dir.path(), &mut data, Some(&resume));
+        assert_eq!(root, expected_root);
+        assert!(data == expected_data);
+
+        // The first base tree is reused without encoding its range again, and the range of the
+        // second one, whose encoding was interrupted, is restored before it is encoded.
+        let configs = split_config(
+            StoreConfig::new(cache_dir.path(), CacheKey::CommRLastTree.to_string(), 0),
+            tree_count,
+        )
+        .expect("split_config failure");
+        tree_progress::mark_started(&configs[1], &resume.inputs).expect("mark_started failure");
+        let root = generate(cache_dir.path(), &mut data, Some(&resume));
+        assert_eq!(root, expected_root);
+        assert!(data == expected_data);
+    }
+}
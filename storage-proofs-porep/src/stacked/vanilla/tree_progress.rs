@@ -0,0 +1,227 @@
+//! The progress of building the base trees of tree_c and tree_r_last, which lets an interrupted
+//! `replicate_phase2` reuse the base trees it completed, see [`TreeResume`].
+
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use anyhow::Context;
+use filecoin_hashers::Domain;
+use log::{info, warn};
+use merkletree::store::StoreConfig;
+use storage_proofs_core::{error::Result, util::NODE_SIZE};
+
+use crate::stacked::vanilla::create_label::layer_digest_path;
+
+/// Lets the tree builders reuse the base trees which are recorded as complete for `inputs`.
+#[derive(Clone, Debug)]
+pub(crate) struct TreeResume {
+    /// Identifies what the trees are built from, see `tree_c_inputs` and `tree_r_last_inputs`,
+    /// so that the base trees of e.g. a sector which was labeled again are not reused.
+    pub inputs: String,
+    /// The store of tree_d, whose leaves are the unencoded replica, which resuming tree_r_last
+    /// needs. Building tree_r_last encodes the replica in place, so the range of a base tree whose
+    /// encoding was interrupted is restored from it before it is encoded again.
+    pub tree_d_config: Option<StoreConfig>,
+}
+
+/// Whether the base tree stored as `config` is recorded as complete for `resume`, so that it does
+/// not have to be built again.
+pub(crate) fn is_reusable(resume: Option<&TreeResume>, config: &StoreConfig) -> bool {
+    resume.map_or(false, |resume| {
+        base_tree_state(config, &resume.inputs) == BaseTreeState::Complete
+    })
+}
+
+/// Records that the nodes `start..end` of the replica `data` are encoded for the base tree of
+/// tree_r_last stored as `config`. If encoding them started before, they are restored from tree_d
+/// first, as some of them may already be encoded.
+pub(crate) fn start_encoding(
+    resume: &TreeResume,
+    config: &StoreConfig,
+    data: &mut [u8],
+    start: usize,
+    end: usize,
+) -> Result<()> {
+    if base_tree_state(config, &resume.inputs) == BaseTreeState::Started {
+        let tree_d_config = resume
+            .tree_d_config
+            .as_ref()
+            .context("tree_r_last cannot be resumed without tree_d")?;
+        info!(
+            "restoring the unencoded nodes {}..{} from tree_d",
+            start, end
+        );
+        restore_original_data(tree_d_config, data, start, end)?;
+    }
+
+    mark_started(config, &resume.inputs)
+}
+
+/// How far a base tree was built, as recorded next to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BaseTreeState {
+    /// Nothing is recorded for the inputs.
+    Missing,
+    /// Building the base tree started, see `mark_started`. The range of the replica of a base
+    /// tree of tree_r_last may be partially encoded.
+    Started,
+    /// The base tree is stored, and the range of the replica of a base tree of tree_r_last is
+    /// encoded, see `mark_complete`.
+    Complete,
+}
+
+/// Returns the path of the file recording the progress of the base tree stored as `config`.
+pub(crate) fn progress_path(config: &StoreConfig) -> PathBuf {
+    StoreConfig::data_path(&config.path, &config.id).with_extension("progress")
+}
+
+/// Returns the state of the base tree stored as `config`, which was built from `inputs`.
+///
+/// A base tree recorded as complete whose store does not have the recorded length is returned as
+/// started, as the replica may then be encoded, but the tree is not.
+pub(crate) fn base_tree_state(config: &StoreConfig, inputs: &str) -> BaseTreeState {
+    let progress = match fs::read_to_string(progress_path(config)) {
+        Ok(progress) => progress,
+        Err(_) => return BaseTreeState::Missing,
+    };
+    let (state, recorded_inputs) = match progress.split_once('\n') {
+        Some(progress) => progress,
+        None => return BaseTreeState::Missing,
+    };
+    if recorded_inputs != inputs {
+        return BaseTreeState::Missing;
+    }
+
+    match state.split_once(' ') {
+        None if state == "started" => BaseTreeState::Started,
+        Some(("complete", len)) => {
+            let data_path = StoreConfig::data_path(&config.path, &config.id);
+            match fs::metadata(&data_path) {
+                Ok(metadata) if metadata.len().to_string() == len => BaseTreeState::Complete,
+                _ => {
+                    warn!(
+                        "{:?} does not have the recorded length, building it again",
+                        data_path
+                    );
+                    BaseTreeState::Started
+                }
+            }
+        }
+        _ => BaseTreeState::Missing,
+    }
+}
+
+/// Records that building the base tree stored as `config` from `inputs` started.
+pub(crate) fn mark_started(config: &StoreConfig, inputs: &str) -> Result<()> {
+    write_progress(config, &format!("started\n{}", inputs))
+}
+
+/// Records that the base tree stored as `config` was built from `inputs`, once its store is
+/// synced to disk.
+pub(crate) fn mark_complete(config: &StoreConfig, inputs: &str) -> Result<()> {
+    let data_path = StoreConfig::data_path(&config.path, &config.id);
+    let file = File::open(&data_path).with_context(|| format!("failed to open {:?}", data_path))?;
+    file.sync_all()
+        .with_context(|| format!("failed to sync {:?}", data_path))?;
+    let len = file.metadata()?.len();
+
+    write_progress(config, &format!("complete {}\n{}", len, inputs))
+}
+
+fn write_progress(config: &StoreConfig, progress: &str) -> Result<()> {
+    let path = progress_path(config);
+    let tmp_path = path.with_extension("progress.tmp");
+    fs::write(&tmp_path, progress)
+        .with_context(|| format!("failed to write tree progress {:?}", tmp_path))?;
+    fs::rename(&tmp_path, &path)
+        .with_context(|| format!("failed to rename tree progress {:?}", tmp_path))?;
+
+    Ok(())
+}
+
+/// Removes the progress of the base tree stored as `config`, if there is one.
+pub(crate) fn remove_progress(config: &StoreConfig) {
+    let path = progress_path(config);
+    if path.exists() {
+        if let Err(err) = fs::remove_file(&path) {
+            warn!("failed to delete tree progress {:?}: {}", path, err);
+        }
+    }
+}
+
+/// Returns the inputs of tree_c, the digests of the layers stored as `label_configs`, or `None`
+/// if a layer has no digest.
+pub(crate) fn tree_c_inputs(label_configs: &[StoreConfig]) -> Option<String> {
+    let digests: Option<Vec<String>> = label_configs
+        .iter()
+        .map(|config| {
+            fs::read_to_string(layer_digest_path(config))
+                .ok()
+                .map(|digest| digest.trim().to_string())
+        })
+        .collect();
+
+    digests.map(|digests| digests.join(" "))
+}
+
+/// Returns the inputs of tree_r_last, the digest of the last layer stored as `last_layer` and
+/// `comm_d`, or `None` if the layer has no digest.
+pub(crate) fn tree_r_last_inputs<D: Domain>(
+    last_layer: &StoreConfig,
+    comm_d: &D,
+) -> Option<String> {
+    let digest = tree_c_inputs(std::slice::from_ref(last_layer))?;
+
+    Some(format!("{} {}", digest, hex::encode(comm_d.into_bytes())))
+}
+
+/// Copies the unencoded nodes `start..end` of the replica `data` from the leaves of tree_d,
+/// stored as `tree_d_config`.
+pub(crate) fn restore_original_data(
+    tree_d_config: &StoreConfig,
+    data: &mut [u8],
+    start: usize,
+    end: usize,
+) -> Result<()> {
+    let path = StoreConfig::data_path(&tree_d_config.path, &tree_d_config.id);
+    let mut file = File::open(&path).with_context(|| format!("failed to open {:?}", path))?;
+    file.seek(SeekFrom::Start((start * NODE_SIZE) as u64))?;
+    file.read_exact(&mut data[(start * NODE_SIZE)..(end * NODE_SIZE)])
+        .with_context(|| format!("failed to read the original data from {:?}", path))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use storage_proofs_core::cache_key::CacheKey;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_base_tree_state() {
+        let cache_dir = tempdir().expect("tempdir failure");
+        let config = StoreConfig::new(cache_dir.path(), CacheKey::CommCTree.to_string(), 0);
+        let data_path = StoreConfig::data_path(&config.path, &config.id);
+        let state = |inputs| base_tree_state(&config, inputs);
+
+        assert_eq!(state("a"), BaseTreeState::Missing);
+
+        mark_started(&config, "a").expect("mark_started failure");
+        assert_eq!(state("a"), BaseTreeState::Started);
+        assert_eq!(state("b"), BaseTreeState::Missing);
+
+        fs::write(&data_path, vec![1u8; 64]).expect("failed to write tree");
+        mark_complete(&config, "a").expect("mark_complete failure");
+        assert_eq!(state("a"), BaseTreeState::Complete);
+        assert_eq!(state("b"), BaseTreeState::Missing);
+
+        fs::write(&data_path, vec![1u8; 32]).expect("failed to write tree");
+        assert_eq!(state("a"), BaseTreeState::Started);
+
+        remove_progress(&config);
+        assert_eq!(state("a"), BaseTreeState::Missing);
+    }
+}
@@ -15,7 +15,7 @@ use storage_proofs_core::{
     cache_key::CacheKey,
     compound_proof::CompoundProof,
     drgraph::BASE_DEGREE,
-    merkle::{get_base_tree_count, DiskTree, MerkleTreeTrait},
+    merkle::{get_base_tree_count, DiskTree, MerkleTreeTrait, WithTreeCArity},
     proof::ProofScheme,
     test_helper::setup_replica,
     util::default_rows_to_discard,
@@ -40,6 +40,13 @@ fn test_stacked_input_circuit_poseidon_base_8() {
     test_stacked_porep_circuit::<DiskTree<PoseidonHasher, U8, U0, U0>>(22, 1_199_620);
 }
 
+#[test]
+fn test_stacked_input_circuit_poseidon_base_8_tree_c_2() {
+    test_stacked_porep_circuit::<WithTreeCArity<DiskTree<PoseidonHasher, U8, U0, U0>, U2>>(
+        22, 1_205_800,
+    );
+}
+
 #[test]
 fn test_stacked_input_circuit_poseidon_sub_8_4() {
     test_stacked_porep_circuit::<DiskTree<PoseidonHasher, U8, U4, U0>>(22, 1_296_576);
@@ -1,4 +1,4 @@
-use std::fs::remove_file;
+use std::fs::{self, remove_file};
 
 use blstrs::Scalar as Fr;
 use ff::{Field, PrimeField};
@@ -15,7 +15,7 @@ use storage_proofs_core::{
     api_version::ApiVersion,
     cache_key::CacheKey,
     drgraph::BASE_DEGREE,
-    merkle::{get_base_tree_count, DiskTree, MerkleTreeTrait},
+    merkle::{get_base_tree_count, DiskTree, MerkleTreeTrait, WithTreeCArity},
     proof::ProofScheme,
     table_tests,
     test_helper::setup_replica,
@@ -164,6 +164,24 @@ fn test_extract_all<Tree: 'static + MerkleTreeTrait>() {
         assert!(!state.generated);
     }
 
+    // A layer which does not match its digest is generated again, with all layers after it.
+    let corrupt = 1;
+    let layer_config = &label_states[corrupt].config;
+    let data_path = StoreConfig::data_path(&layer_config.path, &layer_config.id);
+    let mut labels = fs::read(&data_path).expect("failed to read layer");
+    labels[0] ^= 1;
+    fs::write(&data_path, &labels).expect("failed to write layer");
+
+    let (_, label_states) = StackedDrg::<Tree, Blake2sHasher>::generate_labels_for_encoding(
+        &pp.graph,
+        &layer_challenges,
+        &replica_id,
+        config.clone(),
+    )
+    .expect("label generation failed");
+    assert!(label_states[..corrupt].iter().all(|state| state.generated));
+    assert!(label_states[corrupt..].iter().all(|state| !state.generated));
+
     assert_ne!(data, &mmapped_data[..], "replication did not change data");
 
     StackedDrg::<Tree, Blake2sHasher>::extract_all(
@@ -338,7 +356,14 @@ fn test_prove_verify_fixed(n: usize) {
 
     test_prove_verify::<DiskTree<PoseidonHasher, U8, U0, U0>>(n, challenges.clone());
     test_prove_verify::<DiskTree<PoseidonHasher, U8, U2, U0>>(n, challenges.clone());
-    test_prove_verify::<DiskTree<PoseidonHasher, U8, U8, U2>>(n, challenges);
+    test_prove_verify::<DiskTree<PoseidonHasher, U8, U8, U2>>(n, challenges.clone());
+
+    // tree_c with another arity than tree_r_last.
+    test_prove_verify::<WithTreeCArity<DiskTree<PoseidonHasher, U8, U0, U0>, U2>>(
+        n,
+        challenges.clone(),
+    );
+    test_prove_verify::<WithTreeCArity<DiskTree<PoseidonHasher, U8, U8, U2>, U4>>(n, challenges);
 }
 
 fn test_prove_verify<Tree: 'static + MerkleTreeTrait>(n: usize, challenges: LayerChallenges) {
@@ -95,6 +95,9 @@ pub struct PrivateSector<'a, Tree: MerkleTreeTrait> {
     >,
     pub comm_c: <Tree::Hasher as Hasher>::Domain,
     pub comm_r_last: <Tree::Hasher as Hasher>::Domain,
+    /// The number of rows `tree` was stored without, as recorded when it was built, or `None` for
+    /// the default of its leaves.
+    pub rows_to_discard: Option<usize>,
 }
 
 #[derive(Debug)]
@@ -253,6 +256,98 @@ pub fn generate_leaf_challenge_inner<T: Domain>(
     leaf_challenge % (pub_params.sector_size / NODE_SIZE as u64)
 }
 
+/// The outcome of verifying the proof of a single sector, see [`verify_sector_proof`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectorProofVerification {
+    /// Whether `comm_r` is the hash of `comm_c` and the root of the inclusion proofs.
+    pub comm_r_valid: bool,
+    /// Whether the inclusion proof of each challenge is valid, in challenge order.
+    pub challenges: Vec<bool>,
+}
+
+impl SectorProofVerification {
+    pub fn is_valid(&self) -> bool {
+        self.comm_r_valid && self.challenges.iter().all(|valid| *valid)
+    }
+}
+
+/// Verifies the proof of the sector at `sector_index` among all proven sectors, which determines
+/// its challenges, and reports the outcome of every check instead of stopping at the first
+/// failure.
+pub fn verify_sector_proof<P: MerkleProofTrait>(
+    pub_params: &PublicParams,
+    randomness: &<P::Hasher as Hasher>::Domain,
+    sector_index: usize,
+    pub_sector: &PublicSector<<P::Hasher as Hasher>::Domain>,
+    sector_proof: &SectorProof<P>,
+) -> Result<SectorProofVerification> {
+    let sector_id = pub_sector.id;
+    let inclusion_proofs = &sector_proof.inclusion_proofs;
+    ensure!(
+        pub_params.challenge_count == inclusion_proofs.len(),
+        "unexpected number of inclusion proofs: {} != {}",
+        pub_params.challenge_count,
+        inclusion_proofs.len()
+    );
+
+    // Verify that H(Comm_c || Comm_r_last) == Comm_R
+
+    // comm_r_last is the root of the proof
+    let comm_r_last = inclusion_proofs[0].root();
+
+    let comm_r_valid = AsRef::<[u8]>::as_ref(&<P::Hasher as Hasher>::Function::hash2(
+        &sector_proof.comm_c,
+        &comm_r_last,
+    )) == AsRef::<[u8]>::as_ref(&pub_sector.comm_r);
+    if !comm_r_valid {
+        error!("hash(comm_c || comm_r_last) != comm_r: {:?}", sector_id);
+    }
+
+    // avoid rehashing fixed inputs
+    let mut challenge_hasher = Sha256::new();
+    challenge_hasher.update(AsRef::<[u8]>::as_ref(randomness));
+    challenge_hasher.update(&u64::from(sector_id).to_le_bytes()[..]);
+
+    let challenges = inclusion_proofs
+        .par_iter()
+        .enumerate()
+        .map(|(n, inclusion_proof)| {
+            let challenge_index = sector_index * pub_params.challenge_count + n;
+            let challenged_leaf = generate_leaf_challenge_inner::<<P::Hasher as Hasher>::Domain>(
+                challenge_hasher.clone(),
+                pub_params,
+                challenge_index as u64,
+            );
+
+            // validate all comm_r_lasts match
+            if inclusion_proof.root() != comm_r_last {
+                error!("inclusion proof root != comm_r_last: {:?}", sector_id);
+                return false;
+            }
+
+            // validate the path length
+            let expected_path_length =
+                inclusion_proof.expected_len(pub_params.sector_size as usize / NODE_SIZE);
+
+            if expected_path_length != inclusion_proof.path().len() {
+                error!("wrong path length: {:?}", sector_id);
+                return false;
+            }
+
+            if !inclusion_proof.validate(challenged_leaf as usize) {
+                error!("invalid inclusion proof: {:?}", sector_id);
+                return false;
+            }
+            true
+        })
+        .collect();
+
+    Ok(SectorProofVerification {
+        comm_r_valid,
+        challenges,
+    })
+}
+
 // Generates a single vanilla proof, given the private inputs and sector challenges.
 pub fn vanilla_proof<Tree: MerkleTreeTrait>(
     sector_id: SectorId,
@@ -270,7 +365,9 @@ pub fn vanilla_proof<Tree: MerkleTreeTrait>(
     let tree = priv_sector.tree;
 
     let tree_leafs = tree.leafs();
-    let rows_to_discard = default_rows_to_discard(tree_leafs, Tree::Arity::to_usize());
+    let rows_to_discard = priv_sector
+        .rows_to_discard
+        .unwrap_or_else(|| default_rows_to_discard(tree_leafs, Tree::Arity::to_usize()));
 
     trace!(
         "Generating proof for tree leafs {} and arity {}",
@@ -381,8 +478,9 @@ impl<'a, Tree: 'a + MerkleTreeTrait> ProofScheme<'a> for FallbackPoSt<'a, Tree>
                     let sector_id = pub_sector.id;
                     let tree = priv_sector.tree;
                     let tree_leafs = tree.leafs();
-                    let rows_to_discard =
-                        default_rows_to_discard(tree_leafs, Tree::Arity::to_usize());
+                    let rows_to_discard = priv_sector.rows_to_discard.unwrap_or_else(|| {
+                        default_rows_to_discard(tree_leafs, Tree::Arity::to_usize())
+                    });
 
                     trace!(
                         "Generating proof for tree leafs {} and arity {}",
@@ -564,7 +662,6 @@ impl<'a, Tree: 'a + MerkleTreeTrait> ProofScheme<'a> for FallbackPoSt<'a, Tree>
             "must be called with a partition index"
         );
         let partition_index = pub_inputs.k.expect("prechecked");
-        let challenge_count = pub_params.challenge_count;
         let num_sectors_per_chunk = pub_params.sector_count;
 
         let j = partition_index;
@@ -590,74 +687,14 @@ impl<'a, Tree: 'a + MerkleTreeTrait> ProofScheme<'a> for FallbackPoSt<'a, Tree>
             .zip(proof.sectors.par_iter())
             .enumerate()
             .map(|(i, (pub_sector, sector_proof))| {
-                let sector_id = pub_sector.id;
-                let comm_r = &pub_sector.comm_r;
-                let comm_c = sector_proof.comm_c;
-                let inclusion_proofs = &sector_proof.inclusion_proofs;
-
-                // Verify that H(Comm_c || Comm_r_last) == Comm_R
-
-                // comm_r_last is the root of the proof
-                let comm_r_last = inclusion_proofs[0].root();
-
-                if AsRef::<[u8]>::as_ref(&<Tree::Hasher as Hasher>::Function::hash2(
-                    &comm_c,
-                    &comm_r_last,
-                )) != AsRef::<[u8]>::as_ref(comm_r)
-                {
-                    error!("hash(comm_c || comm_r_last) != comm_r: {:?}", sector_id);
-                    return Ok(false);
-                }
-
-                ensure!(
-                    challenge_count == inclusion_proofs.len(),
-                    "unexpected number of inclusion proofs: {} != {}",
-                    challenge_count,
-                    inclusion_proofs.len()
-                );
-
-                // avoid rehashing fixed inputs
-                let mut challenge_hasher = Sha256::new();
-                challenge_hasher.update(AsRef::<[u8]>::as_ref(&pub_inputs.randomness));
-                challenge_hasher.update(&u64::from(sector_id).to_le_bytes()[..]);
-
-                let is_valid_list = inclusion_proofs
-                    .par_iter()
-                    .enumerate()
-                    .map(|(n, inclusion_proof)| -> Result<bool> {
-                        let challenge_index =
-                            (j * num_sectors_per_chunk + i) * pub_params.challenge_count + n;
-                        let challenged_leaf =
-                            generate_leaf_challenge_inner::<<Tree::Hasher as Hasher>::Domain>(
-                                challenge_hasher.clone(),
-                                pub_params,
-                                challenge_index as u64,
-                            );
-
-                        // validate all comm_r_lasts match
-                        if inclusion_proof.root() != comm_r_last {
-                            error!("inclusion proof root != comm_r_last: {:?}", sector_id);
-                            return Ok(false);
-                        }
-
-                        // validate the path length
-                        let expected_path_length = inclusion_proof
-                            .expected_len(pub_params.sector_size as usize / NODE_SIZE);
-
-                        if expected_path_length != inclusion_proof.path().len() {
-                            error!("wrong path length: {:?}", sector_id);
-                            return Ok(false);
-                        }
-
-                        if !inclusion_proof.validate(challenged_leaf as usize) {
-                            error!("invalid inclusion proof: {:?}", sector_id);
-                            return Ok(false);
-                        }
-                        Ok(true)
-                    })
-                    .collect::<Result<Vec<bool>>>()?;
-
-                Ok(is_valid_list.into_iter().all(|v| v))
+                verify_sector_proof(
+                    pub_params,
+                    &pub_inputs.randomness,
+                    j * num_sectors_per_chunk + i,
+                    pub_sector,
+                    sector_proof,
+                )
+                .map(|verification| verification.is_valid())
             })
             .reduce(
                 || Ok(true),
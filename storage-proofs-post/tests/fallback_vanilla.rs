@@ -151,6 +151,7 @@ fn test_fallback_post<Tree: MerkleTreeTrait>(
             tree,
             comm_c,
             comm_r_last,
+            rows_to_discard: None,
         });
 
         let comm_r = <Tree::Hasher as Hasher>::Function::hash2(&comm_c, &comm_r_last);
@@ -333,6 +334,7 @@ fn test_invalid_fallback_post<Tree: MerkleTreeTrait>(
             tree: if make_faulty { &wrong_tree } else { tree },
             comm_c,
             comm_r_last,
+            rows_to_discard: None,
         });
 
         let comm_r = <Tree::Hasher as Hasher>::Function::hash2(&comm_c, &comm_r_last);
@@ -31,7 +31,7 @@ use storage_proofs_core::{
     parameter_cache::ParameterSetMetadata,
     proof::ProofScheme,
 };
-use storage_proofs_porep::stacked::{StackedDrg, TreeRElementData};
+use storage_proofs_porep::stacked::{GpuOptions, StackedDrg, TreeRElementData};
 
 use crate::{
     constants::{
@@ -932,6 +932,7 @@ where
             new_replica_path.to_path_buf(),
             &new_replica_store,
             Some(Self::prepare_tree_r_data),
+            &GpuOptions::default(),
         )?;
 
         let comm_r_last_new = tree_r_last.root();
@@ -1195,6 +1196,7 @@ where
             sector_key_cache_path.to_path_buf(),
             &sector_key_store,
             Some(Self::prepare_tree_r_data),
+            &GpuOptions::default(),
         )?;
 
         Ok(tree_r_last.root())